@@ -0,0 +1,170 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// This is a version of the "histogram" diff algorithm (originally from JGit). Like the patience
+// algorithm, it works by finding a matching pair of lines to use as an anchor, and then
+// recursing on the parts before and after. Instead of insisting that the anchor be unique, it
+// picks the lines that occur least often in the first file, and then extends them to the longest
+// possible common run.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::{match_ends, myers, LineDiff};
+
+// Lines that occur more often than this are too common to be useful as anchors. If we only find
+// lines like this, we fall back to Myers' algorithm.
+const MAX_OCCURRENCES: usize = 64;
+
+// A common run of lines: `len` lines starting at `a_start` in the first file match `len` lines
+// starting at `b_start` in the second file.
+struct Run {
+    a_start: usize,
+    b_start: usize,
+    len: usize,
+    // The number of times that the least common line in this run appears in the first file.
+    occurrences: usize,
+}
+
+fn find_anchor<T: Hash + Eq>(a: &[T], b: &[T]) -> Option<Run> {
+    let mut positions = HashMap::<&T, Vec<usize>>::new();
+    for (i, line) in a.iter().enumerate() {
+        positions.entry(line).or_default().push(i);
+    }
+
+    let mut best: Option<Run> = None;
+    let mut b_idx = 0;
+    while b_idx < b.len() {
+        let mut next_b_idx = b_idx + 1;
+        if let Some(a_positions) = positions.get(&b[b_idx]) {
+            let occurrences = a_positions.len();
+            let good_enough = best
+                .as_ref()
+                .map(|r| occurrences <= r.occurrences)
+                .unwrap_or(true);
+            if occurrences <= MAX_OCCURRENCES && good_enough {
+                for &a_idx in a_positions {
+                    // Extend the match as far as possible in both directions.
+                    let before = a[..a_idx]
+                        .iter()
+                        .rev()
+                        .zip(b[..b_idx].iter().rev())
+                        .take_while(|(x, y)| x == y)
+                        .count();
+                    let after = a[a_idx..]
+                        .iter()
+                        .zip(b[b_idx..].iter())
+                        .take_while(|(x, y)| x == y)
+                        .count();
+                    let run = Run {
+                        a_start: a_idx - before,
+                        b_start: b_idx - before,
+                        len: before + after,
+                        occurrences,
+                    };
+                    next_b_idx = next_b_idx.max(b_idx + after);
+
+                    let better = best
+                        .as_ref()
+                        .map(|r| {
+                            run.occurrences < r.occurrences
+                                || (run.occurrences == r.occurrences && run.len > r.len)
+                        })
+                        .unwrap_or(true);
+                    if better {
+                        best = Some(run);
+                    }
+                }
+            }
+        }
+        // Lines in the middle of a run that we already found can't start a longer run.
+        b_idx = next_b_idx;
+    }
+    best
+}
+
+fn diff_rec<T: Hash + Eq>(
+    a: &[T],
+    a_offset: usize,
+    b: &[T],
+    b_offset: usize,
+    diff: &mut Vec<LineDiff>,
+) {
+    let (pref_len, a_mid, b_mid, suff_len) = match_ends(a, b);
+    for i in 0..pref_len {
+        diff.push(LineDiff::Keep(a_offset + i, b_offset + i));
+    }
+
+    let a_mid_offset = a_offset + pref_len;
+    let b_mid_offset = b_offset + pref_len;
+    if let Some(run) = find_anchor(a_mid, b_mid) {
+        diff_rec(
+            &a_mid[..run.a_start],
+            a_mid_offset,
+            &b_mid[..run.b_start],
+            b_mid_offset,
+            diff,
+        );
+        for i in 0..run.len {
+            diff.push(LineDiff::Keep(
+                a_mid_offset + run.a_start + i,
+                b_mid_offset + run.b_start + i,
+            ));
+        }
+        diff_rec(
+            &a_mid[(run.a_start + run.len)..],
+            a_mid_offset + run.a_start + run.len,
+            &b_mid[(run.b_start + run.len)..],
+            b_mid_offset + run.b_start + run.len,
+            diff,
+        );
+    } else {
+        myers::diff_rec(a_mid, a_mid_offset, b_mid, b_mid_offset, diff);
+    }
+
+    for i in 0..suff_len {
+        diff.push(LineDiff::Keep(
+            a_mid_offset + a_mid.len() + i,
+            b_mid_offset + b_mid.len() + i,
+        ));
+    }
+}
+
+pub fn histogram_diff<T: Hash + Eq>(a: &[T], b: &[T]) -> Vec<LineDiff> {
+    let mut ret = Vec::with_capacity(a.len().max(b.len()));
+    diff_rec(a, 0, b, 0, &mut ret);
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LineDiff::*;
+
+    #[test]
+    fn rare_lines() {
+        // There are no unique lines, but "b" is less common than "a".
+        let a = ["a", "b", "a", "a", "b", "a"];
+        let b = ["b", "a", "b", "a"];
+        let d = histogram_diff(&a, &b);
+        assert_eq!(
+            d,
+            vec![
+                Delete(0),
+                Keep(1, 0),
+                Delete(2),
+                Keep(3, 1),
+                Keep(4, 2),
+                Keep(5, 3),
+            ]
+        );
+    }
+}
@@ -13,11 +13,17 @@
 #[macro_use]
 extern crate proptest;
 
-use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
 
+mod histogram;
 mod lis;
+mod myers;
+mod patience;
+mod words;
+
+pub use crate::myers::myers_diff;
+pub use crate::words::{changed_line_pairs, word_diff, WordDiff};
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum LineDiff {
@@ -120,76 +126,51 @@ fn diff_ends<T: Eq>(a: &[T], a_offset: usize, b: &[T], b_offset: usize, diff: &m
     }
 }
 
-pub fn diff<T: Hash + Eq>(a: &[T], b: &[T]) -> Vec<LineDiff> {
-    let (pref_len, a_mid, b_mid, suff_len) = match_ends(a, b);
-    let a_line_counts = line_counts(a_mid);
-    let mut b_line_counts = line_counts(b_mid);
-    let a_unique = a_line_counts
-        .into_iter()
-        .filter(|(_, count)| *count == 1)
-        .map(|(line, _)| line);
-
-    // `both_unique` is a Vec of (usize, usize) pairs corresponding to lines that are unique in
-    // both files. The first usize is the index *in file b* and the second is the index in file a,
-    // and `both_unique` will be sorted according to the index in file a. The order of the indices
-    // seems backwards, but the point is that we'll look for a longest increasing subsequence and
-    // we want "increasing" here to mean according to appearance in file b.
-    let mut both_unique = a_unique
-        .filter_map(|a_line| {
-            // TODO: This is a bit awkward, but it can get better if HashMap::get_key_value is
-            // stabilized.
-            let a_idx = a_line.idx;
-            if let Entry::Occupied(entry) = b_line_counts.entry(a_line) {
-                if entry.get() == &1 {
-                    return Some((entry.key().idx, a_idx));
-                }
-            }
-            None
-        })
-        .collect::<Vec<(usize, usize)>>();
-    both_unique.sort_unstable_by_key(|(_b_idx, a_idx)| *a_idx);
+/// The different algorithms that can be used for computing a diff.
+///
+/// Any of these will produce a valid diff, but they make different tradeoffs when there are
+/// several ways to describe the same change.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum Algorithm {
+    /// Myers' algorithm, which finds a diff that keeps as many lines as possible.
+    #[default]
+    Myers,
+    /// The "patience" algorithm, which matches up lines that appear exactly once in both files,
+    /// and then recurses on the parts in between. This gives up on finding the smallest diff,
+    /// but it tends to produce diffs that make more sense to humans when blocks of code are
+    /// moved around, because it doesn't try to match common lines like "}" or blank lines.
+    Patience,
+    /// The "histogram" algorithm, which is similar to the patience algorithm but also uses lines
+    /// that aren't unique, preferring the ones that appear the least often.
+    Histogram,
+}
 
-    let mut ret = Vec::with_capacity(a.len().max(b.len()));
-    for i in 0..pref_len {
-        ret.push(LineDiff::Keep(i, i));
-    }
+/// Options for controlling how a diff is computed.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct DiffOptions {
+    /// Which diff algorithm to use.
+    pub algorithm: Algorithm,
+}
 
-    let lis = lis::longest_increasing_subsequence(&both_unique);
-    let mut prev_b_idx = 0;
-    let mut prev_a_idx = 0;
-    for i in lis {
-        let (next_b_idx, next_a_idx) = both_unique[i];
-        let a_chunk = &a_mid[prev_a_idx..next_a_idx];
-        let b_chunk = &b_mid[prev_b_idx..next_b_idx];
-        diff_ends(
-            a_chunk,
-            pref_len + prev_a_idx,
-            b_chunk,
-            pref_len + prev_b_idx,
-            &mut ret,
-        );
-        prev_b_idx = next_b_idx;
-        prev_a_idx = next_a_idx;
+impl DiffOptions {
+    /// Returns the default options, but using the given algorithm.
+    pub fn with_algorithm(algorithm: Algorithm) -> DiffOptions {
+        DiffOptions { algorithm }
     }
+}
 
-    let a_chunk = &a_mid[prev_a_idx..];
-    let b_chunk = &b_mid[prev_b_idx..];
-    diff_ends(
-        a_chunk,
-        pref_len + prev_a_idx,
-        b_chunk,
-        pref_len + prev_b_idx,
-        &mut ret,
-    );
+/// Computes a diff between `a` and `b`, using the default options.
+pub fn diff<T: Hash + Eq>(a: &[T], b: &[T]) -> Vec<LineDiff> {
+    diff_with_options(a, b, &DiffOptions::default())
+}
 
-    for i in 0..suff_len {
-        ret.push(LineDiff::Keep(
-            a.len() - suff_len + i,
-            b.len() - suff_len + i,
-        ));
+/// Computes a diff between `a` and `b`.
+pub fn diff_with_options<T: Hash + Eq>(a: &[T], b: &[T], opts: &DiffOptions) -> Vec<LineDiff> {
+    match opts.algorithm {
+        Algorithm::Myers => myers_diff(a, b),
+        Algorithm::Patience => patience::patience_diff(a, b),
+        Algorithm::Histogram => histogram::histogram_diff(a, b),
     }
-
-    ret
 }
 
 #[cfg(test)]
@@ -237,7 +218,7 @@ mod tests {
     // - every input index appears exactly once in the diff, in increasing order
     // - every output index appears exactly once in the diff, in increasing order
     // - for every Keep line in the diff, the input and output lines are the same.
-    fn assert_valid<T: Debug + Eq>(a: &[T], b: &[T], diff: &[LineDiff]) {
+    pub(crate) fn assert_valid<T: Debug + Eq>(a: &[T], b: &[T], diff: &[LineDiff]) {
         let input_indices = diff
             .iter()
             .filter_map(|line| match *line {
@@ -278,7 +259,7 @@ mod tests {
 
     // Generates two files for diffing by first generating one, and then making another by changing
     // the first one a bit.
-    fn two_files() -> BoxedStrategy<(Vec<i32>, Vec<i32>)> {
+    pub(crate) fn two_files() -> BoxedStrategy<(Vec<i32>, Vec<i32>)> {
         file()
             .prop_perturb(|f, mut rng| {
                 let mut g = f.clone();
@@ -316,5 +297,13 @@ mod tests {
             let d = diff(&f, &g);
             assert_valid(&f, &g, &d);
         }
+
+        #[test]
+        fn test_valid_diff_all_algorithms((f, g) in two_files()) {
+            for &alg in &[Algorithm::Myers, Algorithm::Patience, Algorithm::Histogram] {
+                let d = diff_with_options(&f, &g, &DiffOptions::with_algorithm(alg));
+                assert_valid(&f, &g, &d);
+            }
+        }
     }
 }
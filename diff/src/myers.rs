@@ -0,0 +1,251 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// This is an implementation of the diff algorithm from Eugene Myers' paper "An O(ND) Difference
+// Algorithm and Its Variations". It finds a shortest edit script, and it does so in linear space by
+// using the "middle snake" divide-and-conquer strategy from section 4b of the paper.
+//
+// The paper thinks of the diff as a path through an edit graph, starting in the top-left corner
+// (0, 0) and ending in the bottom-right corner (n, m), where n and m are the lengths of the two
+// files. Moving right corresponds to deleting a line of the first file, moving down corresponds to
+// inserting a line of the second file, and (if the corresponding lines are equal) we can move
+// diagonally, which corresponds to keeping a line. A "snake" is a sequence of diagonal moves, and
+// the diagonal k is the set of points (x, y) with x - y = k.
+//
+// The main observation of the paper is that if V[k] is the furthest point along diagonal k that
+// can be reached with d non-diagonal moves, then V can be easily updated to find the furthest
+// points reachable with d + 1 non-diagonal moves. To find the middle snake, we run this search
+// both forwards from (0, 0) and backwards from (n, m) until the two searches meet.
+
+use crate::{diff_ends, match_ends, LineDiff};
+
+// The state of the search in one direction. `v[k + offset]` is the furthest x coordinate that we
+// have reached on diagonal `k`.
+struct Frontier {
+    v: Vec<isize>,
+    offset: isize,
+}
+
+impl Frontier {
+    fn new(max: isize) -> Frontier {
+        Frontier {
+            v: vec![0; 2 * max as usize + 3],
+            offset: max + 1,
+        }
+    }
+
+    fn get(&self, k: isize) -> isize {
+        self.v[(k + self.offset) as usize]
+    }
+
+    fn set(&mut self, k: isize, x: isize) {
+        self.v[(k + self.offset) as usize] = x;
+    }
+
+    // Returns the starting point for extending the search along diagonal `k`, given that the
+    // previous round of search used `d - 1` non-diagonal moves.
+    fn start(&self, k: isize, d: isize) -> isize {
+        if k == -d || (k != d && self.get(k - 1) < self.get(k + 1)) {
+            // Move down from diagonal k + 1.
+            self.get(k + 1)
+        } else {
+            // Move right from diagonal k - 1.
+            self.get(k - 1) + 1
+        }
+    }
+}
+
+// A snake, given by its start and end points.
+struct Snake {
+    x_start: usize,
+    y_start: usize,
+    x_end: usize,
+    y_end: usize,
+}
+
+// Finds the middle snake of a shortest path from (0, 0) to (a.len(), b.len()). Both `a` and `b`
+// must be non-empty, and they should have no common prefix or suffix.
+fn middle_snake<T: Eq>(a: &[T], b: &[T]) -> Snake {
+    let n = a.len() as isize;
+    let m = b.len() as isize;
+    let delta = n - m;
+    let odd = delta % 2 != 0;
+    let max = (n + m + 1) / 2;
+
+    // The backwards search is done in reversed coordinates, in which (n, m) is the origin. The
+    // point (x, y) in reversed coordinates is (n - x, m - y) in the original coordinates, and so
+    // the reversed diagonal k corresponds to the original diagonal delta - k.
+    let mut forward = Frontier::new(max);
+    let mut backward = Frontier::new(max);
+
+    for d in 0..=max {
+        let mut k = -d;
+        while k <= d {
+            let x_start = forward.start(k, d);
+            let y_start = x_start - k;
+            let (mut x, mut y) = (x_start, y_start);
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            forward.set(k, x);
+
+            // If delta is odd, the two searches can only meet after the forward search has taken
+            // its step.
+            if odd && (delta - k).abs() < d && x + backward.get(delta - k) >= n {
+                return Snake {
+                    x_start: x_start as usize,
+                    y_start: y_start as usize,
+                    x_end: x as usize,
+                    y_end: y as usize,
+                };
+            }
+            k += 2;
+        }
+
+        let mut k = -d;
+        while k <= d {
+            let x_start = backward.start(k, d);
+            let y_start = x_start - k;
+            let (mut x, mut y) = (x_start, y_start);
+            while x < n && y < m && a[(n - x - 1) as usize] == b[(m - y - 1) as usize] {
+                x += 1;
+                y += 1;
+            }
+            backward.set(k, x);
+
+            if !odd && (delta - k).abs() <= d && x + forward.get(delta - k) >= n {
+                return Snake {
+                    x_start: (n - x) as usize,
+                    y_start: (m - y) as usize,
+                    x_end: (n - x_start) as usize,
+                    y_end: (m - y_start) as usize,
+                };
+            }
+            k += 2;
+        }
+    }
+
+    // The two searches must meet after at most `max` steps each, since there is always a path
+    // with n + m non-diagonal moves.
+    unreachable!()
+}
+
+pub(crate) fn diff_rec<T: Eq>(
+    a: &[T],
+    a_offset: usize,
+    b: &[T],
+    b_offset: usize,
+    diff: &mut Vec<LineDiff>,
+) {
+    let (pref_len, a, b, suff_len) = match_ends(a, b);
+    for i in 0..pref_len {
+        diff.push(LineDiff::Keep(a_offset + i, b_offset + i));
+    }
+
+    let a_mid_offset = a_offset + pref_len;
+    let b_mid_offset = b_offset + pref_len;
+    if a.is_empty() || b.is_empty() {
+        diff_ends(a, a_mid_offset, b, b_mid_offset, diff);
+    } else {
+        let snake = middle_snake(a, b);
+        diff_rec(
+            &a[..snake.x_start],
+            a_mid_offset,
+            &b[..snake.y_start],
+            b_mid_offset,
+            diff,
+        );
+        for i in 0..(snake.x_end - snake.x_start) {
+            diff.push(LineDiff::Keep(
+                a_mid_offset + snake.x_start + i,
+                b_mid_offset + snake.y_start + i,
+            ));
+        }
+        diff_rec(
+            &a[snake.x_end..],
+            a_mid_offset + snake.x_end,
+            &b[snake.y_end..],
+            b_mid_offset + snake.y_end,
+            diff,
+        );
+    }
+
+    for i in 0..suff_len {
+        diff.push(LineDiff::Keep(
+            a_mid_offset + a.len() + i,
+            b_mid_offset + b.len() + i,
+        ));
+    }
+}
+
+/// Computes a diff between `a` and `b` using Myers' algorithm.
+///
+/// The resulting diff is as small as possible, in the sense that it keeps as many lines as
+/// possible.
+pub fn myers_diff<T: Eq>(a: &[T], b: &[T]) -> Vec<LineDiff> {
+    let mut ret = Vec::with_capacity(a.len().max(b.len()));
+    diff_rec(a, 0, b, 0, &mut ret);
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::tests::{assert_valid, two_files};
+    use crate::LineDiff::*;
+
+    // Computes the length of the longest common subsequence, using the standard dynamic
+    // programming algorithm.
+    fn lcs_len<T: Eq>(a: &[T], b: &[T]) -> usize {
+        let mut table = vec![vec![0; b.len() + 1]; a.len() + 1];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                table[i][j] = if a[i] == b[j] {
+                    table[i + 1][j + 1] + 1
+                } else {
+                    table[i + 1][j].max(table[i][j + 1])
+                };
+            }
+        }
+        table[0][0]
+    }
+
+    #[test]
+    fn paper_example() {
+        // This is the example from Myers' paper, which has an edit distance of 5.
+        let a = b"abcabba";
+        let b = b"cbabac";
+        let d = myers_diff(a, b);
+        assert_valid(a, b, &d);
+        assert_eq!(d.iter().filter(|x| matches!(x, Keep(..))).count(), 4);
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(myers_diff::<u8>(&[], &[]), vec![]);
+        assert_eq!(myers_diff(&[1], &[]), vec![Delete(0)]);
+        assert_eq!(myers_diff(&[], &[1]), vec![New(0)]);
+    }
+
+    proptest! {
+        #[test]
+        fn test_valid_myers_diff((f, g) in two_files()) {
+            let d = myers_diff(&f, &g);
+            assert_valid(&f, &g, &d);
+
+            let keeps = d.iter().filter(|x| matches!(x, Keep(..))).count();
+            assert_eq!(keeps, lcs_len(&f, &g));
+        }
+    }
+}
@@ -0,0 +1,139 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+use std::collections::hash_map::Entry;
+use std::hash::Hash;
+
+use crate::{line_counts, lis, match_ends, myers, LineDiff};
+
+// Returns the indices of the lines that appear exactly once in both files, and that can be
+// matched up with each other without crossing. The returned pairs are (a_idx, b_idx), sorted in
+// increasing order.
+fn unique_anchors<T: Hash + Eq>(a: &[T], b: &[T]) -> Vec<(usize, usize)> {
+    let a_line_counts = line_counts(a);
+    let mut b_line_counts = line_counts(b);
+    let a_unique = a_line_counts
+        .into_iter()
+        .filter(|(_, count)| *count == 1)
+        .map(|(line, _)| line);
+
+    // `both_unique` is a Vec of (usize, usize) pairs corresponding to lines that are unique in
+    // both files. The first usize is the index *in file b* and the second is the index in file a,
+    // and `both_unique` will be sorted according to the index in file a. The order of the indices
+    // seems backwards, but the point is that we'll look for a longest increasing subsequence and
+    // we want "increasing" here to mean according to appearance in file b.
+    let mut both_unique = a_unique
+        .filter_map(|a_line| {
+            // TODO: This is a bit awkward, but it can get better if HashMap::get_key_value is
+            // stabilized.
+            let a_idx = a_line.idx;
+            if let Entry::Occupied(entry) = b_line_counts.entry(a_line) {
+                if entry.get() == &1 {
+                    return Some((entry.key().idx, a_idx));
+                }
+            }
+            None
+        })
+        .collect::<Vec<(usize, usize)>>();
+    both_unique.sort_unstable_by_key(|(_b_idx, a_idx)| *a_idx);
+
+    lis::longest_increasing_subsequence(&both_unique)
+        .into_iter()
+        .map(|i| (both_unique[i].1, both_unique[i].0))
+        .collect()
+}
+
+fn diff_rec<T: Hash + Eq>(
+    a: &[T],
+    a_offset: usize,
+    b: &[T],
+    b_offset: usize,
+    diff: &mut Vec<LineDiff>,
+) {
+    let (pref_len, a_mid, b_mid, suff_len) = match_ends(a, b);
+    for i in 0..pref_len {
+        diff.push(LineDiff::Keep(a_offset + i, b_offset + i));
+    }
+
+    let a_mid_offset = a_offset + pref_len;
+    let b_mid_offset = b_offset + pref_len;
+    let anchors = unique_anchors(a_mid, b_mid);
+    if anchors.is_empty() {
+        // There's nothing for the patience algorithm to work with, so fall back to Myers.
+        myers::diff_rec(a_mid, a_mid_offset, b_mid, b_mid_offset, diff);
+    } else {
+        // Each anchor is at the beginning of the chunk following it, where it will be matched as
+        // part of the common prefix. This guarantees that all of the recursive calls are on
+        // strictly smaller inputs.
+        let mut prev_a_idx = 0;
+        let mut prev_b_idx = 0;
+        for (next_a_idx, next_b_idx) in anchors {
+            diff_rec(
+                &a_mid[prev_a_idx..next_a_idx],
+                a_mid_offset + prev_a_idx,
+                &b_mid[prev_b_idx..next_b_idx],
+                b_mid_offset + prev_b_idx,
+                diff,
+            );
+            prev_a_idx = next_a_idx;
+            prev_b_idx = next_b_idx;
+        }
+        diff_rec(
+            &a_mid[prev_a_idx..],
+            a_mid_offset + prev_a_idx,
+            &b_mid[prev_b_idx..],
+            b_mid_offset + prev_b_idx,
+            diff,
+        );
+    }
+
+    for i in 0..suff_len {
+        diff.push(LineDiff::Keep(
+            a_mid_offset + a_mid.len() + i,
+            b_mid_offset + b_mid.len() + i,
+        ));
+    }
+}
+
+pub fn patience_diff<T: Hash + Eq>(a: &[T], b: &[T]) -> Vec<LineDiff> {
+    let mut ret = Vec::with_capacity(a.len().max(b.len()));
+    diff_rec(a, 0, b, 0, &mut ret);
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LineDiff::*;
+
+    #[test]
+    fn moved_block() {
+        // One of the two blocks has to be deleted and re-added. The unique lines in the other
+        // block get matched up (along with the common "}" at the end).
+        let a = ["fn a() {", "x", "}", "fn b() {", "y", "}"];
+        let b = ["fn b() {", "y", "}", "fn a() {", "x", "}"];
+        let d = patience_diff(&a, &b);
+        assert_eq!(
+            d,
+            vec![
+                Delete(0),
+                Delete(1),
+                Delete(2),
+                Keep(3, 0),
+                Keep(4, 1),
+                New(2),
+                New(3),
+                New(4),
+                Keep(5, 5),
+            ]
+        );
+    }
+}
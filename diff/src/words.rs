@@ -0,0 +1,172 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+use std::ops::Range;
+
+use crate::{myers_diff, LineDiff};
+
+/// The result of diffing two lines word-by-word.
+///
+/// This is purely for presentation: when a line is replaced by a similar line, it's nice to be
+/// able to point out exactly which parts of it changed.
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct WordDiff {
+    /// The byte ranges of the old line that were removed. They are sorted and non-overlapping.
+    pub old_changed: Vec<Range<usize>>,
+    /// The byte ranges of the new line that were added. They are sorted and non-overlapping.
+    pub new_changed: Vec<Range<usize>>,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum CharClass {
+    Word,
+    Space,
+    Other,
+}
+
+fn class(b: u8) -> CharClass {
+    if b.is_ascii_alphanumeric() || b == b'_' || b >= 0x80 {
+        // We treat all non-ASCII bytes as word characters, so that we never split a multi-byte
+        // UTF-8 character.
+        CharClass::Word
+    } else if b.is_ascii_whitespace() {
+        CharClass::Space
+    } else {
+        CharClass::Other
+    }
+}
+
+// Splits a line into words, runs of whitespace, and individual punctuation characters, returning
+// the byte range of each piece.
+fn tokenize(line: &[u8]) -> Vec<Range<usize>> {
+    let mut ret = Vec::new();
+    let mut start = 0;
+    while start < line.len() {
+        let cls = class(line[start]);
+        let mut end = start + 1;
+        if cls != CharClass::Other {
+            while end < line.len() && class(line[end]) == cls {
+                end += 1;
+            }
+        }
+        ret.push(start..end);
+        start = end;
+    }
+    ret
+}
+
+// Adds a range to a sorted list of ranges, merging it with the last one if they touch.
+fn push_range(ranges: &mut Vec<Range<usize>>, range: Range<usize>) {
+    if let Some(last) = ranges.last_mut() {
+        if last.end == range.start {
+            last.end = range.end;
+            return;
+        }
+    }
+    ranges.push(range);
+}
+
+/// Compares two lines word-by-word, and returns the parts of them that differ.
+pub fn word_diff(old: &[u8], new: &[u8]) -> WordDiff {
+    let old_tokens = tokenize(old);
+    let new_tokens = tokenize(new);
+    let old_words = old_tokens
+        .iter()
+        .map(|r| &old[r.clone()])
+        .collect::<Vec<_>>();
+    let new_words = new_tokens
+        .iter()
+        .map(|r| &new[r.clone()])
+        .collect::<Vec<_>>();
+
+    let mut ret = WordDiff::default();
+    for d in myers_diff(&old_words, &new_words) {
+        match d {
+            LineDiff::Delete(i) => push_range(&mut ret.old_changed, old_tokens[i].clone()),
+            LineDiff::New(i) => push_range(&mut ret.new_changed, new_tokens[i].clone()),
+            LineDiff::Keep(..) => {}
+        }
+    }
+    ret
+}
+
+/// Finds pairs of lines that were (probably) modified versions of one another.
+///
+/// Whenever there is a block of deleted lines followed (or preceded) by a block of new lines,
+/// we pair up the lines in the order that they appear. The returned pairs are the line numbers
+/// in the first and second files respectively.
+pub fn changed_line_pairs(diff: &[LineDiff]) -> Vec<(usize, usize)> {
+    let mut ret = Vec::new();
+    let mut deleted = Vec::new();
+    let mut added = Vec::new();
+    let mut flush = |deleted: &mut Vec<usize>, added: &mut Vec<usize>| {
+        ret.extend(deleted.iter().cloned().zip(added.iter().cloned()));
+        deleted.clear();
+        added.clear();
+    };
+
+    for d in diff {
+        match *d {
+            LineDiff::Delete(i) => deleted.push(i),
+            LineDiff::New(i) => added.push(i),
+            LineDiff::Keep(..) => flush(&mut deleted, &mut added),
+        }
+    }
+    flush(&mut deleted, &mut added);
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens() {
+        let line = b"let x = foo(bar_baz);";
+        let words = tokenize(line)
+            .into_iter()
+            .map(|r| std::str::from_utf8(&line[r]).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            words,
+            vec!["let", " ", "x", " ", "=", " ", "foo", "(", "bar_baz", ")", ";"]
+        );
+    }
+
+    #[test]
+    fn changed_word() {
+        let d = word_diff(b"let x = foo(bar);", b"let x = foo(baz);");
+        assert_eq!(d.old_changed, vec![12..15]);
+        assert_eq!(d.new_changed, vec![12..15]);
+    }
+
+    #[test]
+    fn adjacent_words_merge() {
+        let d = word_diff(b"a b c", b"a x y z c");
+        assert_eq!(d.old_changed, vec![2..3]);
+        assert_eq!(d.new_changed, vec![2..7]);
+    }
+
+    #[test]
+    fn line_pairs() {
+        use crate::LineDiff::*;
+        let diff = [
+            Keep(0, 0),
+            Delete(1),
+            Delete(2),
+            New(1),
+            Keep(3, 2),
+            New(3),
+            Delete(4),
+        ];
+        assert_eq!(changed_line_pairs(&diff), vec![(1, 1), (4, 3)]);
+    }
+}
@@ -123,6 +123,66 @@ impl<'a, G: Graph + ?Sized> Iterator for Dfs<'a, G> {
     }
 }
 
+/// An iterator over the nodes of a graph in depth-first preorder (i.e. each node is returned when
+/// it is first discovered).
+///
+/// This is usually created by [`Graph::preorder`] or [`Graph::preorder_from`].
+pub struct Preorder<'a, G: Graph + ?Sized> {
+    dfs: Dfs<'a, G>,
+}
+
+impl<'a, G: Graph + ?Sized> Preorder<'a, G> {
+    pub(crate) fn new(dfs: Dfs<'a, G>) -> Preorder<'a, G> {
+        Preorder { dfs }
+    }
+}
+
+impl<'a, G: Graph + ?Sized> Iterator for Preorder<'a, G> {
+    type Item = G::Node;
+
+    fn next(&mut self) -> Option<G::Node> {
+        for visit in &mut self.dfs {
+            match visit {
+                Visit::Root(u) => return Some(u),
+                Visit::Edge {
+                    dst,
+                    status: Status::New,
+                    ..
+                } => return Some(dst),
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+/// An iterator over the nodes of a graph in depth-first postorder (i.e. each node is returned
+/// once all of its descendants have been returned).
+///
+/// This is usually created by [`Graph::postorder`] or [`Graph::postorder_from`].
+pub struct Postorder<'a, G: Graph + ?Sized> {
+    dfs: Dfs<'a, G>,
+}
+
+impl<'a, G: Graph + ?Sized> Postorder<'a, G> {
+    pub(crate) fn new(dfs: Dfs<'a, G>) -> Postorder<'a, G> {
+        Postorder { dfs }
+    }
+}
+
+impl<'a, G: Graph + ?Sized> Iterator for Postorder<'a, G> {
+    type Item = G::Node;
+
+    fn next(&mut self) -> Option<G::Node> {
+        for visit in &mut self.dfs {
+            if let Visit::Retreat { u, .. } = visit {
+                return Some(u);
+            }
+        }
+        None
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::Status::*;
@@ -177,6 +237,20 @@ mod tests {
         ]
     );
 
+    #[test]
+    fn preorder() {
+        let g = graph("0-1, 0-2, 1-2, 3-0");
+        assert_eq!(g.preorder().collect::<Vec<_>>(), vec![0, 1, 2, 3]);
+        assert_eq!(g.preorder_from(&1).collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn postorder() {
+        let g = graph("0-1, 0-2, 1-2, 3-0");
+        assert_eq!(g.postorder().collect::<Vec<_>>(), vec![2, 1, 0, 3]);
+        assert_eq!(g.postorder_from(&3).collect::<Vec<_>>(), vec![2, 1, 0, 3]);
+    }
+
     dfs_test!(
         repeat_visit,
         "0-1, 0-2, 1-2",
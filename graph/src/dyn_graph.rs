@@ -0,0 +1,100 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+use std::hash::Hash;
+
+use crate::{Edge, Graph};
+
+// `Graph` itself can't be made into a trait object, because several of its provided methods are
+// generic. This trait contains just the required methods of `Graph`, which are all object-safe.
+trait ErasedGraph<N, E> {
+    fn erased_nodes<'a>(&'a self) -> Box<dyn Iterator<Item = N> + 'a>;
+    fn erased_out_edges<'a>(&'a self, u: &N) -> Box<dyn Iterator<Item = E> + 'a>;
+    fn erased_in_edges<'a>(&'a self, u: &N) -> Box<dyn Iterator<Item = E> + 'a>;
+}
+
+impl<G: Graph> ErasedGraph<G::Node, G::Edge> for G {
+    fn erased_nodes<'a>(&'a self) -> Box<dyn Iterator<Item = G::Node> + 'a> {
+        self.nodes()
+    }
+
+    fn erased_out_edges<'a>(&'a self, u: &G::Node) -> Box<dyn Iterator<Item = G::Edge> + 'a> {
+        self.out_edges(u)
+    }
+
+    fn erased_in_edges<'a>(&'a self, u: &G::Node) -> Box<dyn Iterator<Item = G::Edge> + 'a> {
+        self.in_edges(u)
+    }
+}
+
+/// A graph whose concrete type has been erased.
+///
+/// Any two graphs with the same node and edge types can be turned into `DynGraph`s of the same
+/// type, which makes it possible to store different kinds of graphs (for example, a graph and a
+/// filtered view of some other graph) in the same collection.
+///
+/// The most convenient way to create one is with [`Graph::boxed`].
+pub struct DynGraph<'a, N, E> {
+    inner: Box<dyn ErasedGraph<N, E> + 'a>,
+}
+
+impl<'a, N, E> DynGraph<'a, N, E> {
+    /// Erases the type of `graph`.
+    pub fn new<G: Graph<Node = N, Edge = E> + 'a>(graph: G) -> DynGraph<'a, N, E> {
+        DynGraph {
+            inner: Box::new(graph),
+        }
+    }
+}
+
+impl<'a, N, E> Graph for DynGraph<'a, N, E>
+where
+    N: Copy + Eq + Hash,
+    E: Copy + Eq + Edge<N>,
+{
+    type Node = N;
+    type Edge = E;
+
+    fn nodes<'b>(&'b self) -> Box<dyn Iterator<Item = N> + 'b> {
+        self.inner.erased_nodes()
+    }
+
+    fn out_edges<'b>(&'b self, u: &N) -> Box<dyn Iterator<Item = E> + 'b> {
+        self.inner.erased_out_edges(u)
+    }
+
+    fn in_edges<'b>(&'b self, u: &N) -> Box<dyn Iterator<Item = E> + 'b> {
+        self.inner.erased_in_edges(u)
+    }
+}
+
+impl<'a, N, E> std::fmt::Debug for DynGraph<'a, N, E> {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt.debug_struct("DynGraph").finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::graph;
+
+    #[test]
+    fn heterogeneous() {
+        let g = graph("0-1, 1-2, 2-3");
+        let filtered = g.node_filtered(|u| *u != 3);
+        let graphs = [graph("0-1, 1-2, 2-3").boxed(), filtered.boxed()];
+
+        assert_eq!(graphs[0].top_sort(), Some(vec![0, 1, 2, 3]));
+        assert_eq!(graphs[1].top_sort(), Some(vec![0, 1, 2]));
+        assert_eq!(graphs[1].in_neighbors(&2).collect::<Vec<_>>(), vec![1]);
+    }
+}
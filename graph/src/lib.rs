@@ -14,14 +14,19 @@
 extern crate proptest;
 
 use itertools::Itertools;
-use std::collections::HashSet;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::hash::Hash;
 
 pub mod dfs;
+pub mod dyn_graph;
 pub mod partition;
+pub mod series_parallel;
 pub mod tarjan;
 
+pub use crate::dyn_graph::DynGraph;
 pub use crate::partition::Partition;
+pub use crate::series_parallel::SeriesParallel;
 
 pub trait Edge<N> {
     fn target(&self) -> N;
@@ -68,6 +73,28 @@ pub trait Graph {
         dfs::Dfs::new_from(self, root)
     }
 
+    /// Returns an iterator over all nodes of this graph, in depth-first preorder.
+    fn preorder<'a>(&'a self) -> dfs::Preorder<'a, Self> {
+        dfs::Preorder::new(self.dfs())
+    }
+
+    /// Returns an iterator over all nodes that are reachable from `root` (including `root`
+    /// itself), in depth-first preorder.
+    fn preorder_from<'a>(&'a self, root: &Self::Node) -> dfs::Preorder<'a, Self> {
+        dfs::Preorder::new(self.dfs_from(root))
+    }
+
+    /// Returns an iterator over all nodes of this graph, in depth-first postorder.
+    fn postorder<'a>(&'a self) -> dfs::Postorder<'a, Self> {
+        dfs::Postorder::new(self.dfs())
+    }
+
+    /// Returns an iterator over all nodes that are reachable from `root` (including `root`
+    /// itself), in depth-first postorder.
+    fn postorder_from<'a>(&'a self, root: &Self::Node) -> dfs::Postorder<'a, Self> {
+        dfs::Postorder::new(self.dfs_from(root))
+    }
+
     fn has_path(&self, u: &Self::Node, v: &Self::Node) -> bool {
         use self::dfs::Visit;
 
@@ -121,6 +148,19 @@ pub trait Graph {
         Doubled { graph: self }
     }
 
+    /// Returns the graph that has the same edges as this one, but pointing in the other direction.
+    fn reversed<'a>(&'a self) -> Reversed<'a, Self> {
+        Reversed { graph: self }
+    }
+
+    /// Erases the type of this graph, turning it into a [`DynGraph`].
+    fn boxed<'a>(self) -> DynGraph<'a, Self::Node, Self::Edge>
+    where
+        Self: Sized + 'a,
+    {
+        DynGraph::new(self)
+    }
+
     /// Returns the subgraph of this graph that is induced by the set of nodes for which
     /// `predicate` returns `true`.
     fn node_filtered<'a, F>(&'a self, predicate: F) -> NodeFiltered<'a, Self, F>
@@ -184,6 +224,50 @@ pub trait Graph {
         Some(top_sort)
     }
 
+    /// If this graph is acyclic, returns the lexicographically smallest topological sort of the
+    /// vertices. Otherwise, returns `None`.
+    ///
+    /// Unlike [`Graph::top_sort`], the result depends only on the graph and the ordering of the
+    /// nodes, and not on the order in which the nodes and edges happen to be stored.
+    fn top_sort_lexicographic(&self) -> Option<Vec<Self::Node>>
+    where
+        Self::Node: Ord,
+    {
+        // This is Kahn's algorithm: we repeatedly output a node with no remaining in-edges. By
+        // keeping the available nodes in a heap, we always choose the smallest one.
+        let mut in_degree: HashMap<Self::Node, usize> = self.nodes().map(|u| (u, 0)).collect();
+        for u in self.nodes() {
+            for v in self.out_neighbors(&u) {
+                *in_degree.get_mut(&v).expect("edge to an unknown node") += 1;
+            }
+        }
+
+        let mut available = in_degree
+            .iter()
+            .filter(|&(_, &deg)| deg == 0)
+            .map(|(u, _)| Reverse(*u))
+            .collect::<BinaryHeap<_>>();
+        let mut ret = Vec::with_capacity(in_degree.len());
+        while let Some(Reverse(u)) = available.pop() {
+            ret.push(u);
+            for v in self.out_neighbors(&u) {
+                // The unwrap is ok because we checked above that every neighbor is in the map.
+                let deg = in_degree.get_mut(&v).unwrap();
+                *deg -= 1;
+                if *deg == 0 {
+                    available.push(Reverse(v));
+                }
+            }
+        }
+
+        // If there was a cycle, the nodes on it never became available.
+        if ret.len() == in_degree.len() {
+            Some(ret)
+        } else {
+            None
+        }
+    }
+
     fn linear_order<'a>(&'a self) -> Option<Vec<Self::Node>> {
         if let Some(top) = self.top_sort() {
             // A graph has a linear order if and only if it has a unique topological sort. A
@@ -200,6 +284,18 @@ pub trait Graph {
         }
     }
 
+    /// If this graph is a series-parallel DAG, returns its decomposition into series and parallel
+    /// parts (see [`SeriesParallel`] for more details). Returns `None` if this graph has a cycle,
+    /// or if it isn't series-parallel.
+    fn series_parallel(&self) -> Option<SeriesParallel<Self::Node>> {
+        series_parallel::decompose(self)
+    }
+
+    /// Returns `true` if this graph is a series-parallel DAG.
+    fn is_series_parallel(&self) -> bool {
+        self.series_parallel().is_some()
+    }
+
     /// Returns the set of all nodes that are adjacent (either an in-neighbor or an out-neighbor)
     /// to something in `set`.
     fn neighbor_set<'a, I: Iterator<Item = &'a Self::Node>>(&self, set: I) -> HashSet<Self::Node>
@@ -320,6 +416,31 @@ where
     }
 }
 
+#[derive(Clone, Debug)]
+pub struct Reversed<'a, G: Graph + ?Sized> {
+    graph: &'a G,
+}
+
+impl<'a, G> Graph for Reversed<'a, G>
+where
+    G: Graph + ?Sized,
+{
+    type Node = G::Node;
+    type Edge = G::Edge;
+
+    fn nodes<'b>(&'b self) -> Box<dyn Iterator<Item = G::Node> + 'b> {
+        self.graph.nodes()
+    }
+
+    fn out_edges<'b>(&'b self, u: &Self::Node) -> Box<dyn Iterator<Item = G::Edge> + 'b> {
+        self.graph.in_edges(u)
+    }
+
+    fn in_edges<'b>(&'b self, u: &Self::Node) -> Box<dyn Iterator<Item = G::Edge> + 'b> {
+        self.graph.out_edges(u)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use proptest::prelude::*;
@@ -403,6 +524,17 @@ mod tests {
         };
     }
 
+    macro_rules! top_sort_lexicographic_test {
+        ($name:ident, $graph:expr, $expected:expr) => {
+            #[test]
+            fn $name() {
+                let g = graph($graph);
+                let top_sort = g.top_sort_lexicographic();
+                assert_eq!(top_sort, $expected);
+            }
+        };
+    }
+
     macro_rules! linear_order_test {
         ($name:ident, $graph:expr, $expected:expr) => {
             #[test]
@@ -418,6 +550,23 @@ mod tests {
     top_sort_test!(top_sort_cycle, "0-1, 1-2, 2-3, 3-1", None);
     top_sort_test!(top_sort_tree, "0-2, 2-3, 1-3", Some(vec![1, 0, 2, 3]));
 
+    top_sort_lexicographic_test!(
+        top_sort_lexicographic_chain,
+        "0-1, 1-3, 3-2",
+        Some(vec![0, 1, 3, 2])
+    );
+    top_sort_lexicographic_test!(top_sort_lexicographic_cycle, "0-1, 1-2, 2-3, 3-1", None);
+    top_sort_lexicographic_test!(
+        top_sort_lexicographic_tree,
+        "0-2, 2-3, 1-3",
+        Some(vec![0, 1, 2, 3])
+    );
+    top_sort_lexicographic_test!(
+        top_sort_lexicographic_diamond,
+        "3-2, 3-1, 2-0, 1-0, 3-4",
+        Some(vec![3, 1, 2, 0, 4])
+    );
+
     linear_order_test!(linear_order_chain, "0-1, 1-3, 3-2", Some(vec![0, 1, 3, 2]));
     linear_order_test!(
         linear_order_chain_with_extra,
@@ -492,6 +641,20 @@ mod tests {
             }
         }
 
+        #[test]
+        fn top_sort_lexicographic_proptest(ref g in arb_graph()) {
+            let lex = g.top_sort_lexicographic();
+            assert_eq!(lex.is_some(), g.top_sort().is_some());
+            if let Some(sort) = lex {
+                assert_eq!(sort.len(), g.nodes().count());
+                for i in 0..sort.len() {
+                    for j in (i+1)..sort.len() {
+                        assert!(!g.has_edge(sort[j], sort[i]));
+                    }
+                }
+            }
+        }
+
         #[test]
         fn doubled_proptest(ref g in arb_graph()) {
             let d = g.doubled();
@@ -513,6 +676,22 @@ mod tests {
             }
         }
 
+        #[test]
+        fn reversed_proptest(ref g in arb_graph()) {
+            let r = g.reversed();
+            for u in g.nodes() {
+                for v in g.out_neighbors(&u) {
+                    assert!(r.in_neighbors(&u).any(|x| x == v));
+                    assert!(r.out_neighbors(&v).any(|x| x == u));
+                }
+            }
+            for u in r.nodes() {
+                for v in r.out_neighbors(&u) {
+                    assert!(g.out_neighbors(&v).any(|x| x == u));
+                }
+            }
+        }
+
         #[test]
         fn weak_components_proptest(ref g in arb_graph()) {
             // This is not a complete test of the correctness of weak_components: it checks that
@@ -0,0 +1,254 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::Graph;
+
+/// The decomposition of a series-parallel DAG.
+///
+/// A DAG is series-parallel if the ordering that it induces on its nodes can be built up from
+/// single nodes using two operations: putting things one after the other ("series"), and putting
+/// things side by side with no ordering between them ("parallel"). These are exactly the DAGs
+/// that can be displayed as a file with (possibly nested) conflict markers: a series composition
+/// is a sequence of lines and conflicts, while a parallel composition is a conflict whose
+/// alternatives are its children.
+///
+/// The decomposition is canonical: the children of a `Series` are never themselves `Series`, and
+/// the children of a `Parallel` are never themselves `Parallel`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SeriesParallel<N> {
+    /// A single node.
+    Node(N),
+    /// A sequence of parts, each of which must come before the next one.
+    Series(Vec<SeriesParallel<N>>),
+    /// A collection of parts, with no ordering between them.
+    Parallel(Vec<SeriesParallel<N>>),
+}
+
+impl<N: Copy> SeriesParallel<N> {
+    /// Returns all of the nodes in this decomposition, in an order that is consistent with the
+    /// original graph.
+    pub fn nodes(&self) -> Vec<N> {
+        let mut ret = Vec::new();
+        self.collect_nodes(&mut ret);
+        ret
+    }
+
+    fn collect_nodes(&self, out: &mut Vec<N>) {
+        match self {
+            SeriesParallel::Node(u) => out.push(*u),
+            SeriesParallel::Series(parts) | SeriesParallel::Parallel(parts) => {
+                for p in parts {
+                    p.collect_nodes(out);
+                }
+            }
+        }
+    }
+}
+
+// We decompose the graph by looking at reachability between nodes. Since the regions that we
+// care about (conflicts in a file) are small, we just compute the full reachability relation.
+struct Decomposer<N> {
+    // The nodes, in topological order. Everywhere else, we refer to nodes by their index here.
+    nodes: Vec<N>,
+    // The position of each node in the graph's own iteration order. We use this to put the parts
+    // of a parallel composition in a predictable order.
+    position: Vec<usize>,
+    // reach[i] is the set of nodes that can be reached from node i by a non-empty path.
+    reach: Vec<HashSet<usize>>,
+}
+
+impl<N: Copy> Decomposer<N> {
+    fn comparable(&self, i: usize, j: usize) -> bool {
+        self.reach[i].contains(&j) || self.reach[j].contains(&i)
+    }
+
+    // Splits `set` into the connected components of the graph in which `i` and `j` are adjacent
+    // whenever `adjacent(i, j)` is true. Each component is sorted, and the components are sorted
+    // by their smallest elements (i.e. in topological order).
+    fn components<F: Fn(usize, usize) -> bool>(
+        &self,
+        set: &[usize],
+        adjacent: F,
+    ) -> Vec<Vec<usize>> {
+        let mut component_of: HashMap<usize, usize> = HashMap::new();
+        let mut ret = Vec::new();
+        for &start in set {
+            if component_of.contains_key(&start) {
+                continue;
+            }
+            let idx = ret.len();
+            let mut component = vec![start];
+            let mut stack = vec![start];
+            component_of.insert(start, idx);
+            while let Some(i) = stack.pop() {
+                for &j in set {
+                    if !component_of.contains_key(&j) && adjacent(i, j) {
+                        component_of.insert(j, idx);
+                        component.push(j);
+                        stack.push(j);
+                    }
+                }
+            }
+            component.sort();
+            ret.push(component);
+        }
+        ret
+    }
+
+    // `set` must be non-empty and sorted.
+    fn decompose(&self, set: &[usize]) -> Option<SeriesParallel<N>> {
+        if set.len() == 1 {
+            return Some(SeriesParallel::Node(self.nodes[set[0]]));
+        }
+
+        // If the comparability graph is disconnected, this is a parallel composition.
+        let parallel = self.components(set, |i, j| i != j && self.comparable(i, j));
+        if parallel.len() > 1 {
+            let mut parallel = parallel;
+            parallel.sort_by_key(|part| part.iter().map(|&i| self.position[i]).min());
+            let parts = parallel
+                .iter()
+                .map(|part| self.decompose(part))
+                .collect::<Option<Vec<_>>>()?;
+            return Some(SeriesParallel::Parallel(parts));
+        }
+
+        // If the incomparability graph is disconnected, this is a series composition. Every node
+        // in one component is comparable to every node in every other component, and so sorting
+        // the components by their first node puts them in the right order.
+        let series = self.components(set, |i, j| i != j && !self.comparable(i, j));
+        if series.len() > 1 {
+            let parts = series
+                .iter()
+                .map(|part| self.decompose(part))
+                .collect::<Option<Vec<_>>>()?;
+            return Some(SeriesParallel::Series(parts));
+        }
+
+        // Both the comparability and the incomparability graphs are connected, so this set cannot
+        // be decomposed.
+        None
+    }
+}
+
+pub(crate) fn decompose<G: Graph + ?Sized>(g: &G) -> Option<SeriesParallel<G::Node>> {
+    let nodes = g.top_sort()?;
+    if nodes.is_empty() {
+        return Some(SeriesParallel::Series(vec![]));
+    }
+
+    let index = nodes
+        .iter()
+        .enumerate()
+        .map(|(i, u)| (*u, i))
+        .collect::<HashMap<_, _>>();
+    let mut reach = vec![HashSet::new(); nodes.len()];
+    // Every out-neighbor comes later in the topological sort, so by going backwards we can build
+    // up the reachable sets from the ones that we have already computed.
+    for i in (0..nodes.len()).rev() {
+        let mut r = HashSet::new();
+        for v in g.out_neighbors(&nodes[i]) {
+            let j = index[&v];
+            r.insert(j);
+            r.extend(reach[j].iter().cloned());
+        }
+        reach[i] = r;
+    }
+
+    let position_of = g
+        .nodes()
+        .enumerate()
+        .map(|(i, u)| (u, i))
+        .collect::<HashMap<_, _>>();
+    let position = nodes.iter().map(|u| position_of[u]).collect();
+
+    let all = (0..nodes.len()).collect::<Vec<_>>();
+    Decomposer {
+        nodes,
+        position,
+        reach,
+    }
+    .decompose(&all)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SeriesParallel::*;
+    use super::*;
+    use crate::tests::{arb_dag, graph};
+
+    macro_rules! sp_test {
+        ($name:ident, $graph:expr, $expected:expr) => {
+            #[test]
+            fn $name() {
+                let g = graph($graph);
+                assert_eq!(g.series_parallel(), $expected);
+            }
+        };
+    }
+
+    sp_test!(
+        chain,
+        "0-1, 1-2",
+        Some(Series(vec![Node(0), Node(1), Node(2)]))
+    );
+    sp_test!(
+        diamond,
+        "0-1, 0-2, 1-3, 2-3",
+        Some(Series(vec![
+            Node(0),
+            Parallel(vec![Node(1), Node(2)]),
+            Node(3)
+        ]))
+    );
+    sp_test!(
+        nested,
+        "0-1, 1-2, 1-3, 2-5, 3-5, 0-4, 4-5",
+        Some(Series(vec![
+            Node(0),
+            Parallel(vec![
+                Series(vec![Node(1), Parallel(vec![Node(2), Node(3)])]),
+                Node(4)
+            ]),
+            Node(5)
+        ]))
+    );
+    sp_test!(
+        two_sources,
+        "0-2, 1-2",
+        Some(Series(vec![Parallel(vec![Node(0), Node(1)]), Node(2)]))
+    );
+    // The "N" shape is the smallest DAG that isn't series-parallel.
+    sp_test!(n_shape, "0-2, 1-2, 1-3", None);
+    sp_test!(cycle, "0-1, 1-2, 2-0", None);
+
+    proptest! {
+        #[test]
+        fn series_parallel_proptest(ref g in arb_dag()) {
+            if let Some(sp) = g.series_parallel() {
+                // Every node appears exactly once.
+                let nodes = sp.nodes();
+                let node_set = nodes.iter().cloned().collect::<HashSet<_>>();
+                assert_eq!(nodes.len(), node_set.len());
+                assert_eq!(node_set, g.nodes().collect::<HashSet<_>>());
+
+                // The order of the nodes is consistent with the graph.
+                for (i, u) in nodes.iter().enumerate() {
+                    for v in &nodes[..i] {
+                        assert!(!g.has_path(u, v));
+                    }
+                }
+            }
+        }
+    }
+}
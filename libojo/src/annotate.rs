@@ -0,0 +1,101 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+use crate::conflict::{regions, Region};
+use crate::{Error, Graggle, NodeId, PatchHeader, Repo};
+
+/// A line, together with where it came from (see [`Repo::annotate`]).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AnnotatedLine<'a> {
+    /// The line's id. Its `patch` field is the patch that introduced the line.
+    pub id: NodeId,
+    /// The contents of the line.
+    pub contents: &'a [u8],
+    /// The line's author (see [`PatchHeader::line_author`]).
+    pub author: &'a str,
+    /// The metadata of the patch that introduced the line.
+    pub header: &'a PatchHeader,
+}
+
+pub(crate) fn annotate<'a>(
+    repo: &'a Repo,
+    graggle: Graggle<'_>,
+) -> Result<Vec<AnnotatedLine<'a>>, Error> {
+    let mut ret = Vec::new();
+    for region in regions(graggle) {
+        let ids = match region {
+            Region::Line(id) => vec![id],
+            Region::Conflict(c) => c.lines().cloned().collect(),
+        };
+        for id in ids {
+            let header = repo.patch_header(&id.patch)?;
+            ret.push(AnnotatedLine {
+                id,
+                contents: repo.contents(&id),
+                author: header.line_author(id.node),
+                header,
+            });
+        }
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::create_as;
+    use crate::{Change, Changes, Repo};
+
+    #[test]
+    fn annotate() {
+        let mut repo = Repo::init_tmp();
+        let first = create_as(&mut repo, "master", "Alice", b"a\nb\n");
+        let second = create_as(&mut repo, "master", "Bob", b"a\nc\nb\n");
+
+        let lines = repo.annotate("master").unwrap();
+        let summary = lines
+            .iter()
+            .map(|l| (l.contents, l.author, l.id.patch))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (&b"a\n"[..], "Alice", first),
+                (&b"c\n"[..], "Bob", second),
+                (&b"b\n"[..], "Alice", first),
+            ]
+        );
+        assert_eq!(lines[1].header.author, "Bob");
+    }
+
+    #[test]
+    fn annotate_file() {
+        let mut repo = Repo::init_tmp();
+        let changes = Changes {
+            changes: vec![Change::NewFile {
+                path: "a.txt".to_owned(),
+            }],
+        };
+        let id = repo.create_patch("Alice", "Msg", changes).unwrap();
+        repo.apply_patch("master", &id).unwrap();
+        let file = repo.file_id("master", "a.txt").unwrap();
+        let diff = repo.diff_file("master", "a.txt", b"a\n").unwrap();
+        let mut changes = Changes { changes: vec![] };
+        changes.add_file_changes(file, diff.changes());
+        let id = repo.create_patch("Bob", "Msg", changes).unwrap();
+        repo.apply_patch("master", &id).unwrap();
+
+        let lines = repo.annotate_file("master", "a.txt").unwrap();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].author, "Bob");
+        assert_eq!(lines[0].id.patch, id);
+        assert!(repo.annotate_file("master", "b.txt").is_err());
+    }
+}
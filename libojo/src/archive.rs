@@ -0,0 +1,467 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Writing out the files on a branch as a tar or zip archive.
+//
+// Both formats are simple enough to write by hand. Tar archives are in the POSIX format, with an
+// extended header for the paths (and link targets) that don't fit in the usual one. Zip archives
+// are stored without compression, and don't use any of the extensions for huge archives.
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use chrono::{DateTime, Datelike, TimeZone, Timelike, Utc};
+use std::convert::TryFrom;
+use std::io::{self, Write};
+
+use crate::tracked::branch_paths;
+use crate::{Error, Repo, DEFAULT_WORKING_FILE};
+
+/// The format of an archive (see [`Repo::archive`]).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ArchiveFormat {
+    /// A POSIX tar archive (without any compression).
+    Tar,
+    /// A zip archive, in which the files are stored without compression.
+    Zip,
+}
+
+/// What to do about files with conflicts when making an archive (see [`Repo::archive`]).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ArchiveConflicts {
+    /// Fail with [`Error::HasConflicts`].
+    Refuse,
+    /// Archive the files with conflict markers (see [`Repo::render`]).
+    Markers,
+}
+
+// A file to go in an archive.
+struct Entry {
+    path: String,
+    contents: Vec<u8>,
+    executable: bool,
+    // If this is set, the file is a symbolic link and `contents` is its target.
+    symlink: bool,
+}
+
+impl Entry {
+    fn mode(&self) -> u32 {
+        if self.symlink {
+            0o777
+        } else if self.executable {
+            0o755
+        } else {
+            0o644
+        }
+    }
+}
+
+const TAR_BLOCK: usize = 512;
+
+// Writes a number into a field of a tar header, in octal and followed by a NUL.
+fn tar_number(field: &mut [u8], n: u64) {
+    let s = format!("{:0width$o}", n, width = field.len() - 1);
+    field[..s.len()].copy_from_slice(s.as_bytes());
+    field[s.len()] = 0;
+}
+
+// Writes a tar header. `name` and `link` need to fit in their fields.
+fn tar_header<W: Write>(
+    w: &mut W,
+    name: &[u8],
+    link: &[u8],
+    size: u64,
+    mode: u32,
+    mtime: i64,
+    kind: u8,
+) -> io::Result<()> {
+    let mut header = [0u8; TAR_BLOCK];
+    header[..name.len()].copy_from_slice(name);
+    tar_number(&mut header[100..108], mode.into());
+    tar_number(&mut header[108..116], 0);
+    tar_number(&mut header[116..124], 0);
+    tar_number(&mut header[124..136], size);
+    tar_number(&mut header[136..148], mtime.max(0) as u64);
+    header[156] = kind;
+    header[157..157 + link.len()].copy_from_slice(link);
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is computed with its own field filled with spaces.
+    header[148..156].copy_from_slice(b"        ");
+    let sum = header.iter().map(|&b| u64::from(b)).sum();
+    tar_number(&mut header[148..155], sum);
+    w.write_all(&header)
+}
+
+// Writes some data into a tar archive, padded to a whole number of blocks.
+fn tar_data<W: Write>(w: &mut W, data: &[u8]) -> io::Result<()> {
+    w.write_all(data)?;
+    let padding = (TAR_BLOCK - data.len() % TAR_BLOCK) % TAR_BLOCK;
+    w.write_all(&[0; TAR_BLOCK][..padding])
+}
+
+// A record in an extended tar header. The record starts with its own length.
+fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+    let mut ret = format!("{} {}=", len, key).into_bytes();
+    ret.extend_from_slice(value);
+    ret.push(b'\n');
+    ret
+}
+
+fn write_tar<W: Write>(mut w: W, entries: &[Entry], mtime: i64) -> io::Result<()> {
+    for e in entries {
+        let link = if e.symlink { &e.contents[..] } else { b"" };
+        let mut pax = Vec::new();
+        if e.path.len() > 100 {
+            pax.extend(pax_record("path", e.path.as_bytes()));
+        }
+        if link.len() > 100 {
+            pax.extend(pax_record("linkpath", link));
+        }
+        if !pax.is_empty() {
+            tar_header(
+                &mut w,
+                b"pax_header",
+                b"",
+                pax.len() as u64,
+                0o644,
+                mtime,
+                b'x',
+            )?;
+            tar_data(&mut w, &pax)?;
+        }
+
+        let name = &e.path.as_bytes()[..e.path.len().min(100)];
+        let link = &link[..link.len().min(100)];
+        if e.symlink {
+            tar_header(&mut w, name, link, 0, e.mode(), mtime, b'2')?;
+        } else {
+            let size = e.contents.len() as u64;
+            tar_header(&mut w, name, b"", size, e.mode(), mtime, b'0')?;
+            tar_data(&mut w, &e.contents)?;
+        }
+    }
+    w.write_all(&[0; 2 * TAR_BLOCK])
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= u32::from(b);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// Converts a time to the format used in zip files, which can't represent anything before 1980.
+fn dos_time(time: DateTime<Utc>) -> (u16, u16) {
+    let time = time.max(Utc.timestamp_opt(315_532_800, 0).unwrap());
+    let date =
+        ((time.year() - 1980) as u16) << 9 | (time.month() as u16) << 5 | (time.day() as u16);
+    let clock =
+        (time.hour() as u16) << 11 | (time.minute() as u16) << 5 | (time.second() as u16 / 2);
+    (clock, date)
+}
+
+fn too_big() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "too big for a zip archive")
+}
+
+fn write_zip<W: Write>(mut w: W, entries: &[Entry], mtime: DateTime<Utc>) -> io::Result<()> {
+    let (time, date) = dos_time(mtime);
+    // The paths are UTF-8.
+    let flags = 1 << 11;
+    let mut central = Vec::new();
+    let mut offset = 0u64;
+    for e in entries {
+        let crc = crc32(&e.contents);
+        let size = u32::try_from(e.contents.len()).map_err(|_| too_big())?;
+        let offset32 = u32::try_from(offset).map_err(|_| too_big())?;
+        let name = e.path.as_bytes();
+
+        w.write_u32::<LittleEndian>(0x0403_4b50)?;
+        w.write_u16::<LittleEndian>(10)?;
+        w.write_u16::<LittleEndian>(flags)?;
+        w.write_u16::<LittleEndian>(0)?;
+        w.write_u16::<LittleEndian>(time)?;
+        w.write_u16::<LittleEndian>(date)?;
+        w.write_u32::<LittleEndian>(crc)?;
+        w.write_u32::<LittleEndian>(size)?;
+        w.write_u32::<LittleEndian>(size)?;
+        w.write_u16::<LittleEndian>(name.len() as u16)?;
+        w.write_u16::<LittleEndian>(0)?;
+        w.write_all(name)?;
+        w.write_all(&e.contents)?;
+        offset += 30 + name.len() as u64 + u64::from(size);
+
+        // The entry in the central directory says that the file was made on unix, so that it can
+        // have unix permissions.
+        let kind = if e.symlink { 0o120_000 } else { 0o100_000 };
+        central.write_u32::<LittleEndian>(0x0201_4b50)?;
+        central.write_u16::<LittleEndian>(3 << 8 | 10)?;
+        central.write_u16::<LittleEndian>(10)?;
+        central.write_u16::<LittleEndian>(flags)?;
+        central.write_u16::<LittleEndian>(0)?;
+        central.write_u16::<LittleEndian>(time)?;
+        central.write_u16::<LittleEndian>(date)?;
+        central.write_u32::<LittleEndian>(crc)?;
+        central.write_u32::<LittleEndian>(size)?;
+        central.write_u32::<LittleEndian>(size)?;
+        central.write_u16::<LittleEndian>(name.len() as u16)?;
+        central.write_u16::<LittleEndian>(0)?;
+        central.write_u16::<LittleEndian>(0)?;
+        central.write_u16::<LittleEndian>(0)?;
+        central.write_u16::<LittleEndian>(0)?;
+        central.write_u32::<LittleEndian>((kind | e.mode()) << 16)?;
+        central.write_u32::<LittleEndian>(offset32)?;
+        central.write_all(name)?;
+    }
+
+    let count = u16::try_from(entries.len()).map_err(|_| too_big())?;
+    let central_len = u32::try_from(central.len()).map_err(|_| too_big())?;
+    let offset = u32::try_from(offset).map_err(|_| too_big())?;
+    w.write_all(&central)?;
+    w.write_u32::<LittleEndian>(0x0605_4b50)?;
+    w.write_u16::<LittleEndian>(0)?;
+    w.write_u16::<LittleEndian>(0)?;
+    w.write_u16::<LittleEndian>(count)?;
+    w.write_u16::<LittleEndian>(count)?;
+    w.write_u32::<LittleEndian>(central_len)?;
+    w.write_u32::<LittleEndian>(offset)?;
+    w.write_u16::<LittleEndian>(0)
+}
+
+pub(crate) fn archive<W: Write>(
+    repo: &Repo,
+    branch: &str,
+    format: ArchiveFormat,
+    conflicts: ArchiveConflicts,
+    w: W,
+) -> Result<(), Error> {
+    if conflicts == ArchiveConflicts::Refuse {
+        if let Some(path) = repo.conflicts(branch)?.keys().next() {
+            return Err(Error::HasConflicts(path.clone()));
+        }
+    }
+
+    let mut entries = Vec::new();
+    for path in branch_paths(repo, branch)? {
+        let mut contents = Vec::new();
+        let (executable, symlink) = if path == DEFAULT_WORKING_FILE {
+            repo.render(branch, &mut contents)?;
+            (false, false)
+        } else {
+            repo.render_file(branch, &path, &mut contents)?;
+            (
+                repo.is_executable(branch, &path)?,
+                repo.is_symlink(branch, &path)?,
+            )
+        };
+        entries.push(Entry {
+            path,
+            contents,
+            executable,
+            symlink,
+        });
+    }
+
+    // Every file gets the time of the newest patch, so that archiving the same files always gives
+    // the same archive.
+    let mtime = repo
+        .patches(branch)
+        .map(|p| repo.patch_header(p).map(|h| h.timestamp))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .max()
+        .unwrap_or_else(|| Utc.timestamp_opt(0, 0).unwrap());
+    match format {
+        ArchiveFormat::Tar => write_tar(w, &entries, mtime.timestamp())?,
+        ArchiveFormat::Zip => write_zip(w, &entries, mtime)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::create;
+    use crate::{Change, Changes, MemoryWorkingCopy, RecordOptions};
+    use byteorder::{ByteOrder, LittleEndian};
+
+    fn repo() -> Repo {
+        let wc = MemoryWorkingCopy::new();
+        let mut repo = Repo::init_in_memory(wc.clone());
+        let long = format!("{}/file.txt", "dir".repeat(40));
+        wc.write("a.txt", b"a\n");
+        wc.write("run.sh", b"#!/bin/sh\n");
+        wc.write(&long, b"long\n");
+        for path in &["a.txt", "run.sh", &long] {
+            repo.track_file(path).unwrap();
+        }
+        repo.record("Author", "Msg", &RecordOptions::default())
+            .unwrap();
+
+        let file = repo.file_ref("master", "run.sh").unwrap();
+        let changes = vec![Change::SetExecutable {
+            file,
+            executable: true,
+        }];
+        let id = repo
+            .create_patch("Author", "Msg", Changes { changes })
+            .unwrap();
+        repo.apply_patch("master", &id).unwrap();
+        repo
+    }
+
+    // Reads the names, modes and contents of the files in a tar archive.
+    fn read_tar(data: &[u8]) -> Vec<(String, u32, Vec<u8>)> {
+        let number = |field: &[u8]| {
+            let s = std::str::from_utf8(field).unwrap();
+            u64::from_str_radix(s.trim_end_matches('\0'), 8).unwrap()
+        };
+        let mut ret = Vec::new();
+        let mut pos = 0;
+        let mut long_path = None;
+        while data[pos..pos + TAR_BLOCK].iter().any(|&b| b != 0) {
+            let header = &data[pos..pos + TAR_BLOCK];
+            let mut sum = header.iter().map(|&b| u64::from(b)).sum::<u64>();
+            sum -= header[148..156].iter().map(|&b| u64::from(b)).sum::<u64>();
+            assert_eq!(number(&header[148..155]), sum + 8 * u64::from(b' '));
+
+            let size = number(&header[124..136]) as usize;
+            let contents = data[pos + TAR_BLOCK..pos + TAR_BLOCK + size].to_vec();
+            pos += TAR_BLOCK + size.div_ceil(TAR_BLOCK) * TAR_BLOCK;
+            if header[156] == b'x' {
+                let record = String::from_utf8(contents).unwrap();
+                long_path = Some(record.split_once("path=").unwrap().1.trim_end().to_owned());
+                continue;
+            }
+            let name = header[..100].split(|&b| b == 0).next().unwrap();
+            let name = long_path
+                .take()
+                .unwrap_or_else(|| String::from_utf8(name.to_vec()).unwrap());
+            ret.push((name, number(&header[100..108]) as u32, contents));
+        }
+        ret
+    }
+
+    #[test]
+    fn tar() {
+        let repo = repo();
+        let mut data = Vec::new();
+        repo.archive(
+            "master",
+            ArchiveFormat::Tar,
+            ArchiveConflicts::Refuse,
+            &mut data,
+        )
+        .unwrap();
+        assert_eq!(data.len() % TAR_BLOCK, 0);
+        let long = format!("{}/file.txt", "dir".repeat(40));
+        assert_eq!(
+            read_tar(&data),
+            vec![
+                ("a.txt".to_owned(), 0o644, b"a\n".to_vec()),
+                (long, 0o644, b"long\n".to_vec()),
+                ("run.sh".to_owned(), 0o755, b"#!/bin/sh\n".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn zip() {
+        let repo = repo();
+        let mut data = Vec::new();
+        repo.archive(
+            "master",
+            ArchiveFormat::Zip,
+            ArchiveConflicts::Refuse,
+            &mut data,
+        )
+        .unwrap();
+
+        // Read the files from the central directory.
+        let end = data.len() - 22;
+        assert_eq!(LittleEndian::read_u32(&data[end..]), 0x0605_4b50);
+        assert_eq!(LittleEndian::read_u16(&data[end + 10..]), 3);
+        let mut pos = LittleEndian::read_u32(&data[end + 16..]) as usize;
+        let mut files = Vec::new();
+        while pos < end {
+            assert_eq!(LittleEndian::read_u32(&data[pos..]), 0x0201_4b50);
+            let crc = LittleEndian::read_u32(&data[pos + 16..]);
+            let size = LittleEndian::read_u32(&data[pos + 24..]) as usize;
+            let name_len = LittleEndian::read_u16(&data[pos + 28..]) as usize;
+            let mode = LittleEndian::read_u32(&data[pos + 38..]) >> 16;
+            let offset = LittleEndian::read_u32(&data[pos + 42..]) as usize;
+            let name = String::from_utf8(data[pos + 46..pos + 46 + name_len].to_vec()).unwrap();
+            pos += 46 + name_len;
+
+            assert_eq!(LittleEndian::read_u32(&data[offset..]), 0x0403_4b50);
+            let start = offset + 30 + name_len;
+            let contents = data[start..start + size].to_vec();
+            assert_eq!(crc32(&contents), crc);
+            files.push((name, mode, contents));
+        }
+        assert_eq!(files.len(), 3);
+        assert_eq!(files[0], ("a.txt".to_owned(), 0o100_644, b"a\n".to_vec()));
+        assert_eq!(files[2].1, 0o100_755);
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn conflicts() {
+        let mut repo = Repo::init_tmp();
+        create(&mut repo, "master", b"a\n");
+        repo.clone_branch("master", "other").unwrap();
+        create(&mut repo, "master", b"a\nb\n");
+        let p = create(&mut repo, "other", b"a\nc\n");
+        repo.apply_patch("master", &p).unwrap();
+
+        let mut data = Vec::new();
+        match repo.archive(
+            "master",
+            ArchiveFormat::Tar,
+            ArchiveConflicts::Refuse,
+            &mut data,
+        ) {
+            Err(Error::HasConflicts(path)) => assert_eq!(path, DEFAULT_WORKING_FILE),
+            x => panic!("unexpected result {:?}", x),
+        }
+        assert!(data.is_empty());
+
+        repo.archive(
+            "master",
+            ArchiveFormat::Tar,
+            ArchiveConflicts::Markers,
+            &mut data,
+        )
+        .unwrap();
+        let files = read_tar(&data);
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].0, DEFAULT_WORKING_FILE);
+        assert!(files[0].2.starts_with(b"a\n<<<<<<<"));
+    }
+}
@@ -0,0 +1,306 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Bisecting: finding the patch on a branch that made it bad, by checking out sets of patches in
+// between a good set and the whole branch.
+//
+// The patches on a branch aren't in any particular order, so instead of cutting a sequence of
+// patches in half, we keep a set of patches that are known to be good, and a set of suspects (the
+// other patches on the branch). Both the good patches and the good patches together with the
+// suspects are closed under taking dependencies. Each step tests the good patches together with
+// the first half of an order in which the suspects can be applied: since every suspect comes after
+// the suspects that it depends on, that is closed under taking dependencies too. If the result is
+// bad, the culprit is one of the tested suspects; otherwise, they are all good.
+
+use std::collections::BTreeSet;
+
+use crate::{ApplyPolicy, Error, PatchId, Repo};
+
+// The name of the branch that holds the patches being tested. Branch names can't normally contain
+// NUL characters, so this can't clash with a real branch.
+const SCRATCH_BRANCH: &str = "\0bisect";
+
+// The branch that the next set of patches is put together on, before it replaces the scratch
+// branch.
+const NEXT_BRANCH: &str = "\0bisect-next";
+
+// The state of a bisection. It is kept in the storage, so that a bisection can last for more than
+// one run of ojo.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub(crate) struct Bisection {
+    // The branch that is being bisected.
+    branch: String,
+    // The branch that was checked out when the bisection started.
+    original_branch: String,
+    // The patches that are known to be good.
+    good: BTreeSet<PatchId>,
+    // The patches on `branch` that aren't known to be good.
+    suspects: BTreeSet<PatchId>,
+    // The suspects that are checked out (along with the good patches), in the order that they
+    // were applied.
+    testing: Vec<PatchId>,
+}
+
+/// The state of a bisection after a step (see [`Repo::bisect_start`]).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum BisectStep {
+    /// Some of the patches are checked out, and they need to be marked as good
+    /// ([`Repo::bisect_good`]) or bad ([`Repo::bisect_bad`]).
+    Testing {
+        /// The patches that might be the culprit and that are checked out, in the order that they
+        /// were applied. The working copy has these patches and the ones that are known to be
+        /// good.
+        patches: Vec<PatchId>,
+        /// The number of patches that might still be the culprit.
+        suspects: usize,
+    },
+    /// The culprit was found. The working copy has the culprit and the patches that are known to
+    /// be good.
+    Found(PatchId),
+}
+
+impl Bisection {
+    fn step(&self) -> BisectStep {
+        if self.suspects.len() == 1 {
+            BisectStep::Found(self.testing[0])
+        } else {
+            BisectStep::Testing {
+                patches: self.testing.clone(),
+                suspects: self.suspects.len(),
+            }
+        }
+    }
+}
+
+// Chooses the suspects to test next. If there is only one suspect, that's the culprit.
+fn choose(repo: &Repo, suspects: &BTreeSet<PatchId>) -> Vec<PatchId> {
+    let suspects = suspects.iter().cloned().collect::<Vec<_>>();
+    let mut ret = repo.patch_graph().apply_order(&suspects);
+    ret.truncate((suspects.len() / 2).max(1));
+    ret
+}
+
+// Checks out the good patches of a bisection, along with the suspects that are being tested.
+fn check_out(repo: &mut Repo, bisection: &Bisection) -> Result<(), Error> {
+    if repo.storage.inode(NEXT_BRANCH).is_some() {
+        repo.delete_branch(NEXT_BRANCH)?;
+    }
+    repo.create_branch(NEXT_BRANCH)?;
+    let patches = bisection
+        .good
+        .iter()
+        .chain(&bisection.testing)
+        .cloned()
+        .collect::<Vec<_>>();
+    for p in repo.patch_graph().apply_order(&patches) {
+        repo.apply_without_hooks(NEXT_BRANCH, &p, ApplyPolicy::Refuse)?;
+    }
+    repo.checkout(NEXT_BRANCH)?;
+
+    if repo.storage.inode(SCRATCH_BRANCH).is_some() {
+        repo.delete_branch(SCRATCH_BRANCH)?;
+    }
+    repo.clone_branch(NEXT_BRANCH, SCRATCH_BRANCH)?;
+    repo.current_branch = SCRATCH_BRANCH.to_owned();
+    repo.delete_branch(NEXT_BRANCH)
+}
+
+pub(crate) fn start(repo: &mut Repo, branch: &str, good: &[PatchId]) -> Result<BisectStep, Error> {
+    if repo.storage.bisection.is_some() {
+        return Err(Error::Bisecting);
+    }
+    repo.inode(branch)?;
+    let mut good_set = BTreeSet::new();
+    for p in good {
+        if !repo.storage.branch_patches.contains(branch, p) {
+            return Err(Error::NotOnBranch(*p, branch.to_owned()));
+        }
+        good_set.insert(*p);
+        good_set.extend(repo.patch_graph().transitive_deps(p));
+    }
+    let suspects = repo
+        .patches(branch)
+        .filter(|p| !good_set.contains(p))
+        .cloned()
+        .collect::<BTreeSet<_>>();
+    if suspects.is_empty() {
+        return Err(Error::NothingToBisect(branch.to_owned()));
+    }
+
+    let bisection = Bisection {
+        branch: branch.to_owned(),
+        original_branch: repo.current_branch.clone(),
+        good: good_set,
+        testing: choose(repo, &suspects),
+        suspects,
+    };
+    check_out_and_save(repo, bisection)
+}
+
+// Checks out the patches that a bisection is testing, and remembers the bisection.
+fn check_out_and_save(repo: &mut Repo, bisection: Bisection) -> Result<BisectStep, Error> {
+    repo.transaction(|repo| {
+        check_out(repo, &bisection)?;
+        let ret = bisection.step();
+        repo.storage.bisection = Some(bisection);
+        Ok(ret)
+    })
+}
+
+pub(crate) fn mark(repo: &mut Repo, good: bool) -> Result<BisectStep, Error> {
+    let mut bisection = repo.storage.bisection.clone().ok_or(Error::NoBisection)?;
+    if bisection.suspects.len() == 1 {
+        return Ok(bisection.step());
+    }
+
+    let testing = std::mem::take(&mut bisection.testing);
+    if good {
+        bisection.suspects.retain(|p| !testing.contains(p));
+        bisection.good.extend(testing);
+    } else {
+        bisection.suspects = testing.into_iter().collect();
+    }
+    bisection.testing = choose(repo, &bisection.suspects);
+    check_out_and_save(repo, bisection)
+}
+
+pub(crate) fn branch(repo: &Repo) -> Option<&str> {
+    repo.storage.bisection.as_ref().map(|b| b.branch.as_str())
+}
+
+pub(crate) fn reset(repo: &mut Repo) -> Result<(), Error> {
+    let bisection = repo.storage.bisection.clone().ok_or(Error::NoBisection)?;
+    repo.transaction(|repo| {
+        repo.checkout(&bisection.original_branch)?;
+        repo.delete_branch(SCRATCH_BRANCH)?;
+        repo.storage.bisection = None;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemoryWorkingCopy, RecordOptions, DEFAULT_WORKING_FILE};
+
+    fn record(repo: &mut Repo, wc: &MemoryWorkingCopy, contents: &[u8]) -> PatchId {
+        wc.write(DEFAULT_WORKING_FILE, contents);
+        repo.record("Author", "Msg", &RecordOptions::default())
+            .unwrap()
+    }
+
+    // Finishes a bisection, with the patches being bad whenever `bad` is checked out. Returns the
+    // culprit and the number of steps.
+    fn finish(repo: &mut Repo, mut step: BisectStep, bad: &PatchId) -> (PatchId, usize) {
+        let mut steps = 0;
+        loop {
+            match step {
+                BisectStep::Found(p) => return (p, steps),
+                BisectStep::Testing { .. } => {
+                    steps += 1;
+                    step = if repo.patches(SCRATCH_BRANCH).any(|p| p == bad) {
+                        repo.bisect_bad().unwrap()
+                    } else {
+                        repo.bisect_good().unwrap()
+                    };
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn linear() {
+        let wc = MemoryWorkingCopy::new();
+        let mut repo = Repo::init_in_memory(wc.clone());
+        let mut contents = Vec::new();
+        let mut patches = Vec::new();
+        for i in 0..16 {
+            contents.extend_from_slice(format!("{}\n", i).as_bytes());
+            patches.push(record(&mut repo, &wc, &contents));
+        }
+
+        for culprit in &patches[1..] {
+            let step = repo.bisect_start("master", &patches[..1]).unwrap();
+            let (found, steps) = finish(&mut repo, step, culprit);
+            assert_eq!(found, *culprit);
+            assert!(steps <= 4);
+            assert_eq!(repo.current_branch, SCRATCH_BRANCH);
+            repo.bisect_reset().unwrap();
+            assert_eq!(repo.current_branch, "master");
+            assert_eq!(repo.branches().collect::<Vec<_>>(), vec!["master"]);
+            assert_eq!(repo.bisecting(), None);
+        }
+        assert_eq!(wc.read(DEFAULT_WORKING_FILE).unwrap(), contents);
+    }
+
+    #[test]
+    fn independent() {
+        // Patches that don't depend on one another: each one adds a different file.
+        let wc = MemoryWorkingCopy::new();
+        let mut repo = Repo::init_in_memory(wc.clone());
+        let mut patches = Vec::new();
+        for i in 0..8 {
+            let path = format!("{}.txt", i);
+            wc.write(&path, b"a\n");
+            repo.track_file(&path).unwrap();
+            patches.push(
+                repo.record("Author", "Msg", &RecordOptions::default())
+                    .unwrap(),
+            );
+        }
+
+        let step = repo.bisect_start("master", &[]).unwrap();
+        match step {
+            BisectStep::Testing { suspects, .. } => assert_eq!(suspects, 8),
+            ref x => panic!("unexpected step {:?}", x),
+        }
+        let (found, steps) = finish(&mut repo, step, &patches[5]);
+        assert_eq!(found, patches[5]);
+        assert_eq!(steps, 3);
+        assert!(wc.read("5.txt").is_some());
+        repo.bisect_reset().unwrap();
+        assert_eq!(wc.paths().len(), 8);
+    }
+
+    #[test]
+    fn errors() {
+        let wc = MemoryWorkingCopy::new();
+        let mut repo = Repo::init_in_memory(wc.clone());
+        let a = record(&mut repo, &wc, b"a\n");
+        match repo.bisect_good() {
+            Err(Error::NoBisection) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+        match repo.bisect_start("master", &[a]) {
+            Err(Error::NothingToBisect(b)) => assert_eq!(b, "master"),
+            x => panic!("unexpected result {:?}", x),
+        }
+        record(&mut repo, &wc, b"a\nb\n");
+
+        // Unrecorded changes stop the bisection from starting.
+        wc.write(DEFAULT_WORKING_FILE, b"a\nc\n");
+        match repo.bisect_start("master", &[]) {
+            Err(Error::UnrecordedChanges(_)) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+        assert_eq!(repo.bisecting(), None);
+        assert_eq!(repo.branches().collect::<Vec<_>>(), vec!["master"]);
+        wc.write(DEFAULT_WORKING_FILE, b"a\nb\n");
+
+        repo.bisect_start("master", &[]).unwrap();
+        assert_eq!(repo.bisecting(), Some("master"));
+        match repo.bisect_start("master", &[]) {
+            Err(Error::Bisecting) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+        assert_eq!(wc.read(DEFAULT_WORKING_FILE).unwrap(), b"a\n");
+    }
+}
@@ -0,0 +1,323 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Binary contents don't fit into the line-based graggle, so they are tracked separately: a
+// `Change::BinaryReplace` (or a `Change::ChunkedReplace`, for large contents) replaces the whole
+// contents at once, and the repository stores the contents (indexed by their hash) in `Storage`.
+
+use sha2::{Digest, Sha256};
+
+use crate::error::PatchIdError;
+use crate::{Error, PatchId};
+
+/// The SHA256 hash of some binary contents.
+#[derive(Clone, Copy, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(transparent)]
+pub struct BlobHash {
+    #[serde(with = "crate::patch::patch_id_base64")]
+    data: [u8; 32],
+}
+
+impl std::fmt::Debug for BlobHash {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_tuple("BlobHash")
+            .field(&self.to_base64())
+            .finish()
+    }
+}
+
+impl BlobHash {
+    /// Computes the hash of some binary contents.
+    pub fn of(contents: &[u8]) -> BlobHash {
+        let mut data = [0; 32];
+        data.copy_from_slice(&Sha256::digest(contents)[..]);
+        BlobHash { data }
+    }
+
+    /// Represents this hash in (URL-safe) base64.
+    pub fn to_base64(&self) -> String {
+        base64::encode_config(&self.data[..], base64::URL_SAFE)
+    }
+
+    /// Converts from base64 (as returned by [`BlobHash::to_base64`]) to a `BlobHash`.
+    pub fn from_base64<S: ?Sized + AsRef<[u8]>>(s: &S) -> Result<BlobHash, Error> {
+        let data = base64::decode_config(s, base64::URL_SAFE).map_err(PatchIdError::from)?;
+        let mut ret = BlobHash { data: [0; 32] };
+        if data.len() != ret.data.len() {
+            Err(PatchIdError::InvalidLength(data.len()).into())
+        } else {
+            ret.data.copy_from_slice(&data);
+            Ok(ret)
+        }
+    }
+}
+
+/// Refers to the binary contents that were introduced by a particular patch.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct BlobRef {
+    /// The patch that introduced the contents (with a [`Change::BinaryReplace`](crate::Change)).
+    pub patch: PatchId,
+    /// The hash of the contents.
+    pub hash: BlobHash,
+}
+
+// Some binary contents, as the repository stores them.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(transparent)]
+pub(crate) struct Blob {
+    #[serde(with = "bytes_base64")]
+    pub data: Vec<u8>,
+}
+
+// Like `patch_id_base64`, but for arbitrary byte strings. Without this, YAML would represent binary
+// contents as a list of numbers, one per line.
+pub(crate) mod bytes_base64 {
+    pub fn serialize<S>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&base64::encode_config(bytes, base64::URL_SAFE))
+        } else {
+            // This needs to match the non-human-readable branch of `deserialize`.
+            serde::Serialize::serialize(bytes, serializer)
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        if deserializer.is_human_readable() {
+            let s = <String as serde::Deserialize>::deserialize(deserializer)?;
+            base64::decode_config(&s, base64::URL_SAFE).map_err(serde::de::Error::custom)
+        } else {
+            <Vec<u8> as serde::Deserialize>::deserialize(deserializer)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Change, Changes, Error, Patch, PatchHeader, Repo};
+
+    fn set_binary(repo: &mut Repo, branch: &str, contents: &[u8]) -> crate::PatchId {
+        let changes = repo.binary_changes(branch, contents.to_vec()).unwrap();
+        let id = repo.create_patch("Author", "Msg", changes).unwrap();
+        repo.apply_patch(branch, &id).unwrap();
+        id
+    }
+
+    #[test]
+    fn replace() {
+        let mut repo = Repo::init_tmp();
+        assert_eq!(repo.binary("master").unwrap().as_deref(), None);
+
+        let first = set_binary(&mut repo, "master", b"\x89PNG\0\xff\n");
+        assert_eq!(
+            repo.binary("master").unwrap().as_deref(),
+            Some(&b"\x89PNG\0\xff\n"[..])
+        );
+        let second = set_binary(&mut repo, "master", b"\0\0\0");
+        assert_eq!(
+            repo.binary("master").unwrap().as_deref(),
+            Some(&b"\0\0\0"[..])
+        );
+        assert_eq!(repo.patch_deps(&second).collect::<Vec<_>>(), vec![&first]);
+
+        repo.unapply_patch("master", &second).unwrap();
+        assert_eq!(
+            repo.binary("master").unwrap().as_deref(),
+            Some(&b"\x89PNG\0\xff\n"[..])
+        );
+        repo.unapply_patch("master", &first).unwrap();
+        assert_eq!(repo.binary("master").unwrap().as_deref(), None);
+    }
+
+    #[test]
+    fn conflict() {
+        let mut repo = Repo::init_tmp();
+        let base = set_binary(&mut repo, "master", b"base");
+        repo.clone_branch("master", "other").unwrap();
+        set_binary(&mut repo, "master", b"ours");
+        let theirs = set_binary(&mut repo, "other", b"theirs");
+
+        match repo.apply_patch("master", &theirs) {
+            Err(Error::BinaryConflict(p)) => assert_eq!(p, theirs),
+            x => panic!("expected a conflict, got {:?}", x),
+        }
+        assert_eq!(
+            repo.binary("master").unwrap().as_deref(),
+            Some(&b"ours"[..])
+        );
+        assert!(repo.patches("master").any(|p| p == &base));
+    }
+
+    #[test]
+    fn invalid_old_contents() {
+        let mut repo = Repo::init_tmp();
+        let first = set_binary(&mut repo, "master", b"first");
+        let changes = Changes {
+            changes: vec![Change::BinaryReplace {
+                old: Some(super::BlobRef {
+                    patch: first,
+                    hash: super::BlobHash::of(b"something else"),
+                }),
+                new_blob: b"second".to_vec(),
+            }],
+        };
+        match repo.create_patch("Author", "Msg", changes) {
+            Err(Error::UnknownBlob(_)) => {}
+            x => panic!("expected an error, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn compose() {
+        let mut repo = Repo::init_tmp();
+        let first = set_binary(&mut repo, "master", b"first");
+        let second = set_binary(&mut repo, "master", b"second");
+        let third = set_binary(&mut repo, "master", b"third");
+
+        let patches = [
+            repo.open_patch(&second).unwrap(),
+            repo.open_patch(&third).unwrap(),
+        ];
+        let header = PatchHeader::new("Author".to_owned(), "Composed".to_owned());
+        let composed = Patch::compose(&patches, header).unwrap();
+        let mut composed_data = Vec::new();
+        composed.write_out(&mut composed_data).unwrap();
+
+        let mut other_repo = Repo::init_tmp();
+        for data in &[repo.open_patch_data(&first).unwrap(), &composed_data[..]] {
+            let id = other_repo.register_patch(data).unwrap();
+            other_repo.apply_patch("master", &id).unwrap();
+        }
+        assert_eq!(
+            other_repo.binary("master").unwrap().as_deref(),
+            Some(&b"third"[..])
+        );
+    }
+
+    // Some bytes that are large enough to be chunked, and that don't repeat themselves.
+    fn large_contents() -> Vec<u8> {
+        let mut state = 7u64;
+        (0..(3 * crate::CHUNK_THRESHOLD))
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6_364_136_223_846_793_005)
+                    .wrapping_add(1_442_695_040_888_963_407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn chunked() {
+        let mut repo = Repo::init_tmp();
+        let first_contents = large_contents();
+        let first = set_binary(&mut repo, "master", &first_contents);
+
+        let mut second_contents = first_contents.clone();
+        second_contents.splice(1000..1000, b"inserted".iter().cloned());
+        let second = set_binary(&mut repo, "master", &second_contents);
+        let mut third_contents = second_contents.clone();
+        third_contents.truncate(2 * crate::CHUNK_THRESHOLD);
+        let third = set_binary(&mut repo, "master", &third_contents);
+        assert_eq!(
+            repo.binary("master").unwrap().as_deref(),
+            Some(&third_contents[..])
+        );
+        for id in &[second, third] {
+            let patch = repo.open_patch(id).unwrap();
+            assert!(matches!(
+                patch.changes().changes[0],
+                Change::ChunkedReplace { .. }
+            ));
+            assert!(repo.open_patch_data(id).unwrap().len() < crate::CHUNK_THRESHOLD);
+        }
+
+        // Another repository can put the contents together from the patches.
+        let mut other_repo = Repo::init_tmp();
+        for id in &[first, second, third] {
+            let id = other_repo
+                .register_patch(repo.open_patch_data(id).unwrap())
+                .unwrap();
+            other_repo.apply_patch("master", &id).unwrap();
+        }
+        assert_eq!(
+            other_repo.binary("master").unwrap().as_deref(),
+            Some(&third_contents[..])
+        );
+        other_repo.unapply_patch("master", &third).unwrap();
+        assert_eq!(
+            other_repo.binary("master").unwrap().as_deref(),
+            Some(&second_contents[..])
+        );
+
+        // Composing chunked replacements gives another chunked replacement.
+        let patches = [
+            repo.open_patch(&second).unwrap(),
+            repo.open_patch(&third).unwrap(),
+        ];
+        let header = PatchHeader::new("Author".to_owned(), "Composed".to_owned());
+        let composed = Patch::compose(&patches, header).unwrap();
+        let mut composed_data = Vec::new();
+        composed.write_out(&mut composed_data).unwrap();
+        let mut composed_repo = Repo::init_tmp();
+        let first = composed_repo
+            .register_patch(repo.open_patch_data(&first).unwrap())
+            .unwrap();
+        composed_repo.apply_patch("master", &first).unwrap();
+        let composed = composed_repo.register_patch(&composed_data).unwrap();
+        assert!(matches!(
+            composed_repo
+                .open_patch(&composed)
+                .unwrap()
+                .changes()
+                .changes[0],
+            Change::ChunkedReplace { .. }
+        ));
+        composed_repo.apply_patch("master", &composed).unwrap();
+        assert_eq!(
+            composed_repo.binary("master").unwrap().as_deref(),
+            Some(&third_contents[..])
+        );
+    }
+
+    #[test]
+    fn invalid_chunks() {
+        let mut repo = Repo::init_tmp();
+        let first = set_binary(&mut repo, "master", b"first");
+        let old = super::BlobRef {
+            patch: first,
+            hash: super::BlobHash::of(b"first"),
+        };
+        let chunked = |hash: &[u8], len| Changes {
+            changes: vec![Change::ChunkedReplace {
+                old,
+                hash: super::BlobHash::of(hash),
+                chunks: vec![crate::BlobChunk::Copy { start: 0, len }],
+            }],
+        };
+        for changes in vec![chunked(b"fir", 4), chunked(b"first!", 5), chunked(b"", 6)] {
+            match repo.create_patch("Author", "Msg", changes) {
+                Err(Error::InvalidChunks(_)) => {}
+                x => panic!("expected an error, got {:?}", x),
+            }
+        }
+        let id = repo
+            .create_patch("Author", "Msg", chunked(b"fir", 3))
+            .unwrap();
+        repo.apply_patch("master", &id).unwrap();
+        assert_eq!(repo.binary("master").unwrap().as_deref(), Some(&b"fir"[..]));
+    }
+}
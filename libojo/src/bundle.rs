@@ -0,0 +1,502 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::HashSet;
+use std::io::prelude::*;
+
+use crate::patch::binary::{read_section, write_section};
+use crate::{Error, ObsoleteMarker, Patch, PatchId, Repo};
+
+/// The bytes at the beginning of every bundle file.
+pub const BUNDLE_MAGIC: &[u8; 8] = b"OJOBUNDL";
+
+/// The current version of the bundle file format.
+///
+/// Version 2 added the obsolescence markers; bundles in version 1 can still be read.
+pub const BUNDLE_FORMAT_VERSION: u32 = 2;
+
+// The date on the line that starts each message of an mbox file. Like the one that `git
+// format-patch` writes, it's a fixed date that says nothing about the message.
+const MBOX_FROM_DATE: &str = "Mon Sep 17 00:00:00 2001";
+
+/// A collection of patches, packaged together with all of their dependencies.
+///
+/// Bundles are for moving patches between repositories: [`Bundle::create`] collects some patches
+/// from one repository, [`Bundle::write`] and [`Bundle::read`] convert the bundle to and from a
+/// file, and [`Bundle::unbundle`] adds the patches to another repository.
+///
+/// A bundle also contains every [`ObsoleteMarker`] whose successor is in the bundle, so that the
+/// receiving repository can replace the obsolete patches.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Bundle {
+    // The patches, in the format returned by `Repo::open_patch_data`. They are ordered so that
+    // every patch comes after all of its dependencies.
+    patches: Vec<(PatchId, Vec<u8>)>,
+    // Sorted.
+    markers: Vec<ObsoleteMarker>,
+}
+
+impl Bundle {
+    /// Creates a bundle containing the given patches, together with everything that they
+    /// (directly or indirectly) depend on.
+    pub fn create(repo: &Repo, ids: &[PatchId]) -> Result<Bundle, Error> {
+        let mut patches = Vec::new();
+        let mut seen = HashSet::new();
+
+        // Do a depth-first search through the dependencies. A patch gets added to the bundle when
+        // we retreat from it, so its dependencies are always added before it is.
+        for id in ids {
+            if !seen.insert(*id) {
+                continue;
+            }
+            let mut stack = vec![(*id, repo.patch_deps(id).cloned().collect::<Vec<_>>())];
+            while let Some((cur, deps)) = stack.last_mut() {
+                if let Some(dep) = deps.pop() {
+                    if seen.insert(dep) {
+                        let dep_deps = repo.patch_deps(&dep).cloned().collect();
+                        stack.push((dep, dep_deps));
+                    }
+                } else {
+                    patches.push((*cur, repo.open_patch_data(cur)?.to_owned()));
+                    stack.pop();
+                }
+            }
+        }
+
+        let mut markers = repo
+            .obsolete_markers()
+            .filter(|m| seen.contains(&m.successor))
+            .collect::<Vec<_>>();
+        markers.sort();
+        Ok(Bundle { patches, markers })
+    }
+
+    /// The ids of all the patches in this bundle.
+    ///
+    /// Every patch comes after all of its dependencies.
+    pub fn ids(&self) -> impl Iterator<Item = &PatchId> {
+        self.patches.iter().map(|(id, _)| id)
+    }
+
+    /// The obsolescence markers in this bundle, in sorted order.
+    pub fn markers(&self) -> &[ObsoleteMarker] {
+        &self.markers
+    }
+
+    /// Adds all of the patches in this bundle to a repository.
+    ///
+    /// This only registers the patches (as in [`Repo::register_patch`]) and the obsolescence
+    /// markers (as in [`Repo::mark_obsolete`]); it doesn't apply anything to any branch. Since the
+    /// patches came from somewhere else, they are marked as published (see
+    /// [`Repo::mark_published`]). Returns the ids of all the patches that weren't already in the
+    /// repository.
+    pub fn unbundle(&self, repo: &mut Repo) -> Result<Vec<PatchId>, Error> {
+        let mut ret = Vec::new();
+        for (id, data) in &self.patches {
+            if repo.open_patch_data(id).is_err() {
+                let new_id = repo.register_patch(data)?;
+                if new_id != *id {
+                    return Err(Error::IdMismatch(new_id, *id));
+                }
+                ret.push(new_id);
+            }
+            repo.mark_published(id)?;
+        }
+        for m in &self.markers {
+            repo.mark_obsolete(*m)?;
+        }
+        Ok(ret)
+    }
+
+    /// Writes out this bundle.
+    ///
+    /// A bundle file consists of the magic bytes [`BUNDLE_MAGIC`], the format version (as a
+    /// little-endian `u32`), and the number of patches (as a little-endian `u64`), followed by the
+    /// patches themselves. Each patch is prefixed by its length in bytes (as a little-endian
+    /// `u64`). Finally, there is the number of obsolescence markers (as a little-endian `u64`),
+    /// followed by the markers: each one is the 32 bytes of the obsolete patch's id, and then the
+    /// 32 bytes of its successor's id.
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<(), Error> {
+        writer.write_all(BUNDLE_MAGIC)?;
+        writer.write_u32::<LittleEndian>(BUNDLE_FORMAT_VERSION)?;
+        writer.write_u64::<LittleEndian>(self.patches.len() as u64)?;
+        for (_, data) in &self.patches {
+            write_section(&mut writer, data)?;
+        }
+        writer.write_u64::<LittleEndian>(self.markers.len() as u64)?;
+        for m in &self.markers {
+            writer.write_all(&m.obsolete.data)?;
+            writer.write_all(&m.successor.data)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a bundle that was written by [`Bundle::write`].
+    pub fn read<R: Read>(mut reader: R) -> Result<Bundle, Error> {
+        let mut magic = [0; 8];
+        reader
+            .read_exact(&mut magic)
+            .map_err(|_| Error::NotABundle)?;
+        if &magic != BUNDLE_MAGIC {
+            return Err(Error::NotABundle);
+        }
+        let version = reader.read_u32::<LittleEndian>()?;
+        if version != 1 && version != BUNDLE_FORMAT_VERSION {
+            return Err(Error::UnsupportedBundleVersion(version));
+        }
+
+        let count = reader.read_u64::<LittleEndian>()?;
+        let mut patches = Vec::new();
+        for _ in 0..count {
+            let data = read_section(&mut reader)?;
+            let id = *crate::Patch::from_reader(&data[..])?.id();
+            patches.push((id, data));
+        }
+
+        let mut markers = Vec::new();
+        if version >= 2 {
+            let count = reader.read_u64::<LittleEndian>()?;
+            for _ in 0..count {
+                let mut obsolete = PatchId { data: [0; 32] };
+                let mut successor = PatchId { data: [0; 32] };
+                reader.read_exact(&mut obsolete.data)?;
+                reader.read_exact(&mut successor.data)?;
+                markers.push(ObsoleteMarker {
+                    obsolete,
+                    successor,
+                });
+            }
+        }
+        Ok(Bundle { patches, markers })
+    }
+
+    /// Writes out this bundle as an mbox file, with one email message for each patch.
+    ///
+    /// This is for sending patches to a mailing list, like `git format-patch` does. Each message
+    /// is from the patch's author, its subject is the patch's summary, and its body is the patch
+    /// in the textual format (see [`Patch::to_text`]). The patch's id is in the `X-Ojo-Patch`
+    /// header, and there is an `X-Ojo-Depends` header for each of its dependencies and an
+    /// `X-Ojo-Obsoletes` header for each patch that it makes obsolete.
+    pub fn to_mbox(&self) -> Result<String, Error> {
+        let mut ret = String::new();
+        for (i, (id, data)) in self.patches.iter().enumerate() {
+            let patch = Patch::from_reader_with_id(&data[..], id)?;
+            let header = patch.header();
+            let id = id.to_base64();
+            ret.push_str(&format!("From {} {}\n", id, MBOX_FROM_DATE));
+            ret.push_str(&format!("From: {}\n", header.full_author()));
+            #[cfg(not(target_arch = "wasm32"))]
+            ret.push_str(&format!("Date: {}\n", header.timestamp.to_rfc2822()));
+            if self.patches.len() == 1 {
+                ret.push_str(&format!("Subject: [PATCH] {}\n", header.summary()));
+            } else {
+                ret.push_str(&format!(
+                    "Subject: [PATCH {}/{}] {}\n",
+                    i + 1,
+                    self.patches.len(),
+                    header.summary()
+                ));
+            }
+            ret.push_str(&format!("X-Ojo-Patch: {}\n", id));
+            for dep in patch.deps() {
+                ret.push_str(&format!("X-Ojo-Depends: {}\n", dep.to_base64()));
+            }
+            for m in self.markers.iter().filter(|m| m.successor == *patch.id()) {
+                ret.push_str(&format!("X-Ojo-Obsoletes: {}\n", m.obsolete.to_base64()));
+            }
+            ret.push_str("MIME-Version: 1.0\n");
+            ret.push_str("Content-Type: text/plain; charset=UTF-8\n");
+            ret.push_str("Content-Transfer-Encoding: 8bit\n\n");
+
+            // No line of the textual format starts with "From ", so the body doesn't need any
+            // quoting.
+            for line in patch.to_text().lines() {
+                ret.push_str(line);
+                ret.push('\n');
+            }
+            ret.push('\n');
+        }
+        Ok(ret)
+    }
+
+    /// Reads a bundle from an mbox file, like the ones written by [`Bundle::to_mbox`].
+    ///
+    /// Messages without an `X-Ojo-Patch` header (like replies, or a cover letter) are skipped. The
+    /// headers of the other messages are checked against the patches in their bodies, and their
+    /// `X-Ojo-Obsoletes` headers become obsolescence markers. The patches have to come after any
+    /// of their dependencies that are in the file, but (unlike in a bundle made by
+    /// [`Bundle::create`]) they don't all have to be there: someone applying patches from a
+    /// mailing list probably has the earlier ones already.
+    pub fn from_mbox(input: &str) -> Result<Bundle, Error> {
+        let lines = input
+            .lines()
+            .map(|l| l.trim_end_matches('\r'))
+            .collect::<Vec<_>>();
+        let syntax = |line: usize, msg: &str| Error::MboxSyntax(line, msg.to_owned());
+        let mut patches: Vec<(PatchId, Vec<u8>)> = Vec::new();
+        let mut all_deps = Vec::new();
+        let mut markers = Vec::new();
+
+        let mut pos = lines.iter().take_while(|l| l.is_empty()).count();
+        while pos < lines.len() {
+            if !lines[pos].starts_with("From ") {
+                return Err(syntax(pos + 1, "expected the start of a message"));
+            }
+            let start = pos + 1;
+            pos += 1;
+
+            // Read the headers, joining the ones that are folded over several lines. Each one is
+            // paired with its line number.
+            let mut headers: Vec<(usize, String)> = Vec::new();
+            while pos < lines.len() && !lines[pos].is_empty() {
+                let line = lines[pos];
+                if line.starts_with([' ', '\t']) {
+                    match headers.last_mut() {
+                        Some((_, h)) => h.push_str(line),
+                        None => return Err(syntax(pos + 1, "expected a header")),
+                    }
+                } else {
+                    headers.push((pos + 1, line.to_owned()));
+                }
+                pos += 1;
+            }
+
+            // The body is everything up to the next message. The blank line separating it from
+            // the headers is skipped, so its first line is `pos + 1`.
+            pos += 1;
+            let body_start = pos;
+            let mut body = String::new();
+            while pos < lines.len() && !lines[pos].starts_with("From ") {
+                body.push_str(lines[pos]);
+                body.push('\n');
+                pos += 1;
+            }
+
+            let mut id = None;
+            let mut deps = Vec::new();
+            let mut obsoletes = Vec::new();
+            for (line_num, h) in &headers {
+                let (name, value) = h
+                    .split_once(':')
+                    .ok_or_else(|| syntax(*line_num, "expected a header"))?;
+                let value = value.trim();
+                let parse_id = || {
+                    PatchId::from_base64(value)
+                        .map_err(|_| syntax(*line_num, &format!("invalid patch id {:?}", value)))
+                };
+                match name.trim().to_ascii_lowercase().as_str() {
+                    "x-ojo-patch" => id = Some(parse_id()?),
+                    "x-ojo-depends" => deps.push(parse_id()?),
+                    "x-ojo-obsoletes" => obsoletes.push(parse_id()?),
+                    "content-transfer-encoding" => {
+                        let enc = value.to_ascii_lowercase();
+                        if enc != "7bit" && enc != "8bit" && enc != "binary" {
+                            let msg = format!("unsupported transfer encoding {:?}", value);
+                            return Err(syntax(*line_num, &msg));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            let id = match id {
+                Some(id) => id,
+                None => continue,
+            };
+
+            let patch = Patch::from_text(&body).map_err(|e| match e {
+                Error::PatchSyntax(line, msg) => Error::MboxSyntax(body_start + line, msg),
+                e => e,
+            })?;
+            if *patch.id() != id {
+                return Err(Error::IdMismatch(*patch.id(), id));
+            }
+            let mut patch_deps = patch.deps().to_vec();
+            patch_deps.sort();
+            deps.sort();
+            if patch_deps != deps {
+                let msg = "the X-Ojo-Depends headers don't match the patch's dependencies";
+                return Err(syntax(start, msg));
+            }
+            if patches.iter().any(|(p, _)| *p == id) {
+                continue;
+            }
+            patches.push((id, patch.canonical_data()?.into_bytes()));
+            all_deps.push(deps);
+            markers.extend(obsoletes.into_iter().map(|obsolete| ObsoleteMarker {
+                obsolete,
+                successor: id,
+            }));
+        }
+
+        for (i, deps) in all_deps.iter().enumerate() {
+            if let Some(dep) = deps
+                .iter()
+                .find(|d| patches[i..].iter().any(|(p, _)| p == *d))
+            {
+                return Err(Error::DependencyOrder(patches[i].0, *dep));
+            }
+        }
+        markers.sort();
+        markers.dedup();
+        Ok(Bundle { patches, markers })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::create;
+
+    #[test]
+    fn round_trip() {
+        let mut repo = Repo::init_tmp();
+        let p1 = create(&mut repo, "master", b"a\n");
+        let p2 = create(&mut repo, "master", b"a\nb\n");
+        let p3 = create(&mut repo, "master", b"a\nb\nc\n");
+
+        let bundle = Bundle::create(&repo, &[p2]).unwrap();
+        assert_eq!(bundle.ids().cloned().collect::<Vec<_>>(), vec![p1, p2]);
+
+        let mut data = Vec::new();
+        bundle.write(&mut data).unwrap();
+        assert_eq!(Bundle::read(&data[..]).unwrap(), bundle);
+
+        let mut other_repo = Repo::init_tmp();
+        assert_eq!(bundle.unbundle(&mut other_repo).unwrap(), vec![p1, p2]);
+        assert!(other_repo.is_published(&p2));
+        assert!(!repo.is_published(&p2));
+        other_repo.apply_patch("master", &p2).unwrap();
+        assert_eq!(other_repo.file("master").unwrap().as_bytes(), b"a\nb\n");
+
+        // Unbundling a bundle with some already-known patches only adds the new ones.
+        let bundle = Bundle::create(&repo, &[p3, p1]).unwrap();
+        assert_eq!(bundle.ids().cloned().collect::<Vec<_>>(), vec![p1, p2, p3]);
+        assert_eq!(bundle.unbundle(&mut other_repo).unwrap(), vec![p3]);
+    }
+
+    #[test]
+    fn version_1() {
+        // Bundles from before the obsolescence markers existed.
+        let bundle = Bundle::read(&b"OJOBUNDL\x01\0\0\0\0\0\0\0\0\0\0\0"[..]).unwrap();
+        assert_eq!(bundle.ids().count(), 0);
+        assert!(bundle.markers().is_empty());
+    }
+
+    #[test]
+    fn bad_magic() {
+        match Bundle::read(&b"OJOPATCH\x01\0\0\0"[..]) {
+            Err(Error::NotABundle) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn mbox_round_trip() {
+        let mut repo = Repo::init_tmp();
+        let p1 = create(&mut repo, "master", b"a\n");
+        let p2 = create(&mut repo, "master", b"a\nb\n");
+        let old = PatchId { data: [7; 32] };
+        repo.mark_obsolete(ObsoleteMarker {
+            obsolete: old,
+            successor: p2,
+        })
+        .unwrap();
+
+        let bundle = Bundle::create(&repo, &[p2]).unwrap();
+        let mbox = bundle.to_mbox().unwrap();
+        assert!(mbox.starts_with(&format!("From {} ", p1.to_base64())));
+        assert!(mbox.contains("\nFrom: Author\n"));
+        assert!(mbox.contains("\nSubject: [PATCH 2/2] Msg\n"));
+        assert!(mbox.contains(&format!("\nX-Ojo-Depends: {}\n", p1.to_base64())));
+        assert!(mbox.contains(&format!("\nX-Ojo-Obsoletes: {}\n", old.to_base64())));
+        assert_eq!(Bundle::from_mbox(&mbox).unwrap(), bundle);
+
+        // Mail clients might change the line endings.
+        let crlf = mbox.replace('\n', "\r\n");
+        assert_eq!(Bundle::from_mbox(&crlf).unwrap(), bundle);
+    }
+
+    #[test]
+    fn mbox_partial() {
+        let mut repo = Repo::init_tmp();
+        let p1 = create(&mut repo, "master", b"a\n");
+        let p2 = create(&mut repo, "master", b"a\nb\n");
+        let mbox = Bundle::create(&repo, &[p2]).unwrap().to_mbox().unwrap();
+        let split = mbox.rfind("\nFrom ").unwrap() + 1;
+        let (first, second) = mbox.split_at(split);
+
+        // Someone who has the first patch already only needs the second one. Messages that aren't
+        // patches get skipped.
+        let cover = "From alice Mon Sep 17 00:00:00 2001\nSubject: [PATCH 0/1] Hi\n\nHello\n\n";
+        let bundle = Bundle::from_mbox(&format!("{}{}", cover, second)).unwrap();
+        assert_eq!(bundle.ids().cloned().collect::<Vec<_>>(), vec![p2]);
+
+        let mut other_repo = Repo::init_tmp();
+        other_repo
+            .register_patch(repo.open_patch_data(&p1).unwrap())
+            .unwrap();
+        assert_eq!(bundle.unbundle(&mut other_repo).unwrap(), vec![p2]);
+
+        match Bundle::from_mbox(&format!("{}{}", second, first)) {
+            Err(Error::DependencyOrder(p, dep)) => assert_eq!((p, dep), (p2, p1)),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn mbox_errors() {
+        let mut repo = Repo::init_tmp();
+        let p1 = create(&mut repo, "master", b"a\n");
+        let p2 = create(&mut repo, "master", b"a\nb\n");
+        let mbox = Bundle::create(&repo, &[p1]).unwrap().to_mbox().unwrap();
+
+        let wrong_id = mbox.replace(
+            &format!("X-Ojo-Patch: {}", p1.to_base64()),
+            &format!("X-Ojo-Patch: {}", p2.to_base64()),
+        );
+        match Bundle::from_mbox(&wrong_id) {
+            Err(Error::IdMismatch(actual, expected)) => assert_eq!((actual, expected), (p1, p2)),
+            x => panic!("unexpected result {:?}", x),
+        }
+
+        let wrong_deps = mbox.replace(
+            "X-Ojo-Patch:",
+            &format!("X-Ojo-Depends: {}\nX-Ojo-Patch:", p2.to_base64()),
+        );
+        match Bundle::from_mbox(&wrong_deps) {
+            Err(Error::MboxSyntax(1, _)) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+
+        let encoded = mbox.replace(": 8bit", ": quoted-printable");
+        match Bundle::from_mbox(&encoded) {
+            Err(Error::MboxSyntax(..)) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+
+        let garbled = mbox.replace("\nauthor: ", "\nauthr: ");
+        let line = garbled
+            .lines()
+            .position(|l| l.starts_with("authr"))
+            .unwrap()
+            + 1;
+        match Bundle::from_mbox(&garbled) {
+            Err(Error::MboxSyntax(l, _)) => assert_eq!(l, line),
+            x => panic!("unexpected result {:?}", x),
+        }
+
+        match Bundle::from_mbox("Subject: hi\n\nhello\n") {
+            Err(Error::MboxSyntax(1, _)) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+}
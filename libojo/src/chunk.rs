@@ -0,0 +1,282 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Large binary contents are split into chunks at content-defined boundaries, so that a small edit
+// only changes the chunks around it. The boundaries are found with a gear hash, as in FastCDC: a
+// chunk ends wherever the hash of the bytes just before it has some particular bits unset. Since
+// the hash only depends on the last 64 bytes, the boundaries after an edit are the same as before.
+//
+// Storage uses the chunks to store each of them only once, and recording uses them to find the
+// parts of the old contents that a `Change::ChunkedReplace` can reuse.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::BlobHash;
+
+/// Binary contents that are larger than this (in bytes) are split into chunks.
+pub const CHUNK_THRESHOLD: usize = 1 << 20;
+
+const MIN_CHUNK_SIZE: usize = 1 << 14;
+const AVG_CHUNK_SIZE: usize = 1 << 16;
+const MAX_CHUNK_SIZE: usize = 1 << 18;
+
+// Before the average chunk size, a boundary needs more bits to be unset than after it. This makes
+// the chunk sizes cluster around the average (FastCDC calls it "normalized chunking").
+const MASK_SMALL: u64 = !(!0 >> 18);
+const MASK_LARGE: u64 = !(!0 >> 14);
+
+// The random numbers that the gear hash adds for each byte. They come from a fixed seed, because
+// everyone needs to agree on the boundaries.
+static GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    // This is splitmix64.
+    let mut table = [0; 256];
+    let mut state = 0u64;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// A piece of some binary contents (see [`Change::ChunkedReplace`](crate::Change)).
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum BlobChunk {
+    /// Some new bytes.
+    New(#[serde(with = "crate::blob::bytes_base64")] Vec<u8>),
+    /// Some bytes that are copied from the replaced contents.
+    Copy {
+        /// The offset of the first copied byte.
+        start: u64,
+        /// The number of copied bytes.
+        len: u64,
+    },
+}
+
+impl BlobChunk {
+    /// The number of bytes in this piece.
+    pub fn len(&self) -> u64 {
+        match self {
+            BlobChunk::New(data) => data.len() as u64,
+            BlobChunk::Copy { len, .. } => *len,
+        }
+    }
+
+    /// Is this piece empty?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Returns the length of the first chunk of `data`.
+fn cut_point(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK_SIZE {
+        return data.len();
+    }
+    let normal = data.len().min(AVG_CHUNK_SIZE);
+    let max = data.len().min(MAX_CHUNK_SIZE);
+    let mut hash = 0u64;
+    for (i, &b) in data.iter().enumerate().take(max).skip(MIN_CHUNK_SIZE) {
+        hash = (hash << 1).wrapping_add(GEAR[b as usize]);
+        let mask = if i < normal { MASK_SMALL } else { MASK_LARGE };
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+    max
+}
+
+// Splits `data` into chunks, returning their ranges.
+pub(crate) fn chunks(data: &[u8]) -> Vec<Range<usize>> {
+    let mut ret = Vec::new();
+    let mut start = 0;
+    while start < data.len() {
+        let end = start + cut_point(&data[start..]);
+        ret.push(start..end);
+        start = end;
+    }
+    ret
+}
+
+// Appends a piece to `pieces`, merging it with the last one if they are adjacent.
+fn push(pieces: &mut Vec<BlobChunk>, piece: BlobChunk) {
+    if piece.is_empty() {
+        return;
+    }
+    match (pieces.last_mut(), piece) {
+        (Some(BlobChunk::New(last)), BlobChunk::New(data)) => last.extend_from_slice(&data),
+        (Some(BlobChunk::Copy { start, len }), BlobChunk::Copy { start: s, len: l })
+            if *start + *len == s =>
+        {
+            *len += l
+        }
+        (_, piece) => pieces.push(piece),
+    }
+}
+
+// Describes `new` as a sequence of pieces, copying the chunks that it has in common with `old`.
+pub(crate) fn delta(old: &[u8], new: &[u8]) -> Vec<BlobChunk> {
+    let old_chunks = chunks(old)
+        .into_iter()
+        .map(|r| (BlobHash::of(&old[r.clone()]), r))
+        .collect::<HashMap<_, _>>();
+    let mut ret = Vec::new();
+    for r in chunks(new) {
+        let piece = match old_chunks.get(&BlobHash::of(&new[r.clone()])) {
+            Some(old_r) => BlobChunk::Copy {
+                start: old_r.start as u64,
+                len: old_r.len() as u64,
+            },
+            None => BlobChunk::New(new[r].to_vec()),
+        };
+        push(&mut ret, piece);
+    }
+    ret
+}
+
+// Puts together the contents described by `pieces`, or returns `None` if they copy something
+// that isn't in `old`.
+pub(crate) fn assemble(old: &[u8], pieces: &[BlobChunk]) -> Option<Vec<u8>> {
+    let mut ret = Vec::new();
+    for p in pieces {
+        match p {
+            BlobChunk::New(data) => ret.extend_from_slice(data),
+            BlobChunk::Copy { start, len } => {
+                let end = start.checked_add(*len)?;
+                if end > old.len() as u64 {
+                    return None;
+                }
+                ret.extend_from_slice(&old[(*start as usize)..(end as usize)]);
+            }
+        }
+    }
+    Some(ret)
+}
+
+// Given the pieces that describe some contents in terms of `old`, and the pieces that describe
+// some more contents in terms of those, describes the second contents in terms of `old`. Returns
+// `None` if `second` copies something that isn't in the first contents.
+pub(crate) fn compose(first: &[BlobChunk], second: &[BlobChunk]) -> Option<Vec<BlobChunk>> {
+    let mut ret = Vec::new();
+    for p in second {
+        let (mut start, len) = match p {
+            BlobChunk::New(_) => {
+                push(&mut ret, p.clone());
+                continue;
+            }
+            BlobChunk::Copy { start, len } => (*start, *len),
+        };
+        let end = start.checked_add(len)?;
+        // The offset of the current piece of `first` in the first contents.
+        let mut pos = 0;
+        for q in first {
+            let q_end = pos + q.len();
+            if start < q_end && start < end {
+                let take_end = end.min(q_end);
+                let (off, n) = (start - pos, take_end - start);
+                let piece = match q {
+                    BlobChunk::New(data) => {
+                        BlobChunk::New(data[(off as usize)..((off + n) as usize)].to_vec())
+                    }
+                    BlobChunk::Copy { start: s, .. } => BlobChunk::Copy {
+                        start: s + off,
+                        len: n,
+                    },
+                };
+                push(&mut ret, piece);
+                start = take_end;
+            }
+            pos = q_end;
+        }
+        if start < end {
+            return None;
+        }
+    }
+    Some(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Some bytes that don't repeat themselves.
+    fn random_bytes(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6_364_136_223_846_793_005)
+                    .wrapping_add(1_442_695_040_888_963_407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn chunk_sizes() {
+        let data = random_bytes(4 * CHUNK_THRESHOLD, 1);
+        let ranges = chunks(&data);
+        assert_eq!(ranges.first().unwrap().start, 0);
+        assert_eq!(ranges.last().unwrap().end, data.len());
+        for w in ranges.windows(2) {
+            assert_eq!(w[0].end, w[1].start);
+        }
+        for r in &ranges[..(ranges.len() - 1)] {
+            assert!(r.len() >= MIN_CHUNK_SIZE && r.len() <= MAX_CHUNK_SIZE);
+        }
+    }
+
+    #[test]
+    fn small_edit() {
+        let old = random_bytes(2 * CHUNK_THRESHOLD, 2);
+        let mut new = old.clone();
+        new.splice(1000..1010, b"something new".iter().cloned());
+
+        let pieces = delta(&old, &new);
+        assert_eq!(assemble(&old, &pieces).unwrap(), new);
+        let new_bytes: u64 = pieces
+            .iter()
+            .filter(|p| matches!(p, BlobChunk::New(_)))
+            .map(|p| p.len())
+            .sum();
+        assert!(new_bytes <= 2 * MAX_CHUNK_SIZE as u64);
+    }
+
+    #[test]
+    fn compose_pieces() {
+        let first = vec![
+            BlobChunk::Copy { start: 10, len: 5 },
+            BlobChunk::New(b"abc".to_vec()),
+            BlobChunk::Copy { start: 0, len: 5 },
+        ];
+        let second = vec![
+            BlobChunk::Copy { start: 3, len: 4 },
+            BlobChunk::New(b"x".to_vec()),
+            BlobChunk::Copy { start: 9, len: 3 },
+        ];
+        let old = random_bytes(20, 3);
+        let first_contents = assemble(&old, &first).unwrap();
+        let second_contents = assemble(&first_contents, &second).unwrap();
+        let composed = compose(&first, &second).unwrap();
+        assert_eq!(assemble(&old, &composed).unwrap(), second_contents);
+
+        let too_long = [BlobChunk::Copy { start: 10, len: 5 }];
+        assert_eq!(compose(&first, &too_long), None);
+        assert_eq!(assemble(&first_contents, &too_long), None);
+    }
+}
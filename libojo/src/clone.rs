@@ -0,0 +1,157 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+use crate::progress::Reporter;
+use crate::{ApplyPolicy, Bundle, Error, PatchId, Phase, Repo};
+
+// Copies the patches on some branches of `src` (or on all of them, if `branch` is `None`) into
+// `dst`, which should be empty, and then rebuilds those branches in `dst`.
+pub(crate) fn copy(src: &Repo, dst: &mut Repo, branch: Option<&str>) -> Result<(), Error> {
+    let branches = match branch {
+        Some(b) => {
+            src.inode(b)?;
+            vec![b.to_owned()]
+        }
+        None => src.branches().map(|b| b.to_owned()).collect(),
+    };
+    let ids = match branch {
+        Some(b) => src.patches(b).cloned().collect::<Vec<_>>(),
+        None => src.all_patches().cloned().collect(),
+    };
+
+    // The bundle puts every patch after its dependencies, and unbundling checks that every
+    // patch's contents still match its id.
+    let bundle = Bundle::create(src, &ids)?;
+    bundle.unbundle(dst)?;
+    dst.config = src.config.clone();
+    dst.dictionary = src.dictionary.clone();
+
+    let total = branches
+        .iter()
+        .map(|b| src.patches(b).count())
+        .sum::<usize>();
+    let mut reporter = Reporter::start(dst, Phase::Clone, total);
+    for b in &branches {
+        if dst.storage.inode(b).is_none() {
+            dst.create_branch(b)?;
+        }
+        let on_branch = bundle
+            .ids()
+            .filter(|id| src.storage.branch_patches.contains(b, id))
+            .cloned()
+            .collect::<Vec<PatchId>>();
+        for id in on_branch {
+            dst.apply(b, &id, ApplyPolicy::Refuse)?;
+            reporter.patch(dst, &id);
+        }
+    }
+    reporter.finish();
+
+    dst.current_branch = match branch {
+        Some(b) => b.to_owned(),
+        None => src.current_branch.clone(),
+    };
+    if !branches.iter().any(|b| b == "master") {
+        dst.delete_branch("master")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{create, tmp_dir};
+    use std::fs;
+
+    // Creates a repository on disk with two branches, and returns it along with a path for
+    // cloning it to.
+    fn setup(name: &str) -> (Repo, std::path::PathBuf) {
+        let src = tmp_dir(&format!("{}-src", name));
+        let dst = tmp_dir(&format!("{}-dst", name));
+        let mut src = Repo::init(&src).unwrap();
+        create(&mut src, "master", b"a\n");
+        src.clone_branch("master", "other").unwrap();
+        create(&mut src, "other", b"a\nb\n");
+        src.switch_branch("other").unwrap();
+        src.write().unwrap();
+        (src, dst)
+    }
+
+    #[test]
+    fn clone() {
+        let (src, dst) = setup("clone");
+        let cloned = Repo::clone(&src.root_dir, &dst, None).unwrap();
+        assert_eq!(cloned.current_branch, "other");
+        assert_eq!(
+            cloned.branches().collect::<Vec<_>>(),
+            vec!["master", "other"]
+        );
+        assert_eq!(cloned.file("other").unwrap().as_bytes(), b"a\nb\n");
+        assert!(cloned.is_published(src.patches("other").next().unwrap()));
+
+        // The clone was written out.
+        let reopened = Repo::open(&dst).unwrap();
+        assert_eq!(reopened.file("master").unwrap().as_bytes(), b"a\n");
+        assert!(Repo::clone(&src.root_dir, &dst, None).is_err());
+        fs::remove_dir_all(&src.root_dir).unwrap();
+        fs::remove_dir_all(&dst).unwrap();
+    }
+
+    #[test]
+    fn clone_with_progress() {
+        use crate::{Phase, Progress, ProgressSink};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        #[derive(Default)]
+        struct Count(AtomicUsize);
+        impl ProgressSink for Count {
+            fn step(&self, p: &Progress<'_>) {
+                if p.phase == Phase::Clone {
+                    assert_eq!(p.total, 3);
+                    self.0.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        }
+
+        let (src, dst) = setup("clone-with-progress");
+        let count = Arc::new(Count::default());
+        Repo::clone_with_progress(&src.root_dir, &dst, None, count.clone()).unwrap();
+        assert_eq!(count.0.load(Ordering::SeqCst), 3);
+        fs::remove_dir_all(&src.root_dir).unwrap();
+        fs::remove_dir_all(&dst).unwrap();
+    }
+
+    #[test]
+    fn clone_branch() {
+        let (src, dst) = setup("clone-branch");
+        let cloned = Repo::clone(&src.root_dir, &dst, Some("master")).unwrap();
+        assert_eq!(cloned.branches().collect::<Vec<_>>(), vec!["master"]);
+        assert_eq!(cloned.all_patches().count(), 1);
+        assert!(Repo::clone(&src.root_dir, dst.join("x"), Some("missing")).is_err());
+        fs::remove_dir_all(&src.root_dir).unwrap();
+        fs::remove_dir_all(&dst).unwrap();
+    }
+
+    #[test]
+    fn corrupt_patch() {
+        let (mut src, dst) = setup("clone-corrupt");
+        let id = *src.patches("master").next().unwrap();
+        let data = src.storage.patches[&id].replace("Msg", "Changed");
+        src.storage.patches.insert(id, data);
+        let mut dst = Repo::init(&dst).unwrap();
+        match copy(&src, &mut dst, None) {
+            Err(Error::IdMismatch(..)) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+        fs::remove_dir_all(&src.root_dir).unwrap();
+    }
+}
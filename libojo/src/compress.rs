@@ -0,0 +1,254 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// On-disk compression of patches.
+//
+// In memory, we keep every patch as a YAML string (see `Storage::patches`). When the repository is
+// written out, each patch gets compressed separately with zstd, so that we can later decompress
+// them one at a time. Since the same lines tend to show up in many patches (a line that gets
+// added in one patch gets referred to by lots of others), we can optionally train a zstd
+// dictionary on the repository's patches and use it for compressing all of them. The compressed
+// patches can also be encrypted (see `encrypt`).
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{EncryptionKey, Error, PatchId};
+
+/// The compression level that is used if the repository config doesn't specify one.
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+
+// The compressed patches, as they are stored in the database. Since the database is YAML, the
+// compressed data is encoded in base64.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub(crate) struct PatchStore {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dictionary: Option<String>,
+    #[serde(default)]
+    patches: BTreeMap<PatchId, String>,
+    // If this is present, the patches (and the dictionary) are encrypted, and this contains the
+    // encrypted contents of the branches, which would otherwise be stored in the clear with the
+    // rest of the storage (see `Storage::contents_data`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sealed_contents: Option<String>,
+}
+
+const DICTIONARY_AAD: &[u8] = b"dictionary";
+const CONTENTS_AAD: &[u8] = b"contents";
+
+fn encode(data: &[u8]) -> String {
+    base64::encode(data)
+}
+
+fn decode(data: &str) -> Result<Vec<u8>, Error> {
+    base64::decode(data).map_err(|e| {
+        Error::Io(
+            std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+            "corrupt compressed patch".to_owned(),
+        )
+    })
+}
+
+impl PatchStore {
+    /// Compresses all of the given patches.
+    #[cfg(feature = "compression")]
+    pub fn compress(
+        patches: &HashMap<PatchId, String>,
+        level: i32,
+        dictionary: Option<&[u8]>,
+    ) -> Result<PatchStore, Error> {
+        let mut compressor = match dictionary {
+            Some(dict) => zstd::bulk::Compressor::with_dictionary(level, dict)?,
+            None => zstd::bulk::Compressor::new(level)?,
+        };
+        let mut ret = PatchStore {
+            dictionary: dictionary.map(encode),
+            patches: BTreeMap::new(),
+            sealed_contents: None,
+        };
+        for (id, data) in patches {
+            let compressed = compressor.compress(data.as_bytes())?;
+            ret.patches.insert(*id, encode(&compressed));
+        }
+        Ok(ret)
+    }
+
+    /// Encrypts the compressed patches and the dictionary, and stores `contents` (encrypted) along
+    /// with them.
+    pub fn encrypt(&mut self, key: &EncryptionKey, contents: &[u8]) -> Result<(), Error> {
+        for (id, data) in &mut self.patches {
+            *data = encode(&key.seal(&id.data, &decode(data)?));
+        }
+        if let Some(dict) = self.dictionary.as_mut() {
+            *dict = encode(&key.seal(DICTIONARY_AAD, &decode(dict)?));
+        }
+        self.sealed_contents = Some(encode(&key.seal(CONTENTS_AAD, contents)));
+        Ok(())
+    }
+
+    /// Returns true if the patches are encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        self.sealed_contents.is_some()
+    }
+
+    /// If the patches are encrypted, decrypts them (and the dictionary) and returns the contents
+    /// that were stored with them.
+    pub fn decrypt(&mut self, key: Option<&EncryptionKey>) -> Result<Option<Vec<u8>>, Error> {
+        let contents = match self.sealed_contents {
+            Some(ref c) => decode(c)?,
+            None => return Ok(None),
+        };
+        let key = key.ok_or(Error::MissingKey)?;
+        let contents = key.unseal(CONTENTS_AAD, &contents)?;
+        for (id, data) in &mut self.patches {
+            *data = encode(&key.unseal(&id.data, &decode(data)?)?);
+        }
+        if let Some(dict) = self.dictionary.as_mut() {
+            *dict = encode(&key.unseal(DICTIONARY_AAD, &decode(dict)?)?);
+        }
+        self.sealed_contents = None;
+        Ok(Some(contents))
+    }
+
+    /// Returns the dictionary that these patches were compressed with, if there was one.
+    pub fn dictionary(&self) -> Result<Option<Vec<u8>>, Error> {
+        self.dictionary.as_ref().map(|d| decode(d)).transpose()
+    }
+
+    /// Decompresses all of the patches.
+    #[cfg(feature = "compression")]
+    pub fn decompress(&self) -> Result<HashMap<PatchId, String>, Error> {
+        use std::io::Read;
+
+        let dictionary = self.dictionary()?.unwrap_or_default();
+        let mut ret = HashMap::new();
+        for (id, data) in &self.patches {
+            let compressed = decode(data)?;
+            let mut decoder = zstd::stream::Decoder::with_dictionary(&compressed[..], &dictionary)?;
+            let mut patch = String::new();
+            decoder.read_to_string(&mut patch)?;
+            ret.insert(*id, patch);
+        }
+        Ok(ret)
+    }
+
+    #[cfg(not(feature = "compression"))]
+    pub fn decompress(&self) -> Result<HashMap<PatchId, String>, Error> {
+        if self.patches.is_empty() {
+            Ok(HashMap::new())
+        } else {
+            Err(Error::Io(
+                std::io::Error::new(std::io::ErrorKind::Other, "compression is not supported"),
+                "this repository has compressed patches".to_owned(),
+            ))
+        }
+    }
+}
+
+/// Trains a zstd dictionary on a collection of patches.
+///
+/// zstd needs a reasonable number of samples to train on, and so this will fail if there are
+/// only a handful of patches.
+#[cfg(feature = "compression")]
+pub(crate) fn train_dictionary(
+    patches: &HashMap<PatchId, String>,
+    max_size: usize,
+) -> Result<Vec<u8>, Error> {
+    // Sort the samples, so that the trained dictionary doesn't depend on the hash map's order.
+    let mut samples = patches.iter().collect::<Vec<_>>();
+    samples.sort_by(|a, b| a.0.cmp(b.0));
+    let samples = samples
+        .into_iter()
+        .map(|(_, data)| data.as_bytes())
+        .collect::<Vec<_>>();
+    Ok(zstd::dict::from_samples(&samples, max_size)?)
+}
+
+#[cfg(all(test, feature = "compression"))]
+mod tests {
+    use super::*;
+
+    // Creates a bunch of patch-like strings with lots of shared content.
+    fn patches() -> HashMap<PatchId, String> {
+        (0..200u8)
+            .map(|i| {
+                let id = PatchId { data: [i; 32] };
+                let data = format!(
+                    "header:\n  author: Author {}\n  description: Patch number {}\nchanges:\n  - NewNode:\n      contents: \"line {} of the file\"\n",
+                    i % 7,
+                    i,
+                    i
+                );
+                (id, data)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let patches = patches();
+        let store = PatchStore::compress(&patches, DEFAULT_COMPRESSION_LEVEL, None).unwrap();
+        assert!(store.dictionary().unwrap().is_none());
+        assert_eq!(store.decompress().unwrap(), patches);
+    }
+
+    #[test]
+    fn round_trip_with_dictionary() {
+        let patches = patches();
+        let dict = train_dictionary(&patches, 4096).unwrap();
+        let store = PatchStore::compress(&patches, DEFAULT_COMPRESSION_LEVEL, Some(&dict)).unwrap();
+        assert_eq!(store.dictionary().unwrap(), Some(dict));
+
+        // The store survives a trip through YAML, which is how it's saved in the database.
+        let yaml = serde_yaml::to_string(&store).unwrap();
+        let store: PatchStore = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(store.decompress().unwrap(), patches);
+    }
+
+    #[test]
+    fn encrypted_round_trip() {
+        let patches = patches();
+        let key = EncryptionKey::from_bytes([1; 32]);
+        let dict = train_dictionary(&patches, 4096).unwrap();
+        let mut store =
+            PatchStore::compress(&patches, DEFAULT_COMPRESSION_LEVEL, Some(&dict)).unwrap();
+        store.encrypt(&key, b"contents").unwrap();
+        assert!(store.is_encrypted());
+        assert!(store.dictionary().unwrap() != Some(dict.clone()));
+
+        let yaml = serde_yaml::to_string(&store).unwrap();
+        let mut store: PatchStore = serde_yaml::from_str(&yaml).unwrap();
+        match store.decrypt(None) {
+            Err(Error::MissingKey) => {}
+            x => panic!("expected an error, got {:?}", x),
+        }
+
+        let mut store: PatchStore = serde_yaml::from_str(&yaml).unwrap();
+        let wrong_key = EncryptionKey::from_bytes([2; 32]);
+        assert!(store.decrypt(Some(&wrong_key)).is_err());
+
+        let mut store: PatchStore = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(store.decrypt(Some(&key)).unwrap().unwrap(), b"contents");
+        assert!(!store.is_encrypted());
+        assert_eq!(store.dictionary().unwrap(), Some(dict));
+        assert_eq!(store.decompress().unwrap(), patches);
+    }
+
+    #[test]
+    fn dictionary_helps() {
+        let patches = patches();
+        let dict = train_dictionary(&patches, 4096).unwrap();
+        let size = |store: &PatchStore| store.patches.values().map(|p| p.len()).sum::<usize>();
+        let plain = PatchStore::compress(&patches, DEFAULT_COMPRESSION_LEVEL, None).unwrap();
+        let with_dict =
+            PatchStore::compress(&patches, DEFAULT_COMPRESSION_LEVEL, Some(&dict)).unwrap();
+        assert!(size(&with_dict) < size(&plain));
+    }
+}
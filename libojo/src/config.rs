@@ -0,0 +1,472 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::compress::DEFAULT_COMPRESSION_LEVEL;
+use crate::hooks::HookPoint;
+use crate::ignore::matches_pattern;
+use crate::text::{looks_binary, Encoding, Newline, TextFormat, TextRule};
+use crate::{DiffAlgorithm, DiffOptions, Error};
+
+/// The default value of [`Config::rename_threshold`].
+pub const DEFAULT_RENAME_THRESHOLD: u32 = 50;
+
+/// The default value of [`Config::max_text_size`].
+pub const DEFAULT_MAX_TEXT_SIZE: u64 = 8 * 1024 * 1024;
+
+/// The name of the file, inside the `.ojo` directory, that holds a repository's own settings.
+pub const CONFIG_FILE: &str = "config.toml";
+
+/// Settings, as they are stored in a single configuration file.
+///
+/// There are three layers of settings: the system-wide ones (see [`Config::system_path`]), the
+/// ones of the current user (see [`Config::user_path`]) and the ones of the repository, which are
+/// saved along with the rest of the repository when [`Repo::write`](crate::Repo::write) is called.
+/// Each layer only needs to mention the settings that it changes, and the ones in later layers
+/// take precedence (see [`Config::merge`]). The settings that are actually in effect are returned
+/// by [`Repo::config`](crate::Repo::config); the typed accessors on this struct fill in the
+/// defaults for anything that none of the layers set.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default)]
+pub struct Config {
+    /// The name to record patches under, if none is given explicitly.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+
+    /// The email address to record patches under, if none is given explicitly.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+
+    /// The zstd compression level to use when storing patches (see [`Config::compression_level`]).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression_level: Option<i32>,
+
+    /// When recording, a removed file and an added one are treated as a move (see
+    /// [`Config::rename_threshold`]) if this percentage of their lines are the same.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rename_threshold: Option<u32>,
+
+    /// The algorithm to use for computing diffs (see [`Config::diff_options`]).
+    #[serde(skip_serializing_if = "Option::is_none", with = "algorithm_name")]
+    pub diff_algorithm: Option<DiffAlgorithm>,
+
+    /// Patterns of files in the working copy to ignore, in the same format as the lines of
+    /// [`IGNORE_FILE`](crate::IGNORE_FILE). They apply in every directory, but the ignore files
+    /// take precedence over them.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ignore: Vec<String>,
+
+    /// The line endings of text files in the working copy. If this is set, text files are
+    /// recorded with LF line endings, whatever they have in the working copy (see
+    /// [`Config::text_format`]).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub newline: Option<Newline>,
+
+    /// The encoding of text files in the working copy. If this is set, UTF-16 files are recorded
+    /// as UTF-8 (see [`Config::text_format`]).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<Encoding>,
+
+    /// Files that are bigger than this many bytes are recorded as binary contents (see
+    /// [`Config::is_binary`]).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_text_size: Option<u64>,
+
+    /// Settings for particular files, which override [`Config::newline`] and [`Config::encoding`],
+    /// and say whether the files are binary. When several rules match a file, the later ones
+    /// take precedence.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub text: Vec<TextRule>,
+
+    /// Whether symbolic links in the working copy are recorded as links, and written out as
+    /// links. If this is off, links are written out as ordinary files containing their targets
+    /// (see [`Config::symlinks`]).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub symlinks: Option<bool>,
+
+    /// The other repositories that patches are exchanged with, by name (see
+    /// [`Repo::add_remote`](crate::Repo::add_remote)).
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub remotes: BTreeMap<String, Remote>,
+
+    /// Shell commands to run at each hook point (see [`HookPoint`]), in order. They run in the
+    /// root directory of the repository, and the operation is described to them by the
+    /// environment variables `OJO_HOOK`, `OJO_BRANCH`, `OJO_PATCH` (except before recording),
+    /// `OJO_AUTHOR`, `OJO_EMAIL` (if there is one) and `OJO_DESCRIPTION`. If one of the commands
+    /// that runs before an operation fails, the operation is cancelled.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", with = "hook_names")]
+    pub hooks: BTreeMap<HookPoint, Vec<String>>,
+}
+
+impl Config {
+    /// Reads the settings in a configuration file. If there is no such file, none of the
+    /// settings are set.
+    pub fn load(path: &Path) -> Result<Config, Error> {
+        let data = match fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(Error::Io(e, format!("Could not read {}", path.display()))),
+        };
+        Config::from_toml(&data, path)
+    }
+
+    // Parses the contents of the configuration file at `path`.
+    pub(crate) fn from_toml(data: &str, path: &Path) -> Result<Config, Error> {
+        toml::from_str(data).map_err(|e| Error::InvalidConfig(path.to_owned(), e.to_string()))
+    }
+
+    // Serializes the settings, to go in the configuration file at `path`.
+    pub(crate) fn to_toml(&self, path: &Path) -> Result<String, Error> {
+        toml::to_string(self).map_err(|e| Error::InvalidConfig(path.to_owned(), e.to_string()))
+    }
+
+    /// Writes the settings out to a configuration file, creating its directory if necessary.
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let data = self.to_toml(path)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|e| Error::Io(e, format!("Could not create {}", dir.display())))?;
+        }
+        fs::write(path, data)
+            .map_err(|e| Error::Io(e, format!("Could not write {}", path.display())))
+    }
+
+    /// The path of the file holding the system-wide settings, if there is one on this platform.
+    ///
+    /// This is `/etc/ojo/config.toml`, unless it is overridden by the `OJO_SYSTEM_CONFIG`
+    /// environment variable.
+    pub fn system_path() -> Option<PathBuf> {
+        if let Some(path) = std::env::var_os("OJO_SYSTEM_CONFIG") {
+            return Some(path.into());
+        }
+        if cfg!(unix) {
+            Some(PathBuf::from("/etc/ojo/config.toml"))
+        } else {
+            None
+        }
+    }
+
+    /// The path of the file holding the settings of the current user, if the user has a home
+    /// directory.
+    ///
+    /// This is `ojo/config.toml` in `$XDG_CONFIG_HOME` (or `~/.config` if that isn't set), or in
+    /// `%APPDATA%` on Windows. It can be overridden by the `OJO_USER_CONFIG` environment variable.
+    pub fn user_path() -> Option<PathBuf> {
+        let var = |name| std::env::var_os(name).filter(|v| !v.is_empty());
+        if let Some(path) = var("OJO_USER_CONFIG") {
+            return Some(path.into());
+        }
+        let dir = if cfg!(windows) {
+            var("APPDATA").map(PathBuf::from)
+        } else {
+            var("XDG_CONFIG_HOME")
+                .map(PathBuf::from)
+                .or_else(|| var("HOME").map(|home| Path::new(&home).join(".config")))
+        };
+        dir.map(|d| d.join("ojo").join("config.toml"))
+    }
+
+    /// Reads the system-wide settings and the ones of the current user, and merges them.
+    pub fn load_global() -> Result<Config, Error> {
+        let mut ret = Config::default();
+        for path in Config::system_path().iter().chain(&Config::user_path()) {
+            ret.merge(&Config::load(path)?);
+        }
+        Ok(ret)
+    }
+
+    /// Layers the settings in `over` on top of these ones.
+    ///
+    /// The settings that `over` sets replace the ones here, as do its remotes with the same
+    /// names. The ignore patterns, text rules and hook commands of `over` are added after the ones
+    /// here.
+    pub fn merge(&mut self, over: &Config) {
+        fn merge_opt<T: Clone>(base: &mut Option<T>, over: &Option<T>) {
+            if over.is_some() {
+                *base = over.clone();
+            }
+        }
+
+        merge_opt(&mut self.author, &over.author);
+        merge_opt(&mut self.email, &over.email);
+        merge_opt(&mut self.compression_level, &over.compression_level);
+        merge_opt(&mut self.rename_threshold, &over.rename_threshold);
+        merge_opt(&mut self.diff_algorithm, &over.diff_algorithm);
+        merge_opt(&mut self.newline, &over.newline);
+        merge_opt(&mut self.encoding, &over.encoding);
+        merge_opt(&mut self.max_text_size, &over.max_text_size);
+        merge_opt(&mut self.symlinks, &over.symlinks);
+        self.ignore.extend(over.ignore.iter().cloned());
+        self.text.extend(over.text.iter().cloned());
+        self.remotes
+            .extend(over.remotes.iter().map(|(k, v)| (k.clone(), v.clone())));
+        for (point, commands) in &over.hooks {
+            self.hooks
+                .entry(*point)
+                .or_default()
+                .extend(commands.iter().cloned());
+        }
+    }
+
+    /// The name to record patches under, if one is set.
+    pub fn author(&self) -> Option<&str> {
+        self.author.as_deref()
+    }
+
+    /// The email address to record patches under, if one is set.
+    pub fn email(&self) -> Option<&str> {
+        self.email.as_deref()
+    }
+
+    /// The zstd compression level to use when storing patches.
+    ///
+    /// Higher levels give smaller repositories, at the cost of slower writes. Negative levels are
+    /// faster than any of the positive ones. The default is [`DEFAULT_COMPRESSION_LEVEL`].
+    pub fn compression_level(&self) -> i32 {
+        self.compression_level.unwrap_or(DEFAULT_COMPRESSION_LEVEL)
+    }
+
+    /// The percentage of lines that a removed file and an added one need to share in order for
+    /// [`Repo::record`](crate::Repo::record) to treat them as a move. Anything over 100 turns
+    /// this off. The default is [`DEFAULT_RENAME_THRESHOLD`].
+    pub fn rename_threshold(&self) -> u32 {
+        self.rename_threshold.unwrap_or(DEFAULT_RENAME_THRESHOLD)
+    }
+
+    /// The options to use for computing diffs, when none are given explicitly.
+    pub fn diff_options(&self) -> DiffOptions {
+        DiffOptions::with_algorithm(self.diff_algorithm.unwrap_or_default())
+    }
+
+    /// How the file at `path` (relative to the root of the working copy) is converted between
+    /// the working copy and the repository.
+    ///
+    /// By default, files are recorded exactly as they are. Setting [`Config::newline`] or
+    /// [`Config::encoding`] (or the ones in a matching rule in [`Config::text`]) makes text files
+    /// get recorded as UTF-8 with LF line endings, and written out to the working copy with the
+    /// line endings and encoding that are set.
+    pub fn text_format(&self, path: &str) -> TextFormat {
+        let mut ret = TextFormat {
+            newline: self.newline,
+            encoding: self.encoding,
+            binary: None,
+        };
+        for rule in self
+            .text
+            .iter()
+            .filter(|r| matches_pattern(&r.pattern, path))
+        {
+            ret.newline = rule.newline.or(ret.newline);
+            ret.encoding = rule.encoding.or(ret.encoding);
+            ret.binary = rule.binary.or(ret.binary);
+        }
+        ret
+    }
+
+    /// The size (in bytes) above which files are recorded as binary contents. The default is
+    /// [`DEFAULT_MAX_TEXT_SIZE`].
+    pub fn max_text_size(&self) -> u64 {
+        self.max_text_size.unwrap_or(DEFAULT_MAX_TEXT_SIZE)
+    }
+
+    /// Should the file at `path`, with these contents (in the form in which they would be
+    /// recorded), be recorded as binary contents instead of as lines?
+    ///
+    /// If a rule in [`Config::text`] says whether the file is binary, that's the answer.
+    /// Otherwise, the file is binary if it is bigger than [`Config::max_text_size`] or if its
+    /// contents look binary (see [`looks_binary`](crate::looks_binary)).
+    pub fn is_binary(&self, path: &str, contents: &[u8]) -> bool {
+        self.text_format(path).binary.unwrap_or_else(|| {
+            contents.len() as u64 > self.max_text_size() || looks_binary(contents)
+        })
+    }
+
+    /// Are symbolic links written out to the working copy as links? If not, the files that are
+    /// links in the repository are written out as ordinary files containing the links' targets,
+    /// and the files in the working copy are never recorded as links. The default is to use links
+    /// on the platforms where ojo can make them, which are the Unix-like ones.
+    pub fn symlinks(&self) -> bool {
+        self.symlinks.unwrap_or(cfg!(unix))
+    }
+
+    /// Returns the remote with the given name, if there is one.
+    pub fn remote(&self, name: &str) -> Option<&Remote> {
+        self.remotes.get(name)
+    }
+}
+
+// Diff algorithms are stored by their names, because the diff crate doesn't know about serde.
+mod algorithm_name {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    use crate::DiffAlgorithm;
+
+    pub fn serialize<S: Serializer>(alg: &Option<DiffAlgorithm>, s: S) -> Result<S::Ok, S::Error> {
+        match alg {
+            Some(DiffAlgorithm::Myers) => s.serialize_str("myers"),
+            Some(DiffAlgorithm::Patience) => s.serialize_str("patience"),
+            Some(DiffAlgorithm::Histogram) => s.serialize_str("histogram"),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<DiffAlgorithm>, D::Error> {
+        match String::deserialize(d)?.as_str() {
+            "myers" => Ok(Some(DiffAlgorithm::Myers)),
+            "patience" => Ok(Some(DiffAlgorithm::Patience)),
+            "histogram" => Ok(Some(DiffAlgorithm::Histogram)),
+            other => Err(de::Error::unknown_variant(
+                other,
+                &["myers", "patience", "histogram"],
+            )),
+        }
+    }
+}
+
+// TOML only allows strings as keys, so the hooks are indexed by the names of their hook points.
+mod hook_names {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+    use std::collections::BTreeMap;
+
+    use crate::HookPoint;
+
+    const POINTS: [HookPoint; 4] = [
+        HookPoint::PreRecord,
+        HookPoint::PostRecord,
+        HookPoint::PreApply,
+        HookPoint::PostApply,
+    ];
+
+    pub fn serialize<S: Serializer>(
+        hooks: &BTreeMap<HookPoint, Vec<String>>,
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        s.collect_map(hooks.iter().map(|(point, cmds)| (point.name(), cmds)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<BTreeMap<HookPoint, Vec<String>>, D::Error> {
+        let mut ret = BTreeMap::new();
+        for (name, cmds) in BTreeMap::<String, Vec<String>>::deserialize(d)? {
+            let point = POINTS.iter().find(|p| p.name() == name).ok_or_else(|| {
+                de::Error::unknown_variant(
+                    &name,
+                    &["pre-record", "post-record", "pre-apply", "post-apply"],
+                )
+            })?;
+            ret.insert(*point, cmds);
+        }
+        Ok(ret)
+    }
+}
+
+/// Another repository that patches are exchanged with.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Remote {
+    /// The address of the repository (see [`remote::connect`](crate::remote::connect)).
+    pub url: String,
+
+    /// The branches of the other repository that correspond to local branches, indexed by the
+    /// local branches. These are the branches that get fetched (see
+    /// [`Repo::fetch`](crate::Repo::fetch)); local branches that aren't in here are pushed to the
+    /// branch of the same name.
+    #[serde(default)]
+    pub branches: BTreeMap<String, String>,
+}
+
+impl Remote {
+    /// Creates a remote at `url`, whose "master" branch corresponds to the local one.
+    pub fn new(url: &str) -> Remote {
+        let mut branches = BTreeMap::new();
+        branches.insert("master".to_owned(), "master".to_owned());
+        Remote {
+            url: url.to_owned(),
+            branches,
+        }
+    }
+
+    /// Returns the branch of the other repository that corresponds to a local branch.
+    pub fn remote_branch<'a>(&'a self, branch: &'a str) -> &'a str {
+        self.branches.get(branch).map_or(branch, |b| b.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::tmp_dir;
+
+    #[test]
+    fn merge() {
+        let mut user = Config::default();
+        user.author = Some("Me".to_owned());
+        user.compression_level = Some(10);
+        user.ignore = vec!["*.o".to_owned()];
+        user.remotes.insert("origin".to_owned(), Remote::new("a:b"));
+
+        let mut repo = Config::default();
+        repo.compression_level = Some(1);
+        repo.ignore = vec!["*.tmp".to_owned()];
+        repo.remotes.insert("origin".to_owned(), Remote::new("c:d"));
+
+        let mut config = Config::default();
+        assert_eq!(config.author(), None);
+        assert_eq!(config.compression_level(), DEFAULT_COMPRESSION_LEVEL);
+        assert_eq!(config.rename_threshold(), DEFAULT_RENAME_THRESHOLD);
+
+        config.merge(&user);
+        config.merge(&repo);
+        assert_eq!(config.author(), Some("Me"));
+        assert_eq!(config.compression_level(), 1);
+        assert_eq!(config.ignore, vec!["*.o".to_owned(), "*.tmp".to_owned()]);
+        assert_eq!(config.remote("origin").unwrap().url, "c:d");
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut config = Config::default();
+        config.email = Some("me@example.com".to_owned());
+        config.diff_algorithm = Some(DiffAlgorithm::Histogram);
+        config.newline = Some(Newline::Native);
+        config.text.push(TextRule {
+            pattern: "*.txt".to_owned(),
+            newline: None,
+            encoding: Some(Encoding::Utf16Le),
+            binary: Some(true),
+        });
+        config
+            .remotes
+            .insert("origin".to_owned(), Remote::new("a:b"));
+        config
+            .hooks
+            .insert(HookPoint::PreRecord, vec!["true".to_owned()]);
+
+        let dir = tmp_dir("config");
+        let path = dir.join("config.toml");
+        config.save(&path).unwrap();
+        assert_eq!(Config::load(&path).unwrap(), config);
+        assert_eq!(
+            Config::load(&dir.join("missing.toml")).unwrap(),
+            Config::default()
+        );
+
+        fs::write(&path, "diff_algorithm = \"fastest\"\n").unwrap();
+        match Config::load(&path) {
+            Err(Error::InvalidConfig(..)) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
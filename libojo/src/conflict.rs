@@ -0,0 +1,582 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Finding the parts of a graggle that aren't totally ordered.
+//
+// We work on the strongly connected components of the graggle, in topological order. A line is
+// "settled" if it is a component by itself and it is comparable to every other line (i.e. every
+// line either comes before it or after it). Everything between two consecutive settled lines is a
+// conflict, which we split into alternatives (its weakly connected pieces).
+//
+// To find the settled lines efficiently, note that every line before the `i`th component in the
+// topological order comes before it if and only if every sink of the first `i` components has an
+// edge to it: since nothing after the `i`th component can lead back to it, a path from a sink
+// would have to start with such an edge.
+
+use ojo_graph::Graph;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use crate::storage::graggle::EdgeKind;
+use crate::{Change, Changes, Error, Graggle, NodeId, PatchId, Repo, DEFAULT_WORKING_FILE};
+
+/// A part of a graggle whose lines aren't totally ordered (see [`Graggle::conflicts`]).
+///
+/// The lines of a conflict are exactly the lines between [`Conflict::before`] and
+/// [`Conflict::after`]. They are divided into alternatives: there is no ordering at all between
+/// the lines of different alternatives. Usually, each alternative was introduced by a different
+/// patch, and the conflict is resolved by choosing an order for the alternatives (or by deleting
+/// some of them). If the lines of a single alternative aren't totally ordered either (for
+/// example, because there is a cycle), that alternative also needs to be resolved.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Conflict {
+    /// The last line before the conflict, or `None` if the conflict is at the beginning. This line
+    /// is ordered with respect to all the other lines in the graggle.
+    pub before: Option<NodeId>,
+    /// The first line after the conflict, or `None` if the conflict is at the end. This line is
+    /// ordered with respect to all the other lines in the graggle.
+    pub after: Option<NodeId>,
+    /// The alternatives, sorted by where they start in a topological sort of the graggle. The
+    /// lines of each alternative are in topological order, as far as they are ordered (lines in
+    /// a cycle are sorted by id).
+    pub alternatives: Vec<Vec<NodeId>>,
+    /// The patches involved in the conflict, in sorted order. These are the patches that
+    /// introduced its lines, and the patches that introduced the edges between them (or between
+    /// them and [`Conflict::before`] and [`Conflict::after`]).
+    pub patches: Vec<PatchId>,
+}
+
+impl Conflict {
+    /// Returns the patches that introduced the lines of an alternative, in the order that they
+    /// first appear.
+    pub fn alternative_patches(&self, alternative: usize) -> Vec<PatchId> {
+        let mut seen = HashSet::new();
+        self.alternatives[alternative]
+            .iter()
+            .map(|id| id.patch)
+            .filter(|p| seen.insert(*p))
+            .collect()
+    }
+
+    /// Returns all the lines of this conflict.
+    pub fn lines(&self) -> impl Iterator<Item = &NodeId> {
+        self.alternatives.iter().flat_map(|alt| alt.iter())
+    }
+}
+
+/// A conflict on a branch, along with the patches that caused it (see [`Repo::conflicts`]).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BranchConflict {
+    /// The conflict.
+    pub conflict: Conflict,
+    /// The patches whose combination caused the conflict, in sorted order.
+    ///
+    /// These are the patches in [`Conflict::patches`] that none of the others depend on. The other
+    /// patches in [`Conflict::patches`] are dependencies of these ones, so applying these patches
+    /// (along with their dependencies) is enough to get the conflict, and leaving out any one of
+    /// them usually avoids it.
+    pub causes: Vec<PatchId>,
+}
+
+// Returns the patches in `patches` that none of the others depend on, directly or indirectly.
+fn causes(repo: &Repo, patches: &[PatchId]) -> Vec<PatchId> {
+    let mut deps = HashSet::new();
+    let mut stack = patches
+        .iter()
+        .flat_map(|p| repo.patch_deps(p))
+        .cloned()
+        .collect::<Vec<_>>();
+    while let Some(p) = stack.pop() {
+        if deps.insert(p) {
+            stack.extend(repo.patch_deps(&p).cloned());
+        }
+    }
+    patches
+        .iter()
+        .filter(|p| !deps.contains(p))
+        .cloned()
+        .collect()
+}
+
+// Returns the conflicts on a branch, indexed by the paths of the files that have them (see
+// `Repo::conflicts`).
+pub(crate) fn branch_conflicts(
+    repo: &Repo,
+    branch: &str,
+) -> Result<BTreeMap<String, Vec<BranchConflict>>, Error> {
+    let with_causes = |conflicts: Vec<Conflict>| {
+        conflicts
+            .into_iter()
+            .map(|conflict| BranchConflict {
+                causes: causes(repo, &conflict.patches),
+                conflict,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let mut ret = BTreeMap::new();
+    let conflicts = repo.graggle(branch)?.conflicts();
+    if !conflicts.is_empty() {
+        ret.insert(DEFAULT_WORKING_FILE.to_owned(), with_causes(conflicts));
+    }
+    for path in repo.files(branch)? {
+        let conflicts = repo.file_graggle(branch, path)?.conflicts();
+        if !conflicts.is_empty() {
+            ret.insert(path.to_owned(), with_causes(conflicts));
+        }
+    }
+    Ok(ret)
+}
+
+// A piece of a graggle: either a line that is ordered with respect to all the others, or a
+// conflict.
+pub(crate) enum Region {
+    Line(NodeId),
+    Conflict(Conflict),
+}
+
+// For each component (in topological order), is every component before it connected to it?
+fn preceded_by_all<F>(num_components: usize, preds: F) -> Vec<bool>
+where
+    F: Fn(usize) -> HashSet<usize>,
+{
+    let mut ret = Vec::with_capacity(num_components);
+    let mut sinks = HashSet::new();
+    for i in 0..num_components {
+        let preds = preds(i);
+        ret.push(sinks.iter().all(|s| preds.contains(s)));
+        sinks.retain(|s| !preds.contains(s));
+        sinks.insert(i);
+    }
+    ret
+}
+
+// Divides a graggle into settled lines and conflicts, in order.
+pub(crate) fn regions(graggle: Graggle<'_>) -> Vec<Region> {
+    let live = graggle.as_live_graph();
+    let sccs = graggle.sccs();
+    let n = sccs.len();
+    let component = sccs
+        .iter()
+        .enumerate()
+        .flat_map(|(i, part)| part.iter().map(move |u| (*u, i)))
+        .collect::<HashMap<_, _>>();
+    let neighbors = |i: usize, forwards: bool| {
+        sccs[i]
+            .iter()
+            .flat_map(|u| {
+                if forwards {
+                    live.out_neighbors(u).collect::<Vec<_>>()
+                } else {
+                    live.in_neighbors(u).collect::<Vec<_>>()
+                }
+            })
+            .map(|v| component[&v])
+            .filter(|&j| j != i)
+            .collect::<HashSet<_>>()
+    };
+
+    let before = preceded_by_all(n, |i| neighbors(i, false));
+    // Going backwards, the components are numbered from the end.
+    let mut after = preceded_by_all(n, |i| {
+        neighbors(n - 1 - i, true)
+            .into_iter()
+            .map(|j| n - 1 - j)
+            .collect()
+    });
+    after.reverse();
+
+    let mut ret = Vec::new();
+    let mut pending = Vec::new();
+    let mut last_line = None;
+    let make_conflict = |pending: &[usize], before, after| {
+        let alternatives = alternatives(pending, &sccs, |j| neighbors(j, true));
+        Region::Conflict(conflict(graggle, before, after, alternatives))
+    };
+    for i in 0..n {
+        if before[i] && after[i] && sccs[i].len() == 1 {
+            let line = *sccs[i].iter().next().unwrap();
+            if !pending.is_empty() {
+                ret.push(make_conflict(&pending, last_line, Some(line)));
+                pending.clear();
+            }
+            ret.push(Region::Line(line));
+            last_line = Some(line);
+        } else {
+            pending.push(i);
+        }
+    }
+    if !pending.is_empty() {
+        ret.push(make_conflict(&pending, last_line, None));
+    }
+    ret
+}
+
+// Splits the components of a conflict into weakly connected pieces, and returns the lines in each
+// piece. The pieces are sorted by where they start, and the lines in each piece are in
+// topological order.
+fn alternatives<F>(components: &[usize], sccs: &[HashSet<NodeId>], succs: F) -> Vec<Vec<NodeId>>
+where
+    F: Fn(usize) -> HashSet<usize>,
+{
+    let mut partition = ojo_partition::Partition::new();
+    for &i in components {
+        partition.insert(i);
+    }
+    for &i in components {
+        for j in succs(i) {
+            if partition.contains(j) {
+                partition.merge(i, j);
+            }
+        }
+    }
+
+    let mut pieces = Vec::<Vec<NodeId>>::new();
+    let mut piece_idx = HashMap::new();
+    for &i in components {
+        let rep = partition.representative(i);
+        let idx = *piece_idx.entry(rep).or_insert_with(|| {
+            pieces.push(Vec::new());
+            pieces.len() - 1
+        });
+        let mut lines = sccs[i].iter().cloned().collect::<Vec<_>>();
+        lines.sort();
+        pieces[idx].extend(lines);
+    }
+    pieces
+}
+
+fn conflict(
+    graggle: Graggle<'_>,
+    before: Option<NodeId>,
+    after: Option<NodeId>,
+    alternatives: Vec<Vec<NodeId>>,
+) -> Conflict {
+    let lines = alternatives.iter().flatten().collect::<HashSet<_>>();
+    let mut patches = lines.iter().map(|id| id.patch).collect::<BTreeSet<_>>();
+    // The edges that we're interested in all touch a line of the conflict, which means that they
+    // start either at one of its lines or at the line before it.
+    for &u in lines.iter().chain(&before.as_ref()) {
+        for e in graggle.out_edges(u) {
+            let touches_conflict = lines.contains(u) || lines.contains(&e.dest);
+            if e.kind == EdgeKind::Live && touches_conflict {
+                patches.insert(e.patch);
+            }
+        }
+    }
+    Conflict {
+        before,
+        after,
+        alternatives,
+        patches: patches.into_iter().collect(),
+    }
+}
+
+impl<'a> Graggle<'a> {
+    /// Returns all the parts of this graggle whose lines aren't totally ordered, in order.
+    ///
+    /// If there are no conflicts, the graggle is totally ordered (i.e. it represents a file).
+    pub fn conflicts(self) -> Vec<Conflict> {
+        regions(self)
+            .into_iter()
+            .filter_map(|r| match r {
+                Region::Conflict(c) => Some(c),
+                Region::Line(_) => None,
+            })
+            .collect()
+    }
+}
+
+// Returns the changes that put some lines of a graggle in the given order (see
+// `Repo::ordering_changes`).
+pub(crate) fn ordering_changes(graggle: Graggle<'_>, order: &[NodeId]) -> Result<Changes, Error> {
+    let graph = graggle.as_live_graph();
+    let mut position = HashMap::new();
+    for (i, id) in order.iter().enumerate() {
+        if !graggle.has_node(id) || !graggle.is_live(id) {
+            return Err(Error::UnknownNode(*id));
+        }
+        position.insert(*id, i);
+    }
+    for (j, id) in order.iter().enumerate() {
+        if let Some(earlier) = graph
+            .preorder_from(id)
+            .find(|n| position.get(n).is_some_and(|&i| i < j))
+        {
+            return Err(Error::OrderConflict(earlier, *id));
+        }
+    }
+
+    let changes = order
+        .windows(2)
+        .filter(|w| !graph.has_path(&w[0], &w[1]))
+        .map(|w| Change::NewEdge {
+            src: w[0],
+            dest: w[1],
+        })
+        .collect();
+    Ok(Changes { changes })
+}
+
+// Returns the changes that resolve a conflict by keeping only the lines in `order`, in that order
+// (see `Repo::resolve_conflict`).
+//
+// Deleting lines doesn't change which lines come before which, because the graggle keeps track of
+// the paths through deleted lines. That means that we can check the surviving lines against the
+// order that the graggle already has, before deleting anything.
+pub(crate) fn resolution_changes(
+    graggle: Graggle<'_>,
+    conflict: &Conflict,
+    order: &[NodeId],
+) -> Result<Changes, Error> {
+    let lines = conflict.lines().collect::<HashSet<_>>();
+    let mut kept = HashSet::new();
+    for id in order {
+        if !lines.contains(id) {
+            return Err(Error::NotInConflict(*id));
+        }
+        if !kept.insert(id) {
+            return Err(Error::OrderConflict(*id, *id));
+        }
+    }
+
+    let chain = conflict
+        .before
+        .iter()
+        .chain(order)
+        .chain(&conflict.after)
+        .cloned()
+        .collect::<Vec<_>>();
+    let mut changes = ordering_changes(graggle, &chain)?;
+    for &id in conflict.lines() {
+        if !kept.contains(&id) {
+            if !graggle.has_node(&id) || !graggle.is_live(&id) {
+                return Err(Error::UnknownNode(id));
+            }
+            changes.changes.push(Change::DeleteNode { id });
+        }
+    }
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::create;
+    use crate::{
+        Change, Changes, Error, MemoryWorkingCopy, PatchKind, RecordOptions, Repo,
+        DEFAULT_WORKING_FILE,
+    };
+
+    #[test]
+    fn no_conflicts() {
+        let mut repo = Repo::init_tmp();
+        assert!(repo.graggle("master").unwrap().conflicts().is_empty());
+        create(&mut repo, "master", b"a\nb\n");
+        assert!(repo.graggle("master").unwrap().conflicts().is_empty());
+    }
+
+    #[test]
+    fn insertions() {
+        let mut repo = Repo::init_tmp();
+        let base = create(&mut repo, "master", b"a\nb\n");
+        repo.clone_branch("master", "other").unwrap();
+        let ours = create(&mut repo, "master", b"a\nc\nd\nb\n");
+        let theirs = create(&mut repo, "other", b"a\ne\nb\n");
+        repo.apply_patch("master", &theirs).unwrap();
+
+        let file = repo.file("other").unwrap();
+        let (a, b) = (*file.node_id(0), *file.node_id(2));
+        let conflicts = repo.graggle("master").unwrap().conflicts();
+        assert_eq!(conflicts.len(), 1);
+        let conflict = &conflicts[0];
+        assert_eq!(conflict.before, Some(a));
+        assert_eq!(conflict.after, Some(b));
+        assert_eq!(conflict.alternatives.len(), 2);
+        assert_eq!(conflict.lines().count(), 3);
+        let mut alt_patches = (0..2)
+            .map(|i| conflict.alternative_patches(i))
+            .collect::<Vec<_>>();
+        alt_patches.sort();
+        let mut expected = vec![vec![ours], vec![theirs]];
+        expected.sort();
+        assert_eq!(alt_patches, expected);
+
+        let mut patches = vec![ours, theirs];
+        patches.sort();
+        assert_eq!(conflict.patches, patches);
+        assert!(!conflict.patches.contains(&base));
+    }
+
+    #[test]
+    fn cycle() {
+        let mut repo = Repo::init_tmp();
+        let base = create(&mut repo, "master", b"a\nb\nc\n");
+        let file = repo.file("master").unwrap();
+        let (a, b, c) = (*file.node_id(0), *file.node_id(1), *file.node_id(2));
+        let changes = Changes {
+            changes: vec![Change::NewEdge { src: b, dest: a }],
+        };
+        let id = repo.create_patch("Author", "Msg", changes).unwrap();
+        repo.apply_patch("master", &id).unwrap();
+
+        let conflicts = repo.graggle("master").unwrap().conflicts();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].before, None);
+        assert_eq!(conflicts[0].after, Some(c));
+        assert_eq!(conflicts[0].alternatives, vec![vec![a, b]]);
+        let mut patches = vec![base, id];
+        patches.sort();
+        assert_eq!(conflicts[0].patches, patches);
+    }
+
+    #[test]
+    fn resolve() {
+        let mut repo = Repo::init_tmp();
+        create(&mut repo, "master", b"a\nb\n");
+        repo.clone_branch("master", "other").unwrap();
+        create(&mut repo, "master", b"a\nc\nd\nb\n");
+        let theirs = create(&mut repo, "other", b"a\ne\nb\n");
+        let file = repo.file("master").unwrap();
+        let (c, d) = (*file.node_id(1), *file.node_id(2));
+        let e = *repo.file("other").unwrap().node_id(1);
+        repo.apply_patch("master", &theirs).unwrap();
+        let conflict = repo.graggle("master").unwrap().conflicts().remove(0);
+
+        // The order can't contradict the existing order, and it can only contain the conflict's
+        // lines.
+        let a = conflict.before.unwrap();
+        match repo.resolve_conflict("master", "Author", "Msg", &conflict, &[d, c]) {
+            Err(Error::OrderConflict(x, y)) if x == d && y == c => {}
+            other => panic!("expected an order conflict, got {:?}", other),
+        }
+        match repo.resolve_conflict("master", "Author", "Msg", &conflict, &[a, c]) {
+            Err(Error::NotInConflict(x)) if x == a => {}
+            other => panic!("expected a line outside the conflict, got {:?}", other),
+        }
+
+        let id = repo
+            .resolve_conflict("master", "Author", "Msg", &conflict, &[c, e, d])
+            .unwrap();
+        assert_eq!(repo.patch_header(&id).unwrap().kind, PatchKind::Resolution);
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"a\nc\ne\nd\nb\n");
+    }
+
+    #[test]
+    fn resolve_by_deleting() {
+        let mut repo = Repo::init_tmp();
+        create(&mut repo, "master", b"a\nb\nc\n");
+        let file = repo.file("master").unwrap();
+        let (a, b) = (*file.node_id(0), *file.node_id(1));
+        let changes = Changes {
+            changes: vec![Change::NewEdge { src: b, dest: a }],
+        };
+        let id = repo.create_patch("Author", "Msg", changes).unwrap();
+        repo.apply_patch("master", &id).unwrap();
+
+        // Lines in a cycle can't all survive.
+        let conflict = repo.graggle("master").unwrap().conflicts().remove(0);
+        assert!(repo
+            .resolve_conflict("master", "Author", "Msg", &conflict, &[a, b])
+            .is_err());
+        repo.resolve_conflict("master", "Author", "Msg", &conflict, &[b])
+            .unwrap();
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"b\nc\n");
+    }
+
+    #[test]
+    fn resolve_file() {
+        let mut repo = Repo::init_tmp();
+        let changes = Changes {
+            changes: vec![Change::NewFile {
+                path: "a.txt".to_owned(),
+            }],
+        };
+        let id = repo.create_patch("Author", "Msg", changes).unwrap();
+        repo.apply_patch("master", &id).unwrap();
+        let file = repo.file_id("master", "a.txt").unwrap();
+        let edit = |repo: &mut Repo, contents: &[u8]| {
+            let diff = repo.diff_file("master", "a.txt", contents).unwrap();
+            let mut changes = Changes { changes: vec![] };
+            changes.add_file_changes(file.clone(), diff.changes());
+            repo.create_patch("Author", "Msg", changes).unwrap()
+        };
+        let first = edit(&mut repo, b"a\n");
+        let second = edit(&mut repo, b"b\n");
+        repo.apply_patch("master", &first).unwrap();
+        repo.apply_patch("master", &second).unwrap();
+
+        let graggle = repo.file_graggle("master", "a.txt").unwrap();
+        let conflict = graggle.conflicts().remove(0);
+        let mut order = conflict.lines().cloned().collect::<Vec<_>>();
+        order.sort_by_key(|id| repo.contents(id).to_owned());
+        repo.resolve_file_conflict("master", "a.txt", "Author", "Msg", &conflict, &order)
+            .unwrap();
+        assert_eq!(
+            repo.file_at("master", "a.txt").unwrap().as_bytes(),
+            b"a\nb\n"
+        );
+    }
+
+    #[test]
+    fn causes() {
+        let mut repo = Repo::init_tmp();
+        let base = create(&mut repo, "master", b"a\nb\n");
+        repo.clone_branch("master", "other").unwrap();
+        let ours = create(&mut repo, "master", b"a\nc\nb\n");
+        let ours2 = create(&mut repo, "master", b"a\nc\nd\nb\n");
+        let theirs = create(&mut repo, "other", b"a\ne\nb\n");
+        assert!(repo.conflicts("master").unwrap().is_empty());
+        repo.apply_patch("master", &theirs).unwrap();
+
+        let conflicts = repo.conflicts("master").unwrap();
+        assert_eq!(
+            conflicts.keys().collect::<Vec<_>>(),
+            vec![DEFAULT_WORKING_FILE]
+        );
+        let conflicts = &conflicts[DEFAULT_WORKING_FILE];
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(
+            conflicts[0].conflict,
+            repo.graggle("master").unwrap().conflicts()[0]
+        );
+        // `ours2` depends on `ours`, so `ours` isn't one of the causes even though it's involved.
+        assert!(conflicts[0].conflict.patches.contains(&ours));
+        let mut causes = vec![ours2, theirs];
+        causes.sort();
+        assert_eq!(conflicts[0].causes, causes);
+        assert!(!conflicts[0].causes.contains(&base));
+    }
+
+    #[test]
+    fn file_conflict_causes() {
+        let wc = MemoryWorkingCopy::new();
+        let mut repo = Repo::init_in_memory(wc.clone());
+        let record = |repo: &mut Repo| {
+            repo.record("Author", "Msg", &RecordOptions::default())
+                .unwrap()
+        };
+        wc.write("a.txt", b"a\n");
+        repo.track_file("a.txt").unwrap();
+        record(&mut repo);
+        repo.clone_branch("master", "other").unwrap();
+        wc.write("a.txt", b"a\nb\n");
+        let ours = record(&mut repo);
+        repo.checkout("other").unwrap();
+        wc.write("a.txt", b"a\nc\n");
+        let theirs = record(&mut repo);
+
+        repo.merge("other", "master", false).unwrap();
+        let conflicts = repo.conflicts("master").unwrap();
+        assert_eq!(conflicts.keys().collect::<Vec<_>>(), vec!["a.txt"]);
+        let mut causes = vec![ours, theirs];
+        causes.sort();
+        assert_eq!(conflicts["a.txt"][0].causes, causes);
+    }
+}
@@ -0,0 +1,584 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Importing darcs patches (see `Repo::import_darcs`).
+//
+// A darcs bundle (as written by `darcs send`) has the new patches, followed by the patches that
+// they apply on top of (the "context"), which only have their metadata. Each of the new patches
+// has a list of primitive changes, which apply one after the other: adding, removing and moving
+// files and directories, and changing the lines of files (a "hunk" says which lines to remove at a
+// given line number, and which ones to put in their place).
+//
+// The primitive changes are carried out on the files of the branch, held in memory, and then the
+// branch's files are changed to match, in the same way as when importing a git commit. Since ojo
+// doesn't track directories, the changes to directories only matter when files are moved along
+// with them.
+
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use std::collections::BTreeMap;
+use std::io::BufRead;
+
+use crate::git_import::{self, GitFile, Tree};
+use crate::{Error, PatchHeader, PatchId, Repo};
+
+// A primitive change in a darcs patch.
+#[derive(Debug)]
+pub(crate) enum Prim {
+    AddFile(String),
+    RemoveFile(String),
+    // Moves a file or a directory.
+    Move(String, String),
+    Hunk {
+        path: String,
+        // The line that the hunk starts at, counting from 1.
+        line: usize,
+        old: Vec<Vec<u8>>,
+        new: Vec<Vec<u8>>,
+    },
+    Binary(String, Vec<u8>),
+    // Replaces one token by another, everywhere in a file. The characters that tokens are made of
+    // are given in the syntax of a regular expression's character class, like `[A-Za-z_0-9]`.
+    Replace {
+        path: String,
+        chars: String,
+        old: Vec<u8>,
+        new: Vec<u8>,
+    },
+    // Adding and removing directories, and changing darcs' settings, don't affect any files.
+    Nothing,
+}
+
+pub(crate) struct DarcsPatch {
+    pub header: PatchHeader,
+    // The primitive changes, with the lines that they start at.
+    pub prims: Vec<(usize, Prim)>,
+}
+
+struct Parser {
+    lines: Vec<Vec<u8>>,
+    // The number of lines that have been read.
+    pos: usize,
+}
+
+impl Parser {
+    fn error<T>(&self, msg: &str) -> Result<T, Error> {
+        Err(Error::DarcsPatch(self.pos, msg.to_owned()))
+    }
+
+    fn peek(&self) -> Option<String> {
+        self.lines
+            .get(self.pos)
+            .map(|l| String::from_utf8_lossy(l).into_owned())
+    }
+
+    fn next_line(&mut self) -> Result<String, Error> {
+        match self.peek() {
+            Some(line) => {
+                self.pos += 1;
+                Ok(line)
+            }
+            None => self.error("unexpected end of the patch"),
+        }
+    }
+
+    // Decodes a path, like `./a\32\b.txt` (darcs writes the bytes of some characters, like
+    // spaces, as decimal numbers between backslashes).
+    fn path(&self, s: &str) -> Result<String, Error> {
+        let s = match s.strip_prefix("./") {
+            Some(s) if !s.is_empty() => s,
+            _ => return self.error(&format!("invalid path {:?}", s)),
+        };
+        let mut ret = Vec::new();
+        let mut parts = s.split('\\');
+        ret.extend_from_slice(parts.next().unwrap().as_bytes());
+        while let Some(code) = parts.next() {
+            match (code.parse::<u8>(), parts.next()) {
+                (Ok(b), Some(rest)) => {
+                    ret.push(b);
+                    ret.extend_from_slice(rest.as_bytes());
+                }
+                _ => return self.error(&format!("invalid path {:?}", s)),
+            }
+        }
+        match String::from_utf8(ret) {
+            Ok(path) => Ok(path),
+            Err(_) => self.error(&format!("invalid path {:?}", s)),
+        }
+    }
+
+    // Reads the lines of a hunk that start with `prefix`.
+    fn hunk_lines(&mut self, prefix: u8) -> Vec<Vec<u8>> {
+        let mut ret = Vec::new();
+        while let Some(line) = self.lines.get(self.pos) {
+            if line.first() != Some(&prefix) {
+                break;
+            }
+            ret.push(line[1..].to_owned());
+            self.pos += 1;
+        }
+        ret
+    }
+
+    // Reads the hex-encoded contents of a binary file, which come after a line saying `name`.
+    fn hex(&mut self, name: &str) -> Result<Vec<u8>, Error> {
+        if self.next_line()? != name {
+            return self.error(&format!("expected {}", name));
+        }
+        let mut hex = String::new();
+        while let Some(line) = self.peek().filter(|l| l.starts_with('*')) {
+            hex.push_str(&line[1..]);
+            self.pos += 1;
+        }
+        let digit = |i: usize| {
+            hex.get(i..i + 2)
+                .and_then(|d| u8::from_str_radix(d, 16).ok())
+        };
+        match (0..hex.len()).step_by(2).map(digit).collect() {
+            Some(ret) if hex.len().is_multiple_of(2) => Ok(ret),
+            _ => self.error("invalid hex data"),
+        }
+    }
+
+    fn prim(&mut self) -> Result<Prim, Error> {
+        let line = self.next_line()?;
+        let words = line.split(' ').collect::<Vec<_>>();
+        Ok(match (words[0], words.len()) {
+            ("addfile", 2) => Prim::AddFile(self.path(words[1])?),
+            ("rmfile", 2) => Prim::RemoveFile(self.path(words[1])?),
+            ("adddir", 2) | ("rmdir", 2) => Prim::Nothing,
+            ("move", 3) => Prim::Move(self.path(words[1])?, self.path(words[2])?),
+            ("hunk", 3) => {
+                let path = self.path(words[1])?;
+                let line = match words[2].parse() {
+                    Ok(line) if line > 0 => line,
+                    _ => return self.error("invalid line number"),
+                };
+                let old = self.hunk_lines(b'-');
+                let new = self.hunk_lines(b'+');
+                Prim::Hunk {
+                    path,
+                    line,
+                    old,
+                    new,
+                }
+            }
+            ("binary", 2) => {
+                let path = self.path(words[1])?;
+                self.hex("oldhex")?;
+                Prim::Binary(path, self.hex("newhex")?)
+            }
+            ("replace", 5) => Prim::Replace {
+                path: self.path(words[1])?,
+                chars: words[2].to_owned(),
+                old: words[3].as_bytes().to_owned(),
+                new: words[4].as_bytes().to_owned(),
+            },
+            ("changepref", 2) => {
+                // The old and new values of the setting are on the next two lines.
+                self.next_line()?;
+                self.next_line()?;
+                Prim::Nothing
+            }
+            ("merger", _) | ("conflictor", _) | ("tcommute", _) => {
+                return self.error("conflicted darcs patches aren't supported")
+            }
+            _ => return self.error(&format!("unknown change {:?}", words[0])),
+        })
+    }
+
+    // Reads a patch, whose first line (starting with `[`) is next.
+    fn patch(&mut self) -> Result<DarcsPatch, Error> {
+        let name = self.next_line()?[1..].to_owned();
+        let info = self.next_line()?;
+        let (author, rest) = match info.rfind("**") {
+            Some(i) => (&info[..i], &info[i + 2..]),
+            None if info.contains("*-") => {
+                return self.error("inverted darcs patches aren't supported")
+            }
+            None => return self.error("expected an author and a date"),
+        };
+        let date = rest.get(..14).unwrap_or(rest);
+        let timestamp = match NaiveDateTime::parse_from_str(date, "%Y%m%d%H%M%S") {
+            Ok(date) => Utc.from_utc_datetime(&date),
+            Err(_) => return self.error(&format!("invalid date {:?}", date)),
+        };
+
+        // The long description is indented by a space, and `]` comes right after its last line.
+        let mut log = Vec::new();
+        let mut rest = rest[date.len()..].to_owned();
+        while !rest.trim_end().ends_with(']') && !rest.trim_end().ends_with("] {") {
+            let line = self.next_line()?;
+            match line.strip_prefix(' ') {
+                Some(line) => rest = line.to_owned(),
+                // The space on an empty line is easily lost, for example when the bundle is sent
+                // by email.
+                None if line.is_empty() => rest = line,
+                None => return self.error("expected the end of the patch's description"),
+            }
+            log.push(rest.clone());
+        }
+        let end = rest.rfind(']').unwrap();
+        let has_changes = rest[end..].contains('{');
+        if let Some(last) = log.last_mut() {
+            last.truncate(end);
+        }
+
+        let mut prims = Vec::new();
+        if has_changes {
+            while self.peek().as_deref() != Some("}") {
+                let line = self.pos + 1;
+                prims.push((line, self.prim()?));
+            }
+            self.pos += 1;
+        }
+
+        let log = log
+            .iter()
+            .filter(|l| !l.starts_with("Ignore-this:"))
+            .map(|l| l.as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let log = log.trim();
+        let description = if log.is_empty() {
+            name
+        } else {
+            format!("{}\n\n{}", name, log)
+        };
+        let mut header = PatchHeader::new(author.to_owned(), description);
+        header.timestamp = timestamp;
+        if let (Some(open), Some(close)) = (author.find('<'), author.rfind('>')) {
+            if open < close {
+                header.author = author[..open].trim().to_owned();
+                header.email = Some(author[open + 1..close].to_owned());
+            }
+        }
+        Ok(DarcsPatch { header, prims })
+    }
+
+    fn patches(&mut self) -> Result<Vec<DarcsPatch>, Error> {
+        let mut ret = Vec::new();
+        while let Some(line) = self.peek() {
+            if line.starts_with('[') {
+                ret.push(self.patch()?);
+            } else if line == "Context:" {
+                // The patches in the context only have their metadata.
+                break;
+            } else {
+                self.pos += 1;
+            }
+        }
+        Ok(ret)
+    }
+}
+
+// Replaces the token `old` by `new` in `contents`.
+fn replace_token(contents: &[u8], chars: &str, old: &[u8], new: &[u8]) -> Option<Vec<u8>> {
+    let class = chars.strip_prefix('[')?.strip_suffix(']')?.as_bytes();
+    let (negated, class) = match class.split_first() {
+        Some((b'^', rest)) => (true, rest),
+        _ => (false, class),
+    };
+    let mut ranges = Vec::new();
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == b'-' {
+            ranges.push((class[i], class[i + 2]));
+            i += 3;
+        } else {
+            ranges.push((class[i], class[i]));
+            i += 1;
+        }
+    }
+    let is_token = |c: u8| ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != negated;
+
+    let mut ret = Vec::new();
+    let mut i = 0;
+    while i < contents.len() {
+        let len = contents[i..].iter().take_while(|&&c| is_token(c)).count();
+        if len == 0 {
+            ret.push(contents[i]);
+            i += 1;
+        } else {
+            let token = &contents[i..i + len];
+            ret.extend_from_slice(if token == old { new } else { token });
+            i += len;
+        }
+    }
+    Some(ret)
+}
+
+// Carries out a primitive change on the files in `tree`. The files that are moved are recorded in
+// `renames`, indexed by their new paths.
+fn apply(
+    tree: &mut Tree,
+    renames: &mut BTreeMap<String, String>,
+    prim: &Prim,
+) -> Result<(), String> {
+    let file = |tree: &mut Tree, path: &str| -> Result<GitFile, String> {
+        tree.remove(path)
+            .ok_or_else(|| format!("there is no file {:?}", path))
+    };
+    match prim {
+        Prim::AddFile(path) => {
+            if tree.contains_key(path) {
+                return Err(format!("there is already a file {:?}", path));
+            }
+            let file = GitFile {
+                contents: Vec::new().into(),
+                executable: false,
+                symlink: false,
+            };
+            tree.insert(path.clone(), file);
+        }
+        Prim::RemoveFile(path) => {
+            file(tree, path)?;
+            renames.remove(path);
+        }
+        Prim::Move(from, to) => {
+            // Either a file moves, or all the files in a directory do.
+            let prefix = format!("{}/", from);
+            let paths = tree
+                .keys()
+                .filter(|p| *p == from || p.starts_with(&prefix))
+                .cloned()
+                .collect::<Vec<_>>();
+            for path in paths {
+                let new_path = format!("{}{}", to, &path[from.len()..]);
+                if tree.contains_key(&new_path) {
+                    return Err(format!("there is already a file {:?}", new_path));
+                }
+                let f = file(tree, &path)?;
+                tree.insert(new_path.clone(), f);
+                let origin = renames.remove(&path).unwrap_or(path);
+                renames.insert(new_path, origin);
+            }
+        }
+        Prim::Hunk {
+            path,
+            line,
+            old,
+            new,
+        } => {
+            let mut f = file(tree, path)?;
+            let mut lines = f.contents.split(|&c| c == b'\n').collect::<Vec<_>>();
+            let start = line - 1;
+            let end = start + old.len();
+            if end > lines.len() || lines[start..end].iter().ne(old.iter()) {
+                tree.insert(path.clone(), f);
+                return Err(format!("a hunk doesn't match the lines of {:?}", path));
+            }
+            lines.splice(start..end, new.iter().map(|l| &l[..]));
+            f.contents = lines.join(&b'\n').into();
+            tree.insert(path.clone(), f);
+        }
+        Prim::Binary(path, contents) => {
+            let mut f = file(tree, path)?;
+            f.contents = contents.clone().into();
+            tree.insert(path.clone(), f);
+        }
+        Prim::Replace {
+            path,
+            chars,
+            old,
+            new,
+        } => {
+            let mut f = file(tree, path)?;
+            let contents = replace_token(&f.contents, chars, old, new);
+            let contents = contents.ok_or_else(|| format!("invalid token characters {}", chars))?;
+            f.contents = contents.into();
+            tree.insert(path.clone(), f);
+        }
+        Prim::Nothing => {}
+    }
+    Ok(())
+}
+
+// Reads the files on a branch.
+fn branch_tree(repo: &Repo, branch: &str) -> Result<Tree, Error> {
+    let mut ret = Tree::new();
+    for path in repo.files(branch)? {
+        let contents = match repo.binary_at(branch, path)? {
+            Some(contents) => contents.into_owned(),
+            None => repo.file_at(branch, path)?.as_bytes().to_owned(),
+        };
+        let file = GitFile {
+            contents: contents.into(),
+            executable: repo.is_executable(branch, path)?,
+            symlink: repo.is_symlink(branch, path)?,
+        };
+        ret.insert(path.to_owned(), file);
+    }
+    Ok(ret)
+}
+
+pub(crate) fn import<R: BufRead>(
+    repo: &mut Repo,
+    branch: &str,
+    mut input: R,
+) -> Result<Vec<PatchId>, Error> {
+    let mut lines = Vec::new();
+    loop {
+        let mut line = Vec::new();
+        if input.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        if line.ends_with(b"\n") {
+            line.pop();
+        }
+        lines.push(line);
+    }
+    let mut parser = Parser { lines, pos: 0 };
+    let patches = parser.patches()?;
+    if patches.is_empty() {
+        return parser.error("there are no patches");
+    }
+
+    replay(repo, branch, patches, Error::DarcsPatch)
+}
+
+// Applies patches to the files on a branch, one after the other, and records a patch for each one
+// that changes them. The errors from carrying out the primitive changes are reported by `error`,
+// which gets the line that the change starts at.
+pub(crate) fn replay(
+    repo: &mut Repo,
+    branch: &str,
+    patches: Vec<DarcsPatch>,
+    error: fn(usize, String) -> Error,
+) -> Result<Vec<PatchId>, Error> {
+    let config = repo.config();
+    repo.transaction(|repo| {
+        let mut tree = branch_tree(repo, branch)?;
+        let mut ret = Vec::new();
+        for patch in patches {
+            let mut renames = BTreeMap::new();
+            for (line, prim) in &patch.prims {
+                apply(&mut tree, &mut renames, prim).map_err(|e| error(*line, e))?;
+            }
+            let changes = git_import::changes(repo, &config, branch, &tree, &renames)?;
+            if changes.changes.is_empty() {
+                continue;
+            }
+            let id = repo.create_patch_with_header(patch.header, changes)?;
+            repo.apply_patch(branch, &id)?;
+            ret.push(id);
+        }
+        Ok(ret)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUNDLE: &str = "
+New patches:
+
+[Add some files
+Alice <alice@example.com>**20190102030405
+ Ignore-this: 0123456789abcdef0123456789abcdef
+
+ With a longer description.] {
+adddir ./dir
+addfile ./dir/a\\32\\file.txt
+hunk ./dir/a\\32\\file.txt 1
++foo bar
++baz
+addfile ./b.bin
+binary ./b.bin
+oldhex
+*
+newhex
+*00ff
+}
+
+[Move things around
+bob**20190103000000] {
+move ./dir ./renamed
+hunk ./renamed/a\\32\\file.txt 2
+-baz
++qux
+replace ./renamed/a\\32\\file.txt [A-Za-z_0-9] foo food
+rmfile ./b.bin
+}
+
+Context:
+
+[Initial
+alice**20190101000000]
+
+Patch bundle hash:
+0123456789abcdef0123456789abcdef01234567
+";
+
+    #[test]
+    fn bundle() {
+        let mut repo = Repo::init_tmp();
+        let ids = repo.import_darcs("master", BUNDLE.as_bytes()).unwrap();
+        assert_eq!(ids.len(), 2);
+
+        let header = repo.patch_header(&ids[0]).unwrap();
+        assert_eq!(header.author, "Alice");
+        assert_eq!(header.email.as_deref(), Some("alice@example.com"));
+        assert_eq!(
+            header.description,
+            "Add some files\n\nWith a longer description."
+        );
+        assert_eq!(header.timestamp.to_rfc3339(), "2019-01-02T03:04:05+00:00");
+        let header = repo.patch_header(&ids[1]).unwrap();
+        assert_eq!(header.author, "bob");
+        assert_eq!(header.description, "Move things around");
+
+        assert_eq!(
+            repo.files("master").unwrap().collect::<Vec<_>>(),
+            vec!["renamed/a file.txt"]
+        );
+        assert_eq!(
+            repo.file_at("master", "renamed/a file.txt")
+                .unwrap()
+                .as_bytes(),
+            b"food bar\nqux\n"
+        );
+        // The file was moved, rather than deleted and created again.
+        let id = repo.file_id("master", "renamed/a file.txt").unwrap();
+        assert_eq!(id.patch, ids[0]);
+    }
+
+    #[test]
+    fn errors() {
+        let mut repo = Repo::init_tmp();
+        let mut import = |bundle: &str| repo.import_darcs("master", bundle.as_bytes());
+        match import("[Name\nauthor**20190102030405] {\nfrobnicate ./a\n}\n") {
+            Err(Error::DarcsPatch(3, _)) => {}
+            x => panic!("expected an error, got {:?}", x),
+        }
+        match import("[Name\nauthor**20190102030405] {\nhunk ./a 1\n+a\n}\n") {
+            Err(Error::DarcsPatch(3, _)) => {}
+            x => panic!("expected an error, got {:?}", x),
+        }
+        match import("[Name\nauthor*-20190102030405] {\n}\n") {
+            Err(Error::DarcsPatch(2, _)) => {}
+            x => panic!("expected an error, got {:?}", x),
+        }
+        assert_eq!(repo.all_patches().count(), 0);
+    }
+
+    #[test]
+    fn tokens() {
+        assert_eq!(
+            replace_token(b"foo foo_bar (foo)", "[A-Za-z_]", b"foo", b"x"),
+            Some(b"x foo_bar (x)".to_vec())
+        );
+        assert_eq!(
+            replace_token(b"a-b a", "[^ ]", b"a", b"c"),
+            Some(b"a-b c".to_vec())
+        );
+    }
+}
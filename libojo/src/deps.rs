@@ -0,0 +1,282 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+use ojo_graph::Graph;
+use std::collections::HashSet;
+
+use crate::storage::Storage;
+use crate::PatchId;
+
+/// What [`Repo::apply`](crate::Repo::apply) should do if some of the patches that the patch being
+/// applied depends on aren't on the branch.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ApplyPolicy {
+    /// Don't apply anything, and return [`Error::UnappliedDeps`](crate::Error::UnappliedDeps).
+    Refuse,
+    /// Apply the missing dependencies too.
+    Cascade,
+}
+
+/// What [`Repo::unapply`](crate::Repo::unapply) should do if other patches on the branch depend on
+/// the patch being unapplied.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UnapplyPolicy {
+    /// Don't unapply anything, and return [`Error::HasDependents`](crate::Error::HasDependents).
+    Refuse,
+    /// Unapply the dependent patches too.
+    Cascade,
+}
+
+/// The dependency graph of all the patches in a repository.
+///
+/// There is an edge from `p` to `q` whenever `p` depends on `q`. Since a patch can only depend on
+/// patches that already exist, this graph is acyclic. Use [`Graph::reversed`] to follow the edges
+/// from patches to their dependents.
+pub struct PatchGraph<'a> {
+    storage: &'a Storage,
+}
+
+impl<'a> PatchGraph<'a> {
+    pub(crate) fn new(storage: &'a Storage) -> PatchGraph<'a> {
+        PatchGraph { storage }
+    }
+
+    /// Returns all the patches that `patch` depends on, directly or indirectly (not including
+    /// `patch` itself), in sorted order.
+    pub fn transitive_deps(&self, patch: &PatchId) -> Vec<PatchId> {
+        reachable(self, patch)
+    }
+
+    /// Returns all the patches that depend on `patch`, directly or indirectly (not including
+    /// `patch` itself), in sorted order.
+    pub fn transitive_rev_deps(&self, patch: &PatchId) -> Vec<PatchId> {
+        reachable(&self.reversed(), patch)
+    }
+
+    /// Returns `patches` in an order in which they can be applied: every patch comes after all of
+    /// the patches in `patches` that it depends on, directly or indirectly.
+    pub fn apply_order(&self, patches: &[PatchId]) -> Vec<PatchId> {
+        let mut closure = HashSet::new();
+        let mut stack = patches.to_vec();
+        while let Some(p) = stack.pop() {
+            if closure.insert(p) {
+                stack.extend(self.out_edges(&p));
+            }
+        }
+        // Dependencies point from patches to the patches they depend on, so the topological order
+        // needs to be reversed.
+        let mut ret = self
+            .node_filtered(|p| closure.contains(p))
+            .top_sort_lexicographic()
+            .expect("the patch dependency graph has a cycle");
+        ret.reverse();
+        let patches = patches.iter().collect::<HashSet<_>>();
+        ret.retain(|p| patches.contains(p));
+        ret
+    }
+
+    /// Returns the patches that need to be unapplied from a branch in order to unapply `patch`
+    /// from it, in an order in which they can be unapplied: every patch comes before all of its
+    /// dependencies, and `patch` itself comes last.
+    ///
+    /// If `patch` isn't applied to the branch, this is empty.
+    pub fn unapply_order(&self, branch: &str, patch: &PatchId) -> Vec<PatchId> {
+        if !self.storage.branch_patches.contains(branch, patch) {
+            return Vec::new();
+        }
+
+        // Since a branch contains all the dependencies of its patches, any patch on the branch
+        // that depends on `patch` is connected to it by a path that stays within the branch.
+        let on_branch = |p: &PatchId| self.storage.branch_patches.contains(branch, p);
+        let rev = self.reversed();
+        let rev = rev.node_filtered(on_branch);
+        let to_unapply = rev.preorder_from(patch).collect::<HashSet<_>>();
+        self.node_filtered(|p| to_unapply.contains(p))
+            .top_sort_lexicographic()
+            .expect("the patch dependency graph has a cycle")
+    }
+}
+
+fn reachable<G: Graph<Node = PatchId>>(g: &G, patch: &PatchId) -> Vec<PatchId> {
+    let mut ret = g
+        .preorder_from(patch)
+        .filter(|p| p != patch)
+        .collect::<Vec<_>>();
+    ret.sort();
+    ret
+}
+
+impl<'a> Graph for PatchGraph<'a> {
+    type Node = PatchId;
+    type Edge = PatchId;
+
+    fn nodes<'b>(&'b self) -> Box<dyn Iterator<Item = PatchId> + 'b> {
+        Box::new(
+            self.storage
+                .patches
+                .keys()
+                .chain(&self.storage.ghosts)
+                .cloned(),
+        )
+    }
+
+    fn out_edges<'b>(&'b self, u: &PatchId) -> Box<dyn Iterator<Item = PatchId> + 'b> {
+        Box::new(self.storage.patch_deps.get(u).cloned())
+    }
+
+    fn in_edges<'b>(&'b self, u: &PatchId) -> Box<dyn Iterator<Item = PatchId> + 'b> {
+        Box::new(self.storage.patch_rev_deps.get(u).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ojo_graph::Graph;
+
+    use super::{ApplyPolicy, UnapplyPolicy};
+    use crate::{Change, Changes, Error, PatchId, Repo};
+
+    // Creates a patch adding a line after the given lines.
+    fn add_line(repo: &mut Repo, contents: &[u8]) -> PatchId {
+        let diff = repo.diff("master", contents).unwrap();
+        let id = repo.create_patch("Author", "Msg", diff.changes()).unwrap();
+        repo.apply_patch("master", &id).unwrap();
+        id
+    }
+
+    #[test]
+    fn queries() {
+        // `a` is at the bottom, `b` and `c` both depend on it, and `d` depends on `b`.
+        let mut repo = Repo::init_tmp();
+        let a = add_line(&mut repo, b"a\n");
+        let b = add_line(&mut repo, b"a\nb\n");
+        repo.clone_branch("master", "other").unwrap();
+        let c = add_line(&mut repo, b"c\na\nb\n");
+        let d = add_line(&mut repo, b"c\na\nb\nd\n");
+        assert_eq!(repo.patch_deps(&d).collect::<Vec<_>>(), vec![&b]);
+
+        let graph = repo.patch_graph();
+        let sorted = |mut v: Vec<PatchId>| {
+            v.sort();
+            v
+        };
+        assert_eq!(graph.transitive_deps(&d), sorted(vec![a, b]));
+        assert_eq!(graph.transitive_deps(&a), vec![]);
+        assert_eq!(graph.transitive_rev_deps(&a), sorted(vec![b, c, d]));
+        assert_eq!(graph.transitive_rev_deps(&b), vec![d]);
+        assert!(graph.top_sort().is_some());
+
+        // `c` doesn't need to be unapplied in order to unapply `b`.
+        assert_eq!(graph.unapply_order("master", &b), vec![d, b]);
+        assert_eq!(graph.unapply_order("other", &b), vec![b]);
+        assert_eq!(graph.unapply_order("other", &d), vec![]);
+        let order = graph.unapply_order("master", &a);
+        assert_eq!(order.len(), 4);
+        assert_eq!(order[3], a);
+        let pos = |p| order.iter().position(|x| x == p).unwrap();
+        assert!(pos(&d) < pos(&b));
+
+        assert_eq!(repo.unapply_patch("master", &a).unwrap(), order);
+        assert_eq!(repo.patches("master").count(), 0);
+    }
+
+    #[test]
+    fn refuse() {
+        let mut repo = Repo::init_tmp();
+        let a = add_line(&mut repo, b"a\n");
+        let b = add_line(&mut repo, b"a\nb\n");
+
+        match repo.unapply("master", &a, UnapplyPolicy::Refuse) {
+            Err(Error::HasDependents(p, deps)) => {
+                assert_eq!(p, a);
+                assert_eq!(deps, vec![b]);
+            }
+            x => panic!("expected an error, got {:?}", x),
+        }
+        assert_eq!(repo.patches("master").count(), 2);
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"a\nb\n");
+
+        let unapplied = repo.unapply("master", &b, UnapplyPolicy::Refuse).unwrap();
+        assert_eq!(unapplied, vec![b]);
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"a\n");
+        assert!(repo
+            .unapply("master", &b, UnapplyPolicy::Refuse)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn apply_refuse() {
+        let mut repo = Repo::init_tmp();
+        let a = add_line(&mut repo, b"a\n");
+        let b = add_line(&mut repo, b"a\nb\n");
+        repo.create_branch("other").unwrap();
+        match repo.apply("other", &b, ApplyPolicy::Refuse) {
+            Err(Error::UnappliedDeps(p, deps)) => {
+                assert_eq!(p, b);
+                assert_eq!(deps, vec![a]);
+            }
+            x => panic!("unexpected result {:?}", x),
+        }
+        assert_eq!(repo.patches("other").count(), 0);
+
+        assert_eq!(
+            repo.apply("other", &a, ApplyPolicy::Refuse).unwrap(),
+            vec![a]
+        );
+        assert_eq!(
+            repo.apply("other", &b, ApplyPolicy::Refuse).unwrap(),
+            vec![b]
+        );
+        assert!(repo.apply("missing", &b, ApplyPolicy::Refuse).is_err());
+    }
+
+    #[test]
+    fn apply_rolls_back() {
+        let new_file = |path: &str| Changes {
+            changes: vec![Change::NewFile {
+                path: path.to_owned(),
+            }],
+        };
+        let mut repo = Repo::init_tmp();
+        repo.create_branch("other").unwrap();
+        let x = repo
+            .create_patch("Author", "Msg", new_file("x.txt"))
+            .unwrap();
+        repo.apply_patch("master", &x).unwrap();
+
+        // On "other", create y.txt and then move it to x.txt, which already exists on "master".
+        let y = repo
+            .create_patch("Author", "Msg", new_file("y.txt"))
+            .unwrap();
+        repo.apply_patch("other", &y).unwrap();
+        let changes = Changes {
+            changes: vec![Change::MoveFile {
+                from: repo.file_ref("other", "y.txt").unwrap(),
+                to: "x.txt".to_owned(),
+            }],
+        };
+        let mv = repo.create_patch("Author", "Msg", changes).unwrap();
+        assert_eq!(repo.patch_deps(&mv).collect::<Vec<_>>(), vec![&y]);
+
+        // Applying the move to "master" applies `y` first, but then the move fails, and so `y`
+        // gets unapplied again.
+        match repo.apply("master", &mv, ApplyPolicy::Cascade) {
+            Err(Error::FileConflict(p, _)) => assert_eq!(p, mv),
+            x => panic!("unexpected result {:?}", x),
+        }
+        assert_eq!(repo.patches("master").collect::<Vec<_>>(), vec![&x]);
+        assert_eq!(
+            repo.files("master").unwrap().collect::<Vec<_>>(),
+            vec!["x.txt"]
+        );
+    }
+}
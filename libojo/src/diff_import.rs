@@ -0,0 +1,717 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Turning unified diffs (like the ones written by `diff -u`, `git diff` and `git format-patch`)
+// into patches.
+//
+// A unified diff doesn't say which lines it changes, only what they (and a few lines around them)
+// look like, so each hunk has to be found in the current contents of its file. Like `patch`, we
+// look for the hunk's lines close to where the diff says that they are first, since the file may
+// have changed above them. If they aren't anywhere, we try again without the outermost lines of
+// context (up to `MAX_FUZZ` of them on either side), which may have changed too. Once the new
+// contents of a file are known, it is diffed against the old ones in the usual way.
+
+use chrono::{DateTime, Utc};
+use std::io::BufRead;
+
+use crate::config::Config;
+use crate::git_import::{edit_changes, last_path, split_path};
+use crate::storage::File;
+use crate::{Change, Changes, Error, FileRef, PatchHeader, PatchId, Repo, DEFAULT_WORKING_FILE};
+
+// The number of lines of context that may be ignored at either end of a hunk.
+const MAX_FUZZ: usize = 2;
+
+// The part of a diff that changes one file.
+#[derive(Debug, Default)]
+struct FileDiff {
+    // The paths of the file before and after the diff (`None` for `/dev/null`).
+    old_path: Option<String>,
+    new_path: Option<String>,
+    // Did the paths come from `---` and `+++` lines (as opposed to a `diff --git` line)?
+    has_paths: bool,
+    new_file: bool,
+    deleted: bool,
+    new_mode: Option<String>,
+    hunks: Vec<Hunk>,
+}
+
+#[derive(Debug)]
+struct Hunk {
+    // The index of the line in the old file that the hunk starts at.
+    old_start: usize,
+    // The lines of the hunk, along with their prefixes (' ', '-' or '+').
+    lines: Vec<(u8, Vec<u8>)>,
+}
+
+impl Hunk {
+    // Looks for the old lines of this hunk in `lines`, not before `min`, ignoring up to `fuzz`
+    // lines of context at either end. Returns the position of the match, the number of lines
+    // that matched and the lines to replace them with.
+    fn find<'a>(
+        &'a self,
+        lines: &[&[u8]],
+        min: usize,
+        offset: isize,
+        fuzz: usize,
+    ) -> Option<(usize, usize, Vec<&'a [u8]>)> {
+        let is_context = |(c, _): &&(u8, Vec<u8>)| *c == b' ';
+        let lead = self.lines.iter().take_while(is_context).count();
+        let trail = self.lines.iter().rev().take_while(is_context).count();
+        let (top, bottom) = (fuzz.min(lead), fuzz.min(trail));
+        // There's no point in trying again if there's no more context to ignore.
+        if fuzz > 0 && top < fuzz && bottom < fuzz || top + bottom >= self.lines.len() {
+            return None;
+        }
+
+        let body = &self.lines[top..(self.lines.len() - bottom)];
+        let old = body
+            .iter()
+            .filter(|(c, _)| *c != b'+')
+            .map(|(_, l)| &l[..])
+            .collect::<Vec<_>>();
+        let new = body
+            .iter()
+            .filter(|(c, _)| *c != b'-')
+            .map(|(_, l)| &l[..])
+            .collect::<Vec<_>>();
+        let max = lines.len().checked_sub(old.len())?;
+        if max < min {
+            return None;
+        }
+        let expected = ((self.old_start + top) as isize + offset).max(min as isize) as usize;
+        let expected = expected.min(max);
+        let matches = |p: usize| lines[p..(p + old.len())] == old[..];
+        for d in 0..=(max - min) {
+            if expected >= min + d && matches(expected - d) {
+                return Some((expected - d, old.len(), new));
+            }
+            if expected + d <= max && matches(expected + d) {
+                return Some((expected + d, old.len(), new));
+            }
+        }
+        None
+    }
+}
+
+// Applies the hunks of a diff to a file.
+fn apply_hunks(old: &File, hunks: &[Hunk], path: &str) -> Result<Vec<u8>, Error> {
+    let lines = (0..old.num_nodes())
+        .map(|i| old.node(i))
+        .collect::<Vec<_>>();
+    let mut ret = Vec::new();
+    let mut pos = 0;
+    // How far the hunks were from where the diff said they'd be.
+    let mut offset = 0;
+    for (i, hunk) in hunks.iter().enumerate() {
+        let (at, len, new) = (0..=MAX_FUZZ)
+            .find_map(|fuzz| hunk.find(&lines, pos, offset, fuzz))
+            .ok_or_else(|| Error::HunkFailed(path.to_owned(), i + 1))?;
+        for line in lines[pos..at].iter().chain(&new) {
+            ret.extend_from_slice(line);
+        }
+        pos = at + len;
+        offset = at as isize - hunk.old_start as isize;
+    }
+    for line in &lines[pos..] {
+        ret.extend_from_slice(line);
+    }
+    Ok(ret)
+}
+
+struct Parser {
+    lines: Vec<Vec<u8>>,
+    // The number of lines that have been read.
+    pos: usize,
+}
+
+impl Parser {
+    fn error<T>(&self, msg: &str) -> Result<T, Error> {
+        Err(Error::DiffSyntax(self.pos, msg.to_owned()))
+    }
+
+    fn peek(&self) -> Option<&[u8]> {
+        self.lines.get(self.pos).map(|l| &l[..])
+    }
+
+    // Returns the next line, without its line ending.
+    fn next_line(&mut self) -> Option<String> {
+        let line = self.peek()?;
+        let line = String::from_utf8_lossy(line)
+            .trim_end_matches(&['\n', '\r'][..])
+            .to_owned();
+        self.pos += 1;
+        Some(line)
+    }
+
+    // If the diff starts with the headers of an email (as written by `git format-patch`), reads
+    // the author, date and message from them.
+    fn mail(&mut self, header: &mut PatchHeader) -> Result<(), Error> {
+        match self.peek() {
+            Some(line) if line.starts_with(b"From ") || line.starts_with(b"From: ") => {}
+            _ => return Ok(()),
+        }
+        let mut fields: Vec<(String, String)> = Vec::new();
+        while let Some(line) = self.next_line() {
+            if line.is_empty() {
+                break;
+            }
+            if line.starts_with(|c: char| c.is_whitespace()) {
+                // A header that continues on the next line.
+                if let Some((_, value)) = fields.last_mut() {
+                    value.push(' ');
+                    value.push_str(line.trim());
+                }
+            } else if let Some(colon) = line.find(':') {
+                let value = line[colon + 1..].trim().to_owned();
+                fields.push((line[..colon].to_ascii_lowercase(), value));
+            }
+        }
+
+        let mut body = String::new();
+        while let Some(line) = self.peek() {
+            if line.starts_with(b"---") || line.starts_with(b"diff ") {
+                break;
+            }
+            body.push_str(&self.next_line().unwrap());
+            body.push('\n');
+        }
+
+        for (name, value) in fields {
+            match name.as_str() {
+                "from" => match (value.find('<'), value.rfind('>')) {
+                    (Some(open), Some(close)) if open < close => {
+                        header.author = value[..open].trim().trim_matches('"').to_owned();
+                        header.email = Some(value[open + 1..close].to_owned());
+                    }
+                    _ => header.author = value,
+                },
+                "date" => match DateTime::parse_from_rfc2822(&value) {
+                    Ok(date) => header.timestamp = date.with_timezone(&Utc),
+                    Err(_) => return self.error(&format!("invalid date {:?}", value)),
+                },
+                "subject" => {
+                    // `git format-patch` puts something like "[PATCH 1/2]" in front.
+                    let subject = match value.strip_prefix('[') {
+                        Some(rest) if rest.contains(']') => {
+                            rest[rest.find(']').unwrap() + 1..].trim_start()
+                        }
+                        _ => &value[..],
+                    };
+                    let body = body.trim();
+                    header.description = if body.is_empty() {
+                        subject.to_owned()
+                    } else {
+                        format!("{}\n\n{}", subject, body)
+                    };
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Parses a path on a `---` or `+++` line.
+    fn path(&self, s: &str, prefix: &str) -> Result<Option<String>, Error> {
+        let path = if s.starts_with('"') {
+            match split_path(s) {
+                Some((path, _)) => path,
+                None => return self.error("invalid quoted path"),
+            }
+        } else {
+            // `diff -u` puts the modification time after a tab.
+            s.split('\t').next().unwrap().to_owned()
+        };
+        if path == "/dev/null" {
+            return Ok(None);
+        }
+        Ok(Some(match path.strip_prefix(prefix) {
+            Some(p) => p.to_owned(),
+            None => path,
+        }))
+    }
+
+    // Parses the paths on a `diff --git` line. They can only be told apart if they are quoted,
+    // or if they are the same; otherwise, the paths come from other lines.
+    fn git_paths(&self, s: &str) -> Result<FileDiff, Error> {
+        let mut ret = FileDiff::default();
+        if s.starts_with('"') {
+            match split_path(s) {
+                Some((old, new)) => {
+                    ret.old_path = Some(old.strip_prefix("a/").unwrap_or(&old).to_owned());
+                    ret.new_path = self.path(new, "b/")?;
+                }
+                None => return self.error("invalid quoted path"),
+            }
+        } else if s.len() % 2 == 1 {
+            let (old, new) = (&s[..s.len() / 2], &s[s.len() / 2 + 1..]);
+            if let (Some(old), Some(new)) = (old.strip_prefix("a/"), new.strip_prefix("b/")) {
+                if old == new {
+                    ret.old_path = Some(old.to_owned());
+                    ret.new_path = Some(new.to_owned());
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    fn hunk(&mut self, header: &str) -> Result<Hunk, Error> {
+        // The header looks like "@@ -1,2 +1,3 @@", where the counts are optional.
+        let range = |s: Option<&str>, sign: char| -> Option<(usize, usize)> {
+            let mut parts = s?.strip_prefix(sign)?.splitn(2, ',');
+            let start = parts.next()?.parse().ok()?;
+            let count = match parts.next() {
+                Some(count) => count.parse().ok()?,
+                None => 1,
+            };
+            Some((start, count))
+        };
+        let mut words = header.split(' ').skip(1);
+        let (old, new) = match (range(words.next(), '-'), range(words.next(), '+')) {
+            (Some(old), Some(new)) => (old, new),
+            _ => return self.error("invalid hunk header"),
+        };
+
+        let (mut old_left, mut new_left) = (old.1, new.1);
+        let mut lines: Vec<(u8, Vec<u8>)> = Vec::new();
+        while old_left > 0 || new_left > 0 {
+            let line = match self.peek() {
+                Some(line) => line.to_owned(),
+                None => return self.error("the diff ended in the middle of a hunk"),
+            };
+            self.pos += 1;
+            let (kind, contents) = match line.split_first() {
+                // Some tools remove the space from empty lines of context.
+                Some((b'\n', _)) | Some((b'\r', _)) => (b' ', line),
+                Some((&c, rest)) if c == b' ' || c == b'-' || c == b'+' => (c, rest.to_owned()),
+                Some((b'\\', _)) => {
+                    strip_newline(&mut lines);
+                    continue;
+                }
+                _ => return self.error("invalid line in a hunk"),
+            };
+            match kind {
+                b' ' if old_left > 0 && new_left > 0 => {
+                    old_left -= 1;
+                    new_left -= 1;
+                }
+                b'-' if old_left > 0 => old_left -= 1,
+                b'+' if new_left > 0 => new_left -= 1,
+                _ => return self.error("the hunk is longer than its header says"),
+            }
+            lines.push((kind, contents));
+        }
+        if self.peek().is_some_and(|l| l.starts_with(b"\\")) {
+            self.pos += 1;
+            strip_newline(&mut lines);
+        }
+
+        Ok(Hunk {
+            // If the hunk has no old lines, the diff gives the line before it.
+            old_start: if old.1 == 0 { old.0 } else { old.0 - 1 },
+            lines,
+        })
+    }
+
+    fn files(&mut self) -> Result<Vec<FileDiff>, Error> {
+        let mut ret: Vec<FileDiff> = Vec::new();
+        while let Some(line) = self.next_line() {
+            if let Some(rest) = line.strip_prefix("diff --git ") {
+                ret.push(self.git_paths(rest)?);
+            } else if let Some(rest) = line.strip_prefix("--- ") {
+                match self.peek() {
+                    Some(next) if next.starts_with(b"+++ ") => {}
+                    // This must be something else, like the end of a commit message.
+                    _ => continue,
+                }
+                let next = self.next_line().unwrap();
+                // Unless this belongs to the `diff --git` line before it, it starts a new file.
+                match ret.last() {
+                    Some(file) if !file.has_paths && file.hunks.is_empty() => {}
+                    _ => ret.push(FileDiff::default()),
+                }
+                let old_path = self.path(rest, "a/")?;
+                let new_path = self.path(&next[4..], "b/")?;
+                let file = ret.last_mut().unwrap();
+                file.new_file |= old_path.is_none();
+                file.deleted |= new_path.is_none();
+                file.old_path = old_path.or_else(|| file.old_path.take());
+                file.new_path = new_path.or_else(|| file.new_path.take());
+                file.has_paths = true;
+            } else if line.starts_with("@@ ") {
+                let hunk = self.hunk(&line)?;
+                match ret.last_mut() {
+                    Some(file) if file.has_paths => file.hunks.push(hunk),
+                    _ => return self.error("a hunk needs to come after the paths of its file"),
+                }
+            } else if let Some(file) = ret.last_mut().filter(|f| f.hunks.is_empty()) {
+                // The extended headers of `git diff`.
+                if let Some(path) = line.strip_prefix("rename from ") {
+                    file.old_path = last_path(path);
+                } else if let Some(path) = line.strip_prefix("rename to ") {
+                    file.new_path = last_path(path);
+                } else if let Some(mode) = line.strip_prefix("new file mode ") {
+                    file.new_file = true;
+                    file.new_mode = Some(mode.to_owned());
+                } else if line.starts_with("deleted file mode ") {
+                    file.deleted = true;
+                } else if let Some(mode) = line.strip_prefix("new mode ") {
+                    file.new_mode = Some(mode.to_owned());
+                } else if line.starts_with("copy from ") {
+                    return self.error("copies aren't supported");
+                } else if line.starts_with("Binary files ") || line == "GIT binary patch" {
+                    return self.error("binary diffs aren't supported");
+                }
+            }
+        }
+
+        for file in &ret {
+            if file.old_path.is_none() && file.new_path.is_none() {
+                return self.error("a file in the diff has no path");
+            }
+        }
+        Ok(ret)
+    }
+}
+
+// Handles a "\ No newline at end of file" line, which is about the line before it.
+fn strip_newline(lines: &mut [(u8, Vec<u8>)]) {
+    if let Some((_, line)) = lines.last_mut() {
+        if line.ends_with(b"\n") {
+            line.pop();
+        }
+        if line.ends_with(b"\r") {
+            line.pop();
+        }
+    }
+}
+
+// Returns the changes that a diff makes to the files on a branch.
+fn changes(
+    repo: &Repo,
+    config: &Config,
+    branch: &str,
+    files: &[FileDiff],
+) -> Result<Changes, Error> {
+    let mut ret = Changes { changes: vec![] };
+    let mut file_changes = Vec::new();
+    for diff in files {
+        let path = diff.new_path.as_ref().or(diff.old_path.as_ref()).unwrap();
+        // The lines of the branch itself are written as the default working file.
+        if path == DEFAULT_WORKING_FILE && repo.file_id(branch, path).is_err() {
+            let new = apply_hunks(&repo.file(branch)?, &diff.hunks, path)?;
+            let graggle = repo.graggle(branch)?;
+            let changes = edit_changes(repo, config, path, Some(graggle), &new);
+            ret.changes.extend(changes.changes);
+            continue;
+        }
+
+        let executable = diff.new_mode.as_ref().map(|m| m == "100755");
+        let symlink = diff.new_mode.as_ref().map(|m| m == "120000");
+        let old_path = diff.old_path.as_ref().filter(|_| !diff.new_file);
+        let new_path = diff.new_path.as_ref().filter(|_| !diff.deleted);
+        match (old_path, new_path) {
+            (None, Some(path)) => {
+                ret.changes.push(Change::NewFile { path: path.clone() });
+                let file = FileRef {
+                    patch: PatchId::staging(),
+                    path: path.clone(),
+                };
+                if executable == Some(true) {
+                    let executable = true;
+                    ret.changes.push(Change::SetExecutable {
+                        file: file.clone(),
+                        executable,
+                    });
+                }
+                if symlink == Some(true) {
+                    let symlink = true;
+                    ret.changes.push(Change::SetSymlink {
+                        file: file.clone(),
+                        symlink,
+                    });
+                }
+                let new = apply_hunks(&File::from_bytes(b""), &diff.hunks, path)?;
+                file_changes.push((file, edit_changes(repo, config, path, None, &new)));
+            }
+            (Some(path), None) => {
+                // The hunks don't matter, but they should still apply.
+                apply_hunks(&repo.file_at(branch, path)?, &diff.hunks, path)?;
+                let file = repo.file_ref(branch, path)?;
+                ret.changes.push(Change::DeleteFile { file });
+            }
+            (Some(from), Some(to)) => {
+                let id = repo.file_id(branch, from)?;
+                let mut file = repo.file_ref(branch, from)?;
+                if from != to {
+                    ret.changes.push(Change::MoveFile {
+                        from: file,
+                        to: to.clone(),
+                    });
+                    file = FileRef {
+                        patch: PatchId::staging(),
+                        path: to.clone(),
+                    };
+                }
+                match executable {
+                    Some(executable) if executable != repo.is_executable(branch, from)? => {
+                        ret.changes.push(Change::SetExecutable {
+                            file: file.clone(),
+                            executable,
+                        });
+                        file.patch = PatchId::staging();
+                    }
+                    _ => {}
+                }
+                match symlink {
+                    Some(symlink) if symlink != repo.is_symlink(branch, from)? => {
+                        ret.changes.push(Change::SetSymlink { file, symlink });
+                    }
+                    _ => {}
+                }
+                if !diff.hunks.is_empty() {
+                    let new = apply_hunks(&repo.file_at(branch, from)?, &diff.hunks, to)?;
+                    let graggle = repo.file_graggle(branch, from)?;
+                    file_changes.push((id, edit_changes(repo, config, to, Some(graggle), &new)));
+                }
+            }
+            (None, None) => return Err(Error::InvalidPath(path.clone())),
+        }
+    }
+    for (id, changes) in file_changes {
+        if !changes.changes.is_empty() {
+            ret.add_file_changes(id, changes);
+        }
+    }
+    if ret.changes.is_empty() {
+        return Err(Error::NoChanges);
+    }
+    Ok(ret)
+}
+
+// Returns the changes that a diff, split into lines, makes to the files on a branch, or `None` if
+// the diff doesn't change any files. Syntax errors give their line numbers as if the first line of
+// the diff came after `offset` other lines.
+pub(crate) fn diff_changes(
+    repo: &Repo,
+    config: &Config,
+    branch: &str,
+    lines: Vec<Vec<u8>>,
+    offset: usize,
+) -> Result<Option<Changes>, Error> {
+    let mut parser = Parser { lines, pos: 0 };
+    let files = parser.files().map_err(|e| match e {
+        Error::DiffSyntax(line, msg) => Error::DiffSyntax(line + offset, msg),
+        e => e,
+    })?;
+    if files.is_empty() {
+        return Ok(None);
+    }
+    match changes(repo, config, branch, &files) {
+        Ok(changes) => Ok(Some(changes)),
+        Err(Error::NoChanges) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub(crate) fn apply<R: BufRead>(
+    repo: &mut Repo,
+    branch: &str,
+    mut header: PatchHeader,
+    mut input: R,
+) -> Result<PatchId, Error> {
+    let mut lines = Vec::new();
+    loop {
+        let mut line = Vec::new();
+        if input.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        lines.push(line);
+    }
+    let mut parser = Parser { lines, pos: 0 };
+    parser.mail(&mut header)?;
+    let files = parser.files()?;
+    if files.is_empty() {
+        return Err(Error::DiffSyntax(
+            parser.pos,
+            "there are no files in the diff".to_owned(),
+        ));
+    }
+
+    let changes = changes(repo, &repo.config(), branch, &files)?;
+    repo.transaction(|repo| {
+        let id = repo.create_patch_with_header(header, changes)?;
+        repo.apply_patch(branch, &id)?;
+        Ok(id)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemoryWorkingCopy, RecordOptions};
+
+    fn header() -> PatchHeader {
+        PatchHeader::new("Author".to_owned(), "Msg".to_owned())
+    }
+
+    fn numbers(lines: std::ops::RangeInclusive<u32>) -> String {
+        lines.map(|i| format!("{}\n", i)).collect()
+    }
+
+    fn repo_with(files: &[(&str, &str)]) -> Repo {
+        let wc = MemoryWorkingCopy::new();
+        let mut repo = Repo::init_in_memory(wc.clone());
+        for (path, contents) in files {
+            wc.write(path, contents.as_bytes());
+            repo.track_file(path).unwrap();
+        }
+        repo.record("Author", "Msg", &RecordOptions::default())
+            .unwrap();
+        repo
+    }
+
+    fn contents(repo: &Repo, path: &str) -> String {
+        String::from_utf8(repo.file_at("master", path).unwrap().as_bytes().to_owned()).unwrap()
+    }
+
+    #[test]
+    fn offset_and_fuzz() {
+        // The file has two more lines at the top than the diff expects, and one of the lines of
+        // context at the end of the second hunk has changed.
+        let mut repo = repo_with(&[("a.txt", &format!("a\nb\n{}", numbers(1..=20)))]);
+        let diff = "--- a.txt\t2019-01-01 00:00:00\n+++ a.txt\t2019-01-02 00:00:00\n\
+                    @@ -2,3 +2,3 @@\n 2\n-3\n+three\n 4\n\
+                    @@ -15,7 +15,6 @@\n 15\n 16\n 17\n-18\n 19\n 20\n X\n";
+        repo.apply_unified_diff("master", header(), diff.as_bytes())
+            .unwrap();
+        let expected = numbers(1..=20)
+            .replacen("3\n", "three\n", 1)
+            .replace("18\n", "");
+        assert_eq!(contents(&repo, "a.txt"), format!("a\nb\n{}", expected));
+
+        let diff = "--- a/a.txt\n+++ b/a.txt\n@@ -1,3 +1,3 @@\n x\n-y\n+z\n w\n";
+        match repo.apply_unified_diff("master", header(), diff.as_bytes()) {
+            Err(Error::HunkFailed(path, 1)) => assert_eq!(path, "a.txt"),
+            x => panic!("expected a failed hunk, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn format_patch() {
+        let mut repo = repo_with(&[("a.txt", "a\n"), ("b.txt", "b\n"), ("c.txt", "c\n")]);
+        let diff = "From 0123456789abcdef Mon Sep 17 00:00:00 2001
+From: Alice Example <alice@example.com>
+Date: Wed, 2 Jan 2019 00:00:00 +0000
+Subject: [PATCH] Move some files
+ around
+
+Explain why.
+---
+ a.txt | 1 +
+ 2 files changed
+
+diff --git a/a.txt b/a.txt
+index 78981922..422c2b7a 100644
+--- a/a.txt
++++ b/a.txt
+@@ -1 +1,2 @@
+ a
++b
+diff --git a/b.txt b/d.txt
+old mode 100644
+new mode 100755
+similarity index 100%
+rename from b.txt
+rename to d.txt
+diff --git a/c.txt b/c.txt
+deleted file mode 100644
+--- a/c.txt
++++ /dev/null
+@@ -1 +0,0 @@
+-c
+diff --git a/new file.txt b/new file.txt
+new file mode 100644
+--- /dev/null
++++ b/new file.txt
+@@ -0,0 +1 @@
++new
+\\ No newline at end of file
+--
+2.20.1
+";
+        let id = repo
+            .apply_unified_diff("master", header(), diff.as_bytes())
+            .unwrap();
+        let header = repo.patch_header(&id).unwrap();
+        assert_eq!(header.author, "Alice Example");
+        assert_eq!(header.email.as_deref(), Some("alice@example.com"));
+        assert_eq!(header.description, "Move some files around\n\nExplain why.");
+        assert_eq!(header.timestamp.to_rfc3339(), "2019-01-02T00:00:00+00:00");
+
+        assert_eq!(
+            repo.files("master").unwrap().collect::<Vec<_>>(),
+            vec!["a.txt", "d.txt", "new file.txt"]
+        );
+        assert_eq!(contents(&repo, "a.txt"), "a\nb\n");
+        assert_eq!(contents(&repo, "d.txt"), "b\n");
+        assert!(repo.is_executable("master", "d.txt").unwrap());
+        assert_eq!(contents(&repo, "new file.txt"), "new");
+    }
+
+    #[test]
+    fn round_trip() {
+        let wc = MemoryWorkingCopy::new();
+        let mut repo = Repo::init_in_memory(wc.clone());
+        wc.write(DEFAULT_WORKING_FILE, b"a\nb\n");
+        wc.write("x.txt", b"x\n");
+        repo.track_file("x.txt").unwrap();
+        repo.record("Author", "Msg", &RecordOptions::default())
+            .unwrap();
+        repo.clone_branch("master", "other").unwrap();
+        wc.write(DEFAULT_WORKING_FILE, b"a\nc\n");
+        wc.write("x.txt", b"x\ny\n");
+        let id = repo
+            .record("Author", "Msg", &RecordOptions::default())
+            .unwrap();
+
+        let diff = repo
+            .open_patch(&id)
+            .unwrap()
+            .to_unified_diff(&repo)
+            .unwrap();
+        repo.apply_unified_diff("other", header(), diff.as_bytes())
+            .unwrap();
+        assert_eq!(repo.file("other").unwrap().as_bytes(), b"a\nc\n");
+        assert_eq!(
+            repo.file_at("other", "x.txt").unwrap().as_bytes(),
+            b"x\ny\n"
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let mut repo = repo_with(&[("a.txt", "a\n")]);
+        let mut apply = |diff: &str| repo.apply_unified_diff("master", header(), diff.as_bytes());
+        match apply("Just some text\n") {
+            Err(Error::DiffSyntax(_, _)) => {}
+            x => panic!("expected a syntax error, got {:?}", x),
+        }
+        match apply("--- a/a.txt\n+++ b/a.txt\n@@ -1 +1,2 @@\n a\n") {
+            Err(Error::DiffSyntax(4, _)) => {}
+            x => panic!("expected a syntax error, got {:?}", x),
+        }
+        match apply("--- a/b.txt\n+++ b/b.txt\n@@ -1 +1 @@\n-a\n+b\n") {
+            Err(Error::UnknownFile(path)) => assert_eq!(path, "b.txt"),
+            x => panic!("expected an unknown file, got {:?}", x),
+        }
+    }
+}
@@ -0,0 +1,164 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// On-disk encryption of patches.
+//
+// If the embedder provides a key, everything that reveals the contents of the files (the patches,
+// the zstd dictionary that was trained on them, and the cached contents of the branches) is
+// encrypted with ChaCha20-Poly1305 before being written out (see `compress::PatchStore`). The
+// patch headers, the dependencies between patches and the shapes of the graggles stay in the
+// clear, so that the history can still be examined without the key.
+//
+// Every piece of encrypted data is stored together with its nonce. Rather than generating nonces
+// randomly, we derive them by hashing the key together with the data: a nonce can only be reused
+// for the same data, which doesn't leak anything (except the fact that the data is the same).
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+use crate::Error;
+
+const NONCE_LEN: usize = 12;
+
+/// A key for encrypting the patches in a repository (see [`Repo::open_encrypted`]).
+///
+/// [`Repo::open_encrypted`]: crate::Repo::open_encrypted
+#[derive(Clone, Eq, PartialEq)]
+pub struct EncryptionKey {
+    key: [u8; 32],
+}
+
+impl EncryptionKey {
+    /// Creates a key from 32 bytes, which should be chosen uniformly at random.
+    pub fn from_bytes(key: [u8; 32]) -> EncryptionKey {
+        EncryptionKey { key }
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(&Key::from(self.key))
+    }
+
+    // Encrypts some data, returning the nonce followed by the ciphertext.
+    //
+    // The associated data isn't encrypted, but decryption will fail unless the same associated
+    // data is provided. We use it to make sure that encrypted data can't be moved around (for
+    // example, by swapping the contents of two patches).
+    pub(crate) fn seal(&self, aad: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut hasher = Sha256::default();
+        hasher.input(&self.key);
+        hasher.input(&(aad.len() as u64).to_le_bytes());
+        hasher.input(aad);
+        hasher.input(data);
+        let hash = hasher.result();
+        let nonce = Nonce::from_slice(&hash[..NONCE_LEN]);
+
+        let mut ret = nonce.to_vec();
+        let ciphertext = self
+            .cipher()
+            .encrypt(nonce, Payload { msg: data, aad })
+            .expect("encryption failed");
+        ret.extend_from_slice(&ciphertext);
+        ret
+    }
+
+    // The inverse of `seal`.
+    pub(crate) fn unseal(&self, aad: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+        if data.len() < NONCE_LEN {
+            return Err(Error::Decryption);
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        self.cipher()
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| Error::Decryption)
+    }
+}
+
+// Don't print out the key by accident.
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EncryptionKey(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::tmp_dir;
+    use crate::Repo;
+
+    #[test]
+    fn seal() {
+        let key = EncryptionKey::from_bytes([1; 32]);
+        let sealed = key.seal(b"aad", b"secret");
+        assert!(!sealed.windows(6).any(|w| w == b"secret"));
+        assert_eq!(key.unseal(b"aad", &sealed).unwrap(), b"secret");
+        // Sealing is deterministic.
+        assert_eq!(key.seal(b"aad", b"secret"), sealed);
+        assert_ne!(
+            &key.seal(b"aad", b"secreT")[..NONCE_LEN],
+            &sealed[..NONCE_LEN]
+        );
+
+        let other_key = EncryptionKey::from_bytes([2; 32]);
+        assert!(other_key.unseal(b"aad", &sealed).is_err());
+        assert!(key.unseal(b"other aad", &sealed).is_err());
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(key.unseal(b"aad", &tampered).is_err());
+        assert!(key.unseal(b"aad", &sealed[..4]).is_err());
+    }
+
+    #[test]
+    fn encrypted_repo() {
+        let dir = tmp_dir("encrypt");
+        let key = EncryptionKey::from_bytes([1; 32]);
+        let mut repo = Repo::init(&dir).unwrap();
+        let diff = repo.diff("master", b"top secret\n").unwrap();
+        let id = repo.create_patch("Author", "Msg", diff.changes()).unwrap();
+        repo.apply_patch("master", &id).unwrap();
+        repo.set_encryption_key(Some(key.clone()));
+        repo.write().unwrap();
+
+        let db = std::fs::read(&repo.db_path).unwrap();
+        assert!(!db.windows(6).any(|w| w == b"secret"));
+        match Repo::open(&dir) {
+            Err(Error::MissingKey) => {}
+            x => panic!("expected an error, got {:?}", x),
+        }
+        let wrong_key = EncryptionKey::from_bytes([2; 32]);
+        match Repo::open_encrypted(&dir, wrong_key) {
+            Err(Error::Decryption) => {}
+            x => panic!("expected an error, got {:?}", x),
+        }
+
+        // The metadata is still available without decrypting anything.
+        let mut repo = Repo::open_encrypted(&dir, key).unwrap();
+        assert!(repo.is_encrypted());
+        assert_eq!(repo.patch_header(&id).unwrap().author, "Author");
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"top secret\n");
+        assert_eq!(repo.open_patch(&id).unwrap().id(), &id);
+
+        // Removing the key decrypts the repository.
+        repo.set_encryption_key(None);
+        repo.write().unwrap();
+        let repo = Repo::open(&dir).unwrap();
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"top secret\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
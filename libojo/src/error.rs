@@ -18,7 +18,7 @@ use std::ffi::OsString;
 use std::path::PathBuf;
 use std::{self, fmt, io};
 
-use crate::{NodeId, PatchId};
+use crate::{BlobHash, HookPoint, HunkId, NodeId, PatchId};
 
 #[derive(Debug)]
 pub enum PatchIdError {
@@ -62,48 +62,278 @@ impl std::error::Error for PatchIdError {
 
 #[derive(Debug)]
 pub enum Error {
+    AlreadyTracked(String),
+    AmbiguousPatchPrefix(String, Vec<PatchId>),
+    BinaryConflict(PatchId),
+    Bincode(bincode::Error),
+    Bisecting,
     BranchExists(String),
     CurrentBranch(String),
+    CurrentWorktree(PathBuf),
+    DarcsPatch(usize, String),
     DbCorruption,
+    Decryption,
+    DependencyOrder(PatchId, PatchId),
+    DiffSyntax(usize, String),
+    EditedConflict(String),
     Encoding(std::string::FromUtf8Error),
+    FastExportSyntax(usize, String),
+    FileConflict(PatchId, String),
+    GhostPatch(PatchId),
+    GitDiverged(String),
+    GitMarks(String),
+    HasConflicts(String),
+    HasDependents(PatchId, Vec<PatchId>),
+    HgExport(usize, String),
+    HookFailed(HookPoint, String),
+    HunkFailed(String, usize),
     IdMismatch(PatchId, PatchId),
+    IgnoredPath(String),
+    InvalidChunks(BlobHash),
+    InvalidConfig(PathBuf, String),
+    InvalidFileEdit(PatchId),
+    InvalidObsoleteMarker(PatchId, PatchId),
+    InvalidOrdering(PatchId),
+    InvalidPath(String),
+    InvalidRemote(String),
+    InvalidResolution(PatchId),
     Io(io::Error, String),
+    Locked(PathBuf),
+    MboxSyntax(usize, String),
     MissingDep(PatchId),
+    MissingKey,
+    MultipleBinaryChanges(PatchId),
+    NoBisection,
+    NoChanges,
     NoFilename(PathBuf),
     NoParent(PathBuf),
+    NoStash,
     NonUtfFilename(OsString),
+    NotABundle,
+    NotAPatchFile,
+    NotInConflict(NodeId),
+    NotOnBranch(PatchId, String),
     NotOrdered,
+    NothingToBisect(String),
+    OrderConflict(NodeId, NodeId),
     PatchId(PatchIdError),
+    PatchSyntax(usize, String),
+    PijulChange(usize, String),
+    PublishedPatch(PatchId),
+    ReadOnly(PathBuf),
+    Remote(String),
+    RemoteExists(String),
+    RepoChanged(PathBuf),
     RepoExists(PathBuf),
     RepoNotFound(PathBuf),
     Serde(serde_yaml::Error),
+    SvnDump(usize, String),
+    TagExists(String),
+    TruncatedPatchFile,
+    UnappliedDeps(PatchId, Vec<PatchId>),
+    UnknownBlob(BlobHash),
     UnknownBranch(String),
+    UnknownFile(String),
+    UnknownHunk(HunkId),
+    UnknownInode(u64),
     UnknownNode(NodeId),
     UnknownPatch(PatchId),
+    UnknownPatchPrefix(String),
+    UnknownRemote(String),
+    UnknownTag(String),
+    UnknownWorktree(PathBuf),
+    UnrecordedChanges(String),
+    UnsupportedBundleVersion(u32),
+    UnsupportedPatchVersion(u32),
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            Error::AlreadyTracked(path) => write!(f, "The file {} is already tracked", path),
+            Error::AmbiguousPatchPrefix(prefix, candidates) => {
+                write!(f, "The prefix \"{}\" matches several patches:", prefix)?;
+                for c in candidates {
+                    write!(f, " {}", c.to_base64())?;
+                }
+                Ok(())
+            }
+            Error::BinaryConflict(p) => write!(
+                f,
+                "Patch {} replaces binary contents that aren't on the branch",
+                p.to_base64()
+            ),
+            Error::Bincode(e) => e.fmt(f),
+            Error::Bisecting => write!(f, "There is already a bisection in progress"),
             Error::BranchExists(b) => write!(f, "The branch \"{}\" already exists", b),
             Error::CurrentBranch(b) => write!(f, "\"{}\" is the current branch", b),
+            Error::CurrentWorktree(p) => write!(f, "The worktree at {:?} is the one in use", p),
             Error::DbCorruption => write!(f, "Found corruption in the database"),
+            Error::Decryption => write!(
+                f,
+                "Failed to decrypt the repository: either the key is wrong or the data is corrupt"
+            ),
+            Error::DependencyOrder(p, dep) => write!(
+                f,
+                "Patch {} depends on {}, which comes after it",
+                p.to_base64(),
+                dep.to_base64()
+            ),
+            Error::DarcsPatch(line, msg) => {
+                write!(f, "Invalid darcs patch at line {}: {}", line, msg)
+            }
+            Error::DiffSyntax(line, msg) => {
+                write!(f, "Invalid unified diff at line {}: {}", line, msg)
+            }
+            Error::EditedConflict(path) => write!(
+                f,
+                "The file {} has conflict markers and local changes, so it can't be reverted",
+                path
+            ),
             Error::Encoding(e) => e.fmt(f),
+            Error::FastExportSyntax(line, msg) => write!(
+                f,
+                "Invalid git fast-export stream at line {}: {}",
+                line, msg
+            ),
+            Error::FileConflict(p, path) => write!(
+                f,
+                "Patch {} conflicts with the file at {:?}",
+                p.to_base64(),
+                path
+            ),
+            Error::GhostPatch(p) => write!(
+                f,
+                "Patch {} hasn't been downloaded (this is a shallow clone)",
+                p.to_base64()
+            ),
+            Error::GitDiverged(b) => write!(
+                f,
+                "The branch \"{}\" has diverged from the git branch that it is synced with",
+                b
+            ),
+            Error::GitMarks(msg) => write!(f, "Invalid git marks file: {}", msg),
+            Error::HasConflicts(path) => write!(f, "There are conflicts in {}", path),
+            Error::HasDependents(p, deps) => {
+                write!(f, "Patch {} is needed by:", p.to_base64())?;
+                for d in deps {
+                    write!(f, " {}", d.to_base64())?;
+                }
+                Ok(())
+            }
+            Error::HgExport(line, msg) => {
+                write!(f, "Invalid hg export at line {}: {}", line, msg)
+            }
+            Error::HookFailed(point, msg) => write!(f, "The {} hook failed: {}", point, msg),
+            Error::HunkFailed(path, hunk) => {
+                write!(f, "Hunk {} of the diff to {} doesn't apply", hunk, path)
+            }
             Error::IdMismatch(actual, expected) => write!(
                 f,
                 "Expected {}, found {}",
                 expected.to_base64(),
                 actual.to_base64()
             ),
+            Error::IgnoredPath(path) => write!(f, "The path {} is ignored", path),
+            Error::InvalidChunks(h) => write!(
+                f,
+                "The chunks of the binary contents with hash {} don't fit together",
+                h.to_base64()
+            ),
+            Error::InvalidConfig(path, msg) => {
+                write!(f, "The configuration file {:?} is invalid: {}", path, msg)
+            }
+            Error::InvalidFileEdit(p) => write!(
+                f,
+                "Patch {} changes the lines of a file, but it does more than add and delete lines",
+                p.to_base64()
+            ),
+            Error::InvalidObsoleteMarker(obsolete, successor) => write!(
+                f,
+                "Patch {} can't be made obsolete by {}, which depends on it or is made obsolete by it",
+                obsolete.to_base64(),
+                successor.to_base64()
+            ),
+            Error::InvalidOrdering(p) => write!(
+                f,
+                "Patch {} only orders lines, but it does more than add edges",
+                p.to_base64()
+            ),
+            Error::InvalidPath(path) => write!(
+                f,
+                "{:?} is not a valid path relative to the root of the repository",
+                path
+            ),
+            Error::InvalidRemote(r) => write!(f, "\"{}\" is not a valid remote address", r),
+            Error::InvalidResolution(p) => write!(
+                f,
+                "Patch {} is a conflict resolution, but it does more than reorder and delete lines",
+                p.to_base64()
+            ),
             Error::Io(e, msg) => write!(f, "I/O error: {}. Details: {}", msg, e),
+            Error::Locked(p) => write!(
+                f,
+                "The repository is being written by another process (if there isn't one, remove {:?})",
+                p
+            ),
+            Error::MboxSyntax(line, msg) => {
+                write!(f, "Invalid mbox file at line {}: {}", line, msg)
+            }
             Error::MissingDep(id) => write!(f, "Missing a dependency: {}", id.to_base64()),
+            Error::MissingKey => write!(f, "This repository is encrypted, but no key was given"),
+            Error::MultipleBinaryChanges(p) => write!(
+                f,
+                "Patch {} replaces the binary contents more than once",
+                p.to_base64()
+            ),
+            Error::NoBisection => write!(f, "There is no bisection in progress"),
+            Error::NoChanges => write!(f, "There are no changes to record"),
             Error::NoFilename(p) => write!(f, "This path didn't end in a filename: {:?}", p),
             Error::NoParent(p) => write!(f, "I could not find the parent directory of: {:?}", p),
+            Error::NoStash => write!(f, "There are no stashed changes"),
             Error::NonUtfFilename(p) => {
                 write!(f, "This filename couldn't be converted to UTF-8: {:?}", p)
             }
+            Error::NotABundle => write!(f, "This is not a bundle file"),
+            Error::NotAPatchFile => write!(f, "This is not a patch file"),
+            Error::NotInConflict(n) => write!(f, "Node {:?} isn't part of the conflict", n),
+            Error::NotOnBranch(p, b) => write!(
+                f,
+                "Patch {} isn't on the branch \"{}\"",
+                p.to_base64(),
+                b
+            ),
             Error::NotOrdered => write!(f, "The data does not represent a totally ordered file"),
+            Error::NothingToBisect(b) => {
+                write!(f, "All the patches on the branch \"{}\" are good", b)
+            }
+            Error::OrderConflict(a, b) => write!(
+                f,
+                "Node {:?} can't come before {:?}, because it already comes after it",
+                a, b
+            ),
             Error::PatchId(e) => write!(f, "Found a broken PatchId\n\tcaused by: {}", e),
+            Error::PatchSyntax(line, msg) => write!(f, "Syntax error on line {}: {}", line, msg),
+            Error::PijulChange(line, msg) => {
+                write!(f, "Invalid pijul change at line {}: {}", line, msg)
+            }
+            Error::PublishedPatch(p) => write!(
+                f,
+                "Patch {} has been published, so it can't be rewritten",
+                p.to_base64()
+            ),
+            Error::ReadOnly(p) => write!(
+                f,
+                "The repository at {:?} was opened read-only, so it can't be changed",
+                p
+            ),
+            Error::Remote(msg) => write!(f, "The remote repository reported an error: {}", msg),
+            Error::RemoteExists(r) => write!(f, "There is already a remote named \"{}\"", r),
+            Error::RepoChanged(p) => write!(
+                f,
+                "The repository at {:?} was changed by another process since it was opened",
+                p
+            ),
             Error::RepoExists(p) => write!(f, "There is already a repository in {:?}", p),
             Error::RepoNotFound(p) => write!(
                 f,
@@ -111,9 +341,44 @@ impl fmt::Display for Error {
                 p
             ),
             Error::Serde(e) => e.fmt(f),
+            Error::SvnDump(line, msg) => {
+                write!(f, "Invalid svn dump at line {}: {}", line, msg)
+            }
+            Error::TagExists(t) => write!(f, "The tag \"{}\" already exists", t),
+            Error::TruncatedPatchFile => write!(f, "The patch file ended unexpectedly"),
+            Error::UnappliedDeps(p, deps) => {
+                write!(f, "Patch {} depends on patches that aren't applied:", p.to_base64())?;
+                for d in deps {
+                    write!(f, " {}", d.to_base64())?;
+                }
+                Ok(())
+            }
+            Error::UnknownBlob(h) => {
+                write!(
+                    f,
+                    "There are no binary contents with hash {}",
+                    h.to_base64()
+                )
+            }
             Error::UnknownBranch(b) => write!(f, "There is no branch named {:?}", b),
+            Error::UnknownFile(path) => write!(f, "There is no file at {:?}", path),
+            Error::UnknownHunk(h) => write!(f, "There is no hunk with id {}", h),
+            Error::UnknownInode(i) => write!(f, "There is no inode {} in the mounted history", i),
             Error::UnknownNode(n) => write!(f, "There is no node with id {:?}", n),
             Error::UnknownPatch(p) => write!(f, "There is no patch with hash {:?}", p.to_base64()),
+            Error::UnknownPatchPrefix(p) => write!(f, "There is no patch starting with {:?}", p),
+            Error::UnknownRemote(r) => write!(f, "There is no remote named \"{}\"", r),
+            Error::UnknownTag(t) => write!(f, "There is no tag named {:?}", t),
+            Error::UnknownWorktree(p) => write!(f, "There is no linked worktree at {:?}", p),
+            Error::UnrecordedChanges(path) => {
+                write!(f, "The file {} has changes that haven't been recorded", path)
+            }
+            Error::UnsupportedBundleVersion(v) => {
+                write!(f, "Unsupported version of the bundle file format: {}", v)
+            }
+            Error::UnsupportedPatchVersion(v) => {
+                write!(f, "Unsupported version of the patch file format: {}", v)
+            }
         }
     }
 }
@@ -121,6 +386,7 @@ impl fmt::Display for Error {
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
+            Error::Bincode(e) => Some(e),
             Error::Encoding(e) => Some(e),
             Error::Io(e, _) => Some(e),
             Error::PatchId(e) => Some(e),
@@ -148,6 +414,12 @@ impl From<(io::Error, &'static str)> for Error {
     }
 }
 
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Error {
+        Error::Bincode(e)
+    }
+}
+
 impl From<serde_yaml::Error> for Error {
     fn from(e: serde_yaml::Error) -> Error {
         Error::Serde(e)
@@ -0,0 +1,133 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Garbage collection: forgetting the patches that nothing needs any more.
+
+use chrono::{DateTime, Utc};
+use std::collections::BTreeSet;
+
+use crate::progress::{patch_size, Reporter};
+use crate::tag::TAG_KEY;
+use crate::{PatchId, Phase, Repo};
+
+// Returns the patches that must be kept: the ones that are on some branch, the stashes, the tags,
+// the ones created after `cutoff` (or all of them, if there is no cutoff), and everything that
+// those depend on.
+fn live_patches(repo: &Repo, cutoff: Option<DateTime<Utc>>) -> BTreeSet<PatchId> {
+    let storage = &repo.storage;
+    let mut stack = storage
+        .branches()
+        .flat_map(|b| storage.branch_patches.get(b))
+        .chain(&storage.stashes)
+        .cloned()
+        .collect::<Vec<_>>();
+    for id in storage.patches.keys().chain(&storage.ghosts) {
+        // We don't know anything about patches that aren't in the index, so we don't touch them.
+        let keep = storage.patch_index.header(id).is_none_or(|h| {
+            h.extra.contains_key(TAG_KEY) || cutoff.is_none_or(|c| h.timestamp > c)
+        });
+        if keep {
+            stack.push(*id);
+        }
+    }
+
+    let mut ret = BTreeSet::new();
+    while let Some(id) = stack.pop() {
+        if ret.insert(id) {
+            stack.extend(storage.patch_deps.get(&id).cloned());
+        }
+    }
+    ret
+}
+
+// Finds the patches that aren't needed (see `live_patches`), removes them unless `dry_run` is set,
+// and returns them in sorted order.
+pub(crate) fn gc(repo: &mut Repo, cutoff: Option<DateTime<Utc>>, dry_run: bool) -> Vec<PatchId> {
+    let live = live_patches(repo, cutoff);
+    let garbage = repo
+        .storage
+        .patches
+        .keys()
+        .chain(&repo.storage.ghosts)
+        .filter(|id| !live.contains(id))
+        .cloned()
+        .collect::<BTreeSet<_>>();
+    if !dry_run {
+        let mut reporter = Reporter::start(repo, Phase::Gc, garbage.len());
+        let bytes = garbage.iter().map(|id| patch_size(repo, id)).sum();
+        repo.storage.remove_patches(&garbage);
+        if !garbage.is_empty() {
+            reporter.steps(garbage.len(), bytes, None);
+        }
+        reporter.finish();
+    }
+    garbage.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::create;
+    use chrono::Duration;
+
+    // Returns a cutoff that is after all of the patches in the repository.
+    fn later() -> Option<DateTime<Utc>> {
+        Some(Utc::now() + Duration::hours(1))
+    }
+
+    #[test]
+    fn unapplied_patches() {
+        let mut repo = Repo::init_tmp();
+        let a = create(&mut repo, "master", b"a\n");
+        let b = create(&mut repo, "master", b"a\nb\n");
+        let c = create(&mut repo, "master", b"a\nb\nc\n");
+        repo.unapply_patch("master", &b).unwrap();
+        assert!(repo.patches("master").any(|p| *p == a));
+
+        // The unapplied patches are too recent to collect, unless the grace period is short.
+        assert_eq!(repo.gc(std::time::Duration::from_secs(60), false), vec![]);
+
+        let mut garbage = vec![b, c];
+        garbage.sort();
+        assert_eq!(gc(&mut repo, later(), true), garbage);
+        assert!(repo.open_patch(&b).is_ok());
+        assert_eq!(gc(&mut repo, later(), false), garbage);
+        assert!(repo.open_patch(&b).is_err());
+        assert!(repo.patch_header(&c).is_err());
+        assert!(repo.patch_deps(&a).next().is_none());
+        assert!(repo.patch_rev_deps(&a).next().is_none());
+        assert_eq!(repo.all_patches().collect::<Vec<_>>(), vec![&a]);
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"a\n");
+        assert_eq!(gc(&mut repo, later(), false), vec![]);
+    }
+
+    #[test]
+    fn tags() {
+        let mut repo = Repo::init_tmp();
+        let a = create(&mut repo, "master", b"a\n");
+        let b = create(&mut repo, "master", b"a\nb\n");
+        let tag = repo.create_tag("master", "v1", "Author", "Msg").unwrap();
+        create(&mut repo, "master", b"a\nb\nc\n");
+        repo.create_branch("other").unwrap();
+        repo.switch_branch("other").unwrap();
+        repo.delete_branch("master").unwrap();
+
+        // The tag keeps its patches, but the last patch goes.
+        let removed = gc(&mut repo, later(), false);
+        assert_eq!(removed.len(), 1);
+        let mut kept = repo.all_patches().cloned().collect::<Vec<_>>();
+        kept.sort();
+        let mut expected = vec![a, b, tag];
+        expected.sort();
+        assert_eq!(kept, expected);
+        assert_eq!(repo.tag("v1").unwrap().patches().len(), 2);
+    }
+}
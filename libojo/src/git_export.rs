@@ -0,0 +1,301 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Exporting the patches on a branch as a `git fast-import` stream.
+//
+// Git history is a sequence of snapshots, so the patches are put in some order in which they can
+// be applied (the oldest patch whose dependencies have all been applied goes next), and applied
+// one at a time to a scratch branch. Each of them becomes a commit, whose files are the ones on
+// the scratch branch after applying it. Files with conflicts are written out the way that they
+// are rendered, with conflict markers.
+
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::io::Write;
+
+use crate::tracked::branch_paths;
+use crate::{
+    ApplyPolicy, BlobHash, Error, PatchHeader, PatchId, Repo, DEFAULT_WORKING_FILE, GIT_COMMIT_KEY,
+};
+
+// The branch that the patches are applied to, one at a time. Branch names can't normally contain
+// NUL characters, so this can't clash with a real branch.
+pub(crate) const SCRATCH_BRANCH: &str = "\0git-export";
+
+// Puts the patches on a branch in the order that their commits will have.
+pub(crate) fn linearize(repo: &Repo, branch: &str) -> Result<Vec<(PatchId, PatchHeader)>, Error> {
+    let mut headers = HashMap::new();
+    for p in repo.patches(branch) {
+        headers.insert(*p, repo.patch_header(p)?.clone());
+    }
+    // The number of dependencies of each patch that haven't been put in order yet. Since a branch
+    // has all the dependencies of its patches, they are all in `headers`.
+    let mut waiting = headers
+        .keys()
+        .map(|p| (*p, repo.patch_deps(p).count()))
+        .collect::<HashMap<_, _>>();
+    let mut ready = waiting
+        .iter()
+        .filter(|(_, &n)| n == 0)
+        .map(|(p, _)| Reverse((headers[p].timestamp, *p)))
+        .collect::<BinaryHeap<_>>();
+
+    let mut ret = Vec::new();
+    while let Some(Reverse((_, p))) = ready.pop() {
+        for rev_dep in repo.patch_rev_deps(&p) {
+            if let Some(n) = waiting.get_mut(rev_dep) {
+                *n -= 1;
+                if *n == 0 {
+                    ready.push(Reverse((headers[rev_dep].timestamp, *rev_dep)));
+                }
+            }
+        }
+        let header = headers.remove(&p).unwrap();
+        ret.push((p, header));
+    }
+    Ok(ret)
+}
+
+// The mode of a file in a git tree.
+fn mode(executable: bool, symlink: bool) -> &'static str {
+    if symlink {
+        "120000"
+    } else if executable {
+        "100755"
+    } else {
+        "100644"
+    }
+}
+
+// Quotes a path for a fast-import stream, if it needs quoting.
+fn quote_path(path: &str) -> Cow<'_, str> {
+    let needs_quoting = path.starts_with('"')
+        || path
+            .chars()
+            .any(|c| c == '"' || c == '\\' || c.is_ascii_control());
+    if !needs_quoting {
+        return Cow::Borrowed(path);
+    }
+    let mut ret = String::from("\"");
+    for c in path.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\t' => ret.push_str("\\t"),
+            c if c.is_ascii_control() => ret.push_str(&format!("\\{:03o}", c as u8)),
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    Cow::Owned(ret)
+}
+
+fn data<W: Write>(out: &mut W, data: &[u8]) -> Result<(), Error> {
+    writeln!(out, "data {}", data.len())?;
+    out.write_all(data)?;
+    writeln!(out)?;
+    Ok(())
+}
+
+// Returns the contents of a path on the scratch branch, along with their hash and the file's mode.
+fn file(repo: &Repo, path: &str) -> Result<(Vec<u8>, (BlobHash, &'static str)), Error> {
+    let mut contents = Vec::new();
+    let mode = if path == DEFAULT_WORKING_FILE {
+        repo.render(SCRATCH_BRANCH, &mut contents)?;
+        mode(false, false)
+    } else {
+        repo.render_file(SCRATCH_BRANCH, path, &mut contents)?;
+        mode(
+            repo.is_executable(SCRATCH_BRANCH, path)?,
+            repo.is_symlink(SCRATCH_BRANCH, path)?,
+        )
+    };
+    let hash = BlobHash::of(&contents);
+    Ok((contents, (hash, mode)))
+}
+
+// The files of a commit, with their hashes and modes.
+pub(crate) type Files = BTreeMap<String, (BlobHash, &'static str)>;
+
+// Returns the files that are on the scratch branch.
+pub(crate) fn snapshot(repo: &Repo) -> Result<Files, Error> {
+    let mut ret = Files::new();
+    for path in branch_paths(repo, SCRATCH_BRANCH)? {
+        let (_, entry) = file(repo, &path)?;
+        ret.insert(path, entry);
+    }
+    Ok(ret)
+}
+
+// Writes out the commit for a patch, which is applied to the scratch branch. `files` has the
+// files of the previous commit (which is `from`), and gets updated to the files of this one.
+pub(crate) fn commit<W: Write>(
+    repo: &Repo,
+    git_ref: &str,
+    mark: usize,
+    from: Option<String>,
+    header: &PatchHeader,
+    files: &mut Files,
+    out: &mut W,
+) -> Result<(), Error> {
+    writeln!(out, "commit {}", git_ref)?;
+    writeln!(out, "mark :{}", mark)?;
+    if let Some(id) = header.extra.get(GIT_COMMIT_KEY) {
+        writeln!(out, "original-oid {}", id)?;
+    }
+    let person = format!(
+        "{} <{}> {} +0000",
+        header.author,
+        header.email.as_deref().unwrap_or(""),
+        header.timestamp.timestamp()
+    );
+    writeln!(out, "author {}", person)?;
+    writeln!(out, "committer {}", person)?;
+    let mut msg = header.description.clone();
+    if !msg.ends_with('\n') {
+        msg.push('\n');
+    }
+    data(out, msg.as_bytes())?;
+    if let Some(from) = from {
+        writeln!(out, "from {}", from)?;
+    }
+
+    let paths = branch_paths(repo, SCRATCH_BRANCH)?;
+    let removed = files
+        .keys()
+        .filter(|p| !paths.contains(*p))
+        .cloned()
+        .collect::<Vec<_>>();
+    for path in removed {
+        writeln!(out, "D {}", quote_path(&path))?;
+        files.remove(&path);
+    }
+    for path in paths {
+        let (contents, entry) = file(repo, &path)?;
+        if files.get(&path) != Some(&entry) {
+            writeln!(out, "M {} inline {}", entry.1, quote_path(&path))?;
+            data(out, &contents)?;
+            files.insert(path, entry);
+        }
+    }
+    writeln!(out)?;
+    Ok(())
+}
+
+fn export<W: Write>(repo: &mut Repo, branch: &str, mut out: W) -> Result<Vec<PatchId>, Error> {
+    let patches = linearize(repo, branch)?;
+    let git_ref = format!("refs/heads/{}", branch);
+    repo.create_branch(SCRATCH_BRANCH)?;
+    let mut files = BTreeMap::new();
+    let mut ret = Vec::new();
+    for (i, (p, header)) in patches.iter().enumerate() {
+        repo.apply_without_hooks(SCRATCH_BRANCH, p, ApplyPolicy::Refuse)?;
+        let from = if i > 0 { Some(format!(":{}", i)) } else { None };
+        commit(repo, &git_ref, i + 1, from, header, &mut files, &mut out)?;
+        ret.push(*p);
+    }
+    writeln!(out, "done")?;
+    Ok(ret)
+}
+
+pub(crate) fn export_branch<W: Write>(
+    repo: &mut Repo,
+    branch: &str,
+    out: W,
+) -> Result<Vec<PatchId>, Error> {
+    repo.inode(branch)?;
+    // The scratch branch doesn't survive the export, so it's allowed even if the repository was
+    // opened read-only.
+    let readonly = std::mem::replace(&mut repo.readonly, false);
+    let ret = repo.transaction(|repo| {
+        let ret = export(repo, branch, out);
+        if repo.storage.inode(SCRATCH_BRANCH).is_some() {
+            repo.delete_branch(SCRATCH_BRANCH)?;
+        }
+        ret
+    });
+    repo.readonly = readonly;
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemoryWorkingCopy, RecordOptions};
+
+    fn record(repo: &mut Repo, msg: &str) -> PatchId {
+        repo.record("Author", msg, &RecordOptions::default())
+            .unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let wc = MemoryWorkingCopy::new();
+        let mut repo = Repo::init_in_memory(wc.clone());
+        wc.write("a.txt", b"a\n");
+        wc.write("odd \"name\".txt", b"b\n");
+        repo.track_file("a.txt").unwrap();
+        repo.track_file("odd \"name\".txt").unwrap();
+        let first = record(&mut repo, "First");
+        wc.write(DEFAULT_WORKING_FILE, b"lines\n");
+        wc.write("a.txt", b"a\nb\n");
+        repo.untrack_file("odd \"name\".txt").unwrap();
+        wc.remove("odd \"name\".txt");
+        let second = record(&mut repo, "Second\n\nWith a body");
+
+        let mut out = Vec::new();
+        let exported = repo.export_git("master", &mut out).unwrap();
+        assert_eq!(exported, vec![first, second]);
+        assert_eq!(repo.branches().collect::<Vec<_>>(), vec!["master"]);
+        let stream = String::from_utf8(out.clone()).unwrap();
+        assert!(stream.contains("M 100644 inline \"odd \\\"name\\\".txt\"\n"));
+        assert!(stream.contains("D \"odd \\\"name\\\".txt\"\n"));
+        assert!(stream.ends_with("done\n"));
+
+        let mut copy = Repo::init_tmp();
+        let imported = copy.import_git(&out[..]).unwrap();
+        assert_eq!(imported.patches.len(), 2);
+        assert_eq!(
+            copy.files("master").unwrap().collect::<Vec<_>>(),
+            vec!["a.txt", DEFAULT_WORKING_FILE]
+        );
+        assert_eq!(
+            copy.file_at("master", "a.txt").unwrap().as_bytes(),
+            b"a\nb\n"
+        );
+        let header = copy.patch_header(&imported.patches[1].1).unwrap();
+        assert_eq!(header.description, "Second\n\nWith a body");
+    }
+
+    #[test]
+    fn order() {
+        // Patches that don't depend on one another come out oldest first.
+        let wc = MemoryWorkingCopy::new();
+        let mut repo = Repo::init_in_memory(wc.clone());
+        let mut patches = Vec::new();
+        for name in &["b.txt", "a.txt", "c.txt"] {
+            wc.write(name, b"x\n");
+            repo.track_file(name).unwrap();
+            patches.push(record(&mut repo, name));
+        }
+        let exported = repo.export_git("master", std::io::sink()).unwrap();
+        assert_eq!(exported, patches);
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote_path("a b/c.txt"), "a b/c.txt");
+        assert_eq!(quote_path("\"a"), "\"\\\"a\"");
+        assert_eq!(quote_path("a\nb\\\u{1}"), "\"a\\nb\\\\\\001\"");
+    }
+}
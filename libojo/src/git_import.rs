@@ -0,0 +1,1043 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Importing the history of a git repository from the output of `git fast-export`.
+//
+// Each commit becomes a patch that turns the files of its parents into its own files. In git, a
+// commit's files are a snapshot; in ojo, the files on a branch are determined by the set of
+// patches on it. So for every commit, we remember both its files and the set of patches that it
+// corresponds to: the patches of its parents (all of them, for a merge), and the commit's own
+// patch. The commit's patch is made by putting its parents' patches on a scratch branch, and
+// comparing the files there with the commit's files. The dependencies of the patches are worked
+// out from their changes, in the usual way.
+//
+// When syncing with a git repository (see `git_mirror`), the same importer runs in "mirror" mode:
+// the commits that were synced before are skipped (or looked up, if the stream refers to them
+// without having them), and the branches only move forward along the git history.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::BufRead;
+use std::rc::Rc;
+
+use chrono::{DateTime, TimeZone, Utc};
+use ojo_graph::Graph;
+
+use crate::git_mirror::{self, MirroredCommit};
+use crate::patch::UnidentifiedPatch;
+use crate::storage::{File, Graggle};
+use crate::{
+    tag, ApplyPolicy, BlobHash, Change, Changes, Config, Diff, Error, FileRef, PatchHeader,
+    PatchId, Repo,
+};
+
+// The branch that the patches of each commit's parents are put on. Branch names can't normally
+// contain NUL characters, so this can't clash with a real branch.
+const SCRATCH_BRANCH: &str = "\0git-import";
+
+/// The key, in the extra metadata of an imported patch (see [`PatchHeader::extra`]), whose value
+/// is the id of the git commit that the patch was imported from.
+pub const GIT_COMMIT_KEY: &str = "git-commit";
+
+/// What [`Repo::import_git`] imported.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct GitImport {
+    /// The patches that were made from git commits, in the order that they were made, along with
+    /// the commits. A commit is identified by its id if the stream has it (that is, if it was
+    /// exported with `--show-original-ids`), and otherwise by its mark in the stream (like
+    /// `:12`). The commits that didn't change anything (like most merges) don't have patches.
+    pub patches: Vec<(String, PatchId)>,
+    /// The branches that were created or added to.
+    pub branches: Vec<String>,
+    /// The tags that were created.
+    pub tags: Vec<String>,
+}
+
+// A file in a git commit.
+#[derive(Clone, Debug)]
+pub(crate) struct GitFile {
+    pub contents: Rc<[u8]>,
+    pub executable: bool,
+    pub symlink: bool,
+}
+
+pub(crate) type Tree = BTreeMap<String, GitFile>;
+
+// A commit that was imported.
+struct Commit {
+    id: Option<String>,
+    files: Tree,
+    // The patches that make up the commit: its own patch (if it has one), and those of its
+    // ancestors.
+    patches: BTreeSet<PatchId>,
+}
+
+// Reads the lines and the data of a fast-export stream.
+struct Parser<R> {
+    input: R,
+    line_no: usize,
+    // A line that was read, but that turned out to belong to the next command.
+    pending: Option<String>,
+}
+
+impl<R: BufRead> Parser<R> {
+    fn error<T>(&self, msg: &str) -> Result<T, Error> {
+        Err(Error::FastExportSyntax(self.line_no, msg.to_owned()))
+    }
+
+    // Returns the next line, without its line ending, or `None` at the end of the stream.
+    fn next_line(&mut self) -> Result<Option<String>, Error> {
+        if let Some(line) = self.pending.take() {
+            return Ok(Some(line));
+        }
+        let mut buf = Vec::new();
+        if self.input.read_until(b'\n', &mut buf)? == 0 {
+            return Ok(None);
+        }
+        self.line_no += 1;
+        if buf.last() == Some(&b'\n') {
+            buf.pop();
+        }
+        match String::from_utf8(buf) {
+            Ok(line) => Ok(Some(line)),
+            Err(_) => self.error("the line isn't valid UTF-8"),
+        }
+    }
+
+    // Returns the next line if it starts with `prefix`, without the prefix.
+    fn optional(&mut self, prefix: &str) -> Result<Option<String>, Error> {
+        // Data can be followed by an empty line, and commands can be separated by them.
+        let mut line = String::new();
+        while line.is_empty() {
+            line = match self.next_line()? {
+                Some(line) => line,
+                None => return Ok(None),
+            };
+        }
+        match line.strip_prefix(prefix) {
+            Some(rest) => Ok(Some(rest.to_owned())),
+            None => {
+                self.pending = Some(line);
+                Ok(None)
+            }
+        }
+    }
+
+    fn mark(&mut self) -> Result<Option<u64>, Error> {
+        match self.optional("mark :")? {
+            Some(mark) => match mark.parse() {
+                Ok(mark) => Ok(Some(mark)),
+                Err(_) => self.error("invalid mark"),
+            },
+            None => Ok(None),
+        }
+    }
+
+    // Reads a `data` command, and the data after it.
+    fn data(&mut self) -> Result<Vec<u8>, Error> {
+        let len = match self.optional("data ")? {
+            Some(len) => len,
+            None => return self.error("expected data"),
+        };
+        let len = match len.parse::<usize>() {
+            Ok(len) => len,
+            Err(_) => return self.error("only data with an exact length is supported"),
+        };
+        let mut ret = vec![0; len];
+        self.input.read_exact(&mut ret)?;
+        self.line_no += ret.iter().filter(|&&b| b == b'\n').count();
+        Ok(ret)
+    }
+}
+
+// Splits off the first path in `s` (which might be quoted), returning it and the rest of `s`.
+pub(crate) fn split_path(s: &str) -> Option<(String, &str)> {
+    if !s.starts_with('"') {
+        return Some(match s.find(' ') {
+            Some(i) => (s[..i].to_owned(), &s[i + 1..]),
+            None => (s.to_owned(), ""),
+        });
+    }
+
+    // Quoted paths use C-style escapes, with the bytes of non-ASCII characters in octal.
+    let bytes = s.as_bytes();
+    let mut ret = Vec::new();
+    let mut i = 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' => {
+                let rest = s[i + 1..].strip_prefix(' ').unwrap_or(&s[i + 1..]);
+                return Some((String::from_utf8(ret).ok()?, rest));
+            }
+            b'\\' => {
+                let c = *bytes.get(i + 1)?;
+                i += 2;
+                ret.push(match c {
+                    b'n' => b'\n',
+                    b't' => b'\t',
+                    b'a' => 7,
+                    b'b' => 8,
+                    b'f' => 12,
+                    b'r' => b'\r',
+                    b'v' => 11,
+                    b'0'..=b'7' => {
+                        let digits = s.get(i - 1..i + 2)?;
+                        i += 2;
+                        u8::from_str_radix(digits, 8).ok()?
+                    }
+                    c => c,
+                });
+            }
+            b => {
+                ret.push(b);
+                i += 1;
+            }
+        }
+    }
+    None
+}
+
+// Parses a path that is all that's left of a line.
+pub(crate) fn last_path(s: &str) -> Option<String> {
+    if s.starts_with('"') {
+        split_path(s)
+            .filter(|(_, rest)| rest.is_empty())
+            .map(|(p, _)| p)
+    } else {
+        Some(s.to_owned())
+    }
+}
+
+// Parses the name, email and time of an `author`, `committer` or `tagger` command.
+fn parse_person(s: &str) -> Option<(String, String, DateTime<Utc>)> {
+    let open = s.find('<')?;
+    let close = open + s[open..].find('>')?;
+    let name = s[..open].trim().to_owned();
+    let email = s[open + 1..close].to_owned();
+    let secs = s[close + 1..].split_whitespace().next()?.parse().ok()?;
+    Some((name, email, Utc.timestamp_opt(secs, 0).single()?))
+}
+
+// The name of the branch that a git ref corresponds to.
+fn branch_name(git_ref: &str) -> &str {
+    git_ref.strip_prefix("refs/heads/").unwrap_or(git_ref)
+}
+
+struct Importer<'a, R> {
+    repo: &'a mut Repo,
+    parser: Parser<R>,
+    config: Config,
+    blobs: HashMap<u64, Rc<[u8]>>,
+    commits: Vec<Commit>,
+    marks: HashMap<u64, usize>,
+    // The commits' ids, if the stream has them.
+    ids: HashMap<String, usize>,
+    // The commit at the tip of each ref.
+    tips: BTreeMap<String, usize>,
+    // The patches that are on the scratch branch.
+    scratch: BTreeSet<PatchId>,
+    // Are we syncing with the git repository, rather than importing it?
+    mirror: bool,
+    ret: GitImport,
+}
+
+impl<R: BufRead> Importer<'_, R> {
+    fn run(mut self) -> Result<GitImport, Error> {
+        self.repo.create_branch(SCRATCH_BRANCH)?;
+        while let Some(line) = self.parser.next_line()? {
+            let (command, arg) = match line.find(' ') {
+                Some(i) => (&line[..i], &line[i + 1..]),
+                None => (&line[..], ""),
+            };
+            match command {
+                "" | "progress" | "feature" | "option" | "checkpoint" => {}
+                _ if command.starts_with('#') => {}
+                "blob" => self.blob()?,
+                "commit" => self.commit(arg.to_owned())?,
+                "reset" => self.reset(arg.to_owned())?,
+                "tag" => self.tag(arg.to_owned())?,
+                "done" => break,
+                _ => return self.parser.error(&format!("unknown command {:?}", command)),
+            }
+        }
+        self.finish()
+    }
+
+    fn blob(&mut self) -> Result<(), Error> {
+        let mark = self.parser.mark()?;
+        self.parser.optional("original-oid ")?;
+        let data = self.parser.data()?;
+        if let Some(mark) = mark {
+            self.blobs.insert(mark, data.into());
+        }
+        Ok(())
+    }
+
+    // Finds the commit that a `from` or `merge` command refers to.
+    fn find_commit(&mut self, name: &str) -> Result<usize, Error> {
+        let found = match name.strip_prefix(':') {
+            Some(mark) => mark.parse().ok().and_then(|m| self.marks.get(&m)),
+            None => self.ids.get(name).or_else(|| self.tips.get(name)),
+        };
+        match found {
+            Some(&idx) => Ok(idx),
+            None if self.mirror && self.repo.storage.git_mirror.commits.contains_key(name) => {
+                self.synced_commit(name)
+            }
+            None => self.parser.error(&format!("unknown commit {:?}", name)),
+        }
+    }
+
+    // Adds a commit that was synced before (but isn't in the stream), so that the commits in the
+    // stream can refer to it.
+    fn synced_commit(&mut self, id: &str) -> Result<usize, Error> {
+        let patches = self.repo.storage.git_mirror.commit_patches(id);
+        self.set_scratch(&patches)?;
+        let files = git_mirror::tree(self.repo, SCRATCH_BRANCH)?;
+        let idx = self.commits.len();
+        self.commits.push(Commit {
+            id: Some(id.to_owned()),
+            files,
+            patches,
+        });
+        self.ids.insert(id.to_owned(), idx);
+        Ok(idx)
+    }
+
+    fn commit(&mut self, git_ref: String) -> Result<(), Error> {
+        let mark = self.parser.mark()?;
+        let id = self.parser.optional("original-oid ")?;
+        if self.mirror && id.is_none() {
+            return self.parser.error(
+                "syncing needs the commits' ids (from `git fast-export --show-original-ids`)",
+            );
+        }
+        let author = self.parser.optional("author ")?;
+        let committer = match self.parser.optional("committer ")? {
+            Some(c) => c,
+            None => return self.parser.error("expected a committer"),
+        };
+        let (name, email, time) = match parse_person(author.as_ref().unwrap_or(&committer)) {
+            Some(person) => person,
+            None => return self.parser.error("invalid author"),
+        };
+        self.parser.optional("encoding ")?;
+        let msg = String::from_utf8_lossy(&self.parser.data()?).into_owned();
+
+        // Without a `from`, a commit follows on from the tip of its ref.
+        let mut parents = Vec::new();
+        match self.parser.optional("from ")? {
+            Some(from) => parents.push(self.find_commit(&from)?),
+            None => parents.extend(self.tips.get(&git_ref).cloned()),
+        }
+        while let Some(merge) = self.parser.optional("merge ")? {
+            parents.push(self.find_commit(&merge)?);
+        }
+
+        let mut files = parents
+            .first()
+            .map(|&p| self.commits[p].files.clone())
+            .unwrap_or_default();
+        // The files that were renamed in this commit, with the paths that they had in its first
+        // parent.
+        let mut renames = BTreeMap::new();
+        while let Some(line) = self.parser.next_line()? {
+            if line.is_empty() {
+                continue;
+            }
+            if !self.file_command(&line, &mut files, &mut renames)? {
+                self.parser.pending = Some(line);
+                break;
+            }
+        }
+
+        let mut patches = BTreeSet::new();
+        for &p in &parents {
+            patches.extend(self.commits[p].patches.iter().cloned());
+        }
+        let synced = match &id {
+            Some(id) if self.mirror => self.repo.storage.git_mirror.commits.get(id).cloned(),
+            _ => None,
+        };
+        if let Some(synced) = synced {
+            // The commit was synced before, so it already has its patch.
+            patches.extend(synced.patch);
+        } else {
+            self.set_scratch(&patches)?;
+            let changes = changes(self.repo, &self.config, SCRATCH_BRANCH, &files, &renames)?;
+            let mut own = None;
+            if !changes.changes.is_empty() {
+                let mut header = PatchHeader::new(name, msg.trim_end().to_owned());
+                if !email.is_empty() {
+                    header.email = Some(email);
+                }
+                header.timestamp = time;
+                if let Some(id) = &id {
+                    header.extra.insert(GIT_COMMIT_KEY.to_owned(), id.clone());
+                }
+                let patch = self.repo.create_patch_with_header(header, changes)?;
+                self.repo
+                    .apply_without_hooks(SCRATCH_BRANCH, &patch, ApplyPolicy::Refuse)?;
+                self.scratch.insert(patch);
+                patches.insert(patch);
+                if let Some(id) = &id {
+                    self.repo.storage.git_commits.insert(id.clone(), patch);
+                }
+                let name = id
+                    .clone()
+                    .or_else(|| mark.map(|m| format!(":{}", m)))
+                    .unwrap_or_default();
+                self.ret.patches.push((name, patch));
+                own = Some(patch);
+            }
+            if let (true, Some(id)) = (self.mirror, &id) {
+                let parents = parents
+                    .iter()
+                    .filter_map(|&p| self.commits[p].id.clone())
+                    .collect();
+                let commit = MirroredCommit {
+                    patch: own,
+                    parents,
+                };
+                self.repo
+                    .storage
+                    .git_mirror
+                    .commits
+                    .insert(id.clone(), commit);
+            }
+        }
+
+        let idx = self.commits.len();
+        self.commits.push(Commit {
+            id: id.clone(),
+            files,
+            patches,
+        });
+        if let Some(mark) = mark {
+            self.marks.insert(mark, idx);
+        }
+        if let Some(id) = id {
+            self.ids.insert(id, idx);
+        }
+        self.tips.insert(git_ref, idx);
+        Ok(())
+    }
+
+    // Carries out a command that changes the files of a commit. Returns false if the line isn't
+    // one of those commands.
+    fn file_command(
+        &mut self,
+        line: &str,
+        files: &mut Tree,
+        renames: &mut BTreeMap<String, String>,
+    ) -> Result<bool, Error> {
+        if line == "deleteall" {
+            files.clear();
+            renames.clear();
+        } else if let Some(rest) = line.strip_prefix("M ") {
+            let mut words = rest.splitn(3, ' ');
+            let (mode, data, path) = match (words.next(), words.next(), words.next()) {
+                (Some(mode), Some(data), Some(path)) => (mode, data, path),
+                _ => return self.parser.error("invalid file modification"),
+            };
+            let path = match last_path(path) {
+                Some(path) => path,
+                None => return self.parser.error("invalid path"),
+            };
+            let contents = if data == "inline" {
+                self.parser.data()?.into()
+            } else {
+                let blob = data
+                    .strip_prefix(':')
+                    .and_then(|m| m.parse().ok())
+                    .and_then(|m| self.blobs.get(&m));
+                match blob {
+                    Some(blob) => blob.clone(),
+                    None => return self.parser.error(&format!("unknown blob {:?}", data)),
+                }
+            };
+            let (executable, symlink) = match mode {
+                "100644" | "644" => (false, false),
+                "100755" | "755" => (true, false),
+                "120000" => (false, true),
+                // Submodules aren't files in the repository.
+                "160000" => return Ok(true),
+                _ => return self.parser.error(&format!("unsupported mode {}", mode)),
+            };
+            let file = GitFile {
+                contents,
+                executable,
+                symlink,
+            };
+            files.insert(path, file);
+        } else if let Some(rest) = line.strip_prefix("D ") {
+            let path = match last_path(rest) {
+                Some(path) => path,
+                None => return self.parser.error("invalid path"),
+            };
+            files.remove(&path);
+            // Deleting a directory deletes everything in it.
+            let dir = format!("{}/", path);
+            files.retain(|p, _| !p.starts_with(&dir));
+            renames.remove(&path);
+        } else if line.starts_with("R ") || line.starts_with("C ") {
+            let paths = split_path(&line[2..]).and_then(|(from, to)| Some((from, last_path(to)?)));
+            let (from, to) = match paths {
+                Some(paths) => paths,
+                None => return self.parser.error("invalid paths"),
+            };
+            let file = match files.get(&from) {
+                Some(file) => file.clone(),
+                None => return self.parser.error(&format!("there is no file {:?}", from)),
+            };
+            files.insert(to.clone(), file);
+            if line.starts_with("R ") {
+                files.remove(&from);
+                let orig = renames.remove(&from).unwrap_or(from);
+                renames.insert(to, orig);
+            }
+        } else if line.starts_with("N ") {
+            // Notes aren't imported, but they might have inline data.
+            if line.split(' ').nth(1) == Some("inline") {
+                self.parser.data()?;
+            }
+        } else {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn reset(&mut self, git_ref: String) -> Result<(), Error> {
+        match self.parser.optional("from ")? {
+            Some(from) => {
+                let idx = self.find_commit(&from)?;
+                self.tips.insert(git_ref, idx);
+            }
+            None => {
+                self.tips.remove(&git_ref);
+            }
+        }
+        Ok(())
+    }
+
+    fn tag(&mut self, name: String) -> Result<(), Error> {
+        self.parser.mark()?;
+        let from = match self.parser.optional("from ")? {
+            Some(from) => self.find_commit(&from)?,
+            None => return self.parser.error("expected the tagged commit"),
+        };
+        self.parser.optional("original-oid ")?;
+        let tagger = self.parser.optional("tagger ")?;
+        let msg = String::from_utf8_lossy(&self.parser.data()?).into_owned();
+        let author = tagger
+            .and_then(|t| parse_person(&t))
+            .map(|(name, _, _)| name)
+            .unwrap_or_default();
+        self.create_tag(&name, from, &author, msg.trim_end())
+    }
+
+    // Makes a tag containing the patches of a commit.
+    fn create_tag(
+        &mut self,
+        name: &str,
+        commit: usize,
+        author: &str,
+        msg: &str,
+    ) -> Result<(), Error> {
+        if self.repo.tag(name).is_ok() {
+            // When syncing, the tags that were pulled before come along again.
+            if self.mirror {
+                return Ok(());
+            }
+            return Err(Error::TagExists(name.to_owned()));
+        }
+        let mut header = PatchHeader::new(author.to_owned(), msg.to_owned());
+        header
+            .extra
+            .insert(tag::TAG_KEY.to_owned(), name.to_owned());
+        let patches = self.commits[commit].patches.iter().cloned().collect();
+        self.repo
+            .create_unidentified_patch(UnidentifiedPatch::with_deps(header, patches))?;
+        self.ret.tags.push(name.to_owned());
+        Ok(())
+    }
+
+    // Puts exactly the given patches on the scratch branch.
+    fn set_scratch(&mut self, patches: &BTreeSet<PatchId>) -> Result<(), Error> {
+        if !self.scratch.is_subset(patches) {
+            self.repo.delete_branch(SCRATCH_BRANCH)?;
+            self.repo.create_branch(SCRATCH_BRANCH)?;
+            self.scratch.clear();
+        }
+        let missing = patches
+            .difference(&self.scratch)
+            .cloned()
+            .collect::<Vec<_>>();
+        for p in self.repo.patch_graph().apply_order(&missing) {
+            self.repo
+                .apply_without_hooks(SCRATCH_BRANCH, &p, ApplyPolicy::Refuse)?;
+        }
+        self.scratch = patches.clone();
+        Ok(())
+    }
+
+    // Puts the patches of the commits at the tips of the refs on their branches (or in their
+    // tags).
+    fn finish(mut self) -> Result<GitImport, Error> {
+        self.repo.delete_branch(SCRATCH_BRANCH)?;
+        for (git_ref, commit) in std::mem::take(&mut self.tips) {
+            if let Some(name) = git_ref.strip_prefix("refs/tags/") {
+                // Annotated tags have already been made.
+                if !self.ret.tags.iter().any(|t| t == name) {
+                    let name = name.to_owned();
+                    self.create_tag(&name, commit, "", "")?;
+                }
+                continue;
+            }
+            let branch = branch_name(&git_ref);
+            if self.mirror {
+                // Every commit has an id when syncing.
+                let id = self.commits[commit].id.clone().unwrap_or_default();
+                let mirror = &self.repo.storage.git_mirror;
+                match mirror.tips.get(branch) {
+                    Some(tip) if *tip == id => continue,
+                    Some(tip) if !mirror.is_ancestor(tip, &id) => {
+                        return Err(Error::GitDiverged(branch.to_owned()));
+                    }
+                    _ => {}
+                }
+                self.repo
+                    .storage
+                    .git_mirror
+                    .tips
+                    .insert(branch.to_owned(), id);
+            }
+            if self.repo.storage.inode(branch).is_none() {
+                self.repo.create_branch(branch)?;
+            }
+            let missing = self.commits[commit]
+                .patches
+                .iter()
+                .filter(|p| !self.repo.storage.branch_patches.contains(branch, p))
+                .cloned()
+                .collect::<Vec<_>>();
+            for p in self.repo.patch_graph().apply_order(&missing) {
+                self.repo
+                    .apply_without_hooks(branch, &p, ApplyPolicy::Refuse)?;
+            }
+            self.ret.branches.push(branch.to_owned());
+        }
+        Ok(self.ret)
+    }
+}
+
+// Returns the changes to the lines of a file (whose lines are in `graggle`, if it isn't new) that
+// give it the contents `new`.
+pub(crate) fn edit_changes(
+    repo: &Repo,
+    config: &Config,
+    path: &str,
+    graggle: Option<Graggle<'_>>,
+    new: &[u8],
+) -> Changes {
+    let old_blob = graggle.as_ref().and_then(|g| g.binary());
+    // Once a file has binary contents, it stays binary.
+    if old_blob.is_some() || config.is_binary(path, new) {
+        if old_blob.map(|b| b.hash) == Some(BlobHash::of(new)) {
+            return Changes { changes: vec![] };
+        }
+        return Changes {
+            changes: vec![repo.binary_replace(old_blob, new.to_owned())],
+        };
+    }
+
+    let graggle = match graggle {
+        Some(graggle) => graggle,
+        None => return Diff::new(File::from_bytes(b""), new, &config.diff_options()).changes(),
+    };
+    if let Some(order) = graggle.as_live_graph().linear_order() {
+        let old = File::from_ids(&order, &repo.storage);
+        return Diff::new(old, new, &config.diff_options()).changes();
+    }
+    // The lines aren't in order, because the commit is a merge whose parents conflict. The
+    // commit has the resolution, so the lines are replaced by it.
+    let mut ret = Diff::new(File::from_bytes(b""), new, &config.diff_options()).changes();
+    ret.changes
+        .extend(graggle.nodes().map(|id| Change::DeleteNode { id }));
+    ret
+}
+
+// Returns the changes that turn the files on a branch into `files`. The files that were renamed
+// are in `renames`, indexed by their new paths.
+pub(crate) fn changes(
+    repo: &Repo,
+    config: &Config,
+    branch: &str,
+    files: &Tree,
+    renames: &BTreeMap<String, String>,
+) -> Result<Changes, Error> {
+    let old = repo
+        .files(branch)?
+        .map(|p| p.to_owned())
+        .collect::<BTreeSet<_>>();
+    // The paths that the files had on the branch.
+    let origins = files
+        .keys()
+        .filter_map(|path| match renames.get(path) {
+            Some(from) if old.contains(from) && !old.contains(path) => Some((path, from)),
+            _ if old.contains(path) => Some((path, path)),
+            _ => None,
+        })
+        .collect::<BTreeMap<_, _>>();
+    let kept = origins
+        .values()
+        .map(|&p| p.clone())
+        .collect::<BTreeSet<_>>();
+
+    let mut ret = Changes { changes: vec![] };
+    for path in old.difference(&kept) {
+        let file = repo.file_ref(branch, path)?;
+        ret.changes.push(Change::DeleteFile { file });
+    }
+    for (&to, &from) in &origins {
+        if to != from {
+            let from = repo.file_ref(branch, from)?;
+            let to = to.clone();
+            ret.changes.push(Change::MoveFile { from, to });
+        }
+    }
+
+    let mut file_changes = Vec::new();
+    for (path, git_file) in files {
+        let (id, mut file, graggle, executable, symlink) = match origins.get(path) {
+            Some(&from) => (
+                repo.file_id(branch, from)?,
+                // A file that was moved was put in place by the new patch.
+                if from == path {
+                    repo.file_ref(branch, from)?
+                } else {
+                    FileRef {
+                        patch: PatchId::staging(),
+                        path: path.clone(),
+                    }
+                },
+                Some(repo.file_graggle(branch, from)?),
+                repo.is_executable(branch, from)?,
+                repo.is_symlink(branch, from)?,
+            ),
+            None => {
+                ret.changes.push(Change::NewFile { path: path.clone() });
+                let file = FileRef {
+                    patch: PatchId::staging(),
+                    path: path.clone(),
+                };
+                (file.clone(), file, None, false, false)
+            }
+        };
+        if git_file.executable != executable {
+            ret.changes.push(Change::SetExecutable {
+                file: file.clone(),
+                executable: git_file.executable,
+            });
+            // Changing the metadata puts the file in place again.
+            file.patch = PatchId::staging();
+        }
+        if git_file.symlink != symlink {
+            ret.changes.push(Change::SetSymlink {
+                file,
+                symlink: git_file.symlink,
+            });
+        }
+        let changes = edit_changes(repo, config, path, graggle, &git_file.contents);
+        if !changes.changes.is_empty() {
+            file_changes.push((id, changes));
+        }
+    }
+    for (id, changes) in file_changes {
+        ret.add_file_changes(id, changes);
+    }
+    Ok(ret)
+}
+
+pub(crate) fn import<R: BufRead>(repo: &mut Repo, input: R) -> Result<GitImport, Error> {
+    run_import(repo, input, false)
+}
+
+// Imports the new commits in a stream, and moves the synced branches forward to them.
+pub(crate) fn pull<R: BufRead>(repo: &mut Repo, input: R) -> Result<GitImport, Error> {
+    run_import(repo, input, true)
+}
+
+fn run_import<R: BufRead>(repo: &mut Repo, input: R, mirror: bool) -> Result<GitImport, Error> {
+    let config = repo.config();
+    repo.transaction(|repo| {
+        let importer = Importer {
+            repo,
+            parser: Parser {
+                input,
+                line_no: 0,
+                pending: None,
+            },
+            config,
+            blobs: HashMap::new(),
+            commits: Vec::new(),
+            marks: HashMap::new(),
+            ids: HashMap::new(),
+            tips: BTreeMap::new(),
+            scratch: BTreeSet::new(),
+            mirror,
+            ret: GitImport::default(),
+        };
+        importer.run()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A repository with a few commits on two branches, which get merged, as `git fast-export
+    // --all --show-original-ids` would write it (apart from the made-up commit ids).
+    const STREAM: &[u8] = b"blob
+mark :1
+data 4
+a
+b
+
+reset refs/heads/master
+commit refs/heads/master
+mark :2
+original-oid 1111
+author Alice <alice@example.com> 1546300800 +0000
+committer Alice <alice@example.com> 1546300800 +0000
+data 6
+First
+M 100644 :1 file.txt
+
+blob
+mark :3
+data 13
+#!/bin/sh
+ls
+
+commit refs/heads/master
+mark :4
+original-oid 2222
+author Alice <alice@example.com> 1546387200 +0000
+committer Alice <alice@example.com> 1546387200 +0000
+data 7
+Script
+from :2
+M 100755 :3 \"bin/run me.sh\"
+M 100644 inline file.txt
+data 6
+a
+b
+c
+
+commit refs/heads/other
+mark :5
+original-oid 3333
+author Bob <bob@example.com> 1546473600 +0000
+committer Bob <bob@example.com> 1546473600 +0000
+data 7
+Rename
+from :2
+R file.txt renamed.txt
+
+commit refs/heads/master
+mark :6
+original-oid 4444
+author Alice <alice@example.com> 1546560000 +0000
+committer Alice <alice@example.com> 1546560000 +0000
+data 6
+Merge
+from :4
+merge :5
+D file.txt
+M 100644 inline renamed.txt
+data 6
+a
+b
+c
+
+reset refs/tags/v1
+from :4
+
+done
+";
+
+    #[test]
+    fn import() {
+        let mut repo = Repo::init_tmp();
+        let ret = repo.import_git(STREAM).unwrap();
+        let ids = ret
+            .patches
+            .iter()
+            .map(|(c, _)| c.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["1111", "2222", "3333"]);
+        assert_eq!(ret.branches, vec!["master", "other"]);
+        assert_eq!(ret.tags, vec!["v1"]);
+        let patch = |commit: &str| repo.git_patch(commit).unwrap();
+
+        assert_eq!(
+            repo.files("master").unwrap().collect::<Vec<_>>(),
+            vec!["bin/run me.sh", "renamed.txt"]
+        );
+        assert!(repo.is_executable("master", "bin/run me.sh").unwrap());
+        assert_eq!(
+            repo.file_at("master", "renamed.txt").unwrap().as_bytes(),
+            b"a\nb\nc\n"
+        );
+        assert_eq!(
+            repo.files("other").unwrap().collect::<Vec<_>>(),
+            vec!["renamed.txt"]
+        );
+        assert_eq!(
+            repo.file_at("other", "renamed.txt").unwrap().as_bytes(),
+            b"a\nb\n"
+        );
+
+        // The merge didn't need a patch: the rename and the new line don't conflict.
+        assert_eq!(repo.git_patch("4444"), None);
+        let mut deps = repo.patch_graph().transitive_deps(&patch("3333"));
+        deps.sort();
+        assert_eq!(deps, vec![patch("1111")]);
+        let header = repo.open_patch(&patch("2222")).unwrap().header().clone();
+        assert_eq!(header.author, "Alice");
+        assert_eq!(header.email.as_deref(), Some("alice@example.com"));
+        assert_eq!(header.description, "Script");
+        assert_eq!(header.timestamp.to_rfc3339(), "2019-01-02T00:00:00+00:00");
+        assert_eq!(header.extra[GIT_COMMIT_KEY], "2222");
+
+        let mut tagged = repo.tag("v1").unwrap().patches().to_vec();
+        tagged.sort();
+        let mut expected = vec![patch("1111"), patch("2222")];
+        expected.sort();
+        assert_eq!(tagged, expected);
+        assert_eq!(repo.branches().collect::<Vec<_>>(), vec!["master", "other"]);
+    }
+
+    #[test]
+    fn conflicting_merge() {
+        let stream = b"commit refs/heads/master
+mark :1
+committer A <a@example.com> 0 +0000
+data 0
+M 644 inline a.txt
+data 2
+a
+
+commit refs/heads/side
+mark :2
+committer A <a@example.com> 0 +0000
+data 0
+from :1
+M 644 inline a.txt
+data 4
+a
+b
+
+commit refs/heads/master
+mark :3
+committer A <a@example.com> 0 +0000
+data 0
+from :1
+M 644 inline a.txt
+data 4
+a
+c
+
+commit refs/heads/master
+mark :4
+committer A <a@example.com> 0 +0000
+data 0
+from :3
+merge :2
+M 644 inline a.txt
+data 6
+a
+c
+b
+
+";
+        let mut repo = Repo::init_tmp();
+        let ret = repo.import_git(&stream[..]).unwrap();
+        let ids = ret
+            .patches
+            .iter()
+            .map(|(c, _)| c.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![":1", ":2", ":3", ":4"]);
+        assert_eq!(
+            repo.file_at("master", "a.txt").unwrap().as_bytes(),
+            b"a\nc\nb\n"
+        );
+        assert_eq!(repo.file_at("side", "a.txt").unwrap().as_bytes(), b"a\nb\n");
+    }
+
+    #[test]
+    fn errors() {
+        let mut repo = Repo::init_tmp();
+        match repo.import_git(&b"blob\nmark :1\ndata 2\na\n\nbogus\n"[..]) {
+            Err(Error::FastExportSyntax(6, _)) => {}
+            x => panic!("expected a syntax error, got {:?}", x),
+        }
+        let stream = b"commit refs/heads/master\ncommitter A <a> 0 +0000\ndata 0\nfrom :7\n";
+        match repo.import_git(&stream[..]) {
+            Err(Error::FastExportSyntax(4, _)) => {}
+            x => panic!("expected a syntax error, got {:?}", x),
+        }
+        assert_eq!(repo.branches().collect::<Vec<_>>(), vec!["master"]);
+    }
+
+    #[test]
+    fn rename_and_chmod() {
+        let stream = b"commit refs/heads/master
+mark :1
+committer A <a@example.com> 0 +0000
+data 0
+M 100644 inline a.txt
+data 2
+a
+
+commit refs/heads/master
+mark :2
+committer A <a@example.com> 0 +0000
+data 0
+from :1
+R a.txt b.txt
+M 120000 inline b.txt
+data 2
+a
+
+done
+";
+        let mut repo = Repo::init_tmp();
+        repo.import_git(&stream[..]).unwrap();
+        assert_eq!(
+            repo.files("master").unwrap().collect::<Vec<_>>(),
+            vec!["b.txt"]
+        );
+        assert!(repo.is_symlink("master", "b.txt").unwrap());
+    }
+
+    #[test]
+    fn quoted_paths() {
+        assert_eq!(
+            split_path("\"a \\\"b\\\"\\303\\251\" c"),
+            Some(("a \"b\"é".to_owned(), "c"))
+        );
+        assert_eq!(split_path("a b c"), Some(("a".to_owned(), "b c")));
+        assert_eq!(last_path("a b c"), Some("a b c".to_owned()));
+        assert_eq!(last_path("\"a\" b"), None);
+    }
+}
@@ -0,0 +1,362 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Keeping branches in sync with a git repository, in both directions.
+//
+// Every git commit that has been synced (whichever side it came from) is remembered, along with
+// its parents and the patch that it corresponds to (if it changed anything). Since a commit's
+// files are determined by the patches of it and its ancestors, this is enough to work out the
+// files of any synced commit, which is what an incremental `git fast-export` stream needs when
+// it has commits whose parents aren't in the stream. For each branch, we also remember the last
+// commit that it was synced with: new commits on the git side have to descend from it, and new
+// patches on the ojo side are exported as commits on top of it.
+//
+// The importing itself is done by `git_import`, which uses the remembered commits to look up the
+// commits that aren't in the stream, and to skip the ones that were already imported (or
+// exported).
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, Write};
+
+use crate::git_export::{self, SCRATCH_BRANCH};
+use crate::git_import::{GitFile, Tree};
+use crate::{ApplyPolicy, Error, PatchId, Repo};
+
+// A git commit that was synced.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct MirroredCommit {
+    // The patch that the commit made, unless it didn't change anything (like most merges).
+    pub patch: Option<PatchId>,
+    // The ids of the commit's parents, which were all synced before it.
+    pub parents: Vec<String>,
+}
+
+// What we know about the git repository that the branches are synced with.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct GitMirror {
+    // The synced commits, by their ids.
+    pub commits: BTreeMap<String, MirroredCommit>,
+    // The last commit that each branch was synced with.
+    pub tips: BTreeMap<String, String>,
+}
+
+impl GitMirror {
+    // Returns the patches that make up a synced commit: its own, and those of its ancestors.
+    pub fn commit_patches(&self, commit: &str) -> BTreeSet<PatchId> {
+        let mut ret = BTreeSet::new();
+        let mut seen = BTreeSet::new();
+        let mut stack = vec![commit];
+        while let Some(c) = stack.pop() {
+            if !seen.insert(c) {
+                continue;
+            }
+            if let Some(mc) = self.commits.get(c) {
+                ret.extend(mc.patch);
+                stack.extend(mc.parents.iter().map(|p| p.as_str()));
+            }
+        }
+        ret
+    }
+
+    // Is `ancestor` the same as `commit`, or one of its ancestors?
+    pub fn is_ancestor(&self, ancestor: &str, commit: &str) -> bool {
+        let mut seen = BTreeSet::new();
+        let mut stack = vec![commit];
+        while let Some(c) = stack.pop() {
+            if c == ancestor {
+                return true;
+            }
+            if seen.insert(c) {
+                if let Some(mc) = self.commits.get(c) {
+                    stack.extend(mc.parents.iter().map(|p| p.as_str()));
+                }
+            }
+        }
+        false
+    }
+}
+
+// Returns the files on a branch, as they would be in a git commit.
+pub(crate) fn tree(repo: &Repo, branch: &str) -> Result<Tree, Error> {
+    let mut ret = Tree::new();
+    for path in repo.files(branch)? {
+        let mut contents = Vec::new();
+        repo.render_file(branch, path, &mut contents)?;
+        let file = GitFile {
+            contents: contents.into(),
+            executable: repo.is_executable(branch, path)?,
+            symlink: repo.is_symlink(branch, path)?,
+        };
+        ret.insert(path.to_owned(), file);
+    }
+    Ok(ret)
+}
+
+fn push<W: Write>(repo: &mut Repo, branch: &str, mut out: W) -> Result<Vec<PatchId>, Error> {
+    let tip = repo.storage.git_mirror.tips.get(branch).cloned();
+    let synced = match &tip {
+        Some(tip) => repo.storage.git_mirror.commit_patches(tip),
+        None => BTreeSet::new(),
+    };
+    // If some of the patches in the git branch were taken off of this branch, the git branch
+    // would need to be rewritten.
+    if synced
+        .iter()
+        .any(|p| !repo.storage.branch_patches.contains(branch, p))
+    {
+        return Err(Error::GitDiverged(branch.to_owned()));
+    }
+
+    repo.create_branch(SCRATCH_BRANCH)?;
+    let base = synced.iter().cloned().collect::<Vec<_>>();
+    for p in repo.patch_graph().apply_order(&base) {
+        repo.apply_without_hooks(SCRATCH_BRANCH, &p, ApplyPolicy::Refuse)?;
+    }
+    let mut files = git_export::snapshot(repo)?;
+    let git_ref = format!("refs/heads/{}", branch);
+    let mut ret = Vec::new();
+    for (p, header) in git_export::linearize(repo, branch)? {
+        if synced.contains(&p) {
+            continue;
+        }
+        repo.apply_without_hooks(SCRATCH_BRANCH, &p, ApplyPolicy::Refuse)?;
+        // The first new commit goes on top of the synced one, and the others on top of it.
+        let from = match ret.len() {
+            0 => tip.clone(),
+            n => Some(format!(":{}", n)),
+        };
+        let mark = ret.len() + 1;
+        git_export::commit(repo, &git_ref, mark, from, &header, &mut files, &mut out)?;
+        ret.push(p);
+    }
+    writeln!(out, "done")?;
+    Ok(ret)
+}
+
+pub(crate) fn push_branch<W: Write>(
+    repo: &mut Repo,
+    branch: &str,
+    out: W,
+) -> Result<Vec<PatchId>, Error> {
+    repo.inode(branch)?;
+    // As with `export_branch`, the scratch branch doesn't survive, so nothing changes.
+    let readonly = std::mem::replace(&mut repo.readonly, false);
+    let ret = repo.transaction(|repo| {
+        let ret = push(repo, branch, out);
+        if repo.storage.inode(SCRATCH_BRANCH).is_some() {
+            repo.delete_branch(SCRATCH_BRANCH)?;
+        }
+        ret
+    });
+    repo.readonly = readonly;
+    ret
+}
+
+// Reads a marks file, as `git fast-import --export-marks` writes it: each line is a mark and the
+// id of the object that it was given to.
+fn read_marks<R: BufRead>(marks: R) -> Result<BTreeMap<usize, String>, Error> {
+    let mut ret = BTreeMap::new();
+    for (i, line) in marks.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let bad = || Error::GitMarks(format!("line {} isn't a mark and an id", i + 1));
+        let mut words = line.split(' ');
+        let (mark, id) = match (words.next(), words.next(), words.next()) {
+            (Some(mark), Some(id), None) => (mark, id),
+            _ => return Err(bad()),
+        };
+        let mark = mark
+            .strip_prefix(':')
+            .and_then(|m| m.parse().ok())
+            .ok_or_else(bad)?;
+        ret.insert(mark, id.to_owned());
+    }
+    Ok(ret)
+}
+
+pub(crate) fn record_marks<R: BufRead>(
+    repo: &mut Repo,
+    branch: &str,
+    patches: &[PatchId],
+    marks: R,
+) -> Result<(), Error> {
+    repo.inode(branch)?;
+    let marks = read_marks(marks)?;
+    let mut ids = Vec::new();
+    for (i, p) in patches.iter().enumerate() {
+        match marks.get(&(i + 1)) {
+            Some(id) => ids.push((id.clone(), *p)),
+            None => {
+                let msg = format!("there is no commit for the patch {}", p.to_base64());
+                return Err(Error::GitMarks(msg));
+            }
+        }
+    }
+
+    let mirror = &mut repo.storage.git_mirror;
+    let mut parent = mirror.tips.get(branch).cloned();
+    for (id, p) in ids {
+        let commit = MirroredCommit {
+            patch: Some(p),
+            parents: parent.into_iter().collect(),
+        };
+        mirror.commits.insert(id.clone(), commit);
+        repo.storage.git_commits.insert(id.clone(), p);
+        parent = Some(id);
+    }
+    if let Some(tip) = parent {
+        mirror.tips.insert(branch.to_owned(), tip);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemoryWorkingCopy, RecordOptions};
+
+    // Turns a stream from `push_git` into the stream that `git fast-export --show-original-ids`
+    // would give for the same commits, given the ids that they got.
+    fn as_exported(stream: &[u8], ids: &[&str]) -> Vec<u8> {
+        let mut ret = String::from_utf8(stream.to_owned()).unwrap();
+        for (i, id) in ids.iter().enumerate().rev() {
+            let mark = format!("mark :{}\n", i + 1);
+            ret = ret.replace(&mark, &format!("{}original-oid {}\n", mark, id));
+        }
+        ret.into_bytes()
+    }
+
+    // A commit that sets the contents of `a.txt`, in a fast-export stream.
+    fn commit(id: &str, parent: Option<&str>, contents: &str) -> String {
+        let from = parent.map(|p| format!("from {}\n", p)).unwrap_or_default();
+        format!(
+            "commit refs/heads/master\nmark :{id}\noriginal-oid {id}\n\
+             committer A <a@example.com> 0 +0000\ndata 0\n{from}\
+             M 100644 inline a.txt\ndata {len}\n{contents}\n",
+            id = id,
+            from = from,
+            len = contents.len() + 1,
+            contents = contents
+        )
+    }
+
+    #[test]
+    fn sync() {
+        let first = commit("1111", None, "a");
+        let first = first.as_bytes();
+        let wc = MemoryWorkingCopy::new();
+        let mut repo = Repo::init_in_memory(wc.clone());
+        let pulled = repo.pull_git(first).unwrap();
+        assert_eq!(pulled.branches, vec!["master"]);
+        let a = repo.git_patch("1111").unwrap();
+        assert_eq!(repo.git_tip("master"), Some("1111"));
+
+        // Pulling the same commits again doesn't do anything.
+        let pulled = repo.pull_git(first).unwrap();
+        assert!(pulled.patches.is_empty());
+        assert!(pulled.branches.is_empty());
+
+        // A new patch gets exported on top of the synced commit.
+        repo.update_working_copy().unwrap();
+        wc.write("a.txt", b"a\nb\n");
+        let b = repo
+            .record("Author", "Second", &RecordOptions::default())
+            .unwrap();
+        let mut out = Vec::new();
+        assert_eq!(repo.push_git("master", &mut out).unwrap(), vec![b]);
+        let stream = String::from_utf8(out.clone()).unwrap();
+        assert!(stream.contains("mark :1\nauthor Author"));
+        assert!(stream.contains("from 1111\n"));
+        assert!(stream.contains("M 100644 inline a.txt\ndata 4\na\nb\n"));
+        repo.record_git_marks("master", &[b], &b":1 2222\n"[..])
+            .unwrap();
+        assert_eq!(repo.git_tip("master"), Some("2222"));
+        assert_eq!(repo.git_commit(&b).as_deref(), Some("2222"));
+        assert!(repo.push_git("master", std::io::sink()).unwrap().is_empty());
+
+        // The exported commit comes back in the next stream, along with a new one on top of it.
+        let mut stream = as_exported(&out, &["2222"]);
+        let end = stream.len() - "done\n".len();
+        stream.truncate(end);
+        stream.extend_from_slice(commit("3333", Some(":1"), "a\nb\nc").as_bytes());
+        let pulled = repo.pull_git(&stream[..]).unwrap();
+        let ids = pulled
+            .patches
+            .iter()
+            .map(|(c, _)| c.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["3333"]);
+        assert_eq!(
+            repo.file_at("master", "a.txt").unwrap().as_bytes(),
+            b"a\nb\nc\n"
+        );
+
+        // An incremental stream refers to the commits before it by their ids.
+        let pulled = repo
+            .pull_git(commit("4444", Some("3333"), "a\nb\nc\nd").as_bytes())
+            .unwrap();
+        assert_eq!(pulled.patches.len(), 1);
+        assert_eq!(repo.git_tip("master"), Some("4444"));
+        let mut expected = vec![a, b, repo.git_patch("3333").unwrap()];
+        expected.push(repo.git_patch("4444").unwrap());
+        expected.sort();
+        let mut patches = repo.patches("master").cloned().collect::<Vec<_>>();
+        patches.sort();
+        assert_eq!(patches, expected);
+    }
+
+    #[test]
+    fn diverged() {
+        let mut repo = Repo::init_tmp();
+        let stream = commit("1111", None, "a") + &commit("2222", Some(":1111"), "b");
+        repo.pull_git(stream.as_bytes()).unwrap();
+        assert_eq!(repo.git_tip("master"), Some("2222"));
+
+        // A commit that replaces the last one rewrites the history of the git branch.
+        match repo.pull_git(commit("3333", Some("1111"), "c").as_bytes()) {
+            Err(Error::GitDiverged(b)) => assert_eq!(b, "master"),
+            x => panic!("unexpected result {:?}", x),
+        }
+        assert_eq!(repo.git_tip("master"), Some("2222"));
+        assert_eq!(repo.git_patch("3333"), None);
+
+        // So does taking a synced patch off of the branch.
+        let last = repo.git_patch("2222").unwrap();
+        repo.unapply_patch("master", &last).unwrap();
+        match repo.push_git("master", std::io::sink()) {
+            Err(Error::GitDiverged(b)) => assert_eq!(b, "master"),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn marks() {
+        let marks = read_marks(&b":1 abc\n\n:2 def\n"[..]).unwrap();
+        assert_eq!(marks[&1], "abc");
+        assert_eq!(marks[&2], "def");
+        match read_marks(&b":1 abc\n2 def\n"[..]) {
+            Err(Error::GitMarks(msg)) => assert_eq!(msg, "line 2 isn't a mark and an id"),
+            x => panic!("unexpected result {:?}", x),
+        }
+
+        let mut repo = Repo::init_tmp();
+        let id = repo.diff("master", b"a\n").unwrap().changes();
+        let id = repo.create_patch("Author", "Msg", id).unwrap();
+        repo.apply_patch("master", &id).unwrap();
+        match repo.record_git_marks("master", &[id], &b""[..]) {
+            Err(Error::GitMarks(_)) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+        assert_eq!(repo.git_tip("master"), None);
+    }
+}
@@ -0,0 +1,274 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Importing the changesets that `hg export` writes.
+//
+// Each changeset starts with a "# HG changeset patch" line, followed by some header lines (which
+// also start with "#"), the commit message, and the changeset's diff against its parent. The diffs
+// are applied to the files on the branch one after the other, in the same way as
+// `Repo::apply_unified_diff` applies a diff.
+
+use chrono::{TimeZone, Utc};
+use std::io::BufRead;
+
+use crate::diff_import;
+use crate::{Error, PatchHeader, PatchId, Repo};
+
+/// The key, in the extra metadata of an imported patch (see [`PatchHeader::extra`]), whose value
+/// is the id of the mercurial changeset that the patch was imported from.
+pub const HG_NODE_KEY: &str = "hg-node";
+
+const CHANGESET_START: &str = "# HG changeset patch";
+
+struct Changeset {
+    header: PatchHeader,
+    // The number of lines in the input before the diff.
+    diff_start: usize,
+    // The lines of the diff, including their line endings.
+    diff: Vec<Vec<u8>>,
+}
+
+fn error<T>(line: usize, msg: &str) -> Result<T, Error> {
+    Err(Error::HgExport(line, msg.to_owned()))
+}
+
+fn text(line: &[u8]) -> String {
+    String::from_utf8_lossy(line)
+        .trim_end_matches(&['\n', '\r'][..])
+        .to_owned()
+}
+
+// Splits the input into changesets. Anything before the first changeset (like the headers of an
+// email) is ignored.
+fn changesets(lines: &[Vec<u8>]) -> Result<Vec<Changeset>, Error> {
+    let is_start = |l: &Vec<u8>| text(l) == CHANGESET_START;
+    let mut pos = match lines.iter().position(is_start) {
+        Some(pos) => pos,
+        None => return error(1, "there are no changesets"),
+    };
+
+    let mut ret = Vec::new();
+    while pos < lines.len() {
+        let start = pos + 1;
+        pos += 1;
+        let mut author = None;
+        let mut timestamp = None;
+        let mut node = None;
+        let mut parents = 0;
+        while let Some(line) = lines.get(pos).map(|l| text(l)) {
+            let field = match line.strip_prefix("# ") {
+                Some(field) => field,
+                None => break,
+            };
+            pos += 1;
+            if let Some(user) = field.strip_prefix("User ") {
+                author = Some(user.trim().to_owned());
+            } else if let Some(date) = field.strip_prefix("Date ") {
+                // The time is in seconds since the epoch, followed by the time zone's offset.
+                let secs = date.split_whitespace().next().and_then(|s| s.parse().ok());
+                match secs.and_then(|s| Utc.timestamp_opt(s, 0).single()) {
+                    Some(t) => timestamp = Some(t),
+                    None => return error(pos, &format!("invalid date {:?}", date)),
+                }
+            } else if let Some(id) = field.strip_prefix("Node ID ") {
+                node = Some(id.trim().to_owned());
+            } else if field.starts_with("Parent ") {
+                parents += 1;
+                if parents > 1 {
+                    return error(pos, "merges aren't supported");
+                }
+            }
+        }
+
+        let mut message = Vec::new();
+        while pos < lines.len() && !lines[pos].starts_with(b"diff ") && !is_start(&lines[pos]) {
+            message.push(text(&lines[pos]));
+            pos += 1;
+        }
+        let diff_start = pos;
+        while pos < lines.len() && !is_start(&lines[pos]) {
+            pos += 1;
+        }
+
+        let (author, timestamp) = match (author, timestamp) {
+            (Some(author), Some(timestamp)) => (author, timestamp),
+            _ => return error(start, "the changeset needs a user and a date"),
+        };
+        let description = message.join("\n").trim().to_owned();
+        let mut header = match (author.find('<'), author.rfind('>')) {
+            (Some(open), Some(close)) if open < close => {
+                let mut header = PatchHeader::new(author[..open].trim().to_owned(), description);
+                header.email = Some(author[open + 1..close].to_owned());
+                header
+            }
+            _ => PatchHeader::new(author, description),
+        };
+        header.timestamp = timestamp;
+        if let Some(node) = node {
+            header.extra.insert(HG_NODE_KEY.to_owned(), node);
+        }
+        ret.push(Changeset {
+            header,
+            diff_start,
+            diff: lines[diff_start..pos].to_vec(),
+        });
+    }
+    Ok(ret)
+}
+
+pub(crate) fn import<R: BufRead>(
+    repo: &mut Repo,
+    branch: &str,
+    mut input: R,
+) -> Result<Vec<PatchId>, Error> {
+    let mut lines = Vec::new();
+    loop {
+        let mut line = Vec::new();
+        if input.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        lines.push(line);
+    }
+    let changesets = changesets(&lines)?;
+
+    let config = repo.config();
+    repo.transaction(|repo| {
+        let mut ret = Vec::new();
+        for cs in changesets {
+            let changes =
+                match diff_import::diff_changes(repo, &config, branch, cs.diff, cs.diff_start)? {
+                    Some(changes) => changes,
+                    None => continue,
+                };
+            let id = repo.create_patch_with_header(cs.header, changes)?;
+            repo.apply_patch(branch, &id)?;
+            ret.push(id);
+        }
+        Ok(ret)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemoryWorkingCopy, RecordOptions};
+
+    const EXPORT: &str = "\
+# HG changeset patch
+# User Alice <alice@example.com>
+# Date 1546398245 0
+#      Wed Jan 02 03:04:05 2019 +0000
+# Node ID 1111111111111111111111111111111111111111
+# Parent  0000000000000000000000000000000000000000
+Add a file
+
+With a longer description.
+
+diff -r 000000000000 -r 111111111111 a.txt
+--- /dev/null\tThu Jan 01 00:00:00 1970 +0000
++++ b/a.txt\tWed Jan 02 03:04:05 2019 +0000
+@@ -0,0 +1,2 @@
++foo
++bar
+# HG changeset patch
+# User bob
+# Date 1546400000 -3600
+#      Wed Jan 02 04:33:20 2019 +0100
+# Node ID 2222222222222222222222222222222222222222
+# Parent  1111111111111111111111111111111111111111
+Move it
+
+diff --git a/a.txt b/b.txt
+rename from a.txt
+rename to b.txt
+--- a/a.txt
++++ b/b.txt
+@@ -1,2 +1,2 @@
+ foo
+-bar
++baz
+diff --git a/old.txt b/old.txt
+deleted file mode 100644
+--- a/old.txt
++++ /dev/null
+@@ -1,1 +0,0 @@
+-old
+";
+
+    fn repo() -> Repo {
+        let wc = MemoryWorkingCopy::new();
+        let mut repo = Repo::init_in_memory(wc.clone());
+        wc.write("old.txt", b"old\n");
+        repo.track_file("old.txt").unwrap();
+        repo.record("Author", "Msg", &RecordOptions::default())
+            .unwrap();
+        repo
+    }
+
+    #[test]
+    fn import() {
+        let mut repo = repo();
+        let ids = repo.import_hg("master", EXPORT.as_bytes()).unwrap();
+        assert_eq!(ids.len(), 2);
+
+        let files = repo.files("master").unwrap().collect::<Vec<_>>();
+        assert_eq!(files, vec!["b.txt"]);
+        let b = repo.file_at("master", "b.txt").unwrap();
+        assert_eq!(b.as_bytes(), b"foo\nbaz\n");
+
+        let first = repo.patch_header(&ids[0]).unwrap();
+        assert_eq!(first.author, "Alice");
+        assert_eq!(first.email.as_deref(), Some("alice@example.com"));
+        assert_eq!(
+            first.description,
+            "Add a file\n\nWith a longer description."
+        );
+        assert_eq!(first.timestamp, Utc.timestamp_opt(1546398245, 0).unwrap());
+        assert_eq!(first.extra[HG_NODE_KEY], "1".repeat(40));
+        let second = repo.patch_header(&ids[1]).unwrap();
+        assert_eq!(second.author, "bob");
+        assert_eq!(second.email, None);
+        assert_eq!(second.description, "Move it");
+    }
+
+    #[test]
+    fn failed_hunk() {
+        // Nothing is imported if one of the changesets doesn't apply.
+        let mut repo = repo();
+        let export = EXPORT.replace("-bar", "-qux");
+        match repo.import_hg("master", export.as_bytes()) {
+            Err(Error::HunkFailed(path, 1)) => assert_eq!(path, "b.txt"),
+            x => panic!("unexpected result {:?}", x),
+        }
+        assert_eq!(repo.files("master").unwrap().count(), 1);
+        assert_eq!(repo.patches("master").count(), 1);
+    }
+
+    #[test]
+    fn errors() {
+        let mut repo = repo();
+        let line = |e| match e {
+            Err(Error::HgExport(line, _)) | Err(Error::DiffSyntax(line, _)) => line,
+            x => panic!("unexpected result {:?}", x),
+        };
+
+        let merge = EXPORT.replace("# Parent  1111", "# Parent  3333\n# Parent  1111");
+        assert_eq!(line(repo.import_hg("master", merge.as_bytes())), 23);
+        let bad_date = EXPORT.replace("# Date 1546400000", "# Date yesterday");
+        assert_eq!(line(repo.import_hg("master", bad_date.as_bytes())), 19);
+        let no_user = EXPORT.replace("# User bob\n", "");
+        assert_eq!(line(repo.import_hg("master", no_user.as_bytes())), 17);
+        let bad_hunk = EXPORT.replace("@@ -1,2 +1,2 @@", "@@ -1,2 @@");
+        assert_eq!(line(repo.import_hg("master", bad_hunk.as_bytes())), 30);
+        assert_eq!(line(repo.import_hg("master", &b"diff\n"[..])), 1);
+        assert_eq!(repo.patches("master").count(), 1);
+    }
+}
@@ -0,0 +1,163 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use crate::{Error, PatchId, PatchQuery, Repo};
+
+/// An iterator over the patches on a branch, newest first (see [`Repo::log`]).
+///
+/// Every patch comes before all of the patches that it depends on. Apart from that, the patches
+/// are ordered by the time that they were created, newest first.
+pub struct Log<'a> {
+    repo: &'a Repo,
+    // The patches that haven't been returned yet, and how many of their dependents haven't been
+    // returned yet.
+    waiting: HashMap<PatchId, usize>,
+    // The patches whose dependents have all been returned, with their positions in `order`.
+    ready: BinaryHeap<(usize, PatchId)>,
+    // The position of each patch on the branch, if they were sorted by creation time.
+    order: HashMap<PatchId, usize>,
+    // The patches that match the query, or `None` if they all match.
+    matches: Option<HashSet<PatchId>>,
+}
+
+impl<'a> Log<'a> {
+    pub(crate) fn new(repo: &'a Repo, branch: &str, query: &PatchQuery) -> Result<Log<'a>, Error> {
+        if !repo.branches().any(|b| b == branch) {
+            return Err(Error::UnknownBranch(branch.to_owned()));
+        }
+        // The metadata index can sort the patches and filter them without opening any of them.
+        let on_branch = repo.patches(branch).collect::<HashSet<_>>();
+        let order = repo
+            .find_patches(&PatchQuery::default())
+            .into_iter()
+            .filter(|id| on_branch.contains(id))
+            .enumerate()
+            .map(|(i, id)| (id, i))
+            .collect::<HashMap<_, _>>();
+        let matches = if *query == PatchQuery::default() {
+            None
+        } else {
+            Some(repo.find_patches(query).into_iter().collect())
+        };
+
+        let waiting = order
+            .keys()
+            .map(|id| {
+                let dependents = repo.patch_rev_deps(id).filter(|p| order.contains_key(p));
+                (*id, dependents.count())
+            })
+            .collect::<HashMap<_, _>>();
+        let ready = waiting
+            .iter()
+            .filter(|(_, &count)| count == 0)
+            .map(|(id, _)| (order[id], *id))
+            .collect();
+        Ok(Log {
+            repo,
+            waiting,
+            ready,
+            order,
+            matches,
+        })
+    }
+}
+
+impl<'a> Iterator for Log<'a> {
+    type Item = PatchId;
+
+    fn next(&mut self) -> Option<PatchId> {
+        loop {
+            let (_, id) = self.ready.pop()?;
+            for dep in self.repo.patch_deps(&id) {
+                if let Some(count) = self.waiting.get_mut(dep) {
+                    *count -= 1;
+                    if *count == 0 {
+                        self.ready.push((self.order[dep], *dep));
+                    }
+                }
+            }
+            self.waiting.remove(&id);
+
+            if self.matches.as_ref().is_none_or(|m| m.contains(&id)) {
+                return Some(id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Change, Changes, PatchHeader, PatchId, PatchQuery, Repo};
+
+    fn create(repo: &mut Repo, author: &str, changes: Changes, time: &str) -> PatchId {
+        let mut header = PatchHeader::new(author.to_owned(), "Msg".to_owned());
+        header.timestamp = time.parse().unwrap();
+        let id = repo.create_patch_with_header(header, changes).unwrap();
+        repo.apply_patch("master", &id).unwrap();
+        id
+    }
+
+    fn new_file(path: &str) -> Changes {
+        Changes {
+            changes: vec![Change::NewFile {
+                path: path.to_owned(),
+            }],
+        }
+    }
+
+    #[test]
+    fn log() {
+        let mut repo = Repo::init_tmp();
+        let a = create(
+            &mut repo,
+            "Alice",
+            new_file("a.txt"),
+            "2019-01-01T00:00:00Z",
+        );
+        let b = create(&mut repo, "Bob", new_file("b.txt"), "2019-02-01T00:00:00Z");
+
+        // This patch depends on `b`, but claims to be older than it (and even older than `a`).
+        let file = repo.file_id("master", "b.txt").unwrap();
+        let diff = repo.diff_file("master", "b.txt", b"b\n").unwrap();
+        let mut changes = Changes { changes: vec![] };
+        changes.add_file_changes(file, diff.changes());
+        let c = create(&mut repo, "Alice", changes, "2018-12-01T00:00:00Z");
+        let d = create(&mut repo, "Bob", new_file("d.txt"), "2019-03-01T00:00:00Z");
+
+        // A patch that isn't on the branch doesn't show up.
+        let header = PatchHeader::new("Alice".to_owned(), "Msg".to_owned());
+        repo.create_patch_with_header(header, new_file("e.txt"))
+            .unwrap();
+
+        let log = |query: PatchQuery| repo.log("master", &query).unwrap().collect::<Vec<_>>();
+        // `c` has to come before `b`, but otherwise it is sorted by its creation time.
+        assert_eq!(log(PatchQuery::default()), vec![d, a, c, b]);
+        let alice = PatchQuery {
+            author: Some("alice".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(log(alice), vec![a, c]);
+        let path = PatchQuery {
+            path: Some("b.txt".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(log(path), vec![c, b]);
+        let since = PatchQuery {
+            since: Some("2019-01-15T00:00:00Z".parse().unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(log(since), vec![d, b]);
+
+        assert!(repo.log("other", &PatchQuery::default()).is_err());
+    }
+}
@@ -0,0 +1,457 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// A read-only file system with the states of a repository, for `ojo mount`.
+//
+// The file system is organized by inodes, like the kernel's view of a file system (and FUSE's), so
+// that a FUSE driver only has to pass the requests along. Inodes are handed out as the directories
+// are listed (or looked up in), and they stay the same for as long as the `HistoryFs` lives.
+//
+// The state of a branch is just the branch. The other states (of a patch, or of a tag) are put
+// together on scratch branches, the first time that something inside them is looked at. Nothing
+// is ever written to disk, so this works with a repository that was opened read-only.
+
+use chrono::{DateTime, TimeZone, Utc};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::tracked::branch_paths;
+use crate::{ApplyPolicy, Error, PatchId, Repo, DEFAULT_WORKING_FILE};
+
+// The scratch branches are called this, followed by a number. Branch names can't normally contain
+// NUL characters, so they can't clash with real branches.
+const SCRATCH_PREFIX: &str = "\0mount-";
+
+/// The kind of an entry in a [`HistoryFs`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FsKind {
+    /// A directory.
+    Directory,
+    /// A regular file.
+    File,
+    /// A symbolic link, whose contents are its target.
+    Symlink,
+}
+
+/// An entry in a directory of a [`HistoryFs`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FsEntry {
+    /// The name of the entry.
+    pub name: String,
+    /// The inode of the entry.
+    pub inode: u64,
+    /// What kind of entry it is.
+    pub kind: FsKind,
+}
+
+/// The attributes of an inode in a [`HistoryFs`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FsAttr {
+    /// The inode.
+    pub inode: u64,
+    /// What kind of inode it is.
+    pub kind: FsKind,
+    /// The size of the file's contents (or zero, for a directory).
+    pub size: u64,
+    /// Is the file executable? Directories always are.
+    pub executable: bool,
+    /// The time of the newest patch in the state that the inode belongs to.
+    pub modified: DateTime<Utc>,
+}
+
+// A state of the repository, which is the contents of a branch.
+struct State {
+    branch: String,
+    // The patches that need to be applied to the (scratch) branch before it can be used, or `None`
+    // if that has been done.
+    pending: Option<Vec<PatchId>>,
+    modified: DateTime<Utc>,
+}
+
+#[derive(Clone)]
+enum NodeKind {
+    // The directories above the states, with the names of the states inside them.
+    Index(BTreeMap<String, usize>),
+    // A directory or a file in a state. The top directory of a state has an empty path.
+    Dir { state: usize, path: String },
+    File { state: usize, path: String },
+}
+
+struct Node {
+    kind: NodeKind,
+    // The entries of a directory, once they are known.
+    children: Option<Vec<FsEntry>>,
+}
+
+/// A read-only view of the history of a repository, as a file system.
+///
+/// The top directory has three directories in it: `branches`, with a directory for every branch;
+/// `patches`, with a directory for every patch, named by its id (see [`PatchId::to_base64`]); and
+/// `tags`, with a directory for every tag. Each of those has the files that a checkout would have:
+/// the ones on the branch, the ones that the patch and its dependencies make, or the ones that
+/// the tag's patches make. Files are rendered the first time that they are read, with conflict
+/// markers if they have conflicts (like [`Repo::render_file`]). Branch and tag names have `%`
+/// written as `%25` and `/` as `%2F`.
+///
+/// The branches, patches and tags are the ones that the repository had when the `HistoryFs` was
+/// made. Everything is addressed by inode, starting from [`HistoryFs::ROOT`], which is the same
+/// as how FUSE works; see `ojo mount`.
+pub struct HistoryFs {
+    repo: Repo,
+    states: Vec<State>,
+    // The node with inode `i` is `nodes[i - 1]`.
+    nodes: Vec<Node>,
+    // The contents of the files that have been read, by inode.
+    contents: HashMap<u64, Vec<u8>>,
+}
+
+// Escapes the name of a branch or a tag, so that it can be the name of a directory.
+fn escape(name: &str) -> String {
+    name.replace('%', "%25").replace('/', "%2F")
+}
+
+// Returns the time of the newest of some patches.
+fn newest<'a, I: IntoIterator<Item = &'a PatchId>>(
+    repo: &Repo,
+    patches: I,
+) -> Result<DateTime<Utc>, Error> {
+    let mut ret = Utc.timestamp_opt(0, 0).unwrap();
+    for p in patches {
+        ret = ret.max(repo.patch_header(p)?.timestamp);
+    }
+    Ok(ret)
+}
+
+impl HistoryFs {
+    /// The inode of the top directory.
+    pub const ROOT: u64 = 1;
+
+    /// Makes a file system with the states of a repository.
+    pub fn new(repo: Repo) -> Result<HistoryFs, Error> {
+        let mut ret = HistoryFs {
+            repo,
+            states: Vec::new(),
+            nodes: Vec::new(),
+            contents: HashMap::new(),
+        };
+
+        let mut branches = BTreeMap::new();
+        let names = ret
+            .repo
+            .branches()
+            .map(|b| b.to_owned())
+            .collect::<Vec<_>>();
+        for branch in names {
+            let modified = newest(&ret.repo, ret.repo.patches(&branch))?;
+            branches.insert(escape(&branch), ret.states.len());
+            ret.states.push(State {
+                branch,
+                pending: None,
+                modified,
+            });
+        }
+
+        let mut patches = BTreeMap::new();
+        let mut ids = ret
+            .repo
+            .all_patches()
+            .filter(|p| !ret.repo.is_ghost(p))
+            .cloned()
+            .collect::<Vec<_>>();
+        ids.sort();
+        for id in ids {
+            let modified = ret.repo.patch_header(&id)?.timestamp;
+            patches.insert(id.to_base64(), ret.states.len());
+            ret.push_scratch(vec![id], modified);
+        }
+
+        let mut tags = BTreeMap::new();
+        for tag in ret.repo.tags()? {
+            let modified = newest(&ret.repo, tag.patches())?;
+            tags.insert(escape(tag.name()), ret.states.len());
+            ret.push_scratch(tag.patches().to_vec(), modified);
+        }
+
+        ret.push_node(NodeKind::Index(BTreeMap::new()));
+        let mut top = Vec::new();
+        for (name, index) in [("branches", branches), ("patches", patches), ("tags", tags)] {
+            top.push(FsEntry {
+                name: name.to_owned(),
+                inode: ret.push_node(NodeKind::Index(index)),
+                kind: FsKind::Directory,
+            });
+        }
+        // The top directory holds the three directories of states.
+        ret.nodes[0].children = Some(top);
+        Ok(ret)
+    }
+
+    fn push_scratch(&mut self, patches: Vec<PatchId>, modified: DateTime<Utc>) {
+        let branch = format!("{}{}", SCRATCH_PREFIX, self.states.len());
+        self.states.push(State {
+            branch,
+            pending: Some(patches),
+            modified,
+        });
+    }
+
+    fn node(&self, inode: u64) -> Result<&Node, Error> {
+        inode
+            .checked_sub(1)
+            .and_then(|i| self.nodes.get(i as usize))
+            .ok_or(Error::UnknownInode(inode))
+    }
+
+    // Returns the branch with the files of a state, putting it together if it hasn't been yet.
+    fn branch(&mut self, state: usize) -> Result<String, Error> {
+        if let Some(patches) = self.states[state].pending.take() {
+            let branch = self.states[state].branch.clone();
+            // Nothing that happens here is saved, so it's allowed even if the repository was
+            // opened read-only.
+            let readonly = std::mem::replace(&mut self.repo.readonly, false);
+            let ret = self.repo.transaction(|repo| {
+                repo.create_branch(&branch)?;
+                for p in &patches {
+                    repo.apply_without_hooks(&branch, p, ApplyPolicy::Cascade)?;
+                }
+                Ok(())
+            });
+            self.repo.readonly = readonly;
+            if let Err(e) = ret {
+                self.states[state].pending = Some(patches);
+                return Err(e);
+            }
+        }
+        Ok(self.states[state].branch.clone())
+    }
+
+    fn kind(&mut self, inode: u64) -> Result<FsKind, Error> {
+        match self.node(inode)?.kind.clone() {
+            NodeKind::Index(_) | NodeKind::Dir { .. } => Ok(FsKind::Directory),
+            NodeKind::File { state, path } => {
+                let branch = self.branch(state)?;
+                if path != DEFAULT_WORKING_FILE && self.repo.is_symlink(&branch, &path)? {
+                    Ok(FsKind::Symlink)
+                } else {
+                    Ok(FsKind::File)
+                }
+            }
+        }
+    }
+
+    /// Returns the attributes of an inode.
+    ///
+    /// Since this needs the size of a file, it renders the file (if it hasn't been already).
+    pub fn attr(&mut self, inode: u64) -> Result<FsAttr, Error> {
+        let kind = self.kind(inode)?;
+        let (size, executable, modified) = match self.node(inode)?.kind.clone() {
+            NodeKind::Index(_) => (0, true, Utc.timestamp_opt(0, 0).unwrap()),
+            NodeKind::Dir { state, .. } => (0, true, self.states[state].modified),
+            NodeKind::File { state, path } => {
+                let size = self.contents(inode)?.len() as u64;
+                let branch = self.branch(state)?;
+                let executable =
+                    path != DEFAULT_WORKING_FILE && self.repo.is_executable(&branch, &path)?;
+                (size, executable, self.states[state].modified)
+            }
+        };
+        Ok(FsAttr {
+            inode,
+            kind,
+            size,
+            executable,
+            modified,
+        })
+    }
+
+    /// Returns the entries of a directory, in sorted order.
+    pub fn read_dir(&mut self, inode: u64) -> Result<Vec<FsEntry>, Error> {
+        if let Some(children) = &self.node(inode)?.children {
+            return Ok(children.clone());
+        }
+
+        let mut children = Vec::new();
+        match self.node(inode)?.kind.clone() {
+            NodeKind::Index(states) => {
+                for (name, state) in states {
+                    let inode = self.push_node(NodeKind::Dir {
+                        state,
+                        path: String::new(),
+                    });
+                    children.push(FsEntry {
+                        name,
+                        inode,
+                        kind: FsKind::Directory,
+                    });
+                }
+            }
+            NodeKind::Dir { state, path } => {
+                let branch = self.branch(state)?;
+                let prefix = if path.is_empty() {
+                    String::new()
+                } else {
+                    format!("{}/", path)
+                };
+                // The files and directories directly inside this one.
+                let mut names = BTreeMap::new();
+                for p in branch_paths(&self.repo, &branch)? {
+                    if let Some(rest) = p.strip_prefix(&prefix) {
+                        match rest.find('/') {
+                            Some(i) => names.insert(rest[..i].to_owned(), true),
+                            None => names.insert(rest.to_owned(), false),
+                        };
+                    }
+                }
+                for (name, is_dir) in names {
+                    let path = format!("{}{}", prefix, name);
+                    let inode = if is_dir {
+                        self.push_node(NodeKind::Dir { state, path })
+                    } else {
+                        self.push_node(NodeKind::File { state, path })
+                    };
+                    let kind = self.kind(inode)?;
+                    children.push(FsEntry { name, inode, kind });
+                }
+            }
+            NodeKind::File { .. } => return Err(Error::UnknownInode(inode)),
+        }
+        self.nodes[inode as usize - 1].children = Some(children.clone());
+        Ok(children)
+    }
+
+    fn push_node(&mut self, kind: NodeKind) -> u64 {
+        self.nodes.push(Node {
+            kind,
+            children: None,
+        });
+        self.nodes.len() as u64
+    }
+
+    /// Looks up an entry in a directory, and returns its attributes (or `None`, if the directory
+    /// doesn't have an entry with that name).
+    pub fn lookup(&mut self, parent: u64, name: &str) -> Result<Option<FsAttr>, Error> {
+        match self.read_dir(parent)?.into_iter().find(|e| e.name == name) {
+            Some(entry) => Ok(Some(self.attr(entry.inode)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the contents of a file (or the target of a symbolic link).
+    pub fn contents(&mut self, inode: u64) -> Result<&[u8], Error> {
+        if !self.contents.contains_key(&inode) {
+            let (state, path) = match self.node(inode)?.kind.clone() {
+                NodeKind::File { state, path } => (state, path),
+                _ => return Err(Error::UnknownInode(inode)),
+            };
+            let branch = self.branch(state)?;
+            let mut contents = Vec::new();
+            if path == DEFAULT_WORKING_FILE {
+                self.repo.render(&branch, &mut contents)?;
+            } else {
+                self.repo.render_file(&branch, &path, &mut contents)?;
+            }
+            self.contents.insert(inode, contents);
+        }
+        Ok(&self.contents[&inode])
+    }
+
+    /// Reads up to `size` bytes of a file, starting at `offset`.
+    pub fn read(&mut self, inode: u64, offset: u64, size: usize) -> Result<&[u8], Error> {
+        let contents = self.contents(inode)?;
+        let start = (offset as usize).min(contents.len());
+        let end = start.saturating_add(size).min(contents.len());
+        Ok(&contents[start..end])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Change, Changes, MemoryWorkingCopy, RecordOptions};
+
+    // Finds the inode at a path, starting from the top directory.
+    fn find(fs: &mut HistoryFs, path: &str) -> Option<FsAttr> {
+        let mut attr = fs.attr(HistoryFs::ROOT).unwrap();
+        for name in path.split('/') {
+            attr = fs.lookup(attr.inode, name).unwrap()?;
+        }
+        Some(attr)
+    }
+
+    fn names(fs: &mut HistoryFs, path: &str) -> Vec<String> {
+        let inode = find(fs, path).unwrap().inode;
+        fs.read_dir(inode)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect()
+    }
+
+    fn read(fs: &mut HistoryFs, path: &str) -> Vec<u8> {
+        let inode = find(fs, path).unwrap().inode;
+        fs.contents(inode).unwrap().to_vec()
+    }
+
+    #[test]
+    fn states() {
+        let wc = MemoryWorkingCopy::new();
+        let mut repo = Repo::init_in_memory(wc.clone());
+        wc.write("a.txt", b"a\n");
+        wc.write("dir/run.sh", b"run\n");
+        repo.track_file("a.txt").unwrap();
+        repo.track_file("dir/run.sh").unwrap();
+        let first = repo
+            .record("me", "first", &RecordOptions::default())
+            .unwrap();
+        let run = repo.file_ref("master", "dir/run.sh").unwrap();
+        let changes = Changes {
+            changes: vec![Change::SetExecutable {
+                file: run,
+                executable: true,
+            }],
+        };
+        let second = repo.create_patch("me", "second", changes).unwrap();
+        repo.apply_patch("master", &second).unwrap();
+        repo.create_branch("feature/x").unwrap();
+        repo.apply_patch("feature/x", &first).unwrap();
+        let tag = repo.create_tag("feature/x", "v1", "me", "tag").unwrap();
+
+        let mut fs = HistoryFs::new(repo).unwrap();
+        assert_eq!(names(&mut fs, "branches"), vec!["feature%2Fx", "master"]);
+        assert_eq!(names(&mut fs, "branches/master"), vec!["a.txt", "dir"]);
+        assert_eq!(read(&mut fs, "branches/master/a.txt"), b"a\n");
+        let attr = find(&mut fs, "branches/master/dir/run.sh").unwrap();
+        assert_eq!(attr.kind, FsKind::File);
+        assert_eq!(attr.size, 4);
+        assert!(attr.executable);
+        assert!(
+            !find(&mut fs, "branches/feature%2Fx/dir/run.sh")
+                .unwrap()
+                .executable
+        );
+        assert_eq!(find(&mut fs, "branches/master/b.txt"), None);
+
+        // The state of a patch has the patch and its dependencies.
+        let mut patches = vec![first.to_base64(), second.to_base64(), tag.to_base64()];
+        patches.sort();
+        assert_eq!(names(&mut fs, "patches"), patches);
+        let path = format!("patches/{}/dir/run.sh", second.to_base64());
+        assert!(find(&mut fs, &path).unwrap().executable);
+        let path = format!("patches/{}/a.txt", first.to_base64());
+        assert_eq!(read(&mut fs, &path), b"a\n");
+        let inode = find(&mut fs, &path).unwrap().inode;
+        assert_eq!(fs.read(inode, 1, 10).unwrap(), b"\n");
+        assert_eq!(fs.read(inode, 5, 10).unwrap(), b"");
+
+        assert_eq!(names(&mut fs, "tags"), vec!["v1"]);
+        assert_eq!(read(&mut fs, "tags/v1/a.txt"), b"a\n");
+        assert!(fs.attr(1000).is_err());
+    }
+}
@@ -0,0 +1,317 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Hooks that run before and after recording and applying patches. There are two kinds: external
+// commands, which live in the repository's config, and Rust callbacks, which only last as long as
+// the `Repo` that they were registered with.
+
+use std::fmt;
+use std::process::Command;
+
+use crate::{Error, PatchHeader, PatchId, Repo};
+
+/// The points at which hooks run (see [`Repo::add_hook`](crate::Repo::add_hook)).
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HookPoint {
+    /// Before [`Repo::record`](crate::Repo::record) creates a patch. The hook can't see the
+    /// patch's id, because the patch doesn't exist yet.
+    PreRecord,
+    /// After [`Repo::record`](crate::Repo::record) has created and applied a patch.
+    PostRecord,
+    /// Before [`Repo::apply`](crate::Repo::apply) changes a branch.
+    PreApply,
+    /// After [`Repo::apply`](crate::Repo::apply) has changed a branch.
+    PostApply,
+}
+
+impl HookPoint {
+    /// Does this hook run before the operation (and so get a chance to veto it)?
+    pub fn is_pre(self) -> bool {
+        match self {
+            HookPoint::PreRecord | HookPoint::PreApply => true,
+            HookPoint::PostRecord | HookPoint::PostApply => false,
+        }
+    }
+
+    /// The name of the hook point, as it appears in the config.
+    pub fn name(self) -> &'static str {
+        match self {
+            HookPoint::PreRecord => "pre-record",
+            HookPoint::PostRecord => "post-record",
+            HookPoint::PreApply => "pre-apply",
+            HookPoint::PostApply => "post-apply",
+        }
+    }
+}
+
+impl fmt::Display for HookPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// What a hook gets told about the operation that it is running for.
+#[derive(Debug)]
+pub struct HookContext<'a> {
+    /// The point at which the hook is running.
+    pub point: HookPoint,
+    /// The branch that is being changed.
+    pub branch: &'a str,
+    /// The patch that is being applied, or that was recorded. This is `None` for
+    /// [`HookPoint::PreRecord`].
+    pub patch: Option<&'a PatchId>,
+    /// The metadata of the patch.
+    pub header: &'a PatchHeader,
+}
+
+/// A hook that was registered with [`Repo::add_hook`](crate::Repo::add_hook).
+///
+/// If a hook that runs before an operation returns an error, the operation is cancelled with
+/// [`Error::HookFailed`].
+pub type Hook = Box<dyn Fn(&HookContext<'_>) -> Result<(), String> + Send + Sync>;
+
+// The hooks that were registered on a repository.
+#[derive(Default)]
+pub(crate) struct Hooks {
+    hooks: Vec<(HookPoint, Hook)>,
+}
+
+impl Hooks {
+    pub(crate) fn add(&mut self, point: HookPoint, hook: Hook) {
+        self.hooks.push((point, hook));
+    }
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.hooks.iter().map(|(point, _)| point))
+            .finish()
+    }
+}
+
+#[cfg(unix)]
+fn shell(cmd: &str) -> Command {
+    let mut ret = Command::new("sh");
+    ret.arg("-c").arg(cmd);
+    ret
+}
+
+#[cfg(windows)]
+fn shell(cmd: &str) -> Command {
+    let mut ret = Command::new("cmd");
+    ret.arg("/C").arg(cmd);
+    ret
+}
+
+// Runs one of the commands from the config, returning a description of what went wrong if it
+// didn't succeed.
+fn run_command(repo: &Repo, cmd: &str, ctx: &HookContext<'_>) -> Result<(), String> {
+    let mut command = shell(cmd);
+    // A repository that only lives in memory doesn't have a root directory.
+    if !repo.root_dir.as_os_str().is_empty() {
+        command.current_dir(&repo.root_dir);
+    }
+    command
+        .env("OJO_HOOK", ctx.point.name())
+        .env("OJO_BRANCH", ctx.branch)
+        .env("OJO_AUTHOR", &ctx.header.author)
+        .env("OJO_DESCRIPTION", &ctx.header.description);
+    if let Some(email) = &ctx.header.email {
+        command.env("OJO_EMAIL", email);
+    }
+    if let Some(id) = ctx.patch {
+        command.env("OJO_PATCH", id.to_base64());
+    }
+    match command.status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("\"{}\" failed ({})", cmd, status)),
+        Err(e) => Err(format!("could not run \"{}\": {}", cmd, e)),
+    }
+}
+
+// Runs all the hooks for a point: first the commands from the config, and then the registered
+// callbacks. Hooks that run before an operation stop at the first failure, which is returned as
+// an error; for hooks that run afterwards, it's too late to do anything about failures, so they
+// just get logged.
+//
+// Hooks are for the user's branches, so they don't run for the scratch branches that are used
+// internally (like the one that a dry run merges into), whose names start with a NUL character.
+pub(crate) fn run(
+    repo: &Repo,
+    point: HookPoint,
+    branch: &str,
+    patch: Option<&PatchId>,
+    header: &PatchHeader,
+) -> Result<(), Error> {
+    if branch.starts_with('\0') {
+        return Ok(());
+    }
+    let ctx = HookContext {
+        point,
+        branch,
+        patch,
+        header,
+    };
+    let config = repo.config();
+    let commands = config.hooks.get(&point).into_iter().flatten();
+    let results = commands.map(|cmd| run_command(repo, cmd, &ctx)).chain(
+        repo.hooks
+            .hooks
+            .iter()
+            .filter(|(p, _)| *p == point)
+            .map(|(_, hook)| hook(&ctx)),
+    );
+    for result in results {
+        if let Err(msg) = result {
+            if point.is_pre() {
+                return Err(Error::HookFailed(point, msg));
+            }
+            warn!("The {} hook failed: {}", point, msg);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RecordOptions;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn callbacks() {
+        let mut repo = Repo::init_tmp_with_working_copy("hooks-callbacks");
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        for &point in &[
+            HookPoint::PreRecord,
+            HookPoint::PostRecord,
+            HookPoint::PreApply,
+            HookPoint::PostApply,
+        ] {
+            let seen = Arc::clone(&seen);
+            repo.add_hook(
+                point,
+                Box::new(move |ctx| {
+                    seen.lock().unwrap().push((ctx.point, ctx.patch.cloned()));
+                    Ok(())
+                }),
+            );
+        }
+        repo.add_hook(
+            HookPoint::PreRecord,
+            Box::new(|ctx| {
+                if ctx.header.description.is_empty() {
+                    Err("empty description".to_owned())
+                } else {
+                    Ok(())
+                }
+            }),
+        );
+
+        fs::write(repo.root_dir.join("ojo_file.txt"), "a\n").unwrap();
+        let opts = RecordOptions::default();
+        match repo.record("Author", "", &opts) {
+            Err(Error::HookFailed(HookPoint::PreRecord, msg)) => {
+                assert_eq!(msg, "empty description")
+            }
+            x => panic!("unexpected result {:?}", x),
+        }
+        assert_eq!(repo.all_patches().count(), 0);
+
+        seen.lock().unwrap().clear();
+        let id = repo.record("Author", "Msg", &opts).unwrap();
+        // Recording doesn't count as applying.
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                (HookPoint::PreRecord, None),
+                (HookPoint::PostRecord, Some(id))
+            ]
+        );
+
+        seen.lock().unwrap().clear();
+        repo.create_branch("other").unwrap();
+        repo.apply_patch("other", &id).unwrap();
+        // Applying a patch that is already there does nothing, so there are no hooks.
+        repo.apply_patch("other", &id).unwrap();
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                (HookPoint::PreApply, Some(id)),
+                (HookPoint::PostApply, Some(id))
+            ]
+        );
+        fs::remove_dir_all(&repo.root_dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn commands() {
+        let mut repo = Repo::init_tmp_with_working_copy("hooks-commands");
+        let diff = repo.diff("master", b"a\n").unwrap();
+        let id = repo.create_patch("Author", "Msg", diff.changes()).unwrap();
+        repo.config.hooks.insert(
+            HookPoint::PreApply,
+            vec!["test \"$OJO_BRANCH\" = master".to_owned()],
+        );
+        repo.config.hooks.insert(
+            HookPoint::PostApply,
+            vec!["echo \"$OJO_PATCH $OJO_AUTHOR\" > applied".to_owned()],
+        );
+
+        repo.create_branch("other").unwrap();
+        match repo.apply_patch("other", &id) {
+            Err(Error::HookFailed(HookPoint::PreApply, _)) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+        assert_eq!(repo.patches("other").count(), 0);
+
+        repo.apply_patch("master", &id).unwrap();
+        assert_eq!(
+            fs::read_to_string(repo.root_dir.join("applied")).unwrap(),
+            format!("{} Author\n", id.to_base64())
+        );
+
+        // A failing post-apply hook doesn't undo anything.
+        repo.config
+            .hooks
+            .insert(HookPoint::PostApply, vec!["false".to_owned()]);
+        repo.config.hooks.remove(&HookPoint::PreApply);
+        repo.apply_patch("other", &id).unwrap();
+        assert_eq!(repo.patches("other").count(), 1);
+        fs::remove_dir_all(&repo.root_dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dry_run_merge() {
+        let mut repo = Repo::init_tmp_with_working_copy("hooks-dry-run");
+        let diff = repo.diff("master", b"a\n").unwrap();
+        let id = repo.create_patch("Author", "Msg", diff.changes()).unwrap();
+        repo.create_branch("other").unwrap();
+        repo.apply_patch("other", &id).unwrap();
+        repo.config
+            .hooks
+            .insert(HookPoint::PreApply, vec!["touch pre-applied".to_owned()]);
+
+        // A dry run doesn't apply anything to a real branch, so the hooks don't run.
+        let report = repo.merge("other", "master", true).unwrap();
+        assert_eq!(report.applied, vec![id]);
+        assert!(!repo.root_dir.join("pre-applied").exists());
+
+        repo.merge("other", "master", false).unwrap();
+        assert!(repo.root_dir.join("pre-applied").exists());
+        fs::remove_dir_all(&repo.root_dir).unwrap();
+    }
+}
@@ -0,0 +1,399 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::ops::Range;
+
+use crate::{Changes, Diff, Error, File, LineDiff, NodeId, PatchId, Renumbering};
+
+/// An identifier for a [`Hunk`].
+///
+/// The id of a hunk depends only on the hunk's contents and on the line that comes right before
+/// it. In particular, if some of the hunks in a [`PendingChanges`] are selected and recorded,
+/// the remaining hunks keep their ids.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct HunkId(u64);
+
+impl HunkId {
+    // Makes an id by hashing some data.
+    pub(crate) fn from_data(data: &[u8]) -> HunkId {
+        let mut id = [0; 8];
+        id.copy_from_slice(&Sha256::digest(data)[..8]);
+        HunkId(u64::from_le_bytes(id))
+    }
+}
+
+impl std::fmt::Display for HunkId {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(fmt, "{:016x}", self.0)
+    }
+}
+
+/// A group of adjacent changed lines in a diff.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Hunk {
+    /// The id of this hunk.
+    pub id: HunkId,
+    /// The part of [`PendingChanges::diff`] that this hunk covers. This range is never empty,
+    /// and it contains no [`LineDiff::Keep`]s.
+    pub range: Range<usize>,
+    /// The unchanged line right before this hunk, or `None` if the hunk is at the beginning.
+    pub before: Option<NodeId>,
+    /// The unchanged line right after this hunk, or `None` if the hunk is at the end.
+    pub after: Option<NodeId>,
+}
+
+/// Changes that haven't been recorded yet, divided into [`Hunk`]s.
+///
+/// The main point of dividing changes into hunks is to allow only some of them to be recorded
+/// (see [`PendingChanges::select`]).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PendingChanges {
+    diff: Diff,
+    hunks: Vec<Hunk>,
+}
+
+/// The changes that were left over after selecting some hunks from a [`PendingChanges`].
+///
+/// The remaining changes are relative to the file as it will be once the selected changes are
+/// recorded, and that file contains the lines added by the selected changes. Until those changes
+/// have been made into a patch, their lines are staged (see [`PatchId::staging`]). Therefore,
+/// the remaining changes can only be turned back into [`PendingChanges`] once the selected
+/// changes have been made into a patch and we know its id.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Remaining {
+    diff: Diff,
+    // How the staged lines will be renumbered when the selected changes are made into a patch.
+    renumbering: Renumbering,
+}
+
+// Appends a NodeId to some data that is going to be hashed.
+fn write_node_id(data: &mut Vec<u8>, id: &NodeId) {
+    data.extend_from_slice(&id.patch.data);
+    // Writing to a Vec can't fail.
+    data.write_u64::<LittleEndian>(id.node).unwrap();
+}
+
+fn hunk_id(diff: &Diff, range: &Range<usize>) -> HunkId {
+    let mut data = Vec::new();
+    // Include the line just before the hunk, so that hunks with the same contents in different
+    // places get different ids.
+    if range.start > 0 {
+        if let LineDiff::Keep(i, _) = diff.diff[range.start - 1] {
+            data.push(b'k');
+            write_node_id(&mut data, diff.file_a.node_id(i));
+        }
+    }
+    for d in &diff.diff[range.clone()] {
+        match *d {
+            LineDiff::Delete(i) => {
+                data.push(b'-');
+                write_node_id(&mut data, diff.file_a.node_id(i));
+            }
+            LineDiff::New(j) => {
+                let line = diff.file_b.node(j);
+                data.push(b'+');
+                data.write_u64::<LittleEndian>(line.len() as u64).unwrap();
+                data.extend_from_slice(line);
+            }
+            LineDiff::Keep(..) => unreachable!(),
+        }
+    }
+
+    HunkId::from_data(&data)
+}
+
+impl PendingChanges {
+    /// Divides the changes in a diff into hunks.
+    pub fn new(diff: Diff) -> PendingChanges {
+        let mut hunks = Vec::new();
+        let mut start = None;
+        for (idx, d) in diff.diff.iter().enumerate() {
+            match (d, start) {
+                (LineDiff::Keep(..), Some(s)) => {
+                    hunks.push(s..idx);
+                    start = None;
+                }
+                (LineDiff::Keep(..), None) => {}
+                (_, None) => start = Some(idx),
+                (_, Some(_)) => {}
+            }
+        }
+        if let Some(s) = start {
+            hunks.push(s..diff.diff.len());
+        }
+
+        // Since the hunks are as large as possible, they are surrounded by unchanged lines.
+        let kept = |idx: usize| match diff.diff.get(idx) {
+            Some(LineDiff::Keep(i, _)) => Some(*diff.file_a.node_id(*i)),
+            _ => None,
+        };
+        let hunks = hunks
+            .into_iter()
+            .map(|range| Hunk {
+                id: hunk_id(&diff, &range),
+                before: range.start.checked_sub(1).and_then(kept),
+                after: kept(range.end),
+                range,
+            })
+            .collect();
+        PendingChanges { diff, hunks }
+    }
+
+    /// Returns all of the hunks, in the order that they appear in the file.
+    pub fn hunks(&self) -> &[Hunk] {
+        &self.hunks
+    }
+
+    /// Returns the diff that these changes came from.
+    pub fn diff(&self) -> &Diff {
+        &self.diff
+    }
+
+    /// Are there any changes at all?
+    pub fn is_empty(&self) -> bool {
+        self.hunks.is_empty()
+    }
+
+    /// Returns all of the changes.
+    pub fn changes(&self) -> Changes {
+        self.diff.changes()
+    }
+
+    /// Splits these changes in two: the ones belonging to the selected hunks, and the rest.
+    ///
+    /// The selected changes can be made into a patch straight away (for example, with
+    /// [`Repo::create_patch`](crate::Repo::create_patch)). Once that patch has an id, the
+    /// remaining changes can be recovered using [`Remaining::resolve`].
+    pub fn select(&self, selected: &[HunkId]) -> Result<(Changes, Remaining), Error> {
+        let selected = selected.iter().cloned().collect::<HashSet<_>>();
+        for id in &selected {
+            if !self.hunks.iter().any(|h| h.id == *id) {
+                return Err(Error::UnknownHunk(*id));
+            }
+        }
+
+        // We build up an intermediate file, which is the original file with only the selected
+        // hunks applied. We also build a diff from the original file to the intermediate one,
+        // and another from the intermediate one to the final one.
+        let old = &self.diff.file_a;
+        let new = &self.diff.file_b;
+        let mut mid_ids = Vec::new();
+        let mut mid_lines: Vec<&[u8]> = Vec::new();
+        let mut first_diff = Vec::new();
+        let mut second_diff = Vec::new();
+        let mut next_new_node = 0;
+        let mut hunks = self.hunks.iter().peekable();
+        for (idx, d) in self.diff.diff.iter().enumerate() {
+            while hunks.peek().map(|h| h.range.end <= idx).unwrap_or(false) {
+                hunks.next();
+            }
+            let in_selected = hunks
+                .peek()
+                .map(|h| h.range.contains(&idx) && selected.contains(&h.id))
+                .unwrap_or(false);
+            let mid_idx = mid_ids.len();
+
+            match (*d, in_selected) {
+                (LineDiff::Keep(i, j), _) => {
+                    first_diff.push(LineDiff::Keep(i, mid_idx));
+                    second_diff.push(LineDiff::Keep(mid_idx, j));
+                    mid_ids.push(*old.node_id(i));
+                    mid_lines.push(old.node(i));
+                }
+                (LineDiff::Delete(i), true) => {
+                    first_diff.push(LineDiff::Delete(i));
+                }
+                (LineDiff::New(j), true) => {
+                    // This line will belong to the new patch, whose id we don't know yet, so it
+                    // is staged.
+                    first_diff.push(LineDiff::New(mid_idx));
+                    second_diff.push(LineDiff::Keep(mid_idx, j));
+                    mid_ids.push(NodeId::staged(next_new_node));
+                    next_new_node += 1;
+                    mid_lines.push(new.node(j));
+                }
+                (LineDiff::Delete(i), false) => {
+                    first_diff.push(LineDiff::Keep(i, mid_idx));
+                    second_diff.push(LineDiff::Delete(mid_idx));
+                    mid_ids.push(*old.node_id(i));
+                    mid_lines.push(old.node(i));
+                }
+                (LineDiff::New(j), false) => {
+                    second_diff.push(LineDiff::New(j));
+                }
+            }
+        }
+
+        let mid = File::from_lines(mid_ids, mid_lines);
+        let changes = Changes::from_diff(old, &mid, &first_diff);
+        let remaining = Remaining {
+            diff: Diff {
+                file_a: mid,
+                file_b: new.clone(),
+                diff: second_diff,
+            },
+            renumbering: Renumbering::new(&changes),
+        };
+        Ok((changes, remaining))
+    }
+
+    /// Returns the new file, but with the selected hunks undone.
+    ///
+    /// This is the opposite of [`PendingChanges::select`]: instead of keeping the selected
+    /// changes, it throws them away and keeps the others.
+    pub fn revert(&self, reverted: &[HunkId]) -> Result<File, Error> {
+        for id in reverted {
+            if !self.hunks.iter().any(|h| h.id == *id) {
+                return Err(Error::UnknownHunk(*id));
+            }
+        }
+        let kept = self
+            .hunks
+            .iter()
+            .map(|h| h.id)
+            .filter(|id| !reverted.contains(id))
+            .collect::<Vec<_>>();
+        let (_, remaining) = self.select(&kept)?;
+        Ok(remaining.diff.file_a)
+    }
+}
+
+impl Remaining {
+    /// Are there any remaining changes?
+    pub fn is_empty(&self) -> bool {
+        self.diff
+            .diff
+            .iter()
+            .all(|d| matches!(d, LineDiff::Keep(..)))
+    }
+
+    /// Turns these remaining changes back into [`PendingChanges`], given the id of the patch that
+    /// was made from the selected changes.
+    pub fn resolve(mut self, id: &PatchId) -> PendingChanges {
+        self.diff.file_a.finalize_staged(&self.renumbering, id);
+        PendingChanges::new(self.diff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Repo;
+
+    fn repo_with(contents: &[u8]) -> Repo {
+        let mut repo = Repo::init_tmp();
+        let diff = repo.diff("master", contents).unwrap();
+        let id = repo.create_patch("Author", "Msg", diff.changes()).unwrap();
+        repo.apply_patch("master", &id).unwrap();
+        repo
+    }
+
+    #[test]
+    fn hunks() {
+        let repo = repo_with(b"a\nb\nc\nd\ne\n");
+        let diff = repo.diff("master", b"a\nB\nc\nd\nE\nf\n").unwrap();
+        let pending = PendingChanges::new(diff);
+        let hunks = pending.hunks();
+        assert_eq!(hunks.len(), 2);
+        assert_eq!(hunks[0].range, 1..3);
+        assert_eq!(hunks[1].range, 5..8);
+        assert_ne!(hunks[0].id, hunks[1].id);
+
+        let file = repo.file("master").unwrap();
+        assert_eq!(hunks[0].before, Some(*file.node_id(0)));
+        assert_eq!(hunks[0].after, Some(*file.node_id(2)));
+        assert_eq!(hunks[1].before, Some(*file.node_id(3)));
+        assert_eq!(hunks[1].after, None);
+    }
+
+    #[test]
+    fn select() {
+        let mut repo = repo_with(b"a\nb\nc\nd\ne\n");
+        let target = b"a\nB\nc\nd\nE\nf\n";
+        let diff = repo.diff("master", target).unwrap();
+        let pending = PendingChanges::new(diff);
+        let first = pending.hunks()[0].id;
+        let second = pending.hunks()[1].id;
+
+        // Record just the second hunk.
+        let (changes, remaining) = pending.select(&[second]).unwrap();
+        assert!(!remaining.is_empty());
+        let id = repo.create_patch("Author", "Second", changes).unwrap();
+        repo.apply_patch("master", &id).unwrap();
+        assert_eq!(
+            repo.file("master").unwrap().as_bytes(),
+            b"a\nb\nc\nd\nE\nf\n"
+        );
+
+        // The remaining hunk keeps its id, and it matches a fresh diff.
+        let pending = remaining.resolve(&id);
+        assert_eq!(pending.hunks().len(), 1);
+        assert_eq!(pending.hunks()[0].id, first);
+        let fresh = PendingChanges::new(repo.diff("master", target).unwrap());
+        assert_eq!(fresh.hunks()[0].id, first);
+
+        let (changes, remaining) = pending.select(&[first]).unwrap();
+        assert!(remaining.is_empty());
+        let id = repo.create_patch("Author", "First", changes).unwrap();
+        repo.apply_patch("master", &id).unwrap();
+        assert_eq!(repo.file("master").unwrap().as_bytes(), target);
+    }
+
+    #[test]
+    fn select_one_at_a_time() {
+        let mut repo = repo_with(b"a\nb\n");
+        let target = b"x\na\ny\nb\n";
+        let pending = PendingChanges::new(repo.diff("master", target).unwrap());
+        let ids = pending.hunks().iter().map(|h| h.id).collect::<Vec<_>>();
+        assert_eq!(ids.len(), 2);
+
+        let (changes, remaining) = pending.select(&ids[..1]).unwrap();
+        let id = repo.create_patch("Author", "Msg", changes).unwrap();
+        repo.apply_patch("master", &id).unwrap();
+        let (changes, _) = remaining.resolve(&id).select(&ids[1..]).unwrap();
+        let id = repo.create_patch("Author", "Msg", changes).unwrap();
+        repo.apply_patch("master", &id).unwrap();
+        assert_eq!(repo.file("master").unwrap().as_bytes(), target);
+    }
+
+    #[test]
+    fn revert() {
+        let repo = repo_with(b"a\nb\nc\nd\ne\n");
+        let pending = PendingChanges::new(repo.diff("master", b"a\nB\nc\nd\nE\nf\n").unwrap());
+        let first = pending.hunks()[0].id;
+        let second = pending.hunks()[1].id;
+        assert_eq!(
+            pending.revert(&[second]).unwrap().as_bytes(),
+            b"a\nB\nc\nd\ne\n"
+        );
+        assert_eq!(
+            pending.revert(&[first, second]).unwrap().as_bytes(),
+            b"a\nb\nc\nd\ne\n"
+        );
+        assert_eq!(
+            pending.revert(&[]).unwrap().as_bytes(),
+            pending.diff().file_b.as_bytes()
+        );
+    }
+
+    #[test]
+    fn unknown_hunk() {
+        let repo = repo_with(b"a\n");
+        let pending = PendingChanges::new(repo.diff("master", b"b\n").unwrap());
+        match pending.select(&[HunkId(0)]) {
+            Err(Error::UnknownHunk(HunkId(0))) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+}
@@ -0,0 +1,344 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Files in the working copy that ojo should pretend aren't there.
+//
+// The patterns have the same format as the ones in gitignore files. They come from the
+// `.ojoignore` files in the working copy (which apply to the directory they are in and everything
+// below it) and from the repository's config (see `Config::ignore`), which apply everywhere.
+// Deeper `.ojoignore` files take precedence over shallower ones, which take precedence over the
+// config, and within a list the last matching pattern wins. Once a directory is ignored, nothing
+// inside it can be un-ignored.
+
+use crate::{Error, Repo};
+
+/// The name of the files in the working copy that list patterns of files to ignore.
+pub const IGNORE_FILE: &str = ".ojoignore";
+
+// The directory (at the root of the working copy) where ojo keeps its data. It is always ignored.
+const OJO_DIR: &str = ".ojo";
+
+// A single line of an ignore file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Pattern {
+    // The glob, without the leading "!" and "/" or the trailing "/".
+    glob: Vec<char>,
+    // Does this pattern un-ignore the paths that it matches?
+    negated: bool,
+    // Does this pattern only match directories?
+    dir_only: bool,
+    // Is this pattern matched against the whole path (relative to the directory of the ignore
+    // file), or just against the last component?
+    anchored: bool,
+}
+
+impl Pattern {
+    // Parses a line of an ignore file, returning `None` for blank lines and comments.
+    fn parse(line: &str) -> Option<Pattern> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let line = line.strip_prefix('/').unwrap_or(line);
+        if line.is_empty() {
+            return None;
+        }
+        Some(Pattern {
+            glob: line.chars().collect(),
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+
+    // Does this pattern match `path`, which is relative to the directory of the ignore file?
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let path = if self.anchored {
+            path
+        } else {
+            path.rsplit('/').next().unwrap_or(path)
+        };
+        glob_match(&self.glob, &path.chars().collect::<Vec<_>>())
+    }
+}
+
+// Does a single pattern (in the format of the lines of an ignore file, relative to the root of the
+// working copy) match the file at `path`? Negated patterns never match.
+pub(crate) fn matches_pattern(pattern: &str, path: &str) -> bool {
+    Pattern::parse(pattern).is_some_and(|p| !p.negated && p.matches(path, false))
+}
+
+// Parses a character class, starting just after the opening bracket. Returns whether `c` is in
+// the class, and the length of the class (including the closing bracket). Returns `None` if the
+// class doesn't end.
+fn match_class(pat: &[char], c: char) -> Option<(bool, usize)> {
+    let (negated, start) = match pat.first() {
+        Some('!') | Some('^') => (true, 1),
+        _ => (false, 0),
+    };
+    let mut found = false;
+    let mut i = start;
+    loop {
+        let first = *pat.get(i)?;
+        // A closing bracket right at the start is part of the class.
+        if first == ']' && i > start {
+            return Some((found != negated, i + 1));
+        }
+        if pat.get(i + 1) == Some(&'-') && pat.get(i + 2).is_some_and(|&last| last != ']') {
+            found |= first <= c && c <= pat[i + 2];
+            i += 3;
+        } else {
+            found |= first == c;
+            i += 1;
+        }
+    }
+}
+
+// Matches a path against a glob, where `*` and `?` match anything except a slash, and `**` matches
+// anything at all (or, in `**/`, any number of whole directories).
+fn glob_match(pat: &[char], text: &[char]) -> bool {
+    match pat.first() {
+        None => text.is_empty(),
+        Some('*') if pat.get(1) == Some(&'*') => {
+            let rest = &pat[2..];
+            match rest.strip_prefix(&['/']) {
+                Some(rest) => (0..=text.len())
+                    .filter(|&i| i == 0 || text[i - 1] == '/')
+                    .any(|i| glob_match(rest, &text[i..])),
+                None => (0..=text.len()).any(|i| glob_match(rest, &text[i..])),
+            }
+        }
+        Some('*') => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != '/')
+            .any(|i| glob_match(&pat[1..], &text[i..])),
+        Some('?') => text.first().is_some_and(|&c| c != '/') && glob_match(&pat[1..], &text[1..]),
+        Some('[') => match text.first() {
+            Some(&c) if c != '/' => match match_class(&pat[1..], c) {
+                Some((found, len)) => found && glob_match(&pat[(len + 1)..], &text[1..]),
+                // An unclosed bracket is just a bracket.
+                None => c == '[' && glob_match(&pat[1..], &text[1..]),
+            },
+            _ => false,
+        },
+        Some('\\') if pat.len() > 1 => {
+            text.first() == Some(&pat[1]) && glob_match(&pat[2..], &text[1..])
+        }
+        Some(&p) => text.first() == Some(&p) && glob_match(&pat[1..], &text[1..]),
+    }
+}
+
+// The patterns that apply to some directory of the working copy, along with the directories that
+// they came from.
+#[derive(Clone, Debug, Default)]
+struct Rules {
+    // Each pattern comes with the path of its directory (relative to the root of the working
+    // copy, and ending in a slash unless it is the root).
+    patterns: Vec<(String, Pattern)>,
+}
+
+impl Rules {
+    // Adds the patterns that apply everywhere.
+    fn new(repo: &Repo) -> Rules {
+        let mut ret = Rules::default();
+        ret.add("", repo.config().ignore.iter().map(|s| s.as_str()));
+        ret
+    }
+
+    fn add<'a>(&mut self, dir: &str, lines: impl Iterator<Item = &'a str>) {
+        self.patterns.extend(
+            lines
+                .filter_map(Pattern::parse)
+                .map(|p| (dir.to_owned(), p)),
+        );
+    }
+
+    // Adds the patterns in the ignore file of `dir` (if there is one).
+    fn read(&mut self, repo: &Repo, dir: &str) -> Result<(), Error> {
+        let path = format!("{}{}", dir, IGNORE_FILE);
+        match crate::working::read(repo, &path) {
+            Ok(text) => self.add(dir, String::from_utf8_lossy(&text).lines()),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(Error::Io(e, format!("Could not read {}", path))),
+        }
+        Ok(())
+    }
+
+    // Is `path` ignored? This only looks at the path itself, not at the directories containing
+    // it.
+    fn ignores(&self, path: &str, is_dir: bool) -> bool {
+        if path == OJO_DIR {
+            return true;
+        }
+        self.patterns
+            .iter()
+            .rev()
+            .find(|(dir, p)| {
+                path.starts_with(dir.as_str()) && p.matches(&path[dir.len()..], is_dir)
+            })
+            .is_some_and(|(_, p)| !p.negated)
+    }
+}
+
+// Is the file at `path` (relative to the root of the working copy) ignored?
+pub(crate) fn is_ignored(repo: &Repo, path: &str) -> Result<bool, Error> {
+    let mut rules = Rules::new(repo);
+    rules.read(repo, "")?;
+    let mut dir = String::new();
+    let mut components = path.split('/').peekable();
+    while let Some(name) = components.next() {
+        let is_dir = components.peek().is_some();
+        let sub = format!("{}{}", dir, name);
+        if rules.ignores(&sub, is_dir) {
+            return Ok(true);
+        }
+        if is_dir {
+            dir = format!("{}/", sub);
+            rules.read(repo, &dir)?;
+        }
+    }
+    Ok(false)
+}
+
+// Adds the paths of all the files in the directory `prefix` (which is empty or ends with a `/`) to
+// `paths`, skipping the ignored ones.
+fn walk(
+    repo: &Repo,
+    prefix: &str,
+    rules: &mut Rules,
+    paths: &mut Vec<String>,
+) -> Result<(), Error> {
+    let len = rules.patterns.len();
+    rules.read(repo, prefix)?;
+    for (name, is_dir) in crate::working::entries(repo, prefix)? {
+        let path = format!("{}{}", prefix, name);
+        if rules.ignores(&path, is_dir) {
+            continue;
+        }
+        if is_dir {
+            walk(repo, &format!("{}/", path), rules, paths)?;
+        } else {
+            paths.push(path);
+        }
+    }
+    rules.patterns.truncate(len);
+    Ok(())
+}
+
+// Returns the paths of all the files in the working copy that aren't ignored, in sorted order.
+pub(crate) fn working_files(repo: &Repo) -> Result<Vec<String>, Error> {
+    let mut ret = Vec::new();
+    walk(repo, "", &mut Rules::new(repo), &mut ret)?;
+    ret.sort();
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        Pattern::parse(pattern).unwrap().matches(path, false)
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Pattern::parse("# comment"), None);
+        assert_eq!(Pattern::parse("   "), None);
+        assert_eq!(Pattern::parse("/"), None);
+        let p = Pattern::parse("!/build/  ").unwrap();
+        assert_eq!(p.glob, "build".chars().collect::<Vec<_>>());
+        assert!(p.negated && p.dir_only && p.anchored);
+        let p = Pattern::parse("*.o").unwrap();
+        assert!(!p.negated && !p.dir_only && !p.anchored);
+    }
+
+    #[test]
+    fn globs() {
+        assert!(matches("*.o", "a.o"));
+        assert!(matches("*.o", "dir/sub/a.o"));
+        assert!(!matches("*.o", "a.oo"));
+        assert!(matches("a?c", "abc"));
+        assert!(!matches("dir/*", "dir/sub/a"));
+        assert!(matches("dir/*", "dir/a"));
+        assert!(!matches("/a", "dir/a"));
+        assert!(matches("**/a", "a"));
+        assert!(matches("**/a", "dir/sub/a"));
+        assert!(matches("dir/**", "dir/sub/a"));
+        assert!(matches("dir/**/a", "dir/a"));
+        assert!(matches("dir/**/a", "dir/x/y/a"));
+        assert!(!matches("dir/**/a", "dir/xa"));
+        assert!(matches("[a-c]x", "bx"));
+        assert!(!matches("[!a-c]x", "bx"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[a", "[a"));
+        assert!(matches("\\#x", "#x"));
+        assert!(matches("\\!x", "!x"));
+        assert!(!Pattern::parse("dir/").unwrap().matches("dir", false));
+        assert!(Pattern::parse("dir/").unwrap().matches("dir", true));
+    }
+
+    #[test]
+    fn ignore_files() {
+        let mut repo = Repo::init_tmp_with_working_copy("ignore");
+        let root = repo.root_dir.clone();
+        repo.config.ignore = vec!["*.tmp".to_owned()];
+        fs::create_dir_all(root.join("dir/build")).unwrap();
+        fs::create_dir_all(root.join("build")).unwrap();
+        fs::create_dir_all(root.join(".ojo")).unwrap();
+        fs::write(root.join(IGNORE_FILE), "*.log\n/build/\n").unwrap();
+        fs::write(root.join("dir").join(IGNORE_FILE), "!keep.log\n!*.tmp\n").unwrap();
+        for f in &[
+            "a.txt",
+            "a.log",
+            "a.tmp",
+            "build/x",
+            "dir/keep.log",
+            "dir/other.log",
+            "dir/b.tmp",
+            "dir/build/y",
+            ".ojo/db",
+        ] {
+            fs::write(root.join(f), b"").unwrap();
+        }
+
+        assert_eq!(
+            working_files(&repo).unwrap(),
+            vec![
+                IGNORE_FILE,
+                "a.txt",
+                "dir/.ojoignore",
+                "dir/b.tmp",
+                "dir/build/y",
+                "dir/keep.log"
+            ]
+        );
+        assert!(repo.is_ignored("a.log").unwrap());
+        assert!(repo.is_ignored("build/x").unwrap());
+        assert!(repo.is_ignored("build/nonexistent").unwrap());
+        assert!(!repo.is_ignored("dir/keep.log").unwrap());
+        assert!(!repo.is_ignored("dir/b.tmp").unwrap());
+        assert!(repo.is_ignored(".ojo/db").unwrap());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
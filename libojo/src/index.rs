@@ -0,0 +1,280 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// The metadata of every patch is kept in an index, so that we can search through the patches (and
+// print them out) without deserializing each of them.
+
+#[cfg(not(target_arch = "wasm32"))]
+use chrono::{DateTime, Utc};
+use ojo_multimap::MMap;
+use std::collections::{BTreeMap, BTreeSet};
+#[cfg(not(target_arch = "wasm32"))]
+use std::ops::Bound;
+
+use crate::{Change, Patch, PatchHeader, PatchId};
+
+/// A search for patches, based on their metadata (see
+/// [`Repo::find_patches`](crate::Repo::find_patches)).
+///
+/// A patch matches the query if it matches all of the criteria that are set. In particular, the
+/// default query matches every patch.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PatchQuery {
+    /// Only match patches whose author has this name or email address (ignoring case).
+    pub author: Option<String>,
+
+    /// Only match patches that were created at this time or later.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub since: Option<DateTime<Utc>>,
+
+    /// Only match patches that were created strictly before this time.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub until: Option<DateTime<Utc>>,
+
+    /// Only match patches whose description contains all of the words in this string (ignoring
+    /// case and punctuation).
+    pub words: Option<String>,
+
+    /// Only match patches that refer to a file by this path (by creating, deleting, moving or
+    /// editing it, for example). Note that edits refer to a file by the path that it was created
+    /// at (see [`FileRef`](crate::FileRef)).
+    pub path: Option<String>,
+}
+
+// Splits some text into lower-case words, ignoring punctuation.
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub(crate) struct MetadataIndex {
+    headers: BTreeMap<PatchId, PatchHeader>,
+    // Maps the lower-cased names and email addresses of authors to their patches.
+    authors: MMap<String, PatchId>,
+    #[cfg(not(target_arch = "wasm32"))]
+    times: BTreeSet<(DateTime<Utc>, PatchId)>,
+    // Maps the (lower-cased) words appearing in descriptions to the patches they appear in.
+    words: MMap<String, PatchId>,
+    // The paths of the files that each patch refers to. Indices written by older versions of ojo
+    // don't have this, so it gets filled in along with any patches that are missing.
+    #[serde(default)]
+    paths: BTreeMap<PatchId, BTreeSet<String>>,
+}
+
+// Returns the paths of all the files that a patch refers to.
+fn paths(patch: &Patch) -> BTreeSet<String> {
+    patch
+        .changes()
+        .changes
+        .iter()
+        .flat_map(|ch| match ch {
+            Change::NewFile { path } => vec![path.clone()],
+            Change::MoveFile { from, to } => vec![from.path.clone(), to.clone()],
+            Change::DeleteFile { file }
+            | Change::SetExecutable { file, .. }
+            | Change::SetSymlink { file, .. }
+            | Change::EditFile { file, .. } => vec![file.path.clone()],
+            _ => vec![],
+        })
+        .collect()
+}
+
+impl MetadataIndex {
+    pub fn contains(&self, id: &PatchId) -> bool {
+        self.headers.contains_key(id) && self.paths.contains_key(id)
+    }
+
+    pub fn header(&self, id: &PatchId) -> Option<&PatchHeader> {
+        self.headers.get(id)
+    }
+
+    pub fn insert(&mut self, id: PatchId, patch: &Patch) {
+        self.insert_header(id, patch.header(), paths(patch));
+    }
+
+    // Copies the metadata of a patch from another index, so that the patch itself isn't needed.
+    pub fn copy_from(&mut self, other: &MetadataIndex, id: PatchId) {
+        if let (Some(header), Some(paths)) = (other.headers.get(&id), other.paths.get(&id)) {
+            self.insert_header(id, header, paths.clone());
+        }
+    }
+
+    pub fn remove(&mut self, id: &PatchId) {
+        if let Some(header) = self.headers.remove(id) {
+            self.authors.remove(&header.author.to_lowercase(), id);
+            if let Some(ref email) = header.email {
+                self.authors.remove(&email.to_lowercase(), id);
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            self.times.remove(&(header.timestamp, *id));
+            for w in words(&header.description) {
+                self.words.remove(&w, id);
+            }
+        }
+        self.paths.remove(id);
+    }
+
+    fn insert_header(&mut self, id: PatchId, header: &PatchHeader, paths: BTreeSet<String>) {
+        self.authors.insert(header.author.to_lowercase(), id);
+        if let Some(ref email) = header.email {
+            self.authors.insert(email.to_lowercase(), id);
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        self.times.insert((header.timestamp, id));
+        for w in words(&header.description) {
+            self.words.insert(w, id);
+        }
+        self.headers.insert(id, header.clone());
+        self.paths.insert(id, paths);
+    }
+
+    // Returns all of the patches matching a query, sorted by the time that they were created.
+    pub fn find(&self, query: &PatchQuery) -> Vec<PatchId> {
+        let query_words = query
+            .words
+            .as_ref()
+            .map(|w| words(w).collect::<Vec<_>>())
+            .unwrap_or_default();
+        let by_words = |id: &PatchId| query_words.iter().all(|w| self.words.contains(w, id));
+        let by_author = |id: &PatchId| match query.author {
+            Some(ref author) => self.authors.contains(&author.to_lowercase(), id),
+            None => true,
+        };
+        let by_path = |id: &PatchId| match query.path {
+            Some(ref path) => self.paths.get(id).is_some_and(|p| p.contains(path)),
+            None => true,
+        };
+
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            // The staging id is the smallest one, so this includes everything from `since` on.
+            let start = match query.since {
+                Some(t) => Bound::Included((t, PatchId::staging())),
+                None => Bound::Unbounded,
+            };
+            self.times
+                .range((start, Bound::Unbounded))
+                .take_while(|(t, _)| query.until.map(|u| *t < u).unwrap_or(true))
+                .map(|(_, id)| *id)
+                .filter(|id| by_author(id) && by_words(id) && by_path(id))
+                .collect()
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        {
+            self.headers
+                .keys()
+                .filter(|id| by_author(id) && by_words(id) && by_path(id))
+                .cloned()
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Changes, Repo};
+
+    fn create(
+        repo: &mut Repo,
+        author: &str,
+        email: Option<&str>,
+        msg: &str,
+        time: &str,
+    ) -> PatchId {
+        let mut header = PatchHeader::new(author.to_owned(), msg.to_owned());
+        header.email = email.map(|e| e.to_owned());
+        header.timestamp = time.parse().unwrap();
+        repo.create_patch_with_header(header, Changes { changes: vec![] })
+            .unwrap()
+    }
+
+    #[test]
+    fn find() {
+        let mut repo = Repo::init_tmp();
+        let a = create(
+            &mut repo,
+            "Alice",
+            None,
+            "Fix the parser.",
+            "2019-03-01T00:00:00Z",
+        );
+        let b = create(
+            &mut repo,
+            "Bob",
+            Some("bob@example.com"),
+            "Speed up the parser\n\nAnd fix a bug.",
+            "2019-01-01T00:00:00Z",
+        );
+        let c = create(&mut repo, "alice", None, "Add docs", "2019-02-01T00:00:00Z");
+
+        let find = |query: PatchQuery| repo.find_patches(&query);
+        assert_eq!(find(PatchQuery::default()), vec![b, c, a]);
+
+        let author = |a: &str| PatchQuery {
+            author: Some(a.to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(find(author("ALICE")), vec![c, a]);
+        assert_eq!(find(author("bob@example.com")), vec![b]);
+        assert_eq!(find(author("Carol")), vec![]);
+
+        let words = |w: &str| PatchQuery {
+            words: Some(w.to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(find(words("parser")), vec![b, a]);
+        assert_eq!(find(words("FIX parser!")), vec![b, a]);
+        assert_eq!(find(words("fix bug")), vec![b]);
+        assert_eq!(find(words("fix docs")), vec![]);
+
+        let range = PatchQuery {
+            since: Some("2019-02-01T00:00:00Z".parse().unwrap()),
+            until: Some("2019-03-01T00:00:00Z".parse().unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(find(range), vec![c]);
+
+        let combined = PatchQuery {
+            since: Some("2019-02-01T00:00:00Z".parse().unwrap()),
+            ..author("alice")
+        };
+        assert_eq!(find(combined), vec![c, a]);
+
+        assert_eq!(repo.patch_header(&b).unwrap().author, "Bob");
+    }
+
+    #[test]
+    fn rebuild() {
+        let mut repo = Repo::init_tmp();
+        create(&mut repo, "Author", None, "Msg", "2019-01-01T00:00:00Z");
+        create(
+            &mut repo,
+            "Author",
+            None,
+            "Other msg",
+            "2019-01-02T00:00:00Z",
+        );
+        let index = repo.storage.patch_index.clone();
+
+        // The index survives serialization.
+        let data = serde_yaml::to_string(&index).unwrap();
+        assert_eq!(serde_yaml::from_str::<MetadataIndex>(&data).unwrap(), index);
+
+        // Old repositories don't have an index, so it needs to be rebuilt.
+        repo.storage.patch_index = MetadataIndex::default();
+        repo.index_patches().unwrap();
+        assert_eq!(repo.storage.patch_index, index);
+    }
+}
@@ -0,0 +1,292 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Views of the repository that are meant to be serialized (as JSON, for example) for programs
+// that would otherwise have to scrape the output of the command line tool.
+//
+// These are separate from the types that they are built from, so that their schemas can stay the
+// same when the internals change: new fields may be added, but the existing ones won't be renamed,
+// removed, or change their meaning. Patch ids are always in base64, and timestamps are in RFC 3339
+// format.
+
+use std::collections::BTreeMap;
+
+use crate::{Error, NodeId, PatchHeader, PatchId, PatchKind, PatchQuery, Repo};
+
+/// A patch in the log of a branch (see [`Repo::log`]), for serializing.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct LogEntryJson {
+    /// The patch's id.
+    pub id: String,
+    /// The patch's author.
+    pub author: String,
+    /// The email address of the patch's author, if it has one.
+    pub email: Option<String>,
+    /// The time at which the patch was created.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub date: String,
+    /// The first line of the patch's description.
+    pub summary: String,
+    /// The patch's description.
+    pub description: String,
+    /// What kind of patch this is: `"normal"`, `"resolution"` or `"ordering"`.
+    pub kind: String,
+    /// The patch's dependencies.
+    pub deps: Vec<String>,
+    /// Whether the patch is published (see [`Repo::is_published`]).
+    pub published: bool,
+}
+
+impl LogEntryJson {
+    /// Describes a patch in a repository.
+    pub fn new(repo: &Repo, id: &PatchId) -> Result<LogEntryJson, Error> {
+        let header = repo.patch_header(id)?;
+        Ok(LogEntryJson {
+            id: id.to_base64(),
+            author: header.author.clone(),
+            email: header.email.clone(),
+            #[cfg(not(target_arch = "wasm32"))]
+            date: header.timestamp.to_rfc3339(),
+            summary: header.summary().to_owned(),
+            description: header.description.clone(),
+            kind: kind_name(header).to_owned(),
+            deps: repo.patch_deps(id).map(|p| p.to_base64()).collect(),
+            published: repo.is_published(id),
+        })
+    }
+
+    /// Describes the patches on a branch that match a query, in the order of [`Repo::log`].
+    pub fn log(repo: &Repo, branch: &str, query: &PatchQuery) -> Result<Vec<LogEntryJson>, Error> {
+        repo.log(branch, query)?
+            .map(|id| LogEntryJson::new(repo, &id))
+            .collect()
+    }
+}
+
+/// A patch, with its metadata and its changes, for serializing.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct PatchJson {
+    /// The patch's id.
+    pub id: String,
+    /// The patch's author.
+    pub author: String,
+    /// The email address of the patch's author, if it has one.
+    pub email: Option<String>,
+    /// The time at which the patch was created.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub date: String,
+    /// The patch's description.
+    pub description: String,
+    /// What kind of patch this is: `"normal"`, `"resolution"` or `"ordering"`.
+    pub kind: String,
+    /// The patch's other metadata (see [`PatchHeader::extra`]).
+    pub extra: BTreeMap<String, String>,
+    /// The patch's dependencies.
+    pub deps: Vec<String>,
+    /// Whether the patch is published (see [`Repo::is_published`]).
+    pub published: bool,
+    /// The number of files that the patch touches.
+    pub files_changed: usize,
+    /// The number of lines that the patch adds.
+    pub lines_added: usize,
+    /// The number of lines that the patch deletes.
+    pub lines_deleted: usize,
+    /// The patch's changes, as a unified diff (see
+    /// [`Patch::to_unified_diff`](crate::Patch::to_unified_diff)).
+    pub diff: String,
+}
+
+impl PatchJson {
+    /// Describes a patch in a repository.
+    ///
+    /// This needs the patch's contents, so it fails with [`Error::GhostPatch`] if the patch is a
+    /// ghost.
+    pub fn new(repo: &Repo, id: &PatchId) -> Result<PatchJson, Error> {
+        let patch = repo.open_patch(id)?;
+        let header = patch.header();
+        let stats = patch.stats();
+        Ok(PatchJson {
+            id: id.to_base64(),
+            author: header.author.clone(),
+            email: header.email.clone(),
+            #[cfg(not(target_arch = "wasm32"))]
+            date: header.timestamp.to_rfc3339(),
+            description: header.description.clone(),
+            kind: kind_name(header).to_owned(),
+            extra: header.extra.clone(),
+            deps: patch.deps().iter().map(|p| p.to_base64()).collect(),
+            published: repo.is_published(id),
+            files_changed: stats.files,
+            lines_added: stats.lines_added,
+            lines_deleted: stats.lines_deleted,
+            diff: patch.to_unified_diff(repo)?,
+        })
+    }
+}
+
+/// A conflict on a branch (see [`Repo::conflicts`]), for serializing.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ConflictJson {
+    /// The file that the conflict is in.
+    pub path: String,
+    /// The last line before the conflict, or `None` if the conflict is at the beginning.
+    pub before: Option<String>,
+    /// The first line after the conflict, or `None` if the conflict is at the end.
+    pub after: Option<String>,
+    /// The alternatives that need to be put in order.
+    pub alternatives: Vec<AlternativeJson>,
+    /// The patches whose combination caused the conflict (see
+    /// [`BranchConflict::causes`](crate::BranchConflict::causes)).
+    pub causes: Vec<String>,
+}
+
+/// One of the alternatives of a [`ConflictJson`].
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct AlternativeJson {
+    /// The lines, including their line endings. Lines that aren't valid UTF-8 have the invalid
+    /// parts replaced by U+FFFD.
+    pub lines: Vec<String>,
+    /// The patches that introduced the lines, in the order that they first appear.
+    pub patches: Vec<String>,
+}
+
+impl ConflictJson {
+    /// Describes all the conflicts on a branch, ordered by file and then by where they are in
+    /// the file.
+    pub fn for_branch(repo: &Repo, branch: &str) -> Result<Vec<ConflictJson>, Error> {
+        let line = |node: &NodeId| String::from_utf8_lossy(repo.contents(node)).into_owned();
+        let mut ret = Vec::new();
+        for (path, conflicts) in repo.conflicts(branch)? {
+            for c in conflicts {
+                let alternatives = (0..c.conflict.alternatives.len())
+                    .map(|i| AlternativeJson {
+                        lines: c.conflict.alternatives[i].iter().map(line).collect(),
+                        patches: c
+                            .conflict
+                            .alternative_patches(i)
+                            .iter()
+                            .map(|p| p.to_base64())
+                            .collect(),
+                    })
+                    .collect();
+                ret.push(ConflictJson {
+                    path: path.clone(),
+                    before: c.conflict.before.as_ref().map(line),
+                    after: c.conflict.after.as_ref().map(line),
+                    alternatives,
+                    causes: c.causes.iter().map(|p| p.to_base64()).collect(),
+                });
+            }
+        }
+        Ok(ret)
+    }
+}
+
+fn kind_name(header: &PatchHeader) -> &'static str {
+    match header.kind {
+        PatchKind::Normal => "normal",
+        PatchKind::Resolution => "resolution",
+        PatchKind::Ordering => "ordering",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Changes;
+
+    fn create_patch(repo: &mut Repo, branch: &str, contents: &[u8]) -> PatchId {
+        let diff = repo.diff(branch, contents).unwrap();
+        let changes = Changes::from_diff(&diff.file_a, &diff.file_b, &diff.diff);
+        let id = repo.create_patch("Author", "Msg\n\nMore", changes).unwrap();
+        repo.apply_patch(branch, &id).unwrap();
+        id
+    }
+
+    #[test]
+    fn log_and_patch() {
+        let mut repo = Repo::init_tmp();
+        let p1 = create_patch(&mut repo, "master", b"a\n");
+        let p2 = create_patch(&mut repo, "master", b"a\nb\n");
+
+        let log = LogEntryJson::log(&repo, "master", &PatchQuery::default()).unwrap();
+        assert_eq!(log.len(), 2);
+        assert_eq!(log[0].id, p2.to_base64());
+        assert_eq!(log[0].deps, vec![p1.to_base64()]);
+        assert_eq!(log[0].summary, "Msg");
+        assert_eq!(log[0].kind, "normal");
+        assert!(!log[0].published);
+
+        // The field names are part of the schema.
+        let json = serde_json::to_value(&log[0]).unwrap();
+        let mut keys = json
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec![
+                "author",
+                "date",
+                "deps",
+                "description",
+                "email",
+                "id",
+                "kind",
+                "published",
+                "summary"
+            ]
+        );
+
+        let patch = PatchJson::new(&repo, &p2).unwrap();
+        assert_eq!(patch.description, "Msg\n\nMore");
+        assert_eq!((patch.lines_added, patch.lines_deleted), (1, 0));
+        assert!(patch.diff.contains("+b\n"));
+    }
+
+    #[test]
+    fn conflicts() {
+        let mut repo = Repo::init_tmp();
+        let base = create_patch(&mut repo, "master", b"a\n");
+        repo.clone_branch("master", "other").unwrap();
+        let p1 = create_patch(&mut repo, "master", b"a\nb\n");
+        let p2 = create_patch(&mut repo, "other", b"a\nc\n");
+        repo.apply_patch("master", &p2).unwrap();
+
+        let conflicts = ConflictJson::for_branch(&repo, "master").unwrap();
+        assert_eq!(conflicts.len(), 1);
+        let c = &conflicts[0];
+        assert_eq!(c.before.as_deref(), Some("a\n"));
+        assert_eq!(c.after, None);
+        let mut alternatives = c
+            .alternatives
+            .iter()
+            .map(|a| (a.lines.clone(), a.patches.clone()))
+            .collect::<Vec<_>>();
+        alternatives.sort();
+        assert_eq!(
+            alternatives,
+            vec![
+                (vec!["b\n".to_owned()], vec![p1.to_base64()]),
+                (vec!["c\n".to_owned()], vec![p2.to_base64()]),
+            ]
+        );
+        let mut causes = c.causes.clone();
+        causes.sort();
+        let mut expected = vec![p1.to_base64(), p2.to_base64()];
+        expected.sort();
+        assert_eq!(causes, expected);
+        assert!(!c.causes.contains(&base.to_base64()));
+    }
+}
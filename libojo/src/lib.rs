@@ -32,27 +32,127 @@ extern crate proptest;
 #[macro_use]
 extern crate pretty_assertions;
 
+use crate::remote::{Connection, Transfer};
 use ojo_graph::Graph;
-use std::collections::HashSet;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs;
+use std::io::{BufRead, Write};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 // This module needs to go first, because it supplies some macros (for testing) that the other
 // modules use.
 #[macro_use]
 mod storage;
 
+mod annotate;
+mod archive;
+mod bisect;
+mod blob;
+mod bundle;
 mod chain_graggle;
+mod chunk;
+mod clone;
+mod compress;
+mod config;
+mod conflict;
+mod darcs_import;
+mod deps;
+mod diff_import;
+mod encrypt;
 mod error;
+mod gc;
+mod git_export;
+mod git_import;
+mod git_mirror;
+mod hg_import;
+mod history;
+mod history_fs;
+mod hooks;
+mod hunk;
+mod ignore;
+mod index;
+mod json;
+mod merge;
+mod merge_file;
+mod obsolete;
 mod patch;
+mod pijul_import;
+mod progress;
+mod pull;
+mod rebase;
+mod record;
+pub mod remote;
+mod render;
 pub mod resolver;
-
+mod revert;
+mod rollback;
+mod shallow;
+mod staging;
+mod stash;
+mod status;
+mod svn_import;
+mod tag;
+#[cfg(test)]
+pub(crate) mod test_util;
+mod text;
+mod tracked;
+mod tracking;
+mod transaction;
+mod tree;
+mod working;
+mod worktree;
+
+pub use crate::annotate::AnnotatedLine;
+pub use crate::archive::{ArchiveConflicts, ArchiveFormat};
+pub use crate::bisect::BisectStep;
+pub use crate::blob::{BlobHash, BlobRef};
+pub use crate::bundle::{Bundle, BUNDLE_FORMAT_VERSION, BUNDLE_MAGIC};
 pub use crate::chain_graggle::ChainGraggle;
+pub use crate::chunk::{BlobChunk, CHUNK_THRESHOLD};
+pub use crate::compress::DEFAULT_COMPRESSION_LEVEL;
+pub use crate::config::{
+    Config, Remote, CONFIG_FILE, DEFAULT_MAX_TEXT_SIZE, DEFAULT_RENAME_THRESHOLD,
+};
+pub use crate::conflict::{BranchConflict, Conflict};
+pub use crate::deps::{ApplyPolicy, PatchGraph, UnapplyPolicy};
+pub use crate::encrypt::EncryptionKey;
 pub use crate::error::{Error, PatchIdError};
-pub use crate::patch::{Change, Changes, Patch, PatchId, UnidentifiedPatch};
+pub use crate::git_import::{GitImport, GIT_COMMIT_KEY};
+pub use crate::hg_import::HG_NODE_KEY;
+pub use crate::history::Log;
+pub use crate::history_fs::{FsAttr, FsEntry, FsKind, HistoryFs};
+pub use crate::hooks::{Hook, HookContext, HookPoint};
+pub use crate::hunk::{Hunk, HunkId, PendingChanges, Remaining};
+pub use crate::ignore::IGNORE_FILE;
+pub use crate::index::PatchQuery;
+pub use crate::json::{AlternativeJson, ConflictJson, LogEntryJson, PatchJson};
+pub use crate::merge::MergeReport;
+pub use crate::merge_file::{merge_file, FileMerge, MergeChunk};
+pub use crate::obsolete::ObsoleteMarker;
+pub use crate::patch::{
+    ApplyReport, Change, ChangeReader, Changes, Patch, PatchHeader, PatchId, PatchKind, PatchStats,
+    UnidentifiedPatch, BINARY_FORMAT_VERSION, BINARY_MAGIC, MIN_PATCH_PREFIX_LEN,
+};
+pub use crate::progress::{Phase, Progress, ProgressSink};
+pub use crate::pull::{Commuted, PullPolicy, PullReport};
+pub use crate::rebase::{Rebase, RebaseGuess};
+pub use crate::record::{
+    PendingRecord, RecordHunk, RecordHunkKind, RecordOptions, DEFAULT_WORKING_FILE,
+};
+pub use crate::revert::RevertScope;
+pub use crate::staging::Renumbering;
+pub use crate::stash::StashReport;
+pub use crate::status::FileStatus;
 pub use crate::storage::graggle::{Edge, EdgeKind};
 pub use crate::storage::{File, FullGraph, Graggle, LiveGraph};
-pub use ojo_diff::LineDiff;
+pub use crate::svn_import::{SvnImport, SVN_REVISION_KEY};
+pub use crate::tag::Tag;
+pub use crate::text::{detect_encoding, looks_binary, Encoding, Newline, TextFormat, TextRule};
+pub use crate::tree::FileRef;
+pub use crate::working::{MemoryWorkingCopy, NativeVfs, Vfs};
+pub use ojo_diff::{Algorithm as DiffAlgorithm, DiffOptions, LineDiff, WordDiff};
 
 /// A globally unique ID for identifying a node.
 #[derive(Clone, Copy, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
@@ -75,21 +175,26 @@ impl std::fmt::Debug for NodeId {
 
 impl NodeId {
     fn set_patch_id(&mut self, id: &PatchId) {
-        if self.patch.is_cur() {
+        if self.is_staged() {
             self.patch = *id;
         }
     }
 
-    /// Creates a new `NodeId` for referring to a node that is being introduced in the current
-    /// patch.
+    /// Creates a new `NodeId` in the staging namespace, for referring to a node that is being
+    /// introduced by a patch that doesn't have an id yet.
     ///
-    /// See [`PatchId`] for more information on the current patch and its ID.
-    pub fn cur(node: u64) -> NodeId {
+    /// See [`PatchId::staging`] for more information on the staging namespace.
+    pub fn staged(node: u64) -> NodeId {
         NodeId {
-            patch: PatchId::cur(),
+            patch: PatchId::staging(),
             node,
         }
     }
+
+    /// Is this node in the staging namespace (see [`PatchId::staging`])?
+    pub fn is_staged(&self) -> bool {
+        self.patch.is_staging()
+    }
 }
 
 /// This is the main interface to a `ojo` repository.
@@ -99,6 +204,9 @@ impl NodeId {
 #[derive(Debug)]
 pub struct Repo {
     /// The path to the root directory of the repository.
+    ///
+    /// This is empty for the repositories that only live in memory (see [`Repo::init_tmp`] and
+    /// [`Repo::init_in_memory`]).
     pub root_dir: PathBuf,
     /// The path to the directory where all of ojo's data is stored.
     pub repo_dir: PathBuf,
@@ -107,8 +215,33 @@ pub struct Repo {
     /// The path to the directory where patches are stored.
     /// The name of the current branch.
     pub current_branch: String,
+    /// The repository's own settings.
+    ///
+    /// These are layered on top of the system-wide settings and the ones of the current user; the
+    /// settings that are actually in effect are returned by [`Repo::config`].
+    pub config: Config,
 
     storage: storage::Storage,
+    // The zstd dictionary for compressing patches, if one has been trained.
+    dictionary: Option<Vec<u8>>,
+    // The key for encrypting patches, if the repository is encrypted.
+    encryption_key: Option<EncryptionKey>,
+    // The hooks that were registered with `add_hook`.
+    hooks: hooks::Hooks,
+    // The sink that was registered with `set_progress_sink`.
+    progress: progress::Sink,
+    // The filesystem that the working copy (and, for a repository that was created with `init_vfs`
+    // or opened with `open_vfs`, the database) is in, if it isn't the directory at `root_dir`.
+    vfs: Option<Arc<dyn Vfs>>,
+    // The system-wide settings, merged with the ones of the current user.
+    global_config: Config,
+    // Whether the repository was opened with `open_readonly`, in which case it can't be changed.
+    readonly: bool,
+    // If this is a linked worktree (see `add_worktree`), the state of the main worktree, which
+    // goes back into the database when it is written.
+    main_worktree: Option<worktree::WorktreeState>,
+    // The version of the database that was last read or written (see `write`).
+    db_stamp: Mutex<Option<worktree::Stamp>>,
 }
 
 impl Repo {
@@ -128,18 +261,158 @@ impl Repo {
         Ok(ret)
     }
 
-    /// Opens the existing repository with the given root directory.
+    /// Finds the root directory of the repository that contains `dir`.
+    ///
+    /// This is `dir` itself if it is the root of a repository, or else the closest of its
+    /// ancestors that is. If there is no such directory, this returns [`Error::RepoNotFound`].
+    pub fn find_root<P: AsRef<Path>>(dir: P) -> Result<PathBuf, Error> {
+        let dir = dir.as_ref();
+        for root in dir.ancestors() {
+            if Repo::repo_dir(root)?.is_dir() {
+                return Ok(root.to_owned());
+            }
+        }
+        Err(Error::RepoNotFound(dir.to_owned()))
+    }
+
+    /// Opens the existing repository that contains the given directory.
+    ///
+    /// The directory can be the root directory of the repository, or any directory inside it (see
+    /// [`Repo::find_root`]). If the repository is encrypted, this fails with
+    /// [`Error::MissingKey`]; use [`Repo::open_encrypted`] instead.
     pub fn open<P: AsRef<Path>>(dir: P) -> Result<Repo, Error> {
-        let db_path = Repo::db_path(dir.as_ref())?;
+        Repo::open_with_key(dir.as_ref(), None)
+    }
+
+    /// Opens the existing repository that contains the given directory, using `key` to decrypt
+    /// it.
+    ///
+    /// The repository doesn't need to be encrypted already. Either way, it will be encrypted with
+    /// `key` the next time that it is written (see [`Repo::set_encryption_key`]).
+    #[cfg(feature = "compression")]
+    pub fn open_encrypted<P: AsRef<Path>>(dir: P, key: EncryptionKey) -> Result<Repo, Error> {
+        Repo::open_with_key(dir.as_ref(), Some(key))
+    }
+
+    /// Opens the existing repository that contains the given directory, without ever changing it.
+    ///
+    /// This is meant for inspecting a repository, possibly while another process is writing to
+    /// it. Anything that would change the repository or its working copy (including
+    /// [`Repo::write`]) fails with [`Error::ReadOnly`], apart from the dry runs of
+    /// [`Repo::merge`] and [`Repo::gc`] (and `gc` is always a dry run).
+    pub fn open_readonly<P: AsRef<Path>>(dir: P) -> Result<Repo, Error> {
+        let mut repo = Repo::open_with_key(dir.as_ref(), None)?;
+        repo.readonly = true;
+        Ok(repo)
+    }
+
+    /// Returns true if this repository was opened with [`Repo::open_readonly`].
+    pub fn is_readonly(&self) -> bool {
+        self.readonly
+    }
+
+    /// Adds a linked worktree at `dir`: another working copy of this repository, with `branch`
+    /// checked out, and returns the repository as seen from there.
+    ///
+    /// The worktrees share everything apart from their working copies, their current branches
+    /// and their unrecorded changes (see [`Repo::track_file`]). So, for example, a merge can be
+    /// tried out in a linked worktree while the working copy of the main one stays as it is.
+    /// More than one worktree can have the same branch checked out; after one of them records a
+    /// patch on it, the others can catch up with [`Repo::update_working_copy`].
+    ///
+    /// This writes the repository to disk (see [`Repo::write`]), and fails if it was never
+    /// written, or if there is already a repository at `dir`.
+    pub fn add_worktree<P: AsRef<Path>>(&mut self, dir: P, branch: &str) -> Result<Repo, Error> {
+        self.check_writable()?;
+        worktree::add(self, dir.as_ref(), branch)
+    }
+
+    /// Removes the linked worktree at `dir` (see [`Repo::add_worktree`]), which can't be the one
+    /// that this repository was opened from.
+    ///
+    /// The files in its working copy stay where they are, but they aren't a working copy of this
+    /// repository anymore.
+    pub fn remove_worktree<P: AsRef<Path>>(&mut self, dir: P) -> Result<(), Error> {
+        self.check_writable()?;
+        worktree::remove(self, dir.as_ref())
+    }
+
+    /// Returns the root directories of the linked worktrees of this repository (see
+    /// [`Repo::add_worktree`]), in sorted order. This doesn't include the main worktree.
+    pub fn worktrees(&self) -> impl Iterator<Item = &Path> {
+        self.storage.worktrees.iter().map(|p| p.as_path())
+    }
+
+    /// Returns true if this repository was opened from a linked worktree (see
+    /// [`Repo::add_worktree`]).
+    pub fn is_linked_worktree(&self) -> bool {
+        self.main_worktree.is_some()
+    }
+
+    fn open_with_key(dir: &Path, encryption_key: Option<EncryptionKey>) -> Result<Repo, Error> {
+        let dir = &Repo::find_root(dir)?;
+        // A linked worktree has its own state, but everything else is in its repository's
+        // directory.
+        let linked = worktree::load(dir)?;
+        let repo_dir = match &linked {
+            Some((repo_dir, _)) => repo_dir.clone(),
+            None => Repo::repo_dir(dir)?,
+        };
+        let db_path = repo_dir.join("db");
+        let db_stamp = worktree::Stamp::of(&db_path)?;
         let db_file = fs::File::open(&db_path)?;
-        let db: Db = serde_yaml::from_reader(db_file)?;
-        Ok(Repo {
-            root_dir: dir.as_ref().to_owned(),
-            repo_dir: Repo::repo_dir(dir.as_ref())?,
+        let mut db: Db = serde_yaml::from_reader(db_file)?;
+        let mut storage = db.storage;
+        let mut current_branch = db.current_branch;
+        let main_worktree = match linked {
+            Some((_, mut state)) => {
+                state.swap(&mut current_branch, &mut storage);
+                Some(state)
+            }
+            None => None,
+        };
+        load_patches(&mut storage, &mut db.patches, encryption_key.as_ref())?;
+        // Repositories written by older versions of ojo have their settings in the database.
+        let config_path = repo_dir.join(CONFIG_FILE);
+        let config = if config_path.exists() {
+            Config::load(&config_path)?
+        } else {
+            db.config
+        };
+        let mut repo = Repo {
+            root_dir: dir.to_owned(),
+            repo_dir,
             db_path,
-            current_branch: db.current_branch,
-            storage: db.storage,
-        })
+            current_branch,
+            config,
+            storage,
+            dictionary: db.patches.dictionary()?,
+            encryption_key,
+            hooks: hooks::Hooks::default(),
+            progress: progress::Sink::default(),
+            vfs: None,
+            readonly: false,
+            main_worktree,
+            db_stamp: Mutex::new(db_stamp),
+            global_config: Config::load_global()?,
+        };
+        repo.index_patches()?;
+        Ok(repo)
+    }
+
+    // Adds any patches that are missing from the metadata index (which can happen if the
+    // repository was written by an older version of ojo).
+    fn index_patches(&mut self) -> Result<(), Error> {
+        let missing = self
+            .all_patches()
+            .filter(|id| !self.storage.patch_index.contains(id))
+            .cloned()
+            .collect::<Vec<_>>();
+        for id in missing {
+            let patch = self.open_patch(&id)?;
+            self.storage.patch_index.insert(id, &patch);
+        }
+        Ok(())
     }
 
     /// Creates a repo at the given path (which should point to a directory).
@@ -159,11 +432,99 @@ impl Repo {
             repo_dir,
             db_path,
             current_branch: "master".to_owned(),
+            config: Config::default(),
             storage,
+            dictionary: None,
+            encryption_key: None,
+            hooks: hooks::Hooks::default(),
+            progress: progress::Sink::default(),
+            vfs: None,
+            readonly: false,
+            main_worktree: None,
+            db_stamp: Mutex::new(None),
+            global_config: Config::load_global()?,
         })
     }
 
+    /// Creates a new repository at `dst` that is a copy of the repository at `src`, and writes it
+    /// to disk.
+    ///
+    /// If `branch` is given, only that branch and its patches are copied, and it becomes the
+    /// current branch of the new repository. Otherwise, every patch and every branch is copied,
+    /// and the current branch is the same as in the original. The branches are rebuilt by applying
+    /// their patches, and each patch is checked against its id on the way. Since the patches now
+    /// exist in two places, they are marked as published (see [`Repo::mark_published`]).
+    ///
+    /// This doesn't write out the files of the working copy.
+    pub fn clone<P: AsRef<Path>, Q: AsRef<Path>>(
+        src: P,
+        dst: Q,
+        branch: Option<&str>,
+    ) -> Result<Repo, Error> {
+        Repo::clone_with_sink(src, dst, branch, progress::Sink::default())
+    }
+
+    /// Like [`Repo::clone`], but reports its progress to `sink` (see [`Phase::Clone`]).
+    ///
+    /// The new repository keeps reporting to `sink` (see [`Repo::set_progress_sink`]).
+    pub fn clone_with_progress<P: AsRef<Path>, Q: AsRef<Path>>(
+        src: P,
+        dst: Q,
+        branch: Option<&str>,
+        sink: Arc<dyn ProgressSink>,
+    ) -> Result<Repo, Error> {
+        Repo::clone_with_sink(src, dst, branch, progress::Sink(Some(sink)))
+    }
+
+    fn clone_with_sink<P: AsRef<Path>, Q: AsRef<Path>>(
+        src: P,
+        dst: Q,
+        branch: Option<&str>,
+        sink: progress::Sink,
+    ) -> Result<Repo, Error> {
+        let src = Repo::open(src)?;
+        let dst_dir = dst.as_ref();
+        let mut dst = Repo::init(dst_dir)?;
+        dst.progress = sink;
+        clone::copy(&src, &mut dst, branch)?;
+        fs::create_dir_all(dst_dir)
+            .map_err(|e| Error::Io(e, format!("Could not create {}", dst_dir.display())))?;
+        dst.write()?;
+        Ok(dst)
+    }
+
+    /// Creates a new repository at `dst` containing `branch` of the repository at `src`, and writes
+    /// it to disk.
+    ///
+    /// Unlike [`Repo::clone`], this only copies the data of the `depth` most recent patches on
+    /// the branch. The other patches become ghosts (see [`Repo::ghosts`]): their metadata is
+    /// there, and so are the lines that they introduced, but if something needs to read one of
+    /// them, it gets downloaded from `src` first (see [`Repo::fetch_ghosts`]).
+    pub fn clone_shallow<P: AsRef<Path>, Q: AsRef<Path>>(
+        src: P,
+        dst: Q,
+        branch: &str,
+        depth: usize,
+    ) -> Result<Repo, Error> {
+        let src = Repo::open(src)?;
+        let origin = fs::canonicalize(&src.root_dir)?;
+        let origin = origin
+            .to_str()
+            .ok_or_else(|| Error::NonUtfFilename(origin.clone().into_os_string()))?
+            .to_owned();
+        let dst_dir = dst.as_ref();
+        let mut dst = Repo::init(dst_dir)?;
+        shallow::copy(&src, &mut dst, branch, depth, origin)?;
+        fs::create_dir_all(dst_dir)
+            .map_err(|e| Error::Io(e, format!("Could not create {}", dst_dir.display())))?;
+        dst.write()?;
+        Ok(dst)
+    }
+
     /// Creates a temporary in-memory repo that cannot be stored.
+    ///
+    /// Unlike the other ways of creating a repo, this ignores the system-wide settings and the
+    /// ones of the current user.
     pub fn init_tmp() -> Repo {
         let mut storage = storage::Storage::new();
         let master_inode = storage.allocate_inode();
@@ -174,12 +535,95 @@ impl Repo {
             repo_dir: PathBuf::new(),
             db_path: PathBuf::new(),
             current_branch: "master".to_owned(),
+            config: Config::default(),
             storage,
+            dictionary: None,
+            encryption_key: None,
+            hooks: hooks::Hooks::default(),
+            progress: progress::Sink::default(),
+            vfs: None,
+            readonly: false,
+            main_worktree: None,
+            db_stamp: Mutex::new(None),
+            global_config: Config::default(),
+        }
+    }
+
+    /// Creates an in-memory repo whose working copy is also in memory.
+    ///
+    /// Like the repo from [`Repo::init_tmp`], this ignores the system-wide settings and the ones
+    /// of the current user. But since it doesn't touch the filesystem at all, everything that
+    /// works with the working copy (recording, checking out branches, and so on) can be scripted
+    /// by writing files into `working_copy`. This is the same as [`Repo::init_vfs`] with
+    /// `working_copy`, except that it doesn't check whether there is already a repository there.
+    pub fn init_in_memory(working_copy: MemoryWorkingCopy) -> Repo {
+        let mut repo = Repo::init_tmp();
+        repo.set_vfs(Arc::new(working_copy));
+        repo
+    }
+
+    /// Creates a repo in a filesystem other than the native one (see [`Vfs`]).
+    ///
+    /// The repository's working copy is the whole of `vfs`, and [`Repo::write`] stores its data
+    /// there, in the `.ojo` directory, just like for a repository on disk. As with
+    /// [`Repo::init_tmp`], the system-wide settings and the ones of the current user are ignored.
+    pub fn init_vfs(vfs: Arc<dyn Vfs>) -> Result<Repo, Error> {
+        if vfs.exists(VFS_DB_PATH) {
+            return Err(Error::RepoExists(PathBuf::from(VFS_REPO_DIR)));
         }
+        let mut repo = Repo::init_tmp();
+        repo.set_vfs(vfs);
+        Ok(repo)
+    }
+
+    /// Opens a repo that was written to a filesystem other than the native one (see
+    /// [`Repo::init_vfs`]).
+    pub fn open_vfs(vfs: Arc<dyn Vfs>) -> Result<Repo, Error> {
+        let data = match vfs.read(VFS_DB_PATH) {
+            Ok(data) => data,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(Error::RepoNotFound(PathBuf::new()));
+            }
+            Err(e) => return Err(Error::Io(e, format!("Could not read {}", VFS_DB_PATH))),
+        };
+        let mut db: Db = serde_yaml::from_slice(&data)?;
+        let mut storage = db.storage;
+        load_patches(&mut storage, &mut db.patches, None)?;
+        let config_path = format!("{}/{}", VFS_REPO_DIR, CONFIG_FILE);
+        let config = match vfs.read(&config_path) {
+            Ok(data) => {
+                Config::from_toml(&String::from_utf8_lossy(&data), Path::new(&config_path))?
+            }
+            Err(_) => db.config,
+        };
+
+        let mut repo = Repo::init_tmp();
+        repo.set_vfs(vfs);
+        repo.current_branch = db.current_branch;
+        repo.config = config;
+        repo.storage = storage;
+        repo.dictionary = db.patches.dictionary()?;
+        repo.index_patches()?;
+        Ok(repo)
+    }
+
+    fn set_vfs(&mut self, vfs: Arc<dyn Vfs>) {
+        self.repo_dir = PathBuf::from(VFS_REPO_DIR);
+        self.db_path = PathBuf::from(VFS_DB_PATH);
+        self.vfs = Some(vfs);
+    }
+
+    // Creates a temporary in-memory repo whose working copy is a fresh temporary directory.
+    #[cfg(test)]
+    pub(crate) fn init_tmp_with_working_copy(name: &str) -> Repo {
+        let mut repo = Repo::init_tmp();
+        repo.root_dir = test_util::tmp_dir(name);
+        repo
     }
 
     /// Clears a branch, removing all of its patches.
     pub fn clear(&mut self, branch: &str) -> Result<(), Error> {
+        self.check_writable()?;
         let inode = self.inode(branch)?;
         self.storage.branch_patches.remove_all(branch);
         self.storage.remove_graggle(inode);
@@ -190,18 +634,151 @@ impl Repo {
 
     /// Persists the repository to disk.
     ///
-    /// Any modifications that were previously made become permanent.
+    /// Any modifications that were previously made become permanent. The database is written to
+    /// a temporary file first, and then moved into place, so that other processes reading the
+    /// repository (for example, with [`Repo::open_readonly`]) never see it half-written.
+    ///
+    /// While the database is being written, it is locked, and if another process is writing it
+    /// at the same time, this fails with [`Error::Locked`]. If another process (for example, one
+    /// working in a linked worktree; see [`Repo::add_worktree`]) wrote the database since this
+    /// repository was opened, this fails with [`Error::RepoChanged`] instead of overwriting the
+    /// other process's changes.
     pub fn write(&self) -> Result<(), Error> {
+        self.check_writable()?;
+        let patches = self.patch_store()?;
+        if let Some(vfs) = &self.vfs {
+            return self.write_vfs(&**vfs, patches);
+        }
+        self.try_create_dir(&self.repo_dir)?;
+        let _lock = worktree::Lock::acquire(&self.repo_dir)?;
+        let mut db_stamp = self.db_stamp.lock().unwrap();
+        if worktree::Stamp::of(&self.db_path)? != *db_stamp {
+            return Err(Error::RepoChanged(self.root_dir.clone()));
+        }
+
+        let tmp_path = self.db_path.with_extension("tmp");
+        let db_file = fs::File::create(&tmp_path)?;
+        let mut storage = self.db_storage(&patches);
+        // A linked worktree's own state goes in its own file, and the database gets the state of
+        // the main worktree.
+        let mut current_branch = self.current_branch.clone();
+        if let Some(main) = &self.main_worktree {
+            main.clone().swap(&mut current_branch, storage.to_mut());
+            worktree::save(self)?;
+        }
         let db = DbRef {
-            current_branch: &self.current_branch,
-            storage: &self.storage,
+            current_branch: &current_branch,
+            storage: &storage,
+            patches,
         };
-        self.try_create_dir(&self.repo_dir)?;
-        let db_file = fs::File::create(&self.db_path)?;
         serde_yaml::to_writer(db_file, &db)?;
+        fs::rename(&tmp_path, &self.db_path)?;
+        *db_stamp = worktree::Stamp::of(&self.db_path)?;
+        self.config.save(&self.repo_dir.join(CONFIG_FILE))
+    }
+
+    // The part of the storage that goes in the database.
+    fn db_storage(&self, patches: &compress::PatchStore) -> Cow<'_, storage::Storage> {
+        // The contents of the branches are encrypted along with the patches, so they shouldn't
+        // also be written out in the clear.
+        if patches.is_encrypted() {
+            Cow::Owned(self.storage.without_contents())
+        } else {
+            Cow::Borrowed(&self.storage)
+        }
+    }
+
+    // Like `write`, but for a repository in a filesystem other than the native one. Since nothing
+    // else can be using the filesystem at the same time, there's no locking.
+    fn write_vfs(&self, vfs: &dyn Vfs, patches: compress::PatchStore) -> Result<(), Error> {
+        let storage = self.db_storage(&patches);
+        let db = DbRef {
+            current_branch: &self.current_branch,
+            storage: &storage,
+            patches,
+        };
+        let data = serde_yaml::to_string(&db)?;
+        vfs.write(VFS_DB_PATH, data.as_bytes())
+            .map_err(|e| Error::Io(e, format!("Could not write {}", VFS_DB_PATH)))?;
+        let config_path = format!("{}/{}", VFS_REPO_DIR, CONFIG_FILE);
+        let config = self.config.to_toml(Path::new(&config_path))?;
+        vfs.write(&config_path, config.as_bytes())
+            .map_err(|e| Error::Io(e, format!("Could not write {}", config_path)))
+    }
+
+    /// Returns the settings that are in effect for this repository.
+    ///
+    /// These are the system-wide settings (see [`Config::system_path`]), overridden by the
+    /// settings of the current user (see [`Config::user_path`]), overridden by the repository's
+    /// own settings (in the `config` field). The system-wide and user settings are read when the
+    /// repository is opened.
+    pub fn config(&self) -> Config {
+        let mut ret = self.global_config.clone();
+        ret.merge(&self.config);
+        ret
+    }
+
+    #[cfg(feature = "compression")]
+    fn patch_store(&self) -> Result<compress::PatchStore, Error> {
+        let mut store = compress::PatchStore::compress(
+            &self.storage.patches,
+            self.config().compression_level(),
+            self.dictionary.as_ref().map(|d| &d[..]),
+        )?;
+        if let Some(ref key) = self.encryption_key {
+            store.encrypt(key, &self.storage.contents_data()?)?;
+        }
+        Ok(store)
+    }
+
+    // Without compression support, the patches are stored uncompressed along with the rest of
+    // the storage.
+    #[cfg(not(feature = "compression"))]
+    fn patch_store(&self) -> Result<compress::PatchStore, Error> {
+        Ok(compress::PatchStore::default())
+    }
+
+    /// Trains a compression dictionary on the patches that are currently in the repository.
+    ///
+    /// From now on, all patches will be compressed using this dictionary when the repository is
+    /// written to disk. Since lines tend to be shared between many patches, this can
+    /// significantly reduce the size of the repository. The dictionary is not automatically
+    /// updated when new patches are added, but it can be retrained at any time.
+    ///
+    /// Training will fail if there aren't enough patches in the repository to train on.
+    #[cfg(feature = "compression")]
+    pub fn train_compression_dictionary(&mut self, max_size: usize) -> Result<(), Error> {
+        self.check_writable()?;
+        self.dictionary = Some(compress::train_dictionary(&self.storage.patches, max_size)?);
         Ok(())
     }
 
+    /// Stops using a compression dictionary (if one was previously trained).
+    pub fn clear_compression_dictionary(&mut self) {
+        self.dictionary = None;
+    }
+
+    /// Returns true if patches will be compressed using a trained dictionary.
+    pub fn has_compression_dictionary(&self) -> bool {
+        self.dictionary.is_some()
+    }
+
+    /// Sets (or, if `key` is `None`, clears) the key for encrypting the repository.
+    ///
+    /// When the repository is written to disk, the contents of the patches and of the branches
+    /// will be encrypted with this key. The patch headers, the dependencies between patches and
+    /// the names of the branches are not encrypted, so the history can still be examined without
+    /// the key. An encrypted repository needs to be opened with [`Repo::open_encrypted`].
+    #[cfg(feature = "compression")]
+    pub fn set_encryption_key(&mut self, key: Option<EncryptionKey>) {
+        self.encryption_key = key;
+    }
+
+    /// Returns true if the repository will be encrypted when it is written to disk.
+    pub fn is_encrypted(&self) -> bool {
+        self.encryption_key.is_some()
+    }
+
     fn inode(&self, branch: &str) -> Result<storage::INode, Error> {
         Ok(self
             .storage
@@ -230,6 +807,213 @@ impl Repo {
             .ok_or(Error::NotOrdered)
     }
 
+    /// Retrieves the binary contents of a branch, or `None` if it doesn't have any.
+    ///
+    /// Binary contents are tracked separately from the lines of the file (see
+    /// [`Change::BinaryReplace`]); they are set by applying patches created with
+    /// [`Repo::binary_changes`], or by recording a binary file (see [`Config::is_binary`]).
+    pub fn binary(&self, branch: &str) -> Result<Option<Cow<'_, [u8]>>, Error> {
+        let inode = self.inode(branch)?;
+        Ok(self.storage.binary(inode))
+    }
+
+    /// Returns the changes that replace a branch's binary contents with `contents`.
+    ///
+    /// If the contents are large (see [`CHUNK_THRESHOLD`]), the changes only include the parts of
+    /// them that aren't in the old contents (see [`Change::ChunkedReplace`]).
+    pub fn binary_changes(&self, branch: &str, contents: Vec<u8>) -> Result<Changes, Error> {
+        let inode = self.inode(branch)?;
+        let old = self.storage.binary_ref(inode);
+        Ok(Changes {
+            changes: vec![self.binary_replace(old, contents)],
+        })
+    }
+
+    // Returns the change that replaces the binary contents `old` with `contents`.
+    fn binary_replace(&self, old: Option<&BlobRef>, contents: Vec<u8>) -> Change {
+        if let Some(old) = old {
+            if contents.len() > CHUNK_THRESHOLD {
+                let chunks = chunk::delta(&self.storage.blob(&old.hash), &contents);
+                if chunks.iter().any(|c| matches!(c, BlobChunk::Copy { .. })) {
+                    return Change::ChunkedReplace {
+                        old: *old,
+                        hash: BlobHash::of(&contents),
+                        chunks,
+                    };
+                }
+            }
+        }
+        Change::BinaryReplace {
+            old: old.cloned(),
+            new_blob: contents,
+        }
+    }
+
+    // Returns the binary contents that were introduced by a patch. Usually these are in storage
+    // already, but they might not be if the patch was never applied.
+    fn blob_contents(&self, blob: &BlobRef) -> Result<Cow<'_, [u8]>, Error> {
+        if self.storage.has_blob(&blob.hash) {
+            return Ok(self.storage.blob(&blob.hash));
+        }
+        let patch = self.open_patch(&blob.patch)?;
+        for (_, ch) in patch.changes().flattened() {
+            if ch.new_blob_hash() != Some(blob.hash) {
+                continue;
+            }
+            match ch {
+                Change::BinaryReplace { new_blob, .. } => return Ok(Cow::Owned(new_blob.clone())),
+                Change::ChunkedReplace { old, chunks, .. } => {
+                    let old = self.blob_contents(old)?;
+                    return chunk::assemble(&old, chunks)
+                        .map(Cow::Owned)
+                        .ok_or(Error::InvalidChunks(blob.hash));
+                }
+                _ => {}
+            }
+        }
+        Err(Error::UnknownBlob(blob.hash))
+    }
+
+    /// Returns the paths of all the files on a branch, in sorted order.
+    pub fn files<'a>(&'a self, branch: &str) -> Result<impl Iterator<Item = &'a str>, Error> {
+        let inode = self.inode(branch)?;
+        Ok(self.storage.graggle_data(inode).files().paths())
+    }
+
+    /// Returns the directories that contain the files on a branch (see [`Repo::files`]), in sorted
+    /// order.
+    ///
+    /// Directories aren't tracked on their own: a branch has a directory exactly when it has a
+    /// file inside it.
+    pub fn directories(&self, branch: &str) -> Result<Vec<String>, Error> {
+        let inode = self.inode(branch)?;
+        Ok(self.storage.graggle_data(inode).files().directories())
+    }
+
+    /// Returns a reference to the file at `path` on a branch, for use in a [`Change::DeleteFile`],
+    /// [`Change::MoveFile`], [`Change::SetExecutable`] or [`Change::SetSymlink`].
+    pub fn file_ref(&self, branch: &str, path: &str) -> Result<FileRef, Error> {
+        let inode = self.inode(branch)?;
+        match self.storage.graggle_data(inode).files().get(path) {
+            Some(state) => Ok(FileRef {
+                patch: state.patch,
+                path: path.to_owned(),
+            }),
+            None => Err(Error::UnknownFile(path.to_owned())),
+        }
+    }
+
+    /// Returns the identity of the file at `path` on a branch, for use in a [`Change::EditFile`].
+    ///
+    /// Unlike the [`FileRef`] returned by [`Repo::file_ref`], this doesn't change when the file is
+    /// moved or its metadata is changed.
+    pub fn file_id(&self, branch: &str, path: &str) -> Result<FileRef, Error> {
+        let inode = self.inode(branch)?;
+        self.storage
+            .graggle_data(inode)
+            .files()
+            .id(path)
+            .ok_or_else(|| Error::UnknownFile(path.to_owned()))
+    }
+
+    /// Returns the graggle containing the lines of the file at `path` on a branch.
+    pub fn file_graggle<'a>(&'a self, branch: &str, path: &str) -> Result<Graggle<'a>, Error> {
+        let id = self.file_id(branch, path)?;
+        let inode = self.inode(branch)?;
+        self.storage
+            .graggle_data(inode)
+            .file_graggle(&id)
+            .map(|g| g.as_graggle())
+            .ok_or_else(|| Error::UnknownFile(path.to_owned()))
+    }
+
+    /// Retrieves the lines of the file at `path` on a branch, assuming that they are totally
+    /// ordered.
+    pub fn file_at(&self, branch: &str, path: &str) -> Result<File, Error> {
+        self.file_graggle(branch, path)?
+            .as_live_graph()
+            .linear_order()
+            .map(|ref order| File::from_ids(order, &self.storage))
+            .ok_or(Error::NotOrdered)
+    }
+
+    /// Like [`Repo::binary`], but for the file at `path` on a branch.
+    pub fn binary_at(&self, branch: &str, path: &str) -> Result<Option<Cow<'_, [u8]>>, Error> {
+        let graggle = self.file_graggle(branch, path)?;
+        Ok(graggle.binary().map(|b| self.storage.blob(&b.hash)))
+    }
+
+    /// Writes out the lines of a branch (or its binary contents, if it has any).
+    ///
+    /// Unlike [`Repo::file`], this works even if the lines aren't totally ordered. The parts that
+    /// aren't ordered (see [`Graggle::conflicts`]) are written out between conflict markers, with
+    /// the alternatives of each [`Conflict`] one after the other. The first alternative is
+    /// preceded by a line starting with `<<<<<<<`, every other alternative by a line starting with
+    /// `=======`, and the conflict ends with a line consisting of `>>>>>>>`. The marker lines at
+    /// the start of the alternatives also contain the (shortened, see [`Repo::short_patch_id`])
+    /// ids of the patches that introduced the alternative's lines.
+    pub fn render<W: Write>(&self, branch: &str, w: W) -> Result<(), Error> {
+        render::render(self, self.graggle(branch)?, w)
+    }
+
+    /// Like [`Repo::render`], but for the file at `path` on a branch.
+    pub fn render_file<W: Write>(&self, branch: &str, path: &str, w: W) -> Result<(), Error> {
+        render::render(self, self.file_graggle(branch, path)?, w)
+    }
+
+    /// Writes out all the files on a branch as an archive, for making a release tarball, for
+    /// example.
+    ///
+    /// The archive has the files that a checkout of the branch would have (including
+    /// [`DEFAULT_WORKING_FILE`], if the branch has any lines of its own), with their executable
+    /// bits and symbolic links. The files are archived as they are recorded, without the
+    /// conversions of [`Config::text_format`], and all of them get the time of the branch's newest
+    /// patch, so that an archive of the same files is always the same. If any of the files has
+    /// conflicts, `conflicts` says whether to fail (before writing anything) or to write them with
+    /// conflict markers, like [`Repo::render`] does.
+    pub fn archive<W: Write>(
+        &self,
+        branch: &str,
+        format: ArchiveFormat,
+        conflicts: ArchiveConflicts,
+        w: W,
+    ) -> Result<(), Error> {
+        archive::archive(self, branch, format, conflicts, w)
+    }
+
+    /// Returns the lines of a branch, together with the patches that introduced them and their
+    /// authors.
+    ///
+    /// The lines come in the same order as in [`Repo::render`], but without any conflict markers.
+    /// All the metadata comes from the repository's index, so no patches need to be opened.
+    pub fn annotate(&self, branch: &str) -> Result<Vec<AnnotatedLine<'_>>, Error> {
+        annotate::annotate(self, self.graggle(branch)?)
+    }
+
+    /// Like [`Repo::annotate`], but for the file at `path` on a branch.
+    pub fn annotate_file(&self, branch: &str, path: &str) -> Result<Vec<AnnotatedLine<'_>>, Error> {
+        annotate::annotate(self, self.file_graggle(branch, path)?)
+    }
+
+    /// Is the file at `path` on a branch executable?
+    pub fn is_executable(&self, branch: &str, path: &str) -> Result<bool, Error> {
+        let inode = self.inode(branch)?;
+        match self.storage.graggle_data(inode).files().get(path) {
+            Some(state) => Ok(state.executable),
+            None => Err(Error::UnknownFile(path.to_owned())),
+        }
+    }
+
+    /// Is the file at `path` on a branch a symbolic link? If so, its contents are the link's
+    /// target.
+    pub fn is_symlink(&self, branch: &str, path: &str) -> Result<bool, Error> {
+        let inode = self.inode(branch)?;
+        match self.storage.graggle_data(inode).files().get(path) {
+            Some(state) => Ok(state.symlink),
+            None => Err(Error::UnknownFile(path.to_owned())),
+        }
+    }
+
     /// Retrieves the contents associated with a node.
     pub fn contents(&self, id: &NodeId) -> &[u8] {
         self.storage.contents(id)
@@ -242,12 +1026,22 @@ impl Repo {
     /// registered locally with [`Repo::register_patch`].
     pub fn open_patch(&self, id: &PatchId) -> Result<Patch, Error> {
         let patch_data = self.open_patch_data(id)?;
-        let ret = Patch::from_reader(patch_data)?;
-        if ret.id() != id {
-            Err(Error::IdMismatch(*ret.id(), *id))
-        } else {
-            Ok(ret)
-        }
+        Patch::from_reader_with_id(patch_data, id)
+    }
+
+    /// Returns a human-readable representation of a patch (see [`Patch::to_text`]).
+    ///
+    /// Where possible, the contents of the lines that the patch deletes, and of the lines around
+    /// the ones that it adds, are included (see [`Patch::to_text_with_context`]).
+    pub fn patch_text(&self, id: &PatchId) -> Result<String, Error> {
+        let patch = self.open_patch(id)?;
+        Ok(patch.to_text_with_context(|node| {
+            if self.storage.contains_node(node) {
+                Some(self.storage.contents(node).to_owned())
+            } else {
+                None
+            }
+        }))
     }
 
     /// Returns the data associated with a patch.
@@ -255,7 +1049,12 @@ impl Repo {
     /// Currently, this data consists of the patch's contents serialized as YAML, but that isn't
     /// guaranteed. What is guaranteed is that the return value of this function is of the same
     /// format as the argument to [`Repo::register_patch`].
+    ///
+    /// If the patch is a ghost (see [`Repo::ghosts`]), this fails with [`Error::GhostPatch`].
     pub fn open_patch_data(&self, id: &PatchId) -> Result<&[u8], Error> {
+        if self.storage.ghosts.contains(id) {
+            return Err(Error::GhostPatch(*id));
+        }
         self.storage
             .patches
             .get(id)
@@ -263,48 +1062,142 @@ impl Repo {
             .ok_or_else(|| Error::UnknownPatch(*id))
     }
 
+    /// Returns the patches whose data hasn't been downloaded, because this repository is a
+    /// shallow clone (see [`Repo::clone_shallow`]).
+    ///
+    /// Ghosts can be on branches, and everything about them except for their data is known.
+    /// Operations that need their data (such as unapplying them) download them automatically.
+    pub fn ghosts(&self) -> impl Iterator<Item = &PatchId> {
+        self.storage.ghosts.iter()
+    }
+
+    /// Is this patch a ghost (see [`Repo::ghosts`])?
+    pub fn is_ghost(&self, id: &PatchId) -> bool {
+        self.storage.ghosts.contains(id)
+    }
+
+    /// Downloads the ghosts (see [`Repo::ghosts`]) among `ids` from the repository that this one
+    /// was cloned from, and returns the ones that were downloaded. Patches that aren't ghosts are
+    /// ignored.
+    pub fn fetch_ghosts(&mut self, ids: &[PatchId]) -> Result<Vec<PatchId>, Error> {
+        self.check_writable()?;
+        shallow::fetch(self, ids)
+    }
+
+    /// Downloads all of the ghosts (see [`Repo::ghosts`]), so that this repository is no longer
+    /// shallow.
+    pub fn unshallow(&mut self) -> Result<Vec<PatchId>, Error> {
+        self.check_writable()?;
+        let ghosts = self.ghosts().cloned().collect::<Vec<_>>();
+        self.fetch_ghosts(&ghosts)
+    }
+
     /// Introduces a patch to the repository.
     ///
     /// After registering a patch, its data will be stored in the repository and you will be able
     /// to access it by its ID.
     pub fn register_patch(&mut self, patch_data: &[u8]) -> Result<PatchId, Error> {
+        self.check_writable()?;
         let patch = Patch::from_reader(patch_data)?;
         let data = String::from_utf8(patch_data.to_owned())?;
         self.register_patch_with_data(&patch, data)?;
         Ok(*patch.id())
     }
 
+    /// Introduces a patch in the textual format (see [`Patch::to_text`]) to the repository.
+    pub fn register_patch_text(&mut self, text: &str) -> Result<PatchId, Error> {
+        self.check_writable()?;
+        let patch = Patch::from_text(text)?;
+        let data = patch.canonical_data()?;
+        self.register_patch_with_data(&patch, data)?;
+        Ok(*patch.id())
+    }
+
     // Before making any modifications, check the patch for consistency. That means:
     // - all dependencies must already be known
     // - every node that we refer to must already be present
     // - every node that we refer to must be either new, or we must depend on its patch
+    // - there is at most one binary replacement per file, and the binary contents that it replaces
+    //   must be the ones introduced by the patch that it claims, in the same file
+    // - every file that we delete, move or modify must have been put in place by the patch that we
+    //   claim
     // This part is *IMPORTANT*, because it contains all the validation for patches. After
     // this, they go from being treated as untrusted input to being internal data.
     fn check_patch_validity(&self, patch: &Patch) -> Result<(), Error> {
+        let kind = patch.header().kind;
+        if !patch.changes().changes.iter().all(|ch| kind.allows(ch)) {
+            return Err(match kind {
+                PatchKind::Ordering => Error::InvalidOrdering(*patch.id()),
+                _ => Error::InvalidResolution(*patch.id()),
+            });
+        }
         for dep in patch.deps() {
             if !self.storage.patches.contains_key(dep) {
                 return Err(Error::MissingDep(*dep));
             }
         }
-        let dep_set = patch.deps().iter().cloned().collect::<HashSet<_>>();
-        let new_nodes = patch
-            .changes()
-            .changes
-            .iter()
-            .filter_map(|ch| {
-                if let Change::NewNode { ref id, .. } = ch {
-                    Some(id)
+        // Every node belongs to the file whose `EditFile` change introduced it (or to no file, if
+        // it was introduced outside of an `EditFile`).
+        let new_nodes = |p: &Patch| {
+            p.changes()
+                .flattened()
+                .filter_map(|(file, ch)| {
+                    if let Change::NewNode { ref id, .. } = ch {
+                        Some((*id, file.cloned()))
+                    } else {
+                        None
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+        // The nodes that we are allowed to refer to are the ones that we introduce, and the ones
+        // that our dependencies introduce, as long as they belong to the same file that we're
+        // changing. (Note that the dependencies aren't necessarily applied to any branch, so we
+        // need to look at the patches themselves.)
+        let mut known_nodes = new_nodes(patch).into_iter().collect::<HashMap<_, _>>();
+        for dep in patch.deps() {
+            known_nodes.extend(new_nodes(&self.open_patch(dep)?));
+        }
+        // Only the lines that this patch introduces can have their authors recorded in it.
+        for &node in patch.header().line_authors.keys() {
+            let id = NodeId {
+                patch: *patch.id(),
+                node,
+            };
+            if !known_nodes.contains_key(&id) {
+                return Err(Error::UnknownNode(id));
+            }
+        }
+        for ch in &patch.changes().changes {
+            if let Change::EditFile { ref file, .. } = *ch {
+                let creates_file = |p: &Patch| {
+                    p.changes().changes.iter().any(|ch| match ch {
+                        Change::NewFile { path } => path == &file.path,
+                        _ => false,
+                    })
+                };
+                let found = if &file.patch == patch.id() {
+                    creates_file(patch)
                 } else {
-                    None
+                    creates_file(&self.open_patch(&file.patch)?)
+                };
+                if !found {
+                    return Err(Error::UnknownFile(file.path.clone()));
                 }
-            })
-            .collect::<HashSet<_>>();
-        for ch in &patch.changes().changes {
+            }
+        }
+        for (file, ch) in patch.changes().flattened() {
             use crate::patch::Change::*;
-            let has_node = |id| {
-                new_nodes.contains(id)
-                    || (self.storage.contains_node(id) && dep_set.contains(&id.patch))
-            };
+            let has_node = |id| known_nodes.get(id) == Some(&file.cloned());
+            match ch {
+                NewNode { .. }
+                | NewEdge { .. }
+                | DeleteNode { .. }
+                | BinaryReplace { .. }
+                | ChunkedReplace { .. } => {}
+                _ if file.is_some() => return Err(Error::InvalidFileEdit(*patch.id())),
+                _ => {}
+            }
             match ch {
                 NewNode { ref id, .. } => {
                     if !has_node(id) {
@@ -324,6 +1217,68 @@ impl Repo {
                         return Err(Error::UnknownNode(*id));
                     }
                 }
+                DeleteFile { file: ref f }
+                | MoveFile { from: ref f, .. }
+                | SetExecutable { file: ref f, .. }
+                | SetSymlink { file: ref f, .. } => {
+                    let puts_file = |p: &Patch| {
+                        p.changes().changes.iter().any(|ch| match ch {
+                            NewFile { path }
+                            | MoveFile { to: path, .. }
+                            | SetExecutable {
+                                file: FileRef { path, .. },
+                                ..
+                            }
+                            | SetSymlink {
+                                file: FileRef { path, .. },
+                                ..
+                            } => path == &f.path,
+                            _ => false,
+                        })
+                    };
+                    let found = if &f.patch == patch.id() {
+                        puts_file(patch)
+                    } else {
+                        puts_file(&self.open_patch(&f.patch)?)
+                    };
+                    if !found {
+                        return Err(Error::UnknownFile(f.path.clone()));
+                    }
+                }
+                NewFile { .. } | EditFile { .. } => {}
+                BinaryReplace { .. } | ChunkedReplace { .. } => {
+                    if let Some(Some(old)) = ch.replaced_blob() {
+                        // The replaced contents must belong to the same file.
+                        let replaced = self.open_patch(&old.patch)?;
+                        let found = replaced
+                            .changes()
+                            .flattened()
+                            .any(|(f, ch)| f == file && ch.new_blob_hash() == Some(old.hash));
+                        if !found {
+                            return Err(Error::UnknownBlob(old.hash));
+                        }
+                    }
+                    // The chunks must put together the contents that they claim to.
+                    if let ChunkedReplace {
+                        ref old,
+                        ref hash,
+                        ref chunks,
+                    } = ch
+                    {
+                        let old = self.blob_contents(old)?;
+                        match chunk::assemble(&old, chunks) {
+                            Some(ref data) if BlobHash::of(data) == *hash => {}
+                            _ => return Err(Error::InvalidChunks(*hash)),
+                        }
+                    }
+                }
+            }
+        }
+        // Each file (and the branch itself) gets its binary contents replaced at most once.
+        let mut replaced = HashSet::new();
+        for (file, ch) in patch.changes().flattened() {
+            if ch.replaced_blob().is_some() && !replaced.insert(file) {
+                return Err(Error::MultipleBinaryChanges(*patch.id()));
             }
         }
         Ok(())
@@ -332,6 +1287,11 @@ impl Repo {
     fn register_patch_with_data(&mut self, patch: &Patch, data: String) -> Result<(), Error> {
         // If the patch already exists in our repository then there's nothing to do. But if there's
         // a file there with the same hash but different contents then something's really wrong.
+        if self.storage.ghosts.remove(patch.id()) {
+            // We already know everything about ghosts, apart from their data.
+            self.storage.patches.insert(*patch.id(), data);
+            return Ok(());
+        }
         if self.storage.patches.contains_key(patch.id()) {
             let old_patch = self.open_patch(patch.id())?;
             if &old_patch == patch {
@@ -341,6 +1301,8 @@ impl Repo {
             }
         }
 
+        // Checking the patch involves reading its dependencies.
+        self.fetch_ghosts(patch.deps())?;
         self.check_patch_validity(patch)?;
 
         // Record the deps and reverse-deps.
@@ -353,13 +1315,15 @@ impl Repo {
                 .insert(dep.clone(), patch.id().clone());
         }
 
+        self.storage.patch_index.insert(*patch.id(), patch);
         self.storage.patches.insert(patch.id().clone(), data);
         Ok(())
     }
 
     // Applies a single patch to a branch.
     //
-    // Panics if not all of the dependencies are already present.
+    // Panics if not all of the dependencies are already present. Fails without changing anything
+    // if the patch conflicts with the binary contents or the files on the branch.
     fn apply_one_patch(&mut self, branch: &str, patch_id: &PatchId) -> Result<(), Error> {
         let patch = self.open_patch(patch_id)?;
         for dep in patch.deps() {
@@ -369,23 +1333,108 @@ impl Repo {
             );
         }
         let inode = self.storage.inode(branch).unwrap();
-        self.storage
-            .apply_changes(inode, patch.changes(), *patch_id);
+        patch
+            .changes()
+            .check_applicable(self.storage.graggle_data(inode), *patch_id)?;
+        self.storage.apply_patch(inode, &patch);
         self.storage
             .branch_patches
             .insert(branch.to_owned(), patch.id().clone());
         Ok(())
     }
 
+    /// Predicts what would happen if a patch were applied to a branch, without changing anything
+    /// (see [`Patch::check`]).
+    ///
+    /// Unlike [`Patch::check`], this also reports which of the patch's dependencies are missing
+    /// from the branch.
+    pub fn check_patch(&self, branch: &str, patch_id: &PatchId) -> Result<ApplyReport, Error> {
+        let graggle = self.graggle(branch)?;
+        let patch = self.open_patch(patch_id)?;
+        let mut report = patch.check(graggle);
+        report.missing_deps = self
+            .patch_graph()
+            .transitive_deps(patch_id)
+            .into_iter()
+            .filter(|dep| !self.storage.branch_patches.contains(branch, dep))
+            .collect();
+        Ok(report)
+    }
+
     /// Applies a patch (and all its dependencies) to a branch.
     ///
-    /// Returns a list of all the patches that were applied.
+    /// Returns a list of all the patches that were applied. This is the same as [`Repo::apply`]
+    /// with [`ApplyPolicy::Cascade`].
     pub fn apply_patch(&mut self, branch: &str, patch_id: &PatchId) -> Result<Vec<PatchId>, Error> {
-        // If the branch already contains the patch, this is a no-op.
+        self.apply(branch, patch_id, ApplyPolicy::Cascade)
+    }
+
+    /// Applies a patch to a branch.
+    ///
+    /// If the patch depends on patches that aren't on the branch, `policy` determines whether they
+    /// are also applied or whether this fails with [`Error::UnappliedDeps`]. Either way, the branch
+    /// is only modified if all of the necessary patches can be applied: if one of them fails to
+    /// apply, the ones that were already applied are unapplied again. If the branch already
+    /// contains the patch, this does nothing.
+    ///
+    /// Returns a list of all the patches that were applied, in the order that they were applied.
+    ///
+    /// The [`HookPoint::PreApply`] hooks run before anything changes, and can veto the change with
+    /// [`Error::HookFailed`]; the [`HookPoint::PostApply`] hooks run afterwards. Neither runs if
+    /// the branch already contains the patch.
+    pub fn apply(
+        &mut self,
+        branch: &str,
+        patch_id: &PatchId,
+        policy: ApplyPolicy,
+    ) -> Result<Vec<PatchId>, Error> {
+        self.check_writable()?;
+        self.inode(branch)?;
         if self.storage.branch_patches.contains(branch, patch_id) {
             return Ok(vec![]);
         }
-
+        let header = self.patch_header(patch_id)?.clone();
+        hooks::run(self, HookPoint::PreApply, branch, Some(patch_id), &header)?;
+        let applied = self.apply_without_hooks(branch, patch_id, policy)?;
+        hooks::run(self, HookPoint::PostApply, branch, Some(patch_id), &header)?;
+        Ok(applied)
+    }
+
+    // Does the work of `apply`, without running any hooks.
+    fn apply_without_hooks(
+        &mut self,
+        branch: &str,
+        patch_id: &PatchId,
+        policy: ApplyPolicy,
+    ) -> Result<Vec<PatchId>, Error> {
+        let inode = self.inode(branch)?;
+        if !self.storage.patches.contains_key(patch_id) && !self.is_ghost(patch_id) {
+            return Err(Error::UnknownPatch(*patch_id));
+        }
+        // If the branch already contains the patch, this is a no-op.
+        if self.storage.branch_patches.contains(branch, patch_id) {
+            return Ok(vec![]);
+        }
+        let mut needed = self.patch_graph().transitive_deps(patch_id);
+        needed.push(*patch_id);
+        self.fetch_ghosts(&needed)?;
+        if policy == ApplyPolicy::Refuse {
+            let missing = self
+                .patch_graph()
+                .transitive_deps(patch_id)
+                .into_iter()
+                .filter(|dep| !self.storage.branch_patches.contains(branch, dep))
+                .collect::<Vec<_>>();
+            if !missing.is_empty() {
+                return Err(Error::UnappliedDeps(*patch_id, missing));
+            }
+        }
+
+        let total = needed
+            .iter()
+            .filter(|id| !self.storage.branch_patches.contains(branch, id))
+            .count();
+        let mut reporter = progress::Reporter::start(self, Phase::Apply, total);
         let mut patch_stack = vec![*patch_id];
         let mut applied = Vec::new();
         while !patch_stack.is_empty() {
@@ -402,7 +1451,11 @@ impl Repo {
                 // It's possible that this patch was already applied, because it was a dep of
                 // multiple other patches.
                 if !self.storage.branch_patches.contains(branch, &cur) {
-                    self.apply_one_patch(branch, &cur)?;
+                    if let Err(e) = self.apply_one_patch(branch, &cur) {
+                        self.roll_back(branch, &applied);
+                        return Err(e);
+                    }
+                    reporter.patch(self, cur);
                     applied.push(cur.clone());
                 }
                 patch_stack.pop();
@@ -412,64 +1465,113 @@ impl Repo {
         }
 
         // Having applied all the patches, resolve the cache.
-        let inode = self.storage.inode(branch).unwrap();
         self.storage.update_cache(inode);
+        reporter.finish();
         Ok(applied)
     }
 
-    fn unapply_one_patch(&mut self, branch: &str, patch_id: &PatchId) -> Result<(), Error> {
-        debug!("unapplying patch {:?} from branch {:?}", patch_id, branch);
-
-        let patch = self.open_patch(patch_id)?;
-        let inode = self.inode(branch)?;
-        self.storage
-            .unapply_changes(inode, patch.changes(), *patch_id);
-        self.storage.branch_patches.remove(branch, patch.id());
-        Ok(())
+    // Unapplies some patches that were just applied to a branch (in the given order), after a
+    // later patch failed to apply.
+    fn roll_back(&mut self, branch: &str, applied: &[PatchId]) {
+        let inode = self.storage.inode(branch).unwrap();
+        for id in applied.iter().rev() {
+            // We just applied these patches, so we were able to open them a moment ago.
+            let patch = self
+                .open_patch(id)
+                .expect("failed to reopen a patch that was just applied");
+            self.storage.unapply_patch(inode, &patch);
+            self.storage.branch_patches.remove(branch, id);
+        }
+        self.storage.update_cache(inode);
     }
 
     /// Unapplies a patch (and everything that depends on it) to a branch.
     ///
-    /// Returns a list of all the patches that were unapplied.
+    /// Returns a list of all the patches that were unapplied. This is the same as
+    /// [`Repo::unapply`] with [`UnapplyPolicy::Cascade`].
     pub fn unapply_patch(
         &mut self,
         branch: &str,
         patch_id: &PatchId,
     ) -> Result<Vec<PatchId>, Error> {
-        // If the branch doesn't contain the patch, this is a no-op.
-        if !self.storage.branch_patches.contains(branch, patch_id) {
-            return Ok(vec![]);
+        self.unapply(branch, patch_id, UnapplyPolicy::Cascade)
+    }
+
+    /// Unapplies a patch from a branch.
+    ///
+    /// If other patches on the branch depend on this one, `policy` determines whether they are
+    /// also unapplied or whether this fails with [`Error::HasDependents`]. Either way, the branch
+    /// is only modified if all of the necessary patches can be unapplied.
+    ///
+    /// Returns a list of all the patches that were unapplied, in the order that they were
+    /// unapplied.
+    pub fn unapply(
+        &mut self,
+        branch: &str,
+        patch_id: &PatchId,
+        policy: UnapplyPolicy,
+    ) -> Result<Vec<PatchId>, Error> {
+        self.check_writable()?;
+        let inode = self.inode(branch)?;
+        let to_unapply = self.patch_graph().unapply_order(branch, patch_id);
+        if to_unapply.len() > 1 && policy == UnapplyPolicy::Refuse {
+            let dependents = to_unapply[..(to_unapply.len() - 1)].to_vec();
+            return Err(Error::HasDependents(*patch_id, dependents));
         }
+        self.fetch_ghosts(&to_unapply)?;
 
-        let mut patch_stack = vec![*patch_id];
-        let mut unapplied = Vec::new();
-        while !patch_stack.is_empty() {
-            // The unwrap is ok because the stack is non-empty inside the loop.
-            let cur = patch_stack.last().unwrap();
-            let applied_rev_deps = self
-                .storage
-                .patch_rev_deps
-                .get(&cur)
-                .filter(|dep| self.storage.branch_patches.contains(branch, dep))
-                .cloned()
-                .collect::<Vec<_>>();
-            if applied_rev_deps.is_empty() {
-                // It's possible that this patch was already unapplied, because it was a revdep of
-                // multiple other patches.
-                if self.storage.branch_patches.contains(branch, &cur) {
-                    self.unapply_one_patch(branch, &cur)?;
-                    unapplied.push(cur.clone());
-                }
-                patch_stack.pop();
-            } else {
-                patch_stack.extend_from_slice(&applied_rev_deps[..]);
-            }
+        // Read all of the patches before touching the branch, so that failing to read one of them
+        // doesn't leave the branch half-modified.
+        let patches = to_unapply
+            .iter()
+            .map(|p| self.open_patch(p))
+            .collect::<Result<Vec<_>, Error>>()?;
+        for patch in &patches {
+            debug!("unapplying patch {:?} from branch {:?}", patch.id(), branch);
+            self.storage.unapply_patch(inode, patch);
+            self.storage.branch_patches.remove(branch, patch.id());
         }
 
         // Having unapplied all the patches, resolve the cache.
-        let inode = self.storage.inode(branch).unwrap();
-        self.storage.update_cache(inode);
-        Ok(unapplied)
+        if !patches.is_empty() {
+            self.storage.update_cache(inode);
+        }
+        Ok(to_unapply)
+    }
+
+    /// Runs `f`, undoing everything that it did to the repository if it fails.
+    ///
+    /// Within `f`, any number of patches can be created, applied, unapplied and recorded, and
+    /// branches can be created and deleted. If `f` returns an error, the repository is restored to
+    /// the state that it was in before, as if none of that had happened. Only the repository
+    /// itself is restored: changes to the working copy (for example, by [`Repo::checkout`]) and
+    /// whatever the hooks did aren't undone. Nothing is written to disk until [`Repo::write`] is
+    /// called, as usual.
+    ///
+    /// This is only a rollback in memory, for when something goes wrong: the transaction keeps a
+    /// copy of the repository's state from before `f` runs, which costs as much memory (and
+    /// time) as the repository itself.
+    pub fn transaction<T, E, F>(&mut self, f: F) -> Result<T, E>
+    where
+        F: FnOnce(&mut Repo) -> Result<T, E>,
+    {
+        transaction::transaction(self, f)
+    }
+
+    /// Merges one branch into another, by applying every patch that is on `from` but not on
+    /// `into`.
+    ///
+    /// The patches are applied one at a time, in an order that respects their dependencies, and
+    /// each one is reported to the progress sink (see [`Phase::Merge`]). If one of them fails to
+    /// apply, this stops and `into` is left unchanged (see [`Repo::transaction`]). The report lists the patches that were
+    /// applied and the conflicts that `into` has afterwards.
+    ///
+    /// If `dry_run` is true, `into` isn't changed: the report says what would happen.
+    pub fn merge(&mut self, from: &str, into: &str, dry_run: bool) -> Result<MergeReport, Error> {
+        if !dry_run {
+            self.check_writable()?;
+        }
+        merge::merge(self, from, into, dry_run)
     }
 
     /// Returns an iterator over all known patches, applied or otherwise.
@@ -477,12 +1579,66 @@ impl Repo {
         self.storage.patches.keys()
     }
 
+    /// Finds the patch whose id starts with the given prefix.
+    ///
+    /// The prefix is compared against the base64 representation of the id (see
+    /// [`PatchId::to_base64`]), including the leading 'P'. If no known patch matches, or if more
+    /// than one does, this returns an error (which, in the second case, lists all of the
+    /// matching patches).
+    pub fn resolve_patch_prefix(&self, prefix: &str) -> Result<PatchId, Error> {
+        patch::resolve_prefix(self.all_patches(), prefix)
+    }
+
+    /// Returns an abbreviated version of the id of a patch, suitable for display.
+    ///
+    /// The abbreviation is the shortest prefix of the id (but at least
+    /// [`MIN_PATCH_PREFIX_LEN`] characters long) that doesn't match any other patch in this
+    /// repository, so it can be given back to [`Repo::resolve_patch_prefix`]. Note that adding
+    /// more patches to the repository might make it ambiguous.
+    pub fn short_patch_id(&self, id: &PatchId) -> String {
+        let len = patch::unique_prefix_len(self.all_patches(), id);
+        id.to_base64()[..len].to_owned()
+    }
+
     /// Returns an iterator over all of the patches being used in a branch.
     // TODO: maybe a way to check whether a patch is applied to a branch?
     pub fn patches(&self, branch: &str) -> impl Iterator<Item = &PatchId> {
         self.storage.branch_patches.get(branch)
     }
 
+    /// Returns the patches on a branch that match a query, newest first.
+    ///
+    /// Patches always come before the patches that they depend on; apart from that, they are
+    /// sorted by the time that they were created. The patches are chosen and sorted using the
+    /// repository's index of patch metadata, so none of them have to be opened: use
+    /// [`Repo::patch_header`] to get the metadata of the ones that are needed, and
+    /// [`Repo::open_patch`] to get their contents.
+    pub fn log(&self, branch: &str, query: &PatchQuery) -> Result<Log<'_>, Error> {
+        Log::new(self, branch, query)
+    }
+
+    /// Returns the metadata of a patch.
+    ///
+    /// This is cheaper than opening the patch with [`Repo::open_patch`], because the repository
+    /// keeps an index of the metadata of all its patches.
+    pub fn patch_header(&self, id: &PatchId) -> Result<&PatchHeader, Error> {
+        self.storage
+            .patch_index
+            .header(id)
+            .ok_or(Error::UnknownPatch(*id))
+    }
+
+    /// Returns the author of a line (see [`PatchHeader::line_author`]).
+    pub fn line_author(&self, id: &NodeId) -> Result<&str, Error> {
+        Ok(self.patch_header(&id.patch)?.line_author(id.node))
+    }
+
+    /// Returns all the patches (applied or otherwise) whose metadata matches a query, sorted by
+    /// the time that they were created.
+    pub fn find_patches(&self, query: &PatchQuery) -> Vec<PatchId> {
+        self.storage.patch_index.find(query)
+    }
+
     /// Returns an iterator over all direct dependencies of the given patch.
     pub fn patch_deps(&self, patch: &PatchId) -> impl Iterator<Item = &PatchId> {
         self.storage.patch_deps.get(patch)
@@ -493,6 +1649,11 @@ impl Repo {
         self.storage.patch_rev_deps.get(patch)
     }
 
+    /// Returns the dependency graph of all the patches in this repository.
+    pub fn patch_graph(&self) -> PatchGraph<'_> {
+        PatchGraph::new(&self.storage)
+    }
+
     /// Creates a new patch with the given changes and metadata and returns its ID.
     ///
     /// The newly created patch will be automatically registered in the current repository, so
@@ -503,8 +1664,660 @@ impl Repo {
         msg: &str,
         changes: Changes,
     ) -> Result<PatchId, Error> {
-        let patch = UnidentifiedPatch::new(author.to_owned(), msg.to_owned(), changes);
+        let header = PatchHeader::new(author.to_owned(), msg.to_owned());
+        self.create_patch_with_header(header, changes)
+    }
+
+    /// Like [`Repo::create_patch`], but allows all of the patch's metadata to be specified.
+    pub fn create_patch_with_header(
+        &mut self,
+        header: PatchHeader,
+        changes: Changes,
+    ) -> Result<PatchId, Error> {
+        self.check_writable()?;
+        self.create_unidentified_patch(UnidentifiedPatch::with_header(header, changes))
+    }
+
+    /// Creates a patch that resolves a conflict (see [`PatchKind::Resolution`]).
+    ///
+    /// The changes will typically come from [`OrderResolver::changes`](resolver::OrderResolver::changes).
+    /// They may only add edges and delete nodes; otherwise, this returns
+    /// [`Error::InvalidResolution`].
+    pub fn create_resolution_patch(
+        &mut self,
+        author: &str,
+        msg: &str,
+        changes: Changes,
+    ) -> Result<PatchId, Error> {
+        let mut header = PatchHeader::new(author.to_owned(), msg.to_owned());
+        header.kind = PatchKind::Resolution;
+        self.create_patch_with_header(header, changes)
+    }
+
+    /// Returns the changes that put some lines on a branch in the given order.
+    ///
+    /// The lines don't need to include every line on the branch. There is an edge from each line
+    /// to the next one, unless the branch already puts them in that order. If the branch already
+    /// puts some pair of lines in the opposite order, this fails with [`Error::OrderConflict`].
+    pub fn ordering_changes(&self, branch: &str, order: &[NodeId]) -> Result<Changes, Error> {
+        conflict::ordering_changes(self.graggle(branch)?, order)
+    }
+
+    /// Creates a patch that only orders lines (see [`PatchKind::Ordering`]).
+    ///
+    /// The changes will typically come from [`Repo::ordering_changes`]. They may only add edges;
+    /// otherwise, this returns [`Error::InvalidOrdering`].
+    pub fn create_ordering_patch(
+        &mut self,
+        author: &str,
+        msg: &str,
+        changes: Changes,
+    ) -> Result<PatchId, Error> {
+        let mut header = PatchHeader::new(author.to_owned(), msg.to_owned());
+        header.kind = PatchKind::Ordering;
+        self.create_patch_with_header(header, changes)
+    }
+
+    /// Records the changes in the working copy as a new patch, and applies it.
+    ///
+    /// This compares the working copy with a branch (see [`Repo::working_diff`]) and makes a patch
+    /// out of all the differences, so that once it is applied the branch matches the working
+    /// copy. Like any other patch, it depends on the patches that introduced the lines that it
+    /// touches. If there are no differences, this fails with [`Error::NoChanges`].
+    ///
+    /// If the path is ignored (see [`Repo::is_ignored`]) and the branch doesn't have any lines
+    /// yet, this fails with [`Error::IgnoredPath`]: ignored files only get recorded if they were
+    /// already being tracked. If the file doesn't exist and the branch doesn't have any lines,
+    /// there is nothing to record for it.
+    ///
+    /// When recording on the current branch, the patch also contains the changes to the tracked
+    /// files (see [`Repo::tracked_files`]): the files that were added, removed or moved, and the
+    /// changes to the lines of all of them. An added file that has enough lines in common with a
+    /// removed one (see [`Config::rename_threshold`]) is recorded as a move of the removed file,
+    /// followed by an edit, so that its lines keep their history.
+    ///
+    /// The [`HookPoint::PreRecord`] hooks run before the patch is created, and can veto it with
+    /// [`Error::HookFailed`]; the [`HookPoint::PostRecord`] hooks run once it has been applied.
+    /// Applying the new patch doesn't run the hooks for applying patches.
+    pub fn record(
+        &mut self,
+        author: &str,
+        msg: &str,
+        opts: &RecordOptions,
+    ) -> Result<PatchId, Error> {
+        self.check_writable()?;
+        let branch = opts
+            .branch
+            .clone()
+            .unwrap_or_else(|| self.current_branch.clone());
+        let changes = self.working_changes(&branch, opts)?;
+        let tracked = if branch == self.current_branch {
+            Some(tracked::TrackedFiles::default())
+        } else {
+            None
+        };
+
+        let mut header = PatchHeader::new(author.to_owned(), msg.to_owned());
+        header.email = opts.email.clone();
+        self.record_changes(&branch, header, changes, tracked)
+    }
+
+    /// Returns the changes that [`Repo::record`] would record, divided into hunks that can be
+    /// recorded separately.
+    ///
+    /// Unlike [`Repo::record`], this doesn't fail if there are no changes; the returned
+    /// [`PendingRecord`] is just empty.
+    pub fn pending_record(&self, opts: &RecordOptions) -> Result<PendingRecord, Error> {
+        record::pending_record(self, opts)
+    }
+
+    /// Records the hunks that are included in `pending` (see [`Repo::pending_record`]) as a new
+    /// patch, and applies it to `pending`'s branch.
+    ///
+    /// Returns the id of the new patch, along with the changes that are left in the working copy.
+    /// The hunks that weren't recorded keep their ids, so they can be divided up further and
+    /// recorded as more patches. If none of the hunks are included, this fails with
+    /// [`Error::NoChanges`].
+    pub fn record_pending(
+        &mut self,
+        pending: &PendingRecord,
+        author: &str,
+        msg: &str,
+    ) -> Result<(PatchId, PendingRecord), Error> {
+        self.check_writable()?;
+        record::record_pending(self, pending, author, msg)
+    }
+
+    // Makes a patch out of some changes in the working copy, applies it to a branch, and replaces
+    // the unrecorded operations on the tracked files with `tracked` (if it's set).
+    fn record_changes(
+        &mut self,
+        branch: &str,
+        header: PatchHeader,
+        changes: Changes,
+        tracked: Option<tracked::TrackedFiles>,
+    ) -> Result<PatchId, Error> {
+        hooks::run(self, HookPoint::PreRecord, branch, None, &header)?;
+        let id = self.create_patch_with_header(header.clone(), changes)?;
+        self.apply_without_hooks(branch, &id, ApplyPolicy::Cascade)?;
+        if let Some(tracked) = tracked {
+            self.storage.tracked = tracked;
+        }
+        if branch == self.current_branch {
+            tracked::refresh_rendered(self)?;
+        }
+        hooks::run(self, HookPoint::PostRecord, branch, Some(&id), &header)?;
+        Ok(id)
+    }
+
+    // Returns the changes in the working copy that `Repo::record` would record, failing with
+    // `Error::NoChanges` if there aren't any.
+    fn working_changes(&self, branch: &str, opts: &RecordOptions) -> Result<Changes, Error> {
+        let opts = RecordOptions {
+            branch: Some(branch.to_owned()),
+            ..opts.clone()
+        };
+        let (changes, _) = record::pending_record(self, &opts)?.selected_changes(self)?;
+        if changes.changes.is_empty() {
+            return Err(Error::NoChanges);
+        }
+        Ok(changes)
+    }
+
+    /// Puts the changes in the working copy aside, and makes the working copy match the current
+    /// branch again.
+    ///
+    /// The changes are the ones that [`Repo::record`] would record on the current branch (using
+    /// the configured diff options, see [`Repo::config`]), and they are saved as a patch that
+    /// isn't applied to any branch. Returns the id of that patch, whose header has the message
+    /// and the configured author. If there are no changes, this fails with
+    /// [`Error::NoChanges`].
+    ///
+    /// The stashed changes can be brought back with [`Repo::stash_pop`], possibly on a different
+    /// branch.
+    pub fn stash_push(&mut self, msg: &str) -> Result<PatchId, Error> {
+        self.check_writable()?;
+        stash::push(self, msg)
+    }
+
+    /// Brings back the changes that were most recently put aside by [`Repo::stash_push`].
+    ///
+    /// The changes are applied to a copy of the current branch (along with any of the patches
+    /// that they depend on and that aren't on the current branch), and the working copy is made
+    /// to match the result, without recording anything. If this gives conflicts, the working copy
+    /// has conflict markers, and the report lists them.
+    ///
+    /// If the working copy has changes that haven't been recorded, this fails with
+    /// [`Error::UnrecordedChanges`] and nothing changes. If there are no stashed changes, it fails
+    /// with [`Error::NoStash`].
+    pub fn stash_pop(&mut self) -> Result<StashReport, Error> {
+        self.check_writable()?;
+        stash::pop(self)
+    }
+
+    /// Returns the patches holding the changes that were put aside by [`Repo::stash_push`], most
+    /// recent first.
+    pub fn stashes(&self) -> impl Iterator<Item = &PatchId> {
+        self.storage.stashes.iter().rev()
+    }
+
+    /// Registers a hook to run at `point` (see [`HookPoint`]).
+    ///
+    /// Hooks registered this way only last as long as this `Repo`; hooks that should stay with
+    /// the repository are commands in [`Config::hooks`]. At each point, the commands run first and
+    /// then the registered hooks, in the order that they were registered.
+    pub fn add_hook(&mut self, point: HookPoint, hook: Hook) {
+        self.hooks.add(point, hook);
+    }
+
+    /// Registers a sink for the progress reports of long operations (see [`ProgressSink`]),
+    /// replacing the previous one.
+    ///
+    /// Like the hooks that are registered with [`Repo::add_hook`], the sink only lasts as long as
+    /// this `Repo`.
+    pub fn set_progress_sink(&mut self, sink: Arc<dyn ProgressSink>) {
+        self.progress = progress::Sink(Some(sink));
+    }
+
+    /// Returns the paths of the files that the working copy tracks, in sorted order.
+    ///
+    /// These are the files on the current branch, except that files can be added (see
+    /// [`Repo::track_file`]), removed (see [`Repo::untrack_file`]) and moved (see
+    /// [`Repo::move_file`]) before those changes are recorded (see [`Repo::record`]). The
+    /// branch's own lines (see [`DEFAULT_WORKING_FILE`]) aren't included.
+    pub fn tracked_files(&self) -> Result<Vec<String>, Error> {
+        Ok(tracked::tracked(self)?.into_keys().collect())
+    }
+
+    /// Starts tracking the file at `path` (relative to [`Repo::root_dir`]), which must exist.
+    ///
+    /// This fails with [`Error::AlreadyTracked`] if the file is already tracked (and
+    /// [`DEFAULT_WORKING_FILE`] always is), and with [`Error::IgnoredPath`] if it is ignored (see
+    /// [`Repo::is_ignored`]).
+    pub fn track_file(&mut self, path: &str) -> Result<(), Error> {
+        self.check_writable()?;
+        tracked::add(self, path)
+    }
+
+    /// Stops tracking the file at `path`. The file stays in the working copy, but the next
+    /// recording deletes it from the branch.
+    pub fn untrack_file(&mut self, path: &str) -> Result<(), Error> {
+        self.check_writable()?;
+        tracked::remove(self, path)
+    }
+
+    /// Moves a tracked file, both in the working copy (if it is there) and among the tracked
+    /// files.
+    ///
+    /// Unlike untracking the file and tracking it under another name, this keeps the identity of
+    /// the file (see [`Change::MoveFile`]).
+    pub fn move_file(&mut self, from: &str, to: &str) -> Result<(), Error> {
+        self.check_writable()?;
+        tracked::move_file(self, from, to)
+    }
+
+    /// Returns the tracked files that were moved (see [`Repo::move_file`]) but not recorded yet,
+    /// as pairs of their new paths and their paths on the current branch.
+    pub fn moved_files(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.storage.tracked.moved()
+    }
+
+    /// Makes `branch` the current branch, and updates the working copy to match it.
+    ///
+    /// The files on the old branch that aren't on the new one are deleted, and the files on the
+    /// new branch are written out (with conflict markers, if necessary; see [`Repo::render`]).
+    /// If that would throw away anything that hasn't been recorded (changes to tracked files,
+    /// tracked files that were added, removed or moved, or untracked files that are in the
+    /// way), this fails with [`Error::UnrecordedChanges`] and doesn't touch anything. Files that
+    /// already have the right contents aren't written again.
+    pub fn checkout(&mut self, branch: &str) -> Result<(), Error> {
+        self.check_writable()?;
+        tracked::checkout(self, branch)
+    }
+
+    /// Updates the working copy after patches were applied to the current branch (or unapplied
+    /// from it), and returns the paths that were written or removed, in sorted order.
+    ///
+    /// Only the files whose contents on the branch changed are touched: the repository remembers
+    /// the contents that it last wrote to (or recorded from) each file, and the files whose
+    /// contents on the branch are still the same are skipped without even being read. The files
+    /// that changed are rewritten and the ones that left the branch are removed, but only if they
+    /// still have the contents that the repository remembers; if one of them has unrecorded
+    /// changes, this fails with [`Error::UnrecordedChanges`] and doesn't touch anything.
+    pub fn update_working_copy(&mut self) -> Result<Vec<String>, Error> {
+        self.check_writable()?;
+        tracked::update(self)
+    }
+
+    /// Compares the working copy with the current branch.
+    ///
+    /// This returns the status of every file that is either on the branch or in the working copy,
+    /// indexed by its path relative to [`Repo::root_dir`]. The branch's own lines (the ones
+    /// returned by [`Repo::file`]) are compared with [`DEFAULT_WORKING_FILE`]. Files that are
+    /// ignored (see [`Repo::is_ignored`]) are only included if they are on the branch.
+    pub fn status(&self) -> Result<BTreeMap<String, FileStatus>, Error> {
+        status::status(self)
+    }
+
+    /// Returns the paths of the files in the working copy that aren't on the current branch and
+    /// aren't ignored (see [`Repo::is_ignored`]), in sorted order.
+    pub fn untracked_files(&self) -> Result<Vec<String>, Error> {
+        status::untracked_files(self)
+    }
+
+    /// Is the file at `path` (relative to [`Repo::root_dir`]) ignored?
+    ///
+    /// The patterns of files to ignore come from the [`IGNORE_FILE`]s in the working copy, which
+    /// have the same format as gitignore files and apply to their own directory and everything
+    /// below it, and from [`Config::ignore`], which applies everywhere. Ignored files aren't
+    /// reported by [`Repo::status`] unless they are on the branch, and they can't be recorded
+    /// (see [`Repo::record`]). The directory where ojo keeps its data is always ignored.
+    pub fn is_ignored(&self, path: &str) -> Result<bool, Error> {
+        ignore::is_ignored(self, path)
+    }
+
+    /// Throws away changes in the working copy, by rewriting the file at `path` (relative to
+    /// [`Repo::root_dir`]) to match the same file on a branch (or the lines of the branch itself,
+    /// if `path` is [`DEFAULT_WORKING_FILE`]). This fails with [`Error::UnknownFile`] if the
+    /// branch has no file at `path`.
+    ///
+    /// With [`RevertScope::All`], the file is replaced by the branch's version, with conflict
+    /// markers if its lines aren't ordered (see [`Repo::render_file`]). In that case, the file might already
+    /// contain conflict markers that someone is in the middle of resolving: if it does and it
+    /// differs from the branch, this fails with [`Error::EditedConflict`] instead of throwing their
+    /// work away.
+    ///
+    /// With [`RevertScope::Hunks`], only the changes in the given hunks of the working diff (see
+    /// [`Repo::working_diff`]) are thrown away. This only works if the file's lines are ordered.
+    pub fn revert(&self, branch: &str, path: &str, scope: &RevertScope) -> Result<(), Error> {
+        self.check_writable()?;
+        revert::revert(self, branch, path, scope)
+    }
+
+    /// Starts bisecting a branch: searching for the patch that made it bad, by checking out some
+    /// of its patches at a time and being told whether they are good or bad.
+    ///
+    /// The patches in `good` (and the patches that they depend on) are known to be good, and the
+    /// branch as a whole is known to be bad; if `good` is empty, the branch without any patches is
+    /// taken to be good. Every step checks out (on a scratch branch that becomes the current
+    /// branch) the good patches along with about half of the rest, chosen so that all of their
+    /// dependencies are there too. Then [`Repo::bisect_good`] or [`Repo::bisect_bad`] says what
+    /// the outcome was, until the culprit is found. [`Repo::bisect_reset`] goes back to the branch
+    /// that was checked out before.
+    ///
+    /// The bisection is stored along with the rest of the repository, so it can be continued
+    /// after the repository is written and opened again. Since this checks out different
+    /// patches, it fails with [`Error::UnrecordedChanges`] if that would throw anything away (see
+    /// [`Repo::checkout`]). If all the patches on the branch are good, it fails with
+    /// [`Error::NothingToBisect`].
+    pub fn bisect_start(&mut self, branch: &str, good: &[PatchId]) -> Result<BisectStep, Error> {
+        self.check_writable()?;
+        bisect::start(self, branch, good)
+    }
+
+    /// Marks the patches that are checked out in the current bisection (see
+    /// [`Repo::bisect_start`]) as good, and checks out the next ones to test.
+    ///
+    /// Once the culprit has been found, this doesn't do anything more.
+    pub fn bisect_good(&mut self) -> Result<BisectStep, Error> {
+        self.check_writable()?;
+        bisect::mark(self, true)
+    }
+
+    /// Marks the patches that are checked out in the current bisection (see
+    /// [`Repo::bisect_start`]) as bad, and checks out the next ones to test.
+    ///
+    /// Once the culprit has been found, this doesn't do anything more.
+    pub fn bisect_bad(&mut self) -> Result<BisectStep, Error> {
+        self.check_writable()?;
+        bisect::mark(self, false)
+    }
+
+    /// Ends the current bisection (see [`Repo::bisect_start`]), and checks out the branch that
+    /// was checked out before it started.
+    pub fn bisect_reset(&mut self) -> Result<(), Error> {
+        self.check_writable()?;
+        bisect::reset(self)
+    }
+
+    /// Returns the branch that is being bisected, if there is a bisection in progress (see
+    /// [`Repo::bisect_start`]).
+    pub fn bisecting(&self) -> Option<&str> {
+        bisect::branch(self)
+    }
+
+    /// Returns all the conflicts on a branch (see [`Graggle::conflicts`]), along with the patches
+    /// that caused each of them.
+    ///
+    /// The conflicts are indexed by the paths of the files that have them, and the conflicts in
+    /// the branch's own lines are at [`DEFAULT_WORKING_FILE`]. Files without conflicts aren't
+    /// included, so this is empty if the branch has no conflicts.
+    pub fn conflicts(&self, branch: &str) -> Result<BTreeMap<String, Vec<BranchConflict>>, Error> {
+        conflict::branch_conflicts(self, branch)
+    }
+
+    /// Resolves a conflict on a branch (see [`Graggle::conflicts`]) by putting its lines in the
+    /// given order.
+    ///
+    /// `order` lists the lines of the conflict that should survive, in the order that they should
+    /// appear between [`Conflict::before`] and [`Conflict::after`]; the conflict's other lines
+    /// are deleted. The lines of different alternatives can be interleaved in any way, but the
+    /// order can't contradict the order that the branch already puts some lines in: if it does,
+    /// this fails with [`Error::OrderConflict`]. (In particular, if some lines are in a cycle,
+    /// all but one of them need to be deleted.) If one of the lines isn't part of the conflict,
+    /// this fails with [`Error::NotInConflict`].
+    ///
+    /// This creates a resolution patch (see [`Repo::create_resolution_patch`]), applies it to the
+    /// branch, and returns its id.
+    pub fn resolve_conflict(
+        &mut self,
+        branch: &str,
+        author: &str,
+        msg: &str,
+        conflict: &Conflict,
+        order: &[NodeId],
+    ) -> Result<PatchId, Error> {
+        let changes = conflict::resolution_changes(self.graggle(branch)?, conflict, order)?;
+        let id = self.create_resolution_patch(author, msg, changes)?;
+        self.apply_patch(branch, &id)?;
+        Ok(id)
+    }
+
+    /// Like [`Repo::resolve_conflict`], but for a conflict in the file at `path` on a branch
+    /// (see [`Repo::file_graggle`]).
+    pub fn resolve_file_conflict(
+        &mut self,
+        branch: &str,
+        path: &str,
+        author: &str,
+        msg: &str,
+        conflict: &Conflict,
+        order: &[NodeId],
+    ) -> Result<PatchId, Error> {
+        let file_changes =
+            conflict::resolution_changes(self.file_graggle(branch, path)?, conflict, order)?;
+        let mut changes = Changes { changes: vec![] };
+        changes.add_file_changes(self.file_id(branch, path)?, file_changes);
+        let id = self.create_resolution_patch(author, msg, changes)?;
+        self.apply_patch(branch, &id)?;
+        Ok(id)
+    }
+
+    /// Records a patch that undoes the patch `patch_id` on a branch, and applies it to the branch.
+    ///
+    /// Unlike [`Repo::unapply_patch`], this leaves the patch on the branch, along with everything
+    /// that depends on it: the undoing is a new patch, which can be pushed and pulled like any
+    /// other. That makes it the way to take back a patch that has already been published. The
+    /// lines that the patch added are deleted, the lines that it deleted are added again (as new
+    /// lines with the same contents), and the files that it created, deleted, moved or changed
+    /// the permissions of are put back. Changes that later patches have already overridden (for
+    /// example, lines that the patch added and that a later patch deleted) are left alone. The
+    /// working copy isn't touched.
+    ///
+    /// The new patch has the given author and message, and its header records the patch that it
+    /// rolls back (under the key `rollback` in [`PatchHeader::extra`]). Returns the id of the new
+    /// patch. If the patch isn't on the branch, this fails with [`Error::NotOnBranch`], and if
+    /// there is nothing left to undo, it fails with [`Error::NoChanges`].
+    pub fn rollback(
+        &mut self,
+        branch: &str,
+        patch_id: &PatchId,
+        author: &str,
+        msg: &str,
+    ) -> Result<PatchId, Error> {
+        rollback::rollback(self, branch, patch_id, author, msg)
+    }
+
+    /// Imports the history of a git repository, from the output of `git fast-export`.
+    ///
+    /// Every commit that changes something becomes a patch, with the commit's author, date and
+    /// message. The patches' dependencies come from their changes, as usual, so a patch only
+    /// depends on the earlier ones that it needs. Each branch in the stream (`refs/heads/NAME`)
+    /// ends up with the patches of its last commit, and is created if it doesn't exist; each tag
+    /// (`refs/tags/NAME`) becomes a tag (see [`Repo::create_tag`]). The working copy isn't
+    /// touched.
+    ///
+    /// If the stream has the ids of the commits (which `git fast-export --show-original-ids`
+    /// gives it), they are saved in the patches' headers, under [`GIT_COMMIT_KEY`] in
+    /// [`PatchHeader::extra`], and remembered (see [`Repo::git_patch`]). Nothing is imported
+    /// unless everything is.
+    pub fn import_git<R: BufRead>(&mut self, stream: R) -> Result<GitImport, Error> {
+        self.check_writable()?;
+        git_import::import(self, stream)
+    }
+
+    /// Writes the history of a branch to `out`, as a stream that `git fast-import` can read.
+    ///
+    /// Git history is a sequence of snapshots, so the patches on the branch are put in an order
+    /// in which they can be applied, with older patches first where there's a choice, and each
+    /// of them becomes a commit on `refs/heads/BRANCH`, with the files that the branch would have
+    /// with just that patch and the ones before it. Files that would have conflicts are written
+    /// with conflict markers. The patches are returned in the order of their commits, so the
+    /// commit for the `i`th one has the mark `:i+1` in the stream.
+    ///
+    /// This needs a scratch branch while it's working, but the repository doesn't change, and it
+    /// works even if the repository was opened with [`Repo::open_readonly`].
+    pub fn export_git<W: Write>(&mut self, branch: &str, out: W) -> Result<Vec<PatchId>, Error> {
+        git_export::export_branch(self, branch, out)
+    }
+
+    /// Brings the branches up to date with a git repository, from the output of `git fast-export
+    /// --show-original-ids`.
+    ///
+    /// This is like [`Repo::import_git`], except that it can be done over and over, as the git
+    /// repository changes. The commits that were synced before (by this or by
+    /// [`Repo::record_git_marks`]) are skipped, so the stream can be incremental: the commits
+    /// that it refers to without having them (as `git fast-export --reference-excluded-parents
+    /// ^OLD_TIP` does) only need to have been synced. Each branch remembers the commit that it
+    /// was synced with (see [`Repo::git_tip`]), and only moves forward from it: if the new commit
+    /// isn't descended from it, the git history was rewritten, and this fails with
+    /// [`Error::GitDiverged`]. The returned branches are the ones that moved. Nothing changes
+    /// unless everything is pulled.
+    pub fn pull_git<R: BufRead>(&mut self, stream: R) -> Result<GitImport, Error> {
+        self.check_writable()?;
+        git_import::pull(self, stream)
+    }
+
+    /// Writes the patches on a branch that haven't been synced with git to `out`, as commits on
+    /// top of the one that the branch was last synced with (see [`Repo::git_tip`]), in a stream
+    /// that `git fast-import` can read.
+    ///
+    /// The commits are made in the same way as by [`Repo::export_git`], and the patches are
+    /// returned in the order of their commits. Once `git fast-import --export-marks` has made
+    /// them, the marks should be passed to [`Repo::record_git_marks`], so that the patches count
+    /// as synced. If some patches that were synced have been taken off of the branch, the git
+    /// branch would need to be rewritten, so this fails with [`Error::GitDiverged`].
+    pub fn push_git<W: Write>(&mut self, branch: &str, out: W) -> Result<Vec<PatchId>, Error> {
+        git_mirror::push_branch(self, branch, out)
+    }
+
+    /// Records the commits that `git fast-import` made from the stream of [`Repo::push_git`]:
+    /// `patches` are the patches that it returned, and `marks` is the file that `git fast-import
+    /// --export-marks` wrote. The last commit becomes the branch's [`Repo::git_tip`].
+    pub fn record_git_marks<R: BufRead>(
+        &mut self,
+        branch: &str,
+        patches: &[PatchId],
+        marks: R,
+    ) -> Result<(), Error> {
+        self.check_writable()?;
+        git_mirror::record_marks(self, branch, patches, marks)
+    }
+
+    /// Returns the id of the git commit that a branch was last synced with (see
+    /// [`Repo::pull_git`] and [`Repo::push_git`]), if it has been synced.
+    pub fn git_tip(&self, branch: &str) -> Option<&str> {
+        self.storage.git_mirror.tips.get(branch).map(|t| t.as_str())
+    }
+
+    /// Reads a unified diff (like the ones that `diff -u`, `git diff` and `git format-patch`
+    /// write, or [`Patch::to_unified_diff`]), and records the changes that it makes to the files
+    /// on `branch` as a new patch, which is applied to `branch`.
+    ///
+    /// The hunks of the diff are found in the files even if they have moved, and even if a couple
+    /// of the lines of context at either end of a hunk don't match any more; if a hunk can't be
+    /// found, this fails with [`Error::HunkFailed`]. Diffs written by `git diff` can also create,
+    /// delete and rename files, and change their modes. The lines of the branch itself are
+    /// changed by diffing [`DEFAULT_WORKING_FILE`], unless a file with that name is tracked.
+    ///
+    /// The patch gets the metadata in `header`, except that if the diff was written by
+    /// `git format-patch`, its author, date and message come from the email headers. The working
+    /// copy isn't touched.
+    pub fn apply_unified_diff<R: BufRead>(
+        &mut self,
+        branch: &str,
+        header: PatchHeader,
+        diff: R,
+    ) -> Result<PatchId, Error> {
+        self.check_writable()?;
+        diff_import::apply(self, branch, header, diff)
+    }
 
+    /// Reads darcs patches (like the bundles that `darcs send` writes), and turns each of them
+    /// into a patch, which is applied to `branch`.
+    ///
+    /// The patches keep their authors, dates and descriptions, and are made in the order that
+    /// they come in. Their changes are carried out on the files of the branch, so the branch
+    /// should have the same files as the context of the bundle. Darcs patches that don't change
+    /// any files (like the ones that only add directories) are skipped, so the returned patches
+    /// are the ones that were made. Nothing is imported unless everything is.
+    pub fn import_darcs<R: BufRead>(
+        &mut self,
+        branch: &str,
+        input: R,
+    ) -> Result<Vec<PatchId>, Error> {
+        self.check_writable()?;
+        darcs_import::import(self, branch, input)
+    }
+
+    /// Reads pijul changes, in the text format that `pijul change` prints them in, and turns each
+    /// of them into a patch, which is applied to `branch`.
+    ///
+    /// Several changes can be given one after the other, oldest first (so the history of a pijul
+    /// channel can be converted by printing each of the changes in `pijul log`, in reverse). The
+    /// patches keep their messages, their times, and their first authors. Like with
+    /// [`Repo::import_darcs`], the changes are replayed on the files of the branch, so the branch
+    /// should have the files that the first change was made on. Changes that only affect
+    /// directories are skipped, and changes that resolve pijul's conflicts aren't supported.
+    /// Nothing is imported unless everything is.
+    pub fn import_pijul<R: BufRead>(
+        &mut self,
+        branch: &str,
+        input: R,
+    ) -> Result<Vec<PatchId>, Error> {
+        self.check_writable()?;
+        pijul_import::import(self, branch, input)
+    }
+
+    /// Reads mercurial changesets, in the format that `hg export` writes them in, and turns each
+    /// of them into a patch, which is applied to `branch`.
+    ///
+    /// Several changesets can be given one after the other, oldest first (as `hg export -r 0:tip`
+    /// writes them). The patches keep their authors, dates and messages, and the ids of their
+    /// changesets are kept in their extra metadata, under [`HG_NODE_KEY`]. Each changeset's diff
+    /// is applied to the files of the branch like in [`Repo::apply_unified_diff`], so the branch
+    /// should have the files that the first changeset was made on; `hg export --git` is needed
+    /// for renames and executable files to come through. Since merge changesets are diffs against
+    /// only one of their parents, they aren't supported. Changesets that don't change any files
+    /// are skipped, and nothing is imported unless everything is.
+    pub fn import_hg<R: BufRead>(&mut self, branch: &str, input: R) -> Result<Vec<PatchId>, Error> {
+        self.check_writable()?;
+        hg_import::import(self, branch, input)
+    }
+
+    /// Imports the history of a subversion repository, from the output of `svnadmin dump`.
+    ///
+    /// If the repository has the usual layout, `trunk` becomes the "master" branch, each
+    /// `branches/NAME` becomes the branch `NAME` (which is created if it doesn't exist), and each
+    /// `tags/NAME` becomes a tag (see [`Repo::create_tag`]); anything else outside of those
+    /// directories is skipped. Otherwise, the whole repository goes on "master". Every revision
+    /// becomes a patch for each of the branches that it changes, with the revision's author, date
+    /// and message, and with the revision number in [`PatchHeader::extra`], under
+    /// [`SVN_REVISION_KEY`]. A branch or tag that was copied from another one starts out with its
+    /// patches, so they share their history. The dump can't have deltas (`svnadmin dump
+    /// --deltas`). The working copy isn't touched, and nothing is imported unless everything is.
+    pub fn import_svn<R: BufRead>(&mut self, input: R) -> Result<SvnImport, Error> {
+        self.check_writable()?;
+        svn_import::import(self, input)
+    }
+
+    /// Returns the patch that was imported from the git commit with id `commit` (see
+    /// [`Repo::import_git`]), if there is one.
+    pub fn git_patch(&self, commit: &str) -> Option<PatchId> {
+        self.storage.git_commits.get(commit).cloned()
+    }
+
+    /// Returns the id of the git commit that a patch was imported from or exported to (see
+    /// [`Repo::import_git`] and [`Repo::record_git_marks`]), if there is one.
+    pub fn git_commit(&self, patch: &PatchId) -> Option<String> {
+        self.storage
+            .git_commits
+            .iter()
+            .find(|(_, p)| *p == patch)
+            .map(|(c, _)| c.clone())
+    }
+
+    fn create_unidentified_patch(&mut self, patch: UnidentifiedPatch) -> Result<PatchId, Error> {
         // Serialize the patch to a buffer, and get back the identified patch.
         let mut patch_data = Vec::new();
         let patch = patch.write_out(&mut patch_data)?;
@@ -517,6 +2330,433 @@ impl Repo {
         Ok(*patch.id())
     }
 
+    /// Marks a patch, and everything that it depends on, as published.
+    ///
+    /// Published patches might already be in someone else's repository, so they can't be
+    /// rewritten (for example, by [`Repo::squash_patches`]). Patches should be marked as published
+    /// when they are sent somewhere else; patches that were received from elsewhere (for example,
+    /// in a [`Bundle`]) are published already.
+    pub fn mark_published(&mut self, id: &PatchId) -> Result<(), Error> {
+        self.check_writable()?;
+        if !self.storage.patches.contains_key(id) && !self.is_ghost(id) {
+            return Err(Error::UnknownPatch(*id));
+        }
+        let mut stack = vec![*id];
+        while let Some(p) = stack.pop() {
+            if self.storage.published.insert(p) {
+                stack.extend(self.storage.patch_deps.get(&p).cloned());
+            }
+        }
+        Ok(())
+    }
+
+    /// Has this patch been published (see [`Repo::mark_published`])?
+    pub fn is_published(&self, id: &PatchId) -> bool {
+        self.storage.published.contains(id)
+    }
+
+    /// Forgets about the patches that aren't needed any more, and returns them in sorted order.
+    ///
+    /// A patch is needed if it is on some branch, if it is a tag (see [`Repo::create_tag`]) or a
+    /// stash (see [`Repo::stash_push`]), if it was created less than `grace_period` ago, or if
+    /// some needed patch depends on it. The
+    /// others are removed along with everything that the repository knows about them, unless
+    /// `dry_run` is set (in which case this only says what would be removed). In a repository
+    /// that was opened with [`Repo::open_readonly`], this is always a dry run.
+    pub fn gc(&mut self, grace_period: std::time::Duration, dry_run: bool) -> Vec<PatchId> {
+        // If the grace period is too long to represent, then every patch is in it.
+        let cutoff = chrono::Duration::from_std(grace_period)
+            .ok()
+            .and_then(|d| chrono::Utc::now().checked_sub_signed(d));
+        gc::gc(self, cutoff, dry_run || self.readonly)
+    }
+
+    /// Returns all of the published patches, in sorted order.
+    pub fn published_patches(&self) -> impl Iterator<Item = &PatchId> {
+        self.storage.published.iter()
+    }
+
+    /// Fails with [`Error::PublishedPatch`] if any of these patches is published. Anything that
+    /// rewrites patches should check this first.
+    pub fn check_unpublished<'a, I>(&self, ids: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = &'a PatchId>,
+    {
+        match ids.into_iter().find(|p| self.is_published(p)) {
+            Some(p) => Err(Error::PublishedPatch(*p)),
+            None => Ok(()),
+        }
+    }
+
+    /// Squashes some patches into a single patch with the given metadata (see [`Patch::compose`]
+    /// for the requirements on `ids`), and marks the original patches as obsoleted by it.
+    ///
+    /// This doesn't change any branches; use [`Repo::migrate_obsolete`] to replace the original
+    /// patches by the new one. Fails with [`Error::PublishedPatch`] if any of the original patches
+    /// has been published.
+    pub fn squash_patches(
+        &mut self,
+        ids: &[PatchId],
+        header: PatchHeader,
+    ) -> Result<PatchId, Error> {
+        self.check_writable()?;
+        self.check_unpublished(ids)?;
+        let patches = ids
+            .iter()
+            .map(|id| self.open_patch(id))
+            .collect::<Result<Vec<_>, Error>>()?;
+        let new_id = self.create_unidentified_patch(Patch::compose(&patches, header)?)?;
+        for id in ids {
+            self.mark_obsolete(ObsoleteMarker {
+                obsolete: *id,
+                successor: new_id,
+            })?;
+        }
+        Ok(new_id)
+    }
+
+    /// Replaces a patch on a branch by a new version of it, and marks the original as obsoleted by
+    /// the new one. Returns the id of the new patch.
+    ///
+    /// The new patch makes the original patch's changes followed by `extra_changes`, which are
+    /// relative to the branch (for example, they could come from [`Repo::diff`]). It gets the
+    /// metadata in `header`, or the original metadata if that is `None`. If there are no extra
+    /// changes and no new metadata, this fails with [`Error::NoChanges`].
+    ///
+    /// Only the most recent patches can be amended: this fails with [`Error::HasDependents`] if
+    /// other patches on the branch depend on the patch, with [`Error::NotOnBranch`] if it isn't on
+    /// the branch, and with [`Error::PublishedPatch`] if it has been published. In all of these
+    /// cases, nothing changes.
+    pub fn amend(
+        &mut self,
+        branch: &str,
+        id: &PatchId,
+        extra_changes: Changes,
+        header: Option<PatchHeader>,
+    ) -> Result<PatchId, Error> {
+        self.check_writable()?;
+        self.check_unpublished(Some(id))?;
+        if !self.storage.branch_patches.contains(branch, id) {
+            return Err(Error::NotOnBranch(*id, branch.to_owned()));
+        }
+        let to_unapply = self.patch_graph().unapply_order(branch, id);
+        if to_unapply.len() > 1 {
+            let dependents = to_unapply[..(to_unapply.len() - 1)].to_vec();
+            return Err(Error::HasDependents(*id, dependents));
+        }
+        if extra_changes.changes.is_empty() && header.is_none() {
+            return Err(Error::NoChanges);
+        }
+
+        let original = self.open_patch(id)?;
+        let header = header.unwrap_or_else(|| original.header().clone());
+        let mut patches = vec![original];
+        if !extra_changes.changes.is_empty() {
+            // The extra changes need an id in order to be composed, but they're never stored.
+            let extra = UnidentifiedPatch::with_header(header.clone(), extra_changes);
+            patches.push(extra.write_out(std::io::sink())?);
+        }
+        let new_id = self.create_unidentified_patch(Patch::compose(&patches, header)?)?;
+        if new_id == *id {
+            return Err(Error::NoChanges);
+        }
+
+        self.unapply(branch, id, UnapplyPolicy::Refuse)?;
+        if let Err(e) = self.apply_without_hooks(branch, &new_id, ApplyPolicy::Refuse) {
+            self.apply_without_hooks(branch, id, ApplyPolicy::Refuse)?;
+            return Err(e);
+        }
+        self.mark_obsolete(ObsoleteMarker {
+            obsolete: *id,
+            successor: new_id,
+        })?;
+        Ok(new_id)
+    }
+
+    /// Records that one patch supersedes another (see [`ObsoleteMarker`]).
+    ///
+    /// The successor must be known to this repository, but the obsolete patch doesn't need to be.
+    /// The successor can't depend on the obsolete patch, and the markers can't form a cycle.
+    pub fn mark_obsolete(&mut self, marker: ObsoleteMarker) -> Result<(), Error> {
+        self.check_writable()?;
+        let ObsoleteMarker {
+            obsolete,
+            successor,
+        } = marker;
+        if !self.storage.patches.contains_key(&successor) {
+            return Err(Error::UnknownPatch(successor));
+        }
+        let mut stack = vec![successor];
+        let mut seen = HashSet::new();
+        while let Some(p) = stack.pop() {
+            if p == obsolete {
+                return Err(Error::InvalidObsoleteMarker(obsolete, successor));
+            }
+            if seen.insert(p) {
+                stack.extend(self.storage.successors.get(&p).cloned());
+            }
+        }
+        if self
+            .patch_graph()
+            .transitive_deps(&successor)
+            .contains(&obsolete)
+        {
+            return Err(Error::InvalidObsoleteMarker(obsolete, successor));
+        }
+
+        self.storage.successors.insert(obsolete, successor);
+        Ok(())
+    }
+
+    /// Has this patch been made obsolete by some other patch?
+    pub fn is_obsolete(&self, id: &PatchId) -> bool {
+        self.successors(id).next().is_some()
+    }
+
+    /// Returns the patches that directly supersede this one.
+    pub fn successors(&self, id: &PatchId) -> impl Iterator<Item = &PatchId> {
+        self.storage.successors.get(id)
+    }
+
+    /// Returns all of the obsolescence markers that this repository knows about.
+    pub fn obsolete_markers(&self) -> impl Iterator<Item = ObsoleteMarker> + '_ {
+        self.storage
+            .successors
+            .iter()
+            .map(|(obsolete, successor)| ObsoleteMarker {
+                obsolete: *obsolete,
+                successor: *successor,
+            })
+    }
+
+    // Follows the markers to find the patches that supersede this one, and that aren't obsolete
+    // themselves.
+    fn latest_successors(&self, id: &PatchId) -> BTreeSet<PatchId> {
+        let mut ret = BTreeSet::new();
+        let mut stack = self.successors(id).cloned().collect::<Vec<_>>();
+        while let Some(p) = stack.pop() {
+            if self.is_obsolete(&p) {
+                stack.extend(self.successors(&p).cloned());
+            } else {
+                ret.insert(p);
+            }
+        }
+        ret
+    }
+
+    /// Replaces all of the obsolete patches on a branch by the patches that supersede them.
+    ///
+    /// If a patch has been superseded several times, it is replaced by the latest patches only.
+    /// This fails with [`Error::HasDependents`] (without changing the branch) if some patch that
+    /// isn't obsolete depends on an obsolete one, because that patch would need to be removed too.
+    ///
+    /// Returns a marker for every replacement that was made, from the obsolete patch to one of its
+    /// latest successors.
+    pub fn migrate_obsolete(&mut self, branch: &str) -> Result<Vec<ObsoleteMarker>, Error> {
+        self.check_writable()?;
+        let obsolete = self
+            .patches(branch)
+            .filter(|p| self.is_obsolete(p))
+            .cloned()
+            .collect::<BTreeSet<_>>();
+        for p in &obsolete {
+            let needed_by = self
+                .patch_graph()
+                .unapply_order(branch, p)
+                .into_iter()
+                .filter(|q| !obsolete.contains(q))
+                .collect::<Vec<_>>();
+            if !needed_by.is_empty() {
+                return Err(Error::HasDependents(*p, needed_by));
+            }
+        }
+
+        let mut ret = Vec::new();
+        for p in &obsolete {
+            ret.extend(
+                self.latest_successors(p)
+                    .into_iter()
+                    .map(|successor| ObsoleteMarker {
+                        obsolete: *p,
+                        successor,
+                    }),
+            );
+        }
+        for p in &obsolete {
+            self.unapply_patch(branch, p)?;
+        }
+        for m in &ret {
+            self.apply_patch(branch, &m.successor)?;
+        }
+        Ok(ret)
+    }
+
+    /// Applies some of the patches on one branch to another branch.
+    ///
+    /// The patches on `into` need to contain all of the dependencies of the patches in `ids`.
+    /// Depending on `policy`, the dependencies that are missing are either pulled too, or (where
+    /// possible) the selected patches are re-derived so that they don't need them. The
+    /// re-derived patches are new patches, with the same metadata as the original ones.
+    ///
+    /// Every patch in `ids` must be on `from`, or else this fails with [`Error::NotOnBranch`]. If
+    /// any patch fails to apply, `into` is left unchanged.
+    pub fn pull_patches(
+        &mut self,
+        from: &str,
+        into: &str,
+        ids: &[PatchId],
+        policy: PullPolicy,
+    ) -> Result<PullReport, Error> {
+        self.check_writable()?;
+        pull::pull(self, from, into, ids, policy)
+    }
+
+    /// Adds a remote: another repository, at `url`, that patches are exchanged with (see
+    /// [`remote::connect`] for the kinds of addresses). Its "master" branch corresponds to the
+    /// local one; this can be changed in [`Remote::branches`].
+    pub fn add_remote(&mut self, name: &str, url: &str) -> Result<(), Error> {
+        self.check_writable()?;
+        tracking::add(self, name, url)
+    }
+
+    /// Removes a remote, and forgets which patches it has. This doesn't delete its tracking
+    /// branches (see [`Repo::fetch`]).
+    pub fn remove_remote(&mut self, name: &str) -> Result<(), Error> {
+        self.check_writable()?;
+        tracking::remove(self, name)
+    }
+
+    /// Returns the remote with the given name.
+    ///
+    /// The repository's own remotes take precedence over the ones in the system-wide and user
+    /// settings (see [`Repo::config`]).
+    pub fn remote(&self, name: &str) -> Result<&Remote, Error> {
+        self.config
+            .remote(name)
+            .or_else(|| self.global_config.remote(name))
+            .ok_or_else(|| Error::UnknownRemote(name.to_owned()))
+    }
+
+    /// Returns all the patches that a remote is known to have, because they were fetched from it
+    /// or pushed to it.
+    pub fn remote_patches(&self, name: &str) -> impl Iterator<Item = &PatchId> {
+        self.storage.remote_patches.get(name)
+    }
+
+    /// Downloads the patches on the branches of a remote (see [`Remote::branches`]).
+    ///
+    /// Each branch of the remote has a tracking branch, called `<remote>/<branch>`, which is
+    /// created if necessary and made to contain exactly the patches on the remote branch. Returns
+    /// what was transferred to each tracking branch, indexed by the remote branch.
+    pub fn fetch(&mut self, remote: &str) -> Result<BTreeMap<String, Transfer>, Error> {
+        self.check_writable()?;
+        let mut conn = remote::connect(&self.remote(remote)?.url)?;
+        let ret = tracking::fetch(self, remote, &mut conn)?;
+        conn.close()?;
+        Ok(ret)
+    }
+
+    /// Like [`Repo::fetch`], but uses an existing connection to the remote.
+    pub fn fetch_with(
+        &mut self,
+        remote: &str,
+        conn: &mut Connection,
+    ) -> Result<BTreeMap<String, Transfer>, Error> {
+        self.check_writable()?;
+        tracking::fetch(self, remote, conn)
+    }
+
+    /// Sends the patches on `branch` to the corresponding branch of a remote (see
+    /// [`Remote::remote_branch`]), and applies them there.
+    ///
+    /// The patches that the remote is known to have (see [`Repo::remote_patches`]) aren't
+    /// negotiated, so if it has lost some of them since then, the push fails.
+    pub fn push(&mut self, remote: &str, branch: &str) -> Result<Transfer, Error> {
+        self.check_writable()?;
+        let mut conn = remote::connect(&self.remote(remote)?.url)?;
+        let ret = tracking::push(self, remote, branch, &mut conn)?;
+        conn.close()?;
+        Ok(ret)
+    }
+
+    /// Like [`Repo::push`], but uses an existing connection to the remote.
+    pub fn push_with(
+        &mut self,
+        remote: &str,
+        branch: &str,
+        conn: &mut Connection,
+    ) -> Result<Transfer, Error> {
+        self.check_writable()?;
+        tracking::push(self, remote, branch, conn)
+    }
+
+    /// Re-derives a patch against a branch that might not contain all of its dependencies.
+    ///
+    /// If the branch contains all of the patch's dependencies, the patch can be applied to it as
+    /// it is, and the result just contains its changes. Otherwise, every line that the patch
+    /// refers to but that isn't on the branch is replaced, either by a line on the branch with the
+    /// same contents, or by the closest line (in the context that the patch was created in) that
+    /// is on the branch. The result lists every such guess (see [`RebaseGuess`]).
+    ///
+    /// The re-derived changes can be made into a new patch, which can then be marked as
+    /// superseding the original one (see [`Repo::mark_obsolete`]).
+    pub fn rebase_patch(&self, branch: &str, patch_id: &PatchId) -> Result<Rebase, Error> {
+        rebase::rebase(self, branch, patch_id)
+    }
+
+    /// Creates a tag (see [`Tag`]) containing all of the patches currently in a branch.
+    ///
+    /// Returns the id of the patch representing the tag.
+    pub fn create_tag(
+        &mut self,
+        branch: &str,
+        name: &str,
+        author: &str,
+        msg: &str,
+    ) -> Result<PatchId, Error> {
+        self.check_writable()?;
+        if self.tag(name).is_ok() {
+            return Err(Error::TagExists(name.to_owned()));
+        }
+
+        let mut header = PatchHeader::new(author.to_owned(), msg.to_owned());
+        header
+            .extra
+            .insert(tag::TAG_KEY.to_owned(), name.to_owned());
+        let mut patches = self.patches(branch).cloned().collect::<Vec<_>>();
+        patches.sort();
+        self.create_unidentified_patch(UnidentifiedPatch::with_deps(header, patches))
+    }
+
+    /// Returns all of the tags that this repository knows about.
+    pub fn tags(&self) -> Result<Vec<Tag>, Error> {
+        let mut ret = Vec::new();
+        for id in self.all_patches() {
+            if let Some(tag) = Tag::from_patch(&self.open_patch(id)?) {
+                ret.push(tag);
+            }
+        }
+        ret.sort_by(|a, b| a.name().cmp(b.name()));
+        Ok(ret)
+    }
+
+    /// Finds the tag with the given name.
+    pub fn tag(&self, name: &str) -> Result<Tag, Error> {
+        self.tags()?
+            .into_iter()
+            .find(|t| t.name() == name)
+            .ok_or_else(|| Error::UnknownTag(name.to_owned()))
+    }
+
+    // Fails if the repository was opened with `open_readonly`.
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.readonly {
+            Err(Error::ReadOnly(self.root_dir.clone()))
+        } else {
+            Ok(())
+        }
+    }
+
     fn try_create_dir(&self, dir: &Path) -> Result<(), Error> {
         if let Err(e) = std::fs::create_dir(dir) {
             // If the directory already exists, just swallow the error.
@@ -527,13 +2767,17 @@ impl Repo {
         Ok(())
     }
 
-    /// Returns an iterator over the names of all branches.
+    /// Returns an iterator over the names of all branches, in alphabetical order.
+    ///
+    /// The name of the branch that the working copy is based on is in [`Repo::current_branch`];
+    /// it can be changed with [`Repo::switch_branch`], and that branch can't be deleted.
     pub fn branches(&self) -> impl Iterator<Item = &str> {
         self.storage.branches()
     }
 
     /// Creates a new, empty branch.
     pub fn create_branch(&mut self, branch: &str) -> Result<(), Error> {
+        self.check_writable()?;
         if self.storage.inode(branch).is_some() {
             Err(Error::BranchExists(branch.to_owned()))
         } else {
@@ -545,6 +2789,7 @@ impl Repo {
 
     /// Copies data to a new branch (which must not already exist).
     pub fn clone_branch(&mut self, from: &str, to: &str) -> Result<(), Error> {
+        self.check_writable()?;
         if self.storage.inode(to).is_some() {
             Err(Error::BranchExists(to.to_owned()))
         } else {
@@ -572,6 +2817,7 @@ impl Repo {
 
     /// Deletes the branch named `branch`.
     pub fn delete_branch(&mut self, branch: &str) -> Result<(), Error> {
+        self.check_writable()?;
         if branch == self.current_branch {
             return Err(Error::CurrentBranch(branch.to_owned()));
         }
@@ -582,11 +2828,13 @@ impl Repo {
         self.storage.remove_graggle(inode);
         self.storage.remove_inode(branch);
         self.storage.branch_patches.remove_all(branch);
+        self.storage.git_mirror.tips.remove(branch);
         Ok(())
     }
 
     /// Changes the current branch to the one named `branch` (which must already exist).
     pub fn switch_branch(&mut self, branch: &str) -> Result<(), Error> {
+        self.check_writable()?;
         if self.storage.inode(branch).is_none() {
             Err(Error::UnknownBranch(branch.to_owned()))
         } else {
@@ -598,7 +2846,77 @@ impl Repo {
     /// If the given branch represents a totally ordered file (i.e. if [`Repo::file`] returns
     /// something), returns the result of diffing the given branch against `file`.
     pub fn diff(&self, branch: &str, file: &[u8]) -> Result<Diff, Error> {
-        let file_a = self.file(branch)?;
+        self.diff_with_options(branch, file, &DiffOptions::default())
+    }
+
+    /// Like [`Repo::diff`], but allows for choosing the diff algorithm.
+    pub fn diff_with_options(
+        &self,
+        branch: &str,
+        file: &[u8],
+        opts: &DiffOptions,
+    ) -> Result<Diff, Error> {
+        Ok(Diff::new(self.file(branch)?, file, opts))
+    }
+
+    /// Like [`Repo::diff`], but for the file at `path` on a branch (see [`Repo::file_at`]).
+    ///
+    /// To turn the result into a [`Change::EditFile`], use [`Changes::add_file_changes`] with the
+    /// file's identity (see [`Repo::file_id`]).
+    pub fn diff_file(&self, branch: &str, path: &str, file: &[u8]) -> Result<Diff, Error> {
+        Ok(Diff::new(
+            self.file_at(branch, path)?,
+            file,
+            &DiffOptions::default(),
+        ))
+    }
+
+    /// Compares the file at `path` in the working copy (i.e. relative to [`Repo::root_dir`]) with
+    /// the same file on a branch (see [`Repo::file_at`]), or with the lines of the branch itself
+    /// (see [`Repo::file`]) if `path` is [`DEFAULT_WORKING_FILE`].
+    ///
+    /// The result is divided into hunks, which refer to the unchanged lines around them by id.
+    /// The changes can be made into a patch all at once (see [`PendingChanges::changes`]) or a
+    /// few hunks at a time (see [`PendingChanges::select`]).
+    pub fn working_diff(
+        &self,
+        branch: &str,
+        path: &str,
+        opts: &DiffOptions,
+    ) -> Result<PendingChanges, Error> {
+        let contents = self.read_working_file(path)?;
+        let file = if path == DEFAULT_WORKING_FILE {
+            self.file(branch)?
+        } else {
+            self.file_at(branch, path)?
+        };
+        Ok(PendingChanges::new(Diff::new(file, &contents, opts)))
+    }
+
+    /// Like [`Repo::working_diff`], but compares with the file at `path` on a branch (see
+    /// [`Repo::file_at`]) instead of with the lines of the branch itself.
+    pub fn working_diff_file(
+        &self,
+        branch: &str,
+        path: &str,
+        opts: &DiffOptions,
+    ) -> Result<PendingChanges, Error> {
+        let contents = self.read_working_file(path)?;
+        Ok(PendingChanges::new(Diff::new(
+            self.file_at(branch, path)?,
+            &contents,
+            opts,
+        )))
+    }
+
+    fn read_working_file(&self, path: &str) -> Result<Vec<u8>, Error> {
+        text::read_working(self, path)
+            .map_err(|e| Error::Io(e, format!("Could not read the file {}", path)))
+    }
+}
+
+impl Diff {
+    fn new(file_a: File, file: &[u8], opts: &DiffOptions) -> Diff {
         let lines_a = (0..file_a.num_nodes())
             .map(|i| file_a.node(i))
             .collect::<Vec<_>>();
@@ -608,28 +2926,53 @@ impl Repo {
             .map(|i| file_b.node(i))
             .collect::<Vec<_>>();
 
-        let diff = ojo_diff::diff(&lines_a, &lines_b);
-        Ok(Diff {
+        let diff = ojo_diff::diff_with_options(&lines_a, &lines_b, opts);
+        Diff {
             diff,
             file_a,
             file_b,
-        })
+        }
     }
 }
 
+// Where the data of a repository goes, in a filesystem other than the native one.
+const VFS_REPO_DIR: &str = ".ojo";
+const VFS_DB_PATH: &str = ".ojo/db";
+
+// Puts the patches from the database into `storage`.
+fn load_patches(
+    storage: &mut storage::Storage,
+    patches: &mut compress::PatchStore,
+    encryption_key: Option<&EncryptionKey>,
+) -> Result<(), Error> {
+    if let Some(contents) = patches.decrypt(encryption_key)? {
+        storage.restore_contents(&contents)?;
+    }
+    // Repositories written by older versions of ojo have their patches stored uncompressed in
+    // `storage`, and so they might already be there.
+    storage.patches.extend(patches.decompress()?);
+    Ok(())
+}
+
 /// This struct, serialized, is the contents of the database.
 #[derive(Debug, Deserialize, Serialize)]
 struct Db {
     current_branch: String,
+    #[serde(default)]
+    config: Config,
     storage: storage::Storage,
+    #[serde(default)]
+    patches: compress::PatchStore,
 }
 
 // The auto-generated Serialize implementation here should be compatible with the auto-generated
-// Seserialize implementation for Db.
+// Seserialize implementation for Db. The settings aren't written here anymore, because they have
+// their own file (see `CONFIG_FILE`).
 #[derive(Debug, Serialize)]
 struct DbRef<'a> {
     current_branch: &'a str,
     storage: &'a storage::Storage,
+    patches: compress::PatchStore,
 }
 
 /// Represents a diff between two [`File`](crate::File)s.
@@ -642,3 +2985,52 @@ pub struct Diff {
     /// The diff going from `file_a` to `file_b`.
     pub diff: Vec<LineDiff>,
 }
+
+impl Diff {
+    /// Returns the changes that need to be made to the repository in order to turn `file_a` into
+    /// `file_b`.
+    ///
+    /// These changes can be made into a patch with [`UnidentifiedPatch::new`].
+    pub fn changes(&self) -> Changes {
+        Changes::from_diff(&self.file_a, &self.file_b, &self.diff)
+    }
+
+    /// Finds lines that were replaced by similar lines, and compares them word-by-word.
+    ///
+    /// This is only for presenting the diff (for example, by highlighting the words that
+    /// changed): the changes themselves are always in terms of whole lines.
+    pub fn changed_lines(&self) -> Vec<ChangedLine> {
+        ojo_diff::changed_line_pairs(&self.diff)
+            .into_iter()
+            .map(|(old_line, new_line)| ChangedLine {
+                old_line,
+                new_line,
+                words: ojo_diff::word_diff(self.file_a.node(old_line), self.file_b.node(new_line)),
+            })
+            .collect()
+    }
+}
+
+/// A line that was replaced by another line, together with a word-by-word comparison between
+/// them (see [`Diff::changed_lines`]).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ChangedLine {
+    /// The index of the line in the first file.
+    pub old_line: usize,
+    /// The index of the line in the second file.
+    pub new_line: usize,
+    /// The parts of the two lines that differ.
+    pub words: WordDiff,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Servers and language bindings hand repositories between threads.
+    #[test]
+    fn repo_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Repo>();
+    }
+}
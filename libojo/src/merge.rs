@@ -0,0 +1,250 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+use std::collections::BTreeMap;
+
+use crate::progress::Reporter;
+use crate::{ApplyPolicy, Conflict, Error, PatchId, Phase, Repo};
+
+// The name of the scratch branch that a dry run merges into. Branch names can't normally contain
+// NUL characters, so this can't clash with a real branch.
+const DRY_RUN_BRANCH: &str = "\0merge-dry-run";
+
+// The conflicts in each file on a branch, indexed by the files' paths.
+pub(crate) type FileConflicts = BTreeMap<String, Vec<Conflict>>;
+
+/// The result of merging one branch into another (see [`Repo::merge`]).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MergeReport {
+    /// The patches that were applied, in the order that they were applied.
+    pub applied: Vec<PatchId>,
+    /// The conflicts in the lines of the branch itself, after the merge.
+    pub conflicts: Vec<Conflict>,
+    /// The conflicts in each file on the branch, after the merge. Files without conflicts are
+    /// omitted.
+    pub file_conflicts: FileConflicts,
+}
+
+impl MergeReport {
+    /// Does the branch have any conflicts after the merge?
+    pub fn has_conflicts(&self) -> bool {
+        !self.conflicts.is_empty() || !self.file_conflicts.is_empty()
+    }
+}
+
+// Returns the patches that are on `from` but not on `into`, in an order in which they can be
+// applied.
+fn missing_patches(repo: &Repo, from: &str, into: &str) -> Vec<PatchId> {
+    let missing = repo
+        .patches(from)
+        .filter(|p| !repo.patches(into).any(|q| q == *p))
+        .cloned()
+        .collect::<Vec<_>>();
+    repo.patch_graph().apply_order(&missing)
+}
+
+fn merge_into(repo: &mut Repo, into: &str, order: &[PatchId]) -> Result<MergeReport, Error> {
+    let mut report = MergeReport::default();
+    let mut reporter = Reporter::start(repo, Phase::Merge, order.len());
+    for id in order {
+        // The patches are in dependency order, so each one only applies itself.
+        report
+            .applied
+            .extend(repo.apply(into, id, ApplyPolicy::Cascade)?);
+        reporter.patch(repo, id);
+    }
+    reporter.finish();
+
+    let (conflicts, file_conflicts) = conflicts(repo, into)?;
+    report.conflicts = conflicts;
+    report.file_conflicts = file_conflicts;
+    Ok(report)
+}
+
+// Returns the conflicts in the lines of a branch, and the ones in each of its files that has any.
+pub(crate) fn conflicts(
+    repo: &Repo,
+    branch: &str,
+) -> Result<(Vec<Conflict>, FileConflicts), Error> {
+    let mut file_conflicts = BTreeMap::new();
+    for path in repo.files(branch)? {
+        let conflicts = repo.file_graggle(branch, path)?.conflicts();
+        if !conflicts.is_empty() {
+            file_conflicts.insert(path.to_owned(), conflicts);
+        }
+    }
+    Ok((repo.graggle(branch)?.conflicts(), file_conflicts))
+}
+
+pub(crate) fn merge(
+    repo: &mut Repo,
+    from: &str,
+    into: &str,
+    dry_run: bool,
+) -> Result<MergeReport, Error> {
+    for branch in &[from, into] {
+        if !repo.branches().any(|b| b == *branch) {
+            return Err(Error::UnknownBranch((*branch).to_owned()));
+        }
+    }
+    if !dry_run {
+        // Applying a single patch already undoes itself if it fails, so that doesn't need a
+        // transaction.
+        let order = missing_patches(repo, from, into);
+        if order.len() <= 1 {
+            return merge_into(repo, into, &order);
+        }
+        return repo.transaction(|repo| merge_into(repo, into, &order));
+    }
+
+    // For a dry run, we merge into a copy of the branch and then throw it away. Since nothing
+    // survives it, it's allowed even if the repository was opened read-only. The transaction
+    // undoes everything if something fails, and the flag is put back whatever happens.
+    let readonly = std::mem::replace(&mut repo.readonly, false);
+    let ret = repo.transaction(|repo| {
+        let ret = dry_run_into(repo, from, into);
+        if repo.storage.inode(DRY_RUN_BRANCH).is_some() {
+            repo.delete_branch(DRY_RUN_BRANCH)?;
+        }
+        ret
+    });
+    repo.readonly = readonly;
+    ret
+}
+
+fn dry_run_into(repo: &mut Repo, from: &str, into: &str) -> Result<MergeReport, Error> {
+    repo.clone_branch(into, DRY_RUN_BRANCH)?;
+    let order = missing_patches(repo, from, DRY_RUN_BRANCH);
+    merge_into(repo, DRY_RUN_BRANCH, &order)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{create, tmp_dir};
+    use crate::{Error, Repo};
+
+    #[test]
+    fn merge() {
+        let mut repo = Repo::init_tmp();
+        create(&mut repo, "master", b"a\nb\n");
+        repo.clone_branch("master", "other").unwrap();
+        let first = create(&mut repo, "other", b"a\nc\nb\n");
+        let second = create(&mut repo, "other", b"a\nc\nd\nb\n");
+
+        let report = repo.merge("other", "master", false).unwrap();
+        assert_eq!(report.applied, vec![first, second]);
+        assert!(!report.has_conflicts());
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"a\nc\nd\nb\n");
+
+        // There's nothing left to merge.
+        let report = repo.merge("other", "master", false).unwrap();
+        assert!(report.applied.is_empty());
+    }
+
+    #[test]
+    fn dry_run() {
+        let mut repo = Repo::init_tmp();
+        create(&mut repo, "master", b"a\nb\n");
+        repo.clone_branch("master", "other").unwrap();
+        let theirs = create(&mut repo, "other", b"a\nc\nb\n");
+        create(&mut repo, "master", b"a\nd\nb\n");
+
+        let report = repo.merge("other", "master", true).unwrap();
+        assert_eq!(report.applied, vec![theirs]);
+        assert_eq!(report.conflicts.len(), 1);
+        assert!(report.has_conflicts());
+
+        // Nothing changed.
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"a\nd\nb\n");
+        assert_eq!(repo.branches().count(), 2);
+        assert!(repo.merge("other", "missing", true).is_err());
+    }
+
+    #[test]
+    fn readonly() {
+        let dir = tmp_dir("merge-readonly");
+        let mut repo = Repo::init(&dir).unwrap();
+        create(&mut repo, "master", b"a\n");
+        repo.clone_branch("master", "other").unwrap();
+        let theirs = create(&mut repo, "other", b"a\nb\n");
+        repo.write().unwrap();
+
+        let mut repo = Repo::open_readonly(&dir).unwrap();
+        assert!(repo.is_readonly());
+        // A dry run doesn't change anything, so it's allowed.
+        let report = repo.merge("other", "master", true).unwrap();
+        assert_eq!(report.applied, vec![theirs]);
+        match repo.merge("other", "master", false) {
+            Err(Error::ReadOnly(d)) => assert_eq!(d, dir),
+            x => panic!("unexpected result {:?}", x),
+        }
+        assert!(matches!(repo.create_branch("new"), Err(Error::ReadOnly(_))));
+        assert!(matches!(repo.write(), Err(Error::ReadOnly(_))));
+        assert_eq!(repo.branches().count(), 2);
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"a\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_merge() {
+        let mut repo = Repo::init_tmp();
+        create(&mut repo, "master", b"a\n");
+        let base = repo.binary_changes("master", b"base".to_vec()).unwrap();
+        let base = repo.create_patch("Author", "Msg", base).unwrap();
+        repo.apply_patch("master", &base).unwrap();
+        repo.clone_branch("master", "other").unwrap();
+        let ours = repo.binary_changes("master", b"ours".to_vec()).unwrap();
+        let ours = repo.create_patch("Author", "Msg", ours).unwrap();
+        repo.apply_patch("master", &ours).unwrap();
+
+        // The second patch on "other" depends on the first one, and it conflicts with "master".
+        let first = create(&mut repo, "other", b"a\nb\n");
+        let mut changes = repo.diff("other", b"a\n").unwrap().changes();
+        let theirs = repo.binary_changes("other", b"theirs".to_vec()).unwrap();
+        changes.changes.extend(theirs.changes);
+        let second = repo.create_patch("Author", "Msg", changes).unwrap();
+        repo.apply_patch("other", &second).unwrap();
+
+        match repo.merge("other", "master", false) {
+            Err(Error::BinaryConflict(p)) => assert_eq!(p, second),
+            x => panic!("expected a conflict, got {:?}", x),
+        }
+        // The first patch was applied, but not any more.
+        assert!(!repo.patches("master").any(|p| *p == first));
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"a\n");
+        assert_eq!(
+            repo.binary("master").unwrap().as_deref(),
+            Some(&b"ours"[..])
+        );
+    }
+
+    #[test]
+    fn failed_dry_run() {
+        let mut repo = Repo::init_tmp();
+        let base = repo.binary_changes("master", b"base".to_vec()).unwrap();
+        let base = repo.create_patch("Author", "Msg", base).unwrap();
+        repo.apply_patch("master", &base).unwrap();
+        repo.clone_branch("master", "other").unwrap();
+        let ours = repo.binary_changes("master", b"ours".to_vec()).unwrap();
+        let ours = repo.create_patch("Author", "Msg", ours).unwrap();
+        repo.apply_patch("master", &ours).unwrap();
+        let theirs = repo.binary_changes("other", b"theirs".to_vec()).unwrap();
+        let theirs = repo.create_patch("Author", "Msg", theirs).unwrap();
+        repo.apply_patch("other", &theirs).unwrap();
+
+        // A dry run that fails doesn't leave its scratch branch behind.
+        match repo.merge("other", "master", true) {
+            Err(Error::BinaryConflict(p)) => assert_eq!(p, theirs),
+            x => panic!("expected a conflict, got {:?}", x),
+        }
+        assert_eq!(repo.branches().count(), 2);
+    }
+}
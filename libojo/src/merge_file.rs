@@ -0,0 +1,255 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Three-way merges of files that aren't in a repository.
+//
+// We make a throwaway repository with three patches in it: one that creates the base file, and two
+// that change it into "ours" and "theirs". Applying all three to the same branch gives a graggle,
+// whose settled lines are the clean parts of the merge and whose conflicts are the parts that
+// aren't. So the result is what merging the two branches of a real repository would give.
+
+use std::io::{self, Write};
+
+use crate::conflict::{regions, Region};
+use crate::{Change, Changes, Error, PatchId, Repo};
+
+// The branch that keeps the base file, so that "theirs" can be compared with it.
+const BASE_BRANCH: &str = "base";
+
+/// A piece of the result of a three-way merge (see [`merge_file`]).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MergeChunk {
+    /// Lines that the merge settled on, including their line endings.
+    Clean(Vec<u8>),
+    /// Lines that the two sides disagree about. Lines that are in the base file but that one of
+    /// the sides deleted can show up on both sides.
+    Conflict {
+        /// The lines from our side.
+        ours: Vec<u8>,
+        /// The lines from their side.
+        theirs: Vec<u8>,
+    },
+}
+
+/// The result of a three-way merge (see [`merge_file`]).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FileMerge {
+    /// The merged file, in order. Consecutive clean lines are in a single chunk.
+    pub chunks: Vec<MergeChunk>,
+}
+
+impl FileMerge {
+    fn push_clean(&mut self, lines: &[u8]) {
+        if let Some(MergeChunk::Clean(clean)) = self.chunks.last_mut() {
+            clean.extend_from_slice(lines);
+        } else {
+            self.chunks.push(MergeChunk::Clean(lines.to_owned()));
+        }
+    }
+
+    /// Does the merge have any conflicts?
+    pub fn has_conflicts(&self) -> bool {
+        self.chunks
+            .iter()
+            .any(|c| matches!(c, MergeChunk::Conflict { .. }))
+    }
+
+    /// Returns the merged file, or `None` if there are conflicts.
+    pub fn clean(&self) -> Option<Vec<u8>> {
+        let mut ret = Vec::new();
+        for chunk in &self.chunks {
+            match chunk {
+                MergeChunk::Clean(lines) => ret.extend_from_slice(lines),
+                MergeChunk::Conflict { .. } => return None,
+            }
+        }
+        Some(ret)
+    }
+
+    /// Writes out the merged file, with the same conflict markers as `git merge-file`: each
+    /// conflict starts with `<<<<<<<` followed by `ours_label`, the two sides are separated by
+    /// `=======`, and the conflict ends with `>>>>>>>` followed by `theirs_label`.
+    pub fn write_with_markers<W: Write>(
+        &self,
+        ours_label: &str,
+        theirs_label: &str,
+        mut w: W,
+    ) -> io::Result<()> {
+        // The markers need to be on lines of their own, even if the lines before them don't end
+        // in a newline.
+        fn write_lines<W: Write>(w: &mut W, lines: &[u8]) -> io::Result<()> {
+            w.write_all(lines)?;
+            if !lines.is_empty() && !lines.ends_with(b"\n") {
+                w.write_all(b"\n")?;
+            }
+            Ok(())
+        }
+
+        for (i, chunk) in self.chunks.iter().enumerate() {
+            match chunk {
+                MergeChunk::Clean(lines) if i + 1 == self.chunks.len() => w.write_all(lines)?,
+                MergeChunk::Clean(lines) => write_lines(&mut w, lines)?,
+                MergeChunk::Conflict { ours, theirs } => {
+                    writeln!(w, "<<<<<<< {}", ours_label)?;
+                    write_lines(&mut w, ours)?;
+                    writeln!(w, "=======")?;
+                    write_lines(&mut w, theirs)?;
+                    writeln!(w, ">>>>>>> {}", theirs_label)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Creates a patch with some changes and applies it to "master", unless there aren't any changes.
+fn apply(repo: &mut Repo, changes: Changes) -> Result<Option<PatchId>, Error> {
+    if changes.changes.is_empty() {
+        return Ok(None);
+    }
+    let id = repo.create_patch("ojo", "merge-file", changes)?;
+    repo.apply_patch("master", &id)?;
+    Ok(Some(id))
+}
+
+/// Merges the changes that turned `base` into `ours` with the ones that turned it into `theirs`.
+///
+/// This doesn't need a repository, so it can be used as a merge driver for other version control
+/// systems (see `ojo merge-file`). The files are compared line by line, as with [`Repo::diff`],
+/// and the merge is the same as merging two branches of a repository: where the two sides made
+/// changes in the same place, the result has a conflict.
+pub fn merge_file(base: &[u8], ours: &[u8], theirs: &[u8]) -> Result<FileMerge, Error> {
+    let mut repo = Repo::init_tmp();
+    let changes = repo.diff("master", base)?.changes();
+    apply(&mut repo, changes)?;
+    repo.clone_branch("master", BASE_BRANCH)?;
+    let changes = repo.diff("master", ours)?.changes();
+    let ours_id = apply(&mut repo, changes)?;
+
+    // A line can't be deleted twice, so their side doesn't delete the lines that our side already
+    // deleted. This isn't the same as a real merge (where both patches would delete those lines)
+    // but the graggles come out the same.
+    let mut changes = repo.diff(BASE_BRANCH, theirs)?.changes();
+    let graggle = repo.graggle("master")?;
+    changes.changes.retain(|ch| match ch {
+        Change::DeleteNode { id } => graggle.is_live(id),
+        _ => true,
+    });
+    let theirs_id = apply(&mut repo, changes)?;
+
+    let mut ret = FileMerge::default();
+    for region in regions(repo.graggle("master")?) {
+        match region {
+            Region::Line(id) => ret.push_clean(repo.contents(&id)),
+            Region::Conflict(conflict) => {
+                let mut ours = Vec::new();
+                let mut theirs = Vec::new();
+                for (i, alt) in conflict.alternatives.iter().enumerate() {
+                    // Every alternative has lines from one of the sides, unless it only has lines
+                    // from the base file, in which case it belongs to both.
+                    let patches = conflict.alternative_patches(i);
+                    let from = |id: Option<PatchId>| id.is_some_and(|p| patches.contains(&p));
+                    let (from_ours, from_theirs) = (from(ours_id), from(theirs_id));
+                    for node in alt {
+                        if from_ours || !from_theirs {
+                            ours.extend_from_slice(repo.contents(node));
+                        }
+                        if from_theirs || !from_ours {
+                            theirs.extend_from_slice(repo.contents(node));
+                        }
+                    }
+                }
+                // If both sides made the same change, there's nothing to choose between.
+                if ours == theirs {
+                    ret.push_clean(&ours);
+                } else {
+                    ret.chunks.push(MergeChunk::Conflict { ours, theirs });
+                }
+            }
+        }
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_markers(merge: &FileMerge) -> String {
+        let mut out = Vec::new();
+        merge
+            .write_with_markers("ours", "theirs", &mut out)
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn clean() {
+        let merge = merge_file(
+            b"a\nb\nc\nd\ne\n",
+            b"a\nB\nc\nd\ne\n",
+            b"a\nb\nc\nD\ne\nf\n",
+        )
+        .unwrap();
+        assert!(!merge.has_conflicts());
+        assert_eq!(merge.clean().unwrap(), b"a\nB\nc\nD\ne\nf\n");
+        assert_eq!(merge.chunks.len(), 1);
+
+        // If one side didn't change anything, the result is the other side.
+        let merge = merge_file(b"a\n", b"a\n", b"b\n").unwrap();
+        assert_eq!(merge.clean().unwrap(), b"b\n");
+
+        // Both sides can delete the same lines, or make the same changes.
+        let merge = merge_file(b"a\nb\nc\n", b"a\nc\n", b"c\n").unwrap();
+        assert_eq!(merge.clean().unwrap(), b"c\n");
+        let merge = merge_file(b"a\nb\nc\n", b"a\nB\nc\n", b"a\nB\nc\n").unwrap();
+        assert_eq!(merge.clean().unwrap(), b"a\nB\nc\n");
+    }
+
+    #[test]
+    fn conflict() {
+        let merge = merge_file(b"a\nb\nc\n", b"a\nours\nc\n", b"a\ntheirs\nc\n").unwrap();
+        assert!(merge.has_conflicts());
+        assert_eq!(merge.clean(), None);
+        assert_eq!(
+            merge.chunks,
+            vec![
+                MergeChunk::Clean(b"a\n".to_vec()),
+                MergeChunk::Conflict {
+                    ours: b"ours\n".to_vec(),
+                    theirs: b"theirs\n".to_vec(),
+                },
+                MergeChunk::Clean(b"c\n".to_vec()),
+            ]
+        );
+        assert_eq!(
+            with_markers(&merge),
+            "a\n<<<<<<< ours\nours\n=======\ntheirs\n>>>>>>> theirs\nc\n"
+        );
+    }
+
+    #[test]
+    fn empty_base() {
+        let merge = merge_file(b"", b"ours", b"theirs").unwrap();
+        assert_eq!(
+            merge.chunks,
+            vec![MergeChunk::Conflict {
+                ours: b"ours".to_vec(),
+                theirs: b"theirs".to_vec(),
+            }]
+        );
+        // The markers go on lines of their own.
+        assert_eq!(
+            with_markers(&merge),
+            "<<<<<<< ours\nours\n=======\ntheirs\n>>>>>>> theirs\n"
+        );
+    }
+}
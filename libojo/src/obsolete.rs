@@ -0,0 +1,231 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+use crate::PatchId;
+
+/// A record that one patch supersedes another.
+///
+/// Patches are immutable, so amending or rewriting a patch really creates a new patch. Marking the
+/// old patch as obsolete (see [`Repo::mark_obsolete`](crate::Repo::mark_obsolete)) lets anyone who
+/// already has the old patch replace it by the new one (see
+/// [`Repo::migrate_obsolete`](crate::Repo::migrate_obsolete)), instead of ending up with both.
+/// Markers travel in [`Bundle`](crate::Bundle)s together with the patches that replace the
+/// obsolete ones.
+///
+/// A patch may be obsoleted by several patches (for example, if it was split into pieces), and
+/// several patches may be obsoleted by the same patch (for example, if they were composed).
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct ObsoleteMarker {
+    /// The patch that was replaced.
+    pub obsolete: PatchId,
+    /// The patch that replaces it.
+    pub successor: PatchId,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ObsoleteMarker;
+    use crate::test_util::create_unapplied;
+    use crate::{Bundle, Changes, Error, PatchHeader, PatchId, Repo};
+
+    fn marker(obsolete: PatchId, successor: PatchId) -> ObsoleteMarker {
+        ObsoleteMarker {
+            obsolete,
+            successor,
+        }
+    }
+
+    #[test]
+    fn migrate() {
+        let mut repo = Repo::init_tmp();
+        let base = create_unapplied(&mut repo, "master", b"a\n");
+        repo.apply_patch("master", &base).unwrap();
+        repo.clone_branch("master", "other").unwrap();
+        let old = create_unapplied(&mut repo, "master", b"a\nb\n");
+        repo.apply_patch("master", &old).unwrap();
+        let dependent = create_unapplied(&mut repo, "master", b"a\nb\nc\n");
+        repo.apply_patch("master", &dependent).unwrap();
+
+        // Amend `old`, by creating a replacement for it on another branch.
+        let new = create_unapplied(&mut repo, "other", b"a\nB\n");
+        repo.mark_obsolete(marker(old, new)).unwrap();
+        assert!(repo.is_obsolete(&old));
+        assert_eq!(repo.successors(&old).collect::<Vec<_>>(), vec![&new]);
+
+        // The dependent patch would be lost, so it needs to be replaced too.
+        match repo.migrate_obsolete("master") {
+            Err(Error::HasDependents(p, deps)) => {
+                assert_eq!(p, old);
+                assert_eq!(deps, vec![dependent]);
+            }
+            x => panic!("expected an error, got {:?}", x),
+        }
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"a\nb\nc\n");
+
+        repo.apply_patch("other", &new).unwrap();
+        let new_dependent = create_unapplied(&mut repo, "other", b"a\nB\nc\n");
+        repo.mark_obsolete(marker(dependent, new_dependent))
+            .unwrap();
+        let mut migrated = repo.migrate_obsolete("master").unwrap();
+        migrated.sort();
+        let mut expected = vec![marker(old, new), marker(dependent, new_dependent)];
+        expected.sort();
+        assert_eq!(migrated, expected);
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"a\nB\nc\n");
+        assert!(repo.migrate_obsolete("master").unwrap().is_empty());
+    }
+
+    #[test]
+    fn chains() {
+        let mut repo = Repo::init_tmp();
+        let first = create_unapplied(&mut repo, "master", b"a\n");
+        repo.apply_patch("master", &first).unwrap();
+        repo.create_branch("other").unwrap();
+        let second = create_unapplied(&mut repo, "other", b"b\n");
+        let third = create_unapplied(&mut repo, "other", b"c\n");
+        repo.mark_obsolete(marker(first, second)).unwrap();
+        repo.mark_obsolete(marker(second, third)).unwrap();
+
+        // Markers can't form cycles, and a patch can't replace something it depends on.
+        match repo.mark_obsolete(marker(third, first)) {
+            Err(Error::InvalidObsoleteMarker(..)) => {}
+            x => panic!("expected an error, got {:?}", x),
+        }
+        match repo.mark_obsolete(marker(first, first)) {
+            Err(Error::InvalidObsoleteMarker(..)) => {}
+            x => panic!("expected an error, got {:?}", x),
+        }
+        repo.apply_patch("other", &third).unwrap();
+        let dependent = create_unapplied(&mut repo, "other", b"c\nd\n");
+        match repo.mark_obsolete(marker(third, dependent)) {
+            Err(Error::InvalidObsoleteMarker(..)) => {}
+            x => panic!("expected an error, got {:?}", x),
+        }
+
+        // Migrating skips straight to the latest successor.
+        assert_eq!(
+            repo.migrate_obsolete("master").unwrap(),
+            vec![marker(first, third)]
+        );
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"c\n");
+    }
+
+    #[test]
+    fn bundle() {
+        let mut repo = Repo::init_tmp();
+        let old = create_unapplied(&mut repo, "master", b"a\n");
+        repo.apply_patch("master", &old).unwrap();
+        let mut other_repo = Repo::init_tmp();
+        Bundle::create(&repo, &[old])
+            .unwrap()
+            .unbundle(&mut other_repo)
+            .unwrap();
+        other_repo.apply_patch("master", &old).unwrap();
+
+        repo.create_branch("new").unwrap();
+        let new = create_unapplied(&mut repo, "new", b"b\n");
+        repo.mark_obsolete(marker(old, new)).unwrap();
+
+        // The marker is sent along with its successor.
+        let bundle = Bundle::create(&repo, &[new]).unwrap();
+        assert_eq!(bundle.markers(), &[marker(old, new)]);
+        let mut data = Vec::new();
+        bundle.write(&mut data).unwrap();
+        let bundle = Bundle::read(&data[..]).unwrap();
+        bundle.unbundle(&mut other_repo).unwrap();
+        assert!(other_repo.is_obsolete(&old));
+        other_repo.migrate_obsolete("master").unwrap();
+        assert_eq!(other_repo.file("master").unwrap().as_bytes(), b"b\n");
+    }
+
+    #[test]
+    fn squash() {
+        let mut repo = Repo::init_tmp();
+        let first = create_unapplied(&mut repo, "master", b"a\n");
+        repo.apply_patch("master", &first).unwrap();
+        let second = create_unapplied(&mut repo, "master", b"a\nb\n");
+        repo.apply_patch("master", &second).unwrap();
+        let third = create_unapplied(&mut repo, "master", b"a\nb\nc\n");
+        repo.apply_patch("master", &third).unwrap();
+
+        let header = PatchHeader::new("Author".to_owned(), "Squashed".to_owned());
+        let squashed = repo
+            .squash_patches(&[second, third], header.clone())
+            .unwrap();
+        assert_eq!(
+            repo.successors(&second).collect::<Vec<_>>(),
+            vec![&squashed]
+        );
+        assert_eq!(repo.migrate_obsolete("master").unwrap().len(), 2);
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"a\nb\nc\n");
+        assert_eq!(repo.patches("master").count(), 2);
+
+        // Publishing a patch publishes its dependencies, and then they can't be squashed.
+        repo.mark_published(&squashed).unwrap();
+        assert!(repo.is_published(&first));
+        assert_eq!(repo.published_patches().collect::<Vec<_>>().len(), 2);
+        match repo.squash_patches(&[first, squashed], header) {
+            Err(Error::PublishedPatch(p)) => assert_eq!(p, first),
+            x => panic!("expected an error, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn amend() {
+        let mut repo = Repo::init_tmp();
+        let first = create_unapplied(&mut repo, "master", b"a\n");
+        repo.apply_patch("master", &first).unwrap();
+        let second = create_unapplied(&mut repo, "master", b"a\nb\n");
+        repo.apply_patch("master", &second).unwrap();
+
+        // The extra changes can refer to the lines that the amended patch added.
+        let extra = repo.diff("master", b"a\nc\n").unwrap().changes();
+        let amended = repo.amend("master", &second, extra, None).unwrap();
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"a\nc\n");
+        assert_eq!(repo.patches("master").count(), 2);
+        assert_eq!(repo.successors(&second).collect::<Vec<_>>(), vec![&amended]);
+        assert_eq!(repo.patch_deps(&amended).collect::<Vec<_>>(), vec![&first]);
+        assert_eq!(repo.patch_header(&amended).unwrap().description, "Msg");
+
+        let header = PatchHeader::new("Author".to_owned(), "Reworded".to_owned());
+        let reworded = repo
+            .amend(
+                "master",
+                &amended,
+                Changes { changes: vec![] },
+                Some(header),
+            )
+            .unwrap();
+        assert_eq!(
+            repo.patch_header(&reworded).unwrap().description,
+            "Reworded"
+        );
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"a\nc\n");
+
+        match repo.amend("master", &first, Changes { changes: vec![] }, None) {
+            Err(Error::HasDependents(p, deps)) => {
+                assert_eq!(p, first);
+                assert_eq!(deps, vec![reworded]);
+            }
+            x => panic!("expected an error, got {:?}", x),
+        }
+        match repo.amend("master", &second, Changes { changes: vec![] }, None) {
+            Err(Error::NotOnBranch(..)) => {}
+            x => panic!("expected an error, got {:?}", x),
+        }
+        repo.mark_published(&reworded).unwrap();
+        let extra = repo.diff("master", b"a\nd\n").unwrap().changes();
+        match repo.amend("master", &reworded, extra, None) {
+            Err(Error::PublishedPatch(p)) => assert_eq!(p, reworded),
+            x => panic!("expected an error, got {:?}", x),
+        }
+    }
+}
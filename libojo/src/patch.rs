@@ -12,76 +12,37 @@
 use chrono::{DateTime, Utc};
 use serde_yaml;
 use sha2::{Digest, Sha256};
-use std::collections::HashSet;
-use std::io::{self, prelude::*};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::prelude::*;
 
 use crate::error::PatchIdError;
-use crate::Error;
+use crate::storage::graggle::GraggleData;
+use crate::{Error, NodeId, Renumbering};
 
+pub(crate) mod binary;
 mod change;
+mod check;
+mod stats;
+mod text;
+mod unified;
+pub use self::binary::{ChangeReader, BINARY_FORMAT_VERSION, BINARY_MAGIC};
 pub use self::change::{Change, Changes};
-
-// This is just a wrapper around some instance of io::Write that calculates a hash of everything
-// that's written.
-struct HashingWriter<W: Write> {
-    writer: W,
-    hasher: Sha256,
-}
-
-impl<W: Write> HashingWriter<W> {
-    fn new(writer: W) -> HashingWriter<W> {
-        HashingWriter {
-            writer,
-            hasher: Default::default(),
-        }
-    }
-}
-
-impl<W: Write> Write for HashingWriter<W> {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.hasher.input(buf);
-        self.writer.write(buf)
-    }
-
-    fn flush(&mut self) -> io::Result<()> {
-        self.writer.flush()
-    }
-}
-
-struct HashingReader<R: Read> {
-    reader: R,
-    hasher: Sha256,
-}
-
-impl<R: Read> HashingReader<R> {
-    fn new(reader: R) -> HashingReader<R> {
-        HashingReader {
-            reader,
-            hasher: Default::default(),
-        }
-    }
-}
-
-impl<R: Read> Read for HashingReader<R> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let size = self.reader.read(buf)?;
-        self.hasher.input(&buf[..size]);
-        Ok(size)
-    }
-}
+pub use self::check::ApplyReport;
+pub use self::stats::PatchStats;
 
 // PatchId contains a [u8; 32], which by default serializes to an array in yaml (and other
 // human-readable formats). To make the output more compact and readable, it's better to convert it
 // to a base64 string.
-mod patch_id_base64 {
-    pub fn serialize<S>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error>
+pub(crate) mod patch_id_base64 {
+    pub fn serialize<S>(bytes: &[u8; 32], serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
         if serializer.is_human_readable() {
             serializer.serialize_str(&base64::encode_config(bytes, base64::URL_SAFE))
         } else {
-            serializer.serialize_bytes(bytes)
+            // This needs to match the non-human-readable branch of `deserialize`.
+            serde::Serialize::serialize(bytes, serializer)
         }
     }
 
@@ -106,6 +67,11 @@ mod patch_id_base64 {
 ///
 /// A `PatchId` is derived from a patch by hashing its contents. It must be unique: a repository
 /// cannot simultaneously contain two patches with the same id.
+///
+/// The hash is taken over a canonical serialization of the patch (see [`UnidentifiedPatch::id`]),
+/// so the id doesn't depend on incidental details (like whitespace or comments) of the file that
+/// a patch was read from. In particular, anyone who receives a patch can check that it matches
+/// the id that it was advertised under.
 #[derive(Copy, Clone, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
 #[serde(transparent)]
 pub struct PatchId {
@@ -120,14 +86,22 @@ impl std::fmt::Debug for PatchId {
 }
 
 impl PatchId {
-    /// There is a special reserved `PatchId` for patches that are under construction, but not yet
-    /// finished (see [`UnidentifiedPatch`] for more details). This function returns that special id.
-    pub fn cur() -> PatchId {
+    /// Returns the reserved id of the staging area.
+    ///
+    /// A patch that is still under construction doesn't have an id yet (see
+    /// [`UnidentifiedPatch`]), so the lines and files that it introduces are given ids in the
+    /// staging namespace instead: their `PatchId` is this reserved one. When the patch is
+    /// finished, its staged lines are renumbered (see [`Renumbering`]) and then every reference to
+    /// the staging id is replaced by the patch's real id.
+    ///
+    /// No real patch can have this id, because that would require finding some data whose SHA256
+    /// hash is zero.
+    pub fn staging() -> PatchId {
         PatchId { data: [0; 32] }
     }
 
-    /// Checks whether this `PatchId` is the one decribed in [`PatchId::cur`].
-    pub fn is_cur(&self) -> bool {
+    /// Checks whether this `PatchId` is the reserved one described in [`PatchId::staging`].
+    pub fn is_staging(&self) -> bool {
         self.data == [0; 32]
     }
 
@@ -151,7 +125,7 @@ impl PatchId {
     pub fn from_base64<S: ?Sized + AsRef<[u8]>>(name: &S) -> Result<PatchId, Error> {
         let data = base64::decode_config(&name.as_ref()[1..], base64::URL_SAFE)
             .map_err(PatchIdError::from)?;
-        let mut ret = PatchId::cur();
+        let mut ret = PatchId::staging();
         if data.len() != ret.data.len() {
             Err(PatchIdError::InvalidLength(data.len()).into())
         } else {
@@ -160,14 +134,61 @@ impl PatchId {
         }
     }
 
-    // Creates a PatchId from a Sha256 hasher
-    fn from_sha256(hasher: Sha256) -> PatchId {
-        let mut ret = PatchId::cur();
-        ret.data.copy_from_slice(&hasher.result()[..]);
+    // Creates a PatchId by hashing some data.
+    fn from_sha256(data: &[u8]) -> PatchId {
+        let mut ret = PatchId::staging();
+        ret.data.copy_from_slice(&Sha256::digest(data)[..]);
         ret
     }
 }
 
+/// The shortest prefix of a [`PatchId`] that [`Repo::short_patch_id`](crate::Repo::short_patch_id)
+/// will return, even if a shorter one would be unambiguous.
+///
+/// This includes the leading 'P'.
+pub const MIN_PATCH_PREFIX_LEN: usize = 8;
+
+// Finds the unique id in `ids` whose base64 representation starts with `prefix`.
+pub(crate) fn resolve_prefix<'a, I>(ids: I, prefix: &str) -> Result<PatchId, Error>
+where
+    I: IntoIterator<Item = &'a PatchId>,
+{
+    let mut candidates = ids
+        .into_iter()
+        .filter(|id| id.to_base64().starts_with(prefix))
+        .cloned()
+        .collect::<Vec<_>>();
+    match candidates.len() {
+        0 => Err(Error::UnknownPatchPrefix(prefix.to_owned())),
+        1 => Ok(candidates[0]),
+        _ => {
+            candidates.sort_by_key(PatchId::to_base64);
+            Err(Error::AmbiguousPatchPrefix(prefix.to_owned(), candidates))
+        }
+    }
+}
+
+// Returns the length of the shortest prefix of `id`'s base64 representation that isn't shared by
+// any of the other ids in `ids`. The result is never shorter than `MIN_PATCH_PREFIX_LEN`.
+pub(crate) fn unique_prefix_len<'a, I>(ids: I, id: &PatchId) -> usize
+where
+    I: IntoIterator<Item = &'a PatchId>,
+{
+    let name = id.to_base64();
+    let common = ids
+        .into_iter()
+        .filter(|other| *other != id)
+        .map(|other| {
+            name.bytes()
+                .zip(other.to_base64().bytes())
+                .take_while(|(a, b)| a == b)
+                .count()
+        })
+        .max()
+        .unwrap_or(0);
+    (common + 1).max(MIN_PATCH_PREFIX_LEN).min(name.len())
+}
+
 /// Like a [`Patch`], but without the unique id.
 ///
 /// A patch is ultimately identified by its id, which is generated by hashing the contents of the
@@ -177,7 +198,7 @@ impl PatchId {
 /// with an id.
 ///
 /// This is an unidentified patch; it does not have an id field, and any changes that need
-/// to refer to contents of this patch use the placeholder id returned by [`PatchId::cur`].
+/// to refer to contents of this patch use the staging id returned by [`PatchId::staging`].
 ///
 /// This patch *cannot* be applied to a repository, because doing so would require an id. However,
 /// it can be serialized to a file, and it can be turned into an identified patch.
@@ -193,47 +214,63 @@ pub struct UnidentifiedPatch {
     header: PatchHeader,
 
     // The list of other patches on which this depends. This should coincide with the set of all
-    // other PatchIds that are referenced in `changes`.
+    // other PatchIds that are referenced in `changes`. It is sorted, so that the serialization
+    // (and hence the id) of a patch doesn't depend on the order in which we found the deps.
     deps: Vec<PatchId>,
 }
 
 impl UnidentifiedPatch {
     /// Creates a new `UnidentifiedPatch` from some metadata and a set of changes.
     pub fn new(author: String, description: String, changes: Changes) -> UnidentifiedPatch {
-        // The dependencies of this patch consist of all patches that are referred to by the list
-        // of changes.
-        let mut deps = HashSet::new();
-        for c in &changes.changes {
-            match *c {
-                Change::DeleteNode { ref id } => {
-                    if !id.patch.is_cur() {
-                        deps.insert(id.patch);
-                    }
-                }
-                Change::NewEdge { ref src, ref dest } => {
-                    if !src.patch.is_cur() {
-                        deps.insert(src.patch);
-                    }
-                    if !dest.patch.is_cur() {
-                        deps.insert(dest.patch);
-                    }
-                }
-                _ => {}
-            }
-        }
+        UnidentifiedPatch::with_header(PatchHeader::new(author, description), changes)
+    }
 
+    /// Creates a new `UnidentifiedPatch` from a header and a set of changes.
+    ///
+    /// The changes are put into canonical order (see [`Changes::canonicalize`]), and the lines
+    /// that they introduce are renumbered consecutively (see [`Renumbering`]). The keys of
+    /// [`PatchHeader::line_authors`] are renumbered to match.
+    pub fn with_header(mut header: PatchHeader, mut changes: Changes) -> UnidentifiedPatch {
+        let renumbering = Renumbering::new(&changes);
+        if !renumbering.is_identity() {
+            renumbering.apply(&mut changes);
+            header.line_authors = renumbering.apply_to_keys(header.line_authors);
+        }
+        changes.canonicalize();
         UnidentifiedPatch {
-            header: PatchHeader {
-                author,
-                description,
-                #[cfg(not(target_arch = "wasm32"))]
-                timestamp: Utc::now(),
-            },
+            header,
+            deps: changes.deps(),
             changes,
-            deps: deps.into_iter().collect(),
         }
     }
 
+    // Creates a patch that doesn't make any changes, but has some dependencies.
+    pub(crate) fn with_deps(header: PatchHeader, deps: Vec<PatchId>) -> UnidentifiedPatch {
+        UnidentifiedPatch {
+            header,
+            changes: Changes { changes: vec![] },
+            deps,
+        }
+    }
+
+    // The canonical serialization of this patch, which is what gets hashed to produce its id.
+    //
+    // Note that we don't put the changes in canonical order here: patches that were created before
+    // `Changes::canonicalize` existed have their changes in some other order, and they need to
+    // keep their ids. New patches are put in canonical order when they are created, in
+    // `UnidentifiedPatch::with_header`.
+    fn canonical_bytes(&self) -> Result<Vec<u8>, serde_yaml::Error> {
+        serde_yaml::to_vec(self)
+    }
+
+    /// Computes the id that this patch will have once it is identified.
+    ///
+    /// The id is the SHA256 hash of the canonical serialization of this patch (which is also the
+    /// serialization that [`UnidentifiedPatch::write_out`] produces).
+    pub fn id(&self) -> Result<PatchId, Error> {
+        Ok(PatchId::from_sha256(&self.canonical_bytes()?))
+    }
+
     // Assigns an id to this UnidentifiedPatch, and in doing so turns it into a Patch.
     fn set_id(self, id: PatchId) -> Patch {
         let mut ret = Patch {
@@ -251,11 +288,11 @@ impl UnidentifiedPatch {
     ///
     /// While writing out the patch, we compute the hash of its contents and use that to derive an
     /// id for this patch. Assuming that the writing succeeds, we return the resulting [`Patch`].
-    pub fn write_out<W: Write>(self, writer: W) -> Result<Patch, serde_yaml::Error> {
-        let mut w = HashingWriter::new(writer);
-        serde_yaml::to_writer(&mut w, &self)?;
+    pub fn write_out<W: Write>(self, mut writer: W) -> Result<Patch, Error> {
+        let data = self.canonical_bytes()?;
+        writer.write_all(&data)?;
 
-        let patch_id = PatchId::from_sha256(w.hasher);
+        let patch_id = PatchId::from_sha256(&data);
         Ok(self.set_id(patch_id))
     }
 }
@@ -277,14 +314,23 @@ pub struct Patch {
 impl Patch {
     /// Creates a patch by deserializing it from a reader.
     ///
-    /// The id of the resulting patch will be the SHA256 hash of the contents.
+    /// The id of the resulting patch is computed as in [`UnidentifiedPatch::id`].
     pub fn from_reader<R: Read>(input: R) -> Result<Patch, Error> {
-        let mut reader = HashingReader::new(input);
-        let up: UnidentifiedPatch = serde_yaml::from_reader(&mut reader)?;
-        let id = PatchId::from_sha256(reader.hasher);
+        let up: UnidentifiedPatch = serde_yaml::from_reader(input)?;
+        let id = up.id()?;
         Ok(up.set_id(id))
     }
 
+    /// Creates a patch by deserializing it from a reader, and checks that it has the expected id.
+    pub fn from_reader_with_id<R: Read>(input: R, expected: &PatchId) -> Result<Patch, Error> {
+        let ret = Patch::from_reader(input)?;
+        if ret.id() != expected {
+            Err(Error::IdMismatch(*ret.id(), *expected))
+        } else {
+            Ok(ret)
+        }
+    }
+
     /// The unique id of this patch.
     pub fn id(&self) -> &PatchId {
         &self.id
@@ -306,6 +352,278 @@ impl Patch {
     pub fn deps(&self) -> &[PatchId] {
         &self.deps
     }
+
+    /// The author of a line, if it was introduced by this patch.
+    pub fn line_author(&self, id: &NodeId) -> Option<&str> {
+        if id.patch == self.id {
+            Some(self.header.line_author(id.node))
+        } else {
+            None
+        }
+    }
+
+    /// Composes a sequence of patches into a single patch with the same effect.
+    ///
+    /// The patches must be given in an order that respects their dependencies: no patch may depend
+    /// on a patch that comes after it in `patches`. Moreover, the sequence should be "contiguous,"
+    /// in the sense that no patch outside of `patches` depends on one patch in the sequence and is
+    /// depended on by another one (otherwise, the composed patch would end up depending on
+    /// something that depends on it).
+    ///
+    /// The new patch gets the metadata in `header`, and it depends on everything that the original
+    /// patches depended on, apart from the original patches themselves. The lines that the
+    /// original patches added keep their authors (see [`PatchHeader::line_authors`]), even if
+    /// `header` has a different author.
+    pub fn compose(patches: &[Patch], mut header: PatchHeader) -> Result<UnidentifiedPatch, Error> {
+        // The nodes introduced by the patches we're composing get renumbered, because they now
+        // belong to the new patch.
+        let mut new_ids = HashMap::new();
+        let mut seen = HashSet::new();
+        let mut changes = Vec::new();
+        // The index in `changes` of the binary replacement, if there is one.
+        let mut binary_idx = None;
+
+        for (i, p) in patches.iter().enumerate() {
+            for dep in &p.deps {
+                if patches[i..].iter().any(|q| q.id == *dep) {
+                    return Err(Error::DependencyOrder(p.id, *dep));
+                }
+            }
+
+            for (_, ch) in p.changes.flattened() {
+                if let Change::NewNode { ref id, .. } = *ch {
+                    let new_id = NodeId::staged(new_ids.len() as u64);
+                    new_ids.insert(*id, new_id);
+                    let author = p.header.line_author(id.node);
+                    if author == header.author {
+                        header.line_authors.remove(&new_id.node);
+                    } else {
+                        header.line_authors.insert(new_id.node, author.to_owned());
+                    }
+                }
+            }
+
+            for ch in &p.changes.changes {
+                let mut ch = ch.clone();
+                for id in ch.node_ids_mut() {
+                    if let Some(new_id) = new_ids.get(id) {
+                        *id = *new_id;
+                    }
+                }
+                // Files that were put in place by the patches we're composing now belong to the new
+                // patch.
+                if let Some(file) = ch.file_ref_mut() {
+                    if patches.iter().any(|q| q.id == file.patch) {
+                        file.patch = PatchId::staging();
+                    }
+                }
+                // If this replaces binary contents that were introduced by an earlier patch in the
+                // sequence, the two replacements collapse into one.
+                if let Some(old) = ch.replaced_blob() {
+                    let replaces_composed = old
+                        .map(|o| patches.iter().any(|q| q.id == o.patch))
+                        .unwrap_or(false);
+                    match binary_idx {
+                        Some(i) if replaces_composed => {
+                            changes[i] = compose_binary(&changes[i], ch)?;
+                            continue;
+                        }
+                        _ => binary_idx = Some(changes.len()),
+                    }
+                }
+                // Two independent patches might make the same change (e.g. by deleting the same
+                // node), but we only want it once.
+                if seen.insert(ch.clone()) {
+                    changes.push(ch);
+                }
+            }
+        }
+
+        Ok(UnidentifiedPatch::with_header(header, Changes { changes }))
+    }
+
+    // Turns this patch back into an `UnidentifiedPatch`, by replacing all references to our own id
+    // with the staging id.
+    fn to_unidentified(&self) -> UnidentifiedPatch {
+        let mut changes = self.changes.clone();
+        changes.unset_patch_id(&self.id);
+        UnidentifiedPatch {
+            changes,
+            header: self.header.clone(),
+            deps: self.deps.clone(),
+        }
+    }
+
+    // The data that the repository stores for this patch.
+    pub(crate) fn canonical_data(&self) -> Result<String, Error> {
+        Ok(serde_yaml::to_string(&self.to_unidentified())?)
+    }
+
+    /// Writes this patch out in the binary patch file format.
+    ///
+    /// Unlike the YAML representation that the repository stores internally, the binary format
+    /// records the id of the patch, and so it can be used to exchange patches as standalone files.
+    /// See [`Patch::read_binary`] for the format.
+    pub fn write_binary<W: Write>(&self, writer: W) -> Result<(), Error> {
+        binary::write(self, writer)
+    }
+
+    /// Writes a patch out in the binary patch file format, without needing all of its changes in
+    /// memory at once. Returns the id of the patch that was written.
+    ///
+    /// Because the id of the patch (which is written at the beginning) depends on all of its
+    /// changes, the changes need to be gone through twice: `changes` is called once to compute the
+    /// id and once to write them, and it must produce the same changes (in the same order) both
+    /// times. The changes should use the staging id [`PatchId::staging`] to refer to the patch
+    /// itself, as in an [`UnidentifiedPatch`]. Unlike [`UnidentifiedPatch::with_header`], this
+    /// doesn't put the changes in canonical order.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `changes` produces a different number of changes the second time.
+    pub fn write_binary_stream<W, F, I>(
+        writer: W,
+        header: &PatchHeader,
+        changes: F,
+    ) -> Result<PatchId, Error>
+    where
+        W: Write,
+        F: Fn() -> I,
+        I: IntoIterator<Item = Change>,
+    {
+        binary::write_stream(writer, header, changes)
+    }
+
+    /// Reads a patch that was written by [`Patch::write_binary`].
+    ///
+    /// A patch file consists of:
+    /// - the magic bytes [`BINARY_MAGIC`],
+    /// - the format version, as a little-endian `u32` (currently [`BINARY_FORMAT_VERSION`]),
+    /// - the header section, containing the patch's id, dependencies and metadata, and
+    /// - the payload section, containing the patch's changes.
+    ///
+    /// Each section is prefixed by its length in bytes, as a little-endian `u64`. The id that is
+    /// stored in the header is checked against the contents of the patch.
+    ///
+    /// To read the changes one at a time instead of all at once, use [`ChangeReader`].
+    pub fn read_binary<R: Read>(reader: R) -> Result<Patch, Error> {
+        binary::read(reader)
+    }
+
+    /// Represents this patch as human-readable text.
+    ///
+    /// The text starts with the patch's id and a header containing the metadata and the
+    /// dependencies, one per line:
+    ///
+    /// ```text
+    /// patch P5cpGm1tJIG4obxQbAOCH4y9dPykPcRIHhFAzrxw2SHk=
+    /// author: Joe Neeman
+    /// email: joeneeman@gmail.com
+    /// date: 2019-03-06T13:25:12.093012545+00:00
+    /// meta: key=value
+    /// line-author: 3 Someone Else
+    /// depends: PFVkDe1CpRTf7oJ1tFxbyU4cLVyQ5hmt9Rp6fBqd0gWQ=
+    /// ```
+    ///
+    /// There is a `line-author` line for every entry of [`PatchHeader::line_authors`], and a
+    /// `kind: resolution` or `kind: ordering` line if the patch is a [`PatchKind::Resolution`] or
+    /// a [`PatchKind::Ordering`]. A value that has a control character (such as a newline) in it,
+    /// or that starts with a quote, is written as a quoted string (like the contents of the
+    /// lines below), and so is the key of a `meta` line that has a `=` in it.
+    ///
+    /// Next, after an empty line, comes the description. Every line of the description is
+    /// indented by four spaces, and the description is followed by another empty line. Finally,
+    /// there is one line for each change:
+    ///
+    /// ```text
+    /// + 0 "a new line\n"
+    /// > 0 PFVkDe1CpRTf7oJ1tFxbyU4cLVyQ5hmt9Rp6fBqd0gWQ=/3
+    /// - PFVkDe1CpRTf7oJ1tFxbyU4cLVyQ5hmt9Rp6fBqd0gWQ=/2
+    /// ```
+    ///
+    /// These add a node (with the given contents), add an edge, and delete a node respectively.
+    /// Nodes that are introduced by this patch are referred to by number only; other nodes also
+    /// have the id of the patch that introduced them. Empty lines, and lines beginning with `#`,
+    /// are ignored among the changes.
+    ///
+    /// Changes to lines (in the canonical order of [`Changes::canonicalize`]) that could have
+    /// come from a diff are written instead as hunks, which start with `@@`:
+    ///
+    /// ```text
+    /// @@
+    ///   [PFVkDe1CpRTf7oJ1tFxbyU4cLVyQ5hmt9Rp6fBqd0gWQ=/1]
+    /// - "an old line\n" [PFVkDe1CpRTf7oJ1tFxbyU4cLVyQ5hmt9Rp6fBqd0gWQ=/2]
+    /// + "a new line\n" [0]
+    ///   "the next line\n" [PFVkDe1CpRTf7oJ1tFxbyU4cLVyQ5hmt9Rp6fBqd0gWQ=/3]
+    /// ```
+    ///
+    /// A hunk has lines of context (indented by two spaces), deleted lines (starting with `-`)
+    /// and new lines (starting with `+`), each followed by the id of its node in brackets. A new
+    /// line is attached to the line before it (skipping deleted lines), and a line after a new
+    /// line is attached to the new line. The contents of new lines are part of the patch; the
+    /// contents of the other lines are only there for the reader, and are left out if they
+    /// aren't known (see [`Patch::to_text_with_context`]). The lines that stay or are deleted are
+    /// written in the order of their ids, which is their order in the file as long as they came
+    /// from the same patch.
+    ///
+    /// A [`Change::BinaryReplace`] is written as the letter `b`, followed by the replaced contents
+    /// (as `<patch id>/<hash>`, or `-` if there weren't any) and the new contents in base64. A
+    /// [`Change::ChunkedReplace`] is written as the letter `c`, followed by the replaced contents,
+    /// the hash of the new contents, and the pieces of the new contents: new bytes in base64, and
+    /// copied bytes as `<start>+<len>`.
+    ///
+    /// Changes to files look like this:
+    ///
+    /// ```text
+    /// n "new/file"
+    /// m PFVkDe1CpRTf7oJ1tFxbyU4cLVyQ5hmt9Rp6fBqd0gWQ=/"old/path" "new/path"
+    /// d PFVkDe1CpRTf7oJ1tFxbyU4cLVyQ5hmt9Rp6fBqd0gWQ=/"deleted/file"
+    /// x PFVkDe1CpRTf7oJ1tFxbyU4cLVyQ5hmt9Rp6fBqd0gWQ=/"script" +x
+    /// l PFVkDe1CpRTf7oJ1tFxbyU4cLVyQ5hmt9Rp6fBqd0gWQ=/"link" +l
+    /// ```
+    ///
+    /// These create, move, delete, make executable and make a symbolic link out of a file
+    /// respectively (`-x` makes a file non-executable, and `-l` makes it an ordinary file again).
+    /// Existing files are given by the id of the
+    /// patch that put them in place, followed by their path (or just their path, if they were put
+    /// in place by this patch).
+    ///
+    /// A [`Change::EditFile`] is written as the letter `e` followed by the file, and then the
+    /// changes to the file's lines (or hunks), indented by four spaces.
+    pub fn to_text(&self) -> String {
+        text::write(self, |_| None)
+    }
+
+    /// Like [`Patch::to_text`], but adds the contents of the lines that stay or are deleted.
+    ///
+    /// In hunks, the contents go before the lines' ids; deleted nodes that aren't in a hunk get
+    /// their contents as a comment.
+    ///
+    /// The contents of those nodes aren't part of the patch, so they need to be provided by the
+    /// `context` function (which should return `None` if the contents aren't known).
+    pub fn to_text_with_context<F>(&self, context: F) -> String
+    where
+        F: Fn(&NodeId) -> Option<Vec<u8>>,
+    {
+        text::write(self, context)
+    }
+
+    /// Parses a patch from the format produced by [`Patch::to_text`].
+    ///
+    /// The id given in the text is checked against the contents of the patch.
+    pub fn from_text(input: &str) -> Result<Patch, Error> {
+        text::read(input)
+    }
+
+    // Applies the changes in this patch to a graggle.
+    pub(crate) fn apply_to(&self, graggle: &mut GraggleData) {
+        self.changes.apply_to(graggle, self.id);
+    }
+
+    // Undoes the effect of `apply_to`.
+    pub(crate) fn unapply_from(&self, graggle: &mut GraggleData) {
+        self.changes.unapply_from(graggle, self.id);
+    }
 }
 
 /// Various metadata associated with a patch.
@@ -313,16 +631,539 @@ impl Patch {
 /// This data does not affect the changes that a patch actually makes, but it is considered part of
 /// the patch as far as hashing is concerned. In particular, if you change any of this metadata
 /// then the result is a completely different patch.
+//
+// When adding new optional fields, make sure that they aren't serialized when they're empty: that
+// way, the serialization (and hence the id) of old patches doesn't change.
 #[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub struct PatchHeader {
     /// Author of the patch.
     pub author: String,
 
+    /// The author's email address.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+
     /// A description of the patch.
+    ///
+    /// This may span multiple lines, in which case the first line is considered to be a summary
+    /// (see [`PatchHeader::summary`]).
     pub description: String,
 
     /// The time at which the patch was created.
     // We currently disable this on wasm, since chrono::Utc::now() panics there.
     #[cfg(not(target_arch = "wasm32"))]
     pub timestamp: DateTime<Utc>,
+
+    /// Any other metadata, as key/value pairs.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub extra: BTreeMap<String, String>,
+
+    /// What kind of patch this is.
+    #[serde(default, skip_serializing_if = "PatchKind::is_normal")]
+    pub kind: PatchKind,
+
+    /// The authors of the lines that this patch adds, for those lines whose author isn't
+    /// [`PatchHeader::author`].
+    ///
+    /// The keys are the [`NodeId::node`] fields of the new lines. This is how the original
+    /// authors of lines are remembered when patches are composed (see [`Patch::compose`]), so use
+    /// [`PatchHeader::line_author`] rather than `author` to find out who wrote a line.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub line_authors: BTreeMap<u64, String>,
+}
+
+/// The different kinds of patches.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum PatchKind {
+    /// A patch that can make arbitrary changes.
+    #[default]
+    Normal,
+    /// A patch that resolves a conflict. Such a patch doesn't add any new lines: it only adds
+    /// edges to put existing lines in order, and deletes lines (for example, if the two sides of
+    /// a conflict added the same line).
+    Resolution,
+    /// A patch that only adds edges between existing lines, for example to pin down the order of
+    /// some lines that is currently ambiguous (see
+    /// [`Repo::ordering_changes`](crate::Repo::ordering_changes)).
+    Ordering,
+}
+
+impl PatchKind {
+    /// Is this a normal patch?
+    pub fn is_normal(&self) -> bool {
+        *self == PatchKind::Normal
+    }
+
+    // Can a patch of this kind contain this change?
+    pub(crate) fn allows(self, ch: &Change) -> bool {
+        match self {
+            PatchKind::Normal => true,
+            PatchKind::Resolution => match ch {
+                Change::NewEdge { .. } | Change::DeleteNode { .. } => true,
+                Change::EditFile { changes, .. } => changes.changes.iter().all(|c| self.allows(c)),
+                _ => false,
+            },
+            PatchKind::Ordering => match ch {
+                Change::NewEdge { .. } => true,
+                Change::EditFile { changes, .. } => changes.changes.iter().all(|c| self.allows(c)),
+                _ => false,
+            },
+        }
+    }
+}
+
+impl PatchHeader {
+    /// Creates a new header with the given author and description, and the current time.
+    pub fn new(author: String, description: String) -> PatchHeader {
+        PatchHeader {
+            author,
+            email: None,
+            description,
+            #[cfg(not(target_arch = "wasm32"))]
+            timestamp: Utc::now(),
+            extra: BTreeMap::new(),
+            kind: PatchKind::Normal,
+            line_authors: BTreeMap::new(),
+        }
+    }
+
+    /// The author of the line that this patch introduced with the given [`NodeId::node`].
+    pub fn line_author(&self, node: u64) -> &str {
+        self.line_authors
+            .get(&node)
+            .map(|a| a.as_str())
+            .unwrap_or(&self.author)
+    }
+
+    /// The first line of the description.
+    pub fn summary(&self) -> &str {
+        self.description.lines().next().unwrap_or("")
+    }
+
+    /// The author's name and (if there is one) email address, in the usual `name <email>` format.
+    pub fn full_author(&self) -> String {
+        match self.email {
+            Some(ref email) => format!("{} <{}>", self.author, email),
+            None => self.author.clone(),
+        }
+    }
+}
+
+// Collapses two replacements of the same binary contents (the second one replacing the contents
+// that the first one put in place) into one.
+fn compose_binary(first: &Change, second: Change) -> Result<Change, Error> {
+    use crate::chunk;
+
+    match (first, second) {
+        (Change::BinaryReplace { old, .. }, Change::BinaryReplace { new_blob, .. }) => {
+            Ok(Change::BinaryReplace {
+                old: *old,
+                new_blob,
+            })
+        }
+        (Change::ChunkedReplace { old, .. }, Change::BinaryReplace { new_blob, .. }) => {
+            Ok(Change::BinaryReplace {
+                old: Some(*old),
+                new_blob,
+            })
+        }
+        (Change::BinaryReplace { old, new_blob }, Change::ChunkedReplace { hash, chunks, .. }) => {
+            Ok(Change::BinaryReplace {
+                old: *old,
+                new_blob: chunk::assemble(new_blob, &chunks).ok_or(Error::InvalidChunks(hash))?,
+            })
+        }
+        (
+            Change::ChunkedReplace {
+                old,
+                chunks: first_chunks,
+                ..
+            },
+            Change::ChunkedReplace { hash, chunks, .. },
+        ) => Ok(Change::ChunkedReplace {
+            old: *old,
+            hash,
+            chunks: chunk::compose(first_chunks, &chunks).ok_or(Error::InvalidChunks(hash))?,
+        }),
+        _ => panic!("expected two binary replacements"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Repo;
+
+    fn unidentified_patch() -> UnidentifiedPatch {
+        let changes = Changes {
+            changes: vec![
+                Change::NewNode {
+                    id: NodeId::staged(0),
+                    contents: b"first".to_vec(),
+                },
+                Change::NewNode {
+                    id: NodeId::staged(1),
+                    contents: b"second".to_vec(),
+                },
+                Change::NewEdge {
+                    src: NodeId::staged(0),
+                    dest: NodeId::staged(1),
+                },
+            ],
+        };
+        UnidentifiedPatch::new("Author".to_owned(), "Description".to_owned(), changes)
+    }
+
+    #[test]
+    fn prefixes() {
+        // These ids are "PAAAA...", "PAAAB..." and "PAQAA..." in base64.
+        let mut a = PatchId::staging();
+        a.data[0] = 0;
+        a.data[31] = 1;
+        let mut b = a;
+        b.data[2] = 1;
+        let mut c = a;
+        c.data[0] = 1;
+        let ids = vec![a, b, c];
+
+        assert_eq!(resolve_prefix(&ids, "PAQ").unwrap(), c);
+        assert_eq!(resolve_prefix(&ids, &b.to_base64()[..5]).unwrap(), b);
+        match resolve_prefix(&ids, "PAA") {
+            Err(Error::AmbiguousPatchPrefix(_, cands)) => assert_eq!(cands, vec![a, b]),
+            x => panic!("unexpected result {:?}", x),
+        }
+        match resolve_prefix(&ids, "PB") {
+            Err(Error::UnknownPatchPrefix(_)) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+
+        // Short prefixes are padded out to the minimum length.
+        assert_eq!(unique_prefix_len(&ids, &c), MIN_PATCH_PREFIX_LEN);
+        assert_eq!(unique_prefix_len(&ids, &a), MIN_PATCH_PREFIX_LEN);
+
+        // d agrees with a for the first 20 bytes, so it needs a longer prefix.
+        let mut d = a;
+        d.data[20] = 1;
+        let len = unique_prefix_len(&[a, d], &a);
+        assert!(len > MIN_PATCH_PREFIX_LEN);
+        assert_ne!(&a.to_base64()[..len], &d.to_base64()[..len]);
+        assert_eq!(&a.to_base64()[..len - 1], &d.to_base64()[..len - 1]);
+    }
+
+    #[test]
+    fn write_then_read() {
+        let up = unidentified_patch();
+        let expected_id = up.id().unwrap();
+        let mut data = Vec::new();
+        let patch = up.write_out(&mut data).unwrap();
+        assert_eq!(patch.id(), &expected_id);
+        assert!(!patch.id().is_staging());
+
+        let read = Patch::from_reader(&data[..]).unwrap();
+        assert_eq!(read, patch);
+        // Reading the patch back replaces the staging ids with the real one.
+        let expected_node = NodeId {
+            patch: expected_id,
+            node: 0,
+        };
+        assert_eq!(
+            read.changes().changes[0],
+            Change::NewNode {
+                id: expected_node,
+                contents: b"first".to_vec(),
+            }
+        );
+    }
+
+    // The id shouldn't depend on the formatting of the serialized patch.
+    #[test]
+    fn id_is_canonical() {
+        let mut data = Vec::new();
+        let patch = unidentified_patch().write_out(&mut data).unwrap();
+
+        let mut reformatted = b"# A comment that doesn't change the patch.\n".to_vec();
+        reformatted.extend_from_slice(&data);
+        reformatted.extend_from_slice(b"\n\n");
+        let read = Patch::from_reader_with_id(&reformatted[..], patch.id()).unwrap();
+        assert_eq!(read, patch);
+    }
+
+    #[test]
+    fn metadata() {
+        let mut header =
+            PatchHeader::new("Author".to_owned(), "Summary\n\nMore details.".to_owned());
+        header.email = Some("author@example.com".to_owned());
+        header.extra.insert("key".to_owned(), "value".to_owned());
+        assert_eq!(header.summary(), "Summary");
+        assert_eq!(header.full_author(), "Author <author@example.com>");
+
+        let up = UnidentifiedPatch::with_header(header.clone(), unidentified_patch().changes);
+        let mut data = Vec::new();
+        let patch = up.write_out(&mut data).unwrap();
+        let read = Patch::from_reader_with_id(&data[..], patch.id()).unwrap();
+        assert_eq!(read.header(), &header);
+    }
+
+    // Empty optional metadata isn't serialized, so that adding metadata fields doesn't change the
+    // ids of existing patches.
+    #[test]
+    fn empty_metadata() {
+        let data = serde_yaml::to_string(&unidentified_patch()).unwrap();
+        assert!(!data.contains("email"));
+        assert!(!data.contains("extra"));
+    }
+
+    #[test]
+    fn deps() {
+        let dep1 = PatchId { data: [1; 32] };
+        let dep2 = PatchId { data: [2; 32] };
+        let node = |patch, node| NodeId { patch, node };
+        let changes = Changes {
+            changes: vec![
+                Change::NewNode {
+                    id: NodeId::staged(0),
+                    contents: vec![],
+                },
+                Change::NewEdge {
+                    src: node(dep2, 3),
+                    dest: NodeId::staged(0),
+                },
+                Change::DeleteNode { id: node(dep1, 0) },
+                Change::DeleteNode { id: node(dep2, 0) },
+            ],
+        };
+        let up = UnidentifiedPatch::new("Author".to_owned(), "Description".to_owned(), changes);
+        let patch = up.write_out(Vec::new()).unwrap();
+        assert_eq!(patch.deps(), &[dep1, dep2]);
+    }
+
+    #[test]
+    fn compose() {
+        let mut repo = Repo::init_tmp();
+        let create = |repo: &mut Repo, contents: &[u8]| {
+            let diff = repo.diff("master", contents).unwrap();
+            let changes = Changes::from_diff(&diff.file_a, &diff.file_b, &diff.diff);
+            let id = repo.create_patch("Author", "Msg", changes).unwrap();
+            repo.apply_patch("master", &id).unwrap();
+            repo.open_patch(&id).unwrap()
+        };
+        let p1 = create(&mut repo, b"a\nb\n");
+        let p2 = create(&mut repo, b"a\nc\n");
+        let p3 = create(&mut repo, b"c\nd\n");
+
+        let header = PatchHeader::new("Author".to_owned(), "Composed".to_owned());
+        let composed = Patch::compose(&[p2.clone(), p3.clone()], header.clone()).unwrap();
+        assert_eq!(composed.deps, vec![*p1.id()]);
+        let mut composed_data = Vec::new();
+        composed.write_out(&mut composed_data).unwrap();
+
+        let mut other_repo = Repo::init_tmp();
+        for data in &[repo.open_patch_data(p1.id()).unwrap(), &composed_data[..]] {
+            let id = other_repo.register_patch(data).unwrap();
+            other_repo.apply_patch("master", &id).unwrap();
+        }
+        assert_eq!(
+            other_repo.file("master").unwrap().as_bytes(),
+            repo.file("master").unwrap().as_bytes()
+        );
+
+        // The patches need to be given in order.
+        match Patch::compose(&[p3, p2], header) {
+            Err(Error::DependencyOrder(..)) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn compose_authors() {
+        let mut repo = Repo::init_tmp();
+        let mut create = |author: &str, contents: &[u8]| {
+            let diff = repo.diff("master", contents).unwrap();
+            let id = repo.create_patch(author, "Msg", diff.changes()).unwrap();
+            repo.apply_patch("master", &id).unwrap();
+            repo.open_patch(&id).unwrap()
+        };
+        let p1 = create("Alice", b"a\n");
+        let p2 = create("Bob", b"a\nb\n");
+
+        let header = PatchHeader::new("Alice".to_owned(), "Composed".to_owned());
+        let composed = Patch::compose(&[p1, p2], header)
+            .unwrap()
+            .write_out(Vec::new())
+            .unwrap();
+        assert_eq!(composed.header().author, "Alice");
+        let authors = composed
+            .changes()
+            .changes
+            .iter()
+            .filter_map(|ch| match ch {
+                Change::NewNode { id, contents } => {
+                    Some((contents.clone(), composed.line_author(id).unwrap()))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            authors,
+            vec![(b"a\n".to_vec(), "Alice"), (b"b\n".to_vec(), "Bob")]
+        );
+        // Only the lines with a different author are recorded.
+        assert_eq!(composed.header().line_authors.len(), 1);
+
+        // The line authors survive being composed again.
+        let header = PatchHeader::new("Carol".to_owned(), "Recomposed".to_owned());
+        let recomposed = Patch::compose(&[composed], header).unwrap();
+        let line_authors = recomposed.header.line_authors.values().collect::<Vec<_>>();
+        assert_eq!(line_authors, vec!["Alice", "Bob"]);
+    }
+
+    #[test]
+    fn line_authors() {
+        let mut repo = Repo::init_tmp();
+        let diff = repo.diff("master", b"a\nb\n").unwrap();
+        let mut header = PatchHeader::new("Alice".to_owned(), "Msg".to_owned());
+        header.line_authors.insert(1, "Bob".to_owned());
+        let id = repo
+            .create_patch_with_header(header.clone(), diff.changes())
+            .unwrap();
+        repo.apply_patch("master", &id).unwrap();
+        let file = repo.file("master").unwrap();
+        assert_eq!(repo.line_author(file.node_id(0)).unwrap(), "Alice");
+        assert_eq!(repo.line_author(file.node_id(1)).unwrap(), "Bob");
+
+        // The patch can only record the authors of the lines that it adds.
+        header.line_authors.insert(2, "Carol".to_owned());
+        match repo.create_patch_with_header(header, diff.changes()) {
+            Err(Error::UnknownNode(n)) => assert_eq!(n.node, 2),
+            x => panic!("expected an error, got {:?}", x),
+        }
+    }
+
+    // A patch with fixed metadata, so that its serialization never changes.
+    fn fixed_patch(changes: Vec<Change>) -> UnidentifiedPatch {
+        let mut header = PatchHeader::new("Author".to_owned(), "Description".to_owned());
+        header.timestamp = "2019-01-01T00:00:00Z".parse().unwrap();
+        UnidentifiedPatch::with_header(header, Changes { changes })
+    }
+
+    // Test vectors for the canonical serialization: if any of these fail, the ids of existing
+    // patches have changed.
+    #[test]
+    fn canonical_vectors() {
+        let empty = fixed_patch(vec![]);
+        assert_eq!(
+            String::from_utf8(empty.canonical_bytes().unwrap()).unwrap(),
+            "---\nchanges: []\nheader:\n  author: Author\n  description: Description\n  \
+             timestamp: \"2019-01-01T00:00:00Z\"\ndeps: []"
+        );
+
+        let dep_node = NodeId {
+            patch: PatchId { data: [1; 32] },
+            node: 2,
+        };
+        let vectors = vec![
+            (vec![], "POznMUJ7hFJ0FnB_hdvp92tbyJUdB-PE76X4a7AFugmo="),
+            (
+                vec![
+                    Change::NewNode {
+                        id: NodeId::staged(0),
+                        contents: b"line\n".to_vec(),
+                    },
+                    Change::NewEdge {
+                        src: dep_node,
+                        dest: NodeId::staged(0),
+                    },
+                    Change::DeleteNode { id: dep_node },
+                ],
+                "PUS5cLLiNtJzeJjyfA7SBBGaLMl4powhXM2ZL3IxIvVw=",
+            ),
+            (
+                vec![
+                    Change::NewFile {
+                        path: "a".to_owned(),
+                    },
+                    Change::BinaryReplace {
+                        old: None,
+                        new_blob: vec![0, 1, 2],
+                    },
+                ],
+                "PIPMfwCbD-Ar_xiH4M4YQemVsvatd53lCQjY9kLMXuZI=",
+            ),
+        ];
+        for (changes, id) in vectors {
+            assert_eq!(fixed_patch(changes).id().unwrap().to_base64(), id);
+        }
+    }
+
+    // The order of the changes to the graph structure doesn't affect the id.
+    #[test]
+    fn canonical_order() {
+        let new_node = |n| Change::NewNode {
+            id: NodeId::staged(n),
+            contents: vec![],
+        };
+        let new_edge = Change::NewEdge {
+            src: NodeId::staged(0),
+            dest: NodeId::staged(1),
+        };
+        let new_file = |path: &str| Change::NewFile {
+            path: path.to_owned(),
+        };
+
+        let ordered = fixed_patch(vec![
+            new_node(0),
+            new_node(1),
+            new_edge.clone(),
+            new_file("b"),
+            new_file("a"),
+        ]);
+        let shuffled = fixed_patch(vec![
+            new_file("b"),
+            new_edge.clone(),
+            new_node(1),
+            new_edge,
+            new_file("a"),
+            new_node(0),
+        ]);
+        assert_eq!(ordered, shuffled);
+        assert_eq!(ordered.id().unwrap(), shuffled.id().unwrap());
+
+        // The file changes keep their order.
+        let swapped = fixed_patch(vec![new_node(0), new_node(1), new_file("a"), new_file("b")]);
+        assert_ne!(ordered.id().unwrap(), swapped.id().unwrap());
+    }
+
+    // Patches that were created before changes were put in canonical order keep their ids.
+    #[test]
+    fn non_canonical_patch() {
+        let mut up = fixed_patch(vec![]);
+        up.changes.changes = vec![
+            Change::NewEdge {
+                src: NodeId::staged(0),
+                dest: NodeId::staged(1),
+            },
+            Change::NewNode {
+                id: NodeId::staged(1),
+                contents: vec![],
+            },
+            Change::NewNode {
+                id: NodeId::staged(0),
+                contents: vec![],
+            },
+        ];
+        let mut data = Vec::new();
+        let patch = up.clone().write_out(&mut data).unwrap();
+        let read = Patch::from_reader_with_id(&data[..], patch.id()).unwrap();
+        assert_eq!(read.to_unidentified(), up);
+    }
+
+    #[test]
+    fn wrong_id() {
+        let mut data = Vec::new();
+        unidentified_patch().write_out(&mut data).unwrap();
+        match Patch::from_reader_with_id(&data[..], &PatchId::staging()) {
+            Err(Error::IdMismatch(_, expected)) => assert!(expected.is_staging()),
+            _ => panic!("expected an id mismatch"),
+        }
+    }
 }
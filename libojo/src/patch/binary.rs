@@ -0,0 +1,555 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// The binary patch file format. See `Patch::read_binary` for a description.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::io::{self, prelude::*};
+
+use super::{Change, Changes, Patch, PatchHeader, PatchId, UnidentifiedPatch};
+use crate::Error;
+
+/// The bytes at the beginning of every binary patch file.
+pub const BINARY_MAGIC: &[u8; 8] = b"OJOPATCH";
+
+/// The current version of the binary patch file format.
+pub const BINARY_FORMAT_VERSION: u32 = 1;
+
+// The header section of a patch file.
+#[derive(Deserialize, Serialize)]
+struct Header {
+    id: PatchId,
+    deps: Vec<PatchId>,
+    // The patch metadata, serialized as YAML. `PatchHeader` has optional fields that are skipped
+    // when serializing, so it needs a self-describing format.
+    metadata: String,
+}
+
+pub(crate) fn write_section<W: Write>(mut writer: W, data: &[u8]) -> Result<(), Error> {
+    writer.write_u64::<LittleEndian>(data.len() as u64)?;
+    writer.write_all(data)?;
+    Ok(())
+}
+
+pub(crate) fn read_section<R: Read>(mut reader: R) -> Result<Vec<u8>, Error> {
+    let len = reader.read_u64::<LittleEndian>()?;
+    let mut ret = Vec::new();
+    reader.take(len).read_to_end(&mut ret)?;
+    if (ret.len() as u64) < len {
+        Err(Error::TruncatedPatchFile)
+    } else {
+        Ok(ret)
+    }
+}
+
+// Deserializes the contents of a section. Bincode trusts the lengths that it reads, so without a
+// limit a corrupted length inside the section could make it try to allocate far more memory than
+// the whole section takes up.
+fn deserialize_section<T: DeserializeOwned>(data: &[u8]) -> Result<T, Error> {
+    bincode::config()
+        .limit(data.len() as u64)
+        .deserialize(data)
+        .map_err(bincode_error)
+}
+
+// Running out of input (or out of the section being read) while deserializing means that the patch
+// file was truncated.
+fn bincode_error(e: bincode::Error) -> Error {
+    match *e {
+        bincode::ErrorKind::Io(ref io_err) if io_err.kind() == io::ErrorKind::UnexpectedEof => {
+            Error::TruncatedPatchFile
+        }
+        bincode::ErrorKind::SizeLimit => Error::TruncatedPatchFile,
+        _ => Error::Bincode(e),
+    }
+}
+
+pub(super) fn write<W: Write>(patch: &Patch, mut writer: W) -> Result<(), Error> {
+    // The patch file stores the changes as they were before the id was assigned, because
+    // otherwise we couldn't check the id when reading it back in.
+    let up = patch.to_unidentified();
+    let header = Header {
+        id: patch.id,
+        deps: up.deps,
+        metadata: serde_yaml::to_string(&up.header)?,
+    };
+
+    writer.write_all(BINARY_MAGIC)?;
+    writer.write_u32::<LittleEndian>(BINARY_FORMAT_VERSION)?;
+    write_section(&mut writer, &bincode::serialize(&header)?)?;
+    write_section(&mut writer, &bincode::serialize(&up.changes)?)?;
+    Ok(())
+}
+
+// Reads and checks the magic bytes and the version.
+fn read_preamble<R: Read>(mut reader: R) -> Result<(), Error> {
+    let mut magic = [0; 8];
+    reader
+        .read_exact(&mut magic)
+        .map_err(|_| Error::NotAPatchFile)?;
+    if &magic != BINARY_MAGIC {
+        return Err(Error::NotAPatchFile);
+    }
+    let version = reader.read_u32::<LittleEndian>()?;
+    if version != BINARY_FORMAT_VERSION {
+        return Err(Error::UnsupportedPatchVersion(version));
+    }
+    Ok(())
+}
+
+pub(super) fn read<R: Read>(mut reader: R) -> Result<Patch, Error> {
+    read_preamble(&mut reader)?;
+    let header: Header = deserialize_section(&read_section(&mut reader)?)?;
+    let changes: Changes = deserialize_section(&read_section(&mut reader)?)?;
+    let metadata: PatchHeader = serde_yaml::from_str(&header.metadata)?;
+    let up = UnidentifiedPatch {
+        changes,
+        header: metadata,
+        deps: header.deps,
+    };
+    let id = up.id()?;
+    if id != header.id {
+        return Err(Error::IdMismatch(id, header.id));
+    }
+    Ok(up.set_id(id))
+}
+
+// Computes the id of a patch (see `UnidentifiedPatch::id`) while seeing its changes one at a time.
+//
+// The id is the hash of the YAML serialization of the whole patch. YAML serializes every element
+// of a list in the same way, no matter where in the list it appears, so the serialization of the
+// list of changes can be produced one change at a time.
+struct IdHasher {
+    hasher: Sha256,
+    empty: bool,
+}
+
+// A patch with a single change, whose serialization contains the serialization of that change as
+// an element of the list of changes.
+#[derive(Serialize)]
+struct OneChange<'a> {
+    changes: &'a [Change],
+}
+
+// The serialization of every patch starts like this, followed either by " []" or by the
+// serialized changes.
+const CHANGES_PREFIX: &[u8] = b"---\nchanges:";
+
+impl IdHasher {
+    fn new() -> IdHasher {
+        IdHasher {
+            hasher: Sha256::default(),
+            empty: true,
+        }
+    }
+
+    // Adds a change (with staging ids, as in `UnidentifiedPatch`) to the hash.
+    fn push(&mut self, ch: &Change) -> Result<(), Error> {
+        let data = serde_yaml::to_vec(&OneChange {
+            changes: std::slice::from_ref(ch),
+        })?;
+        debug_assert!(data.starts_with(CHANGES_PREFIX));
+        if self.empty {
+            self.hasher.input(CHANGES_PREFIX);
+            self.empty = false;
+        }
+        self.hasher.input(&data[CHANGES_PREFIX.len()..]);
+        Ok(())
+    }
+
+    // Adds the rest of the patch to the hash, and returns the id.
+    fn finish(mut self, header: &PatchHeader, deps: &[PatchId]) -> Result<PatchId, Error> {
+        let rest = UnidentifiedPatch {
+            changes: Changes { changes: vec![] },
+            header: header.clone(),
+            deps: deps.to_vec(),
+        };
+        let data = serde_yaml::to_vec(&rest)?;
+        let empty_prefix_len = CHANGES_PREFIX.len() + b" []".len();
+        debug_assert!(data.starts_with(CHANGES_PREFIX));
+        if self.empty {
+            self.hasher.input(&data);
+        } else {
+            self.hasher.input(&data[empty_prefix_len..]);
+        }
+
+        let mut ret = PatchId::staging();
+        ret.data.copy_from_slice(&self.hasher.result()[..]);
+        Ok(ret)
+    }
+}
+
+/// Reads the changes in a binary patch file (see [`Patch::read_binary`]) one at a time.
+///
+/// Unlike [`Patch::read_binary`], this never holds more than one change in memory, so it can be
+/// used to process patches that add very large files. However, the id of the patch can only be
+/// checked once all of the changes have been read: if it doesn't match, the last item returned by
+/// this iterator is [`Error::IdMismatch`]. Any changes that were read before that should be
+/// discarded.
+///
+/// The changes that this returns refer to the patch by its id (like the changes in a [`Patch`],
+/// and unlike the ones in an [`UnidentifiedPatch`]).
+pub struct ChangeReader<R> {
+    reader: io::Take<R>,
+    id: PatchId,
+    header: PatchHeader,
+    deps: Vec<PatchId>,
+    // The number of changes that haven't been read yet.
+    remaining: u64,
+    // This is `None` once we have finished reading (either successfully or not).
+    hasher: Option<IdHasher>,
+}
+
+impl<R: Read> ChangeReader<R> {
+    /// Reads the header of a binary patch file, leaving the changes to be read later.
+    pub fn new(mut reader: R) -> Result<ChangeReader<R>, Error> {
+        read_preamble(&mut reader)?;
+        let header: Header = deserialize_section(&read_section(&mut reader)?)?;
+        let metadata: PatchHeader = serde_yaml::from_str(&header.metadata)?;
+
+        // The changes section has the same format as the other sections, but instead of reading it
+        // all at once we read the changes one by one. Bincode serializes a list as its length
+        // followed by its elements.
+        let len = reader.read_u64::<LittleEndian>()?;
+        let mut reader = reader.take(len);
+        let remaining = reader
+            .read_u64::<LittleEndian>()
+            .map_err(|e| bincode_error(e.into()))?;
+        Ok(ChangeReader {
+            reader,
+            id: header.id,
+            header: metadata,
+            deps: header.deps,
+            remaining,
+            hasher: Some(IdHasher::new()),
+        })
+    }
+
+    /// The id of the patch, as claimed by the patch file.
+    pub fn id(&self) -> &PatchId {
+        &self.id
+    }
+
+    /// The patch's metadata.
+    pub fn header(&self) -> &PatchHeader {
+        &self.header
+    }
+
+    /// The patches that this patch depends on.
+    pub fn deps(&self) -> &[PatchId] {
+        &self.deps
+    }
+
+    fn next_change(&mut self) -> Result<Option<Change>, Error> {
+        if self.hasher.is_none() {
+            return Ok(None);
+        }
+        if self.remaining == 0 {
+            // The unwrap is ok because we just checked that the hasher is there.
+            let id = self
+                .hasher
+                .take()
+                .unwrap()
+                .finish(&self.header, &self.deps)?;
+            if id != self.id {
+                return Err(Error::IdMismatch(id, self.id));
+            }
+            return Ok(None);
+        }
+
+        // The limit is what's left of the changes section (see `deserialize_section`).
+        let mut ch: Change = bincode::config()
+            .limit(self.reader.limit())
+            .deserialize_from(&mut self.reader)
+            .map_err(bincode_error)?;
+        self.remaining -= 1;
+        // The unwrap is ok because we checked at the beginning that the hasher is there.
+        self.hasher.as_mut().unwrap().push(&ch)?;
+        ch.set_patch_id(&self.id);
+        Ok(Some(ch))
+    }
+}
+
+impl<R: Read> Iterator for ChangeReader<R> {
+    type Item = Result<Change, Error>;
+
+    fn next(&mut self) -> Option<Result<Change, Error>> {
+        let ret = self.next_change();
+        if ret.is_err() {
+            self.hasher = None;
+        }
+        ret.transpose()
+    }
+}
+
+// Writes a patch in the binary format, without holding all of its changes in memory at once. See
+// `Patch::write_binary_stream`.
+pub(super) fn write_stream<W, F, I>(
+    mut writer: W,
+    header: &PatchHeader,
+    changes: F,
+) -> Result<PatchId, Error>
+where
+    W: Write,
+    F: Fn() -> I,
+    I: IntoIterator<Item = Change>,
+{
+    // The header contains the id and the dependencies, and the changes section is prefixed by its
+    // length, so we need to go through the changes once before writing anything.
+    let mut hasher = IdHasher::new();
+    let mut deps = BTreeSet::new();
+    let mut count = 0u64;
+    // The changes section starts with the number of changes.
+    let mut len = 8u64;
+    for ch in changes() {
+        hasher.push(&ch)?;
+        ch.add_deps(&mut deps);
+        count += 1;
+        len += bincode::serialized_size(&ch)?;
+    }
+    deps.remove(&PatchId::staging());
+    let deps = deps.into_iter().collect::<Vec<_>>();
+    let id = hasher.finish(header, &deps)?;
+
+    let file_header = Header {
+        id,
+        deps,
+        metadata: serde_yaml::to_string(header)?,
+    };
+    writer.write_all(BINARY_MAGIC)?;
+    writer.write_u32::<LittleEndian>(BINARY_FORMAT_VERSION)?;
+    write_section(&mut writer, &bincode::serialize(&file_header)?)?;
+    writer.write_u64::<LittleEndian>(len)?;
+    writer.write_u64::<LittleEndian>(count)?;
+    let mut written = 0u64;
+    for ch in changes() {
+        bincode::serialize_into(&mut writer, &ch)?;
+        written += 1;
+    }
+    assert_eq!(written, count, "the changes were different the second time");
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Change, NodeId};
+
+    fn patch() -> Patch {
+        let dep = PatchId { data: [1; 32] };
+        let changes = Changes {
+            changes: vec![
+                Change::NewNode {
+                    id: NodeId::staged(0),
+                    contents: b"line".to_vec(),
+                },
+                Change::NewEdge {
+                    src: NodeId::staged(0),
+                    dest: NodeId {
+                        patch: dep,
+                        node: 1,
+                    },
+                },
+            ],
+        };
+        UnidentifiedPatch::new("Author".to_owned(), "Description".to_owned(), changes)
+            .write_out(Vec::new())
+            .unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let patch = patch();
+        let mut data = Vec::new();
+        patch.write_binary(&mut data).unwrap();
+        assert_eq!(&data[..8], BINARY_MAGIC);
+        assert_eq!(Patch::read_binary(&data[..]).unwrap(), patch);
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut data = Vec::new();
+        patch().write_binary(&mut data).unwrap();
+        data[0] = b'X';
+        match Patch::read_binary(&data[..]) {
+            Err(Error::NotAPatchFile) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn bad_version() {
+        let mut data = Vec::new();
+        patch().write_binary(&mut data).unwrap();
+        data[8] = 100;
+        match Patch::read_binary(&data[..]) {
+            Err(Error::UnsupportedPatchVersion(100)) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let mut data = Vec::new();
+        patch().write_binary(&mut data).unwrap();
+        data.pop();
+        match Patch::read_binary(&data[..]) {
+            Err(Error::TruncatedPatchFile) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    // A length that is bigger than what's left of the input is an error, not an attempt to allocate
+    // that much.
+    #[test]
+    fn oversized() {
+        let mut data = Vec::new();
+        patch().write_binary(&mut data).unwrap();
+        let huge = (u64::MAX >> 1).to_le_bytes();
+
+        // The length of the header section.
+        let mut section = data.clone();
+        section[12..20].copy_from_slice(&huge);
+        match Patch::read_binary(&section[..]) {
+            Err(Error::TruncatedPatchFile) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+        match ChangeReader::new(&section[..]) {
+            Err(Error::TruncatedPatchFile) => {}
+            x => panic!("unexpected result {:?}", x.map(|r| r.id)),
+        }
+
+        // The length of the contents of a new node.
+        let pos = data.windows(4).position(|w| w == b"line").unwrap();
+        data[(pos - 8)..pos].copy_from_slice(&huge);
+        match Patch::read_binary(&data[..]) {
+            Err(Error::TruncatedPatchFile) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+        let mut reader = ChangeReader::new(&data[..]).unwrap();
+        match reader.next() {
+            Some(Err(Error::TruncatedPatchFile)) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn tampered_contents() {
+        let mut data = Vec::new();
+        patch().write_binary(&mut data).unwrap();
+        // Modifying the payload should either make it invalid, or change the id.
+        let len = data.len();
+        data[len - 30] ^= 1;
+        assert!(Patch::read_binary(&data[..]).is_err());
+    }
+
+    fn many_changes() -> Vec<Change> {
+        let dep = PatchId { data: [1; 32] };
+        vec![
+            Change::NewNode {
+                id: NodeId::staged(0),
+                contents: b"line: with \"yaml\"\n".to_vec(),
+            },
+            Change::NewNode {
+                id: NodeId::staged(1),
+                contents: b"\xff\0\n".to_vec(),
+            },
+            Change::NewEdge {
+                src: NodeId::staged(0),
+                dest: NodeId::staged(1),
+            },
+            Change::DeleteNode {
+                id: NodeId {
+                    patch: dep,
+                    node: 3,
+                },
+            },
+            Change::NewFile {
+                path: "dir/file name".to_owned(),
+            },
+            Change::BinaryReplace {
+                old: None,
+                new_blob: vec![0; 100],
+            },
+        ]
+    }
+
+    #[test]
+    fn change_reader() {
+        for changes in [vec![], many_changes()].iter().cloned() {
+            let changes = Changes { changes };
+            let header = PatchHeader::new("Author".to_owned(), "Multi\nline".to_owned());
+            let patch = UnidentifiedPatch::with_header(header, changes)
+                .write_out(Vec::new())
+                .unwrap();
+            let mut data = Vec::new();
+            patch.write_binary(&mut data).unwrap();
+
+            let mut reader = ChangeReader::new(&data[..]).unwrap();
+            assert_eq!(reader.id(), patch.id());
+            assert_eq!(reader.header(), patch.header());
+            assert_eq!(reader.deps(), patch.deps());
+            let read = reader.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
+            assert_eq!(read, patch.changes().changes);
+            assert!(reader.next().is_none());
+        }
+    }
+
+    #[test]
+    fn change_reader_tampered() {
+        let patch = patch();
+        let mut data = Vec::new();
+        patch.write_binary(&mut data).unwrap();
+        let pos = data.windows(4).position(|w| w == b"line").unwrap();
+        data[pos + 2] = b'm';
+
+        let mut reader = ChangeReader::new(&data[..]).unwrap();
+        assert!(reader.next().unwrap().is_ok());
+        assert!(reader.next().unwrap().is_ok());
+        match reader.next() {
+            Some(Err(Error::IdMismatch(_, expected))) => assert_eq!(&expected, patch.id()),
+            x => panic!("unexpected result {:?}", x),
+        }
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn change_reader_truncated() {
+        let mut data = Vec::new();
+        patch().write_binary(&mut data).unwrap();
+        data.pop();
+        let mut reader = ChangeReader::new(&data[..]).unwrap();
+        assert!(reader.next().unwrap().is_ok());
+        match reader.next() {
+            Some(Err(Error::TruncatedPatchFile)) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn write_stream() {
+        let changes = many_changes();
+        let header = PatchHeader::new("Author".to_owned(), "Description".to_owned());
+        let mut data = Vec::new();
+        let id =
+            Patch::write_binary_stream(&mut data, &header, || changes.iter().cloned()).unwrap();
+
+        let expected = UnidentifiedPatch::with_header(header, Changes { changes });
+        assert_eq!(id, expected.id().unwrap());
+        let read = Patch::read_binary(&data[..]).unwrap();
+        assert_eq!(read, expected.write_out(Vec::new()).unwrap());
+    }
+}
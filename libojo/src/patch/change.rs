@@ -10,9 +10,14 @@
 // of this distribution.
 
 use ojo_diff::LineDiff;
+use std::collections::{BTreeMap, BTreeSet};
 
+use crate::blob::{BlobHash, BlobRef};
+use crate::chunk::BlobChunk;
+use crate::storage::graggle::GraggleData;
 use crate::storage::File;
-use crate::{NodeId, PatchId};
+use crate::tree::FileRef;
+use crate::{Error, NodeId, PatchId};
 
 /// A set of [`Change`]s.
 ///
@@ -93,12 +98,301 @@ impl Changes {
         Changes { changes }
     }
 
+    /// Returns the patches that these changes depend on, in sorted order.
+    ///
+    /// These are the patches that introduced the nodes that we delete or attach new edges to (and
+    /// the patches that introduced the binary contents and the files that we replace, delete or
+    /// move, or whose lines we change), not including the staging id [`PatchId::staging`] (which
+    /// refers to the patch that these changes belong to).
+    pub fn deps(&self) -> Vec<PatchId> {
+        let mut deps = BTreeSet::new();
+        for c in &self.changes {
+            c.add_deps(&mut deps);
+        }
+        deps.remove(&PatchId::staging());
+        deps.into_iter().collect()
+    }
+
+    /// Returns the patches that the changes to each file (see [`Change::EditFile`]) depend on.
+    ///
+    /// This is like [`Changes::deps`], but computed file-by-file: the changes to a file depend on
+    /// the patch that created the file and on the patches that introduced the lines that they
+    /// refer to.
+    pub fn file_deps(&self) -> BTreeMap<FileRef, Vec<PatchId>> {
+        let mut ret = BTreeMap::new();
+        for ch in &self.changes {
+            if let Change::EditFile { ref file, .. } = *ch {
+                ret.entry(file.clone()).or_insert_with(BTreeSet::new);
+                ch.add_deps(ret.get_mut(file).unwrap());
+            }
+        }
+        ret.into_iter()
+            .map(|(file, mut deps)| {
+                deps.remove(&PatchId::staging());
+                (file, deps.into_iter().collect())
+            })
+            .collect()
+    }
+
+    /// Adds some changes to the lines of a file.
+    ///
+    /// `changes` should only contain changes to lines (like the ones produced by
+    /// [`Changes::from_diff`]), and `file` is the identity of the file that they apply to (see
+    /// [`Change::EditFile`]). The new lines in `changes` are renumbered so that they don't clash
+    /// with any of the new lines that are already here, which means that the changes to several
+    /// files can be put together into one patch.
+    pub fn add_file_changes(&mut self, file: FileRef, mut changes: Changes) {
+        let offset = self
+            .flattened()
+            .filter_map(|(_, ch)| match ch {
+                Change::NewNode { id, .. } if id.is_staged() => Some(id.node + 1),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+        for ch in &mut changes.changes {
+            for id in ch.node_ids_mut() {
+                if id.is_staged() {
+                    id.node += offset;
+                }
+            }
+        }
+        self.changes.push(Change::EditFile { file, changes });
+    }
+
+    // Iterates over all of the changes, including the ones that modify the lines of files (but not
+    // the `EditFile` changes that contain them). Each change comes with the file that it modifies,
+    // or `None` for changes that don't belong to a file.
+    pub(crate) fn flattened(&self) -> impl Iterator<Item = (Option<&FileRef>, &Change)> {
+        self.changes
+            .iter()
+            .flat_map(|ch| -> Box<dyn Iterator<Item = _>> {
+                match ch {
+                    Change::EditFile { file, changes } => {
+                        Box::new(changes.changes.iter().map(move |c| (Some(file), c)))
+                    }
+                    ch => Box::new(std::iter::once((None, ch))),
+                }
+            })
+    }
+
+    /// Puts these changes into canonical order, so that two sets of changes with the same effect
+    /// are serialized (and hence hashed) in the same way.
+    ///
+    /// The changes to the graph structure don't depend on the order in which they are applied, so
+    /// they come first: the new nodes (sorted by id), then the new edges (sorted by their
+    /// endpoints), and then the deleted nodes (sorted by id). Duplicated changes to the graph
+    /// structure are removed. Then come the changes to the lines of files, with all the changes to
+    /// each file grouped together (sorted by file, and canonicalized in the same way). The
+    /// remaining changes (to binary contents and to files) keep their order relative to one
+    /// another, because moving a file that was created in the same patch only makes sense if it
+    /// was created first.
+    pub fn canonicalize(&mut self) {
+        fn key(ch: &Change) -> (u8, Option<(NodeId, NodeId)>) {
+            match *ch {
+                Change::NewNode { ref id, .. } => (0, Some((*id, *id))),
+                Change::NewEdge { ref src, ref dest } => (1, Some((*src, *dest))),
+                Change::DeleteNode { ref id } => (2, Some((*id, *id))),
+                Change::EditFile { .. } => (3, None),
+                _ => (4, None),
+            }
+        }
+
+        let mut edits = BTreeMap::new();
+        self.changes.retain(|ch| match ch {
+            Change::EditFile { file, changes } => {
+                edits
+                    .entry(file.clone())
+                    .or_insert_with(Vec::new)
+                    .extend(changes.changes.iter().cloned());
+                false
+            }
+            _ => true,
+        });
+        for (file, changes) in edits {
+            let mut changes = Changes { changes };
+            changes.canonicalize();
+            self.changes.push(Change::EditFile { file, changes });
+        }
+
+        // The sort is stable, so the changes that don't involve the graph keep their order (and
+        // the changes to files stay sorted).
+        self.changes.sort_by_key(key);
+        self.changes.dedup_by(|a, b| key(a).1.is_some() && a == b);
+    }
+
     /// Modifies all of the changes in this changeset to have the given [`PatchId`].
     pub fn set_patch_id(&mut self, new_id: &PatchId) {
         for ch in &mut self.changes {
             ch.set_patch_id(new_id);
         }
     }
+
+    // The inverse of `set_patch_id`: replaces all references to `old_id` with the staging id.
+    pub(crate) fn unset_patch_id(&mut self, old_id: &PatchId) {
+        for ch in &mut self.changes {
+            ch.unset_patch_id(old_id);
+        }
+    }
+
+    // Checks whether these changes can be applied to a graggle, without changing anything. The
+    // only things that can go wrong are conflicts involving binary contents or files, because
+    // changes to the graph structure always succeed.
+    pub(crate) fn check_applicable(
+        &self,
+        graggle: &GraggleData,
+        patch: PatchId,
+    ) -> Result<(), Error> {
+        let mut files = graggle.files().clone();
+        for ch in &self.changes {
+            if let Some(old) = ch.replaced_blob() {
+                if graggle.binary() != old {
+                    return Err(Error::BinaryConflict(patch));
+                }
+            }
+            if let Change::EditFile {
+                ref file,
+                ref changes,
+            } = *ch
+            {
+                let current = graggle.file_graggle(file).and_then(|g| g.binary());
+                for c in &changes.changes {
+                    if let Some(old) = c.replaced_blob() {
+                        if current != old {
+                            return Err(Error::BinaryConflict(patch));
+                        }
+                    }
+                }
+            }
+            files
+                .apply(ch, patch)
+                .map_err(|path| Error::FileConflict(patch, path))?;
+        }
+        Ok(())
+    }
+
+    // Applies these changes to a graggle. `patch` is the id of the patch that these changes
+    // belong to; it gets recorded in the edges that we add.
+    //
+    // Note that this only modifies the graph structure: the contents of new nodes need to be
+    // stored separately. The changes must have already passed `check_applicable`.
+    pub(crate) fn apply_to(&self, graggle: &mut GraggleData, patch: PatchId) {
+        for ch in &self.changes {
+            match *ch {
+                Change::NewNode { ref id, .. } => {
+                    debug!("adding node {:?}", id);
+                    graggle.add_node(*id);
+                }
+                Change::DeleteNode { ref id } => {
+                    debug!("deleting node {:?}", id);
+                    graggle.delete_node(id);
+                }
+                Change::NewEdge { ref src, ref dest } => {
+                    debug!("adding edge {:?} -- {:?}", src, dest);
+                    graggle.add_edge(*src, *dest, patch);
+                }
+                Change::BinaryReplace { ref new_blob, .. } => {
+                    debug!("replacing binary contents");
+                    graggle.set_binary(Some(BlobRef {
+                        patch,
+                        hash: BlobHash::of(new_blob),
+                    }));
+                }
+                Change::ChunkedReplace { ref hash, .. } => {
+                    debug!("replacing binary contents in chunks");
+                    graggle.set_binary(Some(BlobRef { patch, hash: *hash }));
+                }
+                Change::EditFile {
+                    ref file,
+                    ref changes,
+                } => {
+                    debug!("changing the lines of {:?}", file);
+                    changes.apply_to(graggle.file_graggle_mut(file), patch);
+                }
+                Change::NewFile { ref path } => {
+                    debug!("creating file {:?}", path);
+                    graggle
+                        .files_mut()
+                        .apply(ch, patch)
+                        .expect("tried to apply a conflicting file change");
+                    let file = FileRef {
+                        patch,
+                        path: path.clone(),
+                    };
+                    graggle.file_graggle_mut(&file);
+                }
+                Change::DeleteFile { .. }
+                | Change::MoveFile { .. }
+                | Change::SetExecutable { .. }
+                | Change::SetSymlink { .. } => {
+                    debug!("changing files: {:?}", ch);
+                    graggle
+                        .files_mut()
+                        .apply(ch, patch)
+                        .expect("tried to apply a conflicting file change");
+                }
+            }
+        }
+    }
+
+    // The inverse of `apply_to`. The graggle must be in the state that `apply_to` left it in,
+    // apart from the application of other patches that don't depend on this one.
+    pub(crate) fn unapply_from(&self, graggle: &mut GraggleData, patch: PatchId) {
+        // Because of the requirements of `unadd_edge`, we need to unadd all edges before we unadd
+        // all nodes.
+        for ch in &self.changes {
+            match *ch {
+                Change::DeleteNode { ref id } => {
+                    debug!("undeleting node {:?}", id);
+                    graggle.undelete_node(id);
+                }
+                Change::NewEdge { ref src, ref dest } => {
+                    debug!("unadding edge {:?} -- {:?}", src, dest);
+                    graggle.unadd_edge(src, dest, patch);
+                }
+                Change::BinaryReplace { ref old, .. } => {
+                    debug!("restoring binary contents {:?}", old);
+                    graggle.set_binary(*old);
+                }
+                Change::ChunkedReplace { ref old, .. } => {
+                    debug!("restoring binary contents {:?}", old);
+                    graggle.set_binary(Some(*old));
+                }
+                Change::EditFile {
+                    ref file,
+                    ref changes,
+                } => {
+                    debug!("restoring the lines of {:?}", file);
+                    changes.unapply_from(graggle.file_graggle_mut(file), patch);
+                }
+                Change::NewNode { .. } => {}
+                Change::NewFile { .. }
+                | Change::DeleteFile { .. }
+                | Change::MoveFile { .. }
+                | Change::SetExecutable { .. }
+                | Change::SetSymlink { .. } => {}
+            }
+        }
+        // Changes to files need to be undone in reverse order, since a patch might (for example)
+        // create a file and then move it.
+        for ch in self.changes.iter().rev() {
+            graggle.files_mut().unapply(ch, patch);
+            if let Change::NewFile { ref path } = *ch {
+                // Every other patch that changes the lines of this file depends on this one, so
+                // they have already been unapplied.
+                graggle.remove_file_graggle(&FileRef {
+                    patch,
+                    path: path.clone(),
+                });
+            }
+        }
+        for ch in &self.changes {
+            if let Change::NewNode { ref id, .. } = *ch {
+                debug!("unadding node {:?}", id);
+                graggle.unadd_node(id);
+            }
+        }
+    }
 }
 
 /// A single change.
@@ -127,26 +421,210 @@ pub enum Change {
         /// The destination of the new edge.
         dest: NodeId,
     },
+    /// Replaces the binary contents of the file.
+    ///
+    /// Binary contents aren't divided into lines, and they don't take part in the graggle at all:
+    /// each `BinaryReplace` replaces them all at once. Two patches that both replace the same
+    /// binary contents conflict with one another, and they can't be applied to the same branch.
+    /// While a file has binary contents, they are its contents in the working copy: its lines
+    /// aren't written out.
+    BinaryReplace {
+        /// The contents that are being replaced, or `None` if there weren't any.
+        old: Option<BlobRef>,
+        /// The new contents.
+        #[serde(with = "crate::blob::bytes_base64")]
+        new_blob: Vec<u8>,
+    },
+    /// Starts tracking a new file. There must not already be a file with the same path.
+    NewFile {
+        /// The path of the new file.
+        path: String,
+    },
+    /// Stops tracking a file.
+    DeleteFile {
+        /// The file to delete.
+        file: FileRef,
+    },
+    /// Moves a file to a new path, where there must not already be a file.
+    ///
+    /// Unlike deleting a file and creating a new one, this keeps the file's history: any patches
+    /// that modify the file later will depend on the move.
+    MoveFile {
+        /// The file to move.
+        from: FileRef,
+        /// The new path of the file.
+        to: String,
+    },
+    /// Sets or clears the executable permission of a file. The permission must actually change:
+    /// it is an error to make an executable file executable.
+    SetExecutable {
+        /// The file to modify.
+        file: FileRef,
+        /// Whether the file should be executable.
+        executable: bool,
+    },
+    /// Changes the lines of a file.
+    ///
+    /// Every file has its own graggle, which is modified by `changes` (which may only contain
+    /// `NewNode`, `NewEdge`, `DeleteNode`, `BinaryReplace` and `ChunkedReplace` changes). Since a
+    /// patch can contain several of these, it can change several files at once.
+    EditFile {
+        /// The file to change, identified by the patch that created it and the path that it was
+        /// created at. Unlike the paths in the other changes, this doesn't change when the file is
+        /// moved.
+        file: FileRef,
+        /// The changes to the file's lines.
+        changes: Changes,
+    },
+    /// Replaces the binary contents of the file, like `BinaryReplace`, but without including all
+    /// of the new contents.
+    ///
+    /// Large binary contents are split into chunks (see [`CHUNK_THRESHOLD`](crate::CHUNK_THRESHOLD)), and the new contents
+    /// are given as a sequence of pieces that are either new, or copied from the contents that
+    /// they replace. A small edit to a large file only needs a small patch.
+    ChunkedReplace {
+        /// The contents that are being replaced.
+        old: BlobRef,
+        /// The hash of the new contents.
+        hash: BlobHash,
+        /// The pieces that make up the new contents.
+        chunks: Vec<BlobChunk>,
+    },
+    /// Makes a file a symbolic link, or makes it an ordinary file again. Like
+    /// `SetExecutable`, this must actually change something.
+    ///
+    /// The target of a symbolic link is the contents of the file, so it is changed in the same way
+    /// as the contents of any other file. In the working copy, the file is a link to that target
+    /// (unless symbolic links are turned off, see [`Config::symlinks`](crate::Config::symlinks)).
+    SetSymlink {
+        /// The file to modify.
+        file: FileRef,
+        /// Whether the file should be a symbolic link.
+        symlink: bool,
+    },
 }
 
 impl Change {
-    // Modifies the PatchId of this Change.
-    fn set_patch_id(&mut self, new_id: &PatchId) {
+    // Adds the patches that this change refers to (possibly including the staging id) to `deps`.
+    pub(crate) fn add_deps(&self, deps: &mut BTreeSet<PatchId>) {
         match *self {
-            Change::NewNode { ref mut id, .. } => {
-                id.set_patch_id(new_id);
+            Change::DeleteNode { ref id } => {
+                deps.insert(id.patch);
             }
-            Change::NewEdge {
-                ref mut src,
-                ref mut dest,
+            Change::NewEdge { ref src, ref dest } => {
+                deps.insert(src.patch);
+                deps.insert(dest.patch);
+            }
+            Change::BinaryReplace {
+                old: Some(ref old), ..
+            }
+            | Change::ChunkedReplace { ref old, .. } => {
+                deps.insert(old.patch);
+            }
+            Change::DeleteFile { ref file } => {
+                deps.insert(file.patch);
+            }
+            Change::MoveFile { ref from, .. } => {
+                deps.insert(from.patch);
+            }
+            Change::SetExecutable { ref file, .. } | Change::SetSymlink { ref file, .. } => {
+                deps.insert(file.patch);
+            }
+            Change::EditFile {
+                ref file,
+                ref changes,
             } => {
-                src.set_patch_id(new_id);
-                dest.set_patch_id(new_id);
+                deps.insert(file.patch);
+                for ch in &changes.changes {
+                    ch.add_deps(deps);
+                }
             }
-            Change::DeleteNode { ref mut id } => {
-                id.set_patch_id(new_id);
+            Change::NewNode { .. }
+            | Change::BinaryReplace { old: None, .. }
+            | Change::NewFile { .. } => {}
+        }
+    }
+
+    // Modifies the PatchId of this Change.
+    pub(crate) fn set_patch_id(&mut self, new_id: &PatchId) {
+        for id in self.node_ids_mut() {
+            id.set_patch_id(new_id);
+        }
+        if let Some(file) = self.file_ref_mut() {
+            if file.patch.is_staging() {
+                file.patch = *new_id;
+            }
+        }
+    }
+
+    fn unset_patch_id(&mut self, old_id: &PatchId) {
+        for id in self.node_ids_mut() {
+            if &id.patch == old_id {
+                id.patch = PatchId::staging();
             }
         }
+        if let Some(file) = self.file_ref_mut() {
+            if &file.patch == old_id {
+                file.patch = PatchId::staging();
+            }
+        }
+    }
+
+    // Returns the existing file that this change refers to, if there is one. For `EditFile`, this is
+    // the file's identity.
+    pub(crate) fn file_ref_mut(&mut self) -> Option<&mut FileRef> {
+        match *self {
+            Change::DeleteFile { ref mut file } => Some(file),
+            Change::MoveFile { ref mut from, .. } => Some(from),
+            Change::SetExecutable { ref mut file, .. } => Some(file),
+            Change::SetSymlink { ref mut file, .. } => Some(file),
+            Change::EditFile { ref mut file, .. } => Some(file),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn node_ids_mut(&mut self) -> Vec<&mut NodeId> {
+        match *self {
+            Change::NewNode { ref mut id, .. } => vec![id],
+            Change::NewEdge {
+                ref mut src,
+                ref mut dest,
+            } => vec![src, dest],
+            Change::DeleteNode { ref mut id } => vec![id],
+            Change::EditFile {
+                ref mut changes, ..
+            } => changes
+                .changes
+                .iter_mut()
+                .flat_map(|ch| ch.node_ids_mut())
+                .collect(),
+            Change::BinaryReplace { .. }
+            | Change::ChunkedReplace { .. }
+            | Change::NewFile { .. }
+            | Change::DeleteFile { .. }
+            | Change::MoveFile { .. }
+            | Change::SetExecutable { .. }
+            | Change::SetSymlink { .. } => vec![],
+        }
+    }
+
+    // If this change replaces binary contents, returns the contents that it replaces (which are
+    // `None` if there weren't any).
+    pub(crate) fn replaced_blob(&self) -> Option<Option<&BlobRef>> {
+        match *self {
+            Change::BinaryReplace { ref old, .. } => Some(old.as_ref()),
+            Change::ChunkedReplace { ref old, .. } => Some(Some(old)),
+            _ => None,
+        }
+    }
+
+    // If this change replaces binary contents, returns the hash of the new contents.
+    pub(crate) fn new_blob_hash(&self) -> Option<BlobHash> {
+        match *self {
+            Change::BinaryReplace { ref new_blob, .. } => Some(BlobHash::of(new_blob)),
+            Change::ChunkedReplace { hash, .. } => Some(hash),
+            _ => None,
+        }
     }
 }
 
@@ -165,7 +643,7 @@ mod tests {
         let diff = vec![New(0)];
 
         let expected = vec![NewNode {
-            id: NodeId::cur(0),
+            id: NodeId::staged(0),
             contents: b"something".to_vec(),
         }];
         assert_eq!(Changes::from_diff(&file1, &file2, &diff).changes, expected);
@@ -0,0 +1,204 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+use ojo_graph::Graph;
+use std::collections::{BTreeSet, HashSet};
+
+use crate::storage::graggle::GraggleData;
+use crate::storage::Graggle;
+use crate::{Change, Error, FileRef, NodeId, Patch, PatchId};
+
+/// A prediction of what would happen if a patch were applied (see [`Patch::check`]).
+#[derive(Debug)]
+pub struct ApplyReport {
+    /// The patches that the patch depends on (directly or indirectly) but that aren't applied.
+    ///
+    /// This is only filled out by [`Repo::check_patch`](crate::Repo::check_patch), because a
+    /// graggle doesn't know which patches were applied to it. Applying the patch with
+    /// [`Repo::apply_patch`](crate::Repo::apply_patch) would apply these too.
+    pub missing_deps: Vec<PatchId>,
+    /// The lines that the patch refers to, but that aren't in the graggle. If this is non-empty,
+    /// the patch can't be applied until its dependencies are.
+    pub unknown_nodes: Vec<NodeId>,
+    /// The number of lines that the patch adds.
+    pub lines_added: usize,
+    /// The number of lines that are currently live, but that the patch deletes.
+    pub lines_deleted: usize,
+    /// If applying the patch would fail because it conflicts with the binary contents or with the
+    /// files in the graggle, this is the error that it would fail with.
+    pub error: Option<Error>,
+    /// Whether the graggle (and the graggle of every file in it) is currently totally ordered
+    /// (i.e. whether it represents a file).
+    pub was_ordered: bool,
+    /// Whether the graggle would be totally ordered after applying the patch.
+    ///
+    /// If the patch can't be applied (see [`ApplyReport::is_applicable`]), this is the same as
+    /// [`ApplyReport::was_ordered`].
+    pub is_ordered: bool,
+}
+
+impl ApplyReport {
+    /// Can the patch be applied as-is, without applying anything else first?
+    pub fn is_applicable(&self) -> bool {
+        self.missing_deps.is_empty() && self.unknown_nodes.is_empty() && self.error.is_none()
+    }
+
+    /// Would applying the patch turn a totally ordered graggle into one that needs to be
+    /// resolved?
+    pub fn creates_conflicts(&self) -> bool {
+        self.was_ordered && !self.is_ordered
+    }
+}
+
+fn is_ordered(graggle: &GraggleData) -> bool {
+    graggle
+        .as_graggle()
+        .as_live_graph()
+        .linear_order()
+        .is_some()
+        && graggle.file_graggles().all(is_ordered)
+}
+
+impl Patch {
+    /// Predicts what would happen if this patch were applied to a graggle, without changing
+    /// anything.
+    pub fn check(&self, graggle: Graggle<'_>) -> ApplyReport {
+        let new_nodes = self
+            .changes()
+            .flattened()
+            .filter_map(|(_, ch)| match ch {
+                Change::NewNode { id, .. } => Some(*id),
+                _ => None,
+            })
+            .collect::<HashSet<_>>();
+        // The lines of each file are in their own graggle.
+        let graggle_for = |file: Option<&FileRef>| match file {
+            Some(file) => graggle.data().file_graggle(file).map(|g| g.as_graggle()),
+            None => Some(graggle),
+        };
+        let existing = |file, id: &NodeId| graggle_for(file).filter(|g| g.has_node(id));
+        let known = |file, id: &NodeId| new_nodes.contains(id) || existing(file, id).is_some();
+
+        let mut unknown_nodes = BTreeSet::new();
+        let mut deleted = HashSet::new();
+        for (file, ch) in self.changes().flattened() {
+            match ch {
+                Change::NewEdge { src, dest } => {
+                    unknown_nodes.extend([src, dest].iter().filter(|id| !known(file, id)).cloned());
+                }
+                Change::DeleteNode { id } => {
+                    if !known(file, id) {
+                        unknown_nodes.insert(*id);
+                    } else if existing(file, id).is_some_and(|g| g.is_live(id)) {
+                        deleted.insert(*id);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let was_ordered = is_ordered(graggle.data());
+        let error = self
+            .changes()
+            .check_applicable(graggle.data(), *self.id())
+            .err();
+        let is_ordered = if unknown_nodes.is_empty() && error.is_none() {
+            let mut data = graggle.data().clone();
+            self.apply_to(&mut data);
+            data.resolve_pseudo_edges();
+            is_ordered(&data)
+        } else {
+            was_ordered
+        };
+
+        ApplyReport {
+            missing_deps: Vec::new(),
+            unknown_nodes: unknown_nodes.into_iter().collect(),
+            lines_added: new_nodes.len(),
+            lines_deleted: deleted.len(),
+            error,
+            was_ordered,
+            is_ordered,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::create_unapplied;
+    use crate::{Error, Repo};
+
+    #[test]
+    fn check() {
+        let mut repo = Repo::init_tmp();
+        let first = create_unapplied(&mut repo, "master", b"a\nb\n");
+        let patch = repo.open_patch(&first).unwrap();
+        let report = patch.check(repo.graggle("master").unwrap());
+        assert!(report.is_applicable());
+        assert_eq!(report.lines_added, 2);
+        assert_eq!(report.lines_deleted, 0);
+        assert!(!report.creates_conflicts());
+        // Checking doesn't apply anything.
+        assert_eq!(repo.graggle("master").unwrap().nodes().count(), 0);
+        repo.apply_patch("master", &first).unwrap();
+
+        // Two patches that both insert a line in the same place conflict.
+        repo.clone_branch("master", "other").unwrap();
+        let ours = create_unapplied(&mut repo, "master", b"a\nc\n");
+        let theirs = create_unapplied(&mut repo, "other", b"a\nd\nb\n");
+        repo.apply_patch("master", &ours).unwrap();
+        repo.apply_patch("other", &theirs).unwrap();
+
+        let report = repo.check_patch("master", &theirs).unwrap();
+        assert!(report.is_applicable());
+        assert_eq!(report.lines_added, 1);
+        assert_eq!(report.lines_deleted, 0);
+        assert!(report.creates_conflicts());
+
+        let report = repo.check_patch("other", &ours).unwrap();
+        assert_eq!(report.lines_added, 1);
+        assert_eq!(report.lines_deleted, 1);
+        assert!(report.creates_conflicts());
+    }
+
+    #[test]
+    fn missing_deps() {
+        let mut repo = Repo::init_tmp();
+        let first = create_unapplied(&mut repo, "master", b"a\n");
+        repo.apply_patch("master", &first).unwrap();
+        let second = create_unapplied(&mut repo, "master", b"a\nb\n");
+        repo.create_branch("empty").unwrap();
+
+        let report = repo.check_patch("empty", &second).unwrap();
+        assert!(!report.is_applicable());
+        assert_eq!(report.missing_deps, vec![first]);
+        assert_eq!(report.unknown_nodes.len(), 1);
+        assert_eq!(report.lines_added, 1);
+    }
+
+    #[test]
+    fn binary_conflict() {
+        let mut repo = Repo::init_tmp();
+        repo.clone_branch("master", "other").unwrap();
+        let changes = repo.binary_changes("master", b"ours".to_vec()).unwrap();
+        let ours = repo.create_patch("Author", "Msg", changes).unwrap();
+        repo.apply_patch("master", &ours).unwrap();
+        let changes = repo.binary_changes("other", b"theirs".to_vec()).unwrap();
+        let theirs = repo.create_patch("Author", "Msg", changes).unwrap();
+
+        let report = repo.check_patch("master", &theirs).unwrap();
+        assert!(!report.is_applicable());
+        match report.error {
+            Some(Error::BinaryConflict(p)) => assert_eq!(p, theirs),
+            x => panic!("expected a conflict, got {:?}", x),
+        }
+    }
+}
@@ -0,0 +1,150 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+use std::collections::HashSet;
+
+use crate::{Change, FileRef, Patch};
+
+/// Some numbers describing the size of a patch (see [`Patch::stats`]).
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct PatchStats {
+    /// The number of files that the patch touches. Changes to the lines or the binary contents of
+    /// a branch's main file count as touching one file.
+    pub files: usize,
+    /// The number of lines that the patch adds.
+    pub lines_added: usize,
+    /// The number of lines that the patch deletes.
+    pub lines_deleted: usize,
+    /// The number of edges that the patch adds.
+    pub edges: usize,
+    /// The size in bytes of the patch's serialization (which is what
+    /// [`Repo::open_patch_data`](crate::Repo::open_patch_data) returns).
+    pub size: usize,
+    /// The number of patches that the patch directly depends on.
+    pub deps: usize,
+}
+
+impl Patch {
+    /// Counts the things that this patch changes.
+    pub fn stats(&self) -> PatchStats {
+        let mut ret = PatchStats {
+            size: self
+                .canonical_data()
+                .expect("failed to serialize a patch")
+                .len(),
+            deps: self.deps().len(),
+            ..Default::default()
+        };
+
+        // Files are identified by the patch that put them in place, and their path.
+        let mut files = HashSet::new();
+        let mut main_file = false;
+        for (file, ch) in self.changes().flattened() {
+            match ch {
+                Change::NewNode { .. } => ret.lines_added += 1,
+                Change::NewEdge { .. } => ret.edges += 1,
+                Change::DeleteNode { .. } => ret.lines_deleted += 1,
+                Change::NewFile { path } => {
+                    files.insert(FileRef {
+                        patch: *self.id(),
+                        path: path.clone(),
+                    });
+                }
+                Change::EditFile { file, .. } => {
+                    files.insert(file.clone());
+                }
+                Change::DeleteFile { file }
+                | Change::MoveFile { from: file, .. }
+                | Change::SetExecutable { file, .. }
+                | Change::SetSymlink { file, .. } => {
+                    files.insert(file.clone());
+                }
+                Change::BinaryReplace { .. } | Change::ChunkedReplace { .. } => main_file = true,
+            }
+            match ch {
+                Change::NewNode { .. } | Change::NewEdge { .. } | Change::DeleteNode { .. }
+                    if file.is_none() =>
+                {
+                    main_file = true
+                }
+                _ => {}
+            }
+        }
+        ret.files = files.len() + main_file as usize;
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PatchStats;
+    use crate::{Change, Changes, FileRef, NodeId, PatchId, Repo};
+
+    #[test]
+    fn stats() {
+        let mut repo = Repo::init_tmp();
+        let diff = repo.diff("master", b"a\nb\n").unwrap();
+        let first = repo.create_patch("Author", "Msg", diff.changes()).unwrap();
+        repo.apply_patch("master", &first).unwrap();
+        let diff = repo.diff("master", b"a\nc\n").unwrap();
+        let second = repo.create_patch("Author", "Msg", diff.changes()).unwrap();
+
+        let patch = repo.open_patch(&second).unwrap();
+        let stats = patch.stats();
+        assert_eq!(
+            stats,
+            PatchStats {
+                files: 1,
+                lines_added: 1,
+                lines_deleted: 1,
+                edges: 1,
+                size: repo.open_patch_data(&second).unwrap().len(),
+                deps: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn files() {
+        let mut repo = Repo::init_tmp();
+        let created = |path: &str| FileRef {
+            patch: PatchId::staging(),
+            path: path.to_owned(),
+        };
+        let mut changes = Changes {
+            changes: vec![
+                Change::NewFile {
+                    path: "a".to_owned(),
+                },
+                Change::NewFile {
+                    path: "b".to_owned(),
+                },
+                Change::MoveFile {
+                    from: created("b"),
+                    to: "c".to_owned(),
+                },
+            ],
+        };
+        let line = Changes {
+            changes: vec![Change::NewNode {
+                id: NodeId::staged(0),
+                contents: b"line\n".to_vec(),
+            }],
+        };
+        changes.add_file_changes(created("a"), line);
+        let id = repo.create_patch("Author", "Msg", changes).unwrap();
+
+        let stats = repo.open_patch(&id).unwrap().stats();
+        assert_eq!(stats.files, 2);
+        assert_eq!(stats.lines_added, 1);
+        assert_eq!(stats.deps, 0);
+    }
+}
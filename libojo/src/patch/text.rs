@@ -0,0 +1,1416 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// The textual patch format. See `Patch::to_text` for a description.
+
+#[cfg(not(target_arch = "wasm32"))]
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use super::{Change, Changes, Patch, PatchHeader, PatchId, PatchKind, UnidentifiedPatch};
+use crate::{BlobChunk, BlobHash, BlobRef, Error, FileRef, NodeId};
+
+const INDENT: &str = "    ";
+
+fn write_node_id(out: &mut String, id: &NodeId) {
+    if id.is_staged() {
+        write!(out, "{}", id.node).unwrap();
+    } else {
+        write!(out, "{}/{}", id.patch.to_base64(), id.node).unwrap();
+    }
+}
+
+fn write_file_ref(out: &mut String, file: &FileRef) {
+    if !file.patch.is_staging() {
+        write!(out, "{}/", file.patch.to_base64()).unwrap();
+    }
+    write_quoted(out, file.path.as_bytes());
+}
+
+fn write_blob_ref(out: &mut String, blob: &BlobRef) {
+    write!(out, "{}/{}", blob.patch.to_base64(), blob.hash.to_base64()).unwrap();
+}
+
+// Writes some (not necessarily UTF-8) bytes as a quoted string.
+fn write_quoted(out: &mut String, bytes: &[u8]) {
+    out.push('"');
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        let (valid, invalid) = match std::str::from_utf8(rest) {
+            Ok(s) => (s, &[][..]),
+            Err(e) => {
+                let (valid, invalid) = rest.split_at(e.valid_up_to());
+                // The unwrap is ok, because we only took the valid part.
+                (std::str::from_utf8(valid).unwrap(), invalid)
+            }
+        };
+        for c in valid.chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                '\t' => out.push_str("\\t"),
+                c if c.is_control() => {
+                    let mut buf = [0; 4];
+                    for b in c.encode_utf8(&mut buf).bytes() {
+                        write!(out, "\\x{:02x}", b).unwrap();
+                    }
+                }
+                c => out.push(c),
+            }
+        }
+        if let Some((&b, tail)) = invalid.split_first() {
+            write!(out, "\\x{:02x}", b).unwrap();
+            rest = tail;
+        } else {
+            rest = invalid;
+        }
+    }
+    out.push('"');
+}
+
+// Writes a header value, quoting it if it couldn't be read back as it is: that is, if it has a
+// control character (like a newline) or one of `special` in it, or if it starts with a quote.
+fn write_header_value(out: &mut String, value: &str, special: &[char]) {
+    if value.starts_with('"') || value.contains(|c: char| c.is_control() || special.contains(&c)) {
+        write_quoted(out, value.as_bytes());
+    } else {
+        out.push_str(value);
+    }
+}
+
+pub(super) fn write<F>(patch: &Patch, context: F) -> String
+where
+    F: Fn(&NodeId) -> Option<Vec<u8>>,
+{
+    let up = patch.to_unidentified();
+    let header = &up.header;
+    let mut out = String::new();
+
+    writeln!(out, "patch {}", patch.id.to_base64()).unwrap();
+    out.push_str("author: ");
+    write_header_value(&mut out, &header.author, &[]);
+    out.push('\n');
+    if let Some(ref email) = header.email {
+        out.push_str("email: ");
+        write_header_value(&mut out, email, &[]);
+        out.push('\n');
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    writeln!(out, "date: {}", header.timestamp.to_rfc3339()).unwrap();
+    for (key, value) in &header.extra {
+        out.push_str("meta: ");
+        write_header_value(&mut out, key, &['=']);
+        out.push('=');
+        write_header_value(&mut out, value, &[]);
+        out.push('\n');
+    }
+    match header.kind {
+        PatchKind::Normal => {}
+        PatchKind::Resolution => writeln!(out, "kind: resolution").unwrap(),
+        PatchKind::Ordering => writeln!(out, "kind: ordering").unwrap(),
+    }
+    for (node, author) in &header.line_authors {
+        write!(out, "line-author: {} ", node).unwrap();
+        write_header_value(&mut out, author, &[]);
+        out.push('\n');
+    }
+    for dep in &up.deps {
+        writeln!(out, "depends: {}", dep.to_base64()).unwrap();
+    }
+    out.push('\n');
+
+    for line in header.description.split('\n') {
+        writeln!(out, "{}{}", INDENT, line).unwrap();
+    }
+    out.push('\n');
+
+    write_changes(&mut out, &up.changes.changes, &context, "");
+    out
+}
+
+// A line of a hunk (see `Patch::to_text`).
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum HunkLine {
+    // The start of a hunk.
+    Start,
+    // A line that stays (but that the lines around it might be attached to).
+    Context(NodeId),
+    Delete(NodeId),
+    New(NodeId, Vec<u8>),
+}
+
+// The last line of a hunk that matters for the ones after it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Last {
+    Start,
+    Context(NodeId),
+    New(NodeId),
+}
+
+fn is_line_change(ch: &Change) -> bool {
+    matches!(
+        ch,
+        Change::NewNode { .. } | Change::DeleteNode { .. } | Change::NewEdge { .. }
+    )
+}
+
+// Adds the changes that a line of a hunk stands for. A new line is attached to the line before it
+// (not counting deleted lines), and a line after a new line is attached to it, exactly as in
+// `Changes::from_diff`.
+fn hunk_changes(line: &HunkLine, last: &mut Last, out: &mut Vec<Change>) {
+    match line {
+        HunkLine::Start => *last = Last::Start,
+        HunkLine::Context(id) => {
+            if let Last::New(src) = *last {
+                out.push(Change::NewEdge { src, dest: *id });
+            }
+            *last = Last::Context(*id);
+        }
+        HunkLine::Delete(id) => out.push(Change::DeleteNode { id: *id }),
+        HunkLine::New(id, contents) => {
+            out.push(Change::NewNode {
+                id: *id,
+                contents: contents.clone(),
+            });
+            if let Last::Context(src) | Last::New(src) = *last {
+                out.push(Change::NewEdge { src, dest: *id });
+            }
+            *last = Last::New(*id);
+        }
+    }
+}
+
+// Some new lines that are attached one after the other, and the lines that they're attached to.
+struct Chain {
+    before: Option<NodeId>,
+    nodes: Vec<NodeId>,
+    after: Option<NodeId>,
+}
+
+// The hunks, as they're being laid out.
+struct Layout {
+    lines: Vec<HunkLine>,
+    last: Last,
+    // The last line that stays or is deleted.
+    prev: Option<NodeId>,
+}
+
+impl Layout {
+    fn start(&mut self) {
+        self.lines.push(HunkLine::Start);
+        self.last = Last::Start;
+    }
+
+    // Whether `id` has to start a new hunk, because it isn't next to the last line.
+    fn is_gap(&self, id: &NodeId) -> bool {
+        self.prev
+            .is_some_and(|p| p.patch != id.patch || p.node + 1 != id.node)
+    }
+
+    fn chain(&mut self, chain: &Chain, contents: &BTreeMap<NodeId, &Vec<u8>>) {
+        let wanted = chain.before.map_or(Last::Start, Last::Context);
+        if self.lines.is_empty() || self.last != wanted {
+            self.start();
+            if let Some(before) = chain.before {
+                self.lines.push(HunkLine::Context(before));
+                self.prev = Some(before);
+            }
+        }
+        for id in &chain.nodes {
+            self.lines.push(HunkLine::New(*id, contents[id].clone()));
+            self.last = Last::New(*id);
+        }
+    }
+
+    fn old(&mut self, id: NodeId, deleted: bool, edges: &BTreeMap<NodeId, NodeId>) {
+        // A line after a new line is attached to it, so it needs a new hunk unless it really is.
+        let attached = match self.last {
+            Last::New(src) => edges.get(&src) == Some(&id),
+            _ => false,
+        };
+        let detached = matches!(self.last, Last::New(_)) && !attached && !deleted;
+        if self.lines.is_empty() || detached || (!attached && self.is_gap(&id)) {
+            self.start();
+        }
+        if deleted {
+            self.lines.push(HunkLine::Delete(id));
+        } else {
+            self.lines.push(HunkLine::Context(id));
+            self.last = Last::Context(id);
+        }
+        self.prev = Some(id);
+    }
+}
+
+// Tries to write some changes to lines as hunks. This returns `None` if they can't be written
+// that way (because they didn't come from a diff, for example, or aren't in canonical order).
+//
+// A patch doesn't say where in the file the lines that it touches are, so the lines that stay or
+// are deleted are put in the order of their ids (which is the order that they were added to the
+// file in, if they came from the same patch), with the new lines in between.
+fn hunk_lines(changes: &[Change]) -> Option<Vec<HunkLine>> {
+    let mut canonical = Changes {
+        changes: changes.to_vec(),
+    };
+    canonical.canonicalize();
+    if canonical.changes != changes {
+        return None;
+    }
+
+    let mut contents = BTreeMap::new();
+    let mut deleted = BTreeSet::new();
+    let mut edges = BTreeMap::new();
+    let mut back_edges = BTreeMap::new();
+    for ch in changes {
+        match ch {
+            Change::NewNode { id, contents: c } => {
+                contents.insert(*id, c);
+            }
+            Change::NewEdge { src, dest } => {
+                if edges.insert(*src, *dest).is_some() || back_edges.insert(*dest, *src).is_some() {
+                    return None;
+                }
+            }
+            Change::DeleteNode { id } => {
+                deleted.insert(*id);
+            }
+            _ => return None,
+        }
+    }
+
+    let mut chains = Vec::new();
+    for &head in contents.keys() {
+        let before = back_edges.get(&head).cloned();
+        if before.is_some_and(|b| contents.contains_key(&b)) {
+            continue;
+        }
+        let mut nodes = vec![head];
+        let mut after = edges.get(&head).cloned();
+        // Every new line has at most one line attached before it, so this can't go around in circles.
+        while let Some(next) = after.filter(|n| contents.contains_key(n)) {
+            nodes.push(next);
+            after = edges.get(&next).cloned();
+        }
+        chains.push(Chain {
+            before,
+            nodes,
+            after,
+        });
+    }
+
+    let mut old = deleted.clone();
+    old.extend(
+        chains
+            .iter()
+            .flat_map(|c| c.before.into_iter().chain(c.after)),
+    );
+    let mut layout = Layout {
+        lines: Vec::new(),
+        last: Last::Start,
+        prev: None,
+    };
+    for chain in chains
+        .iter()
+        .filter(|c| c.before.is_none() && c.after.is_none())
+    {
+        layout.chain(chain, &contents);
+    }
+    // The new lines at the end of a hunk wait until after the deleted lines there.
+    let mut pending = Vec::new();
+    for id in old {
+        let is_deleted = deleted.contains(&id);
+        if !is_deleted || layout.is_gap(&id) {
+            for chain in pending.drain(..) {
+                layout.chain(chain, &contents);
+            }
+        }
+        for chain in chains.iter().filter(|c| c.after == Some(id)) {
+            layout.chain(chain, &contents);
+        }
+        layout.old(id, is_deleted, &edges);
+        if !is_deleted {
+            pending.extend(
+                chains
+                    .iter()
+                    .filter(|c| c.before == Some(id) && c.after.is_none()),
+            );
+        }
+    }
+    for chain in pending {
+        layout.chain(chain, &contents);
+    }
+
+    // Make sure that reading the hunks gives back exactly the same changes.
+    let mut last = Last::Start;
+    let mut read = Changes {
+        changes: Vec::new(),
+    };
+    for line in &layout.lines {
+        hunk_changes(line, &mut last, &mut read.changes);
+    }
+    read.canonicalize();
+    if read.changes == changes {
+        Some(layout.lines)
+    } else {
+        None
+    }
+}
+
+// Writes a node in a hunk: its contents (if we know them), and then its id.
+fn write_hunk_node(out: &mut String, id: &NodeId, contents: Option<&[u8]>) {
+    if let Some(contents) = contents {
+        write_quoted(out, contents);
+        out.push(' ');
+    }
+    out.push('[');
+    write_node_id(out, id);
+    out.push(']');
+}
+
+fn write_hunks<F>(out: &mut String, lines: &[HunkLine], context: &F, indent: &str)
+where
+    F: Fn(&NodeId) -> Option<Vec<u8>>,
+{
+    for line in lines {
+        out.push_str(indent);
+        match line {
+            HunkLine::Start => out.push_str("@@"),
+            HunkLine::Context(id) => {
+                out.push_str("  ");
+                write_hunk_node(out, id, context(id).as_deref());
+            }
+            HunkLine::Delete(id) => {
+                out.push_str("- ");
+                write_hunk_node(out, id, context(id).as_deref());
+            }
+            HunkLine::New(id, contents) => {
+                out.push_str("+ ");
+                write_hunk_node(out, id, Some(contents));
+            }
+        }
+        out.push('\n');
+    }
+}
+
+// Writes some changes, each line prefixed with `indent`. The changes to lines are written as
+// hunks if possible, and one by one otherwise.
+fn write_changes<F>(out: &mut String, changes: &[Change], context: &F, indent: &str)
+where
+    F: Fn(&NodeId) -> Option<Vec<u8>>,
+{
+    let mut i = 0;
+    while i < changes.len() {
+        let run = changes[i..]
+            .iter()
+            .take_while(|ch| is_line_change(ch))
+            .count()
+            .max(1);
+        let run_changes = &changes[i..(i + run)];
+        match hunk_lines(run_changes) {
+            Some(lines) => write_hunks(out, &lines, context, indent),
+            None => {
+                for ch in run_changes {
+                    out.push_str(indent);
+                    write_change(out, ch, context, indent);
+                    out.push('\n');
+                }
+            }
+        }
+        i += run;
+    }
+}
+
+// Writes a single change (without a trailing newline). If the change spans several lines, all but
+// the first are prefixed with `indent`.
+fn write_change<F>(out: &mut String, ch: &Change, context: &F, indent: &str)
+where
+    F: Fn(&NodeId) -> Option<Vec<u8>>,
+{
+    match ch {
+        Change::NewNode { id, contents } => {
+            out.push_str("+ ");
+            write_node_id(out, id);
+            out.push(' ');
+            write_quoted(out, contents);
+        }
+        Change::DeleteNode { id } => {
+            out.push_str("- ");
+            write_node_id(out, id);
+            // The contents of deleted nodes aren't part of the patch, but if we know them
+            // then it's helpful to add them as a comment.
+            if let Some(contents) = context(id) {
+                out.push('\n');
+                out.push_str(indent);
+                out.push_str("# ");
+                write_quoted(out, &contents);
+            }
+        }
+        Change::NewEdge { src, dest } => {
+            out.push_str("> ");
+            write_node_id(out, src);
+            out.push(' ');
+            write_node_id(out, dest);
+        }
+        Change::BinaryReplace { old, new_blob } => {
+            out.push_str("b ");
+            match old {
+                Some(old) => write_blob_ref(out, old),
+                None => out.push('-'),
+            }
+            out.push(' ');
+            out.push_str(&base64::encode_config(new_blob, base64::URL_SAFE));
+        }
+        Change::ChunkedReplace { old, hash, chunks } => {
+            out.push_str("c ");
+            write_blob_ref(out, old);
+            out.push(' ');
+            out.push_str(&hash.to_base64());
+            for c in chunks {
+                out.push(' ');
+                match c {
+                    BlobChunk::New(data) => {
+                        out.push_str(&base64::encode_config(data, base64::URL_SAFE))
+                    }
+                    BlobChunk::Copy { start, len } => write!(out, "{}+{}", start, len).unwrap(),
+                }
+            }
+        }
+        Change::NewFile { path } => {
+            out.push_str("n ");
+            write_quoted(out, path.as_bytes());
+        }
+        Change::DeleteFile { file } => {
+            out.push_str("d ");
+            write_file_ref(out, file);
+        }
+        Change::MoveFile { from, to } => {
+            out.push_str("m ");
+            write_file_ref(out, from);
+            out.push(' ');
+            write_quoted(out, to.as_bytes());
+        }
+        Change::SetExecutable { file, executable } => {
+            out.push_str("x ");
+            write_file_ref(out, file);
+            out.push_str(if *executable { " +x" } else { " -x" });
+        }
+        Change::SetSymlink { file, symlink } => {
+            out.push_str("l ");
+            write_file_ref(out, file);
+            out.push_str(if *symlink { " +l" } else { " -l" });
+        }
+        Change::EditFile { file, changes } => {
+            out.push_str("e ");
+            write_file_ref(out, file);
+            out.push('\n');
+            write_changes(
+                out,
+                &changes.changes,
+                context,
+                &format!("{}{}", indent, INDENT),
+            );
+            // The caller adds the last newline.
+            out.pop();
+        }
+    }
+}
+
+// Lines of hunks are either context (starting with two spaces), or deleted or new lines whose
+// marker is followed by contents or a node id in brackets (unlike the changes that add or delete
+// a single node).
+fn is_hunk_line(line: &str) -> bool {
+    let marked = line.starts_with("+ ") || line.starts_with("- ");
+    line.starts_with("  ") || (marked && line[2..].starts_with(['"', '[']))
+}
+
+// Some consecutive hunks that are being read.
+struct Hunks {
+    // Whether the hunks are indented (and so belong to the last `EditFile`).
+    in_file: bool,
+    last: Last,
+    changes: Changes,
+}
+
+impl Hunks {
+    fn new(in_file: bool) -> Hunks {
+        Hunks {
+            in_file,
+            last: Last::Start,
+            changes: Changes {
+                changes: Vec::new(),
+            },
+        }
+    }
+
+    // Adds the changes from the hunks to the patch's changes. Only changes in canonical order are
+    // written as hunks (see `hunk_lines`), so that's the order that they're put in.
+    fn finish(mut self, changes: &mut Vec<Change>) {
+        self.changes.canonicalize();
+        match changes.last_mut() {
+            Some(Change::EditFile { changes, .. }) if self.in_file => {
+                changes.changes.extend(self.changes.changes)
+            }
+            _ => changes.extend(self.changes.changes),
+        }
+    }
+}
+
+// Keeps track of where we are in the input, for error messages.
+struct Parser<'a> {
+    lines: std::iter::Peekable<std::iter::Enumerate<std::str::Split<'a, char>>>,
+    line_num: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error<T, S: Into<String>>(&self, msg: S) -> Result<T, Error> {
+        Err(Error::PatchSyntax(self.line_num, msg.into()))
+    }
+
+    fn next_line(&mut self) -> Option<&'a str> {
+        let (i, line) = self.lines.next()?;
+        self.line_num = i + 1;
+        Some(line)
+    }
+
+    fn peek_line(&mut self) -> Option<&'a str> {
+        self.lines.peek().map(|(_, line)| *line)
+    }
+
+    fn patch_id(&self, s: &str) -> Result<PatchId, Error> {
+        if !s.starts_with('P') {
+            return self.error(format!("invalid patch id \"{}\"", s));
+        }
+        PatchId::from_base64(s).or_else(|_| self.error(format!("invalid patch id \"{}\"", s)))
+    }
+
+    fn node_id(&self, s: &str) -> Result<NodeId, Error> {
+        let (patch, node) = match s.find('/') {
+            Some(i) => (self.patch_id(&s[..i])?, &s[(i + 1)..]),
+            None => (PatchId::staging(), s),
+        };
+        match node.parse() {
+            Ok(node) => Ok(NodeId { patch, node }),
+            Err(_) => self.error(format!("invalid node id \"{}\"", s)),
+        }
+    }
+
+    fn blob_ref(&self, s: &str) -> Result<Option<BlobRef>, Error> {
+        if s == "-" {
+            return Ok(None);
+        }
+        match s.find('/') {
+            Some(i) => Ok(Some(BlobRef {
+                patch: self.patch_id(&s[..i])?,
+                hash: BlobHash::from_base64(&s[(i + 1)..])
+                    .or_else(|_| self.error(format!("invalid hash \"{}\"", &s[(i + 1)..])))?,
+            })),
+            None => self.error(format!("invalid binary contents \"{}\"", s)),
+        }
+    }
+
+    // Parses a piece of chunked binary contents, which is either base64 or `<start>+<len>`.
+    fn chunk(&self, s: &str) -> Result<BlobChunk, Error> {
+        match s.find('+') {
+            Some(i) => match (s[..i].parse(), s[(i + 1)..].parse()) {
+                (Ok(start), Ok(len)) => Ok(BlobChunk::Copy { start, len }),
+                _ => self.error(format!("invalid chunk \"{}\"", s)),
+            },
+            None => Ok(BlobChunk::New(
+                base64::decode_config(s, base64::URL_SAFE)
+                    .or_else(|_| self.error("invalid base64"))?,
+            )),
+        }
+    }
+
+    // Splits off the quoted string at the beginning of `s`, returning it and the rest of `s`.
+    fn split_quoted<'b>(&self, s: &'b str) -> Result<(&'b str, &'b str), Error> {
+        if !s.starts_with('"') {
+            return self.error("expected a quoted string");
+        }
+        let mut escaped = false;
+        for (i, c) in s.char_indices().skip(1) {
+            match c {
+                '\\' if !escaped => escaped = true,
+                '"' if !escaped => return Ok((&s[..=i], &s[(i + 1)..])),
+                _ => escaped = false,
+            }
+        }
+        self.error("unterminated quoted string")
+    }
+
+    fn path(&self, s: &str) -> Result<String, Error> {
+        String::from_utf8(self.quoted(s)?).or_else(|_| self.error("paths must be UTF-8"))
+    }
+
+    fn file_ref<'b>(&self, s: &'b str) -> Result<(FileRef, &'b str), Error> {
+        let (patch, s) = match s.find('/') {
+            Some(i) if !s.starts_with('"') => (self.patch_id(&s[..i])?, &s[(i + 1)..]),
+            _ => (PatchId::staging(), s),
+        };
+        let (path, rest) = self.split_quoted(s)?;
+        let file = FileRef {
+            patch,
+            path: self.path(path)?,
+        };
+        Ok((file, rest))
+    }
+
+    // Parses a header value, which is quoted if it has special characters in it (see
+    // `write_header_value`).
+    fn header_value(&self, s: &str) -> Result<String, Error> {
+        if s.starts_with('"') {
+            String::from_utf8(self.quoted(s)?).or_else(|_| self.error("headers must be UTF-8"))
+        } else {
+            Ok(s.to_owned())
+        }
+    }
+
+    fn quoted(&self, s: &str) -> Result<Vec<u8>, Error> {
+        if s.len() < 2 || !s.starts_with('"') || !s.ends_with('"') {
+            return self.error("expected a quoted string");
+        }
+        let s = &s[1..(s.len() - 1)];
+        let mut ret = Vec::new();
+        let mut chars = s.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some((_, '"')) => ret.push(b'"'),
+                    Some((_, '\\')) => ret.push(b'\\'),
+                    Some((_, 'n')) => ret.push(b'\n'),
+                    Some((_, 'r')) => ret.push(b'\r'),
+                    Some((_, 't')) => ret.push(b'\t'),
+                    Some((_, 'x')) => {
+                        let hex = s.get((i + 2)..(i + 4));
+                        match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                            Some(b) => ret.push(b),
+                            None => return self.error("invalid \\x escape"),
+                        }
+                        chars.next();
+                        chars.next();
+                    }
+                    _ => return self.error("invalid escape sequence"),
+                },
+                '"' => return self.error("unescaped quote"),
+                c => {
+                    let mut buf = [0; 4];
+                    ret.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    // Parses a line of a hunk (see `is_hunk_line`). The contents of lines that aren't new are
+    // only there for the reader, so they're ignored.
+    fn hunk_line(&self, line: &str) -> Result<HunkLine, Error> {
+        let (marker, rest) = line.split_at(2);
+        let (contents, rest) = if rest.starts_with('"') {
+            let (quoted, rest) = self.split_quoted(rest)?;
+            match rest.strip_prefix(' ') {
+                Some(rest) => (Some(self.quoted(quoted)?), rest),
+                None => return self.error("expected a node id after the contents"),
+            }
+        } else {
+            (None, rest)
+        };
+        let id = match rest.strip_prefix('[').and_then(|r| r.strip_suffix(']')) {
+            Some(id) => self.node_id(id)?,
+            None => return self.error("expected a node id in brackets"),
+        };
+        match (marker, contents) {
+            ("+ ", Some(contents)) => Ok(HunkLine::New(id, contents)),
+            ("+ ", None) => self.error("expected the contents of the new line"),
+            ("- ", _) => Ok(HunkLine::Delete(id)),
+            _ => Ok(HunkLine::Context(id)),
+        }
+    }
+
+    fn change(&self, line: &str) -> Result<Change, Error> {
+        if line.len() < 2 || line.as_bytes()[1] != b' ' {
+            return self.error(format!("invalid change \"{}\"", line));
+        }
+        let rest = &line[2..];
+        match line.as_bytes()[0] {
+            b'+' => {
+                let i = rest
+                    .find(' ')
+                    .map(Ok)
+                    .unwrap_or_else(|| self.error("no contents"))?;
+                Ok(Change::NewNode {
+                    id: self.node_id(&rest[..i])?,
+                    contents: self.quoted(&rest[(i + 1)..])?,
+                })
+            }
+            b'-' => Ok(Change::DeleteNode {
+                id: self.node_id(rest)?,
+            }),
+            b'>' => {
+                let mut words = rest.split(' ');
+                match (words.next(), words.next(), words.next()) {
+                    (Some(src), Some(dest), None) => Ok(Change::NewEdge {
+                        src: self.node_id(src)?,
+                        dest: self.node_id(dest)?,
+                    }),
+                    _ => self.error("expected two node ids"),
+                }
+            }
+            b'b' => {
+                let mut words = rest.split(' ');
+                match (words.next(), words.next(), words.next()) {
+                    (Some(old), Some(new_blob), None) => Ok(Change::BinaryReplace {
+                        old: self.blob_ref(old)?,
+                        new_blob: base64::decode_config(new_blob, base64::URL_SAFE)
+                            .or_else(|_| self.error("invalid base64"))?,
+                    }),
+                    _ => self.error("expected the old and new binary contents"),
+                }
+            }
+            b'c' => {
+                let mut words = rest.split(' ');
+                let (old, hash) = match (words.next(), words.next()) {
+                    (Some(old), Some(hash)) => (old, hash),
+                    _ => return self.error("expected the old and new binary contents"),
+                };
+                let old = match self.blob_ref(old)? {
+                    Some(old) => old,
+                    None => return self.error("expected the replaced binary contents"),
+                };
+                let hash = BlobHash::from_base64(hash)
+                    .or_else(|_| self.error(format!("invalid hash \"{}\"", hash)))?;
+                let chunks = words.map(|w| self.chunk(w)).collect::<Result<_, _>>()?;
+                Ok(Change::ChunkedReplace { old, hash, chunks })
+            }
+            b'n' => Ok(Change::NewFile {
+                path: self.path(rest)?,
+            }),
+            b'd' => match self.file_ref(rest)? {
+                (file, "") => Ok(Change::DeleteFile { file }),
+                _ => self.error("expected a file"),
+            },
+            b'm' => {
+                let (from, to) = self.file_ref(rest)?;
+                if !to.starts_with(' ') {
+                    return self.error("expected a file and a path");
+                }
+                Ok(Change::MoveFile {
+                    from,
+                    to: self.path(&to[1..])?,
+                })
+            }
+            b'e' => match self.file_ref(rest)? {
+                (file, "") => Ok(Change::EditFile {
+                    file,
+                    changes: Changes { changes: vec![] },
+                }),
+                _ => self.error("expected a file"),
+            },
+            b'x' => {
+                let (file, executable) = match self.file_ref(rest)? {
+                    (file, " +x") => (file, true),
+                    (file, " -x") => (file, false),
+                    _ => return self.error("expected a file, followed by +x or -x"),
+                };
+                Ok(Change::SetExecutable { file, executable })
+            }
+            b'l' => {
+                let (file, symlink) = match self.file_ref(rest)? {
+                    (file, " +l") => (file, true),
+                    (file, " -l") => (file, false),
+                    _ => return self.error("expected a file, followed by +l or -l"),
+                };
+                Ok(Change::SetSymlink { file, symlink })
+            }
+            _ => self.error(format!("invalid change \"{}\"", line)),
+        }
+    }
+}
+
+pub(super) fn read(input: &str) -> Result<Patch, Error> {
+    let mut p = Parser {
+        lines: input.split('\n').enumerate().peekable(),
+        line_num: 0,
+    };
+
+    let first = p.next_line().unwrap_or("");
+    if !first.starts_with("patch ") {
+        return p.error("expected \"patch <id>\"");
+    }
+    let expected_id = p.patch_id(&first[6..])?;
+
+    let mut author = None;
+    let mut email = None;
+    #[cfg(not(target_arch = "wasm32"))]
+    let mut date = None;
+    let mut extra = BTreeMap::new();
+    let mut kind = PatchKind::Normal;
+    let mut line_authors = BTreeMap::new();
+    let mut deps = Vec::new();
+    loop {
+        let line = match p.next_line() {
+            Some("") => break,
+            Some(line) => line,
+            None => return p.error("unexpected end of input"),
+        };
+        let i = line
+            .find(": ")
+            .map(Ok)
+            .unwrap_or_else(|| p.error("expected \"<key>: <value>\""))?;
+        let value = &line[(i + 2)..];
+        match &line[..i] {
+            "author" => author = Some(p.header_value(value)?),
+            "email" => email = Some(p.header_value(value)?),
+            #[cfg(not(target_arch = "wasm32"))]
+            "date" => match DateTime::parse_from_rfc3339(value) {
+                Ok(d) => date = Some(d.with_timezone(&Utc)),
+                Err(_) => return p.error(format!("invalid date \"{}\"", value)),
+            },
+            #[cfg(target_arch = "wasm32")]
+            "date" => {}
+            "meta" => {
+                let (key, rest) = if value.starts_with('"') {
+                    p.split_quoted(value)?
+                } else {
+                    value.split_at(value.find('=').unwrap_or(value.len()))
+                };
+                match rest.strip_prefix('=') {
+                    Some(rest) => {
+                        extra.insert(p.header_value(key)?, p.header_value(rest)?);
+                    }
+                    None => return p.error("expected \"meta: <key>=<value>\""),
+                }
+            }
+            "kind" => match value {
+                "resolution" => kind = PatchKind::Resolution,
+                "ordering" => kind = PatchKind::Ordering,
+                _ => return p.error(format!("unknown patch kind \"{}\"", value)),
+            },
+            "line-author" => match value
+                .find(' ')
+                .map(|j| (value[..j].parse(), &value[(j + 1)..]))
+            {
+                Some((Ok(node), author)) => {
+                    line_authors.insert(node, p.header_value(author)?);
+                }
+                _ => return p.error("expected \"line-author: <node> <author>\""),
+            },
+            "depends" => deps.push(p.patch_id(value)?),
+            key => return p.error(format!("unknown header \"{}\"", key)),
+        }
+    }
+
+    let mut description = Vec::new();
+    while let Some(line) = p.peek_line() {
+        if !line.starts_with(INDENT) {
+            break;
+        }
+        p.next_line();
+        description.push(&line[INDENT.len()..]);
+    }
+    match p.next_line() {
+        Some("") => {}
+        _ => return p.error("expected an indented description, followed by an empty line"),
+    }
+
+    let mut changes = Vec::new();
+    // The hunks that we're in the middle of, if any.
+    let mut hunks: Option<Hunks> = None;
+    while let Some(line) = p.next_line() {
+        // Indented lines are changes to the lines of the file in the preceding `EditFile`.
+        let (line, in_file) = match line.strip_prefix(INDENT) {
+            Some(line) => match changes.last() {
+                Some(Change::EditFile { .. }) => (line, true),
+                _ if line.is_empty() || line.starts_with('#') => continue,
+                _ => return p.error("indented change outside of a file"),
+            },
+            None => (line, false),
+        };
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let is_hunk = line == "@@" || is_hunk_line(line);
+        if hunks
+            .as_ref()
+            .is_some_and(|h| !is_hunk || h.in_file != in_file)
+        {
+            hunks.take().unwrap().finish(&mut changes);
+        }
+        if line == "@@" {
+            let h = hunks.get_or_insert_with(|| Hunks::new(in_file));
+            h.last = Last::Start;
+        } else if is_hunk {
+            let hunk_line = p.hunk_line(line)?;
+            match &mut hunks {
+                Some(h) => hunk_changes(&hunk_line, &mut h.last, &mut h.changes.changes),
+                None => return p.error("expected \"@@\" before the lines of a hunk"),
+            }
+        } else {
+            let ch = p.change(line)?;
+            match changes.last_mut() {
+                Some(Change::EditFile { changes, .. }) if in_file => changes.changes.push(ch),
+                _ => changes.push(ch),
+            }
+        }
+    }
+    if let Some(h) = hunks {
+        h.finish(&mut changes);
+    }
+
+    let header = PatchHeader {
+        author: author
+            .map(Ok)
+            .unwrap_or_else(|| p.error("missing author"))?,
+        email,
+        description: description.join("\n"),
+        #[cfg(not(target_arch = "wasm32"))]
+        timestamp: date.map(Ok).unwrap_or_else(|| p.error("missing date"))?,
+        extra,
+        kind,
+        line_authors,
+    };
+    let up = UnidentifiedPatch {
+        changes: Changes { changes },
+        header,
+        deps,
+    };
+    let id = up.id()?;
+    if id != expected_id {
+        return Err(Error::IdMismatch(id, expected_id));
+    }
+    Ok(up.set_id(id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patch() -> Patch {
+        let dep = PatchId { data: [1; 32] };
+        let changes = Changes {
+            changes: vec![
+                Change::NewNode {
+                    id: NodeId::staged(0),
+                    contents: b"line with \"quotes\"\n".to_vec(),
+                },
+                Change::NewNode {
+                    id: NodeId::staged(1),
+                    contents: b"\\ \xff\x01 \xce\xbb\n".to_vec(),
+                },
+                Change::NewEdge {
+                    src: NodeId::staged(0),
+                    dest: NodeId {
+                        patch: dep,
+                        node: 1,
+                    },
+                },
+                // An edge between two lines that are already there (as in a resolution) can't be
+                // written in a hunk, so this patch is written one change at a time.
+                Change::NewEdge {
+                    src: NodeId {
+                        patch: dep,
+                        node: 0,
+                    },
+                    dest: NodeId {
+                        patch: dep,
+                        node: 3,
+                    },
+                },
+                Change::DeleteNode {
+                    id: NodeId {
+                        patch: dep,
+                        node: 2,
+                    },
+                },
+            ],
+        };
+        let mut header = PatchHeader::new("Author".to_owned(), "Summary\n\nDetails\n".to_owned());
+        header.email = Some("author@example.com".to_owned());
+        header.extra.insert("key".to_owned(), "value".to_owned());
+        UnidentifiedPatch::with_header(header, changes)
+            .write_out(Vec::new())
+            .unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let patch = patch();
+        let text = patch.to_text();
+        assert_eq!(Patch::from_text(&text).unwrap(), patch);
+    }
+
+    #[test]
+    fn format() {
+        let text = patch().to_text();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines[1], "author: Author");
+        assert_eq!(lines[2], "email: author@example.com");
+        assert_eq!(lines[4], "meta: key=value");
+        let dep = PatchId { data: [1; 32] }.to_base64();
+        assert_eq!(lines[5], format!("depends: {}", dep));
+        assert_eq!(lines[6], "");
+        assert_eq!(
+            &lines[7..12],
+            &["    Summary", "    ", "    Details", "    ", ""]
+        );
+        assert_eq!(lines[12], r#"+ 0 "line with \"quotes\"\n""#);
+        assert_eq!(lines[13], r#"+ 1 "\\ \xff\x01 λ\n""#);
+        assert_eq!(lines[14], format!("> 0 {}/1", dep));
+        assert_eq!(lines[15], format!("> {}/0 {}/3", dep, dep));
+        assert_eq!(lines[16], format!("- {}/2", dep));
+    }
+
+    #[test]
+    fn comments() {
+        let patch = patch();
+        let text = patch.to_text_with_context(|_| Some(b"deleted".to_vec()));
+        assert!(text.ends_with("/2\n# \"deleted\"\n"));
+        assert_eq!(Patch::from_text(&text).unwrap(), patch);
+    }
+
+    #[test]
+    fn binary() {
+        let dep = PatchId { data: [1; 32] };
+        let changes = Changes {
+            changes: vec![Change::BinaryReplace {
+                old: Some(BlobRef {
+                    patch: dep,
+                    hash: BlobHash::of(b"old"),
+                }),
+                new_blob: b"\0\xffnew".to_vec(),
+            }],
+        };
+        let patch = UnidentifiedPatch::new("Author".to_owned(), "Msg".to_owned(), changes)
+            .write_out(Vec::new())
+            .unwrap();
+        let text = patch.to_text();
+        let last = text.lines().last().unwrap();
+        assert_eq!(
+            last,
+            format!(
+                "b {}/{} AP9uZXc=",
+                dep.to_base64(),
+                BlobHash::of(b"old").to_base64()
+            )
+        );
+        assert_eq!(Patch::from_text(&text).unwrap(), patch);
+        assert_eq!(
+            Patch::from_text(&text.replace("AP9uZXc=", "!"))
+                .unwrap_err()
+                .to_string(),
+            format!(
+                "Syntax error on line {}: invalid base64",
+                text.lines().count()
+            )
+        );
+    }
+
+    #[test]
+    fn chunked_binary() {
+        let dep = PatchId { data: [1; 32] };
+        let old = BlobRef {
+            patch: dep,
+            hash: BlobHash::of(b"old"),
+        };
+        let changes = Changes {
+            changes: vec![Change::ChunkedReplace {
+                old,
+                hash: BlobHash::of(b"new"),
+                chunks: vec![
+                    BlobChunk::Copy { start: 0, len: 2 },
+                    BlobChunk::New(b"\0\xffnew".to_vec()),
+                ],
+            }],
+        };
+        let patch = UnidentifiedPatch::new("Author".to_owned(), "Msg".to_owned(), changes)
+            .write_out(Vec::new())
+            .unwrap();
+        let text = patch.to_text();
+        let last = text.lines().last().unwrap();
+        assert_eq!(
+            last,
+            format!(
+                "c {}/{} {} 0+2 AP9uZXc=",
+                dep.to_base64(),
+                old.hash.to_base64(),
+                BlobHash::of(b"new").to_base64()
+            )
+        );
+        assert_eq!(Patch::from_text(&text).unwrap(), patch);
+        assert!(Patch::from_text(&text.replace("0+2", "0+x")).is_err());
+    }
+
+    #[test]
+    fn resolution() {
+        let dep = PatchId { data: [1; 32] };
+        let changes = Changes {
+            changes: vec![Change::DeleteNode {
+                id: NodeId {
+                    patch: dep,
+                    node: 0,
+                },
+            }],
+        };
+        let mut header = PatchHeader::new("Author".to_owned(), "Msg".to_owned());
+        header.kind = PatchKind::Resolution;
+        let patch = UnidentifiedPatch::with_header(header, changes)
+            .write_out(Vec::new())
+            .unwrap();
+        let text = patch.to_text();
+        assert!(text.lines().any(|line| line == "kind: resolution"));
+        assert_eq!(Patch::from_text(&text).unwrap(), patch);
+
+        // The kind is part of the patch's identity.
+        let text = text.replace("kind: resolution\n", "");
+        match Patch::from_text(&text) {
+            Err(Error::IdMismatch(..)) => {}
+            x => panic!("expected an id mismatch, got {:?}", x),
+        }
+
+        // So is the difference between resolution and ordering patches.
+        let text = patch
+            .to_text()
+            .replace("kind: resolution", "kind: ordering");
+        match Patch::from_text(&text) {
+            Err(Error::IdMismatch(..)) => {}
+            x => panic!("expected an id mismatch, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn hunks() {
+        let mut repo = crate::Repo::init_tmp();
+        let diff = repo.diff("master", b"a\nb\nc\nd\ne\nf\ng\nh\n").unwrap();
+        let first = repo.create_patch("Author", "Msg", diff.changes()).unwrap();
+        repo.apply_patch("master", &first).unwrap();
+        let diff = repo.diff("master", b"a\nB\nc\nd\ne\nf\nG\nh\ni\n").unwrap();
+        let id = repo.create_patch("Author", "Msg", diff.changes()).unwrap();
+        let text = repo.patch_text(&id).unwrap();
+
+        let node = |n| format!("[{}/{}]", first.to_base64(), n);
+        let expected = vec![
+            "@@".to_owned(),
+            format!(r#"  "a\n" {}"#, node(0)),
+            format!(r#"- "b\n" {}"#, node(1)),
+            r#"+ "B\n" [0]"#.to_owned(),
+            format!(r#"  "c\n" {}"#, node(2)),
+            "@@".to_owned(),
+            format!(r#"  "f\n" {}"#, node(5)),
+            format!(r#"- "g\n" {}"#, node(6)),
+            r#"+ "G\n" [1]"#.to_owned(),
+            format!(r#"  "h\n" {}"#, node(7)),
+            r#"+ "i\n" [2]"#.to_owned(),
+        ];
+        let lines = text.lines().skip_while(|l| *l != "@@").collect::<Vec<_>>();
+        assert_eq!(lines, expected);
+        assert_eq!(
+            Patch::from_text(&text).unwrap(),
+            repo.open_patch(&id).unwrap()
+        );
+
+        // The contents of the lines that stay or are deleted are only for the reader.
+        let text = text
+            .replace(r#""f\n" "#, "")
+            .replace(r#""g\n""#, r#""edited\n""#);
+        assert_eq!(
+            Patch::from_text(&text).unwrap(),
+            repo.open_patch(&id).unwrap()
+        );
+        // But a hunk has to start with "@@".
+        let text = text.replacen("@@\n", "", 1);
+        match Patch::from_text(&text) {
+            Err(Error::PatchSyntax(..)) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn line_authors() {
+        let changes = Changes {
+            changes: vec![Change::NewNode {
+                id: NodeId::staged(0),
+                contents: b"line\n".to_vec(),
+            }],
+        };
+        let mut header = PatchHeader::new("Author".to_owned(), "Msg".to_owned());
+        header.line_authors.insert(0, "Other Author".to_owned());
+        let patch = UnidentifiedPatch::with_header(header, changes)
+            .write_out(Vec::new())
+            .unwrap();
+        let text = patch.to_text();
+        assert!(text
+            .lines()
+            .any(|line| line == "line-author: 0 Other Author"));
+        assert_eq!(Patch::from_text(&text).unwrap(), patch);
+
+        match Patch::from_text(&text.replace("line-author: 0", "line-author: x")) {
+            Err(Error::PatchSyntax(..)) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn special_headers() {
+        let changes = Changes {
+            changes: vec![Change::NewNode {
+                id: NodeId::staged(0),
+                contents: b"line\n".to_vec(),
+            }],
+        };
+        let mut header = PatchHeader::new("Two\nLines".to_owned(), "Msg".to_owned());
+        header.email = Some("\"quoted\"".to_owned());
+        header.extra.insert("a=b".to_owned(), "c=d\n".to_owned());
+        header.line_authors.insert(0, "Other\rAuthor".to_owned());
+        let patch = UnidentifiedPatch::with_header(header, changes)
+            .write_out(Vec::new())
+            .unwrap();
+        let text = patch.to_text();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines[1], r#"author: "Two\nLines""#);
+        assert_eq!(lines[2], r#"email: "\"quoted\"""#);
+        assert_eq!(lines[4], r#"meta: "a=b"="c=d\n""#);
+        assert_eq!(lines[5], r#"line-author: 0 "Other\rAuthor""#);
+        assert_eq!(Patch::from_text(&text).unwrap(), patch);
+    }
+
+    #[test]
+    fn files() {
+        let dep = PatchId { data: [1; 32] };
+        let changes = Changes {
+            changes: vec![
+                Change::NewFile {
+                    path: "dir/new file".to_owned(),
+                },
+                Change::MoveFile {
+                    from: FileRef {
+                        patch: PatchId::staging(),
+                        path: "dir/new file".to_owned(),
+                    },
+                    to: "quoted \"name\"".to_owned(),
+                },
+                Change::SetExecutable {
+                    file: FileRef {
+                        patch: dep,
+                        path: "script".to_owned(),
+                    },
+                    executable: true,
+                },
+                Change::SetSymlink {
+                    file: FileRef {
+                        patch: dep,
+                        path: "link".to_owned(),
+                    },
+                    symlink: false,
+                },
+                Change::DeleteFile {
+                    file: FileRef {
+                        patch: dep,
+                        path: "old".to_owned(),
+                    },
+                },
+            ],
+        };
+        let patch = UnidentifiedPatch::new("Author".to_owned(), "Msg".to_owned(), changes)
+            .write_out(Vec::new())
+            .unwrap();
+        let text = patch.to_text();
+        let lines = text.lines().rev().take(5).collect::<Vec<_>>();
+        assert_eq!(lines[4], r#"n "dir/new file""#);
+        assert_eq!(lines[3], r#"m "dir/new file" "quoted \"name\"""#);
+        assert_eq!(lines[2], format!(r#"x {}/"script" +x"#, dep.to_base64()));
+        assert_eq!(lines[1], format!(r#"l {}/"link" -l"#, dep.to_base64()));
+        assert_eq!(lines[0], format!(r#"d {}/"old""#, dep.to_base64()));
+        assert_eq!(Patch::from_text(&text).unwrap(), patch);
+    }
+
+    #[test]
+    fn edit_file() {
+        let dep = PatchId { data: [1; 32] };
+        let file = FileRef {
+            patch: dep,
+            path: "file".to_owned(),
+        };
+        let changes = Changes {
+            changes: vec![
+                Change::NewNode {
+                    id: NodeId::staged(0),
+                    contents: b"line\n".to_vec(),
+                },
+                Change::NewEdge {
+                    src: NodeId {
+                        patch: dep,
+                        node: 0,
+                    },
+                    dest: NodeId::staged(0),
+                },
+            ],
+        };
+        let mut all_changes = Changes {
+            changes: vec![Change::NewFile {
+                path: "other".to_owned(),
+            }],
+        };
+        all_changes.add_file_changes(file, changes);
+        let patch = UnidentifiedPatch::new("Author".to_owned(), "Msg".to_owned(), all_changes)
+            .write_out(Vec::new())
+            .unwrap();
+        let text = patch.to_text();
+        let lines = text.lines().rev().take(5).collect::<Vec<_>>();
+        assert_eq!(lines[4], format!(r#"e {}/"file""#, dep.to_base64()));
+        assert_eq!(lines[3], "    @@");
+        assert_eq!(lines[2], format!("      [{}/0]", dep.to_base64()));
+        assert_eq!(lines[1], r#"    + "line\n" [0]"#);
+        assert_eq!(lines[0], r#"n "other""#);
+        assert_eq!(Patch::from_text(&text).unwrap(), patch);
+
+        // Indented changes have to belong to a file.
+        let text = text.replace(&format!("e {}/\"file\"\n", dep.to_base64()), "");
+        match Patch::from_text(&text) {
+            Err(Error::PatchSyntax(..)) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn register() {
+        let mut repo = crate::Repo::init_tmp();
+        let diff = repo.diff("master", b"a\nb\n").unwrap();
+        let changes = Changes::from_diff(&diff.file_a, &diff.file_b, &diff.diff);
+        let id = repo.create_patch("Author", "Msg", changes).unwrap();
+        let text = repo.patch_text(&id).unwrap();
+
+        let mut other_repo = crate::Repo::init_tmp();
+        assert_eq!(other_repo.register_patch_text(&text).unwrap(), id);
+        assert_eq!(
+            other_repo.open_patch_data(&id).unwrap(),
+            repo.open_patch_data(&id).unwrap()
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let text = patch().to_text();
+        let error_line = |text: &str| match Patch::from_text(text) {
+            Err(Error::PatchSyntax(line, _)) => line,
+            x => panic!("unexpected result {:?}", x),
+        };
+        assert_eq!(error_line(&text.replace("author: ", "author ")), 2);
+        assert_eq!(error_line(&text.replace("+ 0 \"", "+ 0 ")), 13);
+        assert_eq!(error_line(&text.replace("> 0", "* 0")), 15);
+
+        match Patch::from_text(&text.replace("Details", "Other details")) {
+            Err(Error::IdMismatch(..)) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+}
@@ -0,0 +1,338 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Writing a patch as a unified diff, in the format of `git diff`.
+//
+// A patch doesn't say what the files that it changes look like, so they are rebuilt from the
+// patches that it depends on (directly or indirectly), in the same way as when rebasing a patch.
+// The files are rendered before and after applying the patch, and each file that changed gets a
+// section in the diff. Files are matched up by their identity, so that moving a file shows up as
+// a rename.
+
+use ojo_diff::LineDiff;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+
+use crate::storage::graggle::GraggleData;
+use crate::{
+    BlobHash, Change, Diff, Error, File, FileRef, NodeId, Patch, Repo, DEFAULT_WORKING_FILE,
+};
+
+// The number of unchanged lines to show around each change.
+const CONTEXT: usize = 3;
+
+// What a file looks like, on one side of the diff.
+#[derive(PartialEq)]
+struct Side {
+    path: String,
+    mode: &'static str,
+    contents: Contents,
+}
+
+#[derive(PartialEq)]
+enum Contents {
+    Text(Vec<u8>),
+    Binary(BlobHash),
+}
+
+// The files in `data`, indexed by their identity (the lines of the graggle itself are the file
+// without an identity).
+fn sides(
+    repo: &Repo,
+    data: &GraggleData,
+    lines: &HashMap<NodeId, Vec<u8>>,
+) -> Result<BTreeMap<Option<FileRef>, Side>, Error> {
+    let render = |data: Option<&GraggleData>| -> Result<Contents, Error> {
+        let data = match data {
+            Some(data) => data,
+            None => return Ok(Contents::Text(Vec::new())),
+        };
+        if let Some(blob) = data.binary() {
+            return Ok(Contents::Binary(blob.hash));
+        }
+        let mut ret = Vec::new();
+        crate::render::render_with(repo, data.as_graggle(), |id| &lines[id][..], &mut ret)?;
+        Ok(Contents::Text(ret))
+    };
+
+    let mut ret = BTreeMap::new();
+    if data.as_graggle().nodes().next().is_some() || data.binary().is_some() {
+        let side = Side {
+            path: DEFAULT_WORKING_FILE.to_owned(),
+            mode: "100644",
+            contents: render(Some(data))?,
+        };
+        ret.insert(None, side);
+    }
+    for path in data.files().paths() {
+        let id = data.files().id(path).unwrap();
+        let state = data.files().get(path).unwrap();
+        let mode = if state.symlink {
+            "120000"
+        } else if state.executable {
+            "100755"
+        } else {
+            "100644"
+        };
+        let side = Side {
+            path: path.to_owned(),
+            mode,
+            contents: render(data.file_graggle(&id))?,
+        };
+        ret.insert(Some(id), side);
+    }
+    Ok(ret)
+}
+
+// Formats the range of lines in a hunk header.
+fn range(start: usize, len: usize) -> String {
+    match len {
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, len),
+    }
+}
+
+fn write_line(out: &mut String, prefix: char, line: &[u8]) {
+    out.push(prefix);
+    out.push_str(&String::from_utf8_lossy(line));
+    if !line.ends_with(b"\n") {
+        out.push_str("\n\\ No newline at end of file\n");
+    }
+}
+
+// Writes the hunks of a diff, each with up to `CONTEXT` unchanged lines around its changes.
+fn write_hunks(out: &mut String, diff: &Diff) {
+    let ops = &diff.diff;
+    // The positions in the old and new files before each operation.
+    let mut positions = Vec::with_capacity(ops.len() + 1);
+    let (mut old, mut new) = (0, 0);
+    for op in ops {
+        positions.push((old, new));
+        match op {
+            LineDiff::Keep(..) => {
+                old += 1;
+                new += 1;
+            }
+            LineDiff::Delete(_) => old += 1,
+            LineDiff::New(_) => new += 1,
+        }
+    }
+    positions.push((old, new));
+
+    let changed = ops
+        .iter()
+        .enumerate()
+        .filter(|(_, op)| !matches!(op, LineDiff::Keep(..)))
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    let mut k = 0;
+    while k < changed.len() {
+        let start = changed[k].saturating_sub(CONTEXT);
+        let mut last = changed[k];
+        k += 1;
+        // Hunks whose context would overlap are merged.
+        while k < changed.len() && changed[k] - last <= 2 * CONTEXT + 1 {
+            last = changed[k];
+            k += 1;
+        }
+        let end = (last + 1 + CONTEXT).min(ops.len());
+
+        let (old_start, new_start) = positions[start];
+        let (old_end, new_end) = positions[end];
+        writeln!(
+            out,
+            "@@ -{} +{} @@",
+            range(old_start, old_end - old_start),
+            range(new_start, new_end - new_start)
+        )
+        .unwrap();
+        for op in &ops[start..end] {
+            match *op {
+                LineDiff::Keep(i, _) => write_line(out, ' ', diff.file_a.node(i)),
+                LineDiff::Delete(i) => write_line(out, '-', diff.file_a.node(i)),
+                LineDiff::New(j) => write_line(out, '+', diff.file_b.node(j)),
+            }
+        }
+    }
+}
+
+// Writes the section of the diff for one file.
+fn write_file(out: &mut String, repo: &Repo, old: Option<&Side>, new: Option<&Side>) {
+    let path = |side: Option<&Side>, prefix: &str| match side {
+        Some(side) => format!("{}{}", prefix, side.path),
+        None => "/dev/null".to_owned(),
+    };
+    let (old_name, new_name) = (path(old, "a/"), path(new, "b/"));
+    writeln!(
+        out,
+        "diff --git a/{} b/{}",
+        old.or(new).unwrap().path,
+        new.or(old).unwrap().path
+    )
+    .unwrap();
+    match (old, new) {
+        (None, Some(new)) => writeln!(out, "new file mode {}", new.mode).unwrap(),
+        (Some(old), None) => writeln!(out, "deleted file mode {}", old.mode).unwrap(),
+        (Some(old), Some(new)) => {
+            if old.mode != new.mode {
+                writeln!(out, "old mode {}\nnew mode {}", old.mode, new.mode).unwrap();
+            }
+            if old.path != new.path {
+                writeln!(out, "rename from {}\nrename to {}", old.path, new.path).unwrap();
+            }
+        }
+        (None, None) => unreachable!(),
+    }
+
+    let empty = Contents::Text(Vec::new());
+    let old_contents = old.map(|s| &s.contents).unwrap_or(&empty);
+    let new_contents = new.map(|s| &s.contents).unwrap_or(&empty);
+    match (old_contents, new_contents) {
+        (Contents::Text(a), Contents::Text(b)) => {
+            if a != b {
+                let opts = repo.config().diff_options();
+                let diff = Diff::new(File::from_bytes(a), b, &opts);
+                writeln!(out, "--- {}\n+++ {}", old_name, new_name).unwrap();
+                write_hunks(out, &diff);
+            }
+        }
+        (a, b) => {
+            if a != b {
+                writeln!(out, "Binary files {} and {} differ", old_name, new_name).unwrap();
+            }
+        }
+    }
+}
+
+impl Patch {
+    /// Writes this patch as a unified diff, in the format of `git diff`.
+    ///
+    /// The diff is against the files as the patches that this one depends on (directly or
+    /// indirectly) left them, so all of those patches must be known to `repo` (and this one needs
+    /// to be registered with it). Files with conflicts are shown the way that
+    /// [`Repo::render`] writes them, with conflict markers. Changes to binary contents are only
+    /// mentioned, and not shown.
+    pub fn to_unified_diff(&self, repo: &Repo) -> Result<String, Error> {
+        let graph = repo.patch_graph();
+        let deps = graph.transitive_deps(self.id());
+        let mut before = GraggleData::new();
+        let mut lines = HashMap::new();
+        let mut add_lines = |patch: &Patch| {
+            for (_, ch) in patch.changes().flattened() {
+                if let Change::NewNode { id, contents } = ch {
+                    lines.insert(*id, contents.clone());
+                }
+            }
+        };
+        for dep in graph.apply_order(&deps) {
+            let dep = repo.open_patch(&dep)?;
+            dep.apply_to(&mut before);
+            add_lines(&dep);
+        }
+        add_lines(self);
+        let mut after = before.clone();
+        self.apply_to(&mut after);
+        before.resolve_pseudo_edges();
+        after.resolve_pseudo_edges();
+
+        let before = sides(repo, &before, &lines)?;
+        let after = sides(repo, &after, &lines)?;
+        let mut files = before
+            .keys()
+            .chain(after.keys())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(|id| (before.get(id), after.get(id)))
+            .filter(|(old, new)| old != new)
+            .collect::<Vec<_>>();
+        files.sort_by_key(|(old, new)| &new.or(*old).unwrap().path);
+
+        let mut ret = String::new();
+        for (old, new) in files {
+            write_file(&mut ret, repo, old, new);
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Change, Changes, MemoryWorkingCopy, PatchId, RecordOptions, Repo};
+
+    fn record(repo: &mut Repo) -> PatchId {
+        repo.record("Author", "Msg", &RecordOptions::default())
+            .unwrap()
+    }
+
+    fn unified(repo: &Repo, id: &PatchId) -> String {
+        repo.open_patch(id).unwrap().to_unified_diff(repo).unwrap()
+    }
+
+    #[test]
+    fn edit() {
+        let wc = MemoryWorkingCopy::new();
+        let mut repo = Repo::init_in_memory(wc.clone());
+        let lines = (1..=12).map(|i| format!("{}\n", i)).collect::<String>();
+        wc.write("a.txt", lines.as_bytes());
+        repo.track_file("a.txt").unwrap();
+        let first = record(&mut repo);
+        assert_eq!(
+            unified(&repo, &first),
+            format!(
+                "diff --git a/a.txt b/a.txt\nnew file mode 100644\n--- /dev/null\n+++ b/a.txt\n\
+                 @@ -0,0 +1,12 @@\n{}",
+                (1..=12).map(|i| format!("+{}\n", i)).collect::<String>()
+            )
+        );
+
+        let edited = lines.replacen("2\n", "two\n", 1).replace("12\n", "12");
+        wc.write("a.txt", edited.as_bytes());
+        let second = record(&mut repo);
+        assert_eq!(
+            unified(&repo, &second),
+            "diff --git a/a.txt b/a.txt\n--- a/a.txt\n+++ b/a.txt\n\
+             @@ -1,5 +1,5 @@\n 1\n-2\n+two\n 3\n 4\n 5\n\
+             @@ -9,4 +9,4 @@\n 9\n 10\n 11\n-12\n+12\n\\ No newline at end of file\n"
+        );
+    }
+
+    #[test]
+    fn files() {
+        let wc = MemoryWorkingCopy::new();
+        let mut repo = Repo::init_in_memory(wc.clone());
+        wc.write("a.txt", b"a\n");
+        wc.write("b.txt", b"b\n");
+        repo.track_file("a.txt").unwrap();
+        repo.track_file("b.txt").unwrap();
+        let first = record(&mut repo);
+
+        let a = repo.file_ref("master", "a.txt").unwrap();
+        let b = repo.file_ref("master", "b.txt").unwrap();
+        let changes = Changes {
+            changes: vec![
+                Change::MoveFile {
+                    from: a,
+                    to: "c.txt".to_owned(),
+                },
+                Change::DeleteFile { file: b },
+            ],
+        };
+        let id = repo.create_patch("Author", "Msg", changes).unwrap();
+        assert_eq!(repo.patch_deps(&id).collect::<Vec<_>>(), vec![&first]);
+        assert_eq!(
+            unified(&repo, &id),
+            "diff --git a/b.txt b/b.txt\ndeleted file mode 100644\n--- a/b.txt\n+++ /dev/null\n\
+             @@ -1 +0,0 @@\n-b\n\
+             diff --git a/a.txt b/c.txt\nrename from a.txt\nrename to c.txt\n"
+        );
+    }
+}
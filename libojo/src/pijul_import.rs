@@ -0,0 +1,486 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Importing pijul changes (see `Repo::import_pijul`).
+//
+// Pijul keeps its changes in a compressed binary format that only pijul itself reads, but
+// `pijul change <hash>` prints a change as text: a header with its message, its authors and its
+// time, the changes that it depends on, and then a numbered list of hunks. Each hunk says what it
+// does to which file ("File addition", "Edit in", "Replacement in", "File deletion", "Moved"),
+// followed by the positions in pijul's graph that it connects to, and the lines that it adds (with
+// a `+` in front) or deletes (with a `-`).
+//
+// Pijul's graph is a lot like a graggle, but its positions refer to byte offsets in other changes,
+// which the text doesn't have the contents of. So instead of translating the graph, the changes
+// are replayed on the files of the branch, in the same way as darcs patches: the line number that
+// the text gives for each edit says where its lines go. These line numbers refer to the file
+// before the change, so the edits to a file are carried out starting from the bottom. Hunks that
+// only make sense in terms of the graph (like ones that solve conflicts or bring back deleted
+// lines) aren't supported.
+
+use chrono::{DateTime, Utc};
+use std::cmp::Reverse;
+use std::io::BufRead;
+
+use crate::darcs_import::{self, DarcsPatch, Prim};
+use crate::{Error, PatchHeader, PatchId, Repo};
+
+#[derive(Default)]
+struct Author {
+    name: Option<String>,
+    full_name: Option<String>,
+    email: Option<String>,
+    key: Option<String>,
+}
+
+struct Parser {
+    lines: Vec<String>,
+    // The number of lines that have been read.
+    pos: usize,
+}
+
+// Reads a string in double quotes from the start of `s`, and returns it with the rest of `s`.
+// Quotes and backslashes in the string are escaped by backslashes, as are some control
+// characters.
+fn quoted(s: &str) -> Option<(String, &str)> {
+    let mut chars = s.strip_prefix('"')?.char_indices();
+    let mut ret = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((ret, &s[i + 2..])),
+            '\\' => match chars.next()?.1 {
+                'n' => ret.push('\n'),
+                't' => ret.push('\t'),
+                'r' => ret.push('\r'),
+                '0' => ret.push('\0'),
+                'u' => {
+                    // Either `\u{1F600}` or `\u00e9`.
+                    let rest = chars.as_str();
+                    let (hex, len) = match rest.strip_prefix('{') {
+                        Some(r) => {
+                            let end = r.find('}')?;
+                            (&r[..end], end + 2)
+                        }
+                        None => (rest.get(..4)?, 4),
+                    };
+                    ret.push(char::from_u32(u32::from_str_radix(hex, 16).ok()?)?);
+                    for _ in 0..len {
+                        chars.next();
+                    }
+                }
+                c => ret.push(c),
+            },
+            c => ret.push(c),
+        }
+    }
+    None
+}
+
+fn join_path(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+impl Parser {
+    fn error<T>(&self, msg: &str) -> Result<T, Error> {
+        Err(Error::PijulChange(self.pos, msg.to_owned()))
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.lines.get(self.pos).map(|l| l.as_str())
+    }
+
+    // Reads the value of a setting in the header, like `message = 'Fix things'`. Strings in
+    // single quotes are taken as they are, and strings in double quotes can have escapes; either
+    // kind can be tripled, in which case it can span several lines.
+    fn value(&mut self, value: &str) -> Result<String, Error> {
+        for delim in &["'''", "\"\"\""] {
+            if let Some(rest) = value.strip_prefix(delim) {
+                // A newline straight after the opening quotes doesn't count.
+                let mut lines = vec![rest.to_owned()];
+                while !lines.last().unwrap().ends_with(delim) {
+                    match self.lines.get(self.pos) {
+                        Some(line) => lines.push(line.clone()),
+                        None => return self.error("unterminated string"),
+                    }
+                    self.pos += 1;
+                }
+                let mut ret = lines.join("\n");
+                ret.truncate(ret.len() - 3);
+                if ret.starts_with('\n') {
+                    ret.remove(0);
+                }
+                if delim.starts_with('"') {
+                    ret = match quoted(&format!("\"{}\"", ret.replace('"', "\\\""))) {
+                        Some((s, _)) => s,
+                        None => return self.error("invalid string"),
+                    };
+                }
+                return Ok(ret);
+            }
+        }
+        if let Some(rest) = value.strip_prefix('\'') {
+            if let Some(s) = rest.strip_suffix('\'') {
+                return Ok(s.to_owned());
+            }
+        } else if let Some((s, "")) = quoted(value) {
+            return Ok(s);
+        }
+        self.error(&format!("invalid value {}", value))
+    }
+
+    // Reads the lines that a hunk adds and deletes.
+    fn hunk_lines(&mut self) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+        let (mut old, mut new) = (Vec::new(), Vec::new());
+        while let Some(line) = self.peek() {
+            let lines = match line.chars().next() {
+                Some('+') => &mut new,
+                Some('-') => &mut old,
+                // The positions in the graph are indented.
+                Some(' ') | Some('\t') => {
+                    self.pos += 1;
+                    continue;
+                }
+                _ => break,
+            };
+            let line = line[1..].strip_prefix(' ').unwrap_or(&line[1..]);
+            lines.push(line.as_bytes().to_owned());
+            self.pos += 1;
+        }
+        (old, new)
+    }
+
+    // Reads a hunk, whose description (after its number) is `desc`, and adds the changes that it
+    // makes to `prims`.
+    fn hunk(&mut self, desc: &str, prims: &mut Vec<(usize, Prim)>) -> Result<(), Error> {
+        let line = self.pos;
+        let invalid = || Error::PijulChange(line, format!("invalid hunk {:?}", desc));
+        if let Some(rest) = desc.strip_prefix("File addition: ") {
+            let (name, rest) = quoted(rest).ok_or_else(invalid)?;
+            let rest = rest.strip_prefix(" in ").ok_or_else(invalid)?;
+            let (dir, rest) = quoted(rest).ok_or_else(invalid)?;
+            let path = join_path(&dir, &name);
+            let (_, new) = self.hunk_lines();
+            // Directories have a `d` in their permissions, like `+dx`.
+            if rest
+                .split(' ')
+                .any(|w| w.starts_with('+') && w.contains('d'))
+            {
+                prims.push((line, Prim::Nothing));
+            } else {
+                prims.push((line, Prim::AddFile(path.clone())));
+                if !new.is_empty() {
+                    let old = Vec::new();
+                    prims.push((
+                        line,
+                        Prim::Hunk {
+                            path,
+                            line: 1,
+                            old,
+                            new,
+                        },
+                    ));
+                }
+            }
+        } else if let Some(rest) = desc.strip_prefix("File deletion: ") {
+            let (path, rest) = quoted(rest).ok_or_else(invalid)?;
+            let (old, _) = self.hunk_lines();
+            // Only files have an encoding, and the deletion of a directory doesn't matter.
+            if old.is_empty() && !rest.contains('"') {
+                prims.push((line, Prim::Nothing));
+            } else {
+                prims.push((line, Prim::RemoveFile(path)));
+            }
+        } else if let Some(rest) = desc.strip_prefix("Moved: ") {
+            let (from, rest) = quoted(rest).ok_or_else(invalid)?;
+            let (to, _) = quoted(rest.trim_start()).ok_or_else(invalid)?;
+            self.hunk_lines();
+            prims.push((line, Prim::Move(from, to)));
+        } else if let Some(rest) = desc
+            .strip_prefix("Edit in ")
+            .or_else(|| desc.strip_prefix("Replacement in "))
+        {
+            let (path, rest) = quoted(rest).ok_or_else(invalid)?;
+            let rest = rest.strip_prefix(':').ok_or_else(invalid)?;
+            let number = rest.split(' ').next().unwrap();
+            let number = match number.parse() {
+                Ok(n) if n > 0 => n,
+                _ => return self.error("invalid line number"),
+            };
+            let (old, new) = self.hunk_lines();
+            let hunk = Prim::Hunk {
+                path,
+                line: number,
+                old,
+                new,
+            };
+            prims.push((line, hunk));
+        } else {
+            let kind = desc.split([':', '"']).next().unwrap().trim();
+            return self.error(&format!("{:?} hunks aren't supported", kind));
+        }
+        Ok(())
+    }
+
+    // Reads a change, whose first line (setting its message) is next.
+    fn change(&mut self) -> Result<DarcsPatch, Error> {
+        let start = self.pos;
+        let mut message = None;
+        let mut description = None;
+        let mut timestamp = None;
+        let mut authors: Vec<Author> = Vec::new();
+        let mut prims = Vec::new();
+        // Whether we're reading the list of authors (as opposed to the rest of the header).
+        let mut in_authors = false;
+        // Whether we've got to the dependencies or the hunks.
+        let mut in_body = false;
+
+        while let Some(line) = self.peek().map(|l| l.to_owned()) {
+            if line.starts_with("message = ") && self.pos > start {
+                break;
+            }
+            self.pos += 1;
+            let number_len = line.chars().take_while(|c| c.is_ascii_digit()).count();
+            if number_len > 0 && line[number_len..].starts_with(". ") {
+                in_body = true;
+                self.hunk(&line[number_len + 2..], &mut prims)?;
+            } else if line.starts_with('#') {
+                in_body = true;
+            } else if in_body || line.trim().is_empty() {
+                // The dependencies, and the blank lines between sections.
+            } else if line == "[[authors]]" {
+                in_authors = true;
+                authors.push(Author::default());
+            } else if line.starts_with('[') {
+                in_authors = false;
+            } else if let Some(i) = line.find(" = ") {
+                let value = self.value(&line[i + 3..])?;
+                match (in_authors, &line[..i]) {
+                    (false, "message") => message = Some(value),
+                    (false, "description") => description = Some(value),
+                    (false, "timestamp") => match DateTime::parse_from_rfc3339(&value) {
+                        Ok(t) => timestamp = Some(t.with_timezone(&Utc)),
+                        Err(_) => return self.error(&format!("invalid time {:?}", value)),
+                    },
+                    (true, key) => {
+                        let author = authors.last_mut().unwrap();
+                        match key {
+                            "name" => author.name = Some(value),
+                            "full_name" => author.full_name = Some(value),
+                            "email" => author.email = Some(value),
+                            "key" => author.key = Some(value),
+                            _ => {}
+                        }
+                    }
+                    _ => {}
+                }
+            } else {
+                return self.error(&format!("unexpected line {:?}", line));
+            }
+        }
+
+        let message = message.unwrap();
+        let description = match description {
+            Some(d) if !d.trim().is_empty() => format!("{}\n\n{}", message, d.trim()),
+            _ => message,
+        };
+        let author = authors.into_iter().next().unwrap_or_default();
+        let name = author
+            .full_name
+            .or(author.name)
+            .or(author.key)
+            .unwrap_or_default();
+        let mut header = PatchHeader::new(name, description);
+        header.email = author.email;
+        match timestamp {
+            Some(t) => header.timestamp = t,
+            None => {
+                let msg = "the change has no timestamp".to_owned();
+                return Err(Error::PijulChange(start + 1, msg));
+            }
+        }
+
+        // The line numbers of the edits refer to the files before the change, so the edits to a
+        // file are carried out from the bottom up, so that they don't move the ones above them.
+        let mut i = 0;
+        while i < prims.len() {
+            let run = prims[i..]
+                .iter()
+                .take_while(|(_, p)| matches!(p, Prim::Hunk { .. }))
+                .count();
+            prims[i..i + run].sort_by_key(|(_, p)| match p {
+                Prim::Hunk { path, line, .. } => (path.clone(), Reverse(*line)),
+                _ => unreachable!(),
+            });
+            i += run.max(1);
+        }
+        Ok(DarcsPatch { header, prims })
+    }
+
+    fn changes(&mut self) -> Result<Vec<DarcsPatch>, Error> {
+        let mut ret = Vec::new();
+        while let Some(line) = self.peek() {
+            if line.starts_with("message = ") {
+                ret.push(self.change()?);
+            } else if line.trim().is_empty() {
+                self.pos += 1;
+            } else {
+                return self.error("expected the message of a change");
+            }
+        }
+        Ok(ret)
+    }
+}
+
+pub(crate) fn import<R: BufRead>(
+    repo: &mut Repo,
+    branch: &str,
+    input: R,
+) -> Result<Vec<PatchId>, Error> {
+    let mut lines = Vec::new();
+    for line in input.split(b'\n') {
+        let line = line?;
+        lines.push(String::from_utf8_lossy(&line).into_owned());
+    }
+    let mut parser = Parser { lines, pos: 0 };
+    let changes = parser.changes()?;
+    if changes.is_empty() {
+        return parser.error("there are no changes");
+    }
+    darcs_import::replay(repo, branch, changes, Error::PijulChange)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHANGES: &str = r#"message = 'Add some files'
+description = '''
+With a longer description.'''
+timestamp = '2021-02-03T04:05:06.123456Z'
+
+[[authors]]
+name = 'alice'
+full_name = 'Alice'
+email = 'alice@example.com'
+
+# Hunks
+
+1. File addition: "dir" in "" +dx
+  up 1.0, new 0:5
+
+2. File addition: "a file.txt" in "dir" "UTF-8"
+  up 0.5, new 6:20
++ one
++ two
++ three
++ four
+
+message = "Move and edit"
+timestamp = '2021-02-04T00:00:00Z'
+
+[[authors]]
+key = 'ABCDEFG'
+
+# Dependencies
+[2] 4OVMOOBOQ5JR2WXTVRB33KHHNNOZVDAI5PVCXQWUOH2EXTMYSOBAC
+
+# Hunks
+
+1. Moved: "dir/a file.txt" "renamed.txt" "UTF-8"
+  up 2.0, new 0:14
+
+2. Replacement in "renamed.txt":1 2.6 "UTF-8"
+  B:BD 2.10 -> 2.10:14/2
+- one
++ ONE
+
+3. Edit in "renamed.txt":3 2.6 "UTF-8"
+  up 2.18, new 15:21, down 2.18
++ 2 1/2
+
+4. Edit in "renamed.txt":4 2.6 "UTF-8"
+  B:BD 2.22 -> 2.22:27/2
+- four
+"#;
+
+    #[test]
+    fn changes() {
+        let mut repo = Repo::init_tmp();
+        let ids = repo.import_pijul("master", CHANGES.as_bytes()).unwrap();
+        assert_eq!(ids.len(), 2);
+
+        let header = repo.patch_header(&ids[0]).unwrap();
+        assert_eq!(header.author, "Alice");
+        assert_eq!(header.email.as_deref(), Some("alice@example.com"));
+        assert_eq!(
+            header.description,
+            "Add some files\n\nWith a longer description."
+        );
+        assert_eq!(
+            header.timestamp.to_rfc3339(),
+            "2021-02-03T04:05:06.123456+00:00"
+        );
+        let header = repo.patch_header(&ids[1]).unwrap();
+        assert_eq!(header.author, "ABCDEFG");
+        assert_eq!(header.description, "Move and edit");
+
+        assert_eq!(
+            repo.files("master").unwrap().collect::<Vec<_>>(),
+            vec!["renamed.txt"]
+        );
+        assert_eq!(
+            repo.file_at("master", "renamed.txt").unwrap().as_bytes(),
+            b"ONE\ntwo\n2 1/2\nthree\n"
+        );
+        // The file was moved, rather than deleted and created again.
+        let id = repo.file_id("master", "renamed.txt").unwrap();
+        assert_eq!(id.patch, ids[0]);
+    }
+
+    #[test]
+    fn errors() {
+        let mut repo = Repo::init_tmp();
+        let mut import = |changes: &str| repo.import_pijul("master", changes.as_bytes());
+        let header = "message = 'M'\ntimestamp = '2021-02-03T04:05:06Z'\n\n# Hunks\n\n";
+        match import(&format!(
+            "{}1. Resurrecting zombie lines in \"a\":1\n",
+            header
+        )) {
+            Err(Error::PijulChange(6, _)) => {}
+            x => panic!("expected an error, got {:?}", x),
+        }
+        match import(&format!(
+            "{}1. Edit in \"a\":1 2.3 \"UTF-8\"\n+ a\n",
+            header
+        )) {
+            Err(Error::PijulChange(6, _)) => {}
+            x => panic!("expected an error, got {:?}", x),
+        }
+        match import("message = 'M'\n") {
+            Err(Error::PijulChange(1, _)) => {}
+            x => panic!("expected an error, got {:?}", x),
+        }
+        assert_eq!(repo.all_patches().count(), 0);
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(
+            quoted(r#""a \"b\"\\\u{e9}" rest"#),
+            Some(("a \"b\"\\é".to_owned(), " rest"))
+        );
+        assert_eq!(quoted(r#""unterminated"#), None);
+    }
+}
@@ -0,0 +1,232 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Long operations report how far they've got to the `ProgressSink` of the repository, if it has
+// one. The operations themselves only see a `Reporter`, which keeps count.
+
+use std::fmt;
+use std::sync::Arc;
+
+use crate::{PatchId, Repo};
+
+/// A long operation that reports its progress (see [`ProgressSink`]).
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Phase {
+    /// Applying a patch, along with the dependencies that the branch doesn't have yet (see
+    /// [`Repo::apply`](crate::Repo::apply)). Each step is a patch that was applied.
+    Apply,
+    /// Rebuilding the branches of a new repository (see
+    /// [`Repo::clone_with_progress`](crate::Repo::clone_with_progress)). Each step is a patch that
+    /// was applied to one of the branches.
+    Clone,
+    /// Removing the patches that aren't needed any more (see [`Repo::gc`](crate::Repo::gc)).
+    /// The patches are removed all at once, so there is only one step.
+    Gc,
+    /// Merging one branch into another (see [`Repo::merge`](crate::Repo::merge)). Each step is a
+    /// patch that was applied (along with its dependencies).
+    Merge,
+}
+
+/// How far a long operation has got.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Progress<'a> {
+    /// The operation.
+    pub phase: Phase,
+    /// The number of steps that are done.
+    pub done: usize,
+    /// The total number of steps.
+    pub total: usize,
+    /// The total size (in bytes) of the patches that the steps so far have processed.
+    pub bytes: u64,
+    /// The patch that the last step processed, if it processed exactly one.
+    pub patch: Option<&'a PatchId>,
+}
+
+/// Receives reports about the progress of long operations, for example to draw a progress bar
+/// or to log them.
+///
+/// A sink is registered with [`Repo::set_progress_sink`](crate::Repo::set_progress_sink).
+/// Operations can be nested (merging a branch applies patches, for example), so the reports
+/// from different [`Phase`]s can be interleaved. The methods do nothing by default.
+pub trait ProgressSink: Send + Sync {
+    /// An operation started. No steps are done yet.
+    fn start(&self, _progress: &Progress<'_>) {}
+
+    /// A step of an operation finished.
+    fn step(&self, _progress: &Progress<'_>) {}
+
+    /// An operation finished successfully. If it fails, this isn't called.
+    fn finish(&self, _progress: &Progress<'_>) {}
+}
+
+// The progress sink of a repository, if it has one.
+#[derive(Clone, Default)]
+pub(crate) struct Sink(pub Option<Arc<dyn ProgressSink>>);
+
+impl fmt::Debug for Sink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(_) => f.write_str("Sink(..)"),
+            None => f.write_str("Sink(None)"),
+        }
+    }
+}
+
+// Keeps count of the steps of an operation, and tells the sink about them.
+pub(crate) struct Reporter {
+    sink: Sink,
+    phase: Phase,
+    done: usize,
+    total: usize,
+    bytes: u64,
+}
+
+impl Reporter {
+    pub(crate) fn start(repo: &Repo, phase: Phase, total: usize) -> Reporter {
+        let ret = Reporter {
+            sink: repo.progress.clone(),
+            phase,
+            done: 0,
+            total,
+            bytes: 0,
+        };
+        if let Some(ref sink) = ret.sink.0 {
+            sink.start(&ret.progress(None));
+        }
+        ret
+    }
+
+    fn progress<'a>(&self, patch: Option<&'a PatchId>) -> Progress<'a> {
+        Progress {
+            phase: self.phase,
+            done: self.done,
+            total: self.total,
+            bytes: self.bytes,
+            patch,
+        }
+    }
+
+    // Reports that `steps` more steps are done, which processed `bytes` more bytes.
+    pub(crate) fn steps(&mut self, steps: usize, bytes: u64, patch: Option<&PatchId>) {
+        self.done += steps;
+        self.bytes += bytes;
+        if let Some(ref sink) = self.sink.0 {
+            sink.step(&self.progress(patch));
+        }
+    }
+
+    // Reports that a patch was processed.
+    pub(crate) fn patch(&mut self, repo: &Repo, id: &PatchId) {
+        self.steps(1, patch_size(repo, id), Some(id));
+    }
+
+    pub(crate) fn finish(self) {
+        if let Some(ref sink) = self.sink.0 {
+            sink.finish(&self.progress(None));
+        }
+    }
+}
+
+// The size of a patch's data, or zero if we don't have it.
+pub(crate) fn patch_size(repo: &Repo, id: &PatchId) -> u64 {
+    repo.open_patch_data(id)
+        .map(|d| d.len() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::create;
+    use std::sync::Mutex;
+
+    // Remembers the reports, as (phase, done, total, patch) for the steps.
+    #[derive(Default)]
+    struct Recorder {
+        starts: Mutex<Vec<(Phase, usize)>>,
+        steps: Mutex<Vec<(Phase, usize, usize, Option<PatchId>)>>,
+        finishes: Mutex<Vec<Phase>>,
+    }
+
+    impl ProgressSink for Recorder {
+        fn start(&self, p: &Progress<'_>) {
+            self.starts.lock().unwrap().push((p.phase, p.total));
+        }
+
+        fn step(&self, p: &Progress<'_>) {
+            assert!(p.bytes > 0);
+            let step = (p.phase, p.done, p.total, p.patch.cloned());
+            self.steps.lock().unwrap().push(step);
+        }
+
+        fn finish(&self, p: &Progress<'_>) {
+            self.finishes.lock().unwrap().push(p.phase);
+        }
+    }
+
+    #[test]
+    fn apply_and_merge() {
+        let mut repo = Repo::init_tmp();
+        create(&mut repo, "master", b"a\n");
+        repo.clone_branch("master", "other").unwrap();
+        let first = create(&mut repo, "other", b"a\nb\n");
+        let second = create(&mut repo, "other", b"a\nb\nc\n");
+
+        let recorder = Arc::new(Recorder::default());
+        repo.set_progress_sink(recorder.clone());
+        repo.apply_patch("master", &second).unwrap();
+        assert_eq!(*recorder.starts.lock().unwrap(), vec![(Phase::Apply, 2)]);
+        assert_eq!(
+            *recorder.steps.lock().unwrap(),
+            vec![
+                (Phase::Apply, 1, 2, Some(first)),
+                (Phase::Apply, 2, 2, Some(second))
+            ]
+        );
+        assert_eq!(*recorder.finishes.lock().unwrap(), vec![Phase::Apply]);
+
+        repo.unapply_patch("master", &first).unwrap();
+        recorder.steps.lock().unwrap().clear();
+        repo.merge("other", "master", false).unwrap();
+        let merge_steps = recorder
+            .steps
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|s| s.0 == Phase::Merge)
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            merge_steps,
+            vec![
+                (Phase::Merge, 1, 2, Some(first)),
+                (Phase::Merge, 2, 2, Some(second))
+            ]
+        );
+    }
+
+    #[test]
+    fn gc() {
+        let mut repo = Repo::init_tmp();
+        let a = create(&mut repo, "master", b"a\n");
+        repo.unapply_patch("master", &a).unwrap();
+
+        let recorder = Arc::new(Recorder::default());
+        repo.set_progress_sink(recorder.clone());
+        assert_eq!(repo.gc(std::time::Duration::from_secs(0), false), vec![a]);
+        assert_eq!(*recorder.starts.lock().unwrap(), vec![(Phase::Gc, 1)]);
+        assert_eq!(
+            *recorder.steps.lock().unwrap(),
+            vec![(Phase::Gc, 1, 1, None)]
+        );
+        assert_eq!(*recorder.finishes.lock().unwrap(), vec![Phase::Gc]);
+    }
+}
@@ -0,0 +1,191 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+use crate::{ApplyPolicy, Error, PatchId, RebaseGuess, Repo};
+
+/// What to do about the dependencies of a patch that is pulled from one branch to another (see
+/// [`Repo::pull_patches`]).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PullPolicy {
+    /// Pull all of the patch's dependencies, too.
+    Dependencies,
+    /// If possible, re-derive the patch against the branch (see [`Repo::rebase_patch`]) so that it
+    /// doesn't need the dependencies that are missing. This is possible if none of the patch's
+    /// changes have to be left out; otherwise, the dependencies are pulled.
+    Commute,
+}
+
+/// A patch that was re-derived in order to avoid pulling its dependencies.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Commuted {
+    /// The patch that was selected.
+    pub original: PatchId,
+    /// The re-derived patch that was applied instead.
+    pub patch: PatchId,
+    /// The places where the re-derived patch differs from the original one.
+    pub guesses: Vec<RebaseGuess>,
+}
+
+/// The result of pulling patches from one branch to another (see [`Repo::pull_patches`]).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PullReport {
+    /// All of the patches that were applied, in the order that they were applied. This includes
+    /// dependencies and re-derived patches.
+    pub applied: Vec<PatchId>,
+    /// The selected patches that were re-derived instead of being applied as they are.
+    pub commuted: Vec<Commuted>,
+}
+
+// Applies a single patch that was selected for pulling.
+fn pull_one(
+    repo: &mut Repo,
+    into: &str,
+    id: &PatchId,
+    policy: PullPolicy,
+    report: &mut PullReport,
+) -> Result<(), Error> {
+    if policy == PullPolicy::Commute {
+        let rebase = repo.rebase_patch(into, id)?;
+        let dropped = rebase
+            .guesses
+            .iter()
+            .any(|g| matches!(g, RebaseGuess::Dropped(_)));
+        if !rebase.is_exact() && !dropped {
+            let header = repo.patch_header(id)?.clone();
+            let new_id = repo.create_patch_with_header(header, rebase.changes)?;
+            report
+                .applied
+                .extend(repo.apply(into, &new_id, ApplyPolicy::Refuse)?);
+            report.commuted.push(Commuted {
+                original: *id,
+                patch: new_id,
+                guesses: rebase.guesses,
+            });
+            return Ok(());
+        }
+    }
+    report
+        .applied
+        .extend(repo.apply(into, id, ApplyPolicy::Cascade)?);
+    Ok(())
+}
+
+pub(crate) fn pull(
+    repo: &mut Repo,
+    from: &str,
+    into: &str,
+    ids: &[PatchId],
+    policy: PullPolicy,
+) -> Result<PullReport, Error> {
+    repo.inode(from)?;
+    repo.inode(into)?;
+    for id in ids {
+        if !repo.storage.branch_patches.contains(from, id) {
+            return Err(Error::NotOnBranch(*id, from.to_owned()));
+        }
+    }
+
+    // Each selected patch has to be pulled after the selected patches that it depends on.
+    let order = repo.patch_graph().apply_order(ids);
+
+    let pull_all = |repo: &mut Repo| -> Result<PullReport, Error> {
+        let mut report = PullReport::default();
+        for id in &order {
+            pull_one(repo, into, id, policy, &mut report)?;
+        }
+        Ok(report)
+    };
+    // Applying a single patch already undoes itself if it fails, so that doesn't need a
+    // transaction. Otherwise, the transaction also forgets about the patches that were
+    // re-derived if something fails.
+    if order.len() <= 1 && policy == PullPolicy::Dependencies {
+        pull_all(repo)
+    } else {
+        repo.transaction(pull_all)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::create;
+
+    // Creates a branch "other" with "a\nc\n", and returns a patch on "master" that adds "b" and a
+    // patch that adds "x" after "b".
+    fn setup(repo: &mut Repo) -> (PatchId, PatchId) {
+        create(repo, "master", b"a\nc\n");
+        repo.clone_branch("master", "other").unwrap();
+        let b = create(repo, "master", b"a\nb\nc\n");
+        let x = create(repo, "master", b"a\nb\nx\nc\n");
+        (b, x)
+    }
+
+    #[test]
+    fn dependencies() {
+        let mut repo = Repo::init_tmp();
+        let (b, x) = setup(&mut repo);
+        let report = repo
+            .pull_patches("master", "other", &[x], PullPolicy::Dependencies)
+            .unwrap();
+        assert_eq!(report.applied, vec![b, x]);
+        assert!(report.commuted.is_empty());
+        assert_eq!(repo.file("other").unwrap().as_bytes(), b"a\nb\nx\nc\n");
+
+        // Pulling patches that are already there does nothing.
+        let report = repo
+            .pull_patches("master", "other", &[b, x], PullPolicy::Commute)
+            .unwrap();
+        assert!(report.applied.is_empty());
+    }
+
+    #[test]
+    fn commute() {
+        let mut repo = Repo::init_tmp();
+        let (b, x) = setup(&mut repo);
+        let report = repo
+            .pull_patches("master", "other", &[x], PullPolicy::Commute)
+            .unwrap();
+        assert_eq!(report.commuted.len(), 1);
+        assert_eq!(report.commuted[0].original, x);
+        assert_eq!(report.applied, vec![report.commuted[0].patch]);
+        assert_eq!(repo.file("other").unwrap().as_bytes(), b"a\nx\nc\n");
+        assert!(!repo.patches("other").any(|p| *p == b));
+    }
+
+    #[test]
+    fn commute_falls_back() {
+        let mut repo = Repo::init_tmp();
+        let (b, _) = setup(&mut repo);
+        // Deleting "b" can't be done without "b".
+        let delete_b = create(&mut repo, "master", b"a\nx\nc\n");
+        let report = repo
+            .pull_patches("master", "other", &[delete_b], PullPolicy::Commute)
+            .unwrap();
+        assert_eq!(report.applied, vec![b, delete_b]);
+        assert!(report.commuted.is_empty());
+    }
+
+    #[test]
+    fn not_on_branch() {
+        let mut repo = Repo::init_tmp();
+        let (_, x) = setup(&mut repo);
+        let diff = repo.diff("other", b"y\n").unwrap();
+        let y = repo.create_patch("Author", "Msg", diff.changes()).unwrap();
+        match repo.pull_patches("master", "other", &[x, y], PullPolicy::Dependencies) {
+            Err(Error::NotOnBranch(p, _)) => assert_eq!(p, y),
+            x => panic!("unexpected result {:?}", x),
+        }
+        assert_eq!(repo.file("other").unwrap().as_bytes(), b"a\nc\n");
+        assert!(repo
+            .pull_patches("master", "missing", &[x], PullPolicy::Dependencies)
+            .is_err());
+    }
+}
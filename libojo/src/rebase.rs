@@ -0,0 +1,379 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Re-deriving patches against branches that don't contain all of their dependencies.
+//
+// A patch commutes with every patch that it doesn't depend on, so if a branch contains all of a
+// patch's dependencies then the patch can be applied to it as is. Otherwise, the patch refers to
+// some lines that aren't on the branch. We rebuild the patch's original context (by applying all
+// of its dependencies to an empty graggle), and then replace every such line: by a line on the
+// branch with the same contents if there is exactly one, or else by the closest line in the
+// original context that is on the branch.
+
+use ojo_graph::Graph;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+
+use crate::storage::graggle::{EdgeKind, GraggleData};
+use crate::{Change, Changes, Error, NodeId, PatchId, Repo};
+
+/// A patch that was re-derived against a different branch (see
+/// [`Repo::rebase_patch`](crate::Repo::rebase_patch)).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Rebase {
+    /// The re-derived changes. If the original patch can be applied to the branch as it is, these
+    /// are the changes of the original patch.
+    pub changes: Changes,
+    /// All of the places where the changes had to be guessed. If this is empty, the re-derived
+    /// changes have exactly the same effect as the original patch.
+    pub guesses: Vec<RebaseGuess>,
+}
+
+impl Rebase {
+    /// Are the re-derived changes exactly equivalent to the original patch?
+    pub fn is_exact(&self) -> bool {
+        self.guesses.is_empty()
+    }
+}
+
+/// A place where a rebase had to guess, because the patch refers to something that isn't on the
+/// branch.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RebaseGuess {
+    /// A line that isn't on the branch was taken to be a line on the branch with the same
+    /// contents.
+    Matched {
+        /// The line that the patch refers to.
+        original: NodeId,
+        /// The line on the branch that it was replaced by.
+        replacement: NodeId,
+    },
+    /// The patch attaches something to a line that isn't on the branch, and no line with the same
+    /// contents is. Instead, it was attached to the closest line (before the original line, if
+    /// the patch attaches something after it, and vice versa) that is on the branch.
+    Reanchored {
+        /// The line that the patch refers to.
+        original: NodeId,
+        /// The line on the branch that it was replaced by, or `None` if there was no such line
+        /// (in which case the edge was left out, leaving the new line at the start or end of the
+        /// file).
+        replacement: Option<NodeId>,
+    },
+    /// A change that refers to something that isn't on the branch (for example, the deletion of a
+    /// line that isn't there, or a change to a file that isn't there) was left out.
+    Dropped(Change),
+}
+
+struct Rebaser<'a> {
+    repo: &'a Repo,
+    // The dependencies of the patch that aren't on the branch.
+    missing: HashSet<PatchId>,
+    // The contents of the lines that were introduced by the dependencies of the patch.
+    contents: HashMap<NodeId, Vec<u8>>,
+    // Lines that aren't on the branch, and the lines with the same contents that replace them.
+    matched: HashMap<NodeId, Option<NodeId>>,
+    // Lines that aren't on the branch, and the lines that replace them as anchors. The flag says
+    // whether we searched backwards from the original line.
+    anchors: HashMap<(NodeId, bool), Option<NodeId>>,
+    guesses: Vec<RebaseGuess>,
+}
+
+impl<'a> Rebaser<'a> {
+    fn contents(&self, id: &NodeId) -> &[u8] {
+        match self.contents.get(id) {
+            Some(c) => c,
+            None => self.repo.storage.contents(id),
+        }
+    }
+
+    // Finds the unique live line on the branch with the same contents as `id`.
+    fn matching(&mut self, target: &GraggleData, id: &NodeId) -> Option<NodeId> {
+        if let Some(m) = self.matched.get(id) {
+            return *m;
+        }
+        let contents = self.contents(id);
+        let mut candidates = target
+            .as_graggle()
+            .nodes()
+            .filter(|n| self.repo.storage.contents(n) == contents);
+        let ret = match (candidates.next(), candidates.next()) {
+            (Some(n), None) => Some(n),
+            _ => None,
+        };
+        if let Some(replacement) = ret {
+            self.guesses.push(RebaseGuess::Matched {
+                original: *id,
+                replacement,
+            });
+        }
+        self.matched.insert(*id, ret);
+        ret
+    }
+
+    // Finds the line on the branch that should replace `id` as the endpoint of an edge. If
+    // `backwards` is true, `id` is the source of the edge, and so we look for a replacement among
+    // the lines that came before it in the original context.
+    fn endpoint(
+        &mut self,
+        base: &GraggleData,
+        target: &GraggleData,
+        id: &NodeId,
+        backwards: bool,
+    ) -> Option<NodeId> {
+        let known = |n: &NodeId| n.is_staged() || target.as_graggle().has_node(n);
+        if known(id) {
+            return Some(*id);
+        }
+        if let Some(m) = self.matching(target, id) {
+            return Some(m);
+        }
+        if let Some(a) = self.anchors.get(&(*id, backwards)) {
+            return *a;
+        }
+
+        let mut ret = None;
+        let mut queue = VecDeque::new();
+        let mut seen = HashSet::new();
+        queue.push_back(*id);
+        while let Some(n) = queue.pop_front() {
+            if n != *id {
+                if known(&n) {
+                    ret = Some(n);
+                    break;
+                } else if let Some(m) = self.matching(target, &n) {
+                    ret = Some(m);
+                    break;
+                }
+            }
+            let neighbors = if backwards {
+                base.all_in_edges(&n).collect::<Vec<_>>()
+            } else {
+                base.all_out_edges(&n).collect::<Vec<_>>()
+            };
+            for e in neighbors {
+                if e.kind != EdgeKind::Pseudo && seen.insert(e.dest) {
+                    queue.push_back(e.dest);
+                }
+            }
+        }
+        self.guesses.push(RebaseGuess::Reanchored {
+            original: *id,
+            replacement: ret,
+        });
+        self.anchors.insert((*id, backwards), ret);
+        ret
+    }
+
+    fn rebase(&mut self, changes: &Changes, base: &GraggleData, target: &GraggleData) -> Changes {
+        let mut ret = Vec::new();
+        let mut seen = HashSet::new();
+        for ch in &changes.changes {
+            let new_ch = match ch {
+                Change::NewNode { .. } => Some(ch.clone()),
+                Change::NewEdge { src, dest } => {
+                    let src = self.endpoint(base, target, src, true);
+                    let dest = self.endpoint(base, target, dest, false);
+                    match (src, dest) {
+                        (Some(src), Some(dest)) if src != dest => {
+                            Some(Change::NewEdge { src, dest })
+                        }
+                        _ => None,
+                    }
+                }
+                Change::DeleteNode { id } => {
+                    if target.as_graggle().has_node(id) {
+                        Some(ch.clone())
+                    } else if let Some(m) = self.matching(target, id) {
+                        Some(Change::DeleteNode { id: m })
+                    } else {
+                        self.guesses.push(RebaseGuess::Dropped(ch.clone()));
+                        None
+                    }
+                }
+                Change::EditFile { file, changes } if !self.missing.contains(&file.patch) => {
+                    let empty = GraggleData::new();
+                    let base = base.file_graggle(file).unwrap_or(&empty);
+                    let target = target.file_graggle(file).unwrap_or(&empty);
+                    Some(Change::EditFile {
+                        file: file.clone(),
+                        changes: self.rebase(changes, base, target),
+                    })
+                }
+                _ => {
+                    let mut deps = BTreeSet::new();
+                    ch.add_deps(&mut deps);
+                    if deps.iter().any(|d| self.missing.contains(d)) {
+                        self.guesses.push(RebaseGuess::Dropped(ch.clone()));
+                        None
+                    } else {
+                        Some(ch.clone())
+                    }
+                }
+            };
+            if let Some(new_ch) = new_ch {
+                if seen.insert(new_ch.clone()) {
+                    ret.push(new_ch);
+                }
+            }
+        }
+        Changes { changes: ret }
+    }
+}
+
+pub(crate) fn rebase(repo: &Repo, branch: &str, id: &PatchId) -> Result<Rebase, Error> {
+    let inode = repo.inode(branch)?;
+    let patch = repo.open_patch(id)?;
+    let mut changes = patch.changes().clone();
+    changes.unset_patch_id(id);
+
+    let graph = repo.patch_graph();
+    let deps = graph.transitive_deps(id);
+    let missing = deps
+        .iter()
+        .filter(|d| !repo.storage.branch_patches.contains(branch, d))
+        .cloned()
+        .collect::<HashSet<_>>();
+    if missing.is_empty() {
+        return Ok(Rebase {
+            changes,
+            guesses: Vec::new(),
+        });
+    }
+
+    // Rebuild the context that the patch was created in. Dependencies point from patches to the
+    // patches they depend on, so the topological order needs to be reversed.
+    let deps = deps.into_iter().collect::<HashSet<_>>();
+    let mut order = graph
+        .node_filtered(|p| deps.contains(p))
+        .top_sort()
+        .expect("the patch dependency graph has a cycle");
+    order.reverse();
+    let mut base = GraggleData::new();
+    let mut contents = HashMap::new();
+    for dep in &order {
+        let dep = repo.open_patch(dep)?;
+        dep.apply_to(&mut base);
+        for (_, ch) in dep.changes().flattened() {
+            if let Change::NewNode { id, contents: c } = ch {
+                contents.insert(*id, c.clone());
+            }
+        }
+    }
+
+    let mut rebaser = Rebaser {
+        repo,
+        missing,
+        contents,
+        matched: HashMap::new(),
+        anchors: HashMap::new(),
+        guesses: Vec::new(),
+    };
+    let changes = rebaser.rebase(&changes, &base, repo.storage.graggle_data(inode));
+    Ok(Rebase {
+        changes,
+        guesses: rebaser.guesses,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RebaseGuess;
+    use crate::test_util::create;
+    use crate::{Change, PatchId, Repo};
+
+    // Rebases a patch onto "other", and applies the result there.
+    fn rebase(repo: &mut Repo, id: &PatchId) -> Vec<RebaseGuess> {
+        let rebase = repo.rebase_patch("other", id).unwrap();
+        let new_id = repo.create_patch("Author", "Msg", rebase.changes).unwrap();
+        repo.apply_patch("other", &new_id).unwrap();
+        rebase.guesses
+    }
+
+    #[test]
+    fn exact() {
+        let mut repo = Repo::init_tmp();
+        create(&mut repo, "master", b"a\nc\n");
+        repo.clone_branch("master", "other").unwrap();
+        let id = create(&mut repo, "master", b"a\nb\nc\n");
+        let mut rebase = repo.rebase_patch("other", &id).unwrap();
+        assert!(rebase.is_exact());
+        rebase.changes.set_patch_id(&id);
+        assert_eq!(&rebase.changes, repo.open_patch(&id).unwrap().changes());
+    }
+
+    #[test]
+    fn reanchor() {
+        let mut repo = Repo::init_tmp();
+        let a = create(&mut repo, "master", b"a\nc\n");
+        repo.clone_branch("master", "other").unwrap();
+        let b = create(&mut repo, "master", b"a\nb\nc\n");
+        let x = create(&mut repo, "master", b"a\nb\nx\nc\n");
+        let file = repo.file("master").unwrap();
+        let (line_a, line_b) = (*file.node_id(0), *file.node_id(1));
+        assert_eq!(line_a.patch, a);
+        assert_eq!(line_b.patch, b);
+
+        // The new line was attached after "b", which isn't on the other branch.
+        let guesses = rebase(&mut repo, &x);
+        assert_eq!(
+            guesses,
+            vec![RebaseGuess::Reanchored {
+                original: line_b,
+                replacement: Some(line_a),
+            }]
+        );
+        assert_eq!(repo.file("other").unwrap().as_bytes(), b"a\nx\nc\n");
+    }
+
+    #[test]
+    fn matched() {
+        let mut repo = Repo::init_tmp();
+        create(&mut repo, "master", b"a\nc\n");
+        repo.clone_branch("master", "other").unwrap();
+        let b = create(&mut repo, "master", b"a\nb\nc\n");
+        let x = create(&mut repo, "master", b"a\nb\nx\nc\n");
+        let line_b = *repo.file("master").unwrap().node_id(1);
+        assert_eq!(line_b.patch, b);
+        let delete_b = create(&mut repo, "master", b"a\nx\nc\n");
+
+        // Someone else added the same line on the other branch.
+        let diff = repo.diff("other", b"a\nb\nc\n").unwrap();
+        let other_b = repo.create_patch("Author", "Msg", diff.changes()).unwrap();
+        repo.apply_patch("other", &other_b).unwrap();
+        let other_line_b = *repo.file("other").unwrap().node_id(1);
+
+        let guesses = rebase(&mut repo, &x);
+        assert_eq!(
+            guesses,
+            vec![RebaseGuess::Matched {
+                original: line_b,
+                replacement: other_line_b,
+            }]
+        );
+        assert_eq!(repo.file("other").unwrap().as_bytes(), b"a\nb\nx\nc\n");
+
+        let guesses = rebase(&mut repo, &delete_b);
+        assert_eq!(guesses.len(), 1);
+        assert_eq!(repo.file("other").unwrap().as_bytes(), b"a\nx\nc\n");
+    }
+
+    #[test]
+    fn dropped() {
+        let mut repo = Repo::init_tmp();
+        create(&mut repo, "master", b"a\n");
+        repo.create_branch("other").unwrap();
+        let delete_a = create(&mut repo, "master", b"");
+        let guesses = rebase(&mut repo, &delete_a);
+        match &guesses[..] {
+            [RebaseGuess::Dropped(Change::DeleteNode { .. })] => {}
+            x => panic!("unexpected guesses {:?}", x),
+        }
+    }
+}
@@ -0,0 +1,705 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::tracked::{self, TrackedChanges, TrackedFiles};
+use crate::{
+    BlobHash, Change, Changes, Diff, DiffOptions, Error, FileRef, HunkId, PatchHeader, PatchId,
+    PendingChanges, Repo,
+};
+
+/// The file in the working copy that holds the lines of a branch, unless something else is
+/// specified.
+pub const DEFAULT_WORKING_FILE: &str = "ojo_file.txt";
+
+/// Options for recording the changes in the working copy (see
+/// [`Repo::record`](crate::Repo::record)).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RecordOptions {
+    /// The branch to record the changes on. If this isn't set, the current branch is used.
+    pub branch: Option<String>,
+    /// The path of the file in the working copy, relative to the root of the repository. The
+    /// default is [`DEFAULT_WORKING_FILE`].
+    pub path: String,
+    /// How to compare the working copy with the branch.
+    pub diff: DiffOptions,
+    /// The email address of the patch's author.
+    pub email: Option<String>,
+}
+
+impl Default for RecordOptions {
+    fn default() -> RecordOptions {
+        RecordOptions {
+            branch: None,
+            path: DEFAULT_WORKING_FILE.to_owned(),
+            diff: DiffOptions::default(),
+            email: None,
+        }
+    }
+}
+
+/// What a [`RecordHunk`] changes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RecordHunkKind {
+    /// Adds a file at this path.
+    NewFile(String),
+    /// Deletes the file at this path on the branch.
+    DeleteFile(String),
+    /// Moves a file on the branch.
+    MoveFile {
+        /// The path of the file on the branch.
+        from: String,
+        /// The path of the file in the working copy.
+        to: String,
+    },
+    /// Replaces the binary contents of the file at this path in the working copy (see
+    /// [`Config::is_binary`](crate::Config::is_binary)).
+    Binary(String),
+    /// Changes some lines of a file in the working copy.
+    Lines {
+        /// The path of the file in the working copy.
+        path: String,
+        /// The id of the changed lines' hunk in [`PendingRecord::file_changes`] for `path`.
+        hunk: HunkId,
+    },
+}
+
+/// One of the changes in a [`PendingRecord`], which can be recorded independently of the others
+/// (apart from the ones that it requires).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RecordHunk {
+    /// The id of this hunk. Like the ids of [`Hunk`](crate::Hunk)s, it doesn't change when other
+    /// hunks get recorded.
+    pub id: HunkId,
+    /// What this hunk changes.
+    pub kind: RecordHunkKind,
+    /// The hunks that have to be recorded along with this one. For example, the lines of an added
+    /// file can't be recorded without adding the file.
+    pub requires: Vec<HunkId>,
+}
+
+// A file with changed lines.
+#[derive(Clone, Debug)]
+struct PendingFile {
+    // The path of the file in the working copy.
+    path: String,
+    // The file that gets changed, or `None` if the changes are to the lines of the branch.
+    file: Option<FileRef>,
+    changes: PendingChanges,
+}
+
+// A binary file with changed contents.
+#[derive(Clone, Debug)]
+struct PendingBinary {
+    path: String,
+    // The file that gets changed, or `None` if the changes are to the binary contents of the
+    // branch.
+    file: Option<FileRef>,
+    // The `BinaryReplace` (or `ChunkedReplace`) that changes the contents.
+    change: Change,
+}
+
+/// The changes that [`Repo::record`](crate::Repo::record) would record, divided into hunks that
+/// can be included or excluded one at a time.
+///
+/// This is returned by [`Repo::pending_record`](crate::Repo::pending_record). At first, all of the
+/// hunks are included; once the right ones are excluded, the remaining ones can be recorded with
+/// [`Repo::record_pending`](crate::Repo::record_pending).
+#[derive(Clone, Debug)]
+pub struct PendingRecord {
+    opts: RecordOptions,
+    branch: String,
+    hunks: Vec<RecordHunk>,
+    // The changes to the branch's lines (if there are any) come first, because their staged nodes
+    // aren't renumbered when the changes are put together.
+    files: Vec<PendingFile>,
+    binaries: Vec<PendingBinary>,
+    excluded: BTreeSet<HunkId>,
+}
+
+fn record_hunk_id(kind: &RecordHunkKind) -> HunkId {
+    let mut data = Vec::new();
+    match kind {
+        RecordHunkKind::NewFile(path) => {
+            data.push(b'n');
+            data.extend_from_slice(path.as_bytes());
+        }
+        RecordHunkKind::DeleteFile(path) => {
+            data.push(b'd');
+            data.extend_from_slice(path.as_bytes());
+        }
+        RecordHunkKind::MoveFile { from, to } => {
+            // Paths can't contain zero bytes, so they make good separators.
+            data.push(b'm');
+            data.extend_from_slice(from.as_bytes());
+            data.push(0);
+            data.extend_from_slice(to.as_bytes());
+        }
+        RecordHunkKind::Binary(path) => {
+            data.push(b'b');
+            data.extend_from_slice(path.as_bytes());
+        }
+        RecordHunkKind::Lines { path, hunk } => {
+            data.push(b'l');
+            data.extend_from_slice(path.as_bytes());
+            data.push(0);
+            data.extend_from_slice(hunk.to_string().as_bytes());
+        }
+    }
+    HunkId::from_data(&data)
+}
+
+impl PendingRecord {
+    /// The branch that the hunks would be recorded on.
+    pub fn branch(&self) -> &str {
+        &self.branch
+    }
+
+    /// Returns all of the hunks, whether they are included or not.
+    ///
+    /// The hunks that change the contents of files come first, grouped by file, followed by the
+    /// ones that delete, move and add files.
+    pub fn hunks(&self) -> &[RecordHunk] {
+        &self.hunks
+    }
+
+    /// Returns the changed lines of the file at `path` in the working copy, if there are any.
+    pub fn file_changes(&self, path: &str) -> Option<&PendingChanges> {
+        self.files
+            .iter()
+            .find(|f| f.path == path)
+            .map(|f| &f.changes)
+    }
+
+    /// Are there no changes at all?
+    pub fn is_empty(&self) -> bool {
+        self.hunks.is_empty()
+    }
+
+    /// Is the hunk with this id going to be recorded?
+    pub fn is_included(&self, id: &HunkId) -> bool {
+        !self.excluded.contains(id) && self.hunks.iter().any(|h| h.id == *id)
+    }
+
+    /// Includes a hunk, along with all of the hunks that it requires.
+    pub fn include(&mut self, id: &HunkId) -> Result<(), Error> {
+        self.hunk(id)?;
+        let mut stack = vec![*id];
+        while let Some(id) = stack.pop() {
+            // If the hunk was already included, so were the ones that it requires.
+            if self.excluded.remove(&id) {
+                stack.extend_from_slice(&self.hunk(&id)?.requires);
+            }
+        }
+        Ok(())
+    }
+
+    /// Excludes a hunk, along with all of the hunks that require it.
+    pub fn exclude(&mut self, id: &HunkId) -> Result<(), Error> {
+        self.hunk(id)?;
+        let mut stack = vec![*id];
+        while let Some(id) = stack.pop() {
+            if self.excluded.insert(id) {
+                stack.extend(
+                    self.hunks
+                        .iter()
+                        .filter(|h| h.requires.contains(&id))
+                        .map(|h| h.id),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Excludes all of the hunks.
+    pub fn exclude_all(&mut self) {
+        self.excluded = self.hunks.iter().map(|h| h.id).collect();
+    }
+
+    fn hunk(&self, id: &HunkId) -> Result<&RecordHunk, Error> {
+        self.hunks
+            .iter()
+            .find(|h| h.id == *id)
+            .ok_or(Error::UnknownHunk(*id))
+    }
+
+    fn add_hunk(&mut self, kind: RecordHunkKind, requires: Vec<HunkId>) -> HunkId {
+        let id = record_hunk_id(&kind);
+        self.hunks.push(RecordHunk { id, kind, requires });
+        id
+    }
+
+    fn add_file(&mut self, path: String, file: Option<FileRef>, changes: PendingChanges) {
+        for h in changes.hunks() {
+            let kind = RecordHunkKind::Lines {
+                path: path.clone(),
+                hunk: h.id,
+            };
+            self.add_hunk(kind, vec![]);
+        }
+        self.files.push(PendingFile {
+            path,
+            file,
+            changes,
+        });
+    }
+
+    fn add_binary(&mut self, path: String, file: Option<FileRef>, change: Change) {
+        self.add_hunk(RecordHunkKind::Binary(path.clone()), vec![]);
+        self.binaries.push(PendingBinary { path, file, change });
+    }
+
+    // Puts together the changes in the included hunks. Also returns what the unrecorded
+    // operations on the tracked files will be, once the changes are recorded.
+    pub(crate) fn selected_changes(&self, repo: &Repo) -> Result<(Changes, TrackedFiles), Error> {
+        let included = |kind: RecordHunkKind| !self.excluded.contains(&record_hunk_id(&kind));
+        let mut changes = Changes { changes: vec![] };
+        let mut file_changes = Vec::new();
+        for f in &self.files {
+            let selected = f
+                .changes
+                .hunks()
+                .iter()
+                .map(|h| h.id)
+                .filter(|&hunk| {
+                    included(RecordHunkKind::Lines {
+                        path: f.path.clone(),
+                        hunk,
+                    })
+                })
+                .collect::<Vec<_>>();
+            if selected.is_empty() {
+                continue;
+            }
+            let (selected, _) = f.changes.select(&selected)?;
+            match &f.file {
+                Some(file) => file_changes.push((file.clone(), selected)),
+                None => changes.changes.extend(selected.changes),
+            }
+        }
+        for b in &self.binaries {
+            if !included(RecordHunkKind::Binary(b.path.clone())) {
+                continue;
+            }
+            match &b.file {
+                Some(file) => file_changes.push((
+                    file.clone(),
+                    Changes {
+                        changes: vec![b.change.clone()],
+                    },
+                )),
+                None => changes.changes.push(b.change.clone()),
+            }
+        }
+
+        let mut tracked = repo.storage.tracked.clone();
+        for h in &self.hunks {
+            if self.excluded.contains(&h.id) {
+                continue;
+            }
+            match &h.kind {
+                RecordHunkKind::DeleteFile(path) => {
+                    let file = repo.file_ref(&self.branch, path)?;
+                    changes.changes.push(Change::DeleteFile { file });
+                    tracked.forget_removed(path);
+                }
+                RecordHunkKind::MoveFile { from, to } => {
+                    let from_ref = repo.file_ref(&self.branch, from)?;
+                    changes.changes.push(Change::MoveFile {
+                        from: from_ref,
+                        to: to.clone(),
+                    });
+                    tracked.forget_moved(from, to);
+                }
+                RecordHunkKind::NewFile(path) => {
+                    changes.changes.push(Change::NewFile { path: path.clone() });
+                    if repo.config().symlinks() && crate::working::is_symlink(repo, path) {
+                        let file = FileRef {
+                            patch: PatchId::staging(),
+                            path: path.clone(),
+                        };
+                        changes.changes.push(Change::SetSymlink {
+                            file,
+                            symlink: true,
+                        });
+                    }
+                    tracked.forget_added(path);
+                }
+                RecordHunkKind::Binary(_) | RecordHunkKind::Lines { .. } => {}
+            }
+        }
+        for (file, c) in file_changes {
+            changes.add_file_changes(file, c);
+        }
+        Ok((changes, tracked))
+    }
+}
+
+pub(crate) fn pending_record(repo: &Repo, opts: &RecordOptions) -> Result<PendingRecord, Error> {
+    let branch = opts
+        .branch
+        .clone()
+        .unwrap_or_else(|| repo.current_branch.clone());
+    let mut ret = PendingRecord {
+        opts: opts.clone(),
+        branch,
+        hunks: Vec::new(),
+        files: Vec::new(),
+        binaries: Vec::new(),
+        excluded: BTreeSet::new(),
+    };
+
+    let graggle = repo.graggle(&ret.branch)?;
+    let old_blob = graggle.binary();
+    let has_contents = graggle.nodes().next().is_some() || old_blob.is_some();
+    if has_contents || crate::working::exists(repo, &opts.path) {
+        if !has_contents && repo.is_ignored(&opts.path)? {
+            return Err(Error::IgnoredPath(opts.path.clone()));
+        }
+        let contents = repo.read_working_file(&opts.path)?;
+        // Once the branch has binary contents, it stays binary.
+        if old_blob.is_some() || repo.config().is_binary(&opts.path, &contents) {
+            if old_blob.map(|b| b.hash) != Some(BlobHash::of(&contents)) {
+                let change = repo.binary_replace(old_blob, contents);
+                ret.add_binary(opts.path.clone(), None, change);
+            }
+        } else {
+            let diff = Diff::new(repo.file(&ret.branch)?, &contents, &opts.diff);
+            ret.add_file(opts.path.clone(), None, PendingChanges::new(diff));
+        }
+    }
+    if ret.branch != repo.current_branch {
+        return Ok(ret);
+    }
+
+    let TrackedChanges {
+        ops,
+        diffs,
+        binaries,
+    } = tracked::tracked_changes(repo, &opts.diff)?;
+    // The hunks that change the contents of each file.
+    let mut changed = BTreeMap::<String, Vec<usize>>::new();
+    for (path, file, diff) in diffs {
+        let start = ret.hunks.len();
+        ret.add_file(path.clone(), Some(file), PendingChanges::new(diff));
+        changed
+            .entry(path)
+            .or_default()
+            .extend(start..ret.hunks.len());
+    }
+    for (path, file, change) in binaries {
+        changed
+            .entry(path.clone())
+            .or_default()
+            .push(ret.hunks.len());
+        ret.add_binary(path, Some(file), change);
+    }
+
+    // Each operation comes with the path that it vacates and the path that it occupies.
+    let mut file_ops = Vec::new();
+    for path in ops.removed() {
+        file_ops.push((
+            RecordHunkKind::DeleteFile(path.to_owned()),
+            Some(path),
+            None,
+        ));
+    }
+    for (to, from) in ops.moved() {
+        let kind = RecordHunkKind::MoveFile {
+            from: from.to_owned(),
+            to: to.to_owned(),
+        };
+        file_ops.push((kind, Some(from), Some(to)));
+    }
+    for path in ops.added() {
+        file_ops.push((RecordHunkKind::NewFile(path.to_owned()), None, Some(path)));
+    }
+    let vacated = file_ops
+        .iter()
+        .filter_map(|(kind, from, _)| from.map(|p| (p, record_hunk_id(kind))))
+        .collect::<BTreeMap<_, _>>();
+    for (kind, _, to) in file_ops {
+        // A file can only go somewhere once the file that used to be there is gone.
+        let requires = to
+            .and_then(|p| vacated.get(p))
+            .filter(|&&id| id != record_hunk_id(&kind))
+            .cloned()
+            .into_iter()
+            .collect();
+        let new_file = if let RecordHunkKind::NewFile(path) = &kind {
+            changed.remove(path)
+        } else {
+            None
+        };
+        let id = ret.add_hunk(kind, requires);
+        // The contents of an added file need the file to exist.
+        for i in new_file.unwrap_or_default() {
+            ret.hunks[i].requires.push(id);
+        }
+    }
+    Ok(ret)
+}
+
+pub(crate) fn record_pending(
+    repo: &mut Repo,
+    pending: &PendingRecord,
+    author: &str,
+    msg: &str,
+) -> Result<(PatchId, PendingRecord), Error> {
+    let branch = &pending.branch;
+    let (changes, tracked) = pending.selected_changes(repo)?;
+    if changes.changes.is_empty() {
+        return Err(Error::NoChanges);
+    }
+
+    let mut header = PatchHeader::new(author.to_owned(), msg.to_owned());
+    header.email = pending.opts.email.clone();
+    let tracked = if *branch == repo.current_branch {
+        Some(tracked)
+    } else {
+        None
+    };
+    let id = repo.record_changes(branch, header, changes, tracked)?;
+    Ok((id, pending_record(repo, &pending.opts)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Error, Repo};
+    use std::fs;
+
+    #[test]
+    fn record() {
+        let mut repo = Repo::init_tmp_with_working_copy("record");
+        let path = repo.root_dir.join(DEFAULT_WORKING_FILE);
+        fs::write(&path, b"a\nb\n").unwrap();
+        let opts = RecordOptions::default();
+        let first = repo.record("Author", "First", &opts).unwrap();
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"a\nb\n");
+
+        fs::write(&path, b"a\nc\n").unwrap();
+        let opts = RecordOptions {
+            email: Some("author@example.com".to_owned()),
+            ..opts
+        };
+        let second = repo.record("Author", "Second", &opts).unwrap();
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"a\nc\n");
+        assert_eq!(repo.patch_deps(&second).collect::<Vec<_>>(), vec![&first]);
+        let header = repo.patch_header(&second).unwrap();
+        assert_eq!(header.description, "Second");
+        assert_eq!(header.email.as_ref().unwrap(), "author@example.com");
+
+        match repo.record("Author", "Nothing", &opts) {
+            Err(Error::NoChanges) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+        fs::remove_dir_all(&repo.root_dir).unwrap();
+    }
+
+    #[test]
+    fn record_other_branch() {
+        let mut repo = Repo::init_tmp_with_working_copy("record-other-branch");
+        repo.create_branch("other").unwrap();
+        fs::write(repo.root_dir.join("other.txt"), b"a\n").unwrap();
+        let opts = RecordOptions {
+            branch: Some("other".to_owned()),
+            path: "other.txt".to_owned(),
+            ..RecordOptions::default()
+        };
+        let id = repo.record("Author", "Msg", &opts).unwrap();
+        assert_eq!(repo.file("other").unwrap().as_bytes(), b"a\n");
+        assert_eq!(repo.patches("other").collect::<Vec<_>>(), vec![&id]);
+        assert_eq!(repo.patches("master").count(), 0);
+        fs::remove_dir_all(&repo.root_dir).unwrap();
+    }
+
+    #[test]
+    fn record_ignored() {
+        let mut repo = Repo::init_tmp_with_working_copy("record-ignored");
+        let path = repo.root_dir.join(DEFAULT_WORKING_FILE);
+        fs::write(&path, b"a\n").unwrap();
+        repo.config.ignore = vec![DEFAULT_WORKING_FILE.to_owned()];
+        let opts = RecordOptions::default();
+        match repo.record("Author", "Msg", &opts) {
+            Err(Error::IgnoredPath(p)) => assert_eq!(p, DEFAULT_WORKING_FILE),
+            x => panic!("unexpected result {:?}", x),
+        }
+
+        // Once the file is tracked, ignoring it doesn't matter.
+        repo.config.ignore.clear();
+        repo.record("Author", "Msg", &opts).unwrap();
+        repo.config.ignore = vec![DEFAULT_WORKING_FILE.to_owned()];
+        fs::write(&path, b"a\nb\n").unwrap();
+        repo.record("Author", "Msg", &opts).unwrap();
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"a\nb\n");
+        fs::remove_dir_all(&repo.root_dir).unwrap();
+    }
+
+    #[test]
+    fn pending_record() {
+        let mut repo = Repo::init_tmp_with_working_copy("pending-record");
+        let path = repo.root_dir.join(DEFAULT_WORKING_FILE);
+        fs::write(&path, b"a\nb\nc\nd\ne\n").unwrap();
+        let opts = RecordOptions::default();
+        repo.record("Author", "First", &opts).unwrap();
+
+        fs::write(&path, b"a\nB\nc\nd\nE\n").unwrap();
+        fs::write(repo.root_dir.join("new.txt"), b"new\n").unwrap();
+        repo.track_file("new.txt").unwrap();
+        let mut pending = repo.pending_record(&opts).unwrap();
+        let hunks = pending.hunks().to_vec();
+        assert_eq!(hunks.len(), 4);
+        assert_eq!(
+            pending
+                .file_changes(DEFAULT_WORKING_FILE)
+                .unwrap()
+                .hunks()
+                .len(),
+            2
+        );
+        assert_eq!(hunks[3].kind, RecordHunkKind::NewFile("new.txt".to_owned()));
+        assert_eq!(hunks[2].requires, vec![hunks[3].id]);
+
+        // The lines of the new file can't be recorded without it.
+        pending.exclude(&hunks[3].id).unwrap();
+        assert!(!pending.is_included(&hunks[2].id));
+        pending.include(&hunks[2].id).unwrap();
+        assert!(pending.is_included(&hunks[3].id));
+
+        pending.exclude(&hunks[1].id).unwrap();
+        let (_, rest) = repo.record_pending(&pending, "Author", "Second").unwrap();
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"a\nB\nc\nd\ne\n");
+        assert_eq!(
+            repo.file_at("master", "new.txt").unwrap().as_bytes(),
+            b"new\n"
+        );
+        let rest_ids = rest.hunks().iter().map(|h| h.id).collect::<Vec<_>>();
+        assert_eq!(rest_ids, vec![hunks[1].id]);
+
+        repo.record_pending(&rest, "Author", "Third").unwrap();
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"a\nB\nc\nd\nE\n");
+        let pending = repo.pending_record(&opts).unwrap();
+        assert!(pending.is_empty());
+        match repo.record_pending(&pending, "Author", "Nothing") {
+            Err(Error::NoChanges) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+        fs::remove_dir_all(&repo.root_dir).unwrap();
+    }
+
+    #[test]
+    fn pending_record_replaced_file() {
+        let mut repo = Repo::init_tmp_with_working_copy("pending-record-replaced-file");
+        fs::write(repo.root_dir.join("a.txt"), b"a\n").unwrap();
+        repo.track_file("a.txt").unwrap();
+        let opts = RecordOptions::default();
+        repo.record("Author", "First", &opts).unwrap();
+
+        // Move the file out of the way, and put a new one in its place.
+        repo.move_file("a.txt", "b.txt").unwrap();
+        fs::write(repo.root_dir.join("a.txt"), b"new\n").unwrap();
+        repo.track_file("a.txt").unwrap();
+        let mut pending = repo.pending_record(&opts).unwrap();
+        let hunks = pending.hunks().to_vec();
+        assert_eq!(hunks.len(), 3);
+        let moved = RecordHunkKind::MoveFile {
+            from: "a.txt".to_owned(),
+            to: "b.txt".to_owned(),
+        };
+        assert_eq!(hunks[1].kind, moved);
+        assert_eq!(hunks[2].kind, RecordHunkKind::NewFile("a.txt".to_owned()));
+        assert_eq!(hunks[2].requires, vec![hunks[1].id]);
+        assert_eq!(hunks[0].requires, vec![hunks[2].id]);
+
+        // The new file can't be added while the old one is still there.
+        pending.exclude(&hunks[1].id).unwrap();
+        assert!(hunks.iter().all(|h| !pending.is_included(&h.id)));
+
+        pending.include(&hunks[1].id).unwrap();
+        let (_, rest) = repo.record_pending(&pending, "Author", "Move").unwrap();
+        assert_eq!(repo.file_at("master", "b.txt").unwrap().as_bytes(), b"a\n");
+        assert_eq!(rest.hunks().len(), 2);
+        assert_eq!(rest.hunks()[1].kind, hunks[2].kind);
+
+        repo.record_pending(&rest, "Author", "Add").unwrap();
+        assert_eq!(
+            repo.file_at("master", "a.txt").unwrap().as_bytes(),
+            b"new\n"
+        );
+        assert!(repo.pending_record(&opts).unwrap().is_empty());
+        fs::remove_dir_all(&repo.root_dir).unwrap();
+    }
+
+    #[test]
+    fn record_binary() {
+        let mut repo = Repo::init_tmp_with_working_copy("record-binary");
+        let contents = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec();
+        fs::write(repo.root_dir.join("a.png"), &contents).unwrap();
+        repo.track_file("a.png").unwrap();
+        let opts = RecordOptions::default();
+        let pending = repo.pending_record(&opts).unwrap();
+        let hunks = pending.hunks();
+        assert_eq!(hunks.len(), 2);
+        assert_eq!(hunks[0].kind, RecordHunkKind::Binary("a.png".to_owned()));
+        assert_eq!(hunks[0].requires, vec![hunks[1].id]);
+
+        repo.record("Author", "Msg", &opts).unwrap();
+        assert_eq!(
+            repo.binary_at("master", "a.png").unwrap().as_deref(),
+            Some(&contents[..])
+        );
+        assert!(repo
+            .file_at("master", "a.png")
+            .unwrap()
+            .as_bytes()
+            .is_empty());
+        assert!(repo
+            .status()
+            .unwrap()
+            .values()
+            .all(|s| *s == crate::FileStatus::Clean));
+
+        repo.clone_branch("master", "other").unwrap();
+        repo.checkout("other").unwrap();
+        fs::write(repo.root_dir.join("a.png"), b"\0changed").unwrap();
+        repo.record("Author", "Change", &opts).unwrap();
+        assert_eq!(
+            repo.binary_at("other", "a.png").unwrap().as_deref(),
+            Some(&b"\0changed"[..])
+        );
+        repo.checkout("master").unwrap();
+        assert_eq!(fs::read(repo.root_dir.join("a.png")).unwrap(), contents);
+        fs::remove_dir_all(&repo.root_dir).unwrap();
+    }
+
+    #[test]
+    fn record_binary_override() {
+        let mut repo = Repo::init_tmp_with_working_copy("record-binary-override");
+        repo.config.text.push(crate::TextRule {
+            pattern: "*.dat".to_owned(),
+            newline: None,
+            encoding: None,
+            binary: Some(false),
+        });
+        fs::write(repo.root_dir.join("a.dat"), b"a\0\nb\n").unwrap();
+        repo.track_file("a.dat").unwrap();
+        repo.record("Author", "Msg", &RecordOptions::default())
+            .unwrap();
+        assert_eq!(repo.binary_at("master", "a.dat").unwrap(), None);
+        assert_eq!(
+            repo.file_at("master", "a.dat").unwrap().as_bytes(),
+            b"a\0\nb\n"
+        );
+        fs::remove_dir_all(&repo.root_dir).unwrap();
+    }
+}
@@ -0,0 +1,781 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+//! Exchanging patches with other repositories.
+//!
+//! Two repositories talk to each other over a pair of byte streams: a client (represented by a
+//! [`Connection`]) sends requests, and a server (see [`serve`]) answers them. The transport that
+//! carries the streams can be anything; [`SshAddress`] runs the server on another machine using
+//! the `ssh` program. Alternatively, [`HttpAddress`] sends each request over HTTP, to a server that
+//! answers them with [`respond`] (there is a reference server behind the `http-server` feature).
+//! With the `grpc` feature, `GrpcAddress` makes the same requests as calls to a gRPC service (see
+//! `GrpcService`), which is easier to put behind a load balancer.
+//!
+//! Before sending patches, the two sides work out which patches the receiving side is missing, so
+//! that only those are sent. The patches are sent one at a time, and the receiving side keeps each
+//! one as soon as it arrives. So if a transfer is interrupted, the patches that made it across
+//! don't need to be sent again: once the receiving repository is written (see [`Repo::write`]),
+//! the next transfer picks up where this one stopped.
+//!
+//! The requests and the answers to them are encoded with bincode, unless the client asks for
+//! another [`WireFormat`]. Either way, the patches are sent exactly as they are stored, so they
+//! don't need to be encoded or decoded on the way.
+
+use std::collections::BTreeSet;
+use std::io::{BufReader, BufWriter, Cursor, ErrorKind, Read, Write};
+use std::path::Path;
+use std::process::Child;
+
+use crate::patch::binary::{read_section, write_section};
+use crate::{ApplyPolicy, Error, PatchId, Repo};
+
+#[cfg(feature = "grpc")]
+mod grpc;
+mod http;
+mod ssh;
+#[cfg(feature = "flatbuffers")]
+mod wire;
+
+#[cfg(feature = "grpc")]
+pub use self::grpc::{run_grpc_server, GrpcAddress, GrpcService};
+#[cfg(feature = "http-server")]
+pub use self::http::run_http_server;
+pub use self::http::{respond, HttpAddress, HttpAnswer};
+pub use self::ssh::SshAddress;
+
+/// How the requests and the answers of the protocol are encoded.
+///
+/// A server answers every request in the same format as the request, so a client can pick the
+/// format without asking the server first. Every server understands [`WireFormat::Bincode`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WireFormat {
+    /// The messages are serialized with bincode. This is the default.
+    Bincode,
+    /// Every message is a FlatBuffers table, which can be read in place without deserializing
+    /// it. This is worth it for servers that exchange lots of messages, but the server needs to
+    /// be built with the `flatbuffers` feature too.
+    #[cfg(feature = "flatbuffers")]
+    FlatBuffers,
+}
+
+// The file identifier of the FlatBuffers messages, which is at bytes 4 to 8 of every message.
+// Messages that are serialized with bincode start with a four-byte enum tag, which is followed
+// by a length that would have to be enormous in order to look like this.
+const FLATBUFFERS_IDENTIFIER: &str = "OJOW";
+
+fn is_flatbuffer(data: &[u8]) -> bool {
+    data.get(4..8) == Some(FLATBUFFERS_IDENTIFIER.as_bytes())
+}
+
+// The messages that a client sends. Each one is encoded in some `WireFormat` and sent as a section
+// (see `write_section`).
+#[derive(Clone, Debug, Deserialize, Serialize)]
+enum Request {
+    // Asks for the patches on a branch.
+    List {
+        branch: String,
+    },
+    // Asks which of these patches the server doesn't have.
+    Missing {
+        ids: Vec<PatchId>,
+    },
+    // Asks for the data of these patches. The server answers with the order in which it will send
+    // them, and then sends them.
+    Fetch {
+        ids: Vec<PatchId>,
+    },
+    // Announces that the data of `count` patches follows, after which the patches in `ids`
+    // should be applied to `branch`.
+    Push {
+        branch: String,
+        ids: Vec<PatchId>,
+        count: u64,
+    },
+}
+
+// The server's answer to a request.
+#[derive(Debug, Deserialize, Serialize)]
+enum Response {
+    Ids(Vec<PatchId>),
+    Error(String),
+}
+
+fn encode_request(req: &Request, format: WireFormat) -> Result<Vec<u8>, Error> {
+    match format {
+        WireFormat::Bincode => Ok(bincode::serialize(req)?),
+        #[cfg(feature = "flatbuffers")]
+        WireFormat::FlatBuffers => Ok(wire::encode_request(req)),
+    }
+}
+
+// Decodes a request, and returns the format that it was in.
+fn decode_request(data: &[u8]) -> Result<(Request, WireFormat), Error> {
+    if is_flatbuffer(data) {
+        #[cfg(feature = "flatbuffers")]
+        return Ok((wire::decode_request(data)?, WireFormat::FlatBuffers));
+        #[cfg(not(feature = "flatbuffers"))]
+        return Err(Error::Remote(
+            "this server doesn't support the FlatBuffers wire format".to_owned(),
+        ));
+    }
+    Ok((bincode::deserialize(data)?, WireFormat::Bincode))
+}
+
+fn encode_response(resp: &Response, format: WireFormat) -> Result<Vec<u8>, Error> {
+    match format {
+        WireFormat::Bincode => Ok(bincode::serialize(resp)?),
+        #[cfg(feature = "flatbuffers")]
+        WireFormat::FlatBuffers => Ok(wire::encode_response(resp)),
+    }
+}
+
+// Decodes an answer. This accepts every format, because a server that doesn't understand a
+// request answers in bincode.
+fn decode_response(data: &[u8]) -> Result<Response, Error> {
+    #[cfg(feature = "flatbuffers")]
+    {
+        if is_flatbuffer(data) {
+            return wire::decode_response(data);
+        }
+    }
+    Ok(bincode::deserialize(data)?)
+}
+
+/// The result of sending patches from one repository to another.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Transfer {
+    /// The patches that were sent, because the receiving repository didn't have them.
+    pub transferred: Vec<PatchId>,
+    /// The patches that were applied to the receiving branch, in the order that they were
+    /// applied.
+    pub applied: Vec<PatchId>,
+}
+
+// Carries requests to a server, and brings back the answers.
+trait Transport {
+    // Sends a request, followed by the data of some patches (which is only for pushes), and
+    // returns a reader for the answer.
+    fn send(&mut self, req: &Request, patches: &[&[u8]]) -> Result<&mut dyn Read, Error>;
+
+    // Tells the server that there are no more requests.
+    fn close(self: Box<Self>) -> Result<(), Error>;
+
+    // Changes the format of the requests. Transports that don't encode the requests themselves
+    // ignore this.
+    fn set_format(&mut self, _format: WireFormat) {}
+
+    // Asks for the data of some patches, and passes each one to `f` as soon as it arrives.
+    // Transports that get patches some other way than in the answer to a `Request::Fetch` can
+    // override this.
+    fn fetch(
+        &mut self,
+        ids: Vec<PatchId>,
+        f: &mut dyn FnMut(PatchId, Vec<u8>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let reader = self.send(&Request::Fetch { ids }, &[])?;
+        for id in read_ids(&mut *reader)? {
+            f(id, read_section(&mut *reader)?)?;
+        }
+        Ok(())
+    }
+}
+
+// Reads an answer that should be a list of ids.
+fn read_ids(reader: &mut dyn Read) -> Result<Vec<PatchId>, Error> {
+    match decode_response(&read_section(reader)?)? {
+        Response::Ids(ids) => Ok(ids),
+        Response::Error(msg) => Err(Error::Remote(msg)),
+    }
+}
+
+// A transport that writes the requests to one stream and reads the answers from another (see
+// `serve`).
+struct Stream {
+    reader: BufReader<Box<dyn Read>>,
+    writer: BufWriter<Box<dyn Write>>,
+    // The process at the other end of the streams, if there is one.
+    child: Option<Child>,
+    format: WireFormat,
+}
+
+impl Transport for Stream {
+    fn send(&mut self, req: &Request, patches: &[&[u8]]) -> Result<&mut dyn Read, Error> {
+        write_section(&mut self.writer, &encode_request(req, self.format)?)?;
+        for p in patches {
+            write_section(&mut self.writer, p)?;
+        }
+        self.writer.flush()?;
+        Ok(&mut self.reader)
+    }
+
+    fn close(self: Box<Self>) -> Result<(), Error> {
+        let Stream {
+            writer,
+            child,
+            reader,
+            ..
+        } = *self;
+        // Closing the stream tells the server that there are no more requests.
+        writer.into_inner().map_err(|e| e.into_error())?;
+        drop(reader);
+        if let Some(mut child) = child {
+            let status = child.wait()?;
+            if !status.success() {
+                return Err(Error::Remote(format!(
+                    "the transport exited with {}",
+                    status
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn set_format(&mut self, format: WireFormat) {
+        self.format = format;
+    }
+}
+
+// A transport that answers the requests itself, using a repository on this machine.
+struct Local {
+    repo: Repo,
+    // The answer to the last request.
+    answer: Cursor<Vec<u8>>,
+    // Did any of the requests change the repository?
+    modified: bool,
+}
+
+impl Transport for Local {
+    fn send(&mut self, req: &Request, patches: &[&[u8]]) -> Result<&mut dyn Read, Error> {
+        let mut data = Vec::new();
+        for p in patches {
+            write_section(&mut data, p)?;
+        }
+        if let Request::Push { .. } = req {
+            self.modified = true;
+        }
+        let mut answer = Vec::new();
+        handle(
+            &mut self.repo,
+            req.clone(),
+            &data[..],
+            &mut answer,
+            WireFormat::Bincode,
+        )?;
+        self.answer = Cursor::new(answer);
+        Ok(&mut self.answer)
+    }
+
+    fn close(self: Box<Self>) -> Result<(), Error> {
+        if self.modified {
+            self.repo.write()?;
+        }
+        Ok(())
+    }
+}
+
+/// Connects to the repository at `addr`, which can be:
+///
+/// - an HTTP address (see [`HttpAddress`]);
+/// - a gRPC address (see `GrpcAddress`), if libojo was built with the `grpc` feature;
+/// - an SSH address (see [`SshAddress`]), in which case the other machine must have `ojo`
+///   installed;
+/// - the path of a repository on this machine.
+///
+/// As with `scp`, an address with a colon before its first slash is an SSH address, so a local
+/// path containing a colon should start with `./`.
+pub fn connect(addr: &str) -> Result<Connection, Error> {
+    if addr.starts_with("http://") {
+        return Ok(HttpAddress::parse(addr)?.connect());
+    }
+    if addr.starts_with("grpc://") {
+        #[cfg(feature = "grpc")]
+        return GrpcAddress::parse(addr)?.connect();
+        #[cfg(not(feature = "grpc"))]
+        return Err(Error::InvalidRemote(addr.to_owned()));
+    }
+    let is_ssh =
+        addr.starts_with("ssh://") || addr.find(':').is_some_and(|c| !addr[..c].contains('/'));
+    if is_ssh {
+        SshAddress::parse(addr)?.connect("ojo")
+    } else {
+        Connection::open_local(addr)
+    }
+}
+
+/// A connection to a server for another repository.
+pub struct Connection {
+    transport: Box<dyn Transport>,
+}
+
+impl Connection {
+    /// Creates a connection that sends requests to `writer` and reads the answers from `reader`
+    /// (where there should be a server, as in [`serve`]).
+    pub fn new<R, W>(reader: R, writer: W) -> Connection
+    where
+        R: Read + 'static,
+        W: Write + 'static,
+    {
+        Connection::from_stream(reader, writer, None)
+    }
+
+    /// Creates a connection to the repository at `path` on this machine, without a server.
+    ///
+    /// If the repository is changed (by [`Connection::push`]), it gets written when the
+    /// connection is closed.
+    pub fn open_local<P: AsRef<Path>>(path: P) -> Result<Connection, Error> {
+        Ok(Connection {
+            transport: Box::new(Local {
+                repo: Repo::open(path)?,
+                answer: Cursor::new(Vec::new()),
+                modified: false,
+            }),
+        })
+    }
+
+    fn from_stream<R, W>(reader: R, writer: W, child: Option<Child>) -> Connection
+    where
+        R: Read + 'static,
+        W: Write + 'static,
+    {
+        Connection {
+            transport: Box::new(Stream {
+                reader: BufReader::new(Box::new(reader)),
+                writer: BufWriter::new(Box::new(writer)),
+                child,
+                format: WireFormat::Bincode,
+            }),
+        }
+    }
+
+    /// Changes the format of the requests that this connection sends (see [`WireFormat`]).
+    ///
+    /// This only matters for connections over streams (including SSH connections): local
+    /// connections don't encode their requests at all, HTTP connections always use the bodies
+    /// that [`respond`] expects, and gRPC connections always use protocol buffers.
+    pub fn set_wire_format(&mut self, format: WireFormat) {
+        self.transport.set_format(format);
+    }
+
+    // Sends a request, and returns the ids in the answer along with a reader for anything that
+    // follows them.
+    fn request(
+        &mut self,
+        req: &Request,
+        patches: &[&[u8]],
+    ) -> Result<(Vec<PatchId>, &mut dyn Read), Error> {
+        let reader = self.transport.send(req, patches)?;
+        Ok((read_ids(&mut *reader)?, reader))
+    }
+
+    /// Returns the patches on a branch of the other repository.
+    pub fn branch_patches(&mut self, branch: &str) -> Result<Vec<PatchId>, Error> {
+        let req = Request::List {
+            branch: branch.to_owned(),
+        };
+        Ok(self.request(&req, &[])?.0)
+    }
+
+    /// Applies all of the patches on `remote_branch` of the other repository to `branch`, which
+    /// is created if it doesn't exist.
+    ///
+    /// The patches that are received are marked as published (see [`Repo::mark_published`]). If
+    /// the transfer is interrupted, the patches that were already received stay in `repo`, but
+    /// nothing is applied.
+    pub fn pull(
+        &mut self,
+        repo: &mut Repo,
+        remote_branch: &str,
+        branch: &str,
+    ) -> Result<Transfer, Error> {
+        let remote = self.branch_patches(remote_branch)?;
+        self.pull_ids(repo, &remote, branch)
+    }
+
+    // Downloads the patches in `remote` that `repo` doesn't have, and applies all of them to
+    // `branch`.
+    pub(crate) fn pull_ids(
+        &mut self,
+        repo: &mut Repo,
+        remote: &[PatchId],
+        branch: &str,
+    ) -> Result<Transfer, Error> {
+        let missing = remote
+            .iter()
+            .filter(|id| !repo.storage.patches.contains_key(id))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut ret = Transfer::default();
+        self.fetch(missing, |id, data| {
+            let new_id = repo.register_patch(&data)?;
+            if new_id != id {
+                return Err(Error::IdMismatch(new_id, id));
+            }
+            repo.mark_published(&id)?;
+            ret.transferred.push(id);
+            Ok(())
+        })?;
+        ret.applied = apply_all(repo, branch, remote)?;
+        Ok(ret)
+    }
+
+    // Downloads some patches, and passes each one to `f` as soon as it arrives. They arrive in
+    // an order in which they can be registered.
+    pub(crate) fn fetch<F>(&mut self, ids: Vec<PatchId>, mut f: F) -> Result<(), Error>
+    where
+        F: FnMut(PatchId, Vec<u8>) -> Result<(), Error>,
+    {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        self.transport.fetch(ids, &mut f)
+    }
+
+    /// Sends all of the patches on `branch` to the other repository, and applies them to
+    /// `remote_branch` there, which is created if it doesn't exist.
+    ///
+    /// The patches that were sent are marked as published (see [`Repo::mark_published`]).
+    pub fn push(
+        &mut self,
+        repo: &mut Repo,
+        branch: &str,
+        remote_branch: &str,
+    ) -> Result<Transfer, Error> {
+        self.push_except(repo, branch, remote_branch, &BTreeSet::new())
+    }
+
+    // Like `push`, but assumes that the other repository has the patches in `known`, so that it
+    // doesn't need to be asked about them.
+    pub(crate) fn push_except(
+        &mut self,
+        repo: &mut Repo,
+        branch: &str,
+        remote_branch: &str,
+        known: &BTreeSet<PatchId>,
+    ) -> Result<Transfer, Error> {
+        repo.inode(branch)?;
+        let ids = repo.patches(branch).cloned().collect::<Vec<_>>();
+        let ids = repo.patch_graph().apply_order(&ids);
+        let unknown = ids
+            .iter()
+            .filter(|id| !known.contains(id))
+            .cloned()
+            .collect::<Vec<_>>();
+        let missing = if unknown.is_empty() {
+            Vec::new()
+        } else {
+            self.request(&Request::Missing { ids: unknown }, &[])?.0
+        };
+        let to_send = ids
+            .iter()
+            .filter(|id| missing.contains(id))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let push = Request::Push {
+            branch: remote_branch.to_owned(),
+            ids,
+            count: to_send.len() as u64,
+        };
+        let data = to_send
+            .iter()
+            .map(|id| repo.open_patch_data(id))
+            .collect::<Result<Vec<_>, _>>()?;
+        let (applied, _) = self.request(&push, &data)?;
+
+        for id in &to_send {
+            repo.mark_published(id)?;
+        }
+        Ok(Transfer {
+            transferred: to_send,
+            applied,
+        })
+    }
+
+    /// Closes the connection, and waits for the transport to finish.
+    pub fn close(self) -> Result<(), Error> {
+        self.transport.close()
+    }
+}
+
+// Applies some patches to a branch, creating it if necessary. If any of them fails to apply, the
+// branch is left unchanged.
+fn apply_all(repo: &mut Repo, branch: &str, ids: &[PatchId]) -> Result<Vec<PatchId>, Error> {
+    if repo.storage.inode(branch).is_none() {
+        repo.create_branch(branch)?;
+    }
+    let mut applied = Vec::new();
+    for id in &repo.patch_graph().apply_order(ids) {
+        match repo.apply(branch, id, ApplyPolicy::Cascade) {
+            Ok(a) => applied.extend(a),
+            Err(e) => {
+                repo.roll_back(branch, &applied);
+                return Err(e);
+            }
+        }
+    }
+    Ok(applied)
+}
+
+// Reads the next request, or returns `None` if the client closed the stream.
+fn read_request<R: Read>(mut reader: R) -> Result<Option<Vec<u8>>, Error> {
+    let mut first = [0; 1];
+    loop {
+        match reader.read(&mut first) {
+            Ok(0) => return Ok(None),
+            Ok(_) => break,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(Some(read_section(first.chain(reader))?))
+}
+
+// Answers a single request, in the format `format`. For a push, the patches are read from
+// `reader`.
+fn handle<R: Read, W: Write>(
+    repo: &mut Repo,
+    req: Request,
+    mut reader: R,
+    mut writer: W,
+    format: WireFormat,
+) -> Result<(), Error> {
+    let resp = match req {
+        Request::List { branch } => repo.inode(&branch).map(|_| {
+            let ids = repo.patches(&branch).cloned().collect::<Vec<_>>();
+            repo.patch_graph().apply_order(&ids)
+        }),
+        Request::Missing { ids } => Ok(ids
+            .into_iter()
+            .filter(|id| !repo.storage.patches.contains_key(id))
+            .collect()),
+        Request::Fetch { ids } => {
+            match ids.iter().find(|id| !repo.storage.patches.contains_key(id)) {
+                Some(id) => Err(Error::UnknownPatch(*id)),
+                None => {
+                    let order = repo.patch_graph().apply_order(&ids);
+                    let resp = encode_response(&Response::Ids(order.clone()), format)?;
+                    write_section(&mut writer, &resp)?;
+                    for id in &order {
+                        write_section(&mut writer, repo.open_patch_data(id)?)?;
+                    }
+                    return Ok(());
+                }
+            }
+        }
+        Request::Push { branch, ids, count } => {
+            // Every patch needs to be read (even if an earlier one was bad), or else we'd
+            // mistake the rest of them for requests.
+            let mut registered = Ok(());
+            for _ in 0..count {
+                let data = read_section(&mut reader)?;
+                if let Err(e) = repo.register_patch(&data) {
+                    registered = Err(e);
+                }
+            }
+            registered.and_then(|_| apply_all(repo, &branch, &ids))
+        }
+    };
+    let resp = match resp {
+        Ok(ids) => Response::Ids(ids),
+        Err(e) => Response::Error(e.to_string()),
+    };
+    write_section(&mut writer, &encode_response(&resp, format)?)
+}
+
+/// Answers the requests of a client (see [`Connection`]), which reads from `writer` and writes to
+/// `reader`, until the client closes its stream.
+///
+/// Errors that concern a single request (such as asking for a branch that doesn't exist) are sent
+/// to the client; errors in the streams themselves stop the server. Either way, the patches that
+/// were received are registered in `repo` (but it isn't written).
+pub fn serve<R: Read, W: Write>(repo: &mut Repo, reader: R, writer: W) -> Result<(), Error> {
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    while let Some(data) = read_request(&mut reader)? {
+        let (req, format) = match decode_request(&data) {
+            Ok(x) => x,
+            Err(e) => {
+                // Tell the client what went wrong, in a format that every client understands. We
+                // can't tell where the next request starts, so we stop here.
+                let resp = encode_response(&Response::Error(e.to_string()), WireFormat::Bincode)?;
+                write_section(&mut writer, &resp)?;
+                writer.flush()?;
+                return Err(e);
+            }
+        };
+        handle(repo, req, &mut reader, &mut writer, format)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::create;
+    use std::cell::RefCell;
+    use std::io::{self, Read, Write};
+    use std::rc::Rc;
+
+    use super::*;
+
+    // A transport that runs the server in the same thread: whenever the client wants to read an
+    // answer, the server handles all of the requests that were written so far. The server can
+    // stop sending after a certain number of bytes, to simulate an interrupted transfer.
+    struct Loopback {
+        repo: Repo,
+        requests: Vec<u8>,
+        answers: Vec<u8>,
+        limit: Option<usize>,
+    }
+
+    #[derive(Clone)]
+    struct Handle(Rc<RefCell<Loopback>>);
+
+    impl Write for Handle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().requests.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Read for Handle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut lb = self.0.borrow_mut();
+            let lb = &mut *lb;
+            if lb.answers.is_empty() && !lb.requests.is_empty() {
+                let mut out = Vec::new();
+                serve(&mut lb.repo, &lb.requests[..], &mut out).unwrap();
+                lb.requests.clear();
+                if let Some(limit) = lb.limit.as_mut() {
+                    out.truncate(*limit);
+                    *limit -= out.len();
+                }
+                lb.answers = out;
+            }
+            let len = buf.len().min(lb.answers.len());
+            buf[..len].copy_from_slice(&lb.answers[..len]);
+            lb.answers.drain(..len);
+            Ok(len)
+        }
+    }
+
+    fn connect(repo: Repo, limit: Option<usize>) -> (Connection, Handle) {
+        let handle = Handle(Rc::new(RefCell::new(Loopback {
+            repo,
+            requests: Vec::new(),
+            answers: Vec::new(),
+            limit,
+        })));
+        (Connection::new(handle.clone(), handle.clone()), handle)
+    }
+
+    #[test]
+    fn pull() {
+        let mut remote = Repo::init_tmp();
+        let a = create(&mut remote, "master", b"a\n");
+        let b = create(&mut remote, "master", b"a\nb\n");
+        let mut local = Repo::init_tmp();
+
+        let (mut conn, _) = connect(remote, None);
+        assert_eq!(conn.branch_patches("master").unwrap(), vec![a, b]);
+        let transfer = conn.pull(&mut local, "master", "other").unwrap();
+        assert_eq!(transfer.transferred, vec![a, b]);
+        assert_eq!(transfer.applied, vec![a, b]);
+        assert_eq!(local.file("other").unwrap().as_bytes(), b"a\nb\n");
+        assert!(local.is_published(&b));
+
+        // The second time, there's nothing to send.
+        let transfer = conn.pull(&mut local, "master", "master").unwrap();
+        assert!(transfer.transferred.is_empty());
+        assert_eq!(transfer.applied, vec![a, b]);
+        match conn.pull(&mut local, "missing", "master") {
+            Err(Error::Remote(_)) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+        conn.close().unwrap();
+    }
+
+    #[test]
+    fn push() {
+        let mut local = Repo::init_tmp();
+        let a = create(&mut local, "master", b"a\n");
+        let mut remote = Repo::init_tmp();
+        remote
+            .register_patch(local.open_patch_data(&a).unwrap())
+            .unwrap();
+
+        let b = create(&mut local, "master", b"a\nb\n");
+        let (mut conn, server) = connect(remote, None);
+        let transfer = conn.push(&mut local, "master", "master").unwrap();
+        assert_eq!(transfer.transferred, vec![b]);
+        assert_eq!(transfer.applied, vec![a, b]);
+        assert!(local.is_published(&b));
+        let remote = &server.0.borrow().repo;
+        assert_eq!(remote.file("master").unwrap().as_bytes(), b"a\nb\n");
+    }
+
+    #[test]
+    fn resume() {
+        let mut remote = Repo::init_tmp();
+        let a = create(&mut remote, "master", b"a\n");
+        let b = create(&mut remote, "master", b"a\nb\n");
+        let mut local = Repo::init_tmp();
+
+        // Interrupt the transfer in the middle of the second patch. Before the patches, the server
+        // sends two lists of ids.
+        let list_len = 8 + bincode::serialize(&Response::Ids(vec![a, b]))
+            .unwrap()
+            .len();
+        let first_len = remote.open_patch_data(&a).unwrap().len();
+        let limit = 2 * list_len + 8 + first_len + 10;
+        let (mut conn, server) = connect(remote, Some(limit));
+        match conn.pull(&mut local, "master", "master") {
+            Err(Error::TruncatedPatchFile) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+        assert_eq!(local.all_patches().collect::<Vec<_>>(), vec![&a]);
+        assert_eq!(local.patches("master").count(), 0);
+
+        server.0.borrow_mut().limit = None;
+        let transfer = conn.pull(&mut local, "master", "master").unwrap();
+        assert_eq!(transfer.transferred, vec![b]);
+        assert_eq!(local.file("master").unwrap().as_bytes(), b"a\nb\n");
+    }
+
+    #[cfg(feature = "flatbuffers")]
+    #[test]
+    fn flatbuffers() {
+        let mut remote = Repo::init_tmp();
+        let a = create(&mut remote, "master", b"a\n");
+        let mut local = Repo::init_tmp();
+
+        let (mut conn, server) = connect(remote, None);
+        conn.set_wire_format(WireFormat::FlatBuffers);
+        let transfer = conn.pull(&mut local, "master", "master").unwrap();
+        assert_eq!(transfer.transferred, vec![a]);
+        match conn.pull(&mut local, "missing", "master") {
+            Err(Error::Remote(_)) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+
+        let b = create(&mut local, "master", b"a\nb\n");
+        let transfer = conn.push(&mut local, "master", "master").unwrap();
+        assert_eq!(transfer.transferred, vec![b]);
+        assert_eq!(transfer.applied, vec![b]);
+        let remote = &server.0.borrow().repo;
+        assert_eq!(remote.file("master").unwrap().as_bytes(), b"a\nb\n");
+    }
+}
@@ -0,0 +1,801 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// The gRPC transport. The service is described by this schema:
+//
+//     syntax = "proto3";
+//     package ojo;
+//
+//     service Remote {
+//       // Lists the patches on a branch, in an order in which they can be applied.
+//       rpc Advertise(Branch) returns (PatchIds);
+//       // Asks which of these patches the server doesn't have.
+//       rpc Missing(PatchIds) returns (PatchIds);
+//       // Sends the data of these patches, one message per patch, in an order in which they can
+//       // be registered.
+//       rpc Fetch(PatchIds) returns (stream Patch);
+//       // Receives some patches and applies them to a branch. The first message says which
+//       // branch and which patches (including ones that the server already has); each of the
+//       // others carries the data of one patch. The answer is the patches that were applied.
+//       rpc Push(stream PushMessage) returns (PatchIds);
+//       // Describes the patches on a branch, newest first (as in `Repo::log`).
+//       rpc Log(Branch) returns (stream LogEntry);
+//     }
+//
+//     message Branch { string name = 1; }
+//     message PatchIds { repeated bytes ids = 1; }  // 32 bytes each
+//     message Patch { bytes id = 1; bytes data = 2; }
+//     message PushStart { string branch = 1; repeated bytes ids = 2; }
+//     message PushMessage {
+//       oneof item {
+//         PushStart start = 1;
+//         bytes patch = 2;
+//       }
+//     }
+//     message LogEntry {  // the same as `LogEntryJson`
+//       string id = 1;
+//       string author = 2;
+//       optional string email = 3;
+//       string date = 4;
+//       string summary = 5;
+//       string description = 6;
+//       string kind = 7;
+//       repeated string deps = 8;
+//       bool published = 9;
+//     }
+//
+// The messages and the service below are what `tonic-build` would generate for this schema, minus
+// the parts that we don't use. As with the other transports, the patches are sent exactly as they
+// are stored. Unlike them, each patch is a message of its own, so a patch can be used as soon as
+// it arrives without the whole transfer being buffered.
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::io::{self, Cursor, Read};
+use std::sync::{Arc, RwLock};
+
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::body::BoxBody;
+use tonic::codec::{ProstCodec, Streaming};
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::codegen::{http, Body, BoxFuture, Context, Poll, Service, StdError};
+use tonic::server::{ClientStreamingService, NamedService, ServerStreamingService, UnaryService};
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Code, Status};
+
+use super::{apply_all, encode_response, Connection, Request, Response, Transport, WireFormat};
+use crate::patch::binary::write_section;
+use crate::{Error, LogEntryJson, PatchId, PatchIdError, PatchQuery, Repo};
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Branch {
+    #[prost(string, tag = "1")]
+    name: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct PatchIds {
+    #[prost(bytes = "vec", repeated, tag = "1")]
+    ids: Vec<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Patch {
+    #[prost(bytes = "vec", tag = "1")]
+    id: Vec<u8>,
+    #[prost(bytes = "vec", tag = "2")]
+    data: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct PushStart {
+    #[prost(string, tag = "1")]
+    branch: String,
+    #[prost(bytes = "vec", repeated, tag = "2")]
+    ids: Vec<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct PushMessage {
+    #[prost(oneof = "PushItem", tags = "1, 2")]
+    item: Option<PushItem>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+enum PushItem {
+    #[prost(message, tag = "1")]
+    Start(PushStart),
+    #[prost(bytes = "vec", tag = "2")]
+    Patch(Vec<u8>),
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct LogEntry {
+    #[prost(string, tag = "1")]
+    id: String,
+    #[prost(string, tag = "2")]
+    author: String,
+    #[prost(string, optional, tag = "3")]
+    email: Option<String>,
+    #[prost(string, tag = "4")]
+    date: String,
+    #[prost(string, tag = "5")]
+    summary: String,
+    #[prost(string, tag = "6")]
+    description: String,
+    #[prost(string, tag = "7")]
+    kind: String,
+    #[prost(string, repeated, tag = "8")]
+    deps: Vec<String>,
+    #[prost(bool, tag = "9")]
+    published: bool,
+}
+
+impl From<LogEntryJson> for LogEntry {
+    fn from(e: LogEntryJson) -> LogEntry {
+        LogEntry {
+            id: e.id,
+            author: e.author,
+            email: e.email,
+            date: e.date,
+            summary: e.summary,
+            description: e.description,
+            kind: e.kind,
+            deps: e.deps,
+            published: e.published,
+        }
+    }
+}
+
+impl From<LogEntry> for LogEntryJson {
+    fn from(e: LogEntry) -> LogEntryJson {
+        LogEntryJson {
+            id: e.id,
+            author: e.author,
+            email: e.email,
+            date: e.date,
+            summary: e.summary,
+            description: e.description,
+            kind: e.kind,
+            deps: e.deps,
+            published: e.published,
+        }
+    }
+}
+
+const ADVERTISE: &str = "/ojo.Remote/Advertise";
+const MISSING: &str = "/ojo.Remote/Missing";
+const FETCH: &str = "/ojo.Remote/Fetch";
+const PUSH: &str = "/ojo.Remote/Push";
+const LOG: &str = "/ojo.Remote/Log";
+
+fn encode_ids(ids: &[PatchId]) -> PatchIds {
+    PatchIds {
+        ids: ids.iter().map(|id| id.data.to_vec()).collect(),
+    }
+}
+
+fn decode_id(data: &[u8]) -> Result<PatchId, Error> {
+    let mut ret = PatchId::staging();
+    if data.len() != ret.data.len() {
+        return Err(PatchIdError::InvalidLength(data.len()).into());
+    }
+    ret.data.copy_from_slice(data);
+    Ok(ret)
+}
+
+fn decode_ids(ids: &[Vec<u8>]) -> Result<Vec<PatchId>, Error> {
+    ids.iter().map(|id| decode_id(id)).collect()
+}
+
+// Turns an error of the server into one for the client.
+fn status(e: Error) -> Status {
+    let code = match e {
+        Error::UnknownBranch(_) | Error::UnknownPatch(_) => Code::NotFound,
+        Error::PatchId(_) => Code::InvalidArgument,
+        Error::Io(..) => Code::Internal,
+        _ => Code::FailedPrecondition,
+    };
+    Status::new(code, e.to_string())
+}
+
+// Turns an error that the server sent into one for the client.
+fn remote(s: Status) -> Error {
+    Error::Remote(s.message().to_owned())
+}
+
+/// The address of a repository that can be reached over gRPC (see [`GrpcService`]).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GrpcAddress {
+    /// The machine to connect to.
+    pub host: String,
+    /// The port to connect to.
+    pub port: u16,
+}
+
+impl GrpcAddress {
+    /// Parses an address of the form `grpc://host[:port]`. The default port is 50051.
+    pub fn parse(addr: &str) -> Result<GrpcAddress, Error> {
+        let invalid = || Error::InvalidRemote(addr.to_owned());
+        let authority = addr.strip_prefix("grpc://").ok_or_else(invalid)?;
+        let authority = authority.trim_end_matches('/');
+        let (host, port) = match authority.rfind(':') {
+            Some(colon) => {
+                let port = authority[(colon + 1)..].parse().map_err(|_| invalid())?;
+                (&authority[..colon], port)
+            }
+            None => (authority, 50051),
+        };
+        if host.is_empty() || host.contains('/') {
+            return Err(invalid());
+        }
+        Ok(GrpcAddress {
+            host: host.to_owned(),
+            port,
+        })
+    }
+
+    /// Connects to this address.
+    pub fn connect(&self) -> Result<Connection, Error> {
+        Ok(Connection {
+            transport: Box::new(Grpc::connect(self)?),
+        })
+    }
+
+    /// Returns the log of a branch of the repository at this address, newest first (as in
+    /// [`Repo::log`]).
+    pub fn log(&self, branch: &str) -> Result<Vec<LogEntryJson>, Error> {
+        let mut ret = Vec::new();
+        let req = Branch {
+            name: branch.to_owned(),
+        };
+        Grpc::connect(self)?.server_streaming(LOG, req, |e: LogEntry| {
+            ret.push(e.into());
+            Ok(())
+        })?;
+        Ok(ret)
+    }
+}
+
+// A transport that sends each request as a call to a `GrpcService`.
+struct Grpc {
+    // The calls are asynchronous, but the rest of the protocol isn't, so we wait for each one to
+    // finish.
+    runtime: Runtime,
+    client: tonic::client::Grpc<Channel>,
+    // The answer to the last request, in the same format as the answers of `serve`.
+    answer: Cursor<Vec<u8>>,
+}
+
+impl Grpc {
+    fn connect(addr: &GrpcAddress) -> Result<Grpc, Error> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let uri = format!("http://{}:{}", addr.host, addr.port);
+        let endpoint = Endpoint::from_shared(uri)
+            .map_err(|_| Error::InvalidRemote(format!("grpc://{}:{}", addr.host, addr.port)))?;
+        let channel = runtime.block_on(endpoint.connect()).map_err(|e| {
+            Error::Io(
+                io::Error::other(e.to_string()),
+                format!("Could not connect to {}:{}", addr.host, addr.port),
+            )
+        })?;
+        Ok(Grpc {
+            runtime,
+            client: tonic::client::Grpc::new(channel),
+            answer: Cursor::new(Vec::new()),
+        })
+    }
+
+    fn unary<M, R>(&mut self, path: &'static str, msg: M) -> Result<R, Error>
+    where
+        M: prost::Message + Send + 'static,
+        R: prost::Message + Default + Send + 'static,
+    {
+        let client = &mut self.client;
+        self.runtime.block_on(async move {
+            client
+                .ready()
+                .await
+                .map_err(|e| Error::Remote(e.to_string()))?;
+            let path = PathAndQuery::from_static(path);
+            let resp = client
+                .unary(tonic::Request::new(msg), path, ProstCodec::default())
+                .await
+                .map_err(remote)?;
+            Ok(resp.into_inner())
+        })
+    }
+
+    // Makes a call that answers with a stream, and passes each message to `f` as soon as it
+    // arrives.
+    fn server_streaming<M, R, F>(
+        &mut self,
+        path: &'static str,
+        msg: M,
+        mut f: F,
+    ) -> Result<(), Error>
+    where
+        M: prost::Message + Send + 'static,
+        R: prost::Message + Default + Send + 'static,
+        F: FnMut(R) -> Result<(), Error>,
+    {
+        let client = &mut self.client;
+        self.runtime.block_on(async move {
+            client
+                .ready()
+                .await
+                .map_err(|e| Error::Remote(e.to_string()))?;
+            let path = PathAndQuery::from_static(path);
+            let mut stream = client
+                .server_streaming(tonic::Request::new(msg), path, ProstCodec::default())
+                .await
+                .map_err(remote)?
+                .into_inner();
+            while let Some(r) = stream.message().await.map_err(remote)? {
+                f(r)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn push(
+        &mut self,
+        branch: &str,
+        ids: &[PatchId],
+        patches: &[&[u8]],
+    ) -> Result<PatchIds, Error> {
+        let start = PushItem::Start(PushStart {
+            branch: branch.to_owned(),
+            ids: encode_ids(ids).ids,
+        });
+        let msgs = std::iter::once(start)
+            .chain(patches.iter().map(|p| PushItem::Patch(p.to_vec())))
+            .map(|item| PushMessage { item: Some(item) })
+            .collect::<Vec<_>>();
+        let client = &mut self.client;
+        self.runtime.block_on(async move {
+            client
+                .ready()
+                .await
+                .map_err(|e| Error::Remote(e.to_string()))?;
+            let req = tonic::Request::new(tokio_stream::iter(msgs));
+            let path = PathAndQuery::from_static(PUSH);
+            let resp = client
+                .client_streaming(req, path, ProstCodec::default())
+                .await
+                .map_err(remote)?;
+            Ok(resp.into_inner())
+        })
+    }
+}
+
+impl Transport for Grpc {
+    fn send(&mut self, req: &Request, patches: &[&[u8]]) -> Result<&mut dyn Read, Error> {
+        let mut answer = Vec::new();
+        let ids = match req {
+            Request::List { branch } => {
+                let branch = Branch {
+                    name: branch.clone(),
+                };
+                self.unary::<_, PatchIds>(ADVERTISE, branch)?
+            }
+            Request::Missing { ids } => self.unary::<_, PatchIds>(MISSING, encode_ids(ids))?,
+            Request::Fetch { ids } => {
+                // The patches go after the ids, as they do in the answers of `serve`.
+                let mut order = Vec::new();
+                let mut data = Vec::new();
+                self.fetch(ids.clone(), &mut |id, patch| {
+                    order.push(id);
+                    write_section(&mut data, &patch)
+                })?;
+                let resp = encode_response(&Response::Ids(order), WireFormat::Bincode)?;
+                write_section(&mut answer, &resp)?;
+                answer.extend_from_slice(&data);
+                self.answer = Cursor::new(answer);
+                return Ok(&mut self.answer);
+            }
+            Request::Push { branch, ids, .. } => self.push(branch, ids, patches)?,
+        };
+        let resp = Response::Ids(decode_ids(&ids.ids)?);
+        write_section(&mut answer, &encode_response(&resp, WireFormat::Bincode)?)?;
+        self.answer = Cursor::new(answer);
+        Ok(&mut self.answer)
+    }
+
+    fn fetch(
+        &mut self,
+        ids: Vec<PatchId>,
+        f: &mut dyn FnMut(PatchId, Vec<u8>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        self.server_streaming(FETCH, encode_ids(&ids), |p: Patch| {
+            f(decode_id(&p.id)?, p.data)
+        })
+    }
+
+    fn close(self: Box<Self>) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// A gRPC service that answers the requests of [`GrpcAddress::connect`] (and of
+/// [`GrpcAddress::log`]) using a repository.
+///
+/// This can be added to a server alongside other services (with
+/// `tonic::transport::Server::add_service`). Requests that only read the repository are answered
+/// in parallel; pushes are answered one at a time, and the repository is written after each one,
+/// even if it failed part of the way through (so that the patches that did arrive don't need to
+/// be sent again). The service keeps the repository open, so nothing else should change it while
+/// the service is running.
+#[derive(Clone)]
+pub struct GrpcService {
+    repo: Arc<RwLock<Repo>>,
+}
+
+impl GrpcService {
+    /// Creates a service for a repository.
+    pub fn new(repo: Repo) -> GrpcService {
+        GrpcService {
+            repo: Arc::new(RwLock::new(repo)),
+        }
+    }
+
+    // Runs `f` (which is allowed to block) on the repository.
+    async fn read<T, F>(&self, f: F) -> Result<T, Status>
+    where
+        F: FnOnce(&Repo) -> Result<T, Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let repo = Arc::clone(&self.repo);
+        // A lock is poisoned only if a push panicked, and the repository is usable anyway.
+        tokio::task::spawn_blocking(move || f(&repo.read().unwrap_or_else(|e| e.into_inner())))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(status)
+    }
+
+    // Runs `f` (which is allowed to block) on the repository, and sends the messages that it
+    // produces as soon as they are ready. If `f` fails, the stream ends with the error.
+    fn stream<T, F>(&self, f: F) -> ReceiverStream<Result<T, Status>>
+    where
+        F: FnOnce(&Repo, &mut dyn FnMut(T) -> bool) -> Result<(), Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let repo = Arc::clone(&self.repo);
+        let (tx, rx) = mpsc::channel(4);
+        tokio::task::spawn_blocking(move || {
+            // If the client goes away, there's no point in sending it anything else.
+            let mut send = |x| tx.blocking_send(Ok(x)).is_ok();
+            let ret = f(&repo.read().unwrap_or_else(|e| e.into_inner()), &mut send);
+            if let Err(e) = ret {
+                let _ = tx.blocking_send(Err(status(e)));
+            }
+        });
+        ReceiverStream::new(rx)
+    }
+
+    async fn advertise(self, branch: Branch) -> Result<PatchIds, Status> {
+        self.read(move |repo| {
+            repo.inode(&branch.name)?;
+            let ids = repo.patches(&branch.name).cloned().collect::<Vec<_>>();
+            Ok(encode_ids(&repo.patch_graph().apply_order(&ids)))
+        })
+        .await
+    }
+
+    async fn missing(self, ids: PatchIds) -> Result<PatchIds, Status> {
+        self.read(move |repo| {
+            let missing = decode_ids(&ids.ids)?
+                .into_iter()
+                .filter(|id| !repo.storage.patches.contains_key(id))
+                .collect::<Vec<_>>();
+            Ok(encode_ids(&missing))
+        })
+        .await
+    }
+
+    fn fetch(self, ids: PatchIds) -> ReceiverStream<Result<Patch, Status>> {
+        self.stream(move |repo, send| {
+            let ids = decode_ids(&ids.ids)?;
+            if let Some(id) = ids.iter().find(|id| !repo.storage.patches.contains_key(id)) {
+                return Err(Error::UnknownPatch(*id));
+            }
+            for id in repo.patch_graph().apply_order(&ids) {
+                let patch = Patch {
+                    id: id.data.to_vec(),
+                    data: repo.open_patch_data(&id)?.to_owned(),
+                };
+                if !send(patch) {
+                    break;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn log(self, branch: Branch) -> ReceiverStream<Result<LogEntry, Status>> {
+        self.stream(move |repo, send| {
+            for id in repo.log(&branch.name, &PatchQuery::default())? {
+                if !send(LogEntryJson::new(repo, &id)?.into()) {
+                    break;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    async fn push(self, mut msgs: Streaming<PushMessage>) -> Result<PatchIds, Status> {
+        // The messages are handed over to a thread that is allowed to block, which registers
+        // each patch as soon as it arrives.
+        let (tx, mut rx) = mpsc::channel(4);
+        let repo = Arc::clone(&self.repo);
+        let receiver = tokio::task::spawn_blocking(move || receive(&repo, &mut rx));
+        loop {
+            let (item, last) = match msgs.message().await {
+                Ok(Some(msg)) => (
+                    msg.item
+                        .ok_or_else(|| Status::invalid_argument("empty push message")),
+                    false,
+                ),
+                Ok(None) => break,
+                Err(e) => (Err(e), true),
+            };
+            // If the receiver stopped early, it will say why.
+            if tx.send(item).await.is_err() || last {
+                break;
+            }
+        }
+        drop(tx);
+        receiver
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(|e| *e)
+    }
+}
+
+// Receives the messages of a push, and applies the patches. (The error is boxed because a
+// `Status` is big.)
+fn receive(
+    repo: &RwLock<Repo>,
+    rx: &mut mpsc::Receiver<Result<PushItem, Status>>,
+) -> Result<PatchIds, Box<Status>> {
+    let (branch, ids) = match rx.blocking_recv() {
+        Some(Ok(PushItem::Start(start))) => (start.branch, decode_ids(&start.ids).map_err(status)?),
+        Some(Err(e)) => return Err(Box::new(e)),
+        _ => {
+            return Err(Box::new(Status::invalid_argument(
+                "a push must start with the branch and the patches to apply",
+            )))
+        }
+    };
+
+    let mut repo = repo.write().unwrap_or_else(|e| e.into_inner());
+    let mut registered = Ok(());
+    let ret = loop {
+        match rx.blocking_recv() {
+            Some(Ok(PushItem::Patch(data))) => {
+                if let Err(e) = repo.register_patch(&data) {
+                    registered = registered.and(Err(e));
+                }
+            }
+            Some(Ok(PushItem::Start(_))) => {
+                break Err(Status::invalid_argument("a push can only start once"));
+            }
+            Some(Err(e)) => break Err(e),
+            None => {
+                break registered
+                    .and_then(|_| apply_all(&mut repo, &branch, &ids))
+                    .map(|applied| encode_ids(&applied))
+                    .map_err(status);
+            }
+        }
+    };
+    repo.write().map_err(status)?;
+    Ok(ret?)
+}
+
+// The calls are answered by functions that take a message and return a future. These adapt
+// them to the traits that `tonic::server::Grpc` expects.
+struct Unary<F>(F);
+struct ServerStream<F>(F);
+struct ClientStream<F>(F);
+
+impl<M, R, F, Fut> UnaryService<M> for Unary<F>
+where
+    F: FnMut(M) -> Fut,
+    Fut: Future<Output = Result<R, Status>> + Send + 'static,
+{
+    type Response = R;
+    type Future = BoxFuture<tonic::Response<R>, Status>;
+
+    fn call(&mut self, req: tonic::Request<M>) -> Self::Future {
+        let fut = (self.0)(req.into_inner());
+        Box::pin(async move { fut.await.map(tonic::Response::new) })
+    }
+}
+
+impl<M, R, F> ServerStreamingService<M> for ServerStream<F>
+where
+    F: FnMut(M) -> ReceiverStream<Result<R, Status>>,
+{
+    type Response = R;
+    type ResponseStream = ReceiverStream<Result<R, Status>>;
+    type Future = std::future::Ready<Result<tonic::Response<Self::ResponseStream>, Status>>;
+
+    fn call(&mut self, req: tonic::Request<M>) -> Self::Future {
+        std::future::ready(Ok(tonic::Response::new((self.0)(req.into_inner()))))
+    }
+}
+
+impl<M, R, F, Fut> ClientStreamingService<M> for ClientStream<F>
+where
+    F: FnMut(Streaming<M>) -> Fut,
+    Fut: Future<Output = Result<R, Status>> + Send + 'static,
+{
+    type Response = R;
+    type Future = BoxFuture<tonic::Response<R>, Status>;
+
+    fn call(&mut self, req: tonic::Request<Streaming<M>>) -> Self::Future {
+        let fut = (self.0)(req.into_inner());
+        Box::pin(async move { fut.await.map(tonic::Response::new) })
+    }
+}
+
+// The codec depends on the types of the messages, so each method needs its own.
+fn codec<E, D>() -> tonic::server::Grpc<ProstCodec<E, D>>
+where
+    E: prost::Message + Send + 'static,
+    D: prost::Message + Default + Send + 'static,
+{
+    tonic::server::Grpc::new(ProstCodec::default())
+}
+
+impl<B> Service<http::Request<B>> for GrpcService
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Infallible>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let svc = self.clone();
+        match req.uri().path() {
+            ADVERTISE => Box::pin(async move {
+                let method = Unary(move |b| svc.clone().advertise(b));
+                Ok(codec().unary(method, req).await)
+            }),
+            MISSING => Box::pin(async move {
+                let method = Unary(move |ids| svc.clone().missing(ids));
+                Ok(codec().unary(method, req).await)
+            }),
+            FETCH => Box::pin(async move {
+                let method = ServerStream(move |ids| svc.clone().fetch(ids));
+                Ok(codec().server_streaming(method, req).await)
+            }),
+            PUSH => Box::pin(async move {
+                let method = ClientStream(move |msgs| svc.clone().push(msgs));
+                Ok(codec().client_streaming(method, req).await)
+            }),
+            LOG => Box::pin(async move {
+                let method = ServerStream(move |b| svc.clone().log(b));
+                Ok(codec().server_streaming(method, req).await)
+            }),
+            _ => Box::pin(async move { Ok(Status::unimplemented("no such method").into_http()) }),
+        }
+    }
+}
+
+impl NamedService for GrpcService {
+    const NAME: &'static str = "ojo.Remote";
+}
+
+/// Serves a repository over gRPC (see [`GrpcService`]), until the process is killed or the
+/// server fails.
+///
+/// This is a reference server; to serve the repository alongside other services, add a
+/// [`GrpcService`] to a server of your own.
+pub fn run_grpc_server(addr: &std::net::SocketAddr, repo: Repo) -> Result<(), Error> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    let server = tonic::transport::Server::builder()
+        .add_service(GrpcService::new(repo))
+        .serve(*addr);
+    runtime.block_on(server).map_err(|e| {
+        Error::Io(
+            io::Error::other(e.to_string()),
+            format!("Could not serve on {}", addr),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{create, tmp_dir};
+    use std::net::TcpListener;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            GrpcAddress::parse("grpc://example.com:1234/").unwrap(),
+            GrpcAddress {
+                host: "example.com".to_owned(),
+                port: 1234,
+            }
+        );
+        assert_eq!(
+            GrpcAddress::parse("grpc://example.com").unwrap().port,
+            50051
+        );
+        assert!(GrpcAddress::parse("grpc://example.com/repo").is_err());
+        assert!(GrpcAddress::parse("http://example.com").is_err());
+    }
+
+    #[test]
+    fn pull_and_push() {
+        let dir = tmp_dir("grpc");
+        let mut remote = Repo::init(&dir).unwrap();
+        let a = create(&mut remote, "master", b"a\n");
+        remote.write().unwrap();
+
+        // Find a free port, and serve the repository on it.
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let addr = ([127, 0, 0, 1], port).into();
+        std::thread::spawn(move || run_grpc_server(&addr, remote).unwrap());
+        let addr = GrpcAddress {
+            host: "127.0.0.1".to_owned(),
+            port,
+        };
+        let mut conn = loop {
+            match addr.connect() {
+                Ok(conn) => break conn,
+                Err(_) => std::thread::sleep(std::time::Duration::from_millis(10)),
+            }
+        };
+
+        let mut local = Repo::init_tmp();
+        let transfer = conn.pull(&mut local, "master", "master").unwrap();
+        assert_eq!(transfer.transferred, vec![a]);
+        assert_eq!(local.file("master").unwrap().as_bytes(), b"a\n");
+        match conn.pull(&mut local, "missing", "master") {
+            Err(Error::Remote(_)) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+
+        let b = create(&mut local, "master", b"a\nb\n");
+        let transfer = conn.push(&mut local, "master", "other").unwrap();
+        assert_eq!(transfer.transferred, vec![b]);
+        assert_eq!(transfer.applied, vec![a, b]);
+        conn.close().unwrap();
+
+        let log = addr.log("other").unwrap();
+        let ids = log.iter().map(|e| e.id.clone()).collect::<Vec<_>>();
+        assert_eq!(ids, vec![b.to_base64(), a.to_base64()]);
+        let remote = Repo::open(&dir).unwrap();
+        assert_eq!(remote.file("other").unwrap().as_bytes(), b"a\nb\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
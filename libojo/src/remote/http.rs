@@ -0,0 +1,498 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// The HTTP transport. Every request is a separate HTTP request, to one of these endpoints:
+//
+// - `GET /branches/<branch>` lists the patches on a branch (the branch name is percent-encoded);
+// - `POST /missing` asks which patches the server doesn't have;
+// - `POST /fetch` asks for the data of some patches;
+// - `POST /push/<branch>` sends some patches and applies them to a branch.
+//
+// The bodies are the same as in the stream protocol, minus the request itself: the body of
+// `/missing` and `/fetch` is a section containing the ids, and the body of `/push` is a section
+// containing the ids to apply, followed by one section for each patch that is sent. The body of
+// an answer with status 200 is exactly what `serve` would have written. Other statuses mean that
+// the request couldn't be understood, and their bodies are plain text.
+
+use std::io::{BufRead, BufReader, Cursor, Read, Write};
+use std::net::TcpStream;
+
+use super::{handle, Connection, Request, Transport, WireFormat};
+use crate::patch::binary::{read_section, write_section};
+use crate::{Error, PatchId, Repo};
+
+/// The address of a repository that can be reached over HTTP.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HttpAddress {
+    /// The machine to connect to.
+    pub host: String,
+    /// The port to connect to.
+    pub port: u16,
+    /// The path that the endpoints are under (without a trailing slash, so it is empty if they
+    /// are at the root).
+    pub prefix: String,
+}
+
+impl HttpAddress {
+    /// Parses an address of the form `http://host[:port][/prefix]`.
+    pub fn parse(addr: &str) -> Result<HttpAddress, Error> {
+        let invalid = || Error::InvalidRemote(addr.to_owned());
+        let rest = addr.strip_prefix("http://").ok_or_else(invalid)?;
+        let (authority, prefix) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, ""),
+        };
+        let (host, port) = match authority.rfind(':') {
+            Some(colon) => {
+                let port = authority[(colon + 1)..].parse().map_err(|_| invalid())?;
+                (&authority[..colon], port)
+            }
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(HttpAddress {
+            host: host.to_owned(),
+            port,
+            prefix: prefix.trim_end_matches('/').to_owned(),
+        })
+    }
+
+    /// Returns a connection to this address. Nothing is sent until the first request.
+    pub fn connect(&self) -> Connection {
+        Connection {
+            transport: Box::new(Http {
+                addr: self.clone(),
+                answer: Cursor::new(Vec::new()),
+            }),
+        }
+    }
+}
+
+// A transport that sends each request as an HTTP request.
+struct Http {
+    addr: HttpAddress,
+    // The body of the last answer.
+    answer: Cursor<Vec<u8>>,
+}
+
+impl Http {
+    // Sends an HTTP request, and returns the body of the answer.
+    fn exchange(&self, method: &str, path: &str, body: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut stream =
+            TcpStream::connect((self.addr.host.as_str(), self.addr.port)).map_err(|e| {
+                Error::Io(
+                    e,
+                    format!("Could not connect to {}:{}", self.addr.host, self.addr.port),
+                )
+            })?;
+        write!(
+            stream,
+            "{} {}{} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Content-Type: application/octet-stream\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n",
+            method,
+            self.addr.prefix,
+            path,
+            self.addr.host,
+            body.len()
+        )?;
+        stream.write_all(body)?;
+        stream.flush()?;
+
+        let mut reader = BufReader::new(stream);
+        let (status_line, length) = read_head(&mut reader)?;
+        let status = status_line.split(' ').nth(1).unwrap_or("");
+        let mut ret = Vec::new();
+        match length {
+            Some(len) => {
+                ret.resize(len, 0);
+                reader.read_exact(&mut ret)?;
+            }
+            None => {
+                reader.read_to_end(&mut ret)?;
+            }
+        }
+        if status != "200" {
+            return Err(Error::Remote(format!(
+                "{} ({})",
+                status_line.trim(),
+                String::from_utf8_lossy(&ret).trim()
+            )));
+        }
+        Ok(ret)
+    }
+}
+
+impl Transport for Http {
+    fn send(&mut self, req: &Request, patches: &[&[u8]]) -> Result<&mut dyn Read, Error> {
+        let mut body = Vec::new();
+        let (method, path) = match req {
+            Request::List { branch } => ("GET", format!("/branches/{}", percent_encode(branch))),
+            Request::Missing { ids } => {
+                write_section(&mut body, &bincode::serialize(ids)?)?;
+                ("POST", "/missing".to_owned())
+            }
+            Request::Fetch { ids } => {
+                write_section(&mut body, &bincode::serialize(ids)?)?;
+                ("POST", "/fetch".to_owned())
+            }
+            Request::Push { branch, ids, .. } => {
+                write_section(&mut body, &bincode::serialize(ids)?)?;
+                for p in patches {
+                    write_section(&mut body, p)?;
+                }
+                ("POST", format!("/push/{}", percent_encode(branch)))
+            }
+        };
+        self.answer = Cursor::new(self.exchange(method, &path, &body)?);
+        Ok(&mut self.answer)
+    }
+
+    fn close(self: Box<Self>) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+// Reads the first line and the headers of an HTTP message, and returns the first line along with
+// the value of the `Content-Length` header (if there is one).
+fn read_head<R: BufRead>(mut reader: R) -> Result<(String, Option<usize>), Error> {
+    let bad = |msg: &str| Error::Remote(format!("malformed HTTP message: {}", msg));
+    let mut first = String::new();
+    reader.read_line(&mut first)?;
+    if first.is_empty() {
+        return Err(bad("it was empty"));
+    }
+
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(bad("the headers didn't end"));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            return Ok((first, length));
+        }
+        let colon = line.find(':').ok_or_else(|| bad(line))?;
+        let (name, value) = (&line[..colon], line[(colon + 1)..].trim());
+        if name.eq_ignore_ascii_case("content-length") {
+            length = Some(value.parse().map_err(|_| bad(line))?);
+        } else if name.eq_ignore_ascii_case("transfer-encoding")
+            && !value.eq_ignore_ascii_case("identity")
+        {
+            return Err(bad("transfer encodings aren't supported"));
+        }
+    }
+}
+
+// Encodes a branch name so that it can go in a path. Everything except for ASCII letters, digits
+// and `-._~` gets encoded.
+fn percent_encode(s: &str) -> String {
+    let mut ret = String::new();
+    for &b in s.as_bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            ret.push(b as char);
+        } else {
+            ret.push_str(&format!("%{:02X}", b));
+        }
+    }
+    ret
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let mut ret = Vec::new();
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            ret.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            ret.push(b);
+        }
+    }
+    String::from_utf8(ret).ok()
+}
+
+/// The answer to an HTTP request (see [`respond`]).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HttpAnswer {
+    /// The HTTP status code.
+    pub status: u16,
+    /// The body of the answer.
+    pub body: Vec<u8>,
+    /// Did the request change the repository? If so, it needs to be written (see
+    /// [`Repo::write`]).
+    pub modified: bool,
+}
+
+impl HttpAnswer {
+    fn text(status: u16, msg: &str) -> HttpAnswer {
+        HttpAnswer {
+            status,
+            body: msg.as_bytes().to_owned(),
+            modified: false,
+        }
+    }
+}
+
+// Reads the ids at the start of a request body.
+fn read_ids<R: Read>(body: R) -> Option<Vec<PatchId>> {
+    bincode::deserialize(&read_section(body).ok()?).ok()
+}
+
+// Counts the sections in some data, or returns `None` if the last one is cut off.
+fn count_sections(mut data: &[u8]) -> Option<u64> {
+    let mut ret = 0;
+    while !data.is_empty() {
+        if data.len() < 8 {
+            return None;
+        }
+        let (len, rest) = data.split_at(8);
+        let mut len_bytes = [0; 8];
+        len_bytes.copy_from_slice(len);
+        let len = u64::from_le_bytes(len_bytes);
+        if (rest.len() as u64) < len {
+            return None;
+        }
+        data = &rest[(len as usize)..];
+        ret += 1;
+    }
+    Some(ret)
+}
+
+// Turns an HTTP request into a request of the stream protocol, along with the part of the body
+// that contains the patches.
+fn parse_request<'a>(method: &str, path: &str, mut body: &'a [u8]) -> Option<(Request, &'a [u8])> {
+    let branch = |prefix: &str| path.strip_prefix(prefix).and_then(percent_decode);
+    let req = match method {
+        "GET" => Request::List {
+            branch: branch("/branches/")?,
+        },
+        "POST" if path == "/missing" => Request::Missing {
+            ids: read_ids(&mut body)?,
+        },
+        "POST" if path == "/fetch" => Request::Fetch {
+            ids: read_ids(&mut body)?,
+        },
+        "POST" => {
+            let branch = branch("/push/")?;
+            let ids = read_ids(&mut body)?;
+            Request::Push {
+                branch,
+                ids,
+                count: count_sections(body)?,
+            }
+        }
+        _ => return None,
+    };
+    Some((req, body))
+}
+
+/// Answers an HTTP request for the repository `repo`, where `path` is the path of the request
+/// relative to the address that the repository is served at.
+///
+/// This is all that an HTTP server needs in order to serve a repository: it should pass the
+/// requests here, and send back the answers. If an answer says that the repository was modified,
+/// the server should write it before answering (so a client that gets an answer knows that its
+/// patches arrived). Requests shouldn't be answered concurrently.
+pub fn respond(repo: &mut Repo, method: &str, path: &str, body: &[u8]) -> HttpAnswer {
+    let known = path.starts_with("/branches/")
+        || path.starts_with("/push/")
+        || path == "/missing"
+        || path == "/fetch";
+    if !known {
+        return HttpAnswer::text(404, "not found");
+    }
+    let (req, patches) = match parse_request(method, path, body) {
+        Some(x) => x,
+        None => return HttpAnswer::text(400, "malformed request"),
+    };
+    let modified = matches!(req, Request::Push { .. });
+    let mut answer = Vec::new();
+    match handle(repo, req, patches, &mut answer, WireFormat::Bincode) {
+        Ok(()) => HttpAnswer {
+            status: 200,
+            body: answer,
+            modified,
+        },
+        Err(e) => HttpAnswer {
+            status: 500,
+            body: e.to_string().into_bytes(),
+            modified,
+        },
+    }
+}
+
+/// Serves the repository at `path` over HTTP, at the root of `addr`. This doesn't return unless
+/// it fails to start.
+///
+/// This is a reference server, meant to make it easy to host a repository. It opens the
+/// repository separately for each request, and answers one request at a time.
+#[cfg(feature = "http-server")]
+pub fn run_http_server(addr: &std::net::SocketAddr, path: std::path::PathBuf) -> Result<(), Error> {
+    use hyper::rt::{Future, Stream};
+    use hyper::service::service_fn;
+    use hyper::{Body, Response, Server};
+    use std::sync::{Arc, Mutex};
+
+    // Answers a request, with the repository opened and written around it.
+    fn answer(path: &std::path::Path, method: &str, uri: &str, body: &[u8]) -> HttpAnswer {
+        let mut repo = match Repo::open(path) {
+            Ok(r) => r,
+            Err(e) => return HttpAnswer::text(500, &e.to_string()),
+        };
+        let ret = respond(&mut repo, method, uri, body);
+        if ret.modified {
+            if let Err(e) = repo.write() {
+                return HttpAnswer::text(500, &e.to_string());
+            }
+        }
+        ret
+    }
+
+    let lock = Arc::new(Mutex::new(()));
+    let new_service = move || {
+        let path = path.clone();
+        let lock = Arc::clone(&lock);
+        service_fn(move |req: hyper::Request<Body>| {
+            let path = path.clone();
+            let lock = Arc::clone(&lock);
+            let method = req.method().as_str().to_owned();
+            let uri = req.uri().path().to_owned();
+            req.into_body().concat2().map(move |body| {
+                // The lock is poisoned only if answering a request panicked; the repository is
+                // reopened for each request anyway.
+                let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+                let ans = answer(&path, &method, &uri, &body);
+                let mut resp = Response::new(Body::from(ans.body));
+                // The unwrap is ok because all of our statuses are valid.
+                *resp.status_mut() = hyper::StatusCode::from_u16(ans.status).unwrap();
+                resp
+            })
+        })
+    };
+
+    let server = Server::try_bind(addr)
+        .map_err(|e| {
+            Error::Io(
+                std::io::Error::other(e.to_string()),
+                format!("Could not listen on {}", addr),
+            )
+        })?
+        .serve(new_service)
+        .map_err(|e| error!("HTTP server error: {}", e));
+    hyper::rt::run(server);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::create;
+    use std::net::TcpListener;
+
+    #[test]
+    fn parse() {
+        let parse = |s| HttpAddress::parse(s).ok();
+        let addr = |host: &str, port, prefix: &str| HttpAddress {
+            host: host.to_owned(),
+            port,
+            prefix: prefix.to_owned(),
+        };
+        assert_eq!(
+            parse("http://example.com:8080/repos/mine/"),
+            Some(addr("example.com", 8080, "/repos/mine"))
+        );
+        assert_eq!(
+            parse("http://example.com"),
+            Some(addr("example.com", 80, ""))
+        );
+        assert_eq!(parse("https://example.com"), None);
+        assert_eq!(parse("http://:80/"), None);
+        assert_eq!(parse("http://example.com:port"), None);
+    }
+
+    #[test]
+    fn percent_encoding() {
+        let name = "feature/ü %";
+        assert_eq!(percent_encode(name), "feature%2F%C3%BC%20%25");
+        assert_eq!(percent_decode(&percent_encode(name)).unwrap(), name);
+        assert_eq!(percent_decode("%2"), None);
+    }
+
+    #[test]
+    fn respond_errors() {
+        let mut repo = Repo::init_tmp();
+        assert_eq!(respond(&mut repo, "GET", "/nowhere", b"").status, 404);
+        assert_eq!(respond(&mut repo, "POST", "/fetch", b"junk").status, 400);
+        assert_eq!(
+            respond(&mut repo, "PUT", "/branches/master", b"").status,
+            400
+        );
+
+        // Errors in a well-formed request go to the client.
+        let ans = respond(&mut repo, "GET", "/branches/missing", b"");
+        assert_eq!(ans.status, 200);
+        assert!(!ans.modified);
+    }
+
+    // Runs a client against a server on another thread.
+    #[test]
+    fn pull_and_push() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let mut repo = Repo::init_tmp();
+            let first = create(&mut repo, "master", b"a\n");
+            // Two requests for the pull, then two for the push.
+            for _ in 0..4 {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let (line, len) = read_head(&mut reader).unwrap();
+                let mut body = vec![0; len.unwrap_or(0)];
+                reader.read_exact(&mut body).unwrap();
+                let mut words = line.split(' ');
+                let (method, path) = (words.next().unwrap(), words.next().unwrap());
+                let path = path.strip_prefix("/repo").unwrap();
+                let ans = respond(&mut repo, method, path, &body);
+                let mut stream = reader.into_inner();
+                write!(
+                    stream,
+                    "HTTP/1.1 {} X\r\nContent-Length: {}\r\n\r\n",
+                    ans.status,
+                    ans.body.len()
+                )
+                .unwrap();
+                stream.write_all(&ans.body).unwrap();
+            }
+            (first, repo.file("master").unwrap().as_bytes().to_owned())
+        });
+
+        let mut repo = Repo::init_tmp();
+        let addr = HttpAddress::parse(&format!("http://127.0.0.1:{}/repo", port)).unwrap();
+        let mut conn = addr.connect();
+        let pulled = conn.pull(&mut repo, "master", "master").unwrap();
+        assert_eq!(pulled.transferred.len(), 1);
+        create(&mut repo, "master", b"a\nb\n");
+        let pushed = conn.push(&mut repo, "master", "master").unwrap();
+        assert_eq!(pushed.transferred.len(), 1);
+        conn.close().unwrap();
+
+        let (first, contents) = server.join().unwrap();
+        assert_eq!(pulled.transferred, vec![first]);
+        assert_eq!(contents, b"a\nb\n");
+    }
+}
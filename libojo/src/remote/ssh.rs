@@ -0,0 +1,138 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// The SSH transport: we run `ssh`, and ask it to start `ojo serve` on the other machine.
+
+use std::process::{Command, Stdio};
+
+use super::Connection;
+use crate::Error;
+
+/// The address of a repository that can be reached over SSH.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SshAddress {
+    /// The machine to connect to, possibly including a user name (as in `user@host`).
+    pub host: String,
+    /// The port to connect to, if it isn't the default one.
+    pub port: Option<u16>,
+    /// The path of the repository on that machine. If it is relative, it is relative to the home
+    /// directory.
+    pub path: String,
+}
+
+impl SshAddress {
+    /// Parses an address of the form `ssh://[user@]host[:port]/path` or `[user@]host:path`.
+    pub fn parse(addr: &str) -> Result<SshAddress, Error> {
+        let invalid = || Error::InvalidRemote(addr.to_owned());
+        let (host, port, path) = if let Some(rest) = addr.strip_prefix("ssh://") {
+            let slash = rest.find('/').ok_or_else(invalid)?;
+            let (authority, path) = rest.split_at(slash);
+            match authority.rfind(':') {
+                Some(colon) => {
+                    let port = authority[(colon + 1)..].parse().map_err(|_| invalid())?;
+                    (&authority[..colon], Some(port), path)
+                }
+                None => (authority, None, path),
+            }
+        } else {
+            let colon = addr.find(':').ok_or_else(invalid)?;
+            if addr[..colon].contains('/') {
+                return Err(invalid());
+            }
+            (&addr[..colon], None, &addr[(colon + 1)..])
+        };
+        if host.is_empty() || path.is_empty() {
+            return Err(invalid());
+        }
+        Ok(SshAddress {
+            host: host.to_owned(),
+            port,
+            path: path.to_owned(),
+        })
+    }
+
+    /// Returns the command that connects to this address and starts a server there, using the
+    /// program `remote_ojo` on the other machine (this is usually just `ojo`).
+    pub fn command(&self, remote_ojo: &str) -> Command {
+        let mut ret = Command::new("ssh");
+        if let Some(port) = self.port {
+            ret.arg("-p").arg(port.to_string());
+        }
+        // The remote command gets interpreted by a shell.
+        ret.arg(&self.host)
+            .arg(format!("{} serve {}", remote_ojo, shell_quote(&self.path)));
+        ret
+    }
+
+    /// Connects to this address, using the program `remote_ojo` on the other machine (this is
+    /// usually just `ojo`).
+    pub fn connect(&self, remote_ojo: &str) -> Result<Connection, Error> {
+        let mut child = self
+            .command(remote_ojo)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| Error::Io(e, "Could not run ssh".to_owned()))?;
+        // The unwraps are ok because we asked for both streams to be piped.
+        let reader = child.stdout.take().unwrap();
+        let writer = child.stdin.take().unwrap();
+        Ok(Connection::from_stream(reader, writer, Some(child)))
+    }
+}
+
+// Quotes a string so that a POSIX shell will treat it as a single word.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(host: &str, port: Option<u16>, path: &str) -> SshAddress {
+        SshAddress {
+            host: host.to_owned(),
+            port,
+            path: path.to_owned(),
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let parse = |s| SshAddress::parse(s).ok();
+        assert_eq!(
+            parse("ssh://me@example.com:2222/srv/repo"),
+            Some(addr("me@example.com", Some(2222), "/srv/repo"))
+        );
+        assert_eq!(
+            parse("ssh://example.com/repo"),
+            Some(addr("example.com", None, "/repo"))
+        );
+        assert_eq!(
+            parse("me@example.com:repo"),
+            Some(addr("me@example.com", None, "repo"))
+        );
+        assert_eq!(parse("ssh://example.com"), None);
+        assert_eq!(parse("ssh://example.com:port/repo"), None);
+        assert_eq!(parse("dir/repo:x"), None);
+        assert_eq!(parse("example.com:"), None);
+    }
+
+    #[test]
+    fn command() {
+        let cmd = addr("example.com", Some(2222), "it's here").command("ojo");
+        assert_eq!(cmd.get_program(), "ssh");
+        assert_eq!(
+            cmd.get_args().collect::<Vec<_>>(),
+            vec!["-p", "2222", "example.com", "ojo serve 'it'\\''s here'"]
+        );
+    }
+}
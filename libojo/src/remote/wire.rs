@@ -0,0 +1,247 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// The FlatBuffers encoding of the protocol messages (see `WireFormat::FlatBuffers`). Every
+// request and every response is a single table, with this schema:
+//
+//     file_identifier "OJOW";
+//
+//     table Message {
+//       kind: ubyte;   // one of the `KIND_*` constants below
+//       text: string;  // the branch of a `List` or a `Push`, or the message of an `Error`
+//       ids: [ubyte];  // the patch ids, 32 bytes each, one after the other
+//       count: ulong;  // the number of patches that follow a `Push`
+//     }
+//
+//     root_type Message;
+//
+// The accessors below are what `flatc` would generate for this schema, minus the parts that we
+// don't use. Reading a message doesn't copy anything until it gets turned into a `Request` or a
+// `Response`, and the ids get copied straight out of the buffer, without going through serde.
+
+use flatbuffers::{
+    FlatBufferBuilder, Follow, ForwardsUOffset, InvalidFlatbuffer, Table, VOffsetT, Vector,
+    Verifiable, Verifier,
+};
+
+use super::{Request, Response, FLATBUFFERS_IDENTIFIER};
+use crate::{Error, PatchId};
+
+const KIND_LIST: u8 = 0;
+const KIND_MISSING: u8 = 1;
+const KIND_FETCH: u8 = 2;
+const KIND_PUSH: u8 = 3;
+const KIND_IDS: u8 = 4;
+const KIND_ERROR: u8 = 5;
+
+// The offsets of the fields in the vtable.
+const VT_KIND: VOffsetT = 4;
+const VT_TEXT: VOffsetT = 6;
+const VT_IDS: VOffsetT = 8;
+const VT_COUNT: VOffsetT = 10;
+
+#[derive(Clone, Copy)]
+struct Message<'a> {
+    tab: Table<'a>,
+}
+
+impl<'a> Follow<'a> for Message<'a> {
+    type Inner = Message<'a>;
+
+    unsafe fn follow(buf: &'a [u8], loc: usize) -> Message<'a> {
+        Message {
+            tab: Table::new(buf, loc),
+        }
+    }
+}
+
+impl Verifiable for Message<'_> {
+    fn run_verifier(v: &mut Verifier<'_, '_>, pos: usize) -> Result<(), InvalidFlatbuffer> {
+        v.visit_table(pos)?
+            .visit_field::<u8>("kind", VT_KIND, false)?
+            .visit_field::<ForwardsUOffset<&str>>("text", VT_TEXT, false)?
+            .visit_field::<ForwardsUOffset<Vector<'_, u8>>>("ids", VT_IDS, false)?
+            .visit_field::<u64>("count", VT_COUNT, false)?
+            .finish();
+        Ok(())
+    }
+}
+
+// The unsafe blocks are ok because a `Message` only comes from `flatbuffers::root`, which checks
+// that all of the fields are in the buffer and have the right types.
+impl<'a> Message<'a> {
+    fn kind(&self) -> u8 {
+        unsafe { self.tab.get::<u8>(VT_KIND, Some(0)).unwrap_or(0) }
+    }
+
+    fn text(&self) -> &'a str {
+        unsafe {
+            self.tab
+                .get::<ForwardsUOffset<&str>>(VT_TEXT, None)
+                .unwrap_or("")
+        }
+    }
+
+    fn ids(&self) -> &'a [u8] {
+        unsafe {
+            self.tab
+                .get::<ForwardsUOffset<Vector<'a, u8>>>(VT_IDS, None)
+                .map(|v| v.bytes())
+                .unwrap_or(&[])
+        }
+    }
+
+    fn count(&self) -> u64 {
+        unsafe { self.tab.get::<u64>(VT_COUNT, Some(0)).unwrap_or(0) }
+    }
+
+    fn patch_ids(&self) -> Result<Vec<PatchId>, Error> {
+        let ids = self.ids().chunks_exact(32);
+        if !ids.remainder().is_empty() {
+            return Err(invalid("the ids have the wrong length"));
+        }
+        Ok(ids
+            .map(|chunk| {
+                let mut data = [0; 32];
+                data.copy_from_slice(chunk);
+                PatchId { data }
+            })
+            .collect())
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::Remote(format!("invalid message: {}", msg))
+}
+
+fn parse(data: &[u8]) -> Result<Message<'_>, Error> {
+    flatbuffers::root::<Message<'_>>(data).map_err(|e| invalid(&e.to_string()))
+}
+
+fn encode(kind: u8, text: Option<&str>, ids: &[PatchId], count: u64) -> Vec<u8> {
+    let mut fbb = FlatBufferBuilder::new();
+    let text = text.map(|t| fbb.create_string(t));
+    let ids = if ids.is_empty() {
+        None
+    } else {
+        let bytes = ids.iter().flat_map(|id| id.data.iter().cloned());
+        Some(fbb.create_vector(&bytes.collect::<Vec<u8>>()))
+    };
+
+    let start = fbb.start_table();
+    fbb.push_slot::<u64>(VT_COUNT, count, 0);
+    if let Some(ids) = ids {
+        fbb.push_slot_always(VT_IDS, ids);
+    }
+    if let Some(text) = text {
+        fbb.push_slot_always(VT_TEXT, text);
+    }
+    fbb.push_slot::<u8>(VT_KIND, kind, 0);
+    let root = fbb.end_table(start);
+    fbb.finish(root, Some(FLATBUFFERS_IDENTIFIER));
+    fbb.finished_data().to_owned()
+}
+
+pub(super) fn encode_request(req: &Request) -> Vec<u8> {
+    match req {
+        Request::List { branch } => encode(KIND_LIST, Some(branch), &[], 0),
+        Request::Missing { ids } => encode(KIND_MISSING, None, ids, 0),
+        Request::Fetch { ids } => encode(KIND_FETCH, None, ids, 0),
+        Request::Push { branch, ids, count } => encode(KIND_PUSH, Some(branch), ids, *count),
+    }
+}
+
+pub(super) fn decode_request(data: &[u8]) -> Result<Request, Error> {
+    let msg = parse(data)?;
+    match msg.kind() {
+        KIND_LIST => Ok(Request::List {
+            branch: msg.text().to_owned(),
+        }),
+        KIND_MISSING => Ok(Request::Missing {
+            ids: msg.patch_ids()?,
+        }),
+        KIND_FETCH => Ok(Request::Fetch {
+            ids: msg.patch_ids()?,
+        }),
+        KIND_PUSH => Ok(Request::Push {
+            branch: msg.text().to_owned(),
+            ids: msg.patch_ids()?,
+            count: msg.count(),
+        }),
+        k => Err(invalid(&format!("unknown request kind {}", k))),
+    }
+}
+
+pub(super) fn encode_response(resp: &Response) -> Vec<u8> {
+    match resp {
+        Response::Ids(ids) => encode(KIND_IDS, None, ids, 0),
+        Response::Error(msg) => encode(KIND_ERROR, Some(msg), &[], 0),
+    }
+}
+
+pub(super) fn decode_response(data: &[u8]) -> Result<Response, Error> {
+    let msg = parse(data)?;
+    match msg.kind() {
+        KIND_IDS => Ok(Response::Ids(msg.patch_ids()?)),
+        KIND_ERROR => Ok(Response::Error(msg.text().to_owned())),
+        k => Err(invalid(&format!("unknown response kind {}", k))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(byte: u8) -> PatchId {
+        PatchId { data: [byte; 32] }
+    }
+
+    #[test]
+    fn round_trip() {
+        let push = Request::Push {
+            branch: "mäster".to_owned(),
+            ids: vec![id(1), id(2)],
+            count: 1,
+        };
+        let data = encode_request(&push);
+        assert!(flatbuffers::buffer_has_identifier(
+            &data,
+            FLATBUFFERS_IDENTIFIER,
+            false
+        ));
+        match decode_request(&data).unwrap() {
+            Request::Push { branch, ids, count } => {
+                assert_eq!(branch, "mäster");
+                assert_eq!(ids, vec![id(1), id(2)]);
+                assert_eq!(count, 1);
+            }
+            x => panic!("unexpected request {:?}", x),
+        }
+
+        match decode_response(&encode_response(&Response::Ids(vec![]))).unwrap() {
+            Response::Ids(ids) => assert!(ids.is_empty()),
+            x => panic!("unexpected response {:?}", x),
+        }
+        match decode_response(&encode_response(&Response::Error("oops".to_owned()))).unwrap() {
+            Response::Error(msg) => assert_eq!(msg, "oops"),
+            x => panic!("unexpected response {:?}", x),
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        let data = encode_request(&Request::Fetch { ids: vec![id(1)] });
+        assert!(decode_request(&data[..data.len() - 1]).is_err());
+        // A response isn't a request, and vice versa.
+        assert!(decode_request(&encode_response(&Response::Ids(vec![]))).is_err());
+        assert!(decode_response(&data).is_err());
+    }
+}
@@ -0,0 +1,199 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Rendering graggles that aren't totally ordered into files with conflict markers.
+//
+// The lines that are ordered with respect to all the others are written out as they are. The
+// alternatives of each conflict (see `Conflict`) are written out one after the other, between
+// conflict markers.
+
+use std::io::Write;
+
+use crate::conflict::{regions, Region};
+use crate::{Error, Graggle, NodeId, PatchId, Repo};
+
+// The marker at the start of a conflict, which is followed by the ids of the patches that
+// introduced the lines of the first alternative.
+const START_MARKER: &[u8] = b"<<<<<<<";
+// The marker between two alternatives of a conflict, which is followed by the ids of the patches
+// that introduced the lines of the next alternative.
+const SEPARATOR_MARKER: &[u8] = b"=======";
+// The marker at the end of a conflict.
+const END_MARKER: &[u8] = b">>>>>>>";
+
+// Does some text contain a line that starts a conflict?
+pub(crate) fn has_conflict_markers(contents: &[u8]) -> bool {
+    contents
+        .split(|&c| c == b'\n')
+        .any(|line| line.starts_with(START_MARKER))
+}
+
+// Writes a conflict marker, followed by the (shortened) ids of the patches that introduced some
+// lines.
+fn write_marker<W: Write>(
+    repo: &Repo,
+    marker: &[u8],
+    patches: &[PatchId],
+    w: &mut W,
+) -> Result<(), Error> {
+    w.write_all(marker)?;
+    for p in patches {
+        write!(w, " {}", repo.short_patch_id(p))?;
+    }
+    w.write_all(b"\n")?;
+    Ok(())
+}
+
+// Writes out a graggle, with conflict markers around the parts of it that aren't totally ordered.
+// If the graggle has binary contents, they are written out instead of its lines.
+pub(crate) fn render<W: Write>(repo: &Repo, graggle: Graggle<'_>, w: W) -> Result<(), Error> {
+    render_with(repo, graggle, |id| repo.contents(id), w)
+}
+
+// Like `render`, but takes the contents of the lines from `contents`, for graggles whose lines
+// aren't in the repository's storage.
+pub(crate) fn render_with<'c, W, F>(
+    repo: &Repo,
+    graggle: Graggle<'_>,
+    contents: F,
+    mut w: W,
+) -> Result<(), Error>
+where
+    W: Write,
+    F: Fn(&NodeId) -> &'c [u8],
+{
+    if let Some(blob) = graggle.binary() {
+        w.write_all(&repo.storage.blob(&blob.hash))?;
+        return Ok(());
+    }
+    let mut regions = regions(graggle).into_iter().peekable();
+    while let Some(region) = regions.next() {
+        match region {
+            Region::Line(id) => {
+                let line = contents(&id);
+                w.write_all(line)?;
+                // If the last line of a file doesn't end in a newline, it can still be followed
+                // by a conflict; the conflict markers need to be on lines of their own.
+                if !line.ends_with(b"\n") && regions.peek().is_some() {
+                    w.write_all(b"\n")?;
+                }
+            }
+            Region::Conflict(conflict) => {
+                for (i, alt) in conflict.alternatives.iter().enumerate() {
+                    let marker = if i == 0 {
+                        START_MARKER
+                    } else {
+                        SEPARATOR_MARKER
+                    };
+                    write_marker(repo, marker, &conflict.alternative_patches(i), &mut w)?;
+                    for id in alt {
+                        let line = contents(id);
+                        w.write_all(line)?;
+                        if !line.ends_with(b"\n") {
+                            w.write_all(b"\n")?;
+                        }
+                    }
+                }
+                w.write_all(END_MARKER)?;
+                w.write_all(b"\n")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::create;
+    use crate::{Change, Changes, Repo};
+
+    fn render(repo: &Repo, branch: &str) -> String {
+        let mut out = Vec::new();
+        repo.render(branch, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn ordered() {
+        let mut repo = Repo::init_tmp();
+        assert_eq!(render(&repo, "master"), "");
+        create(&mut repo, "master", b"a\nb\nc");
+        assert_eq!(render(&repo, "master"), "a\nb\nc");
+    }
+
+    #[test]
+    fn conflict() {
+        let mut repo = Repo::init_tmp();
+        create(&mut repo, "master", b"a\nb\n");
+        repo.clone_branch("master", "other").unwrap();
+        let ours = create(&mut repo, "master", b"a\nc\nd\nb\n");
+        let theirs = create(&mut repo, "other", b"a\ne\nb\n");
+        repo.apply_patch("master", &theirs).unwrap();
+
+        let ours = repo.short_patch_id(&ours);
+        let theirs = repo.short_patch_id(&theirs);
+        let rendered = render(&repo, "master");
+        let expected = |first: &str, second: &str, first_lines: &str, second_lines: &str| {
+            format!(
+                "a\n<<<<<<< {}\n{}======= {}\n{}>>>>>>>\nb\n",
+                first, first_lines, second, second_lines
+            )
+        };
+        assert!(
+            rendered == expected(&ours, &theirs, "c\nd\n", "e\n")
+                || rendered == expected(&theirs, &ours, "e\n", "c\nd\n"),
+            "unexpected rendering: {}",
+            rendered
+        );
+    }
+
+    #[test]
+    fn cycle() {
+        let mut repo = Repo::init_tmp();
+        create(&mut repo, "master", b"a\nb\nc\n");
+        let file = repo.file("master").unwrap();
+        let (a, b) = (*file.node_id(0), *file.node_id(1));
+        let changes = Changes {
+            changes: vec![Change::NewEdge { src: b, dest: a }],
+        };
+        let id = repo.create_patch("Author", "Msg", changes).unwrap();
+        repo.apply_patch("master", &id).unwrap();
+
+        let first = repo.short_patch_id(&a.patch);
+        assert_eq!(
+            render(&repo, "master"),
+            format!("<<<<<<< {}\na\nb\n>>>>>>>\nc\n", first)
+        );
+    }
+
+    #[test]
+    fn render_file() {
+        let mut repo = Repo::init_tmp();
+        let changes = Changes {
+            changes: vec![Change::NewFile {
+                path: "a.txt".to_owned(),
+            }],
+        };
+        let id = repo.create_patch("Author", "Msg", changes).unwrap();
+        repo.apply_patch("master", &id).unwrap();
+        let file = repo.file_id("master", "a.txt").unwrap();
+        let diff = repo.diff_file("master", "a.txt", b"a\n").unwrap();
+        let mut changes = Changes { changes: vec![] };
+        changes.add_file_changes(file, diff.changes());
+        let id = repo.create_patch("Author", "Msg", changes).unwrap();
+        repo.apply_patch("master", &id).unwrap();
+
+        let mut out = Vec::new();
+        repo.render_file("master", "a.txt", &mut out).unwrap();
+        assert_eq!(out, b"a\n");
+        assert!(repo.render_file("master", "b.txt", &mut out).is_err());
+    }
+}
@@ -271,6 +271,9 @@ impl<'a> OrderResolver<'a> {
     /// Assuming that the entire graggle has already been put in order, returns a [`Changes`] that,
     /// when applied to the graggle, will turn it from the original graggle into the linear order that
     /// we have just created (and which can be retrieved by [`OrderResolver::ordered_nodes`]).
+    ///
+    /// These changes only add edges and delete nodes, so they can be used to create a conflict
+    /// resolution patch (see [`Repo::create_resolution_patch`](crate::Repo::create_resolution_patch)).
     pub fn changes(&self) -> Changes {
         let mut changes = vec![];
 
@@ -352,11 +355,8 @@ mod tests {
         );
         let check = |init: u64, expected: Vec<u64>| {
             let actual =
-                ChainIter::new(graggle.as_graggle(), NodeId::cur(init)).collect::<Vec<_>>();
-            let expected = expected
-                .into_iter()
-                .map(|x| NodeId::cur(x))
-                .collect::<Vec<_>>();
+                ChainIter::new(graggle.as_graggle(), NodeId::staged(init)).collect::<Vec<_>>();
+            let expected = expected.into_iter().map(NodeId::staged).collect::<Vec<_>>();
             assert_eq!(actual, expected);
         };
         check(0, vec![0]);
@@ -379,10 +379,10 @@ mod tests {
         assert_eq!(res.candidates().count(), 1);
         assert_eq!(
             res.candidates().next().unwrap().iter().collect::<Vec<_>>(),
-            vec![NodeId::cur(0)]
+            vec![NodeId::staged(0)]
         );
 
-        res.choose(&NodeId::cur(0));
+        res.choose(&NodeId::staged(0));
         assert_eq!(res.candidates().count(), 2);
         assert_eq!(
             res.candidates()
@@ -390,34 +390,124 @@ mod tests {
                 .flatten()
                 .sorted()
                 .collect::<Vec<_>>(),
-            vec![NodeId::cur(1), NodeId::cur(2)]
+            vec![NodeId::staged(1), NodeId::staged(2)]
         );
 
-        res.choose(&NodeId::cur(1));
+        res.choose(&NodeId::staged(1));
         assert_eq!(res.candidates().count(), 1);
         assert_eq!(
             res.candidates().next().unwrap().iter().collect::<Vec<_>>(),
-            vec![NodeId::cur(2)]
+            vec![NodeId::staged(2)]
         );
 
-        res.choose(&NodeId::cur(2));
+        res.choose(&NodeId::staged(2));
         assert_eq!(res.candidates().count(), 1);
         assert_eq!(
             res.candidates().next().unwrap().iter().collect::<Vec<_>>(),
-            vec![NodeId::cur(3)]
+            vec![NodeId::staged(3)]
         );
 
-        res.choose(&NodeId::cur(3));
+        res.choose(&NodeId::staged(3));
         assert_eq!(res.candidates().count(), 0);
 
         assert_eq!(
             res.changes(),
             Changes {
                 changes: vec![Change::NewEdge {
-                    src: NodeId::cur(1),
-                    dest: NodeId::cur(2)
+                    src: NodeId::staged(1),
+                    dest: NodeId::staged(2)
                 }]
             }
         );
     }
+
+    #[test]
+    fn resolution_patch() {
+        use crate::{Error, PatchKind, Repo};
+
+        let mut repo = Repo::init_tmp();
+        let create = |repo: &mut Repo, branch: &str, contents: &[u8]| {
+            let diff = repo.diff(branch, contents).unwrap();
+            let id = repo.create_patch("Author", "Msg", diff.changes()).unwrap();
+            repo.apply_patch(branch, &id).unwrap();
+            id
+        };
+        create(&mut repo, "master", b"a\n");
+        repo.clone_branch("master", "other").unwrap();
+        create(&mut repo, "master", b"a\nb\n");
+        let theirs = create(&mut repo, "other", b"a\nc\n");
+        repo.apply_patch("master", &theirs).unwrap();
+        assert!(repo.file("master").is_err());
+
+        let changes = {
+            let graggle = repo.graggle("master").unwrap();
+            let mut res = CycleResolver::new(graggle).into_order_resolver();
+            while !res.is_finished() {
+                let next = res.candidates().next().unwrap().first();
+                res.choose(&next);
+            }
+            res.changes()
+        };
+        let id = repo
+            .create_resolution_patch("Author", "Resolve", changes)
+            .unwrap();
+        assert_eq!(repo.patch_header(&id).unwrap().kind, PatchKind::Resolution);
+        repo.apply_patch("master", &id).unwrap();
+        assert_eq!(repo.file("master").unwrap().num_nodes(), 3);
+
+        // Resolution patches can't add lines.
+        let changes = repo.diff("master", b"a\nb\nc\nd\n").unwrap().changes();
+        match repo.create_resolution_patch("Author", "Resolve", changes) {
+            Err(Error::InvalidResolution(_)) => {}
+            x => panic!("expected an error, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn ordering_patch() {
+        use crate::{Error, PatchKind, Repo};
+
+        let mut repo = Repo::init_tmp();
+        let create = |repo: &mut Repo, branch: &str, contents: &[u8]| {
+            let diff = repo.diff(branch, contents).unwrap();
+            let id = repo.create_patch("Author", "Msg", diff.changes()).unwrap();
+            repo.apply_patch(branch, &id).unwrap();
+            id
+        };
+        create(&mut repo, "master", b"a\n");
+        repo.clone_branch("master", "other").unwrap();
+        create(&mut repo, "master", b"a\nb\n");
+        let theirs = create(&mut repo, "other", b"a\nc\n");
+        repo.apply_patch("master", &theirs).unwrap();
+
+        // Put "c" before "b". The line "a" is already first, so that doesn't need an edge.
+        let graggle = repo.graggle("master").unwrap();
+        let line = |contents: &[u8]| {
+            graggle
+                .nodes()
+                .find(|n| repo.contents(n) == contents)
+                .unwrap()
+        };
+        let (a, b, c) = (line(b"a\n"), line(b"b\n"), line(b"c\n"));
+        let changes = repo.ordering_changes("master", &[a, c, b]).unwrap();
+        assert_eq!(changes.changes.len(), 1);
+        match repo.ordering_changes("master", &[b, a]) {
+            Err(Error::OrderConflict(x, y)) => assert_eq!((x, y), (b, a)),
+            x => panic!("expected an error, got {:?}", x),
+        }
+
+        let id = repo
+            .create_ordering_patch("Author", "Order", changes)
+            .unwrap();
+        assert_eq!(repo.patch_header(&id).unwrap().kind, PatchKind::Ordering);
+        repo.apply_patch("master", &id).unwrap();
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"a\nc\nb\n");
+
+        // Ordering patches can't delete lines.
+        let changes = repo.diff("master", b"a\nc\n").unwrap().changes();
+        match repo.create_ordering_patch("Author", "Order", changes) {
+            Err(Error::InvalidOrdering(_)) => {}
+            x => panic!("expected an error, got {:?}", x),
+        }
+    }
 }
@@ -0,0 +1,169 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+use crate::render::has_conflict_markers;
+use crate::text::read_working;
+use crate::{DiffOptions, Error, HunkId, Repo, DEFAULT_WORKING_FILE};
+
+/// Which changes in the working copy to throw away (see [`Repo::revert`](crate::Repo::revert)).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RevertScope {
+    /// All of the changes.
+    All,
+    /// Only the changes in these hunks (see
+    /// [`Repo::working_diff`](crate::Repo::working_diff)).
+    Hunks(Vec<HunkId>),
+}
+
+pub(crate) fn revert(
+    repo: &Repo,
+    branch: &str,
+    path: &str,
+    scope: &RevertScope,
+) -> Result<(), Error> {
+    let contents = match scope {
+        RevertScope::All => {
+            // The default working file holds the lines of the branch itself; every other path is
+            // a file on the branch.
+            let mut rendered = Vec::new();
+            if path == DEFAULT_WORKING_FILE {
+                repo.render(branch, &mut rendered)?;
+            } else {
+                repo.render_file(branch, path, &mut rendered)?;
+            }
+
+            // If the working copy has conflict markers, someone might be in the middle of
+            // resolving them, so we refuse to throw away their work. (If it has no local edits,
+            // there's nothing to lose.)
+            if let Ok(old) = read_working(repo, path) {
+                if has_conflict_markers(&old) && old != rendered {
+                    return Err(Error::EditedConflict(path.to_owned()));
+                }
+            }
+            rendered
+        }
+        RevertScope::Hunks(hunks) => {
+            let pending = repo.working_diff(branch, path, &DiffOptions::default())?;
+            pending.revert(hunks)?.as_bytes().to_owned()
+        }
+    };
+    let contents = repo.config().text_format(path).to_working(contents);
+    crate::working::write(repo, path, &contents)
+        .map_err(|e| Error::Io(e, format!("Could not write the file {}", path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::create;
+    use crate::{Change, Changes, RecordOptions};
+    use std::fs;
+
+    #[test]
+    fn revert_all() {
+        let mut repo = Repo::init_tmp_with_working_copy("revert-all");
+        create(&mut repo, "master", b"a\nb\n");
+        let path = repo.root_dir.join(DEFAULT_WORKING_FILE);
+
+        // The file doesn't even need to exist.
+        repo.revert("master", DEFAULT_WORKING_FILE, &RevertScope::All)
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"a\nb\n");
+
+        fs::write(&path, b"a\nc\n").unwrap();
+        repo.revert("master", DEFAULT_WORKING_FILE, &RevertScope::All)
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"a\nb\n");
+        fs::remove_dir_all(&repo.root_dir).unwrap();
+    }
+
+    #[test]
+    fn revert_hunks() {
+        let mut repo = Repo::init_tmp_with_working_copy("revert-hunks");
+        create(&mut repo, "master", b"a\nb\nc\nd\n");
+        let path = repo.root_dir.join(DEFAULT_WORKING_FILE);
+        fs::write(&path, b"A\nb\nc\nD\n").unwrap();
+
+        let pending = repo
+            .working_diff("master", DEFAULT_WORKING_FILE, &DiffOptions::default())
+            .unwrap();
+        let scope = RevertScope::Hunks(vec![pending.hunks()[1].id]);
+        repo.revert("master", DEFAULT_WORKING_FILE, &scope).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"A\nb\nc\nd\n");
+        fs::remove_dir_all(&repo.root_dir).unwrap();
+    }
+
+    #[test]
+    fn revert_tracked_file() {
+        let mut repo = Repo::init_tmp_with_working_copy("revert-tracked");
+        create(&mut repo, "master", b"own\n");
+        let own = repo.root_dir.join(DEFAULT_WORKING_FILE);
+        fs::write(&own, b"own\n").unwrap();
+        crate::working::write(&repo, "a.txt", b"a\nb\nc\nd\n").unwrap();
+        repo.track_file("a.txt").unwrap();
+        repo.record("Author", "Msg", &RecordOptions::default())
+            .unwrap();
+        let path = repo.root_dir.join("a.txt");
+        // The default working file has changes of its own, which have nothing to do with a.txt.
+        fs::write(&own, b"other\n").unwrap();
+
+        fs::write(&path, b"A\nb\nc\nD\n").unwrap();
+        let pending = repo
+            .working_diff("master", "a.txt", &DiffOptions::default())
+            .unwrap();
+        let scope = RevertScope::Hunks(vec![pending.hunks()[1].id]);
+        repo.revert("master", "a.txt", &scope).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"A\nb\nc\nd\n");
+
+        repo.revert("master", "a.txt", &RevertScope::All).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"a\nb\nc\nd\n");
+        assert_eq!(fs::read(&own).unwrap(), b"other\n");
+
+        match repo.revert("master", "unknown.txt", &RevertScope::All) {
+            Err(Error::UnknownFile(p)) => assert_eq!(p, "unknown.txt"),
+            x => panic!("unexpected result {:?}", x),
+        }
+        fs::remove_dir_all(&repo.root_dir).unwrap();
+    }
+
+    #[test]
+    fn edited_conflict() {
+        let mut repo = Repo::init_tmp_with_working_copy("edited-conflict");
+        create(&mut repo, "master", b"a\nb\n");
+        let file = repo.file("master").unwrap();
+        let (a, b) = (*file.node_id(0), *file.node_id(1));
+        let changes = Changes {
+            changes: vec![Change::NewEdge { src: b, dest: a }],
+        };
+        let id = repo.create_patch("Author", "Msg", changes).unwrap();
+        repo.apply_patch("master", &id).unwrap();
+
+        // Reverting writes out the conflict, and doing it again is fine.
+        let path = repo.root_dir.join(DEFAULT_WORKING_FILE);
+        repo.revert("master", DEFAULT_WORKING_FILE, &RevertScope::All)
+            .unwrap();
+        let rendered = fs::read(&path).unwrap();
+        assert!(has_conflict_markers(&rendered));
+        repo.revert("master", DEFAULT_WORKING_FILE, &RevertScope::All)
+            .unwrap();
+
+        // But once the conflict has been edited, reverting would lose work.
+        let mut edited = rendered.clone();
+        edited.extend_from_slice(b"c\n");
+        fs::write(&path, &edited).unwrap();
+        match repo.revert("master", DEFAULT_WORKING_FILE, &RevertScope::All) {
+            Err(Error::EditedConflict(_)) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+        assert_eq!(fs::read(&path).unwrap(), edited);
+        fs::remove_dir_all(&repo.root_dir).unwrap();
+    }
+}
@@ -0,0 +1,419 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Rolling back a patch: recording a new patch that undoes it. Unlike unapplying the patch, this
+// leaves the patch (and everything that depends on it) on the branch, so it also works for patches
+// that have already been published.
+//
+// Nothing ever really leaves a graggle, so the inverse of a patch has to be worked out against the
+// current state of the branch. Lines that the patch added get deleted. Lines that it deleted can't
+// be brought back to life, so they are added again as new lines with the same contents, between
+// the closest lines around them that are still live. In the same way, a file that the patch
+// deleted is created again, with the contents that it had when it was deleted. Anything that a
+// later patch has already changed again (a line that the patch added and that a later patch
+// deleted, say, or a file that was moved somewhere else) is left alone.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::storage::graggle::EdgeKind;
+use crate::tree::Files;
+use crate::{
+    BlobRef, Change, Changes, Error, FileRef, Graggle, NodeId, PatchHeader, PatchId, Repo,
+};
+
+// The key in the header of a rollback patch that records the patch that it rolls back.
+const ROLLBACK_KEY: &str = "rollback";
+
+// Returns the lines that a restored copy of `node` should be connected to: the closest lines before
+// it (or after it, if `forward` is true) that are either live or being restored. The restored
+// lines are returned with their new ids.
+fn closest(
+    graggle: Graggle,
+    restored: &BTreeMap<NodeId, NodeId>,
+    node: &NodeId,
+    forward: bool,
+) -> BTreeSet<NodeId> {
+    let mut ret = BTreeSet::new();
+    let mut seen = BTreeSet::new();
+    let mut stack = vec![*node];
+    while let Some(n) = stack.pop() {
+        let edges = if forward {
+            graggle.all_out_edges(&n).cloned().collect::<Vec<_>>()
+        } else {
+            graggle.all_in_edges(&n).cloned().collect::<Vec<_>>()
+        };
+        for e in edges.into_iter().filter(|e| e.kind != EdgeKind::Pseudo) {
+            if let Some(new_id) = restored.get(&e.dest) {
+                ret.insert(*new_id);
+            } else if graggle.is_live(&e.dest) {
+                ret.insert(e.dest);
+            } else if seen.insert(e.dest) {
+                stack.push(e.dest);
+            }
+        }
+    }
+    ret
+}
+
+// Returns the changes that undo `changes` (which the patch `patch` made to `graggle`), as far as
+// they are still in effect. The new lines are numbered from zero.
+fn invert_lines(
+    repo: &Repo,
+    graggle: Graggle,
+    patch: &PatchId,
+    changes: &[Change],
+) -> Result<Vec<Change>, Error> {
+    let mut ret = Vec::new();
+    // The deleted lines that are being added again, and the ids of their copies.
+    let mut restored = BTreeMap::new();
+    for ch in changes {
+        match *ch {
+            Change::NewNode { ref id, .. } => {
+                if graggle.has_node(id) && graggle.is_live(id) {
+                    ret.push(Change::DeleteNode { id: *id });
+                }
+            }
+            Change::DeleteNode { ref id } => {
+                if graggle.has_node(id) && !graggle.is_live(id) {
+                    let new_id = NodeId::staged(restored.len() as u64);
+                    restored.insert(*id, new_id);
+                    ret.push(Change::NewNode {
+                        id: new_id,
+                        contents: repo.contents(id).to_owned(),
+                    });
+                }
+            }
+            Change::BinaryReplace { .. } | Change::ChunkedReplace { .. } => {
+                let old = match *ch {
+                    Change::BinaryReplace { ref old, .. } => old.as_ref(),
+                    Change::ChunkedReplace { ref old, .. } => Some(old),
+                    _ => unreachable!(),
+                };
+                let new = ch.new_blob_hash().map(|hash| BlobRef {
+                    patch: *patch,
+                    hash,
+                });
+                // Binary contents can't be removed, only replaced; if there weren't any before,
+                // there's nothing to go back to.
+                if let Some(old) = old {
+                    if let Some(current) = graggle.binary().filter(|b| Some(**b) == new) {
+                        let contents = repo.blob_contents(old)?.into_owned();
+                        ret.push(repo.binary_replace(Some(current), contents));
+                    }
+                }
+            }
+            Change::NewEdge { .. }
+            | Change::NewFile { .. }
+            | Change::DeleteFile { .. }
+            | Change::MoveFile { .. }
+            | Change::SetExecutable { .. }
+            | Change::SetSymlink { .. }
+            | Change::EditFile { .. } => {}
+        }
+    }
+
+    let mut edges = BTreeSet::new();
+    for (old_id, new_id) in &restored {
+        for prev in closest(graggle, &restored, old_id, false) {
+            edges.insert((prev, *new_id));
+        }
+        for next in closest(graggle, &restored, old_id, true) {
+            edges.insert((*new_id, next));
+        }
+    }
+    ret.extend(
+        edges
+            .into_iter()
+            .map(|(src, dest)| Change::NewEdge { src, dest }),
+    );
+    Ok(ret)
+}
+
+// Returns the changes that give a new file a copy of the live lines (or the binary contents) of
+// `graggle`.
+fn copy_lines(repo: &Repo, graggle: Graggle) -> Result<Changes, Error> {
+    if let Some(blob) = graggle.binary() {
+        let new_blob = repo.blob_contents(blob)?.into_owned();
+        return Ok(Changes {
+            changes: vec![Change::BinaryReplace {
+                old: None,
+                new_blob,
+            }],
+        });
+    }
+
+    let ids = graggle
+        .nodes()
+        .enumerate()
+        .map(|(i, id)| (id, NodeId::staged(i as u64)))
+        .collect::<BTreeMap<_, _>>();
+    let mut changes = Vec::new();
+    for (old_id, new_id) in &ids {
+        changes.push(Change::NewNode {
+            id: *new_id,
+            contents: repo.contents(old_id).to_owned(),
+        });
+    }
+    for (old_id, new_id) in &ids {
+        // The pseudo-edges count too, because they carry the order of the lines on either side of
+        // the deleted ones.
+        for next in graggle.out_neighbors(old_id) {
+            changes.push(Change::NewEdge {
+                src: *new_id,
+                dest: ids[next],
+            });
+        }
+    }
+    Ok(Changes { changes })
+}
+
+// Returns the current path of the file whose identity is `id`, if it still exists.
+fn current_path(files: &Files, id: &FileRef) -> Option<String> {
+    files
+        .paths()
+        .find(|path| files.id(path).as_ref() == Some(id))
+        .map(|path| path.to_owned())
+}
+
+// Returns the changes that undo the patch `patch` on a branch.
+fn changes(repo: &Repo, branch: &str, patch: &PatchId) -> Result<Changes, Error> {
+    if !repo.storage.branch_patches.contains(branch, patch) {
+        return Err(Error::NotOnBranch(*patch, branch.to_owned()));
+    }
+    let data = repo.storage.graggle_data(repo.inode(branch)?);
+    let files = data.files();
+    let p = repo.open_patch(patch)?;
+    let p_changes = &p.changes().changes;
+
+    // Is the file at `path` still where this patch put it, and not about to be deleted because
+    // this patch created it?
+    let untouched = |path: &str| {
+        files.get(path).map(|state| state.patch) == Some(*patch)
+            && files.id(path).map(|id| id.patch) != Some(*patch)
+    };
+
+    let mut ret = Changes {
+        changes: invert_lines(repo, data.as_graggle(), patch, p_changes)?,
+    };
+    let mut file_changes = Vec::new();
+    for ch in p_changes {
+        match *ch {
+            Change::NewFile { ref path } => {
+                let id = FileRef {
+                    patch: *patch,
+                    path: path.clone(),
+                };
+                if let Some(path) = current_path(files, &id) {
+                    ret.changes.push(Change::DeleteFile {
+                        file: repo.file_ref(branch, &path)?,
+                    });
+                }
+            }
+            Change::DeleteFile { ref file } => {
+                let state = match files.deleted(patch, &file.path) {
+                    Some(state) if files.get(&file.path).is_none() => state,
+                    _ => continue,
+                };
+                let id = state.created.clone().unwrap_or_else(|| file.clone());
+                let new_file = FileRef {
+                    patch: PatchId::staging(),
+                    path: file.path.clone(),
+                };
+                ret.changes.push(Change::NewFile {
+                    path: file.path.clone(),
+                });
+                if state.executable {
+                    ret.changes.push(Change::SetExecutable {
+                        file: new_file.clone(),
+                        executable: true,
+                    });
+                }
+                if state.symlink {
+                    ret.changes.push(Change::SetSymlink {
+                        file: new_file.clone(),
+                        symlink: true,
+                    });
+                }
+                if let Some(graggle) = data.file_graggle(&id) {
+                    file_changes.push((new_file, copy_lines(repo, graggle.as_graggle())?));
+                }
+            }
+            Change::MoveFile { ref from, ref to }
+                if untouched(to) && files.get(&from.path).is_none() =>
+            {
+                ret.changes.push(Change::MoveFile {
+                    from: FileRef {
+                        patch: *patch,
+                        path: to.clone(),
+                    },
+                    to: from.path.clone(),
+                });
+            }
+            Change::SetExecutable {
+                ref file,
+                executable,
+            } if untouched(&file.path)
+                && files.get(&file.path).map(|s| s.executable) == Some(executable) =>
+            {
+                ret.changes.push(Change::SetExecutable {
+                    file: FileRef {
+                        patch: *patch,
+                        path: file.path.clone(),
+                    },
+                    executable: !executable,
+                });
+            }
+            Change::SetSymlink { ref file, symlink }
+                if untouched(&file.path)
+                    && files.get(&file.path).map(|s| s.symlink) == Some(symlink) =>
+            {
+                ret.changes.push(Change::SetSymlink {
+                    file: FileRef {
+                        patch: *patch,
+                        path: file.path.clone(),
+                    },
+                    symlink: !symlink,
+                });
+            }
+            Change::EditFile {
+                ref file,
+                ref changes,
+            } => {
+                // The lines of a file that this patch created go away along with the file.
+                if file.patch == *patch || current_path(files, file).is_none() {
+                    continue;
+                }
+                if let Some(graggle) = data.file_graggle(file) {
+                    let inverse =
+                        invert_lines(repo, graggle.as_graggle(), patch, &changes.changes)?;
+                    if !inverse.is_empty() {
+                        file_changes.push((file.clone(), Changes { changes: inverse }));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    for (file, c) in file_changes {
+        ret.add_file_changes(file, c);
+    }
+    Ok(ret)
+}
+
+pub(crate) fn rollback(
+    repo: &mut Repo,
+    branch: &str,
+    patch: &PatchId,
+    author: &str,
+    msg: &str,
+) -> Result<PatchId, Error> {
+    let changes = changes(repo, branch, patch)?;
+    if changes.changes.is_empty() {
+        return Err(Error::NoChanges);
+    }
+    let mut header = PatchHeader::new(author.to_owned(), msg.to_owned());
+    header
+        .extra
+        .insert(ROLLBACK_KEY.to_owned(), patch.to_base64());
+    repo.transaction(|repo| {
+        let id = repo.create_patch_with_header(header, changes)?;
+        repo.apply_patch(branch, &id)?;
+        Ok(id)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemoryWorkingCopy, RecordOptions, DEFAULT_WORKING_FILE};
+
+    fn record(repo: &mut Repo) -> PatchId {
+        repo.record("Author", "Msg", &RecordOptions::default())
+            .unwrap()
+    }
+
+    fn contents(repo: &Repo) -> Vec<u8> {
+        repo.file("master").unwrap().as_bytes().to_owned()
+    }
+
+    #[test]
+    fn lines() {
+        let wc = MemoryWorkingCopy::new();
+        let mut repo = Repo::init_in_memory(wc.clone());
+        wc.write(DEFAULT_WORKING_FILE, b"a\nb\nc\nd\n");
+        let p1 = record(&mut repo);
+        wc.write(DEFAULT_WORKING_FILE, b"a\nx\nd\ny\n");
+        let p2 = record(&mut repo);
+
+        let r = repo.rollback("master", &p2, "Author", "Undo").unwrap();
+        assert_eq!(contents(&repo), b"a\nb\nc\nd\n");
+        // The rolled back patch stays on the branch, and the working copy isn't touched.
+        assert!(repo.patches("master").any(|p| *p == p2));
+        assert_eq!(wc.read(DEFAULT_WORKING_FILE).unwrap(), b"a\nx\nd\ny\n");
+        let header = repo.open_patch(&r).unwrap().header().clone();
+        assert_eq!(header.extra[ROLLBACK_KEY], p2.to_base64());
+
+        // Rolling back the rollback brings back the changes.
+        repo.rollback("master", &r, "Author", "Redo").unwrap();
+        assert_eq!(contents(&repo), b"a\nx\nd\ny\n");
+
+        // The lines that p1 added and that are still there get deleted; the others were already
+        // deleted by p2.
+        repo.rollback("master", &p1, "Author", "Undo").unwrap();
+        assert_eq!(contents(&repo), b"x\ny\n");
+        match repo.rollback("master", &p1, "Author", "Undo") {
+            Err(Error::NoChanges) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn files() {
+        let wc = MemoryWorkingCopy::new();
+        let mut repo = Repo::init_in_memory(wc.clone());
+        wc.write("a.txt", b"a\n");
+        wc.write("b.txt", b"b\n");
+        repo.track_file("a.txt").unwrap();
+        repo.track_file("b.txt").unwrap();
+        record(&mut repo);
+
+        repo.move_file("a.txt", "c.txt").unwrap();
+        wc.write("c.txt", b"a\nc\n");
+        repo.untrack_file("b.txt").unwrap();
+        wc.write("d.txt", b"d\n");
+        repo.track_file("d.txt").unwrap();
+        let p = record(&mut repo);
+
+        repo.rollback("master", &p, "Author", "Undo").unwrap();
+        assert_eq!(
+            repo.files("master").unwrap().collect::<Vec<_>>(),
+            vec!["a.txt", "b.txt"]
+        );
+        assert_eq!(repo.file_at("master", "a.txt").unwrap().as_bytes(), b"a\n");
+        assert_eq!(repo.file_at("master", "b.txt").unwrap().as_bytes(), b"b\n");
+    }
+
+    #[test]
+    fn not_on_branch() {
+        let wc = MemoryWorkingCopy::new();
+        let mut repo = Repo::init_in_memory(wc.clone());
+        wc.write(DEFAULT_WORKING_FILE, b"a\n");
+        let p = record(&mut repo);
+        repo.create_branch("other").unwrap();
+        match repo.rollback("other", &p, "Author", "Undo") {
+            Err(Error::NotOnBranch(id, branch)) => {
+                assert_eq!(id, p);
+                assert_eq!(branch, "other");
+            }
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+}
@@ -0,0 +1,190 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Shallow clones, which only have the data of their most recent patches.
+//
+// The other patches are "ghosts": we know their ids, their dependencies and their metadata, and
+// their lines are in the graggles (which are copied directly, rather than being built by applying
+// the patches). Since most operations only look at the graggles, ghosts only need to be
+// downloaded (from the repository that we were cloned from) when something wants to read them.
+
+use std::cmp::Reverse;
+
+use crate::patch::Patch;
+use crate::remote;
+use crate::{Error, PatchId, Repo};
+
+// Copies `branch` from `src` into `dst`, which should be empty. Only the `depth` most recent
+// patches on the branch are copied; the others become ghosts, to be fetched from `origin`.
+pub(crate) fn copy(
+    src: &Repo,
+    dst: &mut Repo,
+    branch: &str,
+    depth: usize,
+    origin: String,
+) -> Result<(), Error> {
+    let src_inode = src.inode(branch)?;
+    let mut ids = src.patches(branch).cloned().collect::<Vec<_>>();
+    ids.sort_by_key(|id| Reverse(src.patch_header(id).ok().map(|h| h.timestamp)));
+
+    for (i, id) in ids.iter().enumerate() {
+        for dep in src.patch_deps(id) {
+            dst.storage.patch_deps.insert(*id, *dep);
+            dst.storage.patch_rev_deps.insert(*dep, *id);
+        }
+        dst.storage
+            .patch_index
+            .copy_from(&src.storage.patch_index, *id);
+        // If `src` is a shallow clone itself, some of its patches might be ghosts already.
+        match src.storage.patches.get(id) {
+            Some(data) if i < depth => {
+                Patch::from_reader_with_id(data.as_bytes(), id)?;
+                dst.storage.patches.insert(*id, data.clone());
+            }
+            _ => {
+                dst.storage.ghosts.insert(*id);
+            }
+        }
+        dst.storage.published.insert(*id);
+        dst.storage.branch_patches.insert(branch.to_owned(), *id);
+    }
+
+    if dst.storage.inode(branch).is_none() {
+        dst.create_branch(branch)?;
+    }
+    let inode = dst.inode(branch)?;
+    dst.storage.copy_graggle(inode, &src.storage, src_inode);
+    dst.storage.update_cache(inode);
+
+    dst.config = src.config.clone();
+    dst.dictionary = src.dictionary.clone();
+    dst.storage.origin = Some(origin);
+    dst.current_branch = branch.to_owned();
+    if branch != "master" {
+        dst.delete_branch("master")?;
+    }
+    Ok(())
+}
+
+// Stores the data of a ghost, after checking that it matches the ghost's id. The patch was
+// checked for validity when it was registered in the original repository, and we already have
+// everything that registering it would record.
+fn fill(repo: &mut Repo, id: PatchId, data: Vec<u8>) -> Result<(), Error> {
+    Patch::from_reader_with_id(data.as_slice(), &id)?;
+    let data = String::from_utf8(data)?;
+    repo.storage.ghosts.remove(&id);
+    repo.storage.patches.insert(id, data);
+    Ok(())
+}
+
+// Downloads the ghosts among `ids` from the repository that this one was cloned from, and returns
+// the ones that were downloaded.
+pub(crate) fn fetch(repo: &mut Repo, ids: &[PatchId]) -> Result<Vec<PatchId>, Error> {
+    let mut wanted = ids
+        .iter()
+        .filter(|id| repo.storage.ghosts.contains(id))
+        .cloned()
+        .collect::<Vec<_>>();
+    wanted.sort();
+    wanted.dedup();
+    let origin = match (wanted.first(), &repo.storage.origin) {
+        (None, _) => return Ok(wanted),
+        (Some(id), None) => return Err(Error::GhostPatch(*id)),
+        (Some(_), Some(origin)) => origin.clone(),
+    };
+
+    let mut conn = remote::connect(&origin)?;
+    conn.fetch(wanted.clone(), |id, data| fill(repo, id, data))?;
+    conn.close()?;
+    Ok(wanted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{create, tmp_dir};
+    use std::fs;
+
+    // Creates a repository on disk with three patches on "master", and returns it along with the
+    // patches (oldest first).
+    fn setup(name: &str) -> (Repo, Vec<PatchId>) {
+        let path = tmp_dir(&format!("shallow-{}-src", name));
+        let mut src = Repo::init(&path).unwrap();
+        let mut ids = Vec::new();
+        for contents in &[&b"a\n"[..], b"a\nb\n", b"a\nb\nc\n"] {
+            ids.push(create(&mut src, "master", contents));
+            // Make sure that the patches have different timestamps.
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+        src.write().unwrap();
+        (src, ids)
+    }
+
+    #[test]
+    fn shallow_clone() {
+        let (src, ids) = setup("clone");
+        let dst = tmp_dir("shallow-clone-dst");
+        let repo = Repo::clone_shallow(&src.root_dir, &dst, "master", 1).unwrap();
+        assert_eq!(repo.ghosts().cloned().collect::<Vec<_>>(), {
+            let mut old = ids[..2].to_vec();
+            old.sort();
+            old
+        });
+        assert!(!repo.is_ghost(&ids[2]));
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"a\nb\nc\n");
+        assert_eq!(repo.patches("master").count(), 3);
+        assert_eq!(repo.patch_header(&ids[0]).unwrap().author, "Author");
+        match repo.open_patch(&ids[0]) {
+            Err(Error::GhostPatch(id)) => assert_eq!(id, ids[0]),
+            x => panic!("unexpected result {:?}", x),
+        }
+
+        // The ghosts survive writing and reopening.
+        let mut repo = Repo::open(&repo.root_dir).unwrap();
+        assert_eq!(repo.ghosts().count(), 2);
+
+        // Recording a change to the line from the first patch needs that patch, but not the
+        // second one.
+        let diff = repo.diff("master", b"b\nc\n").unwrap();
+        let id = repo.create_patch("Author", "Msg", diff.changes()).unwrap();
+        repo.apply_patch("master", &id).unwrap();
+        assert_eq!(repo.ghosts().collect::<Vec<_>>(), vec![&ids[1]]);
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"b\nc\n");
+
+        // Unapplying a ghost downloads it first.
+        repo.unapply_patch("master", &ids[1]).unwrap();
+        assert_eq!(repo.ghosts().count(), 0);
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"");
+
+        fs::remove_dir_all(&src.root_dir).unwrap();
+        fs::remove_dir_all(&dst).unwrap();
+    }
+
+    #[test]
+    fn unshallow() {
+        let (src, ids) = setup("unshallow");
+        let dst = tmp_dir("shallow-unshallow-dst");
+        let mut repo = Repo::clone_shallow(&src.root_dir, &dst, "master", 0).unwrap();
+        assert_eq!(repo.ghosts().count(), 3);
+        assert_eq!(repo.unshallow().unwrap().len(), 3);
+        assert_eq!(repo.ghosts().count(), 0);
+        for id in &ids {
+            assert!(repo.open_patch(id).is_ok());
+        }
+
+        // Without the original repository, the ghosts can't be fetched.
+        let mut repo = Repo::clone_shallow(&src.root_dir, dst.join("x"), "master", 0).unwrap();
+        fs::remove_dir_all(&src.root_dir).unwrap();
+        assert!(repo.unshallow().is_err());
+        assert_eq!(repo.ghosts().count(), 3);
+        fs::remove_dir_all(&dst).unwrap();
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// The lines that a patch introduces are identified by the patch's id, but the id is a hash of the
+// patch's contents, so it isn't known until the patch is finished. Until then, the new lines live
+// in the staging namespace (see `PatchId::staging`). The numbers of staged lines can be chosen
+// freely while a patch is being put together (for example, by combining changes to several files
+// or by dropping some changes), so finishing a patch takes two steps: first its staged lines are
+// renumbered consecutively (see `Renumbering`), and then the patch is hashed and the staging id is
+// replaced by the real one.
+
+use std::collections::BTreeMap;
+
+use crate::{Change, Changes, NodeId, PatchId};
+
+/// A renumbering of the staged lines (see [`PatchId::staging`]) of a patch that is being finished.
+///
+/// The lines that a finished patch introduces are numbered consecutively, starting from zero, in
+/// the same relative order that they had while they were staged. This is done automatically by
+/// [`UnidentifiedPatch::with_header`](crate::UnidentifiedPatch::with_header); a `Renumbering`
+/// can be used to find out what happened to any other references to the staged lines.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Renumbering {
+    // Maps the old number of each staged line to its new number.
+    map: BTreeMap<u64, u64>,
+}
+
+impl Renumbering {
+    /// Computes the renumbering of the staged lines that are introduced by some changes.
+    pub fn new(changes: &Changes) -> Renumbering {
+        let mut staged = changes
+            .flattened()
+            .filter_map(|(_, ch)| match ch {
+                Change::NewNode { id, .. } if id.is_staged() => Some(id.node),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        staged.sort();
+        staged.dedup();
+        Renumbering {
+            map: staged
+                .into_iter()
+                .enumerate()
+                .map(|(new, old)| (old, new as u64))
+                .collect(),
+        }
+    }
+
+    /// Returns the new number of a staged line, or `None` if the line isn't introduced by the
+    /// changes that this renumbering was computed from.
+    pub fn get(&self, node: u64) -> Option<u64> {
+        self.map.get(&node).cloned()
+    }
+
+    /// Does this renumbering leave every line where it was?
+    pub fn is_identity(&self) -> bool {
+        self.map.iter().all(|(old, new)| old == new)
+    }
+
+    /// Returns the id that a line will have once its patch is finished and has the id `patch`.
+    ///
+    /// Lines that aren't staged, and staged lines that aren't introduced by the changes that this
+    /// renumbering was computed from, are left alone.
+    pub fn finalize(&self, id: &NodeId, patch: &PatchId) -> NodeId {
+        match self.get(id.node) {
+            Some(node) if id.is_staged() => NodeId {
+                patch: *patch,
+                node,
+            },
+            _ => *id,
+        }
+    }
+
+    // Renumbers all the staged lines in some changes. They stay in the staging namespace.
+    pub(crate) fn apply(&self, changes: &mut Changes) {
+        for ch in &mut changes.changes {
+            for id in ch.node_ids_mut() {
+                *id = self.finalize(id, &PatchId::staging());
+            }
+        }
+    }
+
+    // Renumbers the keys of a map that is indexed by staged lines (like
+    // `PatchHeader::line_authors`).
+    pub(crate) fn apply_to_keys<T>(&self, map: BTreeMap<u64, T>) -> BTreeMap<u64, T> {
+        map.into_iter()
+            .map(|(node, val)| (self.get(node).unwrap_or(node), val))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PatchHeader, Repo, UnidentifiedPatch};
+
+    fn new_node(node: u64) -> Change {
+        Change::NewNode {
+            id: NodeId::staged(node),
+            contents: vec![b'a' + node as u8, b'\n'],
+        }
+    }
+
+    #[test]
+    fn renumber() {
+        let changes = Changes {
+            changes: vec![
+                new_node(5),
+                new_node(2),
+                Change::NewEdge {
+                    src: NodeId::staged(2),
+                    dest: NodeId::staged(5),
+                },
+                Change::NewEdge {
+                    src: NodeId::staged(5),
+                    dest: NodeId::staged(7),
+                },
+            ],
+        };
+        let renumbering = Renumbering::new(&changes);
+        assert!(!renumbering.is_identity());
+        assert_eq!(renumbering.get(2), Some(0));
+        assert_eq!(renumbering.get(5), Some(1));
+        // Node 7 isn't introduced by the changes, so it can't be renumbered.
+        assert_eq!(renumbering.get(7), None);
+
+        let id = PatchId::from_base64("PAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=").unwrap();
+        let finalized = renumbering.finalize(&NodeId::staged(5), &id);
+        assert_eq!(finalized, NodeId { patch: id, node: 1 });
+        assert_eq!(renumbering.finalize(&finalized, &id), finalized);
+        assert_eq!(
+            renumbering.finalize(&NodeId::staged(7), &id),
+            NodeId::staged(7)
+        );
+
+        let mut renumbered = changes.clone();
+        renumbering.apply(&mut renumbered);
+        assert!(Renumbering::new(&renumbered).is_identity());
+        assert_eq!(
+            renumbered.changes[0],
+            Change::NewNode {
+                id: NodeId::staged(1),
+                contents: b"f\n".to_vec(),
+            }
+        );
+    }
+
+    #[test]
+    fn finished_patch() {
+        let mut repo = Repo::init_tmp();
+        let changes = Changes {
+            changes: vec![
+                new_node(3),
+                new_node(8),
+                Change::NewEdge {
+                    src: NodeId::staged(3),
+                    dest: NodeId::staged(8),
+                },
+            ],
+        };
+        let mut header = PatchHeader::new("Author".to_owned(), "Msg".to_owned());
+        header.line_authors.insert(8, "Other".to_owned());
+        let up = UnidentifiedPatch::with_header(header.clone(), changes.clone());
+        let id = repo.create_patch_with_header(header, changes).unwrap();
+        assert_eq!(up.id().unwrap(), id);
+        repo.apply_patch("master", &id).unwrap();
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"d\ni\n");
+
+        let second = NodeId { patch: id, node: 1 };
+        assert_eq!(repo.line_author(&second).unwrap(), "Other");
+        assert_eq!(
+            repo.line_author(&NodeId { patch: id, node: 0 }).unwrap(),
+            "Author"
+        );
+    }
+}
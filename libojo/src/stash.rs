@@ -0,0 +1,203 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Stashing: putting the changes in the working copy aside as a patch that isn't on any branch,
+// and bringing them back later.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::tracked::{self, TrackedFiles};
+use crate::{
+    ApplyPolicy, Conflict, Error, PatchHeader, PatchId, RecordOptions, Repo, DEFAULT_WORKING_FILE,
+};
+
+// The metadata key that holds the branch that a stash was made on.
+const STASH_KEY: &str = "stash";
+
+// The name of the scratch branch that stashed changes are applied to. Branch names can't normally
+// contain NUL characters, so this can't clash with a real branch.
+const SCRATCH_BRANCH: &str = "\0stash";
+
+/// The result of bringing back stashed changes (see [`Repo::stash_pop`]).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StashReport {
+    /// The patch that held the changes.
+    pub id: PatchId,
+    /// The patches whose changes were brought into the working copy, in the order that they were
+    /// applied. Besides the stashed patch, these are the patches that it depends on and that
+    /// weren't on the current branch.
+    pub applied: Vec<PatchId>,
+    /// The conflicts in the lines of the branch, with the changes brought back.
+    pub conflicts: Vec<Conflict>,
+    /// The conflicts in each file, with the changes brought back. Files without conflicts are
+    /// omitted.
+    pub file_conflicts: BTreeMap<String, Vec<Conflict>>,
+}
+
+impl StashReport {
+    /// Does the working copy have any conflicts after bringing the changes back?
+    pub fn has_conflicts(&self) -> bool {
+        !self.conflicts.is_empty() || !self.file_conflicts.is_empty()
+    }
+}
+
+pub(crate) fn push(repo: &mut Repo, msg: &str) -> Result<PatchId, Error> {
+    let branch = repo.current_branch.clone();
+    let config = repo.config();
+    let opts = RecordOptions {
+        diff: config.diff_options(),
+        ..RecordOptions::default()
+    };
+    let changes = repo.working_changes(&branch, &opts)?;
+
+    let author = config.author().unwrap_or_default();
+    let mut header = PatchHeader::new(author.to_owned(), msg.to_owned());
+    header.email = config.email;
+    header.extra.insert(STASH_KEY.to_owned(), branch.clone());
+    let id = repo.create_patch_with_header(header, changes)?;
+
+    let mut old_paths = tracked::tracked(repo)?.into_keys().collect::<BTreeSet<_>>();
+    old_paths.insert(DEFAULT_WORKING_FILE.to_owned());
+    tracked::write_branch(repo, &branch, &old_paths)?;
+    repo.storage.tracked = TrackedFiles::default();
+    repo.storage.stashes.push(id);
+    Ok(id)
+}
+
+// Applies the stash to the scratch branch (which starts out as a copy of the current branch), and
+// makes the working copy match it.
+fn restore(repo: &mut Repo, id: &PatchId) -> Result<StashReport, Error> {
+    let applied = repo.apply_without_hooks(SCRATCH_BRANCH, id, ApplyPolicy::Cascade)?;
+    tracked::check_clean(repo, SCRATCH_BRANCH)?;
+
+    let branch = repo.current_branch.clone();
+    let changed = tracked::changed_files(repo, &branch, SCRATCH_BRANCH)?;
+    tracked::write_branch(repo, SCRATCH_BRANCH, &tracked::branch_paths(repo, &branch)?)?;
+    // The stashed changes aren't on the current branch, so the files that have them aren't up to
+    // date with it.
+    tracked::refresh_rendered(repo)?;
+    repo.storage.tracked = changed;
+
+    let (conflicts, file_conflicts) = crate::merge::conflicts(repo, SCRATCH_BRANCH)?;
+    Ok(StashReport {
+        id: *id,
+        applied,
+        conflicts,
+        file_conflicts,
+    })
+}
+
+pub(crate) fn pop(repo: &mut Repo) -> Result<StashReport, Error> {
+    let id = *repo.storage.stashes.last().ok_or(Error::NoStash)?;
+    let branch = repo.current_branch.clone();
+    repo.clone_branch(&branch, SCRATCH_BRANCH)?;
+    let ret = restore(repo, &id);
+    repo.delete_branch(SCRATCH_BRANCH)?;
+    if ret.is_ok() {
+        repo.storage.stashes.pop();
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FileStatus;
+    use std::fs;
+
+    fn write(repo: &Repo, path: &str, contents: &[u8]) {
+        fs::write(repo.root_dir.join(path), contents).unwrap();
+    }
+
+    fn read(repo: &Repo, path: &str) -> Vec<u8> {
+        fs::read(repo.root_dir.join(path)).unwrap()
+    }
+
+    fn record(repo: &mut Repo) -> PatchId {
+        repo.record("Author", "Msg", &RecordOptions::default())
+            .unwrap()
+    }
+
+    #[test]
+    fn push_and_pop() {
+        let mut repo = Repo::init_tmp_with_working_copy("stash");
+        write(&repo, DEFAULT_WORKING_FILE, b"a\nb\n");
+        write(&repo, "old.txt", b"old\n");
+        repo.track_file("old.txt").unwrap();
+        let base = record(&mut repo);
+
+        write(&repo, DEFAULT_WORKING_FILE, b"a\nc\n");
+        write(&repo, "new.txt", b"new\n");
+        repo.track_file("new.txt").unwrap();
+        let id = repo.stash_push("Work in progress").unwrap();
+        assert_eq!(repo.stashes().collect::<Vec<_>>(), vec![&id]);
+        assert_eq!(
+            repo.patch_header(&id).unwrap().description,
+            "Work in progress"
+        );
+        assert_eq!(repo.patch_deps(&id).collect::<Vec<_>>(), vec![&base]);
+        assert_eq!(read(&repo, DEFAULT_WORKING_FILE), b"a\nb\n");
+        assert!(!repo.root_dir.join("new.txt").exists());
+        assert!(repo
+            .status()
+            .unwrap()
+            .values()
+            .all(|s| *s == FileStatus::Clean));
+        assert_eq!(repo.patches("master").collect::<Vec<_>>(), vec![&base]);
+        assert!(repo.gc(std::time::Duration::from_secs(0), true).is_empty());
+
+        let report = repo.stash_pop().unwrap();
+        assert_eq!(report.id, id);
+        assert_eq!(report.applied, vec![id]);
+        assert!(!report.has_conflicts());
+        assert_eq!(read(&repo, DEFAULT_WORKING_FILE), b"a\nc\n");
+        assert_eq!(read(&repo, "new.txt"), b"new\n");
+        assert_eq!(repo.status().unwrap()["new.txt"], FileStatus::Added);
+        assert_eq!(repo.patches("master").collect::<Vec<_>>(), vec![&base]);
+        assert_eq!(repo.stashes().count(), 0);
+        assert_eq!(repo.branches().collect::<Vec<_>>(), vec!["master"]);
+
+        match repo.stash_pop() {
+            Err(Error::NoStash) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+        fs::remove_dir_all(&repo.root_dir).unwrap();
+    }
+
+    #[test]
+    fn pop_with_conflicts() {
+        let mut repo = Repo::init_tmp_with_working_copy("stash-conflicts");
+        write(&repo, DEFAULT_WORKING_FILE, b"a\nb\n");
+        record(&mut repo);
+        write(&repo, DEFAULT_WORKING_FILE, b"a\nc\nb\n");
+        repo.stash_push("Mine").unwrap();
+        write(&repo, DEFAULT_WORKING_FILE, b"a\nd\nb\n");
+        record(&mut repo);
+
+        // Changes that haven't been recorded get in the way.
+        write(&repo, DEFAULT_WORKING_FILE, b"a\n");
+        match repo.stash_pop() {
+            Err(Error::UnrecordedChanges(_)) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+        assert_eq!(repo.stashes().count(), 1);
+
+        write(&repo, DEFAULT_WORKING_FILE, b"a\nd\nb\n");
+        let report = repo.stash_pop().unwrap();
+        assert_eq!(report.conflicts.len(), 1);
+        assert!(crate::render::has_conflict_markers(&read(
+            &repo,
+            DEFAULT_WORKING_FILE
+        )));
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"a\nd\nb\n");
+        fs::remove_dir_all(&repo.root_dir).unwrap();
+    }
+}
@@ -0,0 +1,203 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+use std::collections::BTreeMap;
+
+use crate::ignore::working_files;
+use crate::text::read_working;
+use crate::tracked::tracked;
+use crate::{Error, Graggle, Repo, DEFAULT_WORKING_FILE};
+
+/// How a file in the working copy compares to the current branch (see [`Repo::status`]).
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum FileStatus {
+    /// The file in the working copy matches the branch.
+    Clean,
+    /// The file in the working copy differs from the branch.
+    Modified,
+    /// The file's lines on the branch aren't ordered; this is the number of conflicts (see
+    /// [`Graggle::conflicts`]).
+    Conflicted(usize),
+    /// The branch has the file, but the working copy doesn't.
+    Missing,
+    /// The working copy has the file, but the branch doesn't.
+    Untracked,
+    /// The file was added to the tracked files (see [`Repo::track_file`]), but it isn't on the
+    /// branch yet.
+    Added,
+    /// The file is on the branch, but it was removed from the tracked files (see
+    /// [`Repo::untrack_file`]).
+    Removed,
+}
+
+// Compares some lines on a branch with the file at `path` in the working copy.
+fn file_status(repo: &Repo, graggle: Graggle<'_>, path: &str) -> Result<FileStatus, Error> {
+    let contents = match read_working(repo, path) {
+        Ok(contents) => contents,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(FileStatus::Missing),
+        Err(e) => return Err(Error::Io(e, format!("Could not read the file {}", path))),
+    };
+    // Binary contents replace all of the lines, including the conflicted ones.
+    let conflicts = match graggle.binary() {
+        Some(_) => 0,
+        None => graggle.conflicts().len(),
+    };
+    if conflicts > 0 {
+        return Ok(FileStatus::Conflicted(conflicts));
+    }
+    let mut rendered = Vec::new();
+    crate::render::render(repo, graggle, &mut rendered)?;
+    if rendered == contents {
+        Ok(FileStatus::Clean)
+    } else {
+        Ok(FileStatus::Modified)
+    }
+}
+
+pub(crate) fn status(repo: &Repo) -> Result<BTreeMap<String, FileStatus>, Error> {
+    let branch = &repo.current_branch;
+    let mut ret = BTreeMap::new();
+
+    // The lines of the branch itself live in the default working file. That file is only missing
+    // if the branch actually has some lines.
+    let graggle = repo.graggle(branch)?;
+    let status = file_status(repo, graggle, DEFAULT_WORKING_FILE)?;
+    if status != FileStatus::Missing || graggle.nodes().next().is_some() {
+        ret.insert(DEFAULT_WORKING_FILE.to_owned(), status);
+    }
+    for path in repo.storage.tracked.removed() {
+        ret.insert(path.to_owned(), FileStatus::Removed);
+    }
+    // Moved files are compared with their contents on the branch, under their old names.
+    for (path, origin) in tracked(repo)? {
+        let status = match origin {
+            Some(orig) => file_status(repo, repo.file_graggle(branch, &orig)?, &path)?,
+            None if crate::working::exists(repo, &path) => FileStatus::Added,
+            None => FileStatus::Missing,
+        };
+        ret.insert(path, status);
+    }
+
+    for path in working_files(repo)? {
+        ret.entry(path).or_insert(FileStatus::Untracked);
+    }
+    Ok(ret)
+}
+
+pub(crate) fn untracked_files(repo: &Repo) -> Result<Vec<String>, Error> {
+    let branch = &repo.current_branch;
+    let mut tracked = tracked(repo)?;
+    if repo.graggle(branch)?.nodes().next().is_some() {
+        tracked.insert(DEFAULT_WORKING_FILE.to_owned(), None);
+    }
+    Ok(working_files(repo)?
+        .into_iter()
+        .filter(|p| !tracked.contains_key(p))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Change, Changes};
+    use std::fs;
+
+    #[test]
+    fn status() {
+        let mut repo = Repo::init_tmp_with_working_copy("status");
+        let root = repo.root_dir.clone();
+        assert!(repo.status().unwrap().is_empty());
+
+        let changes = Changes {
+            changes: vec![
+                Change::NewFile {
+                    path: "a.txt".to_owned(),
+                },
+                Change::NewFile {
+                    path: "dir/b.txt".to_owned(),
+                },
+            ],
+        };
+        let id = repo.create_patch("Author", "Msg", changes).unwrap();
+        repo.apply_patch("master", &id).unwrap();
+        fs::write(root.join("a.txt"), b"").unwrap();
+        fs::write(root.join(DEFAULT_WORKING_FILE), b"a\n").unwrap();
+        fs::create_dir_all(root.join("dir/sub")).unwrap();
+        fs::write(root.join("dir/sub/c.txt"), b"c\n").unwrap();
+        fs::create_dir_all(root.join(".ojo")).unwrap();
+        fs::write(root.join(".ojo/db"), b"").unwrap();
+
+        let status = repo.status().unwrap();
+        let expected = vec![
+            ("a.txt", FileStatus::Clean),
+            ("dir/b.txt", FileStatus::Missing),
+            ("dir/sub/c.txt", FileStatus::Untracked),
+            (DEFAULT_WORKING_FILE, FileStatus::Modified),
+        ];
+        assert_eq!(
+            status.iter().map(|(p, s)| (&p[..], *s)).collect::<Vec<_>>(),
+            expected
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn ignored() {
+        let mut repo = Repo::init_tmp_with_working_copy("status-ignored");
+        let root = repo.root_dir.clone();
+        let changes = Changes {
+            changes: vec![Change::NewFile {
+                path: "tracked.log".to_owned(),
+            }],
+        };
+        let id = repo.create_patch("Author", "Msg", changes).unwrap();
+        repo.apply_patch("master", &id).unwrap();
+        fs::write(root.join(crate::IGNORE_FILE), b"*.log\n").unwrap();
+        fs::write(root.join("tracked.log"), b"").unwrap();
+        fs::write(root.join("other.log"), b"").unwrap();
+        fs::write(root.join("a.txt"), b"").unwrap();
+
+        // Ignoring a tracked file doesn't hide it.
+        let status = repo.status().unwrap();
+        assert_eq!(
+            status.keys().map(|p| &p[..]).collect::<Vec<_>>(),
+            vec![crate::IGNORE_FILE, "a.txt", "tracked.log"]
+        );
+        assert_eq!(status["tracked.log"], FileStatus::Clean);
+        assert_eq!(
+            repo.untracked_files().unwrap(),
+            vec![crate::IGNORE_FILE, "a.txt"]
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn conflicted() {
+        let mut repo = Repo::init_tmp_with_working_copy("status-conflicted");
+        let diff = repo.diff("master", b"a\nb\n").unwrap();
+        let id = repo.create_patch("Author", "Msg", diff.changes()).unwrap();
+        repo.apply_patch("master", &id).unwrap();
+        let file = repo.file("master").unwrap();
+        let (a, b) = (*file.node_id(0), *file.node_id(1));
+        let changes = Changes {
+            changes: vec![Change::NewEdge { src: b, dest: a }],
+        };
+        let id = repo.create_patch("Author", "Msg", changes).unwrap();
+        repo.apply_patch("master", &id).unwrap();
+
+        let status = repo.status().unwrap();
+        assert_eq!(status[DEFAULT_WORKING_FILE], FileStatus::Missing);
+        fs::write(repo.root_dir.join(DEFAULT_WORKING_FILE), b"a\n").unwrap();
+        let status = repo.status().unwrap();
+        assert_eq!(status[DEFAULT_WORKING_FILE], FileStatus::Conflicted(1));
+        fs::remove_dir_all(&repo.root_dir).unwrap();
+    }
+}
@@ -9,10 +9,18 @@
 // See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
 // of this distribution.
 
-use crate::patch::{Change, Changes};
-use crate::{NodeId, PatchId};
+use crate::bisect::Bisection;
+use crate::blob::{Blob, BlobHash, BlobRef};
+use crate::chunk::{self, CHUNK_THRESHOLD};
+use crate::git_mirror::GitMirror;
+use crate::index::MetadataIndex;
+use crate::patch::{Change, Patch};
+use crate::tracked::TrackedFiles;
+use crate::{Error, NodeId, PatchId};
 use ojo_multimap::MMap;
-use std::collections::{BTreeMap, HashMap};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::PathBuf;
 
 #[macro_use]
 pub mod graggle;
@@ -36,7 +44,7 @@ pub struct INode {
 // repository history grows. A real implementation would need to page in this storage on-demand
 // and would also need to implement copy-on-write in various important places. For now, though, we
 // just serialize and deserialize as a giant chunk.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct Storage {
     // We generate unique INodes by assigning numbers in an increasing sequence. This is the next
     // one to be assigned.
@@ -44,8 +52,26 @@ pub(crate) struct Storage {
 
     // These are the actual, textual contents of the lines. If we wanted to be clever, we could do
     // deduplication and/or compression.
+    //
+    // In encrypted repositories, these (and `blobs`) are stored separately from the rest of the
+    // storage (see `Storage::contents_data`).
+    #[serde(default)]
     contents: BTreeMap<NodeId, Vec<u8>>,
 
+    // The contents of binary files, indexed by their hash. Unlike `contents`, these aren't removed
+    // when the patch that introduced them is unapplied, because other patches might introduce the
+    // same contents.
+    //
+    // Large contents (see `CHUNK_THRESHOLD`) aren't stored here in one piece: they are split into
+    // chunks, which are stored here instead, so that the parts that different versions of a file
+    // have in common are only stored once.
+    #[serde(default)]
+    blobs: BTreeMap<BlobHash, Blob>,
+
+    // The hashes of the chunks of large binary contents, indexed by the hash of the contents.
+    #[serde(default)]
+    chunked_blobs: BTreeMap<BlobHash, Vec<BlobHash>>,
+
     // This is a map from the names of branches to the inodes where those branches' data is stored.
     branches: BTreeMap<String, INode>,
 
@@ -54,7 +80,11 @@ pub(crate) struct Storage {
 
     // These are all the patches that we know about, and have ever known about.
     //
-    // The contents of the patches are YAML.
+    // The contents of the patches are YAML. When compression is enabled, the patches are
+    // serialized separately (see `compress::PatchStore`); we still read them from here, because
+    // that's where older repositories have them.
+    #[serde(default)]
+    #[cfg_attr(feature = "compression", serde(skip_serializing))]
     pub patches: HashMap<PatchId, String>,
 
     // If this contains the key-value pair (branch, patch), it means that the named branch contains
@@ -69,6 +99,71 @@ pub(crate) struct Storage {
     // This is the reverse of `patch_deps`: if this contains the key-value pair (p1, p2), it means
     // that patch p2 depends on patch p1.
     pub patch_rev_deps: MMap<PatchId, PatchId>,
+
+    // An index of the metadata of all the patches in `patches`. Repositories written by older
+    // versions of ojo don't have this, so it gets filled in when they are opened.
+    #[serde(default)]
+    pub patch_index: MetadataIndex,
+
+    // If this contains the key-value pair (p1, p2), it means that patch p1 has been made obsolete
+    // by patch p2 (see `ObsoleteMarker`).
+    #[serde(default)]
+    pub successors: MMap<PatchId, PatchId>,
+
+    // The patches that have been published, and so must not be rewritten. Every dependency of a
+    // published patch is also published.
+    #[serde(default)]
+    pub published: BTreeSet<PatchId>,
+
+    // The patches whose data we don't have, because this is a shallow clone. We know everything
+    // else about them (their dependencies and their metadata), and their lines are in the
+    // graggles, so they only need to be downloaded (from `origin`) if something needs to read
+    // them.
+    #[serde(default)]
+    pub ghosts: BTreeSet<PatchId>,
+
+    // The repository that this one was cloned from, if it is a shallow clone (see
+    // `Repo::clone_shallow`).
+    #[serde(default)]
+    pub origin: Option<String>,
+
+    // If this contains the key-value pair (remote, patch), it means that the named remote is
+    // known to have the patch (because we fetched it from there, or pushed it there).
+    #[serde(default)]
+    pub remote_patches: MMap<String, PatchId>,
+
+    // The changes to the files tracked by the working copy that haven't been recorded yet.
+    #[serde(default)]
+    pub tracked: TrackedFiles,
+
+    // The patches holding changes that were stashed away from the working copy (see
+    // `Repo::stash_push`), with the most recent one last. They aren't on any branch.
+    #[serde(default)]
+    pub stashes: Vec<PatchId>,
+
+    // The bisection that is in progress, if there is one (see `Repo::bisect_start`).
+    #[serde(default)]
+    pub bisection: Option<Bisection>,
+
+    // The hashes of the contents that the files in the working copy are known to have, because
+    // they were written out from the current branch (or recorded on it). These let the working
+    // copy be updated without rewriting the files that didn't change (see
+    // `Repo::update_working_copy`).
+    #[serde(default)]
+    pub rendered: BTreeMap<String, BlobHash>,
+
+    // The root directories of the linked worktrees (see `Repo::add_worktree`).
+    #[serde(default)]
+    pub worktrees: BTreeSet<PathBuf>,
+
+    // The patches that were imported from git commits, by the ids of the commits (see
+    // `Repo::import_git`).
+    #[serde(default)]
+    pub git_commits: BTreeMap<String, PatchId>,
+
+    // The git commits that branches were synced with (see `Repo::pull_git` and `Repo::push_git`).
+    #[serde(default)]
+    pub git_mirror: GitMirror,
 }
 
 impl Storage {
@@ -76,12 +171,27 @@ impl Storage {
         Storage {
             next_inode: 0,
             contents: BTreeMap::new(),
+            blobs: BTreeMap::new(),
+            chunked_blobs: BTreeMap::new(),
             branches: BTreeMap::new(),
             graggles: BTreeMap::new(),
             patches: HashMap::new(),
             branch_patches: MMap::new(),
             patch_deps: MMap::new(),
             patch_rev_deps: MMap::new(),
+            patch_index: MetadataIndex::default(),
+            successors: MMap::new(),
+            published: BTreeSet::new(),
+            ghosts: BTreeSet::new(),
+            origin: None,
+            remote_patches: MMap::new(),
+            tracked: TrackedFiles::default(),
+            stashes: Vec::new(),
+            bisection: None,
+            rendered: BTreeMap::new(),
+            worktrees: BTreeSet::new(),
+            git_commits: BTreeMap::new(),
+            git_mirror: GitMirror::default(),
         }
     }
 
@@ -102,6 +212,49 @@ impl Storage {
         ret
     }
 
+    // Serializes the contents of the lines and of the binary files.
+    pub fn contents_data(&self) -> Result<Vec<u8>, Error> {
+        Ok(bincode::serialize(&(&self.contents, &self.blobs))?)
+    }
+
+    // Restores the contents that were serialized by `contents_data`.
+    pub fn restore_contents(&mut self, data: &[u8]) -> Result<(), Error> {
+        let (contents, blobs) = bincode::deserialize(data)?;
+        self.contents = contents;
+        self.blobs = blobs;
+        Ok(())
+    }
+
+    // Returns a copy of this storage without the things that are serialized by `contents_data`
+    // (and without the patches, which are stored separately when the repository is encrypted).
+    pub fn without_contents(&self) -> Storage {
+        Storage {
+            next_inode: self.next_inode,
+            contents: BTreeMap::new(),
+            blobs: BTreeMap::new(),
+            chunked_blobs: self.chunked_blobs.clone(),
+            branches: self.branches.clone(),
+            graggles: self.graggles.clone(),
+            patches: HashMap::new(),
+            branch_patches: self.branch_patches.clone(),
+            patch_deps: self.patch_deps.clone(),
+            patch_rev_deps: self.patch_rev_deps.clone(),
+            patch_index: self.patch_index.clone(),
+            successors: self.successors.clone(),
+            published: self.published.clone(),
+            ghosts: self.ghosts.clone(),
+            origin: self.origin.clone(),
+            remote_patches: self.remote_patches.clone(),
+            tracked: self.tracked.clone(),
+            stashes: self.stashes.clone(),
+            bisection: self.bisection.clone(),
+            rendered: self.rendered.clone(),
+            worktrees: self.worktrees.clone(),
+            git_commits: self.git_commits.clone(),
+            git_mirror: self.git_mirror.clone(),
+        }
+    }
+
     pub fn contents(&self, id: &NodeId) -> &[u8] {
         self.contents[id].as_slice()
     }
@@ -126,6 +279,71 @@ impl Storage {
         self.contents.contains_key(id)
     }
 
+    pub fn graggle_data(&self, inode: INode) -> &GraggleData {
+        &self.graggles[&inode]
+    }
+
+    pub fn binary_ref(&self, inode: INode) -> Option<&BlobRef> {
+        self.graggles[&inode].binary()
+    }
+
+    pub fn binary(&self, inode: INode) -> Option<Cow<'_, [u8]>> {
+        self.binary_ref(inode).map(|b| self.blob(&b.hash))
+    }
+
+    pub fn blob(&self, hash: &BlobHash) -> Cow<'_, [u8]> {
+        match self.chunked_blobs.get(hash) {
+            Some(chunks) => Cow::Owned(
+                chunks
+                    .iter()
+                    .flat_map(|c| self.blobs[c].data.iter().cloned())
+                    .collect(),
+            ),
+            None => Cow::Borrowed(self.blobs[hash].data.as_slice()),
+        }
+    }
+
+    pub fn has_blob(&self, hash: &BlobHash) -> bool {
+        self.blobs.contains_key(hash) || self.chunked_blobs.contains_key(hash)
+    }
+
+    pub fn add_blob(&mut self, data: &[u8]) {
+        let hash = BlobHash::of(data);
+        if self.has_blob(&hash) {
+            return;
+        }
+        if data.len() <= CHUNK_THRESHOLD {
+            self.blobs.insert(
+                hash,
+                Blob {
+                    data: data.to_owned(),
+                },
+            );
+            return;
+        }
+        let mut chunks = Vec::new();
+        for r in chunk::chunks(data) {
+            let chunk_hash = BlobHash::of(&data[r.clone()]);
+            self.blobs.entry(chunk_hash).or_insert_with(|| Blob {
+                data: data[r].to_owned(),
+            });
+            chunks.push(chunk_hash);
+        }
+        self.chunked_blobs.insert(hash, chunks);
+    }
+
+    // Copies some binary contents (and their chunks) from another storage.
+    fn copy_blob(&mut self, hash: &BlobHash, other: &Storage) {
+        if let Some(chunks) = other.chunked_blobs.get(hash) {
+            for c in chunks {
+                self.blobs.insert(*c, other.blobs[c].clone());
+            }
+            self.chunked_blobs.insert(*hash, chunks.clone());
+        } else {
+            self.blobs.insert(*hash, other.blobs[hash].clone());
+        }
+    }
+
     pub fn inode(&self, branch: &str) -> Option<INode> {
         self.branches.get(branch).cloned()
     }
@@ -155,70 +373,94 @@ impl Storage {
         self.graggles.insert(inode, graggle);
     }
 
-    pub fn branches(&self) -> impl Iterator<Item = &str> {
-        self.branches.keys().map(|s| s.as_str())
-    }
-
-    pub fn apply_changes(&mut self, inode: INode, changes: &Changes, patch: PatchId) {
-        let graggle = self.graggles.get_mut(&inode).unwrap();
-        for ch in &changes.changes {
-            match *ch {
-                Change::NewNode { ref id, .. } => {
-                    debug!("adding node {:?}", id);
-                    graggle.add_node(id.clone());
-                }
-                Change::DeleteNode { ref id } => {
-                    debug!("deleting node {:?}", id);
-                    graggle.delete_node(&id);
-                }
-                Change::NewEdge { ref src, ref dest } => {
-                    debug!("adding edge {:?} -- {:?}", src, dest);
-                    graggle.add_edge(src.clone(), dest.clone(), patch);
+    // Copies a graggle from another storage, along with the contents of its lines and its binary
+    // files. Unlike applying the patches that built the graggle, this doesn't need the patches.
+    pub fn copy_graggle(&mut self, inode: INode, other: &Storage, other_inode: INode) {
+        let data = other.graggles[&other_inode].clone();
+        let mut stack = vec![&data];
+        while let Some(g) = stack.pop() {
+            for id in g.all_nodes() {
+                if let Some(contents) = other.contents.get(id) {
+                    self.contents.insert(*id, contents.clone());
                 }
             }
+            if let Some(b) = g.binary() {
+                self.copy_blob(&b.hash, other);
+            }
+            stack.extend(g.file_graggles());
         }
+        self.graggles.insert(inode, data);
+    }
 
-        // Because we borrowed self.graggles, the borrow checker isn't smart enough to allow this
-        // into the previous loop.
-        for ch in &changes.changes {
-            if let Change::NewNode {
-                ref id,
-                ref contents,
-            } = *ch
-            {
-                self.add_contents(id.clone(), contents.to_owned());
+    // Forgets everything about some patches, which must not be on any branch, and must not be
+    // depended on by any other patches.
+    pub fn remove_patches(&mut self, ids: &BTreeSet<PatchId>) {
+        for id in ids {
+            self.patches.remove(id);
+            self.ghosts.remove(id);
+            self.published.remove(id);
+            self.patch_index.remove(id);
+            let deps = self.patch_deps.get(id).cloned().collect::<Vec<_>>();
+            for dep in deps {
+                self.patch_rev_deps.remove(&dep, id);
             }
+            self.patch_deps.remove_all(id);
+            self.patch_rev_deps.remove_all(id);
+            self.successors.remove_all(id);
+        }
+
+        let stale = self
+            .successors
+            .iter()
+            .filter(|(_, succ)| ids.contains(succ))
+            .map(|(obsolete, succ)| (*obsolete, *succ))
+            .collect::<Vec<_>>();
+        for (obsolete, succ) in stale {
+            self.successors.remove(&obsolete, &succ);
+        }
+        let stale = self
+            .remote_patches
+            .iter()
+            .filter(|(_, id)| ids.contains(id))
+            .map(|(remote, id)| (remote.clone(), *id))
+            .collect::<Vec<_>>();
+        for (remote, id) in stale {
+            self.remote_patches.remove(&remote, &id);
         }
     }
 
-    pub fn unapply_changes(&mut self, inode: INode, changes: &Changes, patch: PatchId) {
-        let graggle = self.graggles.get_mut(&inode).unwrap();
+    pub fn branches(&self) -> impl Iterator<Item = &str> {
+        self.branches.keys().map(|s| s.as_str())
+    }
 
-        // Because of the requirements of `unadd_edge`, we need to unadd all edges before we unadd
-        // all nodes.
-        for ch in &changes.changes {
+    pub fn apply_patch(&mut self, inode: INode, patch: &Patch) {
+        patch.apply_to(self.graggles.get_mut(&inode).unwrap());
+        for (_, ch) in patch.changes().flattened() {
             match *ch {
-                Change::DeleteNode { ref id } => {
-                    debug!("undeleting node {:?}", id);
-                    graggle.undelete_node(id);
+                Change::NewNode {
+                    ref id,
+                    ref contents,
+                } => self.add_contents(*id, contents.to_owned()),
+                Change::BinaryReplace { ref new_blob, .. } => self.add_blob(new_blob),
+                Change::ChunkedReplace {
+                    ref old,
+                    ref chunks,
+                    ..
+                } => {
+                    // The old contents were put in place by a patch that's already applied, and
+                    // the chunks were checked when the patch was registered.
+                    let data = chunk::assemble(&self.blob(&old.hash), chunks)
+                        .expect("invalid chunks in binary contents");
+                    self.add_blob(&data);
                 }
-                Change::NewEdge { ref src, ref dest } => {
-                    debug!("unadding edge {:?} -- {:?}", src, dest);
-                    graggle.unadd_edge(src, dest, patch);
-                }
-                Change::NewNode { .. } => {}
-            }
-        }
-        for ch in &changes.changes {
-            if let Change::NewNode { ref id, .. } = *ch {
-                debug!("unadding node {:?}", id);
-                graggle.unadd_node(id);
+                _ => {}
             }
         }
+    }
 
-        // Because we borrowed self.graggles, the borrow checker isn't smart enough to allow this
-        // into the previous loop.
-        for ch in &changes.changes {
+    pub fn unapply_patch(&mut self, inode: INode, patch: &Patch) {
+        patch.unapply_from(self.graggles.get_mut(&inode).unwrap());
+        for (_, ch) in patch.changes().flattened() {
             if let Change::NewNode { ref id, .. } = *ch {
                 self.remove_contents(id);
             }
@@ -10,7 +10,7 @@
 // of this distribution.
 
 use crate::storage::Storage;
-use crate::NodeId;
+use crate::{NodeId, PatchId, Renumbering};
 
 /// A `File` is a special case of a [`Graggle`](crate::Graggle), in which there is just a linear order.
 ///
@@ -47,10 +47,35 @@ impl File {
         }
     }
 
+    // Creates a `File` from a list of node ids and their contents.
+    pub(crate) fn from_lines(ids: Vec<NodeId>, lines: Vec<&[u8]>) -> File {
+        let mut contents = Vec::new();
+        let mut boundaries = Vec::new();
+        for line in lines {
+            boundaries.push(contents.len());
+            contents.extend_from_slice(line);
+        }
+        boundaries.push(contents.len());
+        File {
+            contents,
+            boundaries,
+            ids,
+        }
+    }
+
+    // Renumbers the staged lines in this file and replaces the staging id (see
+    // `PatchId::staging`) in their ids, now that the patch introducing them is finished.
+    pub(crate) fn finalize_staged(&mut self, renumbering: &Renumbering, id: &PatchId) {
+        for node in &mut self.ids {
+            *node = renumbering.finalize(node, id);
+        }
+    }
+
     /// Creates a [`File`] from the raw bytes, by dividing them into lines.
     ///
-    /// The [`NodeId`]s will be synthesized: they will have empty [`PatchId`](crate::PatchId)s, and
-    /// their node indices will be consecutive, starting from zero.
+    /// The [`NodeId`]s will be synthesized: they will be in the staging namespace (see
+    /// [`PatchId::staging`](crate::PatchId::staging)), and their node indices will be
+    /// consecutive, starting from zero.
     pub fn from_bytes(bytes: &[u8]) -> File {
         let contents = bytes.to_owned();
 
@@ -71,7 +96,7 @@ impl File {
         }
 
         let ids = (0..(boundaries.len() as u64 - 1))
-            .map(NodeId::cur)
+            .map(NodeId::staged)
             .collect();
 
         File {
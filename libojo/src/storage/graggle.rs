@@ -13,8 +13,11 @@ use ojo_graph::Graph;
 use ojo_multimap::MMap;
 use ojo_partition::Partition;
 use std::collections::BTreeSet as Set;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
 
+use crate::blob::BlobRef;
+use crate::tree::{FileRef, Files};
 use crate::{NodeId, PatchId};
 
 /// The different kinds of edges.
@@ -59,7 +62,7 @@ pub struct Edge {
     /// Which patch introduced this edge?
     ///
     /// If this is a pseudo-edge, then this field will be "blank", meaning that it will be set to
-    /// `PatchId::cur`.
+    /// `PatchId::staging`.
     ///
     /// This field is necessary because of the possiblity that two different patches will add the
     /// same edge. If this happens and then one of the patches is unapplied, we'd better make sure
@@ -76,7 +79,7 @@ impl Edge {
         Edge {
             dest: dest,
             kind: EdgeKind::Pseudo,
-            patch: PatchId::cur(),
+            patch: PatchId::staging(),
         }
     }
 
@@ -131,16 +134,69 @@ pub(crate) struct GraggleData {
     // These are the component representatives whose components are dirty (i.e. we need to
     // recalculate the connectedness relation that they induce).
     dirty_reps: Set<NodeId>,
+
+    // The binary contents of the file, if there are any. These live outside the graph: they are
+    // just whatever the most recent `Change::BinaryReplace` put there.
+    #[serde(default)]
+    binary: Option<BlobRef>,
+
+    // The files, along with their metadata.
+    #[serde(default)]
+    files: Files,
+
+    // The lines of the files, indexed by the files' identities (see `Change::EditFile`). Lines
+    // that don't belong to any file are in this graggle itself.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    file_graggles: BTreeMap<FileRef, GraggleData>,
+
+    // This gets incremented every time the graggle is modified. It isn't saved, so it only
+    // means anything to the cache below (which isn't saved either).
+    #[serde(skip)]
+    epoch: u64,
+    // Results of some expensive queries on the live part of the graggle. They are only valid if
+    // the cache's epoch matches the graggle's epoch.
+    #[serde(skip)]
+    cache: CacheCell,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Cache {
+    epoch: u64,
+    sccs: Option<Arc<Vec<HashSet<NodeId>>>>,
+    top_sort: Option<Option<Arc<Vec<NodeId>>>>,
+}
+
+// The cache is behind a mutex (rather than a `RefCell`) so that graggles, and therefore
+// repositories, can be shared between threads.
+#[derive(Debug, Default)]
+struct CacheCell(Mutex<Cache>);
+
+impl CacheCell {
+    fn lock(&self) -> MutexGuard<'_, Cache> {
+        // The cache is only ever replaced or filled in whole, so it's fine even if a thread
+        // panicked while holding the lock.
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
 }
 
-// Two Graggles compare as equal if they have the same nodes and edges (including pseudo-edges). We
-// don't check the rest of the fields, as they are only there for optimization.
+impl Clone for CacheCell {
+    fn clone(&self) -> CacheCell {
+        CacheCell(Mutex::new(self.lock().clone()))
+    }
+}
+
+// Two Graggles compare as equal if they have the same nodes and edges (including pseudo-edges), the
+// same binary contents and the same files (including the files' graggles). We don't check the rest of the fields, as they are only
+// there for optimization.
 impl PartialEq<GraggleData> for GraggleData {
     fn eq(&self, other: &GraggleData) -> bool {
         self.nodes.eq(&other.nodes)
             && self.deleted_nodes.eq(&other.deleted_nodes)
             && self.edges.eq(&other.edges)
             && self.back_edges.eq(&other.back_edges)
+            && self.binary.eq(&other.binary)
+            && self.files.eq(&other.files)
+            && self.file_graggles.eq(&other.file_graggles)
     }
 }
 
@@ -162,13 +218,99 @@ impl GraggleData {
     }
 
     pub fn add_node(&mut self, id: NodeId) {
+        self.touch();
         self.nodes.insert(id);
     }
 
+    // Returns all of the nodes, including the deleted ones (but not the ones in the files'
+    // graggles).
+    pub fn all_nodes(&self) -> impl Iterator<Item = &NodeId> {
+        self.nodes.iter().chain(self.deleted_nodes.iter())
+    }
+
+    pub fn binary(&self) -> Option<&BlobRef> {
+        self.binary.as_ref()
+    }
+
+    pub fn set_binary(&mut self, binary: Option<BlobRef>) {
+        self.binary = binary;
+    }
+
+    pub fn files(&self) -> &Files {
+        &self.files
+    }
+
+    pub fn files_mut(&mut self) -> &mut Files {
+        &mut self.files
+    }
+
+    pub fn file_graggle(&self, file: &FileRef) -> Option<&GraggleData> {
+        self.file_graggles.get(file)
+    }
+
+    pub fn file_graggles(&self) -> impl Iterator<Item = &GraggleData> {
+        self.file_graggles.values()
+    }
+
+    // Returns the graggle containing the lines of a file, creating an empty one if necessary.
+    pub fn file_graggle_mut(&mut self, file: &FileRef) -> &mut GraggleData {
+        self.file_graggles.entry(file.clone()).or_default()
+    }
+
+    pub fn remove_file_graggle(&mut self, file: &FileRef) {
+        self.file_graggles.remove(file);
+    }
+
+    // Records the fact that the graggle was modified, invalidating any cached results.
+    fn touch(&mut self) {
+        self.epoch += 1;
+    }
+
+    // Returns the cache, after clearing it if it is out of date.
+    fn cache(&self) -> MutexGuard<'_, Cache> {
+        let mut cache = self.cache.lock();
+        if cache.epoch != self.epoch {
+            *cache = Cache {
+                epoch: self.epoch,
+                ..Default::default()
+            };
+        }
+        cache
+    }
+
+    fn sccs(&self) -> Arc<Vec<HashSet<NodeId>>> {
+        if let Some(sccs) = &self.cache().sccs {
+            return Arc::clone(sccs);
+        }
+
+        let sccs = Arc::new(self.as_graggle().as_live_graph().tarjan().into_parts());
+        self.cache().sccs = Some(Arc::clone(&sccs));
+        sccs
+    }
+
+    fn top_sort(&self) -> Option<Arc<Vec<NodeId>>> {
+        if let Some(top_sort) = &self.cache().top_sort {
+            return top_sort.clone();
+        }
+
+        // Tarjan's algorithm returns the components in topological order, so if they are all
+        // singletons then we get a topological sort for free.
+        let sccs = self.sccs();
+        let top_sort = if sccs.iter().all(|part| part.len() == 1) {
+            Some(Arc::new(
+                sccs.iter().flat_map(|part| part.iter().cloned()).collect(),
+            ))
+        } else {
+            None
+        };
+        self.cache().top_sort = Some(top_sort.clone());
+        top_sort
+    }
+
     fn has_live_edge(&self, src: &NodeId, dest: &NodeId) -> bool {
         // Construct the smallest (in the sense of Edge's order) edge that could possibly go from
         // src to dest.
-        let e = Edge::new_live(*dest, PatchId::cur());
+        let e = Edge::new_live(*dest, PatchId::staging());
         if let Some(actual_e) = self.edges.get_from(src, &e).next() {
             // actual_e is an edge going from src to something greater than or equal to dest.
             // There's an edge from src to dest if and only if actual_e goes to dest.
@@ -219,6 +361,7 @@ impl GraggleData {
     }
 
     pub fn unadd_node(&mut self, id: &NodeId) {
+        self.touch();
         // If we are unadding a node, it means we are unapplying the patch in which the node was
         // introduced. Since we must have already unapplied any reverse-dependencies of the patch,
         // the node must be live (it can't have been marked as deleted).
@@ -253,6 +396,7 @@ impl GraggleData {
     /// # Panics
     /// Panics if the node doesn't exist, or if exists but is not live.
     pub fn delete_node(&mut self, id: &NodeId) {
+        self.touch();
         assert!(self.nodes.contains(id));
         self.nodes.remove(id);
         self.deleted_nodes.insert(id.clone());
@@ -276,6 +420,7 @@ impl GraggleData {
     }
 
     pub fn undelete_node(&mut self, id: &NodeId) {
+        self.touch();
         assert!(self.deleted_nodes.contains(id));
         self.deleted_nodes.remove(id);
         self.nodes.insert(id.clone());
@@ -395,6 +540,7 @@ impl GraggleData {
     }
 
     pub fn add_edge(&mut self, from: NodeId, to: NodeId, patch: PatchId) {
+        self.touch();
         let from_deleted = !self.nodes.contains(&from);
         let to_deleted = !self.nodes.contains(&to);
         assert!(!from_deleted || self.deleted_nodes.contains(&from));
@@ -415,6 +561,7 @@ impl GraggleData {
     }
 
     pub fn resolve_pseudo_edges(&mut self) {
+        self.touch();
         let mut dirty_reps = Set::new();
         std::mem::swap(&mut dirty_reps, &mut self.dirty_reps);
 
@@ -448,6 +595,10 @@ impl GraggleData {
         for component in components {
             self.add_component_pseudo_edges(&component);
         }
+
+        for file in self.file_graggles.values_mut() {
+            file.resolve_pseudo_edges();
+        }
     }
 
     /// # Panics
@@ -455,6 +606,7 @@ impl GraggleData {
     /// Panics unless `from` and `to` are nodes in this graggle. In particular, if you're planning to
     /// remove some nodes and the edge between them, you need to remove the edge first.
     pub fn unadd_edge(&mut self, from: &NodeId, to: &NodeId, patch: PatchId) {
+        self.touch();
         let from_deleted = self.deleted_nodes.contains(&from);
         let to_deleted = self.deleted_nodes.contains(&to);
         assert!(from_deleted || self.nodes.contains(&from));
@@ -499,13 +651,11 @@ impl GraggleData {
             let sub_graph = graph.edge_filtered(|src, edge| {
                 (src == u && component.contains(&edge.dest)) || component.contains(src)
             });
-            for visit in sub_graph.dfs_from(u) {
-                if let ojo_graph::dfs::Visit::Edge { dst, status, .. } = visit {
-                    // Only take into account the first visit to a node. Besides being more
-                    // efficient, this means we'll avoid adding self-loops.
-                    if status == ojo_graph::dfs::Status::New && graggle.is_live(&dst) {
-                        pairs.push((*u, dst));
-                    }
+            // Only take into account the first visit to a node. Besides being more efficient,
+            // this means we'll avoid adding self-loops.
+            for dst in sub_graph.preorder_from(u).skip(1) {
+                if graggle.is_live(&dst) {
+                    pairs.push((*u, dst));
                 }
             }
         }
@@ -526,8 +676,6 @@ impl GraggleData {
 
     // Brute-force compute the pseudo-edges that should start at node u.
     fn pseudo_edges(&self, u: &NodeId) -> HashSet<NodeId> {
-        use ojo_graph::dfs::{Status, Visit};
-
         let mut ret = HashSet::new();
         // Pseudo-edges that should start at u are those that can be reached from u by ignoring
         // other pseudo-edges, and only going through deleted intermediate edges. This latter
@@ -538,15 +686,9 @@ impl GraggleData {
             edge.kind != EdgeKind::Pseudo
                 && ((src == u && !self.is_live(&edge.dest)) || !self.is_live(src))
         });
-        for visit in u_graph.dfs_from(u) {
-            if let Visit::Edge { dst, status, .. } = visit {
-                if status == Status::New
-                    && dst != *u
-                    && self.is_live(&dst)
-                    && !self.has_live_edge(u, &dst)
-                {
-                    ret.insert(dst);
-                }
+        for dst in u_graph.preorder_from(u).skip(1) {
+            if self.is_live(&dst) && !self.has_live_edge(u, &dst) {
+                ret.insert(dst);
             }
         }
         ret
@@ -658,6 +800,12 @@ pub struct Graggle<'a> {
 }
 
 impl<'a> Graggle<'a> {
+    /// Returns the binary contents of this graggle, if it has any (see
+    /// [`Change::BinaryReplace`](crate::Change::BinaryReplace)).
+    pub fn binary(self) -> Option<&'a BlobRef> {
+        self.data.binary()
+    }
+
     /// Returns an iterator over all live nodes of this graggle.
     pub fn nodes(self) -> impl Iterator<Item = NodeId> + 'a {
         self.data.nodes.iter().cloned()
@@ -702,6 +850,23 @@ impl<'a> Graggle<'a> {
         self.data.nodes.contains(node) || self.data.deleted_nodes.contains(node)
     }
 
+    /// Returns the strongly connected components of the live part of this graggle, in
+    /// topological order.
+    ///
+    /// The result is cached, so calling this repeatedly is cheap as long as the graggle doesn't
+    /// change in between.
+    pub fn sccs(self) -> Arc<Vec<HashSet<NodeId>>> {
+        self.data.sccs()
+    }
+
+    /// Returns a topological sort of the live nodes of this graggle, or `None` if there is a
+    /// cycle.
+    ///
+    /// Like [`Graggle::sccs`], the result is cached until the graggle changes.
+    pub fn top_sort(self) -> Option<Arc<Vec<NodeId>>> {
+        self.data.top_sort()
+    }
+
     /// Returns `true` if `node` is live.
     ///
     /// # Panics
@@ -712,6 +877,10 @@ impl<'a> Graggle<'a> {
         self.data.nodes.contains(node)
     }
 
+    pub(crate) fn data(self) -> &'a GraggleData {
+        self.data
+    }
+
     /// Wraps `self` in [`LiveGraph`], which implements [`graph::Graph`] over the live nodes of
     /// this graggle.
     pub fn as_live_graph(self) -> LiveGraph<'a> {
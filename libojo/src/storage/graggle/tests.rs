@@ -10,7 +10,7 @@
 // of this distribution.
 
 use super::*;
-use crate::patch::Change;
+use crate::patch::{Change, Changes};
 use crate::{NodeId, PatchId};
 
 use byteorder::{LittleEndian, WriteBytesExt};
@@ -30,14 +30,14 @@ macro_rules! graggle {
         {
             let mut d = $crate::storage::graggle::GraggleData::new();
             $($(
-                d.add_node(NodeId::cur($live));
+                d.add_node(NodeId::staged($live));
             )*)*
             $($(
-                d.add_node(NodeId::cur($deleted));
-                d.delete_node(&NodeId::cur($deleted));
+                d.add_node(NodeId::staged($deleted));
+                d.delete_node(&NodeId::staged($deleted));
             )*)*
             $($(
-                d.add_edge(NodeId::cur($src), NodeId::cur($dest), $crate::PatchId::cur());
+                d.add_edge(NodeId::staged($src), NodeId::staged($dest), $crate::PatchId::staging());
             )*)*
             d
         }
@@ -46,10 +46,20 @@ macro_rules! graggle {
 
 #[derive(Clone, Debug)]
 pub struct ChangesWithId {
-    pub changes: Vec<Change>,
+    pub changes: Changes,
     pub id: PatchId,
 }
 
+impl ChangesWithId {
+    fn apply_to(&self, graggle: &mut GraggleData) {
+        self.changes.apply_to(graggle, self.id);
+    }
+
+    fn unapply_from(&self, graggle: &mut GraggleData) {
+        self.changes.unapply_from(graggle, self.id);
+    }
+}
+
 #[doc(hidden)]
 #[macro_export]
 macro_rules! changes {
@@ -59,18 +69,18 @@ macro_rules! changes {
         $(edges : $( $src:literal - $dest:literal ),*)?
     ) => {{
         $crate::storage::graggle::tests::ChangesWithId {
-            changes: vec![
+            changes: $crate::patch::Changes { changes: vec![
                 $($(
-                    Change::DeleteNode { id: NodeId::cur($delete_node) },
+                    Change::DeleteNode { id: NodeId::staged($delete_node) },
                 )*)*
                 $($(
-                    Change::NewNode { id: NodeId::cur($add_node), contents: vec![] },
+                    Change::NewNode { id: NodeId::staged($add_node), contents: vec![] },
                 )*)*
                 $($(
-                    Change::NewEdge { src: NodeId::cur($src), dest: NodeId::cur($dest) },
+                    Change::NewEdge { src: NodeId::staged($src), dest: NodeId::staged($dest) },
                 )*)*
-            ],
-            id: PatchId::cur(),
+            ] },
+            id: PatchId::staging(),
         }
     }}
 }
@@ -99,8 +109,8 @@ trait GraggleExt {
 
 impl GraggleExt for GraggleData {
     fn has_pseudoedge(&self, i: u64, j: u64) -> bool {
-        let src = NodeId::cur(i);
-        let edge = Edge::new_pseudo(NodeId::cur(j));
+        let src = NodeId::staged(i);
+        let edge = Edge::new_pseudo(NodeId::staged(j));
         self.edges.contains(&src, &edge)
     }
 
@@ -143,17 +153,17 @@ fn delete_long_middle() {
     );
     assert_pseudoedges!(d; 0-5);
 
-    d.unadd_edge(&NodeId::cur(2), &NodeId::cur(3), PatchId::cur());
+    d.unadd_edge(&NodeId::staged(2), &NodeId::staged(3), PatchId::staging());
     assert_pseudoedges!(d; );
 
-    d.add_edge(NodeId::cur(2), NodeId::cur(3), PatchId::cur());
+    d.add_edge(NodeId::staged(2), NodeId::staged(3), PatchId::staging());
     assert_pseudoedges!(d; 0-5);
-    d.undelete_node(&NodeId::cur(3));
+    d.undelete_node(&NodeId::staged(3));
     assert_pseudoedges!(d; 0-3, 3-5);
-    d.undelete_node(&NodeId::cur(1));
-    d.undelete_node(&NodeId::cur(2));
+    d.undelete_node(&NodeId::staged(1));
+    d.undelete_node(&NodeId::staged(2));
     assert_pseudoedges!(d; 3-5);
-    d.undelete_node(&NodeId::cur(4));
+    d.undelete_node(&NodeId::staged(4));
     assert_pseudoedges!(d; );
 }
 
@@ -165,8 +175,8 @@ fn add_next_to_deleted() {
         deleted: 1
         edges: 0-1, 1-2
     );
-    d.add_node(NodeId::cur(3));
-    d.add_edge(NodeId::cur(1), NodeId::cur(3), PatchId::cur());
+    d.add_node(NodeId::staged(3));
+    d.add_edge(NodeId::staged(1), NodeId::staged(3), PatchId::staging());
     assert_pseudoedges!(d; 0-2, 0-3);
 }
 
@@ -204,10 +214,10 @@ fn two_reasons() {
     assert_pseudoedges!(d; 0-3);
 
     // If we get rid of one reason, the pseudo-edge should still be there.
-    d.undelete_node(&NodeId::cur(1));
+    d.undelete_node(&NodeId::staged(1));
     assert_pseudoedges!(d; 0-3);
 
-    d.undelete_node(&NodeId::cur(2));
+    d.undelete_node(&NodeId::staged(2));
     assert_pseudoedges!(d; );
 }
 
@@ -224,8 +234,8 @@ fn duplicate_edge() {
         edges: 0-1
     );
 
-    // The changes! macro defaults to setting all ids to PatchId::cur, which isn't really correct
-    // but it doesn't cause an error unless there are (like here) duplicate edges.
+    // The changes! macro defaults to setting all ids to PatchId::staging, which isn't really
+    // correct but it doesn't cause an error unless there are (like here) duplicate edges.
     ch1.id.data[0] = 1;
     ch2.id.data[0] = 2;
 
@@ -237,8 +247,33 @@ fn duplicate_edge() {
 // n*MAX_AVG_DEGREE.
 const MAX_AVG_DEGREE: usize = 5;
 
+// The SCCs and topological sort are cached, but the cache must be invalidated by modifications.
+#[test]
+fn cached_top_sort() {
+    let mut d = graggle!(
+        live: 0, 1, 2
+        edges: 0-1, 1-2
+    );
+    let ids = |v: &[u64]| v.iter().map(|&i| NodeId::staged(i)).collect::<Vec<_>>();
+    let top_sort = d.as_graggle().top_sort().unwrap();
+    assert_eq!(*top_sort, ids(&[0, 1, 2]));
+    assert!(Arc::ptr_eq(&top_sort, &d.as_graggle().top_sort().unwrap()));
+    assert!(Arc::ptr_eq(&d.as_graggle().sccs(), &d.as_graggle().sccs()));
+
+    let epoch = d.epoch;
+    d.add_edge(NodeId::staged(2), NodeId::staged(0), PatchId::staging());
+    assert_ne!(epoch, d.epoch);
+    assert_eq!(d.as_graggle().top_sort(), None);
+    assert_eq!(d.as_graggle().sccs().len(), 1);
+
+    d.unadd_edge(&NodeId::staged(2), &NodeId::staged(0), PatchId::staging());
+    d.delete_node(&NodeId::staged(1));
+    d.resolve_pseudo_edges();
+    assert_eq!(*d.as_graggle().top_sort().unwrap(), ids(&[0, 2]));
+}
+
 fn fake_patch_id(id: usize) -> PatchId {
-    let mut ret = PatchId::cur();
+    let mut ret = PatchId::staging();
     (&mut ret.data[..])
         .write_u64::<LittleEndian>(id as u64)
         .unwrap();
@@ -260,7 +295,7 @@ fn append_and_delete() {
 
     // Manually check the presence of a pseudo-edge from 0 to 2.
     let mut clone = d.clone();
-    apply_changes(&mut clone, &ch);
+    ch.apply_to(&mut clone);
     clone.resolve_pseudo_edges();
     assert_pseudoedges!(clone; 0-2);
 
@@ -277,13 +312,13 @@ fn check_graggle_and_changes(d: GraggleData, chs: &[ChangesWithId]) {
 
     for ch in chs {
         let mut next = cur.clone();
-        apply_changes(&mut next, ch);
+        ch.apply_to(&mut next);
         next.assert_consistent();
         next.resolve_pseudo_edges();
         next.assert_consistent();
 
         let mut unapplied = next.clone();
-        unapply_changes(&mut unapplied, ch);
+        ch.unapply_from(&mut unapplied);
         unapplied.assert_consistent();
         unapplied.resolve_pseudo_edges();
         unapplied.assert_consistent();
@@ -296,7 +331,7 @@ fn check_graggle_and_changes(d: GraggleData, chs: &[ChangesWithId]) {
     // answer.
     let mut all_at_once = d.clone();
     for ch in chs {
-        apply_changes(&mut all_at_once, ch);
+        ch.apply_to(&mut all_at_once);
     }
     all_at_once.assert_consistent();
     all_at_once.resolve_pseudo_edges();
@@ -305,7 +340,7 @@ fn check_graggle_and_changes(d: GraggleData, chs: &[ChangesWithId]) {
 
     // Now unapply them all and make sure it agrees with the original.
     for ch in chs.iter().rev() {
-        unapply_changes(&mut all_at_once, ch);
+        ch.unapply_from(&mut all_at_once);
     }
     all_at_once.assert_consistent();
     all_at_once.resolve_pseudo_edges();
@@ -315,10 +350,10 @@ fn check_graggle_and_changes(d: GraggleData, chs: &[ChangesWithId]) {
     // Now we do the last thing again, but without resolving pseudo-edges after applying all the
     // patches.
     for ch in chs {
-        apply_changes(&mut all_at_once, ch);
+        ch.apply_to(&mut all_at_once);
     }
     for ch in chs.iter().rev() {
-        unapply_changes(&mut all_at_once, ch);
+        ch.unapply_from(&mut all_at_once);
     }
     all_at_once.assert_consistent();
     all_at_once.resolve_pseudo_edges();
@@ -328,10 +363,10 @@ fn check_graggle_and_changes(d: GraggleData, chs: &[ChangesWithId]) {
     // without resolving pseudo-edges in between.
     let mut all_at_once = cur.clone();
     for ch in chs.iter().rev() {
-        unapply_changes(&mut all_at_once, ch);
+        ch.unapply_from(&mut all_at_once);
     }
     for ch in chs {
-        apply_changes(&mut all_at_once, ch);
+        ch.apply_to(&mut all_at_once);
     }
     all_at_once.assert_consistent();
     all_at_once.resolve_pseudo_edges();
@@ -446,14 +481,14 @@ prop_compose! {
     {
         let mut ret = GraggleData::new();
         for i in 0..num_nodes {
-            ret.nodes.insert(NodeId::cur(i as u64));
+            ret.nodes.insert(NodeId::staged(i as u64));
         }
         for (u, v) in edges {
             if u != v {
-                let u = NodeId::cur(u as u64);
-                let v = NodeId::cur(v as u64);
-                ret.edges.insert(u, Edge::new_live(v, PatchId::cur()));
-                ret.back_edges.insert(v, Edge::new_live(u, PatchId::cur()));
+                let u = NodeId::staged(u as u64);
+                let v = NodeId::staged(v as u64);
+                ret.edges.insert(u, Edge::new_live(v, PatchId::staging()));
+                ret.back_edges.insert(v, Edge::new_live(u, PatchId::staging()));
             }
         }
         ret
@@ -514,7 +549,7 @@ fn arb_changes<'a>(graggle: &'a GraggleData, size: usize) -> BoxedStrategy<Chang
 
         let changes = deletions.chain(insertions).chain(edges).collect::<Vec<_>>();
         ChangesWithId {
-            changes,
+            changes: Changes { changes },
             id: patch_id,
         }
     }
@@ -560,47 +595,19 @@ proptest! {
     }
 }
 
-// These two functions are basically copy&paste from `Storage`. TODO: consider refactoring
-fn apply_changes(graggle: &mut GraggleData, changes: &ChangesWithId) {
-    for ch in &changes.changes {
-        match *ch {
-            Change::NewNode { ref id, .. } => graggle.add_node(id.clone()),
-            Change::DeleteNode { ref id } => graggle.delete_node(&id),
-            Change::NewEdge { ref src, ref dest } => {
-                graggle.add_edge(src.clone(), dest.clone(), changes.id)
-            }
-        }
-    }
-}
-
-fn unapply_changes(graggle: &mut GraggleData, changes: &ChangesWithId) {
-    for ch in &changes.changes {
-        match *ch {
-            Change::DeleteNode { ref id } => graggle.undelete_node(id),
-            Change::NewEdge { ref src, ref dest } => graggle.unadd_edge(src, dest, changes.id),
-            Change::NewNode { .. } => {}
-        }
-    }
-    for ch in &changes.changes {
-        if let Change::NewNode { ref id, .. } = *ch {
-            graggle.unadd_node(id);
-        }
-    }
-}
-
 proptest! {
     #[test]
     fn graggle_then_change((ref d, ref ch) in arb_graggle_and_change(20, 10)) {
         let mut d = d.clone();
         d.assert_consistent();
 
-        apply_changes(&mut d, ch);
+        ch.apply_to(&mut d);
         d.assert_consistent();
 
         d.resolve_pseudo_edges();
         d.assert_consistent();
 
-        unapply_changes(&mut d, ch);
+        ch.unapply_from(&mut d);
         d.assert_consistent();
 
         d.resolve_pseudo_edges();
@@ -628,7 +635,7 @@ fn arb_graggle_and_change_seq(
             let next_change = arb_changes(&cur, change_size);
             (Just(orig), Just(cur), Just(changes), next_change)
                 .prop_flat_map(move |(orig, mut cur, mut changes, ch)| {
-                    apply_changes(&mut cur, &ch);
+                    ch.apply_to(&mut cur);
                     changes.push(ch);
                     recurse(orig, change_size, num_changes - 1, cur, changes)
                 })
@@ -656,12 +663,12 @@ proptest! {
         let mut cur = d.clone();
         for ch in chs {
             let mut next = cur.clone();
-            apply_changes(&mut next, ch);
+            ch.apply_to(&mut next);
             next.resolve_pseudo_edges();
             next.assert_consistent();
 
             let mut unapplied = next.clone();
-            unapply_changes(&mut unapplied, ch);
+            ch.unapply_from(&mut unapplied);
             unapplied.resolve_pseudo_edges();
             unapplied.assert_consistent();
             assert_eq!(cur, unapplied);
@@ -673,7 +680,7 @@ proptest! {
         // answer.
         let mut all_at_once = d.clone();
         for ch in chs {
-            apply_changes(&mut all_at_once, ch);
+            ch.apply_to(&mut all_at_once);
         }
         all_at_once.assert_consistent();
         all_at_once.resolve_pseudo_edges();
@@ -0,0 +1,825 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Importing the history of a subversion repository from the output of `svnadmin dump`.
+//
+// A dump is a sequence of revisions, each of which has some properties (the author, date and log
+// message) and a list of nodes. A node adds, changes, deletes or replaces a file or a directory,
+// and a node that adds something can copy it from some path at an earlier revision.
+//
+// Subversion has no branches of its own; by convention, the repository has the directories
+// `trunk`, `branches/NAME` and `tags/NAME`, and a branch is made by copying a directory. We keep
+// track of the files and the patches of each of those directories, revision by revision. A
+// revision becomes one patch for each directory that it changes, made in the same way as the
+// patches for git commits (see `git_import`). When a directory is copied to a new branch or tag,
+// the copy starts out with the patches of the original, so the branches share their history.
+
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::BufRead;
+use std::rc::Rc;
+
+use crate::git_import::{self, GitFile, Tree};
+use crate::patch::UnidentifiedPatch;
+use crate::{tag, ApplyPolicy, Config, Error, PatchHeader, PatchId, Repo};
+
+// The branch that the patches of each directory are put on. Branch names can't normally contain
+// NUL characters, so this can't clash with a real branch.
+const SCRATCH_BRANCH: &str = "\0svn-import";
+
+/// The key, in the extra metadata of an imported patch (see [`PatchHeader::extra`]), whose value
+/// is the number of the subversion revision that the patch was imported from.
+pub const SVN_REVISION_KEY: &str = "svn-revision";
+
+/// What [`Repo::import_svn`] imported.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SvnImport {
+    /// The patches that were made, in the order that they were made, along with the revisions
+    /// that they were made from. A revision that changed several branches has a patch for each of
+    /// them.
+    pub patches: Vec<(u64, PatchId)>,
+    /// The branches that were created or added to.
+    pub branches: Vec<String>,
+    /// The tags that were created.
+    pub tags: Vec<String>,
+}
+
+// The files and the patches of a directory that is a branch or a tag.
+#[derive(Clone, Default)]
+struct Branch {
+    files: Tree,
+    patches: BTreeSet<PatchId>,
+}
+
+// The properties of a node or a revision.
+#[derive(Default)]
+struct Props {
+    set: BTreeMap<String, Vec<u8>>,
+    // With `Prop-delta: true`, the properties that were deleted.
+    deleted: BTreeSet<String>,
+    delta: bool,
+}
+
+// A record in the dump: its headers, its properties and its text.
+struct Record {
+    // The line that the record starts at.
+    line: usize,
+    headers: BTreeMap<String, String>,
+    props: Option<Props>,
+    text: Option<Vec<u8>>,
+}
+
+impl Record {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(|s| s.as_str())
+    }
+
+    fn error<T>(&self, msg: &str) -> Result<T, Error> {
+        Err(Error::SvnDump(self.line, msg.to_owned()))
+    }
+}
+
+struct Parser<R> {
+    input: R,
+    line_no: usize,
+}
+
+impl<R: BufRead> Parser<R> {
+    fn error<T>(&self, msg: &str) -> Result<T, Error> {
+        Err(Error::SvnDump(self.line_no, msg.to_owned()))
+    }
+
+    fn next_line(&mut self) -> Result<Option<String>, Error> {
+        let mut buf = Vec::new();
+        if self.input.read_until(b'\n', &mut buf)? == 0 {
+            return Ok(None);
+        }
+        self.line_no += 1;
+        if buf.last() == Some(&b'\n') {
+            buf.pop();
+        }
+        match String::from_utf8(buf) {
+            Ok(line) => Ok(Some(line)),
+            Err(_) => self.error("the line isn't valid UTF-8"),
+        }
+    }
+
+    fn read_exact(&mut self, len: usize) -> Result<Vec<u8>, Error> {
+        let mut ret = vec![0; len];
+        if self.input.read_exact(&mut ret).is_err() {
+            return self.error("the dump ended unexpectedly");
+        }
+        self.line_no += ret.iter().filter(|&&b| b == b'\n').count();
+        Ok(ret)
+    }
+
+    // Reads the next record, or returns `None` at the end of the dump.
+    fn record(&mut self) -> Result<Option<Record>, Error> {
+        // Records are separated by empty lines.
+        let mut line = String::new();
+        while line.is_empty() {
+            line = match self.next_line()? {
+                Some(line) => line,
+                None => return Ok(None),
+            };
+        }
+        let start = self.line_no;
+        let mut headers = BTreeMap::new();
+        while !line.is_empty() {
+            match line.find(": ") {
+                Some(i) => headers.insert(line[..i].to_owned(), line[i + 2..].to_owned()),
+                None => return self.error(&format!("invalid header {:?}", line)),
+            };
+            line = self.next_line()?.unwrap_or_default();
+        }
+
+        let length = |name: &str| -> Result<Option<usize>, Error> {
+            match headers.get(name).map(|l| l.parse()) {
+                Some(Ok(len)) => Ok(Some(len)),
+                Some(Err(_)) => Err(Error::SvnDump(start, format!("invalid {}", name))),
+                None => Ok(None),
+            }
+        };
+        let prop_len = length("Prop-content-length")?;
+        let text_len = length("Text-content-length")?;
+        let props = match prop_len {
+            Some(len) => {
+                let data = self.read_exact(len)?;
+                let delta = headers.get("Prop-delta").map(|s| s.as_str()) == Some("true");
+                match parse_props(&data, delta) {
+                    Some(props) => Some(props),
+                    None => return Err(Error::SvnDump(start, "invalid properties".to_owned())),
+                }
+            }
+            None => None,
+        };
+        let text = match text_len {
+            Some(len) => Some(self.read_exact(len)?),
+            None => None,
+        };
+        Ok(Some(Record {
+            line: start,
+            headers,
+            props,
+            text,
+        }))
+    }
+}
+
+// Parses a block of properties, which is a list of `K <len>\n<key>\nV <len>\n<value>\n` entries
+// (and `D <len>\n<key>\n` for deleting a property in a delta), followed by `PROPS-END\n`.
+fn parse_props(mut data: &[u8], delta: bool) -> Option<Props> {
+    fn take<'a>(data: &mut &'a [u8], prefix: &str) -> Option<&'a [u8]> {
+        let newline = data.iter().position(|&b| b == b'\n')?;
+        let len = std::str::from_utf8(&data[..newline])
+            .ok()?
+            .strip_prefix(prefix)?
+            .parse::<usize>()
+            .ok()?;
+        let rest = &data[newline + 1..];
+        if rest.get(len) != Some(&b'\n') {
+            return None;
+        }
+        *data = &rest[len + 1..];
+        Some(&rest[..len])
+    }
+
+    let mut ret = Props {
+        delta,
+        ..Props::default()
+    };
+    loop {
+        if data.starts_with(b"PROPS-END\n") || data == b"PROPS-END" {
+            return Some(ret);
+        }
+        if data.starts_with(b"D ") {
+            let key = take(&mut data, "D ")?;
+            ret.deleted
+                .insert(String::from_utf8_lossy(key).into_owned());
+        } else {
+            let key = String::from_utf8_lossy(take(&mut data, "K ")?).into_owned();
+            let value = take(&mut data, "V ")?;
+            ret.set.insert(key, value.to_owned());
+        }
+    }
+}
+
+// The standard layout has a directory for each branch and tag. In a dump that doesn't use it, the
+// whole repository is one branch.
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum Layout {
+    Standard,
+    Single,
+}
+
+// Splits a path into the directory of the branch or tag that it belongs to, and the path inside
+// that directory. Paths outside of the branches (like the `branches` directory itself) don't have
+// one.
+fn split_branch(layout: Layout, path: &str) -> Option<(String, String)> {
+    if layout == Layout::Single {
+        return Some((String::new(), path.to_owned()));
+    }
+    let mut parts = path.splitn(3, '/');
+    let (root, rest) = match parts.next()? {
+        "trunk" => {
+            let rest = path["trunk".len()..].trim_start_matches('/');
+            return Some(("trunk".to_owned(), rest.to_owned()));
+        }
+        dir @ "branches" | dir @ "tags" => (format!("{}/{}", dir, parts.next()?), parts.next()),
+        _ => return None,
+    };
+    Some((root, rest.unwrap_or("").to_owned()))
+}
+
+// Returns the files of `tree` that are inside the directory `dir` (or all of them, if `dir` is
+// empty), with paths relative to it.
+fn subtree(tree: &Tree, dir: &str) -> Tree {
+    if dir.is_empty() {
+        return tree.clone();
+    }
+    let prefix = format!("{}/", dir);
+    let mut ret = tree
+        .iter()
+        .filter_map(|(p, f)| Some((p.strip_prefix(&prefix)?.to_owned(), f.clone())))
+        .collect::<Tree>();
+    if let Some(file) = tree.get(dir) {
+        ret.insert(String::new(), file.clone());
+    }
+    ret
+}
+
+fn join(dir: &str, path: &str) -> String {
+    match (dir.is_empty(), path.is_empty()) {
+        (true, _) => path.to_owned(),
+        (_, true) => dir.to_owned(),
+        _ => format!("{}/{}", dir, path),
+    }
+}
+
+// Removes a file or a directory from a tree.
+fn remove(tree: &mut Tree, path: &str) {
+    if path.is_empty() {
+        tree.clear();
+        return;
+    }
+    tree.remove(path);
+    let prefix = format!("{}/", path);
+    tree.retain(|p, _| !p.starts_with(&prefix));
+}
+
+// A branch as it was after some revision, or `None` if the revision deleted it.
+type Snapshot = (u64, Option<Rc<Branch>>);
+
+struct Importer<'a, R> {
+    repo: &'a mut Repo,
+    parser: Parser<R>,
+    config: Config,
+    layout: Option<Layout>,
+    // The branches as they are now.
+    branches: BTreeMap<String, Branch>,
+    // The branches as they were after each revision that changed them, for looking up the sources
+    // of copies.
+    history: BTreeMap<String, Vec<Snapshot>>,
+    // The authors and messages of the revisions that made the tags.
+    tag_headers: BTreeMap<String, (String, String)>,
+    // The patches that are on the scratch branch.
+    scratch: BTreeSet<PatchId>,
+    ret: SvnImport,
+}
+
+// A revision that is being imported.
+struct Revision {
+    number: u64,
+    author: String,
+    date: DateTime<Utc>,
+    log: String,
+    // The branches that this revision changed, with the files that it renamed in them (indexed
+    // by their new paths).
+    touched: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl<R: BufRead> Importer<'_, R> {
+    fn run(mut self) -> Result<SvnImport, Error> {
+        self.repo.create_branch(SCRATCH_BRANCH)?;
+        let mut rev = None;
+        while let Some(record) = self.parser.record()? {
+            if let Some(version) = record.header("SVN-fs-dump-format-version") {
+                if version != "2" && version != "3" {
+                    return record.error(&format!("unsupported dump format version {}", version));
+                }
+            } else if let Some(number) = record.header("Revision-number") {
+                if let Some(rev) = rev.take() {
+                    self.finish_revision(rev)?;
+                }
+                rev = Some(self.revision(&record, number)?);
+            } else if let Some(path) = record.header("Node-path") {
+                let path = path.to_owned();
+                match rev.as_mut() {
+                    Some(rev) => self.node(rev, &path, &record)?,
+                    None => return record.error("a node must be inside a revision"),
+                }
+            }
+            // Anything else (like the UUID of the repository) is ignored.
+        }
+        if let Some(rev) = rev {
+            self.finish_revision(rev)?;
+        }
+        self.finish()
+    }
+
+    fn revision(&mut self, record: &Record, number: &str) -> Result<Revision, Error> {
+        let number = match number.parse() {
+            Ok(number) => number,
+            Err(_) => return record.error("invalid revision number"),
+        };
+        let props = record.props.as_ref();
+        let prop = |name: &str| {
+            props
+                .and_then(|p| p.set.get(name))
+                .map(|v| String::from_utf8_lossy(v).into_owned())
+                .unwrap_or_default()
+        };
+        let date = match prop("svn:date") {
+            d if d.is_empty() => Utc::now(),
+            d => match DateTime::parse_from_rfc3339(&d) {
+                Ok(date) => date.with_timezone(&Utc),
+                Err(_) => return record.error(&format!("invalid date {:?}", d)),
+            },
+        };
+        Ok(Revision {
+            number,
+            author: prop("svn:author"),
+            date,
+            log: prop("svn:log").trim_end().to_owned(),
+            touched: BTreeMap::new(),
+        })
+    }
+
+    // Finds the files of the branch or tag that `path` belonged to at revision `rev`, along with
+    // the branch's directory and the path inside it.
+    fn source(&self, path: &str, rev: u64) -> Option<(Rc<Branch>, String, String)> {
+        let (root, inner) = split_branch(self.layout?, path)?;
+        let (_, branch) = self
+            .history
+            .get(&root)?
+            .iter()
+            .rev()
+            .find(|(r, _)| *r <= rev)?;
+        Some((branch.clone()?, root, inner))
+    }
+
+    fn node(&mut self, rev: &mut Revision, path: &str, record: &Record) -> Result<(), Error> {
+        let layout = *self
+            .layout
+            .get_or_insert_with(|| match path.split('/').next() {
+                Some("trunk") | Some("branches") | Some("tags") => Layout::Standard,
+                _ => Layout::Single,
+            });
+        let (root, inner) = match split_branch(layout, path) {
+            Some(split) => split,
+            // The `branches` and `tags` directories themselves, and anything outside of the
+            // standard layout, aren't part of any branch.
+            None => return Ok(()),
+        };
+        if record.header("Text-delta") == Some("true") {
+            return record.error("deltas aren't supported (dump the repository without --deltas)");
+        }
+        let action = record.header("Node-action").unwrap_or("");
+        let is_dir = record.header("Node-kind") == Some("dir");
+
+        if action == "delete" || action == "replace" {
+            if inner.is_empty() {
+                self.branches.remove(&root);
+            } else if let Some(branch) = self.branches.get_mut(&root) {
+                remove(&mut branch.files, &inner);
+            }
+            rev.touched.entry(root.clone()).or_default();
+            if action == "delete" {
+                return Ok(());
+            }
+        }
+        if action != "add" && action != "change" && action != "replace" {
+            return record.error(&format!("unknown action {:?}", action));
+        }
+
+        let copy = match (
+            record.header("Node-copyfrom-path"),
+            record.header("Node-copyfrom-rev"),
+        ) {
+            (Some(from), Some(from_rev)) => {
+                let from_rev = match from_rev.parse() {
+                    Ok(r) => r,
+                    Err(_) => return record.error("invalid copy revision"),
+                };
+                match self.source(from, from_rev) {
+                    Some(source) => Some(source),
+                    None => return record.error(&format!("can't copy from {}@{}", from, from_rev)),
+                }
+            }
+            _ => None,
+        };
+
+        // A copy of a whole branch shares its patches.
+        if inner.is_empty() && action != "change" {
+            let branch = match &copy {
+                Some((source, _, source_inner)) if source_inner.is_empty() => (**source).clone(),
+                Some((source, _, source_inner)) => Branch {
+                    files: subtree(&source.files, source_inner),
+                    patches: BTreeSet::new(),
+                },
+                None => Branch::default(),
+            };
+            if root.starts_with("tags/") {
+                self.tag_headers
+                    .insert(root.clone(), (rev.author.clone(), rev.log.clone()));
+            }
+            self.branches.insert(root.clone(), branch);
+            rev.touched.entry(root).or_default();
+            return Ok(());
+        }
+
+        if layout == Layout::Single {
+            // The root of the repository is always there.
+            self.branches.entry(root.clone()).or_default();
+        }
+        let renames = rev.touched.entry(root.clone()).or_default();
+        let branch = match self.branches.get_mut(&root) {
+            Some(branch) => branch,
+            None => return record.error(&format!("{} isn't in a directory", path)),
+        };
+        if let Some((source, source_root, source_inner)) = &copy {
+            let copied = subtree(&source.files, source_inner);
+            for (p, file) in copied {
+                let to = join(&inner, &p);
+                // Keep track of files that might have been renamed, in case their originals get
+                // deleted.
+                if source_root == &root {
+                    renames.insert(to.clone(), join(source_inner, &p));
+                }
+                branch.files.insert(to, file);
+            }
+        }
+        if is_dir {
+            return Ok(());
+        }
+
+        let mut file = branch.files.get(&inner).cloned().unwrap_or(GitFile {
+            contents: Rc::from(&b""[..]),
+            executable: false,
+            symlink: false,
+        });
+        if let Some(props) = &record.props {
+            let has = |name: &str| {
+                if props.set.contains_key(name) {
+                    Some(true)
+                } else if props.deleted.contains(name) || !props.delta {
+                    Some(false)
+                } else {
+                    None
+                }
+            };
+            file.executable = has("svn:executable").unwrap_or(file.executable);
+            file.symlink = has("svn:special").unwrap_or(file.symlink);
+        }
+        if let Some(text) = &record.text {
+            // The text of a symbolic link is "link TARGET".
+            let text = match text.strip_prefix(b"link ") {
+                Some(target) if file.symlink => target,
+                _ => &text[..],
+            };
+            file.contents = Rc::from(text);
+        }
+        branch.files.insert(inner, file);
+        Ok(())
+    }
+
+    // Makes the patches for a revision.
+    fn finish_revision(&mut self, rev: Revision) -> Result<(), Error> {
+        for (root, renames) in rev.touched {
+            let mut branch = match self.branches.remove(&root) {
+                Some(branch) => branch,
+                None => {
+                    self.history
+                        .entry(root)
+                        .or_default()
+                        .push((rev.number, None));
+                    continue;
+                }
+            };
+            // A file that was copied is only renamed if its original is gone.
+            let renames = renames
+                .into_iter()
+                .filter(|(_, from)| !branch.files.contains_key(from))
+                .collect();
+
+            self.set_scratch(&branch.patches)?;
+            let changes = git_import::changes(
+                self.repo,
+                &self.config,
+                SCRATCH_BRANCH,
+                &branch.files,
+                &renames,
+            )?;
+            if !changes.changes.is_empty() {
+                let mut header = PatchHeader::new(rev.author.clone(), rev.log.clone());
+                header.timestamp = rev.date;
+                header
+                    .extra
+                    .insert(SVN_REVISION_KEY.to_owned(), rev.number.to_string());
+                let patch = self.repo.create_patch_with_header(header, changes)?;
+                self.repo
+                    .apply_without_hooks(SCRATCH_BRANCH, &patch, ApplyPolicy::Refuse)?;
+                self.scratch.insert(patch);
+                branch.patches.insert(patch);
+                self.ret.patches.push((rev.number, patch));
+            }
+
+            self.history
+                .entry(root.clone())
+                .or_default()
+                .push((rev.number, Some(Rc::new(branch.clone()))));
+            self.branches.insert(root, branch);
+        }
+        Ok(())
+    }
+
+    // Puts exactly the given patches on the scratch branch.
+    fn set_scratch(&mut self, patches: &BTreeSet<PatchId>) -> Result<(), Error> {
+        if !self.scratch.is_subset(patches) {
+            self.repo.delete_branch(SCRATCH_BRANCH)?;
+            self.repo.create_branch(SCRATCH_BRANCH)?;
+            self.scratch.clear();
+        }
+        let missing = patches
+            .difference(&self.scratch)
+            .cloned()
+            .collect::<Vec<_>>();
+        for p in self.repo.patch_graph().apply_order(&missing) {
+            self.repo
+                .apply_without_hooks(SCRATCH_BRANCH, &p, ApplyPolicy::Refuse)?;
+        }
+        self.scratch = patches.clone();
+        Ok(())
+    }
+
+    // Puts the patches of the directories that still exist on their branches (or in their tags).
+    // The trunk is the "master" branch.
+    fn finish(mut self) -> Result<SvnImport, Error> {
+        self.repo.delete_branch(SCRATCH_BRANCH)?;
+        for (root, branch) in std::mem::take(&mut self.branches) {
+            if let Some(name) = root.strip_prefix("tags/") {
+                if self.repo.tag(name).is_ok() {
+                    return Err(Error::TagExists(name.to_owned()));
+                }
+                let (author, msg) = self.tag_headers.remove(&root).unwrap_or_default();
+                let mut header = PatchHeader::new(author, msg);
+                header
+                    .extra
+                    .insert(tag::TAG_KEY.to_owned(), name.to_owned());
+                let patches = branch.patches.into_iter().collect();
+                self.repo
+                    .create_unidentified_patch(UnidentifiedPatch::with_deps(header, patches))?;
+                self.ret.tags.push(name.to_owned());
+                continue;
+            }
+
+            let name = root.strip_prefix("branches/").unwrap_or("master");
+            if self.repo.storage.inode(name).is_none() {
+                self.repo.create_branch(name)?;
+            }
+            let missing = branch
+                .patches
+                .iter()
+                .filter(|p| !self.repo.storage.branch_patches.contains(name, p))
+                .cloned()
+                .collect::<Vec<_>>();
+            for p in self.repo.patch_graph().apply_order(&missing) {
+                self.repo
+                    .apply_without_hooks(name, &p, ApplyPolicy::Refuse)?;
+            }
+            self.ret.branches.push(name.to_owned());
+        }
+        Ok(self.ret)
+    }
+}
+
+pub(crate) fn import<R: BufRead>(repo: &mut Repo, input: R) -> Result<SvnImport, Error> {
+    let config = repo.config();
+    repo.transaction(|repo| {
+        let importer = Importer {
+            repo,
+            parser: Parser { input, line_no: 0 },
+            config,
+            layout: None,
+            branches: BTreeMap::new(),
+            history: BTreeMap::new(),
+            tag_headers: BTreeMap::new(),
+            scratch: BTreeSet::new(),
+            ret: SvnImport::default(),
+        };
+        importer.run()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Makes the record of a revision.
+    fn rev(number: u64, author: &str, log: &str) -> String {
+        let props = format!(
+            "K 10\nsvn:author\nV {}\n{}\nK 8\nsvn:date\nV 27\n2019-01-0{}T00:00:00.000000Z\n\
+             K 7\nsvn:log\nV {}\n{}\nPROPS-END\n",
+            author.len(),
+            author,
+            number,
+            log.len(),
+            log
+        );
+        format!(
+            "Revision-number: {}\nProp-content-length: {}\nContent-length: {}\n\n{}\n",
+            number,
+            props.len(),
+            props.len(),
+            props
+        )
+    }
+
+    // Makes the record of a node. `copy` is the path and revision that it's copied from.
+    fn node(
+        path: &str,
+        kind: &str,
+        action: &str,
+        copy: Option<(&str, u64)>,
+        props: &str,
+        text: Option<&str>,
+    ) -> String {
+        let mut ret = format!("Node-path: {}\n", path);
+        if !kind.is_empty() {
+            ret += &format!("Node-kind: {}\n", kind);
+        }
+        ret += &format!("Node-action: {}\n", action);
+        if let Some((from, from_rev)) = copy {
+            ret += &format!(
+                "Node-copyfrom-rev: {}\nNode-copyfrom-path: {}\n",
+                from_rev, from
+            );
+        }
+        let mut content = String::new();
+        if !props.is_empty() {
+            content += props;
+            ret += &format!("Prop-content-length: {}\n", props.len());
+        }
+        if let Some(text) = text {
+            content += text;
+            ret += &format!("Text-content-length: {}\n", text.len());
+        }
+        if !content.is_empty() {
+            ret += &format!("Content-length: {}\n", content.len());
+        }
+        format!("{}\n{}\n\n", ret, content)
+    }
+
+    fn dump() -> String {
+        let exec = "K 14\nsvn:executable\nV 1\n*\nPROPS-END\n";
+        [
+            "SVN-fs-dump-format-version: 2\n\nUUID: 0e5a4c1e-0000-0000-0000-000000000000\n\n"
+                .to_owned(),
+            rev(1, "alice", "Layout"),
+            node("trunk", "dir", "add", None, "", None),
+            node("branches", "dir", "add", None, "", None),
+            node("tags", "dir", "add", None, "", None),
+            rev(2, "alice", "Files"),
+            node(
+                "trunk/a.txt",
+                "file",
+                "add",
+                None,
+                "PROPS-END\n",
+                Some("a\nb\n"),
+            ),
+            node("trunk/bin", "dir", "add", None, "", None),
+            node("trunk/bin/run.sh", "file", "add", None, exec, Some("run\n")),
+            rev(3, "bob", "Branch"),
+            node("branches/side", "dir", "add", Some(("trunk", 2)), "", None),
+            rev(4, "bob", "Change on the side"),
+            node(
+                "branches/side/a.txt",
+                "file",
+                "change",
+                None,
+                "",
+                Some("a\nb\nside\n"),
+            ),
+            rev(5, "alice", "Rename"),
+            node(
+                "trunk/b.txt",
+                "file",
+                "add",
+                Some(("trunk/a.txt", 4)),
+                "",
+                None,
+            ),
+            node("trunk/a.txt", "", "delete", None, "", None),
+            rev(6, "alice", "Release"),
+            node("tags/v1", "dir", "add", Some(("trunk", 5)), "", None),
+            rev(7, "alice", "Outside"),
+            node("README", "file", "add", None, "", Some("hi\n")),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn import() {
+        let mut repo = Repo::init_tmp();
+        let ret = repo.import_svn(dump().as_bytes()).unwrap();
+        let revs = ret.patches.iter().map(|(r, _)| *r).collect::<Vec<_>>();
+        // The layout, the branch and the tag don't need patches.
+        assert_eq!(revs, vec![2, 4, 5]);
+        assert_eq!(ret.branches, vec!["side", "master"]);
+        assert_eq!(ret.tags, vec!["v1"]);
+        let patch = |rev: u64| ret.patches.iter().find(|(r, _)| *r == rev).unwrap().1;
+
+        assert_eq!(
+            repo.files("master").unwrap().collect::<Vec<_>>(),
+            vec!["b.txt", "bin/run.sh"]
+        );
+        assert!(repo.is_executable("master", "bin/run.sh").unwrap());
+        assert_eq!(
+            repo.file_at("master", "b.txt").unwrap().as_bytes(),
+            b"a\nb\n"
+        );
+        assert_eq!(
+            repo.files("side").unwrap().collect::<Vec<_>>(),
+            vec!["a.txt", "bin/run.sh"]
+        );
+        assert_eq!(
+            repo.file_at("side", "a.txt").unwrap().as_bytes(),
+            b"a\nb\nside\n"
+        );
+
+        // The branch shares the history of the trunk.
+        assert_eq!(
+            repo.patch_graph().transitive_deps(&patch(4)),
+            vec![patch(2)]
+        );
+        let header = repo.open_patch(&patch(5)).unwrap().header().clone();
+        assert_eq!(header.author, "alice");
+        assert_eq!(header.description, "Rename");
+        assert_eq!(header.timestamp.to_rfc3339(), "2019-01-05T00:00:00+00:00");
+        assert_eq!(header.extra[SVN_REVISION_KEY], "5");
+
+        let mut tagged = repo.tag("v1").unwrap().patches().to_vec();
+        tagged.sort();
+        let mut expected = vec![patch(2), patch(5)];
+        expected.sort();
+        assert_eq!(tagged, expected);
+        assert_eq!(repo.branches().collect::<Vec<_>>(), vec!["master", "side"]);
+    }
+
+    #[test]
+    fn single_branch() {
+        let dump = [
+            rev(1, "alice", "One"),
+            node("a.txt", "file", "add", None, "", Some("a\n")),
+            rev(2, "alice", "Two"),
+            node("dir", "dir", "add", Some(("", 1)), "", None),
+        ]
+        .concat();
+        let mut repo = Repo::init_tmp();
+        let ret = repo.import_svn(dump.as_bytes()).unwrap();
+        assert_eq!(ret.branches, vec!["master"]);
+        assert_eq!(
+            repo.files("master").unwrap().collect::<Vec<_>>(),
+            vec!["a.txt", "dir/a.txt"]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let mut repo = Repo::init_tmp();
+        match repo.import_svn(&b"SVN-fs-dump-format-version: 1\n\n"[..]) {
+            Err(Error::SvnDump(1, _)) => {}
+            x => panic!("expected a dump error, got {:?}", x),
+        }
+        let dump = [
+            rev(1, "alice", "One"),
+            node("trunk", "dir", "add", None, "", None),
+            node("branches/x", "dir", "add", Some(("trunk", 7)), "", None),
+        ]
+        .concat();
+        match repo.import_svn(dump.as_bytes()) {
+            Err(Error::SvnDump(25, _)) => {}
+            x => panic!("expected a dump error, got {:?}", x),
+        }
+        assert_eq!(repo.branches().collect::<Vec<_>>(), vec!["master"]);
+    }
+}
@@ -0,0 +1,102 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+use crate::{Patch, PatchHeader, PatchId};
+
+// The metadata key that marks a patch as being a tag.
+pub(crate) const TAG_KEY: &str = "tag";
+
+/// A named snapshot of a set of patches.
+///
+/// A tag is really just a special kind of patch: it doesn't make any changes, but it depends on
+/// every patch in the snapshot, and it has the tag's name in its metadata. This means that tags
+/// are stored and exchanged in the same way as patches are, and (since the dependencies are part
+/// of the data that gets hashed) the tag's id is a verifiable reference to exactly that set of
+/// patches. Applying a tag to a branch applies all the patches in the snapshot.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Tag {
+    id: PatchId,
+    name: String,
+    header: PatchHeader,
+    patches: Vec<PatchId>,
+}
+
+impl Tag {
+    /// If the patch is a tag, returns that tag.
+    pub fn from_patch(patch: &Patch) -> Option<Tag> {
+        if !patch.changes().changes.is_empty() {
+            return None;
+        }
+        let name = patch.header().extra.get(TAG_KEY)?;
+        Some(Tag {
+            id: *patch.id(),
+            name: name.clone(),
+            header: patch.header().clone(),
+            patches: patch.deps().to_owned(),
+        })
+    }
+
+    /// The id of the patch representing this tag.
+    pub fn id(&self) -> &PatchId {
+        &self.id
+    }
+
+    /// The name of this tag.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The metadata of this tag.
+    pub fn header(&self) -> &PatchHeader {
+        &self.header
+    }
+
+    /// All of the patches in this tag's snapshot, in sorted order.
+    pub fn patches(&self) -> &[PatchId] {
+        &self.patches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Changes, Error, Repo};
+
+    #[test]
+    fn create_and_apply() {
+        let mut repo = Repo::init_tmp();
+        let mut ids = Vec::new();
+        for contents in &[&b"a\n"[..], b"a\nb\n"] {
+            let diff = repo.diff("master", contents).unwrap();
+            let changes = Changes::from_diff(&diff.file_a, &diff.file_b, &diff.diff);
+            let id = repo.create_patch("Author", "Msg", changes).unwrap();
+            repo.apply_patch("master", &id).unwrap();
+            ids.push(id);
+        }
+        ids.sort();
+
+        let tag_id = repo
+            .create_tag("master", "v1", "Author", "Release")
+            .unwrap();
+        let tag = repo.tag("v1").unwrap();
+        assert_eq!(tag.id(), &tag_id);
+        assert_eq!(tag.patches(), &ids[..]);
+        assert_eq!(repo.tags().unwrap(), vec![tag]);
+
+        match repo.create_tag("master", "v1", "Author", "Release") {
+            Err(Error::TagExists(_)) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+
+        repo.create_branch("other").unwrap();
+        repo.apply_patch("other", &tag_id).unwrap();
+        assert_eq!(repo.file("other").unwrap().as_bytes(), b"a\nb\n");
+    }
+}
@@ -0,0 +1,68 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Helpers that are shared by the tests of several modules.
+
+use std::fs;
+use std::path::PathBuf;
+
+use crate::{Change, Changes, Error, PatchId, Repo};
+
+// Makes a patch that changes the lines of a branch to `contents`, without applying it.
+pub(crate) fn create_unapplied(repo: &mut Repo, branch: &str, contents: &[u8]) -> PatchId {
+    let diff = repo.diff(branch, contents).unwrap();
+    repo.create_patch("Author", "Msg", diff.changes()).unwrap()
+}
+
+fn try_create_as(
+    repo: &mut Repo,
+    branch: &str,
+    author: &str,
+    contents: &[u8],
+) -> Result<PatchId, Error> {
+    let diff = repo.diff(branch, contents)?;
+    let id = repo.create_patch(author, "Msg", diff.changes())?;
+    repo.apply_patch(branch, &id)?;
+    Ok(id)
+}
+
+// Makes a patch that changes the lines of a branch to `contents`, and applies it to the branch.
+pub(crate) fn create(repo: &mut Repo, branch: &str, contents: &[u8]) -> PatchId {
+    try_create(repo, branch, contents).unwrap()
+}
+
+// Like `create`, but returns errors instead of panicking.
+pub(crate) fn try_create(repo: &mut Repo, branch: &str, contents: &[u8]) -> Result<PatchId, Error> {
+    try_create_as(repo, branch, "Author", contents)
+}
+
+// Like `create`, but with the given author.
+pub(crate) fn create_as(repo: &mut Repo, branch: &str, author: &str, contents: &[u8]) -> PatchId {
+    try_create_as(repo, branch, author, contents).unwrap()
+}
+
+// Makes a patch out of some changes, and applies it to a branch.
+pub(crate) fn create_changes(repo: &mut Repo, branch: &str, changes: Vec<Change>) -> PatchId {
+    let id = repo
+        .create_patch("Author", "Msg", Changes { changes })
+        .unwrap();
+    repo.apply_patch(branch, &id).unwrap();
+    id
+}
+
+// Returns a fresh, empty temporary directory. Every test should use a different name, and the
+// process id keeps test runs that overlap from getting in each other's way.
+pub(crate) fn tmp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ojo-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,356 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Converting text files between the form that they have in the working copy and the form that
+// they are recorded in.
+//
+// By default, files are recorded exactly as they are in the working copy. If the settings ask for
+// it (see `Config::text_format`), text files are recorded as UTF-8 with LF line endings, and they
+// are converted back when they are written to the working copy. That way, collaborators whose
+// editors use different line endings don't end up rewriting every line of each other's files.
+// Everything that reads or writes files in the working copy goes through here.
+
+use std::io;
+
+use crate::Repo;
+
+/// How the ends of lines are written in the working copy (see
+/// [`Config::newline`](crate::Config::newline)).
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Newline {
+    /// Lines end with "\n".
+    Lf,
+    /// Lines end with "\r\n".
+    Crlf,
+    /// Lines end with "\r\n" on Windows, and with "\n" everywhere else.
+    Native,
+}
+
+impl Newline {
+    fn is_crlf(self) -> bool {
+        match self {
+            Newline::Lf => false,
+            Newline::Crlf => true,
+            Newline::Native => cfg!(windows),
+        }
+    }
+}
+
+/// The encoding of a text file in the working copy (see
+/// [`Config::encoding`](crate::Config::encoding)).
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Encoding {
+    /// UTF-8, which is how text files are recorded.
+    #[serde(rename = "utf-8")]
+    Utf8,
+    /// Little-endian UTF-16, starting with a byte order mark.
+    #[serde(rename = "utf-16le")]
+    Utf16Le,
+    /// Big-endian UTF-16, starting with a byte order mark.
+    #[serde(rename = "utf-16be")]
+    Utf16Be,
+}
+
+/// Settings that apply to some of the files in the working copy (see
+/// [`Config::text`](crate::Config::text)).
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct TextRule {
+    /// The files that this rule applies to, in the same format as the lines of
+    /// [`IGNORE_FILE`](crate::IGNORE_FILE) (but without negation), relative to the root of the
+    /// working copy.
+    pub pattern: String,
+    /// If this is set, it overrides [`Config::newline`](crate::Config::newline).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub newline: Option<Newline>,
+    /// If this is set, it overrides [`Config::encoding`](crate::Config::encoding).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<Encoding>,
+    /// If this is set, it says whether the files are binary, instead of guessing from their
+    /// contents (see [`Config::is_binary`](crate::Config::is_binary)).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub binary: Option<bool>,
+}
+
+/// How a file gets converted when it moves between the working copy and the repository (see
+/// [`Config::text_format`](crate::Config::text_format)).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TextFormat {
+    /// If this is set, text files are recorded with LF line endings, and they are written to the
+    /// working copy with these line endings.
+    pub newline: Option<Newline>,
+    /// If this is set, UTF-16 files are recorded as UTF-8, and text files are written to the
+    /// working copy in this encoding.
+    pub encoding: Option<Encoding>,
+    /// Whether the file is binary, if the settings say so. Otherwise, this is guessed from the
+    /// file's contents (see [`looks_binary`]). Binary files are never converted.
+    pub binary: Option<bool>,
+}
+
+const BOM_LE: &[u8] = &[0xFF, 0xFE];
+const BOM_BE: &[u8] = &[0xFE, 0xFF];
+
+/// Guesses the encoding of some text.
+///
+/// UTF-16 is recognized by its byte order mark. Anything else is UTF-8 if it's valid UTF-8, and
+/// otherwise it probably isn't text at all, so this returns `None`.
+pub fn detect_encoding(contents: &[u8]) -> Option<Encoding> {
+    if contents.starts_with(BOM_LE) {
+        Some(Encoding::Utf16Le)
+    } else if contents.starts_with(BOM_BE) {
+        Some(Encoding::Utf16Be)
+    } else if std::str::from_utf8(contents).is_ok() {
+        Some(Encoding::Utf8)
+    } else {
+        None
+    }
+}
+
+// Converts UTF-16 (including the byte order mark) to UTF-8, returning `None` if it isn't valid.
+fn decode_utf16(contents: &[u8], little_endian: bool) -> Option<Vec<u8>> {
+    let chunks = contents[2..].chunks_exact(2);
+    if !chunks.remainder().is_empty() {
+        return None;
+    }
+    let units = chunks.map(|c| {
+        if little_endian {
+            u16::from_le_bytes([c[0], c[1]])
+        } else {
+            u16::from_be_bytes([c[0], c[1]])
+        }
+    });
+    std::char::decode_utf16(units)
+        .collect::<Result<String, _>>()
+        .ok()
+        .map(String::into_bytes)
+}
+
+fn encode_utf16(text: &str, little_endian: bool) -> Vec<u8> {
+    let mut ret = if little_endian { BOM_LE } else { BOM_BE }.to_vec();
+    for unit in text.encode_utf16() {
+        if little_endian {
+            ret.extend_from_slice(&unit.to_le_bytes());
+        } else {
+            ret.extend_from_slice(&unit.to_be_bytes());
+        }
+    }
+    ret
+}
+
+// Only the start of a file is looked at when guessing whether it's binary.
+const SNIFF_LEN: usize = 8000;
+
+/// Guesses whether some contents are binary, as opposed to text.
+///
+/// Only the first few kilobytes are looked at. They are binary if they contain a zero byte (unless
+/// they start with a UTF-16 byte order mark), or if more than a tenth of them isn't valid UTF-8.
+pub fn looks_binary(contents: &[u8]) -> bool {
+    let sample = &contents[..contents.len().min(SNIFF_LEN)];
+    if sample.starts_with(BOM_LE) || sample.starts_with(BOM_BE) {
+        return false;
+    }
+    if sample.contains(&0) {
+        return true;
+    }
+
+    let mut invalid = 0;
+    let mut rest = sample;
+    while let Err(e) = std::str::from_utf8(rest) {
+        match e.error_len() {
+            Some(len) => {
+                invalid += len;
+                rest = &rest[e.valid_up_to() + len..];
+            }
+            // The sample might have cut a character in half.
+            None => break,
+        }
+    }
+    invalid * 10 > sample.len()
+}
+
+impl TextFormat {
+    // Can these contents be converted? UTF-16 that wasn't decoded would get mangled by converting
+    // its line endings, so it counts as binary here.
+    fn is_text(&self, contents: &[u8]) -> bool {
+        let utf16 = contents.starts_with(BOM_LE) || contents.starts_with(BOM_BE);
+        !utf16 && !self.binary.unwrap_or_else(|| looks_binary(contents))
+    }
+
+    /// Converts the contents of a file in the working copy into the form in which they are
+    /// recorded.
+    ///
+    /// If an encoding is set, UTF-16 files (see [`detect_encoding`]) become UTF-8. If line endings
+    /// are set, the CRLFs in text files become LFs. Anything else is left alone.
+    pub fn to_stored(&self, mut contents: Vec<u8>) -> Vec<u8> {
+        if self.encoding.is_some() && self.binary != Some(true) {
+            let decoded = match detect_encoding(&contents) {
+                Some(Encoding::Utf16Le) => decode_utf16(&contents, true),
+                Some(Encoding::Utf16Be) => decode_utf16(&contents, false),
+                _ => None,
+            };
+            if let Some(decoded) = decoded {
+                contents = decoded;
+            }
+        }
+        if self.newline.is_some() && self.is_text(&contents) && contents.contains(&b'\r') {
+            let mut ret = Vec::with_capacity(contents.len());
+            for (i, &b) in contents.iter().enumerate() {
+                if b != b'\r' || contents.get(i + 1) != Some(&b'\n') {
+                    ret.push(b);
+                }
+            }
+            contents = ret;
+        }
+        contents
+    }
+
+    /// Converts the recorded contents of a file into the form that they should have in the
+    /// working copy. This is the opposite of [`TextFormat::to_stored`].
+    pub fn to_working(&self, mut contents: Vec<u8>) -> Vec<u8> {
+        if self.newline.is_some_and(Newline::is_crlf) && self.is_text(&contents) {
+            let mut ret = Vec::with_capacity(contents.len());
+            for (i, &b) in contents.iter().enumerate() {
+                // Lines that were recorded with CRLFs already have them.
+                if b == b'\n' && (i == 0 || contents[i - 1] != b'\r') {
+                    ret.push(b'\r');
+                }
+                ret.push(b);
+            }
+            contents = ret;
+        }
+        if !self.is_text(&contents) {
+            return contents;
+        }
+        let little_endian = match self.encoding {
+            Some(Encoding::Utf16Le) => true,
+            Some(Encoding::Utf16Be) => false,
+            _ => return contents,
+        };
+        match std::str::from_utf8(&contents) {
+            Ok(text) => encode_utf16(text, little_endian),
+            Err(_) => contents,
+        }
+    }
+}
+
+// Reads a file in the working copy, and converts it to the form in which it would be recorded.
+pub(crate) fn read_working(repo: &Repo, path: &str) -> io::Result<Vec<u8>> {
+    let contents = crate::working::read(repo, path)?;
+    // The target of a symbolic link isn't text to be converted.
+    if crate::working::is_symlink(repo, path) {
+        return Ok(contents);
+    }
+    Ok(repo.config().text_format(path).to_stored(contents))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RecordOptions, DEFAULT_WORKING_FILE};
+    use std::fs;
+
+    const CRLF: TextFormat = TextFormat {
+        newline: Some(Newline::Crlf),
+        encoding: None,
+        binary: None,
+    };
+
+    #[test]
+    fn newlines() {
+        assert_eq!(CRLF.to_stored(b"a\r\nb\n\r".to_vec()), b"a\nb\n\r");
+        assert_eq!(CRLF.to_working(b"a\nb\r\n".to_vec()), b"a\r\nb\r\n");
+
+        // Binary files are left alone.
+        assert_eq!(CRLF.to_stored(b"a\r\n\0".to_vec()), b"a\r\n\0");
+        assert_eq!(CRLF.to_working(b"a\n\0".to_vec()), b"a\n\0");
+
+        // So is everything, if nothing is set.
+        let none = TextFormat::default();
+        assert_eq!(none.to_stored(b"a\r\n".to_vec()), b"a\r\n");
+    }
+
+    #[test]
+    fn encodings() {
+        let le = b"\xFF\xFEa\0\r\0\n\0".to_vec();
+        let be = b"\xFE\xFF\0a\0\r\0\n".to_vec();
+        assert_eq!(detect_encoding(&le), Some(Encoding::Utf16Le));
+        assert_eq!(detect_encoding(&be), Some(Encoding::Utf16Be));
+        assert_eq!(detect_encoding(b"a\n"), Some(Encoding::Utf8));
+        assert_eq!(detect_encoding(b"\xFF\n"), None);
+
+        let format = TextFormat {
+            newline: Some(Newline::Crlf),
+            encoding: Some(Encoding::Utf16Le),
+            binary: None,
+        };
+        assert_eq!(format.to_stored(le.clone()), b"a\n");
+        assert_eq!(format.to_stored(be), b"a\n");
+        assert_eq!(format.to_working(b"a\n".to_vec()), le);
+
+        // UTF-16 is only decoded if an encoding is set, and otherwise it's left alone.
+        assert_eq!(CRLF.to_stored(le.clone()), le);
+        assert_eq!(CRLF.to_working(le.clone()), le);
+    }
+
+    #[test]
+    fn binary() {
+        assert!(!looks_binary(b"a\nb\n"));
+        assert!(!looks_binary("caf\u{e9}\n".as_bytes()));
+        assert!(!looks_binary(b"\xFF\xFEa\0\n\0"));
+        assert!(looks_binary(b"\x89PNG\r\n\x1a\n\0\0"));
+        assert!(looks_binary(b"\xFF\xD8\xFF\xE0ab"));
+        // A few stray bytes don't make a file binary.
+        assert!(!looks_binary(b"Latin-1 caf\xE9, plus a lot more text.\n"));
+
+        // Binary files aren't converted, unless the settings say they're text.
+        assert_eq!(CRLF.to_working(b"\xFF\xD8\n".to_vec()), b"\xFF\xD8\n");
+        let text = TextFormat {
+            binary: Some(false),
+            ..CRLF
+        };
+        assert_eq!(text.to_working(b"\xFF\xD8\n".to_vec()), b"\xFF\xD8\r\n");
+    }
+
+    #[test]
+    fn record_and_checkout() {
+        let mut repo = Repo::init_tmp_with_working_copy("text-record-and-checkout");
+        let path = repo.root_dir.join(DEFAULT_WORKING_FILE);
+        repo.config.newline = Some(Newline::Lf);
+        repo.config.text.push(TextRule {
+            pattern: "*.bat".to_owned(),
+            newline: Some(Newline::Crlf),
+            encoding: None,
+            binary: None,
+        });
+        fs::write(&path, b"a\r\nb\r\n").unwrap();
+        fs::write(repo.root_dir.join("run.bat"), b"c\r\n").unwrap();
+        repo.track_file("run.bat").unwrap();
+        repo.record("Author", "Msg", &RecordOptions::default())
+            .unwrap();
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"a\nb\n");
+        assert_eq!(
+            repo.file_at("master", "run.bat").unwrap().as_bytes(),
+            b"c\n"
+        );
+        assert!(repo
+            .status()
+            .unwrap()
+            .values()
+            .all(|s| *s == crate::FileStatus::Clean));
+
+        repo.create_branch("other").unwrap();
+        repo.checkout("other").unwrap();
+        repo.checkout("master").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"a\nb\n");
+        assert_eq!(fs::read(repo.root_dir.join("run.bat")).unwrap(), b"c\r\n");
+        fs::remove_dir_all(&repo.root_dir).unwrap();
+    }
+}
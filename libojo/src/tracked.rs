@@ -0,0 +1,852 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// The files that the working copy tracks.
+//
+// These start out as the files on the current branch, but they can be added, removed and moved in
+// the working copy before those changes are recorded. We remember those operations (relative to
+// the files on the current branch) until they get recorded (see `Repo::record`), which turns them
+// into `NewFile`, `DeleteFile` and `MoveFile` changes, or until another branch is checked out.
+// Since the files on the branch keep their identities when they are moved, so do the tracked
+// files.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+
+use crate::ignore::is_ignored;
+use crate::status::FileStatus;
+use crate::text::read_working;
+use crate::working;
+use crate::{
+    BlobHash, Change, Config, Diff, DiffOptions, Error, File, FileRef, LineDiff, PatchId, Repo,
+    DEFAULT_WORKING_FILE,
+};
+
+// The operations on the tracked files that haven't been recorded yet.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub(crate) struct TrackedFiles {
+    // The files that were added, and aren't on the branch.
+    #[serde(default)]
+    added: BTreeSet<String>,
+    // The files on the branch that were removed, by their paths on the branch.
+    #[serde(default)]
+    removed: BTreeSet<String>,
+    // The files on the branch that were moved, indexed by their new paths. The values are their
+    // paths on the branch.
+    #[serde(default)]
+    moved: BTreeMap<String, String>,
+}
+
+impl TrackedFiles {
+    pub fn added(&self) -> impl Iterator<Item = &str> {
+        self.added.iter().map(|s| s.as_str())
+    }
+
+    pub fn removed(&self) -> impl Iterator<Item = &str> {
+        self.removed.iter().map(|s| s.as_str())
+    }
+
+    // Returns the moved files, as pairs of their new paths and their paths on the branch.
+    pub fn moved(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.moved
+            .iter()
+            .map(|(to, from)| (to.as_str(), from.as_str()))
+    }
+
+    // The following methods forget operations once they have been recorded.
+
+    pub fn forget_added(&mut self, path: &str) {
+        self.added.remove(path);
+    }
+
+    pub fn forget_removed(&mut self, path: &str) {
+        self.removed.remove(path);
+    }
+
+    // The move might also have been detected from an added file and a removed one (see
+    // `detect_renames`), in which case those are forgotten instead.
+    pub fn forget_moved(&mut self, from: &str, to: &str) {
+        if self.moved.get(to).map(|f| f == from).unwrap_or(false) {
+            self.moved.remove(to);
+        } else {
+            self.added.remove(to);
+            self.removed.remove(from);
+        }
+    }
+}
+
+// Returns the tracked files, indexed by their paths in the working copy. Each one comes with its
+// path on the current branch, or `None` if it was added.
+pub(crate) fn tracked(repo: &Repo) -> Result<BTreeMap<String, Option<String>>, Error> {
+    tracked_with(repo, &repo.storage.tracked)
+}
+
+// Like `tracked`, but with some other operations instead of the ones in the repository.
+fn tracked_with(repo: &Repo, t: &TrackedFiles) -> Result<BTreeMap<String, Option<String>>, Error> {
+    let moved_away = t.moved.values().collect::<BTreeSet<_>>();
+    let mut ret = BTreeMap::new();
+    for path in repo.files(&repo.current_branch)? {
+        let path = path.to_owned();
+        if !t.removed.contains(&path) && !moved_away.contains(&path) {
+            ret.insert(path.clone(), Some(path));
+        }
+    }
+    for (to, from) in &t.moved {
+        ret.insert(to.clone(), Some(from.clone()));
+    }
+    for path in &t.added {
+        ret.insert(path.clone(), None);
+    }
+    Ok(ret)
+}
+
+// Checks that a path is relative to the root of the working copy, and that it doesn't go
+// anywhere funny.
+fn check_path(path: &str) -> Result<(), Error> {
+    let valid = !path.starts_with('/')
+        && path
+            .split('/')
+            .all(|c| !c.is_empty() && c != "." && c != "..")
+        && path.split('/').next() != Some(".ojo");
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidPath(path.to_owned()))
+    }
+}
+
+// The branch's own lines live in the default working file, so it can't be tracked as a file.
+fn check_untracked(repo: &Repo, path: &str) -> Result<(), Error> {
+    if path == DEFAULT_WORKING_FILE || tracked(repo)?.contains_key(path) {
+        Err(Error::AlreadyTracked(path.to_owned()))
+    } else {
+        Ok(())
+    }
+}
+
+pub(crate) fn add(repo: &mut Repo, path: &str) -> Result<(), Error> {
+    check_path(path)?;
+    check_untracked(repo, path)?;
+    if !working::is_file(repo, path) {
+        return Err(Error::UnknownFile(path.to_owned()));
+    }
+    if is_ignored(repo, path)? {
+        return Err(Error::IgnoredPath(path.to_owned()));
+    }
+    let t = &mut repo.storage.tracked;
+    // If the file was on the branch all along, adding it back just undoes its removal.
+    if !t.removed.remove(path) {
+        t.added.insert(path.to_owned());
+    }
+    Ok(())
+}
+
+pub(crate) fn remove(repo: &mut Repo, path: &str) -> Result<(), Error> {
+    let origin = tracked(repo)?
+        .remove(path)
+        .ok_or_else(|| Error::UnknownFile(path.to_owned()))?;
+    let t = &mut repo.storage.tracked;
+    match origin {
+        None => {
+            t.added.remove(path);
+        }
+        Some(orig) => {
+            t.moved.remove(path);
+            t.removed.insert(orig);
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn move_file(repo: &mut Repo, from: &str, to: &str) -> Result<(), Error> {
+    check_path(to)?;
+    let origin = tracked(repo)?
+        .remove(from)
+        .ok_or_else(|| Error::UnknownFile(from.to_owned()))?;
+    check_untracked(repo, to)?;
+
+    if working::exists(repo, from) {
+        working::rename(repo, from, to)
+            .map_err(|e| Error::Io(e, format!("Could not move {} to {}", from, to)))?;
+    }
+
+    let t = &mut repo.storage.tracked;
+    match origin {
+        None => {
+            t.added.remove(from);
+            t.added.insert(to.to_owned());
+        }
+        Some(orig) => {
+            t.moved.remove(from);
+            if orig != to {
+                t.moved.insert(to.to_owned(), orig);
+            }
+        }
+    }
+    Ok(())
+}
+
+// The contents of a file on the branch, as they are compared with the working copy when looking
+// for renames.
+enum Compared {
+    Lines(File),
+    Binary(BlobHash),
+}
+
+// Returns the percentage of lines that two files have in common. Binary files are only similar to
+// identical ones.
+fn similarity(old: &Compared, new: &[u8], new_binary: bool, opts: &DiffOptions) -> usize {
+    let old = match old {
+        Compared::Binary(hash) => return if BlobHash::of(new) == *hash { 100 } else { 0 },
+        Compared::Lines(_) if new_binary => return 0,
+        Compared::Lines(old) => old.clone(),
+    };
+    let diff = Diff::new(old, new, opts);
+    let total = diff.file_a.num_nodes() + diff.file_b.num_nodes();
+    if total == 0 {
+        return 0;
+    }
+    let kept = diff
+        .diff
+        .iter()
+        .filter(|d| matches!(d, LineDiff::Keep(..)))
+        .count();
+    200 * kept / total
+}
+
+// Looks for added files that are similar enough to removed ones (see `Config::rename_threshold`),
+// and turns them into moves.
+fn detect_renames(repo: &Repo, opts: &DiffOptions) -> Result<TrackedFiles, Error> {
+    let mut ret = repo.storage.tracked.clone();
+    let config = repo.config();
+    let threshold = config.rename_threshold() as usize;
+    if threshold > 100 || ret.added.is_empty() || ret.removed.is_empty() {
+        return Ok(ret);
+    }
+
+    let branch = &repo.current_branch;
+    let mut candidates = Vec::new();
+    for path in &ret.removed {
+        let compared = match repo.file_graggle(branch, path)?.binary() {
+            Some(blob) => Compared::Binary(blob.hash),
+            // Files with conflicts can't be compared, so they don't get renamed.
+            None => match repo.file_at(branch, path) {
+                Ok(file) => Compared::Lines(file),
+                Err(_) => continue,
+            },
+        };
+        candidates.push((path.clone(), compared));
+    }
+    for added in ret.added.clone() {
+        let contents = match read_working(repo, &added) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(Error::Io(e, format!("Could not read the file {}", added))),
+        };
+        let binary = config.is_binary(&added, &contents);
+        let best = candidates
+            .iter()
+            .enumerate()
+            .map(|(i, (_, old))| (similarity(old, &contents, binary, opts), i))
+            .filter(|&(sim, _)| sim >= threshold && sim > 0)
+            // Ties go to the first candidate, because `max_by_key` prefers the last one.
+            .max_by_key(|&(sim, i)| (sim, std::cmp::Reverse(i)));
+        if let Some((_, i)) = best {
+            let (removed, _) = candidates.remove(i);
+            ret.removed.remove(&removed);
+            ret.added.remove(&added);
+            ret.moved.insert(added, removed);
+        }
+    }
+    Ok(ret)
+}
+
+// The changes that would make the files on the current branch match the tracked files in the
+// working copy. The tracked files that are missing from the working copy are left alone.
+pub(crate) struct TrackedChanges {
+    // The operations on the files, where added files that are similar to removed ones have become
+    // moves (see `Config::rename_threshold`), so that they keep their history.
+    pub ops: TrackedFiles,
+    // The changed text files, by their paths in the working copy. Each one comes with the file
+    // that it changes (which is staged, if the file was added) and its diff against that file.
+    pub diffs: Vec<(String, FileRef, Diff)>,
+    // The changed binary files (see `Config::is_binary`), by their paths in the working copy. Each
+    // one comes with the file that it changes and the `BinaryReplace` (or `ChunkedReplace`) that
+    // changes it.
+    pub binaries: Vec<(String, FileRef, Change)>,
+}
+
+pub(crate) fn tracked_changes(repo: &Repo, opts: &DiffOptions) -> Result<TrackedChanges, Error> {
+    let branch = &repo.current_branch;
+    let config = repo.config();
+    let ops = detect_renames(repo, opts)?;
+    let mut diffs = Vec::new();
+    let mut binaries = Vec::new();
+    for (path, origin) in tracked_with(repo, &ops)? {
+        let contents = match read_working(repo, &path) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(Error::Io(e, format!("Could not read the file {}", path))),
+        };
+        let (file, old_blob) = match origin {
+            Some(ref orig) => (
+                repo.file_id(branch, orig)?,
+                repo.file_graggle(branch, orig)?.binary(),
+            ),
+            None => (
+                FileRef {
+                    patch: PatchId::staging(),
+                    path: path.clone(),
+                },
+                None,
+            ),
+        };
+
+        // Once a file has binary contents, it stays binary.
+        if old_blob.is_some() || config.is_binary(&path, &contents) {
+            if old_blob.map(|b| b.hash) != Some(BlobHash::of(&contents)) {
+                let change = repo.binary_replace(old_blob, contents);
+                binaries.push((path, file, change));
+            }
+            continue;
+        }
+        let old = match origin {
+            Some(orig) => repo.file_at(branch, &orig)?,
+            None => File::from_bytes(b""),
+        };
+        let diff = Diff::new(old, &contents, opts);
+        if diff.diff.iter().any(|d| !matches!(d, LineDiff::Keep(..))) {
+            diffs.push((path, file, diff));
+        }
+    }
+    Ok(TrackedChanges {
+        ops,
+        diffs,
+        binaries,
+    })
+}
+
+// Removes a file from the working copy, along with any directories that it leaves empty.
+fn remove_working_file(repo: &Repo, path: &str) -> Result<(), Error> {
+    match working::remove(repo, path) {
+        Ok(()) => Ok(()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(Error::Io(e, format!("Could not remove {}", path))),
+    }
+}
+
+// Checks that checking out another branch wouldn't throw away anything in the working copy that
+// hasn't been recorded.
+pub(crate) fn check_clean(repo: &Repo, branch: &str) -> Result<(), Error> {
+    let dirty = |path: &str| Err(Error::UnrecordedChanges(path.to_owned()));
+    if let Some(path) = repo.storage.tracked.added.iter().next() {
+        return dirty(path);
+    }
+    if let Some(path) = repo.storage.tracked.removed.iter().next() {
+        return dirty(path);
+    }
+    if let Some(path) = repo.storage.tracked.moved.keys().next() {
+        return dirty(path);
+    }
+
+    let status = repo.status()?;
+    for (path, status) in &status {
+        match *status {
+            FileStatus::Modified => return dirty(path),
+            FileStatus::Conflicted(_) => {
+                // Conflicted files are rendered with conflict markers, and they only need to be
+                // kept if someone has started resolving them.
+                let mut rendered = Vec::new();
+                if path == DEFAULT_WORKING_FILE {
+                    repo.render(&repo.current_branch, &mut rendered)?;
+                } else {
+                    repo.render_file(&repo.current_branch, path, &mut rendered)?;
+                }
+                if read_working(repo, path)? != rendered {
+                    return dirty(path);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Untracked files would get overwritten by the files on the new branch.
+    let mut new_paths = repo.files(branch)?.collect::<Vec<_>>();
+    if repo.graggle(branch)?.nodes().next().is_some() {
+        new_paths.push(DEFAULT_WORKING_FILE);
+    }
+    for path in new_paths {
+        if status.get(path) == Some(&FileStatus::Untracked) {
+            return dirty(path);
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn checkout(repo: &mut Repo, branch: &str) -> Result<(), Error> {
+    if repo.storage.inode(branch).is_none() {
+        return Err(Error::UnknownBranch(branch.to_owned()));
+    }
+    check_clean(repo, branch)?;
+
+    let old_paths = branch_paths(repo, &repo.current_branch)?;
+    write_branch(repo, branch, &old_paths)?;
+    repo.current_branch = branch.to_owned();
+    repo.storage.tracked = TrackedFiles::default();
+    Ok(())
+}
+
+// Returns the paths in the working copy that a branch has: its files, and the default working
+// file if it has any lines.
+pub(crate) fn branch_paths(repo: &Repo, branch: &str) -> Result<BTreeSet<String>, Error> {
+    let mut ret = repo
+        .files(branch)?
+        .map(|p| p.to_owned())
+        .collect::<BTreeSet<_>>();
+    if repo.graggle(branch)?.nodes().next().is_some() {
+        ret.insert(DEFAULT_WORKING_FILE.to_owned());
+    }
+    Ok(ret)
+}
+
+// Returns the contents that the file at `path` on a branch should have in the working copy.
+fn working_contents(
+    repo: &Repo,
+    branch: &str,
+    path: &str,
+    config: &Config,
+) -> Result<Vec<u8>, Error> {
+    let mut contents = Vec::new();
+    let graggle = if path == DEFAULT_WORKING_FILE {
+        repo.render(branch, &mut contents)?;
+        repo.graggle(branch)?
+    } else {
+        repo.render_file(branch, path, &mut contents)?;
+        repo.file_graggle(branch, path)?
+    };
+    // Binary contents, and the targets of symbolic links, are written out exactly as they were
+    // recorded.
+    if graggle.binary().is_none() && !repo.is_symlink(branch, path).unwrap_or(false) {
+        contents = config.text_format(path).to_working(contents);
+    }
+    Ok(contents)
+}
+
+// Should the file at `path` on a branch be a symbolic link in the working copy? Without symbolic
+// links, links are written out as ordinary files containing their targets.
+fn wants_symlink(repo: &Repo, branch: &str, path: &str) -> bool {
+    repo.is_symlink(branch, path).unwrap_or(false) && repo.config().symlinks()
+}
+
+// Writes out the file at `path` on a branch (unless it already has the right contents), and
+// remembers what it has.
+fn write_working_file(
+    repo: &mut Repo,
+    branch: &str,
+    path: &str,
+    contents: &[u8],
+) -> Result<(), Error> {
+    let symlink = wants_symlink(repo, branch, path);
+    let unchanged = working::read(repo, path).ok().as_deref() == Some(contents)
+        && working::is_symlink(repo, path) == symlink;
+    if !unchanged && symlink {
+        working::write_symlink(repo, path, contents)
+            .map_err(|e| Error::Io(e, format!("Could not make the link {}", path)))?;
+    } else if !unchanged {
+        working::write(repo, path, contents)
+            .map_err(|e| Error::Io(e, format!("Could not write {}", path)))?;
+    }
+    if !symlink && repo.is_executable(branch, path).unwrap_or(false) {
+        working::set_executable(repo, path)
+            .map_err(|e| Error::Io(e, format!("Could not make {} executable", path)))?;
+    }
+    repo.storage
+        .rendered
+        .insert(path.to_owned(), BlobHash::of(contents));
+    Ok(())
+}
+
+// Makes the working copy match a branch, by writing out everything that the branch has and
+// removing the things at `old_paths` that it doesn't have. The files that already have the right
+// contents are left alone, so that their modification times don't change.
+pub(crate) fn write_branch(
+    repo: &mut Repo,
+    branch: &str,
+    old_paths: &BTreeSet<String>,
+) -> Result<(), Error> {
+    let new_paths = branch_paths(repo, branch)?;
+    for path in old_paths.difference(&new_paths) {
+        remove_working_file(repo, path)?;
+    }
+    repo.storage.rendered.clear();
+    let config = repo.config();
+    for path in &new_paths {
+        let contents = working_contents(repo, branch, path, &config)?;
+        write_working_file(repo, branch, path, &contents)?;
+    }
+    Ok(())
+}
+
+// Brings the working copy up to date with the current branch, after patches were applied to it
+// or unapplied from it. Returns the paths that were written or removed.
+pub(crate) fn update(repo: &mut Repo) -> Result<Vec<String>, Error> {
+    let branch = repo.current_branch.clone();
+    let new_paths = branch_paths(repo, &branch)?;
+    let config = repo.config();
+    let working_hash = |repo: &Repo, path: &str| match working::read(repo, path) {
+        Ok(contents) => Ok(Some(BlobHash::of(&contents))),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Error::Io(e, format!("Could not read {}", path))),
+    };
+
+    // Work out everything that needs doing before doing any of it, so that the working copy isn't
+    // left half-updated if some file has unrecorded changes.
+    let mut writes = Vec::new();
+    let mut known = Vec::new();
+    for path in &new_paths {
+        let contents = working_contents(repo, &branch, path, &config)?;
+        let hash = BlobHash::of(&contents);
+        let cached = repo.storage.rendered.get(path).cloned();
+        let same_kind = working::is_symlink(repo, path) == wants_symlink(repo, &branch, path);
+        if cached == Some(hash) && same_kind {
+            continue;
+        }
+        // Files that we don't know about can be written if they aren't there yet, and files that
+        // we wrote can be replaced if they haven't changed since.
+        let current = working_hash(repo, path)?;
+        if current == Some(hash) && same_kind {
+            known.push((path.clone(), hash));
+        } else if current == cached {
+            writes.push((path.clone(), contents));
+        } else {
+            return Err(Error::UnrecordedChanges(path.clone()));
+        }
+    }
+    let mut removals = Vec::new();
+    for (path, hash) in &repo.storage.rendered {
+        if !new_paths.contains(path) {
+            match working_hash(repo, path)? {
+                Some(current) if current != *hash => {
+                    return Err(Error::UnrecordedChanges(path.clone()))
+                }
+                _ => removals.push(path.clone()),
+            }
+        }
+    }
+
+    let mut ret = Vec::new();
+    for path in removals {
+        remove_working_file(repo, &path)?;
+        repo.storage.rendered.remove(&path);
+        ret.push(path);
+    }
+    for (path, hash) in known {
+        repo.storage.rendered.insert(path, hash);
+    }
+    for (path, contents) in writes {
+        write_working_file(repo, &branch, &path, &contents)?;
+        ret.push(path);
+    }
+    ret.sort();
+    Ok(ret)
+}
+
+// Remembers which files in the working copy have the same contents as the current branch, after
+// something was recorded on it.
+pub(crate) fn refresh_rendered(repo: &mut Repo) -> Result<(), Error> {
+    let branch = repo.current_branch.clone();
+    let paths = branch_paths(repo, &branch)?;
+    let config = repo.config();
+    let mut rendered = BTreeMap::new();
+    for path in paths {
+        let contents = working_contents(repo, &branch, &path, &config)?;
+        if working::read(repo, &path).ok().as_deref() == Some(&contents[..]) {
+            rendered.insert(path, BlobHash::of(&contents));
+        }
+    }
+    repo.storage.rendered = rendered;
+    Ok(())
+}
+
+// Returns the operations on the tracked files that turn the files on `from` into the ones on `to`,
+// matching up the files by their identities (see `Repo::file_id`).
+pub(crate) fn changed_files(repo: &Repo, from: &str, to: &str) -> Result<TrackedFiles, Error> {
+    let mut old = BTreeMap::new();
+    for path in repo.files(from)? {
+        old.insert(repo.file_id(from, path)?, path.to_owned());
+    }
+    let mut ret = TrackedFiles::default();
+    for path in repo.files(to)? {
+        match old.remove(&repo.file_id(to, path)?) {
+            Some(ref orig) if orig == path => {}
+            Some(orig) => {
+                ret.moved.insert(path.to_owned(), orig);
+            }
+            None => {
+                ret.added.insert(path.to_owned());
+            }
+        }
+    }
+    ret.removed = old.into_values().collect();
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RecordOptions;
+    use std::fs;
+
+    fn write(repo: &Repo, path: &str, contents: &[u8]) {
+        working::write(repo, path, contents).unwrap();
+    }
+
+    fn read(repo: &Repo, path: &str) -> Vec<u8> {
+        working::read(repo, path).unwrap()
+    }
+
+    fn record(repo: &mut Repo) -> PatchId {
+        repo.record("Author", "Msg", &RecordOptions::default())
+            .unwrap()
+    }
+
+    #[test]
+    fn add_remove_move() {
+        let mut repo = Repo::init_tmp_with_working_copy("tracked");
+        write(&repo, "a.txt", b"a\n");
+        write(&repo, "dir/b.txt", b"b\n");
+        repo.track_file("a.txt").unwrap();
+        repo.track_file("dir/b.txt").unwrap();
+        assert!(repo.track_file("a.txt").is_err());
+        assert!(repo.track_file("missing.txt").is_err());
+        assert!(repo.track_file("../a.txt").is_err());
+        assert!(repo.track_file(DEFAULT_WORKING_FILE).is_err());
+        assert_eq!(repo.status().unwrap()["a.txt"], FileStatus::Added);
+        let first = record(&mut repo);
+        assert_eq!(
+            repo.files("master").unwrap().collect::<Vec<_>>(),
+            vec!["a.txt", "dir/b.txt"]
+        );
+        assert_eq!(repo.directories("master").unwrap(), vec!["dir"]);
+        assert_eq!(
+            repo.file_at("master", "dir/b.txt").unwrap().as_bytes(),
+            b"b\n"
+        );
+        assert!(repo
+            .status()
+            .unwrap()
+            .values()
+            .all(|s| *s == FileStatus::Clean));
+
+        // Moving a file keeps its identity, so the changes to it depend on the patch that
+        // created it.
+        let id = repo.file_id("master", "dir/b.txt").unwrap();
+        repo.move_file("dir/b.txt", "c.txt").unwrap();
+        assert!(!repo.root_dir.join("dir").exists());
+        write(&repo, "c.txt", b"b\nc\n");
+        assert_eq!(
+            repo.moved_files().collect::<Vec<_>>(),
+            vec![("c.txt", "dir/b.txt")]
+        );
+        assert_eq!(repo.status().unwrap()["c.txt"], FileStatus::Modified);
+        repo.untrack_file("a.txt").unwrap();
+        assert_eq!(repo.status().unwrap()["a.txt"], FileStatus::Removed);
+        assert_eq!(repo.untracked_files().unwrap(), vec!["a.txt"]);
+
+        let second = record(&mut repo);
+        assert_eq!(repo.patch_deps(&second).collect::<Vec<_>>(), vec![&first]);
+        assert_eq!(
+            repo.files("master").unwrap().collect::<Vec<_>>(),
+            vec!["c.txt"]
+        );
+        assert_eq!(repo.file_id("master", "c.txt").unwrap(), id);
+        assert_eq!(
+            repo.file_at("master", "c.txt").unwrap().as_bytes(),
+            b"b\nc\n"
+        );
+        assert!(repo.directories("master").unwrap().is_empty());
+        match repo.record("Author", "Msg", &RecordOptions::default()) {
+            Err(Error::NoChanges) => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+        fs::remove_dir_all(&repo.root_dir).unwrap();
+    }
+
+    #[test]
+    fn undo() {
+        let mut repo = Repo::init_tmp_with_working_copy("tracked-undo");
+        write(&repo, "a.txt", b"a\n");
+        repo.track_file("a.txt").unwrap();
+        record(&mut repo);
+
+        repo.move_file("a.txt", "b.txt").unwrap();
+        repo.move_file("b.txt", "a.txt").unwrap();
+        repo.untrack_file("a.txt").unwrap();
+        repo.track_file("a.txt").unwrap();
+        assert_eq!(repo.storage.tracked, TrackedFiles::default());
+
+        write(&repo, "new.txt", b"");
+        repo.track_file("new.txt").unwrap();
+        repo.move_file("new.txt", "dir/new.txt").unwrap();
+        assert_eq!(repo.status().unwrap()["dir/new.txt"], FileStatus::Added);
+        repo.untrack_file("dir/new.txt").unwrap();
+        assert_eq!(repo.storage.tracked, TrackedFiles::default());
+        fs::remove_dir_all(&repo.root_dir).unwrap();
+    }
+
+    #[test]
+    fn renames() {
+        let mut repo = Repo::init_tmp_with_working_copy("tracked-renames");
+        write(&repo, "a.txt", b"1\n2\n3\n4\n");
+        write(&repo, "b.txt", b"x\ny\n");
+        repo.track_file("a.txt").unwrap();
+        repo.track_file("b.txt").unwrap();
+        let first = record(&mut repo);
+        let id = repo.file_id("master", "a.txt").unwrap();
+
+        // Three of the four lines are still there, so this is a rename.
+        fs::rename(repo.root_dir.join("a.txt"), repo.root_dir.join("c.txt")).unwrap();
+        write(&repo, "c.txt", b"1\n2\n3\n5\n");
+        repo.untrack_file("a.txt").unwrap();
+        repo.track_file("c.txt").unwrap();
+        // This one has nothing in common with the old file.
+        write(&repo, "d.txt", b"z\n");
+        repo.untrack_file("b.txt").unwrap();
+        repo.track_file("d.txt").unwrap();
+
+        let second = record(&mut repo);
+        assert_eq!(repo.file_id("master", "c.txt").unwrap(), id);
+        assert_eq!(
+            repo.file_at("master", "c.txt").unwrap().as_bytes(),
+            b"1\n2\n3\n5\n"
+        );
+        assert_eq!(
+            repo.files("master").unwrap().collect::<Vec<_>>(),
+            vec!["c.txt", "d.txt"]
+        );
+        let patch = repo.open_patch(&second).unwrap();
+        let moves = patch
+            .changes()
+            .changes
+            .iter()
+            .filter(|ch| matches!(ch, Change::MoveFile { .. }))
+            .count();
+        assert_eq!(moves, 1);
+        // Only the changed line is new.
+        let new_lines = patch
+            .changes()
+            .flattened()
+            .filter(|(_, ch)| matches!(ch, Change::NewNode { .. }))
+            .count();
+        assert_eq!(new_lines, 2);
+        assert_eq!(repo.patch_deps(&second).collect::<Vec<_>>(), vec![&first]);
+
+        // With a higher threshold, it's a new file.
+        repo.config.rename_threshold = Some(80);
+        fs::rename(repo.root_dir.join("c.txt"), repo.root_dir.join("e.txt")).unwrap();
+        write(&repo, "e.txt", b"1\n2\n3\n6\n");
+        repo.untrack_file("c.txt").unwrap();
+        repo.track_file("e.txt").unwrap();
+        record(&mut repo);
+        assert_ne!(repo.file_id("master", "e.txt").unwrap(), id);
+        fs::remove_dir_all(&repo.root_dir).unwrap();
+    }
+
+    #[test]
+    fn checkout() {
+        let mut repo = Repo::init_tmp_with_working_copy("tracked-checkout");
+        write(&repo, "a.txt", b"a\n");
+        write(&repo, DEFAULT_WORKING_FILE, b"lines\n");
+        repo.track_file("a.txt").unwrap();
+        record(&mut repo);
+
+        repo.create_branch("other").unwrap();
+        repo.checkout("other").unwrap();
+        assert_eq!(repo.current_branch, "other");
+        assert!(!repo.root_dir.join("a.txt").exists());
+        assert!(!repo.root_dir.join(DEFAULT_WORKING_FILE).exists());
+
+        write(&repo, "dir/b.txt", b"b\n");
+        repo.track_file("dir/b.txt").unwrap();
+        match repo.checkout("master") {
+            Err(Error::UnrecordedChanges(p)) => assert_eq!(p, "dir/b.txt"),
+            x => panic!("unexpected result {:?}", x),
+        }
+        record(&mut repo);
+
+        repo.checkout("master").unwrap();
+        assert_eq!(read(&repo, "a.txt"), b"a\n");
+        assert_eq!(read(&repo, DEFAULT_WORKING_FILE), b"lines\n");
+        assert!(!repo.root_dir.join("dir").exists());
+
+        // Local changes and untracked files in the way stop the checkout.
+        write(&repo, "a.txt", b"changed\n");
+        assert!(repo.checkout("other").is_err());
+        write(&repo, "a.txt", b"a\n");
+        write(&repo, "dir/b.txt", b"untracked\n");
+        assert!(repo.checkout("other").is_err());
+        fs::remove_file(repo.root_dir.join("dir/b.txt")).unwrap();
+        repo.checkout("other").unwrap();
+        assert_eq!(read(&repo, "dir/b.txt"), b"b\n");
+        fs::remove_dir_all(&repo.root_dir).unwrap();
+    }
+
+    #[test]
+    fn update_working_copy() {
+        let mut repo = Repo::init_tmp_with_working_copy("tracked-update");
+        write(&repo, "a.txt", b"a\n");
+        write(&repo, "b.txt", b"b\n");
+        write(&repo, "c.txt", b"c\n");
+        repo.track_file("a.txt").unwrap();
+        repo.track_file("b.txt").unwrap();
+        repo.track_file("c.txt").unwrap();
+        record(&mut repo);
+
+        // Make some patches on another branch, and apply them to the current one.
+        repo.clone_branch("master", "other").unwrap();
+        let diff = repo.diff_file("other", "a.txt", b"a\nmore a\n").unwrap();
+        let mut changes = crate::Changes { changes: vec![] };
+        changes.add_file_changes(repo.file_id("other", "a.txt").unwrap(), diff.changes());
+        let edit = repo.create_patch("Author", "Msg", changes).unwrap();
+        let file = repo.file_ref("other", "c.txt").unwrap();
+        let changes = crate::Changes {
+            changes: vec![Change::DeleteFile { file }],
+        };
+        let delete = repo.create_patch("Author", "Msg", changes).unwrap();
+        repo.apply_patch("master", &edit).unwrap();
+        repo.apply_patch("master", &delete).unwrap();
+
+        assert_eq!(repo.update_working_copy().unwrap(), vec!["a.txt", "c.txt"]);
+        assert_eq!(read(&repo, "a.txt"), b"a\nmore a\n");
+        assert!(!repo.root_dir.join("c.txt").exists());
+        assert!(repo.update_working_copy().unwrap().is_empty());
+
+        // Unrecorded changes to a file that needs updating stop the update; unrecorded changes to
+        // the other files don't.
+        write(&repo, "a.txt", b"a\nchanged\n");
+        write(&repo, "b.txt", b"b\nchanged\n");
+        repo.unapply_patch("master", &edit).unwrap();
+        match repo.update_working_copy() {
+            Err(Error::UnrecordedChanges(p)) => assert_eq!(p, "a.txt"),
+            x => panic!("unexpected result {:?}", x),
+        }
+        write(&repo, "a.txt", b"a\nmore a\n");
+        assert_eq!(repo.update_working_copy().unwrap(), vec!["a.txt"]);
+        assert_eq!(read(&repo, "a.txt"), b"a\n");
+        assert_eq!(read(&repo, "b.txt"), b"b\nchanged\n");
+
+        // Recorded files are up to date.
+        record(&mut repo);
+        repo.apply_patch("master", &edit).unwrap();
+        assert_eq!(repo.update_working_copy().unwrap(), vec!["a.txt"]);
+        fs::remove_dir_all(&repo.root_dir).unwrap();
+    }
+}
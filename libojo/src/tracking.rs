@@ -0,0 +1,160 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Exchanging patches with the remotes in the repository's config, and keeping track of which
+// patches each of them has.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::remote::{Connection, Transfer};
+use crate::{Error, PatchId, Remote, Repo, UnapplyPolicy};
+
+// Returns the name of the branch that tracks a branch of a remote.
+pub(crate) fn tracking_branch(remote: &str, branch: &str) -> String {
+    format!("{}/{}", remote, branch)
+}
+
+pub(crate) fn add(repo: &mut Repo, name: &str, url: &str) -> Result<(), Error> {
+    if repo.config.remotes.contains_key(name) {
+        return Err(Error::RemoteExists(name.to_owned()));
+    }
+    repo.config
+        .remotes
+        .insert(name.to_owned(), Remote::new(url));
+    Ok(())
+}
+
+pub(crate) fn remove(repo: &mut Repo, name: &str) -> Result<(), Error> {
+    if repo.config.remotes.remove(name).is_none() {
+        return Err(Error::UnknownRemote(name.to_owned()));
+    }
+    repo.storage.remote_patches.remove_all(name);
+    Ok(())
+}
+
+pub(crate) fn fetch(
+    repo: &mut Repo,
+    name: &str,
+    conn: &mut Connection,
+) -> Result<BTreeMap<String, Transfer>, Error> {
+    let remote = repo.remote(name)?;
+    let remote_branches = remote.branches.values().cloned().collect::<BTreeSet<_>>();
+
+    let mut ret = BTreeMap::new();
+    for remote_branch in remote_branches {
+        let ids = conn.branch_patches(&remote_branch)?;
+        let tracking = tracking_branch(name, &remote_branch);
+
+        // The tracking branch mirrors the remote one, so anything that was unapplied there gets
+        // unapplied here.
+        if repo.storage.inode(&tracking).is_some() {
+            let stale = repo
+                .patches(&tracking)
+                .filter(|p| !ids.contains(p))
+                .cloned()
+                .collect::<Vec<_>>();
+            for p in &stale {
+                repo.unapply(&tracking, p, UnapplyPolicy::Cascade)?;
+            }
+        }
+        let transfer = conn.pull_ids(repo, &ids, &tracking)?;
+        for id in ids {
+            repo.storage.remote_patches.insert(name.to_owned(), id);
+        }
+        ret.insert(remote_branch, transfer);
+    }
+    Ok(ret)
+}
+
+pub(crate) fn push(
+    repo: &mut Repo,
+    name: &str,
+    branch: &str,
+    conn: &mut Connection,
+) -> Result<Transfer, Error> {
+    let remote_branch = repo.remote(name)?.remote_branch(branch).to_owned();
+    let known = repo
+        .storage
+        .remote_patches
+        .get(name)
+        .cloned()
+        .collect::<BTreeSet<PatchId>>();
+    let ret = conn.push_except(repo, branch, &remote_branch, &known)?;
+    let pushed = repo.patches(branch).cloned().collect::<Vec<_>>();
+    for id in pushed {
+        repo.storage.remote_patches.insert(name.to_owned(), id);
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{create, tmp_dir};
+    use std::fs;
+
+    // Creates a repository on disk, and returns it along with its address.
+    fn remote_repo(name: &str) -> (Repo, String) {
+        let dir = tmp_dir(&format!("tracking-{}", name));
+        let repo = Repo::init(&dir).unwrap();
+        repo.write().unwrap();
+        let addr = dir.to_str().unwrap().to_owned();
+        (repo, addr)
+    }
+
+    #[test]
+    fn config() {
+        let mut repo = Repo::init_tmp();
+        repo.add_remote("origin", "example.com:repo").unwrap();
+        assert!(repo.add_remote("origin", "elsewhere:repo").is_err());
+        let remote = repo.remote("origin").unwrap();
+        assert_eq!(remote.url, "example.com:repo");
+        assert_eq!(remote.remote_branch("master"), "master");
+        assert_eq!(remote.remote_branch("other"), "other");
+
+        repo.remove_remote("origin").unwrap();
+        assert!(repo.remote("origin").is_err());
+        assert!(repo.remove_remote("origin").is_err());
+    }
+
+    #[test]
+    fn fetch_and_push() {
+        let (mut remote, addr) = remote_repo("fetch");
+        let a = create(&mut remote, "master", b"a\n");
+        remote.write().unwrap();
+
+        let mut local = Repo::init_tmp();
+        local.add_remote("origin", &addr).unwrap();
+        let fetched = local.fetch("origin").unwrap();
+        assert_eq!(fetched["master"].transferred, vec![a]);
+        assert_eq!(local.file("origin/master").unwrap().as_bytes(), b"a\n");
+        assert_eq!(local.remote_patches("origin").collect::<Vec<_>>(), vec![&a]);
+
+        // The patch that the remote already has doesn't get asked about.
+        local.apply_patch("master", &a).unwrap();
+        let b = create(&mut local, "master", b"a\nb\n");
+        let mut conn = Connection::open_local(&addr).unwrap();
+        let pushed = local.push_with("origin", "master", &mut conn).unwrap();
+        conn.close().unwrap();
+        assert_eq!(pushed.transferred, vec![b]);
+        assert!(local.remote_patches("origin").any(|p| *p == b));
+        let remote = Repo::open(&addr).unwrap();
+        assert_eq!(remote.file("master").unwrap().as_bytes(), b"a\nb\n");
+
+        // Patches that disappear from the remote disappear from the tracking branch.
+        let mut remote = remote;
+        remote.unapply_patch("master", &b).unwrap();
+        remote.write().unwrap();
+        local.fetch("origin").unwrap();
+        assert_eq!(local.file("origin/master").unwrap().as_bytes(), b"a\n");
+        fs::remove_dir_all(&addr).unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Since all of the repository's state is in memory until it is written out, a transaction just
+// needs to remember what the state was at the start, and put it back if something goes wrong.
+// There's no journal: it's a rollback in memory, not a way to make writing to disk atomic.
+
+use crate::storage::Storage;
+use crate::{Config, Repo};
+
+// The parts of a repository that are restored when a transaction rolls back.
+struct Snapshot {
+    storage: Storage,
+    current_branch: String,
+    config: Config,
+}
+
+impl Snapshot {
+    fn take(repo: &Repo) -> Snapshot {
+        Snapshot {
+            storage: repo.storage.clone(),
+            current_branch: repo.current_branch.clone(),
+            config: repo.config.clone(),
+        }
+    }
+
+    fn restore(self, repo: &mut Repo) {
+        repo.storage = self.storage;
+        repo.current_branch = self.current_branch;
+        repo.config = self.config;
+    }
+}
+
+pub(crate) fn transaction<T, E, F>(repo: &mut Repo, f: F) -> Result<T, E>
+where
+    F: FnOnce(&mut Repo) -> Result<T, E>,
+{
+    let snapshot = Snapshot::take(repo);
+    let ret = f(repo);
+    if ret.is_err() {
+        snapshot.restore(repo);
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::try_create;
+    use crate::{Error, Repo};
+
+    #[test]
+    fn commit() {
+        let mut repo = Repo::init_tmp();
+        let (first, second) = repo
+            .transaction(|repo| {
+                Ok::<_, Error>((
+                    try_create(repo, "master", b"a\n")?,
+                    try_create(repo, "master", b"a\nb\n")?,
+                ))
+            })
+            .unwrap();
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"a\nb\n");
+        assert_eq!(repo.patches("master").count(), 2);
+        assert!(repo.patches("master").any(|p| *p == first));
+        assert!(repo.patches("master").any(|p| *p == second));
+    }
+
+    #[test]
+    fn roll_back() {
+        let mut repo = Repo::init_tmp();
+        let first = try_create(&mut repo, "master", b"a\n").unwrap();
+        let result = repo.transaction(|repo| {
+            try_create(repo, "master", b"a\nb\n")?;
+            repo.create_branch("other")?;
+            repo.unapply_patch("master", &first)?;
+            repo.apply_patch("missing", &first)
+        });
+        match result {
+            Err(Error::UnknownBranch(b)) => assert_eq!(b, "missing"),
+            x => panic!("expected an error, got {:?}", x),
+        }
+
+        // Everything is the way it was before the transaction.
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"a\n");
+        assert_eq!(repo.patches("master").collect::<Vec<_>>(), vec![&first]);
+        assert_eq!(repo.all_patches().collect::<Vec<_>>(), vec![&first]);
+        assert_eq!(repo.branches().collect::<Vec<_>>(), vec!["master"]);
+    }
+}
@@ -0,0 +1,543 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Each branch keeps track of a set of file paths, which are modified by the `NewFile`,
+// `DeleteFile`, `MoveFile`, `SetExecutable` and `SetSymlink` changes. For every path, we remember which patch put
+// a file there (or last changed its metadata): changes that refer to an existing file name that
+// patch (see `FileRef`), which means that a patch depends on the patches that created, moved or
+// modified the files that it touches, and that two patches that move the same file in different
+// ways conflict with one another.
+//
+// We also remember where every file was created, because that is what identifies the file when
+// its lines are changed (see `Change::EditFile`): unlike its path, this never changes.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{Change, PatchId};
+
+/// Refers to a file that was put in place (i.e. created or moved, or had its metadata changed) by a
+/// particular patch.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct FileRef {
+    /// The patch that created the file at `path`, moved it there, or last changed its metadata.
+    pub patch: PatchId,
+    /// The path of the file.
+    pub path: String,
+}
+
+// The state of a single file on a branch.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub(crate) struct FileState {
+    // The patch that put the file where it is (or that last changed its metadata).
+    pub patch: PatchId,
+    #[serde(default)]
+    pub executable: bool,
+    // Is the file a symbolic link (whose target is its contents)?
+    #[serde(default)]
+    pub symlink: bool,
+    // The patch that created the file, and the path that it created it at. Repositories written
+    // by older versions of ojo don't have this.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<FileRef>,
+}
+
+// The files on a branch.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub(crate) struct Files {
+    // The current files, indexed by path.
+    live: BTreeMap<String, FileState>,
+    // The files that were deleted, indexed by the patch that deleted them and their path. We only
+    // keep these around so that we can restore their metadata if the deletion is unapplied.
+    #[serde(default)]
+    deleted: BTreeMap<PatchId, BTreeMap<String, FileState>>,
+}
+
+impl Files {
+    pub fn get(&self, path: &str) -> Option<&FileState> {
+        self.live.get(path)
+    }
+
+    pub fn paths(&self) -> impl Iterator<Item = &str> {
+        self.live.keys().map(|s| s.as_str())
+    }
+
+    // Returns the directories containing the files, in sorted order.
+    pub fn directories(&self) -> Vec<String> {
+        let mut ret = BTreeSet::new();
+        for path in self.live.keys() {
+            for (i, _) in path.match_indices('/') {
+                ret.insert(path[..i].to_owned());
+            }
+        }
+        ret.into_iter().collect()
+    }
+
+    // Returns the identity of the file at `path` (see `Change::EditFile`).
+    pub fn id(&self, path: &str) -> Option<FileRef> {
+        self.live.get(path).map(|state| {
+            // If we don't know where the file was created, the best guess is that it was created
+            // right where it is.
+            state.created.clone().unwrap_or_else(|| FileRef {
+                patch: state.patch,
+                path: path.to_owned(),
+            })
+        })
+    }
+
+    // Returns the state that the file at `path` had before the patch `patch` deleted it.
+    pub fn deleted(&self, patch: &PatchId, path: &str) -> Option<&FileState> {
+        self.deleted.get(patch).and_then(|d| d.get(path))
+    }
+
+    // Checks that `file` is currently present, and removes it.
+    fn take(&mut self, file: &FileRef) -> Result<FileState, String> {
+        match self.live.get(&file.path) {
+            Some(state) if state.patch == file.patch => Ok(self.live.remove(&file.path).unwrap()),
+            _ => Err(file.path.clone()),
+        }
+    }
+
+    // Checks that there is no file at `path`, and puts one there.
+    fn put(&mut self, path: &str, state: FileState) -> Result<(), String> {
+        if self.live.contains_key(path) {
+            Err(path.to_owned())
+        } else {
+            self.live.insert(path.to_owned(), state);
+            Ok(())
+        }
+    }
+
+    // Applies a change (belonging to the patch `patch`) to the files. If the change doesn't fit
+    // (because it refers to a file that isn't there, or because it wants to put a file somewhere
+    // that is already taken), returns the offending path and leaves the files unchanged. Changes
+    // that don't involve files are ignored.
+    pub fn apply(&mut self, ch: &Change, patch: PatchId) -> Result<(), String> {
+        match *ch {
+            Change::NewFile { ref path } => self.put(
+                path,
+                FileState {
+                    patch,
+                    executable: false,
+                    symlink: false,
+                    created: Some(FileRef {
+                        patch,
+                        path: path.clone(),
+                    }),
+                },
+            ),
+            Change::DeleteFile { ref file } => {
+                let state = self.take(file)?;
+                self.deleted
+                    .entry(patch)
+                    .or_default()
+                    .insert(file.path.clone(), state);
+                Ok(())
+            }
+            Change::MoveFile { ref from, ref to } => {
+                let state = self.take(from)?;
+                let ret = self.put(
+                    to,
+                    FileState {
+                        patch,
+                        ..state.clone()
+                    },
+                );
+                if ret.is_err() {
+                    self.live.insert(from.path.clone(), state);
+                }
+                ret
+            }
+            Change::SetExecutable {
+                ref file,
+                executable,
+            } => {
+                let state = self.take(file)?;
+                if state.executable == executable {
+                    self.live.insert(file.path.clone(), state);
+                    return Err(file.path.clone());
+                }
+                self.live.insert(
+                    file.path.clone(),
+                    FileState {
+                        patch,
+                        executable,
+                        ..state
+                    },
+                );
+                Ok(())
+            }
+            Change::SetSymlink { ref file, symlink } => {
+                let state = self.take(file)?;
+                if state.symlink == symlink {
+                    self.live.insert(file.path.clone(), state);
+                    return Err(file.path.clone());
+                }
+                self.live.insert(
+                    file.path.clone(),
+                    FileState {
+                        patch,
+                        symlink,
+                        ..state
+                    },
+                );
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // The inverse of `apply`.
+    pub fn unapply(&mut self, ch: &Change, patch: PatchId) {
+        match *ch {
+            Change::NewFile { ref path } => {
+                self.live.remove(path);
+            }
+            Change::DeleteFile { ref file } => {
+                let state = self
+                    .deleted
+                    .get_mut(&patch)
+                    .and_then(|d| d.remove(&file.path))
+                    .expect("tried to restore a file that wasn't deleted");
+                if self.deleted[&patch].is_empty() {
+                    self.deleted.remove(&patch);
+                }
+                self.live.insert(file.path.clone(), state);
+            }
+            Change::MoveFile { ref from, ref to } => {
+                if let Some(state) = self.live.remove(to) {
+                    self.live.insert(
+                        from.path.clone(),
+                        FileState {
+                            patch: from.patch,
+                            ..state
+                        },
+                    );
+                }
+            }
+            Change::SetExecutable {
+                ref file,
+                executable,
+            } => {
+                if let Some(state) = self.live.get_mut(&file.path) {
+                    state.patch = file.patch;
+                    state.executable = !executable;
+                }
+            }
+            Change::SetSymlink { ref file, symlink } => {
+                if let Some(state) = self.live.get_mut(&file.path) {
+                    state.patch = file.patch;
+                    state.symlink = !symlink;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::create_changes;
+    use crate::{Change, Changes, Error, FileRef, NodeId, PatchId, Repo};
+
+    fn new_file(path: &str) -> Change {
+        Change::NewFile {
+            path: path.to_owned(),
+        }
+    }
+
+    fn files(repo: &Repo, branch: &str) -> Vec<String> {
+        repo.files(branch).unwrap().map(|s| s.to_owned()).collect()
+    }
+
+    #[test]
+    fn new_move_delete() {
+        let mut repo = Repo::init_tmp();
+        let create_a = create_changes(&mut repo, "master", vec![new_file("a"), new_file("b")]);
+        assert_eq!(files(&repo, "master"), vec!["a", "b"]);
+
+        let from = repo.file_ref("master", "a").unwrap();
+        assert_eq!(from.patch, create_a);
+        let move_a = create_changes(
+            &mut repo,
+            "master",
+            vec![Change::MoveFile {
+                from,
+                to: "c".to_owned(),
+            }],
+        );
+        assert_eq!(files(&repo, "master"), vec!["b", "c"]);
+        assert_eq!(
+            repo.patch_deps(&move_a).collect::<Vec<_>>(),
+            vec![&create_a]
+        );
+
+        let file = repo.file_ref("master", "c").unwrap();
+        let delete_c = create_changes(&mut repo, "master", vec![Change::DeleteFile { file }]);
+        assert_eq!(files(&repo, "master"), vec!["b"]);
+        assert_eq!(
+            repo.patch_deps(&delete_c).collect::<Vec<_>>(),
+            vec![&move_a]
+        );
+
+        repo.unapply_patch("master", &move_a).unwrap();
+        assert_eq!(files(&repo, "master"), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn within_one_patch() {
+        let mut repo = Repo::init_tmp();
+        let moved = Change::MoveFile {
+            from: FileRef {
+                patch: PatchId::staging(),
+                path: "a".to_owned(),
+            },
+            to: "b".to_owned(),
+        };
+        let id = create_changes(&mut repo, "master", vec![new_file("a"), moved]);
+        assert_eq!(repo.patch_deps(&id).count(), 0);
+        assert_eq!(files(&repo, "master"), vec!["b"]);
+        assert_eq!(repo.file_ref("master", "b").unwrap().patch, id);
+
+        repo.unapply_patch("master", &id).unwrap();
+        assert!(files(&repo, "master").is_empty());
+    }
+
+    #[test]
+    fn conflicting_moves() {
+        let mut repo = Repo::init_tmp();
+        create_changes(&mut repo, "master", vec![new_file("a")]);
+        repo.clone_branch("master", "other").unwrap();
+        let from = repo.file_ref("master", "a").unwrap();
+        let ours = Change::MoveFile {
+            from: from.clone(),
+            to: "b".to_owned(),
+        };
+        let theirs = Change::MoveFile {
+            from,
+            to: "c".to_owned(),
+        };
+        create_changes(&mut repo, "master", vec![ours]);
+        let theirs = create_changes(&mut repo, "other", vec![theirs]);
+
+        match repo.apply_patch("master", &theirs) {
+            Err(Error::FileConflict(p, path)) => {
+                assert_eq!(p, theirs);
+                assert_eq!(path, "a");
+            }
+            x => panic!("expected a conflict, got {:?}", x),
+        }
+        assert_eq!(files(&repo, "master"), vec!["b"]);
+    }
+
+    #[test]
+    fn executable() {
+        let mut repo = Repo::init_tmp();
+        create_changes(&mut repo, "master", vec![new_file("script")]);
+        assert!(!repo.is_executable("master", "script").unwrap());
+
+        let file = repo.file_ref("master", "script").unwrap();
+        let set_x = create_changes(
+            &mut repo,
+            "master",
+            vec![Change::SetExecutable {
+                file,
+                executable: true,
+            }],
+        );
+        assert!(repo.is_executable("master", "script").unwrap());
+
+        // The permission survives moving the file, and deleting and undeleting it.
+        let from = repo.file_ref("master", "script").unwrap();
+        assert_eq!(from.patch, set_x);
+        let to = "bin/script".to_owned();
+        create_changes(&mut repo, "master", vec![Change::MoveFile { from, to }]);
+        assert!(repo.is_executable("master", "bin/script").unwrap());
+        let file = repo.file_ref("master", "bin/script").unwrap();
+        let delete = create_changes(&mut repo, "master", vec![Change::DeleteFile { file }]);
+        repo.unapply_patch("master", &delete).unwrap();
+        assert!(repo.is_executable("master", "bin/script").unwrap());
+
+        repo.unapply_patch("master", &set_x).unwrap();
+        assert!(!repo.is_executable("master", "script").unwrap());
+
+        // The permission has to actually change.
+        let file = repo.file_ref("master", "script").unwrap();
+        let clear_x = Change::SetExecutable {
+            file,
+            executable: false,
+        };
+        let id = repo
+            .create_patch(
+                "Author",
+                "Msg",
+                Changes {
+                    changes: vec![clear_x],
+                },
+            )
+            .unwrap();
+        match repo.apply_patch("master", &id) {
+            Err(Error::FileConflict(..)) => {}
+            x => panic!("expected a conflict, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn symlink() {
+        let mut repo = Repo::init_tmp();
+        create_changes(&mut repo, "master", vec![new_file("link")]);
+        assert!(!repo.is_symlink("master", "link").unwrap());
+
+        let file = repo.file_ref("master", "link").unwrap();
+        let set_link = create_changes(
+            &mut repo,
+            "master",
+            vec![Change::SetSymlink {
+                file,
+                symlink: true,
+            }],
+        );
+        assert!(repo.is_symlink("master", "link").unwrap());
+        assert!(!repo.is_executable("master", "link").unwrap());
+
+        repo.unapply_patch("master", &set_link).unwrap();
+        assert!(!repo.is_symlink("master", "link").unwrap());
+    }
+
+    #[test]
+    fn unknown_file() {
+        let mut repo = Repo::init_tmp();
+        let id = create_changes(&mut repo, "master", vec![new_file("a")]);
+        let file = FileRef {
+            patch: id,
+            path: "b".to_owned(),
+        };
+        let changes = Changes {
+            changes: vec![Change::DeleteFile { file }],
+        };
+        match repo.create_patch("Author", "Msg", changes) {
+            Err(Error::UnknownFile(path)) => assert_eq!(path, "b"),
+            x => panic!("expected an error, got {:?}", x),
+        }
+    }
+
+    // The changes that add a single line to an empty file.
+    fn one_line(contents: &[u8]) -> Changes {
+        Changes {
+            changes: vec![Change::NewNode {
+                id: NodeId::staged(0),
+                contents: contents.to_owned(),
+            }],
+        }
+    }
+
+    #[test]
+    fn edit_files() {
+        let mut repo = Repo::init_tmp();
+        let mut changes = Changes {
+            changes: vec![new_file("a"), new_file("b")],
+        };
+        for (path, contents) in &[("a", b"a\n"), ("b", b"b\n")] {
+            let file = FileRef {
+                patch: PatchId::staging(),
+                path: path.to_string(),
+            };
+            changes.add_file_changes(file, one_line(*contents));
+        }
+        let id = repo.create_patch("Author", "Msg", changes).unwrap();
+        repo.apply_patch("master", &id).unwrap();
+        assert_eq!(repo.file_at("master", "a").unwrap().as_bytes(), b"a\n");
+        assert_eq!(repo.file_at("master", "b").unwrap().as_bytes(), b"b\n");
+
+        // The file keeps its identity when it's moved.
+        let from = repo.file_ref("master", "a").unwrap();
+        create_changes(
+            &mut repo,
+            "master",
+            vec![Change::MoveFile {
+                from,
+                to: "c".to_owned(),
+            }],
+        );
+        let file = repo.file_id("master", "c").unwrap();
+        assert_eq!(file.patch, id);
+        assert_eq!(file.path, "a");
+
+        // Edit both files in one patch; each file's edits depend only on that file's patches.
+        let mut changes = Changes { changes: vec![] };
+        let diff = repo.diff_file("master", "c", b"a\nc\n").unwrap();
+        changes.add_file_changes(file.clone(), diff.changes());
+        let other = repo.file_id("master", "b").unwrap();
+        let diff = repo.diff_file("master", "b", b"").unwrap();
+        changes.add_file_changes(other.clone(), diff.changes());
+        let deps = changes.file_deps();
+        assert_eq!(deps.len(), 2);
+        assert_eq!(deps[&file], vec![id]);
+        assert_eq!(deps[&other], vec![id]);
+
+        let edit = create_changes(&mut repo, "master", changes.changes);
+        assert_eq!(repo.file_at("master", "c").unwrap().as_bytes(), b"a\nc\n");
+        assert_eq!(repo.file_at("master", "b").unwrap().as_bytes(), b"");
+
+        repo.unapply_patch("master", &edit).unwrap();
+        assert_eq!(repo.file_at("master", "c").unwrap().as_bytes(), b"a\n");
+        assert_eq!(repo.file_at("master", "b").unwrap().as_bytes(), b"b\n");
+        repo.unapply_patch("master", &id).unwrap();
+        assert!(files(&repo, "master").is_empty());
+    }
+
+    #[test]
+    fn invalid_file_edits() {
+        let mut repo = Repo::init_tmp();
+        let mut changes = Changes {
+            changes: vec![new_file("a"), new_file("b")],
+        };
+        let file = |path: &str| FileRef {
+            patch: PatchId::staging(),
+            path: path.to_owned(),
+        };
+        changes.add_file_changes(file("a"), one_line(b"a\n"));
+        let id = create_changes(&mut repo, "master", changes.changes);
+        let line = repo
+            .file_graggle("master", "a")
+            .unwrap()
+            .nodes()
+            .next()
+            .unwrap();
+
+        // The lines of one file can't be referred to from another.
+        let created = |path: &str| FileRef {
+            patch: id,
+            path: path.to_owned(),
+        };
+        let mut changes = Changes { changes: vec![] };
+        let delete = Changes {
+            changes: vec![Change::DeleteNode { id: line }],
+        };
+        changes.add_file_changes(created("b"), delete);
+        match repo.create_patch("Author", "Msg", changes) {
+            Err(Error::UnknownNode(..)) => {}
+            x => panic!("expected an error, got {:?}", x),
+        }
+
+        // Files can only be edited by changing their lines.
+        let mut changes = Changes { changes: vec![] };
+        changes.add_file_changes(
+            created("a"),
+            Changes {
+                changes: vec![new_file("c")],
+            },
+        );
+        match repo.create_patch("Author", "Msg", changes) {
+            Err(Error::InvalidFileEdit(_)) => {}
+            x => panic!("expected an error, got {:?}", x),
+        }
+    }
+}
@@ -0,0 +1,581 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// The working copy is usually the directory at `Repo::root_dir`, but it can be anything that
+// implements `Vfs`: a repository that was created with `Repo::init_in_memory` keeps it in memory,
+// for example. Everything that touches the working copy goes through here, so the rest of the code
+// doesn't need to care where it is.
+//
+// All the paths here are relative to the root of the working copy, with `/` as the separator.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::{Error, Repo};
+
+/// A filesystem that a repository can live in.
+///
+/// Most repositories live in a directory on disk (see [`NativeVfs`]), but a repository can be put
+/// anywhere else that implements this trait (see [`Repo::init_vfs`] and [`Repo::open_vfs`]).
+/// This is how ojo runs where there is no filesystem, like in a web browser: there, a repository
+/// can be kept in a [`MemoryWorkingCopy`] that is loaded from (and saved to) the browser's
+/// storage.
+///
+/// All the paths are relative to the root of the filesystem, with `/` as the separator. The
+/// filesystem doesn't need to have directories of its own: a directory can just exist exactly
+/// when there are files in it.
+pub trait Vfs: std::fmt::Debug + Send + Sync {
+    /// Reads the file at `path`, exactly as it is. For a symbolic link, this is the link's target.
+    fn read(&self, path: &str) -> io::Result<Vec<u8>>;
+
+    /// Writes the file at `path`, creating the directories containing it if necessary. If there
+    /// was a symbolic link there, it gets replaced instead of having its target written to.
+    fn write(&self, path: &str, contents: &[u8]) -> io::Result<()>;
+
+    /// Makes a symbolic link at `path` pointing to `target`, creating the directories containing
+    /// it if necessary.
+    fn write_symlink(&self, path: &str, target: &[u8]) -> io::Result<()>;
+
+    /// Removes the file at `path`, along with any directories that it leaves empty.
+    fn remove(&self, path: &str) -> io::Result<()>;
+
+    /// Moves the file at `from` to `to`, which doesn't exist.
+    fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+
+    /// Makes the file at `path` executable.
+    fn set_executable(&self, path: &str) -> io::Result<()>;
+
+    /// Is there a file, a symbolic link or a directory at `path`? Symbolic links don't need to
+    /// point anywhere.
+    fn exists(&self, path: &str) -> bool;
+
+    /// Is there a file (or a symbolic link) at `path`?
+    fn is_file(&self, path: &str) -> bool;
+
+    /// Is there a symbolic link at `path`?
+    fn is_symlink(&self, path: &str) -> bool;
+
+    /// Returns the names of the things in the directory `dir` (which is empty or ends with a
+    /// `/`), along with whether they are directories.
+    fn entries(&self, dir: &str) -> Result<Vec<(String, bool)>, Error>;
+}
+
+/// The filesystem of the operating system, seen from the directory `root`.
+#[derive(Clone, Debug)]
+pub struct NativeVfs {
+    root: PathBuf,
+}
+
+impl NativeVfs {
+    /// Creates a filesystem whose paths are relative to `root`.
+    pub fn new<P: AsRef<Path>>(root: P) -> NativeVfs {
+        NativeVfs {
+            root: root.as_ref().to_owned(),
+        }
+    }
+
+    // Gets ready to write the file at `path`: creates the directories containing it, and removes
+    // it if it's a symbolic link.
+    fn prepare(&self, path: &str) -> io::Result<PathBuf> {
+        let full_path = self.root.join(path);
+        if let Some(parent) = full_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if fs::symlink_metadata(&full_path).is_ok_and(|m| m.file_type().is_symlink()) {
+            fs::remove_file(&full_path)?;
+        }
+        Ok(full_path)
+    }
+
+    // Removes the directories containing `path` (but not the root) for as long as they're empty.
+    fn remove_empty_dirs(&self, path: &Path) {
+        let mut dir = path.parent();
+        while let Some(d) = dir {
+            if d == self.root || fs::remove_dir(d).is_err() {
+                break;
+            }
+            dir = d.parent();
+        }
+    }
+}
+
+impl Vfs for NativeVfs {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        let full_path = self.root.join(path);
+        if fs::symlink_metadata(&full_path)?.file_type().is_symlink() {
+            link_target(&full_path)
+        } else {
+            fs::read(&full_path)
+        }
+    }
+
+    fn write(&self, path: &str, contents: &[u8]) -> io::Result<()> {
+        let full_path = self.prepare(path)?;
+        fs::write(&full_path, contents)
+    }
+
+    fn write_symlink(&self, path: &str, target: &[u8]) -> io::Result<()> {
+        let full_path = self.prepare(path)?;
+        symlink_on_disk(target, &full_path)
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        let full_path = self.root.join(path);
+        fs::remove_file(&full_path)?;
+        self.remove_empty_dirs(&full_path);
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let src = self.root.join(from);
+        let dst = self.root.join(to);
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(&src, &dst)?;
+        self.remove_empty_dirs(&src);
+        Ok(())
+    }
+
+    fn set_executable(&self, path: &str) -> io::Result<()> {
+        set_executable_on_disk(&self.root.join(path))
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        fs::symlink_metadata(self.root.join(path)).is_ok()
+    }
+
+    fn is_file(&self, path: &str) -> bool {
+        fs::symlink_metadata(self.root.join(path))
+            .is_ok_and(|m| m.is_file() || m.file_type().is_symlink())
+    }
+
+    fn is_symlink(&self, path: &str) -> bool {
+        fs::symlink_metadata(self.root.join(path)).is_ok_and(|m| m.file_type().is_symlink())
+    }
+
+    fn entries(&self, dir: &str) -> Result<Vec<(String, bool)>, Error> {
+        let full_path = self.root.join(dir);
+        let read_dir = fs::read_dir(&full_path)
+            .map_err(|e| Error::Io(e, format!("Could not read {}", full_path.display())))?;
+        let mut ret = Vec::new();
+        for entry in read_dir {
+            let entry = entry?;
+            let name = entry
+                .file_name()
+                .into_string()
+                .map_err(Error::NonUtfFilename)?;
+            ret.push((name, entry.file_type()?.is_dir()));
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(unix)]
+fn link_target(path: &Path) -> io::Result<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    Ok(fs::read_link(path)?.as_os_str().as_bytes().to_owned())
+}
+
+#[cfg(not(unix))]
+fn link_target(path: &Path) -> io::Result<Vec<u8>> {
+    let target = fs::read_link(path)?;
+    let target = target
+        .to_str()
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+    Ok(target.replace('\\', "/").into_bytes())
+}
+
+#[cfg(unix)]
+fn symlink_on_disk(target: &[u8], path: &Path) -> io::Result<()> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    std::os::unix::fs::symlink(OsStr::from_bytes(target), path)
+}
+
+#[cfg(not(unix))]
+fn symlink_on_disk(_target: &[u8], _path: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(unix)]
+fn set_executable_on_disk(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut perms = fs::metadata(path)?.permissions();
+    perms.set_mode(perms.mode() | 0o111);
+    fs::set_permissions(path, perms)
+}
+
+#[cfg(not(unix))]
+fn set_executable_on_disk(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[derive(Clone, Debug, Default)]
+struct MemoryFile {
+    // For a symbolic link, this is the link's target.
+    contents: Vec<u8>,
+    executable: bool,
+    symlink: bool,
+}
+
+/// A working copy that lives in memory instead of on disk (see [`Repo::init_in_memory`]).
+///
+/// This is a handle to the files, and its clones are handles to the same files. So a test can keep
+/// a clone of the working copy that it gave to a repository, and use it to edit the files before
+/// recording them, or to look at what the repository wrote out.
+///
+/// There are no directories: a directory exists exactly when there are files in it.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryWorkingCopy {
+    files: Arc<Mutex<BTreeMap<String, MemoryFile>>>,
+}
+
+impl MemoryWorkingCopy {
+    /// Creates an empty working copy.
+    pub fn new() -> MemoryWorkingCopy {
+        MemoryWorkingCopy::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<String, MemoryFile>> {
+        // Nothing that holds the lock can panic, so it can't be poisoned.
+        self.files.lock().unwrap()
+    }
+
+    /// Returns the contents of the file at `path`, if there is one.
+    pub fn read(&self, path: &str) -> Option<Vec<u8>> {
+        self.lock().get(path).map(|f| f.contents.clone())
+    }
+
+    /// Writes a file, replacing the file that was at `path` (if there was one).
+    pub fn write(&self, path: &str, contents: &[u8]) {
+        let mut files = self.lock();
+        let file = files.entry(path.to_owned()).or_default();
+        file.contents = contents.to_owned();
+        file.symlink = false;
+    }
+
+    /// Makes a symbolic link at `path` pointing to `target`, replacing the file that was there (if
+    /// there was one). Reading the link returns its target.
+    pub fn write_symlink(&self, path: &str, target: &[u8]) {
+        let file = MemoryFile {
+            contents: target.to_owned(),
+            executable: false,
+            symlink: true,
+        };
+        self.lock().insert(path.to_owned(), file);
+    }
+
+    /// Removes the file at `path`, returning `false` if there wasn't one.
+    pub fn remove(&self, path: &str) -> bool {
+        self.lock().remove(path).is_some()
+    }
+
+    /// Is the file at `path` executable?
+    pub fn is_executable(&self, path: &str) -> bool {
+        self.lock().get(path).is_some_and(|f| f.executable)
+    }
+
+    /// Is the file at `path` a symbolic link?
+    pub fn is_symlink(&self, path: &str) -> bool {
+        self.lock().get(path).is_some_and(|f| f.symlink)
+    }
+
+    /// Returns the paths of all the files, in sorted order.
+    pub fn paths(&self) -> Vec<String> {
+        self.lock().keys().cloned().collect()
+    }
+
+    fn is_dir(&self, path: &str) -> bool {
+        let dir = format!("{}/", path);
+        self.lock()
+            .range(dir.clone()..)
+            .next()
+            .is_some_and(|(p, _)| p.starts_with(&dir))
+    }
+
+    // Returns the names of the things in the directory `dir` (which is empty or ends with a `/`),
+    // along with whether they are directories.
+    fn entries(&self, dir: &str) -> Vec<(String, bool)> {
+        let mut ret: Vec<(String, bool)> = Vec::new();
+        for path in self.lock().keys().filter(|p| p.starts_with(dir)) {
+            let rest = &path[dir.len()..];
+            let (name, is_dir) = match rest.find('/') {
+                Some(i) => (&rest[..i], true),
+                None => (rest, false),
+            };
+            if ret.last().map(|(n, _)| n.as_str()) != Some(name) {
+                ret.push((name.to_owned(), is_dir));
+            }
+        }
+        ret
+    }
+}
+
+fn not_found() -> io::Error {
+    io::ErrorKind::NotFound.into()
+}
+
+impl Vfs for MemoryWorkingCopy {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        MemoryWorkingCopy::read(self, path).ok_or_else(not_found)
+    }
+
+    fn write(&self, path: &str, contents: &[u8]) -> io::Result<()> {
+        MemoryWorkingCopy::write(self, path, contents);
+        Ok(())
+    }
+
+    fn write_symlink(&self, path: &str, target: &[u8]) -> io::Result<()> {
+        MemoryWorkingCopy::write_symlink(self, path, target);
+        Ok(())
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        if MemoryWorkingCopy::remove(self, path) {
+            Ok(())
+        } else {
+            Err(not_found())
+        }
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut files = self.lock();
+        let file = files.remove(from).ok_or_else(not_found)?;
+        files.insert(to.to_owned(), file);
+        Ok(())
+    }
+
+    fn set_executable(&self, path: &str) -> io::Result<()> {
+        self.lock().get_mut(path).ok_or_else(not_found)?.executable = true;
+        Ok(())
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.lock().contains_key(path) || self.is_dir(path)
+    }
+
+    fn is_file(&self, path: &str) -> bool {
+        self.lock().contains_key(path)
+    }
+
+    fn is_symlink(&self, path: &str) -> bool {
+        MemoryWorkingCopy::is_symlink(self, path)
+    }
+
+    fn entries(&self, dir: &str) -> Result<Vec<(String, bool)>, Error> {
+        Ok(MemoryWorkingCopy::entries(self, dir))
+    }
+}
+
+// Runs `f` on the filesystem that the working copy is in.
+fn with_vfs<T, F: FnOnce(&dyn Vfs) -> T>(repo: &Repo, f: F) -> T {
+    match &repo.vfs {
+        Some(vfs) => f(&**vfs),
+        None => f(&NativeVfs::new(&repo.root_dir)),
+    }
+}
+
+// Reads the file at `path`, exactly as it is. For a symbolic link, this is the link's target.
+pub(crate) fn read(repo: &Repo, path: &str) -> io::Result<Vec<u8>> {
+    with_vfs(repo, |vfs| vfs.read(path))
+}
+
+// Is there a file, a symbolic link or a directory at `path`?
+pub(crate) fn exists(repo: &Repo, path: &str) -> bool {
+    with_vfs(repo, |vfs| vfs.exists(path))
+}
+
+// Is there a file (or a symbolic link) at `path`?
+pub(crate) fn is_file(repo: &Repo, path: &str) -> bool {
+    with_vfs(repo, |vfs| vfs.is_file(path))
+}
+
+// Is there a symbolic link at `path`?
+pub(crate) fn is_symlink(repo: &Repo, path: &str) -> bool {
+    with_vfs(repo, |vfs| vfs.is_symlink(path))
+}
+
+// Writes the file at `path` (see `Vfs::write`).
+pub(crate) fn write(repo: &Repo, path: &str, contents: &[u8]) -> io::Result<()> {
+    with_vfs(repo, |vfs| vfs.write(path, contents))
+}
+
+// Makes a symbolic link at `path` pointing to `target`.
+pub(crate) fn write_symlink(repo: &Repo, path: &str, target: &[u8]) -> io::Result<()> {
+    with_vfs(repo, |vfs| vfs.write_symlink(path, target))
+}
+
+// Removes the file at `path`, along with any directories that it leaves empty.
+pub(crate) fn remove(repo: &Repo, path: &str) -> io::Result<()> {
+    with_vfs(repo, |vfs| vfs.remove(path))
+}
+
+// Moves the file at `from` to `to`, which must not exist.
+pub(crate) fn rename(repo: &Repo, from: &str, to: &str) -> io::Result<()> {
+    with_vfs(repo, |vfs| {
+        if vfs.exists(to) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        vfs.rename(from, to)
+    })
+}
+
+// Makes the file at `path` executable.
+pub(crate) fn set_executable(repo: &Repo, path: &str) -> io::Result<()> {
+    with_vfs(repo, |vfs| vfs.set_executable(path))
+}
+
+// Returns the names of the things in the directory `dir` (which is empty or ends with a `/`),
+// along with whether they are directories.
+pub(crate) fn entries(repo: &Repo, dir: &str) -> Result<Vec<(String, bool)>, Error> {
+    with_vfs(repo, |vfs| vfs.entries(dir))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FileStatus, RecordOptions, DEFAULT_WORKING_FILE};
+
+    #[test]
+    fn entries() {
+        let wc = MemoryWorkingCopy::new();
+        wc.write("a.txt", b"");
+        wc.write("dir/b.txt", b"");
+        wc.write("dir/sub/c.txt", b"");
+        wc.write("dir/sub/d.txt", b"");
+        wc.write("e.txt", b"");
+        assert_eq!(
+            wc.entries(""),
+            vec![
+                ("a.txt".to_owned(), false),
+                ("dir".to_owned(), true),
+                ("e.txt".to_owned(), false)
+            ]
+        );
+        assert_eq!(
+            wc.entries("dir/"),
+            vec![("b.txt".to_owned(), false), ("sub".to_owned(), true)]
+        );
+        assert!(wc.is_dir("dir/sub"));
+        assert!(!wc.is_dir("di"));
+        assert!(!wc.is_dir("a.txt"));
+    }
+
+    #[test]
+    fn record_and_checkout() {
+        let wc = MemoryWorkingCopy::new();
+        let mut repo = Repo::init_in_memory(wc.clone());
+        wc.write(DEFAULT_WORKING_FILE, b"a\n");
+        wc.write("dir/b.txt", b"b\n");
+        repo.track_file("dir/b.txt").unwrap();
+        repo.record("Author", "First", &RecordOptions::default())
+            .unwrap();
+        assert_eq!(repo.untracked_files().unwrap(), Vec::<String>::new());
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"a\n");
+        assert_eq!(
+            repo.file_at("master", "dir/b.txt").unwrap().as_bytes(),
+            b"b\n"
+        );
+
+        repo.clone_branch("master", "other").unwrap();
+        repo.checkout("other").unwrap();
+        repo.move_file("dir/b.txt", "c.txt").unwrap();
+        wc.write("c.txt", b"b\nc\n");
+        assert_eq!(wc.paths(), vec!["c.txt", DEFAULT_WORKING_FILE]);
+        assert_eq!(repo.status().unwrap()["c.txt"], FileStatus::Modified);
+        repo.record("Author", "Move", &RecordOptions::default())
+            .unwrap();
+
+        repo.checkout("master").unwrap();
+        assert_eq!(wc.paths(), vec!["dir/b.txt", DEFAULT_WORKING_FILE]);
+        assert_eq!(wc.read("dir/b.txt").unwrap(), b"b\n");
+        repo.merge("other", "master", false).unwrap();
+        assert_eq!(
+            repo.files("master").unwrap().collect::<Vec<_>>(),
+            vec!["c.txt"]
+        );
+        assert_eq!(
+            repo.file_at("master", "c.txt").unwrap().as_bytes(),
+            b"b\nc\n"
+        );
+    }
+
+    #[test]
+    fn stored_in_vfs() {
+        let wc = MemoryWorkingCopy::new();
+        let mut repo = Repo::init_vfs(Arc::new(wc.clone())).unwrap();
+        wc.write(DEFAULT_WORKING_FILE, b"a\n");
+        let id = repo
+            .record("Author", "Msg", &RecordOptions::default())
+            .unwrap();
+        repo.config.author = Some("Someone".to_owned());
+        repo.write().unwrap();
+        assert!(wc.read(".ojo/db").is_some());
+        assert!(repo.untracked_files().unwrap().is_empty());
+        match Repo::init_vfs(Arc::new(wc.clone())) {
+            Err(Error::RepoExists(_)) => {}
+            x => panic!("expected an error, got {:?}", x),
+        }
+
+        let repo = Repo::open_vfs(Arc::new(wc.clone())).unwrap();
+        assert_eq!(repo.patches("master").collect::<Vec<_>>(), vec![&id]);
+        assert_eq!(repo.file("master").unwrap().as_bytes(), b"a\n");
+        assert_eq!(repo.config.author.as_deref(), Some("Someone"));
+        match Repo::open_vfs(Arc::new(MemoryWorkingCopy::new())) {
+            Err(Error::RepoNotFound(_)) => {}
+            x => panic!("expected an error, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn symlinks() {
+        let wc = MemoryWorkingCopy::new();
+        let mut repo = Repo::init_in_memory(wc.clone());
+        repo.config.symlinks = Some(true);
+        wc.write("a.txt", b"a\n");
+        wc.write_symlink("link", b"a.txt");
+        repo.track_file("a.txt").unwrap();
+        repo.track_file("link").unwrap();
+        repo.record("Author", "Msg", &RecordOptions::default())
+            .unwrap();
+        assert!(repo.is_symlink("master", "link").unwrap());
+        assert!(!repo.is_symlink("master", "a.txt").unwrap());
+        assert_eq!(repo.file_at("master", "link").unwrap().as_bytes(), b"a.txt");
+
+        repo.create_branch("empty").unwrap();
+        repo.checkout("empty").unwrap();
+        assert!(wc.paths().is_empty());
+        repo.checkout("master").unwrap();
+        assert!(wc.is_symlink("link"));
+        assert_eq!(wc.read("link").unwrap(), b"a.txt");
+
+        // Without symbolic links, the link becomes a file containing its target, and turning the
+        // links back on brings it back.
+        repo.config.symlinks = Some(false);
+        repo.update_working_copy().unwrap();
+        assert!(!wc.is_symlink("link"));
+        assert_eq!(wc.read("link").unwrap(), b"a.txt");
+        assert_eq!(repo.status().unwrap()["link"], FileStatus::Clean);
+        repo.config.symlinks = Some(true);
+        assert_eq!(repo.update_working_copy().unwrap(), vec!["link"]);
+        assert!(wc.is_symlink("link"));
+    }
+}
@@ -0,0 +1,293 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+// Linked worktrees: other directories with working copies of the same repository (see
+// `Repo::add_worktree`).
+//
+// A linked worktree has a `.ojo` directory too, but the only thing in it is `WORKTREE_FILE`, which
+// says where the repository's own `.ojo` directory is, and holds the state that belongs to this
+// working copy: its current branch, the unrecorded changes to its tracked files and the contents
+// that its files are known to have. Everything else (the patches, the branches and the settings)
+// is shared. The main worktree keeps its state in the database, as it always did, so a linked
+// worktree holds on to that state while the repository is open, and puts it back when it writes
+// the database.
+//
+// Since several processes can now write the same database, writing takes a lock (`LOCK_FILE`),
+// and refuses to overwrite a database that changed since it was read.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::storage::Storage;
+use crate::tracked::{self, TrackedFiles};
+use crate::{BlobHash, Error, Repo};
+
+// The file, in the `.ojo` directory of a linked worktree, that holds its state.
+const WORKTREE_FILE: &str = "worktree";
+
+// The file, in the repository's `.ojo` directory, that exists while the database is being written.
+const LOCK_FILE: &str = "lock";
+
+// The parts of the storage that belong to one working copy.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct WorktreeState {
+    pub current_branch: String,
+    #[serde(default)]
+    pub tracked: TrackedFiles,
+    #[serde(default)]
+    pub rendered: BTreeMap<String, BlobHash>,
+}
+
+impl WorktreeState {
+    // Swaps this state with the one of the working copy that `storage` and `current_branch`
+    // belong to.
+    pub fn swap(&mut self, current_branch: &mut String, storage: &mut Storage) {
+        std::mem::swap(&mut self.current_branch, current_branch);
+        std::mem::swap(&mut self.tracked, &mut storage.tracked);
+        std::mem::swap(&mut self.rendered, &mut storage.rendered);
+    }
+}
+
+// The contents of `WORKTREE_FILE`.
+#[derive(Debug, Deserialize, Serialize)]
+struct WorktreeFile {
+    // The `.ojo` directory of the repository.
+    repo_dir: PathBuf,
+    #[serde(flatten)]
+    state: WorktreeState,
+}
+
+fn worktree_file(root: &Path) -> PathBuf {
+    root.join(".ojo").join(WORKTREE_FILE)
+}
+
+// If `root` is the root of a linked worktree, returns the `.ojo` directory of its repository, and
+// the state of its working copy.
+pub(crate) fn load(root: &Path) -> Result<Option<(PathBuf, WorktreeState)>, Error> {
+    let path = worktree_file(root);
+    let data = match fs::read_to_string(&path) {
+        Ok(data) => data,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::Io(e, format!("Could not read {}", path.display()))),
+    };
+    let file: WorktreeFile = serde_yaml::from_str(&data)?;
+    Ok(Some((file.repo_dir, file.state)))
+}
+
+// Writes out the state of a linked worktree's working copy.
+pub(crate) fn save(repo: &Repo) -> Result<(), Error> {
+    let file = WorktreeFile {
+        repo_dir: repo.repo_dir.clone(),
+        state: WorktreeState {
+            current_branch: repo.current_branch.clone(),
+            tracked: repo.storage.tracked.clone(),
+            rendered: repo.storage.rendered.clone(),
+        },
+    };
+    let path = worktree_file(&repo.root_dir);
+    let tmp_path = path.with_extension("tmp");
+    let data = serde_yaml::to_string(&file)?;
+    fs::write(&tmp_path, data)
+        .map_err(|e| Error::Io(e, format!("Could not write {}", tmp_path.display())))?;
+    fs::rename(&tmp_path, &path)
+        .map_err(|e| Error::Io(e, format!("Could not write {}", path.display())))
+}
+
+// Identifies a version of the database, so that we can tell if someone else wrote it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct Stamp {
+    modified: SystemTime,
+    len: u64,
+}
+
+impl Stamp {
+    // Returns the stamp of the database at `path`, or `None` if there isn't one.
+    pub fn of(path: &Path) -> Result<Option<Stamp>, Error> {
+        match fs::metadata(path) {
+            Ok(meta) => Ok(Some(Stamp {
+                modified: meta.modified()?,
+                len: meta.len(),
+            })),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::Io(e, format!("Could not read {}", path.display()))),
+        }
+    }
+}
+
+// The lock on a repository's database, which is released when this is dropped.
+#[derive(Debug)]
+pub(crate) struct Lock {
+    path: PathBuf,
+    _file: File,
+}
+
+impl Lock {
+    // Locks the database of the repository whose `.ojo` directory is `repo_dir`, failing if
+    // someone else has the lock.
+    pub fn acquire(repo_dir: &Path) -> Result<Lock, Error> {
+        let path = repo_dir.join(LOCK_FILE);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => Ok(Lock { path, _file: file }),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => Err(Error::Locked(path)),
+            Err(e) => Err(Error::Io(e, format!("Could not create {}", path.display()))),
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn canonical_root(dir: &Path) -> Result<PathBuf, Error> {
+    fs::canonicalize(dir).map_err(|e| Error::Io(e, format!("Could not find {}", dir.display())))
+}
+
+pub(crate) fn add(repo: &mut Repo, dir: &Path, branch: &str) -> Result<Repo, Error> {
+    repo.inode(branch)?;
+    if !repo.db_path.is_file() {
+        return Err(Error::RepoNotFound(repo.root_dir.clone()));
+    }
+    let ojo_dir = dir.join(".ojo");
+    if ojo_dir.exists() {
+        return Err(Error::RepoExists(ojo_dir));
+    }
+    fs::create_dir_all(&ojo_dir)
+        .map_err(|e| Error::Io(e, format!("Could not create {}", ojo_dir.display())))?;
+    let root = canonical_root(dir)?;
+
+    repo.storage.worktrees.insert(root.clone());
+    if let Err(e) = repo.write() {
+        repo.storage.worktrees.remove(&root);
+        let _ = fs::remove_dir(&ojo_dir);
+        return Err(e);
+    }
+
+    let file = WorktreeFile {
+        repo_dir: canonical_root(&repo.repo_dir)?,
+        state: WorktreeState {
+            current_branch: branch.to_owned(),
+            ..WorktreeState::default()
+        },
+    };
+    let path = worktree_file(&root);
+    fs::write(&path, serde_yaml::to_string(&file)?)
+        .map_err(|e| Error::Io(e, format!("Could not write {}", path.display())))?;
+    let mut ret = Repo::open(&root)?;
+    tracked::write_branch(&mut ret, branch, &Default::default())?;
+    save(&ret)?;
+    Ok(ret)
+}
+
+pub(crate) fn remove(repo: &mut Repo, dir: &Path) -> Result<(), Error> {
+    let root = canonical_root(dir)?;
+    if !repo.storage.worktrees.contains(&root) {
+        return Err(Error::UnknownWorktree(root));
+    }
+    if canonical_root(&repo.root_dir)? == root {
+        return Err(Error::CurrentWorktree(root));
+    }
+    let path = worktree_file(&root);
+    match fs::remove_file(&path) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(Error::Io(e, format!("Could not remove {}", path.display()))),
+    }
+    let _ = fs::remove_dir(root.join(".ojo"));
+    repo.storage.worktrees.remove(&root);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::tmp_dir;
+    use crate::{RecordOptions, DEFAULT_WORKING_FILE};
+
+    #[test]
+    fn add_and_remove() {
+        let dir = tmp_dir("worktree");
+        let main_dir = dir.join("main");
+        let linked_dir = dir.join("linked");
+        fs::create_dir_all(&main_dir).unwrap();
+        let mut repo = Repo::init(&main_dir).unwrap();
+        fs::write(main_dir.join(DEFAULT_WORKING_FILE), b"a\n").unwrap();
+        repo.record("Author", "Msg", &RecordOptions::default())
+            .unwrap();
+        repo.clone_branch("master", "test").unwrap();
+        repo.write().unwrap();
+
+        let mut linked = repo.add_worktree(&linked_dir, "test").unwrap();
+        assert!(linked.is_linked_worktree());
+        assert!(!repo.is_linked_worktree());
+        assert_eq!(linked.current_branch, "test");
+        assert_eq!(
+            fs::read(linked_dir.join(DEFAULT_WORKING_FILE)).unwrap(),
+            b"a\n"
+        );
+
+        // Recording in the linked worktree changes the shared branch, but leaves the main
+        // worktree alone.
+        fs::write(linked_dir.join(DEFAULT_WORKING_FILE), b"a\nb\n").unwrap();
+        linked
+            .record("Author", "Msg", &RecordOptions::default())
+            .unwrap();
+        linked.write().unwrap();
+
+        // The main worktree's copy of the repository is out of date now.
+        match repo.write() {
+            Err(Error::RepoChanged(_)) => {}
+            x => panic!("expected a change, got {:?}", x),
+        }
+
+        let mut repo = Repo::open(&main_dir).unwrap();
+        assert_eq!(repo.current_branch, "master");
+        assert_eq!(repo.file("test").unwrap().as_bytes(), b"a\nb\n");
+        assert_eq!(
+            fs::read(main_dir.join(DEFAULT_WORKING_FILE)).unwrap(),
+            b"a\n"
+        );
+        let linked_root = canonical_root(&linked_dir).unwrap();
+        assert_eq!(
+            repo.worktrees().collect::<Vec<_>>(),
+            vec![linked_root.as_path()]
+        );
+        assert_eq!(Repo::open(&linked_dir).unwrap().current_branch, "test");
+
+        match linked.remove_worktree(&linked_dir) {
+            Err(Error::CurrentWorktree(_)) => {}
+            x => panic!("expected an error, got {:?}", x),
+        }
+        repo.remove_worktree(&linked_dir).unwrap();
+        repo.write().unwrap();
+        assert_eq!(repo.worktrees().count(), 0);
+        assert!(!linked_dir.join(".ojo").exists());
+        assert!(linked_dir.join(DEFAULT_WORKING_FILE).exists());
+    }
+
+    #[test]
+    fn lock() {
+        let dir = tmp_dir("worktree-lock");
+        let repo = Repo::init(&dir).unwrap();
+        repo.write().unwrap();
+        let lock = Lock::acquire(&repo.repo_dir).unwrap();
+        match repo.write() {
+            Err(Error::Locked(_)) => {}
+            x => panic!("expected a lock error, got {:?}", x),
+        }
+        drop(lock);
+        repo.write().unwrap();
+    }
+}
@@ -0,0 +1,28 @@
+use clap::ArgMatches;
+use failure::{Error, ResultExt};
+use libojo::{ArchiveConflicts, ArchiveFormat};
+
+pub fn run(m: &ArgMatches<'_>) -> Result<(), Error> {
+    // The unwrap is ok because this is a required argument.
+    let out = m.value_of("OUTPUT").unwrap();
+    let repo = crate::open_repo()?;
+    let branch = crate::branch(&repo, m);
+    let format = match m.value_of("format") {
+        Some("zip") => ArchiveFormat::Zip,
+        Some(_) => ArchiveFormat::Tar,
+        None if out.ends_with(".zip") => ArchiveFormat::Zip,
+        None => ArchiveFormat::Tar,
+    };
+    let conflicts = if m.is_present("markers") {
+        ArchiveConflicts::Markers
+    } else {
+        ArchiveConflicts::Refuse
+    };
+
+    // Write the archive to memory first, so that we don't leave a partial file behind if it fails.
+    let mut contents = Vec::new();
+    repo.archive(&branch, format, conflicts, &mut contents)?;
+    std::fs::write(out, contents).with_context(|_| format!("Couldn't create file '{}'", out))?;
+    eprintln!("Successfully wrote the file '{}'", out);
+    Ok(())
+}
@@ -0,0 +1,12 @@
+use clap::ArgMatches;
+use failure::Error;
+
+pub fn run(m: &ArgMatches<'_>) -> Result<(), Error> {
+    // The unwrap is ok because this is a required argument.
+    let branch = m.value_of("BRANCH").unwrap();
+    let mut repo = crate::open_repo()?;
+    repo.checkout(branch)?;
+    repo.write()?;
+    eprintln!("Checked out branch \"{}\"", branch);
+    Ok(())
+}
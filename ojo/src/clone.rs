@@ -0,0 +1,93 @@
+use clap::ArgMatches;
+use failure::{Error, ResultExt};
+use libojo::Repo;
+use std::path::{Path, PathBuf};
+
+pub fn run(m: &ArgMatches<'_>) -> Result<(), Error> {
+    // The unwrap is ok because this is a required argument.
+    let source = m.value_of("SOURCE").unwrap();
+    let url = url(source)?;
+    let dir = match m.value_of("DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => default_dir(source)?,
+    };
+    if dir.read_dir().is_ok_and(|mut d| d.next().is_some()) {
+        return Err(format_err!("The directory {:?} isn't empty", dir));
+    }
+    let created = !dir.exists();
+    std::fs::create_dir_all(&dir)
+        .with_context(|_| format!("Failed to create the directory {:?}", dir))?;
+
+    let result = clone(&url, &dir);
+    // Don't leave a half-made repository behind. The directory was empty, so everything in it
+    // came from the clone.
+    if result.is_err() {
+        if created {
+            let _ = std::fs::remove_dir_all(&dir);
+        } else if let Ok(entries) = dir.read_dir() {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                let _ = if path.is_dir() {
+                    std::fs::remove_dir_all(path)
+                } else {
+                    std::fs::remove_file(path)
+                };
+            }
+        }
+    }
+    let patches = result?;
+    eprintln!("Cloned {} patch(es) into {:?}.", patches, dir);
+    Ok(())
+}
+
+// Makes a repository in `dir` whose "origin" remote is at `url`, and puts the patches on the
+// remote's master branch on its own master branch. Returns the number of patches.
+fn clone(url: &str, dir: &Path) -> Result<usize, Error> {
+    let mut repo = Repo::init(dir)?;
+    repo.add_remote("origin", url)?;
+    let mut conn =
+        libojo::remote::connect(url).context("Failed to connect to the other repository")?;
+    repo.fetch_with("origin", &mut conn)?;
+    let transfer = conn.pull(&mut repo, "master", "master")?;
+    conn.close()?;
+    repo.update_working_copy()?;
+    repo.write()
+        .context("Failed to write repository to disk.")?;
+    Ok(transfer.applied.len())
+}
+
+// The url of the remote: addresses are used as they are, but paths are made absolute so that
+// they still work from inside the new repository.
+fn url(source: &str) -> Result<String, Error> {
+    let is_address =
+        source.contains("://") || source.find(':').is_some_and(|c| !source[..c].contains('/'));
+    if is_address {
+        return Ok(source.to_owned());
+    }
+    let path = Path::new(source)
+        .canonicalize()
+        .with_context(|_| format!("There is no repository at {:?}", source))?;
+    // Not finding the other repository isn't the same as not being in one, so it gets its own
+    // error.
+    if Repo::find_root(&path).is_err() {
+        return Err(format_err!("There is no repository at {:?}", source));
+    }
+    Ok(path.to_string_lossy().into_owned())
+}
+
+// Like git, the new repository is named after the last part of the source.
+fn default_dir(source: &str) -> Result<PathBuf, Error> {
+    let name = source
+        .trim_end_matches('/')
+        .rsplit(['/', ':'])
+        .next()
+        .unwrap_or("");
+    if name.is_empty() || name == "." || name == ".." {
+        Err(format_err!(
+            "Couldn't choose a directory for {:?}; please give one",
+            source
+        ))
+    } else {
+        Ok(PathBuf::from(name))
+    }
+}
@@ -1,23 +1,61 @@
 use clap::ArgMatches;
 use colored::*;
 use failure::{Error, Fail};
-use libojo::Repo;
+use libojo::{DiffAlgorithm, DiffOptions, PendingChanges, Repo};
 use ojo_diff::LineDiff;
+use std::collections::HashMap;
 use std::fmt;
+use std::ops::Range;
 
 pub struct DiffDisplay(pub libojo::Diff);
 
+// Writes out a line, with the parts in `changed` emphasized.
+fn write_line<F: Fn(&str) -> ColoredString>(
+    fmt: &mut fmt::Formatter<'_>,
+    prefix: &str,
+    line: &[u8],
+    changed: Option<&Vec<Range<usize>>>,
+    color: F,
+) -> fmt::Result {
+    write!(fmt, "{}", color(prefix))?;
+    let changed = match changed {
+        Some(c) => c,
+        None => return write!(fmt, "{}", color(&String::from_utf8_lossy(line))),
+    };
+
+    let mut pos = 0;
+    for range in changed {
+        let before = String::from_utf8_lossy(&line[pos..range.start]);
+        let during = String::from_utf8_lossy(&line[range.clone()]);
+        write!(
+            fmt,
+            "{}{}",
+            color(&before),
+            color(&during).bold().underline()
+        )?;
+        pos = range.end;
+    }
+    write!(fmt, "{}", color(&String::from_utf8_lossy(&line[pos..])))
+}
+
 impl fmt::Display for DiffDisplay {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut old_changed = HashMap::new();
+        let mut new_changed = HashMap::new();
+        for c in self.0.changed_lines() {
+            old_changed.insert(c.old_line, c.words.old_changed);
+            new_changed.insert(c.new_line, c.words.new_changed);
+        }
+
         for &ch in &self.0.diff {
             match ch {
                 LineDiff::New(i) => {
-                    let s = format!("+ {}", String::from_utf8_lossy(&self.0.file_b.node(i)));
-                    write!(fmt, "{}", s.green())?;
+                    let line = self.0.file_b.node(i);
+                    write_line(fmt, "+ ", line, new_changed.get(&i), |s| s.green())?;
                 }
                 LineDiff::Delete(i) => {
-                    let s = format!("- {}", String::from_utf8_lossy(&self.0.file_a.node(i)));
-                    write!(fmt, "{}", s.red())?;
+                    let line = self.0.file_a.node(i);
+                    write_line(fmt, "- ", line, old_changed.get(&i), |s| s.red())?;
                 }
                 LineDiff::Keep(i, _) => {
                     write!(fmt, "  {}", String::from_utf8_lossy(&self.0.file_a.node(i)))?;
@@ -28,22 +66,36 @@ impl fmt::Display for DiffDisplay {
     }
 }
 
-pub fn diff(repo: &Repo, branch: &str, file_name: &str) -> Result<libojo::Diff, Error> {
-    let mut path = repo.root_dir.clone();
-    path.push(file_name);
-    let fs_file_contents = std::fs::read(&path)
-        .map_err(|e| e.context(format!("Could not read the file {}", file_name)))?;
-
-    let ret = repo.diff(branch, &fs_file_contents[..]).map_err(|e| {
-        if let libojo::Error::NotOrdered = e {
-            e.context(format!(
-                "Cannot create a diff because the repo's contents aren't ordered"
-            ))
-            .into()
-        } else {
-            Error::from(e)
-        }
-    });
+/// Reads the diff options from the command line arguments, falling back to the ones in the
+/// repository's configuration.
+pub fn options(repo: &Repo, m: &ArgMatches<'_>) -> DiffOptions {
+    let algorithm = match m.value_of("diff-algorithm") {
+        Some("patience") => DiffAlgorithm::Patience,
+        Some("histogram") => DiffAlgorithm::Histogram,
+        // clap checks that the value is one of the allowed ones, so this must be "myers".
+        Some(_) => DiffAlgorithm::Myers,
+        None => return repo.config().diff_options(),
+    };
+    DiffOptions::with_algorithm(algorithm)
+}
+
+pub fn diff(
+    repo: &Repo,
+    branch: &str,
+    file_name: &str,
+    opts: &DiffOptions,
+) -> Result<PendingChanges, Error> {
+    let ret = repo
+        .working_diff(branch, file_name, opts)
+        .map_err(|e| match e {
+            libojo::Error::Io(e, msg) => e.context(msg).into(),
+            libojo::Error::NotOrdered => e
+                .context(format!(
+                    "Cannot create a diff because the repo's contents aren't ordered"
+                ))
+                .into(),
+            e => Error::from(e),
+        });
     Ok(ret?)
 }
 
@@ -52,8 +104,8 @@ pub fn run(m: &ArgMatches<'_>) -> Result<(), Error> {
     let branch = super::branch(&repo, m);
     let file_name = super::file_path(m);
 
-    let diff = diff(&repo, &branch, &file_name)?;
-    print!("{}", DiffDisplay(diff));
+    let pending = diff(&repo, &branch, &file_name, &options(&repo, m))?;
+    print!("{}", DiffDisplay(pending.diff().clone()));
 
     Ok(())
 }
@@ -0,0 +1,22 @@
+use clap::ArgMatches;
+use failure::Error;
+
+pub fn run(m: &ArgMatches<'_>) -> Result<(), Error> {
+    // The unwrap is ok because this is a required argument.
+    let name = m.value_of("REMOTE").unwrap();
+    let mut repo = crate::open_repo()?;
+    let result = repo.fetch(name);
+    // Even if the transfer was interrupted, we keep the patches that arrived so that they don't
+    // need to be sent again.
+    repo.write()?;
+
+    for (branch, transfer) in result? {
+        eprintln!(
+            "{}/{}: received {} patch(es).",
+            name,
+            branch,
+            transfer.transferred.len()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,50 @@
+use clap::ArgMatches;
+use failure::Error;
+
+pub fn run(m: &ArgMatches<'_>) -> Result<(), Error> {
+    match m.subcommand_name() {
+        Some("add") => add_run(m.subcommand_matches("add").unwrap()),
+        Some("list") => list_run(m.subcommand_matches("list").unwrap()),
+        Some("move") => move_run(m.subcommand_matches("move").unwrap()),
+        Some("remove") => remove_run(m.subcommand_matches("remove").unwrap()),
+        _ => panic!("Unknown subcommand"),
+    }
+}
+
+fn add_run(m: &ArgMatches<'_>) -> Result<(), Error> {
+    let mut repo = crate::open_repo()?;
+    // The unwrap is ok, because PATH is a required argument.
+    for path in m.values_of("PATH").unwrap() {
+        repo.track_file(path)?;
+    }
+    repo.write()?;
+    Ok(())
+}
+
+fn list_run(_m: &ArgMatches<'_>) -> Result<(), Error> {
+    let repo = crate::open_repo()?;
+    for path in repo.tracked_files()? {
+        println!("{}", path);
+    }
+    Ok(())
+}
+
+fn move_run(m: &ArgMatches<'_>) -> Result<(), Error> {
+    // The unwraps are ok, because FROM and TO are required arguments.
+    let from = m.value_of("FROM").unwrap();
+    let to = m.value_of("TO").unwrap();
+    let mut repo = crate::open_repo()?;
+    repo.move_file(from, to)?;
+    repo.write()?;
+    Ok(())
+}
+
+fn remove_run(m: &ArgMatches<'_>) -> Result<(), Error> {
+    let mut repo = crate::open_repo()?;
+    // The unwrap is ok, because PATH is a required argument.
+    for path in m.values_of("PATH").unwrap() {
+        repo.untrack_file(path)?;
+    }
+    repo.write()?;
+    Ok(())
+}
@@ -0,0 +1,24 @@
+use clap::ArgMatches;
+use failure::Error;
+use std::time::Duration;
+
+pub fn run(m: &ArgMatches<'_>) -> Result<(), Error> {
+    let days = m.value_of("grace-days").unwrap_or("14");
+    let days: u64 = days
+        .parse()
+        .map_err(|_| format_err!("'{}' is not a number of days", days))?;
+    let dry_run = m.is_present("dry-run");
+
+    let mut repo = super::open_repo()?;
+    let removed = repo.gc(Duration::from_secs(days.saturating_mul(24 * 60 * 60)), dry_run);
+    for id in &removed {
+        println!("{}", id.to_base64());
+    }
+    if dry_run {
+        eprintln!("Would remove {} patch(es).", removed.len());
+    } else {
+        repo.write()?;
+        eprintln!("Removed {} patch(es).", removed.len());
+    }
+    Ok(())
+}
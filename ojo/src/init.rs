@@ -2,8 +2,15 @@ use clap::ArgMatches;
 use failure::{Error, ResultExt};
 use libojo::Repo;
 
-pub fn run(_m: &ArgMatches<'_>) -> Result<(), Error> {
-    let dir = std::env::current_dir().context("Couldn't open the current directory.")?;
+pub fn run(m: &ArgMatches<'_>) -> Result<(), Error> {
+    let dir = match m.value_of("DIR") {
+        Some(dir) => {
+            std::fs::create_dir_all(dir)
+                .with_context(|_| format!("Failed to create the directory {:?}.", dir))?;
+            dir.into()
+        }
+        None => std::env::current_dir().context("Couldn't open the current directory.")?,
+    };
     let repo = Repo::init(&dir)?;
     repo.write()
         .context("Failed to write repository to disk.")?;
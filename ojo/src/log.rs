@@ -1,18 +1,60 @@
 use clap::ArgMatches;
 use failure::Error;
+use libojo::{LogEntryJson, PatchKind, PatchQuery};
+use std::collections::HashSet;
 
 pub fn run(m: &ArgMatches<'_>) -> Result<(), Error> {
     let repo = super::open_repo()?;
     let branch = super::branch(&repo, m);
+    let query = PatchQuery {
+        author: m.value_of("author").map(|s| s.to_owned()),
+        words: m.value_of("grep").map(|s| s.to_owned()),
+        ..Default::default()
+    };
 
-    for patch_id in repo.patches(&branch) {
-        let patch = repo.open_patch(&patch_id)?;
+    // The repository's metadata index gives us the matching patches in chronological order,
+    // without having to open any of them.
+    let on_branch = repo.patches(&branch).collect::<HashSet<_>>();
+    let mut patches = repo.find_patches(&query);
+    patches.retain(|id| on_branch.contains(id));
+
+    if m.is_present("json") {
+        let entries = patches
+            .iter()
+            .map(|id| LogEntryJson::new(&repo, id))
+            .collect::<Result<Vec<_>, _>>()?;
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+        return Ok(());
+    }
+
+    for patch_id in patches {
+        let header = repo.patch_header(&patch_id)?;
         println!("patch {}", patch_id.to_base64());
-        println!("Author: {}", patch.header().author);
+        println!("Author: {}", header.full_author());
+        println!("Date:   {}", header.timestamp.to_rfc2822());
+        match header.kind {
+            PatchKind::Normal => {}
+            PatchKind::Resolution => println!("Kind:   conflict resolution"),
+            PatchKind::Ordering => println!("Kind:   ordering"),
+        }
+        for (key, value) in &header.extra {
+            println!("{}: {}", key, value);
+        }
+        if m.is_present("stat") {
+            let stats = repo.open_patch(&patch_id)?.stats();
+            println!(
+                "Stat:   {} file(s) changed, {} line(s) added, {} line(s) deleted",
+                stats.files, stats.lines_added, stats.lines_deleted
+            );
+        }
         println!();
-        // TODO: dates and sorting.
-        // TODO: better display for multi-line description.
-        println!("\t{}", patch.header().description);
+        for line in header.description.lines() {
+            if line.is_empty() {
+                println!();
+            } else {
+                println!("\t{}", line);
+            }
+        }
         println!();
     }
     Ok(())
@@ -12,15 +12,30 @@ use failure::{Error, ResultExt};
 use flexi_logger::Logger;
 use libojo::Repo;
 
+mod archive;
 mod branch;
+mod checkout;
 mod clear;
+mod clone;
 mod diff;
+mod fetch;
+mod file;
+mod gc;
 mod graph;
 mod init;
 mod log;
+mod merge;
+mod merge_file;
+mod mount;
 pub mod patch;
+mod pull;
+mod push;
+mod record;
+mod remote;
 mod render;
 mod resolve;
+mod serve;
+mod status;
 mod synthesize;
 
 fn main() {
@@ -33,40 +48,61 @@ fn main() {
         .unwrap_or_else(|e| panic!("Logger initialization failed with {}", e));
 
     let result = match m.subcommand_name() {
+        Some("archive") => archive::run(m.subcommand_matches("archive").unwrap()),
         Some("branch") => branch::run(m.subcommand_matches("branch").unwrap()),
+        Some("checkout") => checkout::run(m.subcommand_matches("checkout").unwrap()),
         Some("clear") => clear::run(m.subcommand_matches("clear").unwrap()),
+        Some("clone") => clone::run(m.subcommand_matches("clone").unwrap()),
         Some("diff") => diff::run(m.subcommand_matches("diff").unwrap()),
+        Some("fetch") => fetch::run(m.subcommand_matches("fetch").unwrap()),
+        Some("file") => file::run(m.subcommand_matches("file").unwrap()),
+        Some("gc") => gc::run(m.subcommand_matches("gc").unwrap()),
         Some("graph") => graph::run(m.subcommand_matches("graph").unwrap()),
         Some("init") => init::run(m.subcommand_matches("init").unwrap()),
         Some("log") => log::run(m.subcommand_matches("log").unwrap()),
+        Some("merge") => merge::run(m.subcommand_matches("merge").unwrap()),
+        Some("merge-file") => merge_file::run(m.subcommand_matches("merge-file").unwrap()),
+        Some("mount") => mount::run(m.subcommand_matches("mount").unwrap()),
         Some("patch") => patch::run(m.subcommand_matches("patch").unwrap()),
+        Some("pull") => pull::run(m.subcommand_matches("pull").unwrap()),
+        Some("push") => push::run(m.subcommand_matches("push").unwrap()),
+        Some("record") => record::run(m.subcommand_matches("record").unwrap()),
+        Some("remote") => remote::run(m.subcommand_matches("remote").unwrap()),
         Some("render") => render::run(m.subcommand_matches("render").unwrap()),
         Some("resolve") => resolve::run(m.subcommand_matches("resolve").unwrap()),
+        Some("serve") => serve::run(m.subcommand_matches("serve").unwrap()),
+        Some("status") => status::run(m.subcommand_matches("status").unwrap()),
         Some("synthesize") => synthesize::run(m.subcommand_matches("synthesize").unwrap()),
         _ => panic!("Unknown subcommand"),
     };
 
     if let Err(e) = result {
-        println!("Error: {}", e);
+        eprintln!("Error: {}", e);
         for cause in e.iter_causes() {
-            println!("\tcaused by: {}", cause);
+            eprintln!("\tcaused by: {}", cause);
         }
-        std::process::exit(1);
+        let code = match e
+            .iter_chain()
+            .find_map(|c| c.downcast_ref::<libojo::Error>())
+        {
+            Some(libojo::Error::RepoNotFound(_)) => {
+                eprintln!("(Use 'ojo init' to create a repository, or 'ojo clone' to copy one.)");
+                EXIT_NO_REPO
+            }
+            _ => EXIT_FAILURE,
+        };
+        std::process::exit(code);
     }
 }
 
+// The exit code when a command fails. (Clap also uses it for invalid arguments.)
+const EXIT_FAILURE: i32 = 1;
+// The exit code when a command needs a repository, but there isn't one here.
+const EXIT_NO_REPO: i32 = 2;
+
 fn open_repo() -> Result<libojo::Repo, Error> {
-    let mut dir = std::env::current_dir().context("Could not open the current directory")?;
-    loop {
-        let mut ojo_dir = dir.clone();
-        ojo_dir.push(".ojo");
-        if ojo_dir.is_dir() {
-            return Ok(libojo::Repo::open(dir).context("Failed to open the ojo repository")?);
-        }
-        if !dir.pop() {
-            bail!("Failed to find a ojo repository");
-        }
-    }
+    let dir = std::env::current_dir().context("Could not open the current directory")?;
+    Ok(libojo::Repo::open(dir).context("Failed to open the ojo repository")?)
 }
 
 fn branch(repo: &Repo, m: &ArgMatches<'_>) -> String {
@@ -75,6 +111,53 @@ fn branch(repo: &Repo, m: &ArgMatches<'_>) -> String {
         .to_owned()
 }
 
+// Connects to the repository given by the "REMOTE" argument, which is either the name of a remote
+// or an address.
+fn connect(repo: &Repo, m: &ArgMatches<'_>) -> Result<libojo::remote::Connection, Error> {
+    // The unwrap is ok because this is a required argument.
+    let remote = m.value_of("REMOTE").unwrap();
+    let addr = repo.remote(remote).map_or(remote, |r| r.url.as_str());
+    let conn = match m.value_of("remote-ojo") {
+        Some(remote_ojo) => libojo::remote::SshAddress::parse(addr)?.connect(remote_ojo),
+        None => libojo::remote::connect(addr),
+    };
+    Ok(conn.context("Failed to connect to the other repository")?)
+}
+
+// Returns the branch in the other repository that corresponds to a local branch: either the one
+// given by the "remote-branch" argument, or the one in the configuration of the remote, or the one
+// with the same name.
+fn remote_branch(repo: &Repo, m: &ArgMatches<'_>, branch: &str) -> String {
+    if let Some(b) = m.value_of("remote-branch") {
+        return b.to_owned();
+    }
+    // The unwrap is ok because this is a required argument.
+    match repo.remote(m.value_of("REMOTE").unwrap()) {
+        Ok(remote) => remote.remote_branch(branch).to_owned(),
+        Err(_) => branch.to_owned(),
+    }
+}
+
+// Returns the author given by the "author" argument, or else the one in the configuration.
+fn author(repo: &Repo, m: &ArgMatches<'_>) -> Result<String, Error> {
+    match m.value_of("author") {
+        Some(a) => Ok(a.to_owned()),
+        None => repo
+            .config()
+            .author
+            .ok_or_else(|| format_err!("No author was given, and none is configured")),
+    }
+}
+
+// Returns the email address given by the "email" argument, or else the one in the configuration.
+fn email(repo: &Repo, m: &ArgMatches<'_>) -> Option<String> {
+    m.value_of("email")
+        .map(|s| s.to_owned())
+        .or_else(|| repo.config().email)
+}
+
 fn file_path(m: &ArgMatches<'_>) -> String {
-    m.value_of("path").unwrap_or("ojo_file.txt").to_owned()
+    m.value_of("path")
+        .unwrap_or(libojo::DEFAULT_WORKING_FILE)
+        .to_owned()
 }
@@ -0,0 +1,42 @@
+use clap::ArgMatches;
+use failure::Error;
+use libojo::{Phase, Progress, ProgressSink};
+use std::sync::Arc;
+
+// Prints a line for each patch that gets merged.
+struct MergeProgress;
+
+impl ProgressSink for MergeProgress {
+    fn step(&self, p: &Progress<'_>) {
+        if let (Phase::Merge, Some(id)) = (p.phase, p.patch) {
+            eprintln!("[{}/{}] {}", p.done, p.total, id.to_base64());
+        }
+    }
+}
+
+pub fn run(m: &ArgMatches<'_>) -> Result<(), Error> {
+    // The unwrap is ok because this is a required argument.
+    let from = m.value_of("FROM").unwrap();
+    let dry_run = m.is_present("dry-run");
+
+    let mut repo = crate::open_repo()?;
+    let into = crate::branch(&repo, m);
+    repo.set_progress_sink(Arc::new(MergeProgress));
+    let report = repo.merge(from, &into, dry_run)?;
+
+    if report.applied.is_empty() {
+        eprintln!("No patches to apply.");
+    }
+    let conflicts = report.conflicts.len();
+    if conflicts > 0 {
+        eprintln!("{} conflict(s) on the branch", conflicts);
+    }
+    for (path, conflicts) in &report.file_conflicts {
+        eprintln!("{} conflict(s) in {}", conflicts.len(), path);
+    }
+
+    if !dry_run {
+        repo.write()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,37 @@
+use clap::ArgMatches;
+use failure::{Error, ResultExt};
+
+// Reads one of the files to merge.
+fn read(path: &str) -> Result<Vec<u8>, Error> {
+    Ok(std::fs::read(path).with_context(|_| format!("Couldn't read file '{}'", path))?)
+}
+
+pub fn run(m: &ArgMatches<'_>) -> Result<(), Error> {
+    // The unwraps are ok because these are required arguments.
+    let current = m.value_of("CURRENT").unwrap();
+    let base = m.value_of("BASE").unwrap();
+    let other = m.value_of("OTHER").unwrap();
+
+    let merge = libojo::merge_file(&read(base)?, &read(current)?, &read(other)?)?;
+    let mut out = Vec::new();
+    merge.write_with_markers(current, other, &mut out)?;
+    if m.is_present("stdout") {
+        std::io::Write::write_all(&mut std::io::stdout(), &out)?;
+    } else {
+        std::fs::write(current, out)
+            .with_context(|_| format!("Couldn't write file '{}'", current))?;
+    }
+
+    // Like `git merge-file`, we signal conflicts with the exit status, so that this can be used
+    // as a merge driver.
+    if merge.has_conflicts() {
+        let conflicts = merge
+            .chunks
+            .iter()
+            .filter(|c| matches!(c, libojo::MergeChunk::Conflict { .. }))
+            .count();
+        eprintln!("{} conflict(s) in '{}'", conflicts, current);
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,33 @@
+use clap::ArgMatches;
+use failure::{Error, ResultExt};
+
+#[cfg(feature = "fuse")]
+mod fuse;
+
+pub fn run(m: &ArgMatches<'_>) -> Result<(), Error> {
+    // The unwrap is ok because this is a required argument.
+    let mountpoint = m.value_of("MOUNTPOINT").unwrap();
+    let dir = std::env::current_dir().context("Could not open the current directory")?;
+    // Nothing that the file system does gets saved, so the repository can be opened read-only.
+    let repo = libojo::Repo::open_readonly(dir).context("Failed to open the ojo repository")?;
+    let fs = libojo::HistoryFs::new(repo)?;
+    mount(fs, mountpoint)
+}
+
+#[cfg(feature = "fuse")]
+fn mount(fs: libojo::HistoryFs, mountpoint: &str) -> Result<(), Error> {
+    let session = fuse::Session::mount(mountpoint)
+        .with_context(|_| format!("Failed to mount at '{}'", mountpoint))?;
+    eprintln!(
+        "Mounted the history at {}, until it is unmounted (with 'fusermount -u {}')",
+        mountpoint, mountpoint
+    );
+    Ok(session.run(fs)?)
+}
+
+#[cfg(not(feature = "fuse"))]
+fn mount(_fs: libojo::HistoryFs, _mountpoint: &str) -> Result<(), Error> {
+    Err(format_err!(
+        "This ojo was built without the \"fuse\" feature"
+    ))
+}
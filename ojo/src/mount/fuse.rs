@@ -0,0 +1,431 @@
+// A small FUSE driver, which answers the kernel's requests from a `HistoryFs`.
+//
+// This speaks the kernel's protocol on `/dev/fuse` directly, without libfuse: the file system is
+// read-only and single-threaded, so it only needs a handful of requests. The structs below are
+// the ones in `linux/fuse.h`, written out field by field in the machine's byte order.
+//
+// Mounting needs privileges. As root, we mount the file system ourselves; otherwise, we ask the
+// `fusermount3` (or `fusermount`) helper to do it, and it sends us the open `/dev/fuse` over a
+// socket, like libfuse does.
+
+use libojo::{FsAttr, FsKind, HistoryFs};
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::process::Command;
+
+// The version of the protocol that we speak. The kernel takes care of older and newer versions.
+const KERNEL_VERSION: u32 = 7;
+const KERNEL_MINOR_VERSION: u32 = 31;
+
+// The largest read that we ask for, and the size of the buffer for requests (which needs room for
+// the headers, as well).
+const MAX_READ: u32 = 128 * 1024;
+const BUFFER_SIZE: usize = MAX_READ as usize + 4096;
+
+// How long (in seconds) the kernel can remember attributes and lookups. Nothing ever changes, so
+// that can be as long as it likes.
+const TTL: u64 = 365 * 24 * 60 * 60;
+
+const FUSE_LOOKUP: u32 = 1;
+const FUSE_FORGET: u32 = 2;
+const FUSE_GETATTR: u32 = 3;
+const FUSE_READLINK: u32 = 5;
+const FUSE_OPEN: u32 = 14;
+const FUSE_READ: u32 = 15;
+const FUSE_STATFS: u32 = 17;
+const FUSE_RELEASE: u32 = 18;
+const FUSE_FLUSH: u32 = 25;
+const FUSE_INIT: u32 = 26;
+const FUSE_OPENDIR: u32 = 27;
+const FUSE_READDIR: u32 = 28;
+const FUSE_RELEASEDIR: u32 = 29;
+const FUSE_INTERRUPT: u32 = 36;
+const FUSE_DESTROY: u32 = 38;
+const FUSE_BATCH_FORGET: u32 = 42;
+
+// Tells the kernel to keep the contents of a file cached when it is opened again.
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+
+// The size of `fuse_in_header`.
+const IN_HEADER_SIZE: usize = 40;
+
+/// A mounted file system, which hasn't started answering requests yet.
+pub struct Session {
+    dev: File,
+}
+
+impl Session {
+    /// Mounts a file system at `mountpoint`.
+    pub fn mount(mountpoint: &str) -> io::Result<Session> {
+        if unsafe { libc::geteuid() } == 0 {
+            mount_as_root(mountpoint)
+        } else {
+            mount_with_helper(mountpoint)
+        }
+    }
+
+    /// Answers requests until the file system is unmounted.
+    pub fn run(mut self, mut fs: HistoryFs) -> io::Result<()> {
+        let mut buf = vec![0; BUFFER_SIZE];
+        loop {
+            let len = match self.dev.read(&mut buf) {
+                Ok(len) => len,
+                // The request was interrupted before we got it, or there was a signal.
+                Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // The file system was unmounted.
+                Err(ref e) if e.raw_os_error() == Some(libc::ENODEV) => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            if len < IN_HEADER_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "short request from the kernel",
+                ));
+            }
+            let header = Header {
+                opcode: u32_at(&buf, 4),
+                unique: u64_at(&buf, 8),
+                node: u64_at(&buf, 16),
+            };
+            if header.opcode == FUSE_DESTROY {
+                self.reply(header.unique, Ok(Vec::new()))?;
+                return Ok(());
+            }
+            if let Some(reply) = answer(&mut fs, &header, &buf[IN_HEADER_SIZE..len]) {
+                self.reply(header.unique, reply)?;
+            }
+        }
+    }
+
+    fn reply(&mut self, unique: u64, reply: Result<Vec<u8>, i32>) -> io::Result<()> {
+        let (error, body) = match reply {
+            Ok(body) => (0, body),
+            Err(errno) => (-errno, Vec::new()),
+        };
+        let mut out = Vec::with_capacity(16 + body.len());
+        push_u32(&mut out, 16 + body.len() as u32);
+        push_u32(&mut out, error as u32);
+        push_u64(&mut out, unique);
+        out.extend_from_slice(&body);
+        match self.dev.write(&out) {
+            Ok(_) => Ok(()),
+            // The request was interrupted, so nobody is waiting for the answer.
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn open_dev() -> io::Result<File> {
+    let fd = unsafe {
+        libc::open(
+            b"/dev/fuse\0".as_ptr() as *const libc::c_char,
+            libc::O_RDWR | libc::O_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+fn c_string(s: &str) -> io::Result<CString> {
+    CString::new(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+fn mount_as_root(mountpoint: &str) -> io::Result<Session> {
+    use std::os::unix::io::AsRawFd;
+
+    let dev = open_dev()?;
+    let options = format!("fd={},rootmode=40000,user_id=0,group_id=0", dev.as_raw_fd());
+    let (source, target) = (c_string("ojo")?, c_string(mountpoint)?);
+    let (fstype, options) = (c_string("fuse.ojo")?, c_string(&options)?);
+    let ret = unsafe {
+        libc::mount(
+            source.as_ptr(),
+            target.as_ptr(),
+            fstype.as_ptr(),
+            libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV,
+            options.as_ptr() as *const libc::c_void,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Session { dev })
+}
+
+fn mount_with_helper(mountpoint: &str) -> io::Result<Session> {
+    let mut fds = [0; 2];
+    if unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Our end of the socket isn't needed by the helper.
+    unsafe { libc::fcntl(fds[0], libc::F_SETFD, libc::FD_CLOEXEC) };
+    let ours = unsafe { File::from_raw_fd(fds[0]) };
+    let theirs = unsafe { File::from_raw_fd(fds[1]) };
+
+    let mut status = None;
+    for helper in &["fusermount3", "fusermount"] {
+        let result = Command::new(helper)
+            .args([
+                "-o",
+                "ro,nosuid,nodev,fsname=ojo,subtype=ojo",
+                "--",
+                mountpoint,
+            ])
+            .env("_FUSE_COMMFD", fds[1].to_string())
+            .status();
+        match result {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            result => {
+                status = Some(result?);
+                break;
+            }
+        }
+    }
+    drop(theirs);
+    match status {
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "mounting needs fusermount3 or fusermount",
+        )),
+        Some(s) if !s.success() => Err(io::Error::other(format!("fusermount failed ({})", s))),
+        Some(_) => Ok(Session {
+            dev: receive_fd(&ours)?,
+        }),
+    }
+}
+
+// Receives the file descriptor that fusermount sends over the socket.
+fn receive_fd(socket: &File) -> io::Result<File> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+        iov_len: 1,
+    };
+    let space = unsafe { libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) } as usize;
+    // A `u64` buffer keeps the control message aligned.
+    let mut control = vec![0u64; space.div_ceil(8)];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+
+    if unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    if cmsg.is_null() || unsafe { (*cmsg).cmsg_type } != libc::SCM_RIGHTS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "fusermount didn't send a file descriptor",
+        ));
+    }
+    let fd = unsafe { std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd) };
+    unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+// The parts of `fuse_in_header` that we need.
+struct Header {
+    opcode: u32,
+    unique: u64,
+    node: u64,
+}
+
+fn u32_at(buf: &[u8], i: usize) -> u32 {
+    let mut b = [0; 4];
+    b.copy_from_slice(&buf[i..i + 4]);
+    u32::from_ne_bytes(b)
+}
+
+fn u64_at(buf: &[u8], i: usize) -> u64 {
+    let mut b = [0; 8];
+    b.copy_from_slice(&buf[i..i + 8]);
+    u64::from_ne_bytes(b)
+}
+
+fn push_u16(out: &mut Vec<u8>, x: u16) {
+    out.extend_from_slice(&x.to_ne_bytes());
+}
+
+fn push_u32(out: &mut Vec<u8>, x: u32) {
+    out.extend_from_slice(&x.to_ne_bytes());
+}
+
+fn push_u64(out: &mut Vec<u8>, x: u64) {
+    out.extend_from_slice(&x.to_ne_bytes());
+}
+
+fn mode(attr: &FsAttr) -> u32 {
+    match attr.kind {
+        FsKind::Directory => libc::S_IFDIR | 0o555,
+        FsKind::Symlink => libc::S_IFLNK | 0o777,
+        FsKind::File if attr.executable => libc::S_IFREG | 0o555,
+        FsKind::File => libc::S_IFREG | 0o444,
+    }
+}
+
+// Writes out a `fuse_attr`.
+fn push_attr(out: &mut Vec<u8>, attr: &FsAttr) {
+    let time = attr.modified.timestamp().max(0) as u64;
+    push_u64(out, attr.inode);
+    push_u64(out, attr.size);
+    push_u64(out, attr.size.div_ceil(512));
+    for _ in 0..3 {
+        push_u64(out, time);
+    }
+    for _ in 0..3 {
+        push_u32(out, 0);
+    }
+    push_u32(out, mode(attr));
+    push_u32(out, if attr.kind == FsKind::Directory { 2 } else { 1 });
+    push_u32(out, unsafe { libc::getuid() });
+    push_u32(out, unsafe { libc::getgid() });
+    // The device, the block size and the flags.
+    push_u32(out, 0);
+    push_u32(out, 512);
+    push_u32(out, 0);
+}
+
+// Converts the errors from the file system into error numbers, after logging the unexpected ones.
+fn errno(e: libojo::Error) -> i32 {
+    match e {
+        libojo::Error::UnknownInode(_) => libc::ENOENT,
+        e => {
+            error!("{}", e);
+            libc::EIO
+        }
+    }
+}
+
+// Answers a request, or returns `None` if the kernel doesn't want an answer.
+fn answer(fs: &mut HistoryFs, header: &Header, body: &[u8]) -> Option<Result<Vec<u8>, i32>> {
+    let node = header.node;
+    let mut out = Vec::new();
+    let reply = match header.opcode {
+        FUSE_INIT => {
+            if body.len() < 16 || u32_at(body, 0) < KERNEL_VERSION {
+                return Some(Err(libc::EPROTO));
+            }
+            // This is `fuse_init_out`.
+            push_u32(&mut out, KERNEL_VERSION);
+            push_u32(&mut out, KERNEL_MINOR_VERSION);
+            push_u32(&mut out, u32_at(body, 8));
+            // The flags, the number of background requests and the congestion threshold.
+            push_u32(&mut out, 0);
+            push_u16(&mut out, 16);
+            push_u16(&mut out, 12);
+            push_u32(&mut out, MAX_READ);
+            // The granularity of times (in nanoseconds), and the rest.
+            push_u32(&mut out, 1_000_000_000);
+            out.resize(64, 0);
+            Ok(out)
+        }
+        FUSE_FORGET | FUSE_BATCH_FORGET | FUSE_INTERRUPT => return None,
+        FUSE_LOOKUP => {
+            let name = body.split(|&b| b == 0).next().unwrap_or(&[]);
+            let name = std::str::from_utf8(name).map_err(|_| libc::ENOENT);
+            match name.and_then(|n| fs.lookup(node, n).map_err(errno)) {
+                Ok(Some(attr)) => {
+                    // This is `fuse_entry_out`.
+                    push_u64(&mut out, attr.inode);
+                    push_u64(&mut out, 0);
+                    push_u64(&mut out, TTL);
+                    push_u64(&mut out, TTL);
+                    push_u32(&mut out, 0);
+                    push_u32(&mut out, 0);
+                    push_attr(&mut out, &attr);
+                    Ok(out)
+                }
+                Ok(None) => Err(libc::ENOENT),
+                Err(e) => Err(e),
+            }
+        }
+        FUSE_GETATTR => fs.attr(node).map_err(errno).map(|attr| {
+            // This is `fuse_attr_out`.
+            push_u64(&mut out, TTL);
+            push_u32(&mut out, 0);
+            push_u32(&mut out, 0);
+            push_attr(&mut out, &attr);
+            out
+        }),
+        FUSE_READLINK => fs.contents(node).map(|c| c.to_vec()).map_err(errno),
+        FUSE_OPEN | FUSE_OPENDIR => {
+            let want_dir = header.opcode == FUSE_OPENDIR;
+            match fs.attr(node).map_err(errno) {
+                Ok(ref a) if want_dir && a.kind != FsKind::Directory => Err(libc::ENOTDIR),
+                Ok(ref a) if !want_dir && a.kind == FsKind::Directory => Err(libc::EISDIR),
+                Ok(_) => {
+                    // This is `fuse_open_out`, with no file handle.
+                    push_u64(&mut out, 0);
+                    push_u32(&mut out, if want_dir { 0 } else { FOPEN_KEEP_CACHE });
+                    push_u32(&mut out, 0);
+                    Ok(out)
+                }
+                Err(e) => Err(e),
+            }
+        }
+        FUSE_READ | FUSE_READDIR if body.len() < 24 => Err(libc::EINVAL),
+        FUSE_READ => {
+            let (offset, size) = (u64_at(body, 8), u32_at(body, 16));
+            fs.read(node, offset, size as usize)
+                .map(|data| data.to_vec())
+                .map_err(errno)
+        }
+        FUSE_READDIR => {
+            let (offset, size) = (u64_at(body, 8), u32_at(body, 16) as usize);
+            fs.read_dir(node).map_err(errno).map(|entries| {
+                // The offset of each entry is the position of the one after it, so that the
+                // kernel can carry on from there. The kernel doesn't look at the inode of "..".
+                let dots = vec![
+                    (node, libc::DT_DIR, ".".to_owned()),
+                    (node, libc::DT_DIR, "..".to_owned()),
+                ];
+                let entries = entries.into_iter().map(|e| {
+                    let kind = match e.kind {
+                        FsKind::Directory => libc::DT_DIR,
+                        FsKind::File => libc::DT_REG,
+                        FsKind::Symlink => libc::DT_LNK,
+                    };
+                    (e.inode, kind, e.name)
+                });
+                let all = dots.into_iter().chain(entries).enumerate();
+                for (i, (inode, kind, name)) in all.skip(offset as usize) {
+                    // This is `fuse_dirent`, padded to a multiple of 8 bytes.
+                    let len = (24 + name.len()).div_ceil(8) * 8;
+                    if out.len() + len > size {
+                        break;
+                    }
+                    push_u64(&mut out, inode);
+                    push_u64(&mut out, i as u64 + 1);
+                    push_u32(&mut out, name.len() as u32);
+                    push_u32(&mut out, u32::from(kind));
+                    out.extend_from_slice(name.as_bytes());
+                    out.resize(out.len().div_ceil(8) * 8, 0);
+                }
+                out
+            })
+        }
+        FUSE_STATFS => {
+            // This is `fuse_kstatfs`: there are no free blocks or inodes, and names can have
+            // up to 255 bytes.
+            out.resize(40, 0);
+            push_u32(&mut out, 512);
+            push_u32(&mut out, 255);
+            push_u32(&mut out, 512);
+            out.resize(80, 0);
+            Ok(out)
+        }
+        FUSE_RELEASE | FUSE_RELEASEDIR | FUSE_FLUSH => Ok(out),
+        _ => Err(libc::ENOSYS),
+    };
+    Some(reply)
+}
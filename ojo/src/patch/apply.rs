@@ -1,13 +1,12 @@
 use clap::ArgMatches;
 use failure::Error;
-use libojo::PatchId;
 
 pub fn run(m: &ArgMatches<'_>) -> Result<(), Error> {
     // The unwrap is ok because this is a required argument.
     let patch_id = m.value_of("PATCH").unwrap();
-    let patch_id = PatchId::from_base64(patch_id)?;
 
     let mut repo = crate::open_repo()?;
+    let patch_id = repo.resolve_patch_prefix(patch_id)?;
     let branch = crate::branch(&repo, m);
 
     if m.is_present("revert") {
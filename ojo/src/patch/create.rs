@@ -1,17 +1,17 @@
 use clap::ArgMatches;
 use failure::Error;
-use libojo::Changes;
+use libojo::PatchHeader;
 
 pub fn run(m: &ArgMatches<'_>) -> Result<(), Error> {
-    // The unwraps are ok because these are required arguments.
+    // The unwrap is ok because this is a required argument.
     let msg = m.value_of("description").unwrap();
-    let author = m.value_of("author").unwrap();
 
     let mut repo = crate::open_repo()?;
+    let author = crate::author(&repo, m)?;
     let branch = crate::branch(&repo, m);
     let path = crate::file_path(m);
-    let diff = crate::diff::diff(&repo, &branch, &path)?;
-    let changes = Changes::from_diff(&diff.file_a, &diff.file_b, &diff.diff);
+    let pending = crate::diff::diff(&repo, &branch, &path, &crate::diff::options(&repo, m))?;
+    let changes = pending.changes();
     let output_hash = m.is_present("output-hash");
 
     if changes.changes.is_empty() {
@@ -21,7 +21,20 @@ pub fn run(m: &ArgMatches<'_>) -> Result<(), Error> {
         return Ok(());
     }
 
-    let id = repo.create_patch(author, msg, changes)?;
+    let mut header = PatchHeader::new(author, msg.to_owned());
+    header.email = crate::email(&repo, m);
+    for kv in m.values_of("meta").into_iter().flatten() {
+        match kv.find('=') {
+            Some(i) => {
+                header
+                    .extra
+                    .insert(kv[..i].to_owned(), kv[(i + 1)..].to_owned());
+            }
+            None => bail!("Expected metadata of the form KEY=VALUE, found \"{}\"", kv),
+        }
+    }
+
+    let id = repo.create_patch_with_header(header, changes)?;
     if m.is_present("then-apply") {
         repo.apply_patch(&branch, &id)?;
         repo.write()?;
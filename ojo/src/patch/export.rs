@@ -1,17 +1,25 @@
 use clap::ArgMatches;
 use failure::{Error, ResultExt};
-use libojo::PatchId;
 
 pub fn run(m: &ArgMatches<'_>) -> Result<(), Error> {
     // The unwrap is ok because this is a required argument.
     let hash = m.value_of("PATCH").unwrap();
-    let out = m.value_of("output").unwrap_or(hash);
 
-    let repo = crate::open_repo()?;
-    let id = PatchId::from_base64(hash)?;
-    let patch_data = repo.open_patch_data(&id)?;
+    let mut repo = crate::open_repo()?;
+    let id = repo.resolve_patch_prefix(hash)?;
+    let name = id.to_base64();
+    let out = m.value_of("output").unwrap_or(&name);
+    let patch_data = if m.is_present("text") {
+        repo.patch_text(&id)?.into_bytes()
+    } else {
+        repo.open_patch_data(&id)?.to_owned()
+    };
     std::fs::write(out, patch_data).with_context(|_| format!("Couldn't create file '{}'", out))?;
 
+    // Now that the patch has left the repository, it shouldn't be rewritten.
+    repo.mark_published(&id)?;
+    repo.write()?;
+
     eprintln!("Successfully wrote the file '{}'", out);
     Ok(())
 }
@@ -8,7 +8,13 @@ pub fn run(m: &ArgMatches<'_>) -> Result<(), Error> {
     let mut repo = crate::open_repo()?;
     let contents =
         std::fs::read(path).with_context(|_| format!("Failed to read file '{}'", path))?;
-    let id = repo.register_patch(&contents)?;
+    let id = if contents.starts_with(b"patch ") {
+        let text = String::from_utf8(contents)
+            .with_context(|_| format!("Failed to read file '{}'", path))?;
+        repo.register_patch_text(&text)?
+    } else {
+        repo.register_patch(&contents)?
+    };
     repo.write()?;
 
     eprintln!("Successfully imported a patch with id {}", id.to_base64());
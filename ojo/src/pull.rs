@@ -0,0 +1,23 @@
+use clap::ArgMatches;
+use failure::Error;
+
+pub fn run(m: &ArgMatches<'_>) -> Result<(), Error> {
+    let mut repo = crate::open_repo()?;
+    let branch = crate::branch(&repo, m);
+    let remote_branch = crate::remote_branch(&repo, m, &branch);
+    let mut conn = crate::connect(&repo, m)?;
+
+    let result = conn.pull(&mut repo, &remote_branch, &branch);
+    // Even if the transfer was interrupted, we keep the patches that arrived so that they don't
+    // need to be sent again.
+    repo.write()?;
+    let transfer = result?;
+    conn.close()?;
+
+    eprintln!(
+        "Received {} patch(es), applied {}.",
+        transfer.transferred.len(),
+        transfer.applied.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,29 @@
+use clap::ArgMatches;
+use failure::Error;
+
+pub fn run(m: &ArgMatches<'_>) -> Result<(), Error> {
+    let mut repo = crate::open_repo()?;
+    let branch = crate::branch(&repo, m);
+    let mut conn = crate::connect(&repo, m)?;
+
+    // The unwrap is ok because this is a required argument.
+    let remote = m.value_of("REMOTE").unwrap();
+    let transfer = match m.value_of("remote-branch") {
+        // Pushing to a configured remote keeps track of what it has.
+        None if repo.remote(remote).is_ok() => repo.push_with(remote, &branch, &mut conn)?,
+        remote_branch => {
+            let remote_branch = remote_branch.unwrap_or(&branch).to_owned();
+            conn.push(&mut repo, &branch, &remote_branch)?
+        }
+    };
+    conn.close()?;
+    // The patches that were sent are now published.
+    repo.write()?;
+
+    eprintln!(
+        "Sent {} patch(es), applied {}.",
+        transfer.transferred.len(),
+        transfer.applied.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,25 @@
+use clap::ArgMatches;
+use failure::Error;
+use libojo::RecordOptions;
+
+pub fn run(m: &ArgMatches<'_>) -> Result<(), Error> {
+    // The unwrap is ok because this is a required argument.
+    let msg = m.value_of("description").unwrap();
+
+    let mut repo = crate::open_repo()?;
+    let author = crate::author(&repo, m)?;
+    let opts = RecordOptions {
+        path: crate::file_path(m),
+        diff: crate::diff::options(&repo, m),
+        email: crate::email(&repo, m),
+        ..RecordOptions::default()
+    };
+    let id = repo.record(&author, msg, &opts)?;
+    repo.write()?;
+    if m.is_present("output-hash") {
+        println!("{}", id.to_base64());
+    } else {
+        eprintln!("Recorded patch {}", id.to_base64());
+    }
+    Ok(())
+}
@@ -0,0 +1,40 @@
+use clap::ArgMatches;
+use failure::Error;
+
+pub fn run(m: &ArgMatches<'_>) -> Result<(), Error> {
+    match m.subcommand_name() {
+        Some("add") => add_run(m.subcommand_matches("add").unwrap()),
+        Some("list") => list_run(m.subcommand_matches("list").unwrap()),
+        Some("remove") => remove_run(m.subcommand_matches("remove").unwrap()),
+        _ => panic!("Unknown subcommand"),
+    }
+}
+
+fn add_run(m: &ArgMatches<'_>) -> Result<(), Error> {
+    // The unwraps are ok, because NAME and URL are required arguments.
+    let name = m.value_of("NAME").unwrap();
+    let url = m.value_of("URL").unwrap();
+    let mut repo = crate::open_repo()?;
+    repo.add_remote(name, url)?;
+    repo.write()?;
+    eprintln!("Added remote \"{}\"", name);
+    Ok(())
+}
+
+fn list_run(_m: &ArgMatches<'_>) -> Result<(), Error> {
+    let repo = crate::open_repo()?;
+    for (name, remote) in &repo.config().remotes {
+        println!("{}\t{}", name, remote.url);
+    }
+    Ok(())
+}
+
+fn remove_run(m: &ArgMatches<'_>) -> Result<(), Error> {
+    // The unwrap is ok, because NAME is a required argument.
+    let name = m.value_of("NAME").unwrap();
+    let mut repo = crate::open_repo()?;
+    repo.remove_remote(name)?;
+    repo.write()?;
+    eprintln!("Removed remote \"{}\"", name);
+    Ok(())
+}
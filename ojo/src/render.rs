@@ -5,6 +5,20 @@ pub fn run(m: &ArgMatches<'_>) -> Result<(), Error> {
     let path = crate::file_path(m);
     let repo = crate::open_repo()?;
     let branch = crate::branch(&repo, m);
+    let format = repo.config().text_format(&path);
+    if let Some(contents) = repo.binary(&branch)? {
+        std::fs::write(&path, contents)?;
+        eprintln!("Successfully wrote file '{}'", path);
+        return Ok(());
+    }
+    if m.is_present("markers") {
+        let mut contents = Vec::new();
+        repo.render(&branch, &mut contents)?;
+        std::fs::write(&path, format.to_working(contents))?;
+        eprintln!("Successfully wrote file '{}'", path);
+        return Ok(());
+    }
+
     let file = repo.file(&branch).map_err(|e| match e {
         libojo::Error::NotOrdered => {
             err_msg("Couldn't render a file, because the data isn't ordered")
@@ -12,7 +26,7 @@ pub fn run(m: &ArgMatches<'_>) -> Result<(), Error> {
         other => other.into(),
     })?;
 
-    std::fs::write(&path, file.as_bytes())?;
+    std::fs::write(&path, format.to_working(file.as_bytes().to_owned()))?;
     eprintln!("Successfully wrote file '{}'", path);
 
     Ok(())
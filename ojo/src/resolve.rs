@@ -10,10 +10,8 @@ use termion::screen::AlternateScreen;
 use termion::{clear, cursor, style};
 
 pub fn run(m: &ArgMatches<'_>) -> Result<(), Error> {
-    // The unwrap is ok because this is a required argument.
-    let author = m.value_of("author").unwrap();
-
     let mut repo = super::open_repo()?;
+    let author = super::author(&repo, m)?;
     let branch = super::branch(&repo, m);
     let graggle = repo.graggle(&branch)?;
     let testing = m.is_present("testing");
@@ -50,7 +48,7 @@ pub fn run(m: &ArgMatches<'_>) -> Result<(), Error> {
     std::io::stdout().flush()?;
 
     if let Some(changes) = changes {
-        let id = repo.create_patch(author, "Resolve to a file", changes)?;
+        let id = repo.create_resolution_patch(&author, "Resolve to a file", changes)?;
         repo.write()?;
         eprintln!("Created patch {}", id.to_base64());
     } else {
@@ -0,0 +1,58 @@
+use clap::ArgMatches;
+use failure::{Error, ResultExt};
+use libojo::Repo;
+
+pub fn run(m: &ArgMatches<'_>) -> Result<(), Error> {
+    // The unwrap is ok because this is a required argument.
+    let path = m.value_of("PATH").unwrap();
+    if let Some(addr) = m.value_of("http") {
+        return serve_http(addr, path);
+    }
+    if let Some(addr) = m.value_of("grpc") {
+        return serve_grpc(addr, path);
+    }
+    let mut repo = Repo::open(path).context("Failed to open the ojo repository")?;
+
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
+    let result = libojo::remote::serve(&mut repo, stdin.lock(), stdout.lock());
+    // Even if the client went away in the middle of a push, we keep the patches that arrived so
+    // that they don't need to be sent again.
+    repo.write()?;
+    Ok(result?)
+}
+
+#[cfg(feature = "http-server")]
+fn serve_http(addr: &str, path: &str) -> Result<(), Error> {
+    let addr = addr
+        .parse()
+        .map_err(|_| format_err!("'{}' is not a valid address", addr))?;
+    // Check that there is a repository, before waiting for requests.
+    let repo = Repo::open(path).context("Failed to open the ojo repository")?;
+    eprintln!("Serving {} at http://{}", path, addr);
+    Ok(libojo::remote::run_http_server(&addr, repo.root_dir)?)
+}
+
+#[cfg(not(feature = "http-server"))]
+fn serve_http(_addr: &str, _path: &str) -> Result<(), Error> {
+    Err(format_err!(
+        "This ojo was built without the \"http-server\" feature"
+    ))
+}
+
+#[cfg(feature = "grpc")]
+fn serve_grpc(addr: &str, path: &str) -> Result<(), Error> {
+    let addr = addr
+        .parse()
+        .map_err(|_| format_err!("'{}' is not a valid address", addr))?;
+    let repo = Repo::open(path).context("Failed to open the ojo repository")?;
+    eprintln!("Serving {} at grpc://{}", path, addr);
+    Ok(libojo::remote::run_grpc_server(&addr, repo)?)
+}
+
+#[cfg(not(feature = "grpc"))]
+fn serve_grpc(_addr: &str, _path: &str) -> Result<(), Error> {
+    Err(format_err!(
+        "This ojo was built without the \"grpc\" feature"
+    ))
+}
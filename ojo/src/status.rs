@@ -0,0 +1,25 @@
+use clap::ArgMatches;
+use failure::Error;
+use libojo::FileStatus;
+use std::collections::BTreeMap;
+
+pub fn run(_m: &ArgMatches<'_>) -> Result<(), Error> {
+    let repo = super::open_repo()?;
+    let moved = repo.moved_files().collect::<BTreeMap<_, _>>();
+    for (path, status) in repo.status()? {
+        if let Some(from) = moved.get(path.as_str()) {
+            println!("{:<16} {} -> {}", "moved", from, path);
+        }
+        let label = match status {
+            FileStatus::Clean => continue,
+            FileStatus::Modified => "modified".to_owned(),
+            FileStatus::Conflicted(n) => format!("conflicted ({})", n),
+            FileStatus::Missing => "missing".to_owned(),
+            FileStatus::Untracked => "untracked".to_owned(),
+            FileStatus::Added => "added".to_owned(),
+            FileStatus::Removed => "removed".to_owned(),
+        };
+        println!("{:<16} {}", label, path);
+    }
+    Ok(())
+}
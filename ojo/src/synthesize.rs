@@ -31,12 +31,12 @@ pub fn run(_m: &ArgMatches<'_>) -> Result<(), Error> {
         .max()
         .ok_or_else(|| err_msg("Input was empty."))?;
     let new_nodes = (0..=max_node).map(|i| Change::NewNode {
-        id: NodeId::cur(i as u64),
+        id: NodeId::staged(i as u64),
         contents: format!("Line {}\n", i).into_bytes(),
     });
     let new_edges = edges.into_iter().map(|(i, j)| Change::NewEdge {
-        src: NodeId::cur(i as u64),
-        dest: NodeId::cur(j as u64),
+        src: NodeId::staged(i as u64),
+        dest: NodeId::staged(j as u64),
     });
     let changes = Changes {
         changes: new_nodes.chain(new_edges).collect::<Vec<_>>(),
@@ -57,6 +57,41 @@ EOF
     assert_output "Error: Couldn't render a file, because the data isn't ordered"
 }
 
+@test "render with conflict markers" {
+    $OJO init
+    cat > ojo_file.txt <<EOF
+First
+Last
+EOF
+    HASH=`$OJO patch create -a Author -m Msg --output-hash`
+    $OJO patch apply "$HASH"
+
+    cat > ojo_file.txt <<EOF
+First
+Second
+Last
+EOF
+    HASH_A=`$OJO patch create -a Author -m Msg --output-hash`
+
+    cat > ojo_file.txt <<EOF
+First
+Middle
+Last
+EOF
+    HASH_B=`$OJO patch create -a Author -m Msg --output-hash`
+
+    $OJO patch apply "$HASH_A"
+    $OJO patch apply "$HASH_B"
+    run $OJO render --markers
+    assert_success
+    run cat ojo_file.txt
+    assert_line --index 0 "First"
+    assert_line --index 1 --regexp "^<<<<<<< P"
+    assert_line --index 3 --regexp "^======= P"
+    assert_line --index 5 ">>>>>>>"
+    assert_line --index 6 "Last"
+}
+
 @test "delete and undelete" {
     $OJO init
     echo "Test" > ojo_file.txt
@@ -83,3 +118,20 @@ EOF
     $OJO patch apply "$HASH"
 }
 
+
+@test "apply by prefix" {
+    $OJO init
+    echo "First" > ojo_file.txt
+    HASH=`$OJO patch create -a Author -m Msg --output-hash`
+    $OJO patch apply "${HASH:0:10}"
+
+    run $OJO log
+    assert_line --index 0 "patch $HASH"
+}
+
+@test "unknown prefix" {
+    $OJO init
+    run $OJO patch apply "Pnothing"
+    assert_failure
+    assert_output --partial "There is no patch starting with"
+}
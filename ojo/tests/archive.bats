@@ -0,0 +1,36 @@
+#!./libs/bats-core/bin/bats
+
+load 'libs/setup'
+
+@test "archive: tar and zip" {
+    $OJO init
+    mkdir dir
+    echo "Content" > dir/file.txt
+    $OJO file add dir/file.txt
+    $OJO record -a Me -m Msg
+
+    run $OJO archive out.tar
+    assert_success
+    assert_output "Successfully wrote the file 'out.tar'"
+    run tar -tf out.tar
+    assert_output "dir/file.txt"
+
+    # The format is guessed from the file name, unless it's given explicitly.
+    run $OJO archive out.zip
+    assert_success
+    assert [ "`head -c 2 out.zip`" = "PK" ]
+    run $OJO archive --format zip out
+    assert_success
+    assert [ "`head -c 2 out`" = "PK" ]
+}
+
+@test "archive: conflicts" {
+    echo "0-1 0-2 1-3 2-3" | $OJO synthesize
+
+    run $OJO archive out.tar
+    assert_failure
+    assert [ ! -e out.tar ]
+
+    run $OJO archive --markers out.tar
+    assert_success
+}
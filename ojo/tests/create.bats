@@ -23,6 +23,17 @@ load 'libs/setup'
     assert_failure
 }
 
+@test "patch create: author from config" {
+    $OJO init
+    echo 'author = "me"' > "$OJO_USER_CONFIG"
+    echo 'email = "me@example.com"' > .ojo/config.toml
+    echo a > ojo_file.txt
+    run $OJO patch create -m msg --then-apply
+    assert_success
+    run $OJO log
+    assert_output --partial "Author: me <me@example.com>"
+}
+
 @test "patch create: msg required" {
     $OJO init
     touch ojo_file.txt
@@ -62,3 +73,16 @@ load 'libs/setup'
     assert_success
     assert_output --regexp "^P[-=_a-zA-Z0-9]{44}$"
 }
+
+@test "patch create: diff-algorithm" {
+    $OJO init
+    printf "a\nb\nc\n" > ojo_file.txt
+    $OJO patch create -a me -m msg --then-apply
+    for alg in myers patience histogram; do
+        printf "c\nb\na\n$alg\n" > ojo_file.txt
+        $OJO patch create -a me -m msg --then-apply --diff-algorithm $alg
+        $OJO render --path out.txt
+        run cat out.txt
+        assert_line --index 3 "$alg"
+    done
+}
@@ -0,0 +1,39 @@
+#!./libs/bats-core/bin/bats
+
+load 'libs/setup'
+
+@test "track, record and check out files" {
+    $OJO init
+    mkdir dir
+    echo A > dir/a.txt
+    echo B > b.txt
+    run $OJO file add dir/a.txt b.txt
+    assert_success
+    run $OJO status
+    assert_line --index 0 "added            b.txt"
+    assert_line --index 1 "added            dir/a.txt"
+
+    run $OJO record -a Author -m Msg
+    assert_success
+    run $OJO file list
+    assert_line --index 0 "b.txt"
+    assert_line --index 1 "dir/a.txt"
+
+    $OJO file move dir/a.txt a.txt
+    $OJO file remove b.txt
+    run $OJO status
+    assert_line --index 0 "moved            dir/a.txt -> a.txt"
+    assert_line --index 1 "removed          b.txt"
+    run $OJO checkout master
+    assert_failure
+
+    $OJO record -a Author -m Msg
+    $OJO branch new other
+    run $OJO checkout other
+    assert_success
+    run ls
+    assert_output "b.txt"
+    $OJO checkout master
+    run cat a.txt
+    assert_output "A"
+}
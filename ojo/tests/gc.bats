@@ -0,0 +1,28 @@
+#!./libs/bats-core/bin/bats
+
+load 'libs/setup'
+
+@test "gc removes unapplied patches" {
+    $OJO init
+    echo First > ojo_file.txt
+    KEEP=`$OJO patch create -a Author -m Msg --output-hash`
+    $OJO patch apply "$KEEP"
+    printf "First\nSecond\n" > ojo_file.txt
+    DROP=`$OJO patch create -a Author -m Msg --output-hash`
+
+    # The new patch is within the grace period.
+    run $OJO gc
+    assert_success
+    assert_output "Removed 0 patch(es)."
+
+    run $OJO gc --grace-days 0 --dry-run
+    assert_success
+    assert_line --index 0 "$DROP"
+    assert_line --index 1 "Would remove 1 patch(es)."
+
+    run $OJO gc --grace-days 0
+    assert_success
+    assert_line --index 0 "$DROP"
+    run $OJO patch apply "$DROP"
+    assert_failure
+}
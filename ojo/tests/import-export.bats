@@ -71,6 +71,24 @@ load 'libs/setup'
     assert_output Content
 }
 
+@test "export: export and import text" {
+    $OJO init
+    echo Content > ojo_file.txt
+    HASH=`$OJO patch create -a Me -m Msg --output-hash`
+    $OJO patch export --text -o patch.txt $HASH
+    run head -n 1 patch.txt
+    assert_output "patch $HASH"
+
+    mkdir other
+    cd other
+    $OJO init
+    $OJO patch import ../patch.txt
+    $OJO patch apply $HASH
+    $OJO render
+    run cat ojo_file.txt
+    assert_output Content
+}
+
 @test "import: bad file" {
     $OJO init
     run $OJO patch import no_such_file.txt
@@ -14,3 +14,18 @@ load 'libs/setup'
     assert_failure
     assert_output --partial "There is already a repository"
 }
+
+@test "open from a subdirectory" {
+    $OJO init
+    mkdir -p a/b
+    cd a/b
+    run $OJO branch list
+    assert_success
+    assert_output "* master"
+}
+
+@test "no repo" {
+    run $OJO branch list
+    assert_failure
+    assert_output --partial "I could not find a repository"
+}
@@ -11,6 +11,10 @@ setup() {
     # Run everything in a clean tmpdir.
     export TEST_WORKING_DIR=$(mktemp -d)
     cd "$TEST_WORKING_DIR"
+
+    # Don't pick up the settings of whoever is running the tests.
+    export OJO_SYSTEM_CONFIG="$TEST_WORKING_DIR/system-config.toml"
+    export OJO_USER_CONFIG="$TEST_WORKING_DIR/user-config.toml"
 }
 
 teardown() {
@@ -7,3 +7,77 @@ load 'libs/setup'
     assert_failure
     assert_output "Error: Failed to find a ojo repository"
 }
+
+@test "log shows metadata" {
+    $OJO init
+    echo First > ojo_file.txt
+    $OJO patch create -a Author --email author@example.com --meta Reviewed-by=Someone -m "Summary
+
+Details" --then-apply
+    run $OJO log
+    assert_success
+    assert_line --index 1 "Author: Author <author@example.com>"
+    assert_line --index 3 "Reviewed-by: Someone"
+    assert_line --index 4 $'\tSummary'
+    assert_line --index 5 $'\tDetails'
+}
+
+@test "patch create: bad metadata" {
+    $OJO init
+    touch ojo_file.txt
+    run $OJO patch create -a me -m msg --meta no-equals-sign
+    assert_failure
+}
+
+@test "log filters by author and description" {
+    $OJO init
+    echo First > ojo_file.txt
+    $OJO patch create -a Alice -m "Add the first line" --then-apply
+    echo Second >> ojo_file.txt
+    $OJO patch create -a Bob --email bob@example.com -m "Add another line" --then-apply
+
+    run $OJO log --author alice
+    assert_success
+    assert_line --index 1 "Author: Alice"
+    refute_output --partial "Bob"
+
+    run $OJO log --author bob@example.com --grep line
+    assert_success
+    assert_line --index 1 "Author: Bob <bob@example.com>"
+    refute_output --partial "Alice"
+
+    run $OJO log --grep "first LINE"
+    assert_success
+    assert_line --index 3 $'\tAdd the first line'
+    refute_output --partial "another"
+}
+
+@test "log --stat" {
+    $OJO init
+    printf "First\nSecond\n" > ojo_file.txt
+    $OJO patch create -a Author -m "Add lines" --then-apply
+    printf "First\nThird\n" > ojo_file.txt
+    $OJO patch create -a Author -m "Change a line" --then-apply
+
+    run $OJO log --stat
+    assert_success
+    assert_line --index 3 "Stat:   1 file(s) changed, 2 line(s) added, 0 line(s) deleted"
+    assert_line --index 8 "Stat:   1 file(s) changed, 1 line(s) added, 1 line(s) deleted"
+}
+
+@test "log --json" {
+    $OJO init
+    echo First > ojo_file.txt
+    $OJO patch create -a Author --email author@example.com -m "Summary
+
+Details" --then-apply
+
+    run $OJO log --json
+    assert_success
+    assert_line --index 0 "["
+    assert_output --partial '"author": "Author"'
+    assert_output --partial '"email": "author@example.com"'
+    assert_output --partial '"summary": "Summary"'
+    assert_output --partial '"description": "Summary\n\nDetails"'
+    assert_output --partial '"deps": []'
+}
@@ -0,0 +1,27 @@
+#!./libs/bats-core/bin/bats
+
+load 'libs/setup'
+
+@test "merge-file: clean" {
+    echo -e "a\nb\nc" > base.txt
+    echo -e "A\nb\nc" > ours.txt
+    echo -e "a\nb\nC" > theirs.txt
+
+    run $OJO merge-file ours.txt base.txt theirs.txt
+    assert_success
+    run cat ours.txt
+    assert_output "$(echo -e "A\nb\nC")"
+}
+
+@test "merge-file: conflict" {
+    echo -e "a\nb\nc" > base.txt
+    echo -e "a\nours\nc" > ours.txt
+    echo -e "a\ntheirs\nc" > theirs.txt
+
+    run $OJO merge-file --stdout ours.txt base.txt theirs.txt
+    assert_failure 1
+    assert_output --partial "$(echo -e "<<<<<<< ours.txt\nours\n=======\ntheirs\n>>>>>>> theirs.txt")"
+    # Nothing gets written with --stdout.
+    run cat ours.txt
+    assert_output "$(echo -e "a\nours\nc")"
+}
@@ -0,0 +1,37 @@
+#!./libs/bats-core/bin/bats
+
+load 'libs/setup'
+
+@test "merge" {
+    $OJO init
+    echo First > ojo_file.txt
+    $OJO patch create -a Author -m Msg --then-apply
+    $OJO branch clone other
+    $OJO branch switch other
+    printf "First\nSecond\n" > ojo_file.txt
+    $OJO patch create -a Author -m Msg --then-apply
+    $OJO branch switch master
+
+    run $OJO merge other --dry-run
+    assert_success
+    $OJO render
+    run cat ojo_file.txt
+    assert_output "First"
+
+    run $OJO merge other
+    assert_success
+    $OJO render
+    run cat ojo_file.txt
+    assert_line --index 0 "First"
+    assert_line --index 1 "Second"
+
+    run $OJO merge other
+    assert_success
+    assert_output "No patches to apply."
+}
+
+@test "merge unknown branch" {
+    $OJO init
+    run $OJO merge missing
+    assert_failure
+}
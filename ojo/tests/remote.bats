@@ -0,0 +1,56 @@
+#!./libs/bats-core/bin/bats
+
+load 'libs/setup'
+
+@test "remote add, list and remove" {
+    $OJO init
+    run $OJO remote add origin example.com:repo
+    assert_success
+    run $OJO remote add origin example.com:other
+    assert_failure
+
+    run $OJO remote list
+    assert_output "origin	example.com:repo"
+
+    run $OJO remote remove origin
+    assert_success
+    run $OJO remote list
+    assert_output ""
+}
+
+@test "fetch and push to a local remote" {
+    mkdir upstream
+    cd upstream
+    $OJO init
+    echo First > ojo_file.txt
+    $OJO patch create -a Author -m Msg --then-apply
+
+    cd ..
+    mkdir local
+    cd local
+    $OJO init
+    $OJO remote add origin ../upstream
+    run $OJO fetch origin
+    assert_success
+    $OJO render origin/master
+    run cat ojo_file.txt
+    assert_output "First"
+
+    $OJO merge origin/master
+    printf "First\nSecond\n" > ojo_file.txt
+    $OJO patch create -a Author -m Msg --then-apply
+    run $OJO push origin
+    assert_success
+    assert_output "Sent 1 patch(es), applied 1."
+
+    cd ../upstream
+    $OJO render
+    run cat ojo_file.txt
+    assert_line --index 1 "Second"
+}
+
+@test "fetch unknown remote" {
+    $OJO init
+    run $OJO fetch origin
+    assert_failure
+}
@@ -10,5 +10,8 @@ load 'libs/setup'
     HASH=`echo "1111" | $OJO resolve --author me --testing 2>&1 | cut -d " " -f 3`
     $OJO patch apply $HASH
     $OJO render
+
+    run $OJO log
+    assert_output --partial "Kind:   conflict resolution"
 }
 
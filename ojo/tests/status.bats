@@ -0,0 +1,42 @@
+#!./libs/bats-core/bin/bats
+
+load 'libs/setup'
+
+@test "status" {
+    $OJO init
+    run $OJO status
+    assert_success
+    assert_output ""
+
+    echo First > ojo_file.txt
+    echo Other > other.txt
+    run $OJO status
+    assert_success
+    assert_line --index 0 "modified         ojo_file.txt"
+    assert_line --index 1 "untracked        other.txt"
+
+    $OJO patch create -a Author -m Msg --then-apply
+    run $OJO status
+    assert_success
+    assert_output "untracked        other.txt"
+
+    rm ojo_file.txt
+    run $OJO status
+    assert_success
+    assert_line --index 0 "missing          ojo_file.txt"
+}
+
+@test "status skips ignored files" {
+    $OJO init
+    mkdir build
+    echo Out > build/out.txt
+    echo Log > debug.log
+    echo Other > other.txt
+    printf "*.log\nbuild/\n" > .ojoignore
+    run $OJO status
+    assert_success
+    assert_line --index 0 "untracked        .ojoignore"
+    assert_line --index 1 "untracked        other.txt"
+    refute_line --partial "debug.log"
+    refute_line --partial "build"
+}
@@ -0,0 +1,75 @@
+/*
+ * A C interface to ojo (see ojo_capi/src/lib.rs for the details).
+ *
+ * Every function returns an ojo_status, and puts its results in the locations that its `out`
+ * arguments point to. If a function fails, ojo_last_error says why. Strings are NUL-terminated
+ * UTF-8, patches are referred to by their base64 ids, and a NULL branch means the repository's
+ * current branch. Strings, lists and contents returned by the library have to be freed with the
+ * matching _free function.
+ */
+
+#ifndef OJO_H
+#define OJO_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef enum ojo_status {
+    OJO_OK = 0,
+    OJO_INVALID_ARGUMENT = 1,
+    OJO_REPO_NOT_FOUND = 2,
+    OJO_UNKNOWN_BRANCH = 3,
+    OJO_UNKNOWN_PATCH = 4,
+    OJO_UNKNOWN_FILE = 5,
+    OJO_NO_CHANGES = 6,
+    OJO_IO = 7,
+    OJO_OTHER = 8,
+    OJO_PANIC = 9,
+} ojo_status;
+
+typedef struct OjoRepo OjoRepo;
+typedef struct OjoStrings OjoStrings;
+
+/* Errors, and freeing the things that the library returns. */
+const char *ojo_last_error(void);
+void ojo_string_free(char *s);
+size_t ojo_strings_len(const OjoStrings *list);
+const char *ojo_strings_get(const OjoStrings *list, size_t index);
+void ojo_strings_free(OjoStrings *list);
+void ojo_bytes_free(uint8_t *bytes, size_t len);
+
+/* Opening and closing repositories. */
+ojo_status ojo_repo_open(const char *path, OjoRepo **out);
+ojo_status ojo_repo_init(const char *path, OjoRepo **out);
+void ojo_repo_free(OjoRepo *repo);
+
+/* Making and applying patches. */
+ojo_status ojo_record(OjoRepo *repo, const char *branch, const char *author, const char *message,
+                      char **out_id);
+ojo_status ojo_apply(OjoRepo *repo, const char *branch, const char *patch_id);
+ojo_status ojo_unapply(OjoRepo *repo, const char *branch, const char *patch_id);
+
+/* History. */
+ojo_status ojo_log(OjoRepo *repo, const char *branch, OjoStrings **out);
+ojo_status ojo_patch_author(OjoRepo *repo, const char *patch_id, char **out);
+ojo_status ojo_patch_description(OjoRepo *repo, const char *patch_id, char **out);
+ojo_status ojo_patch_timestamp(OjoRepo *repo, const char *patch_id, int64_t *out);
+
+/* Files and conflicts. */
+ojo_status ojo_files(OjoRepo *repo, const char *branch, OjoStrings **out);
+ojo_status ojo_render(OjoRepo *repo, const char *branch, const char *path, uint8_t **out,
+                      size_t *out_len);
+ojo_status ojo_conflicted_files(OjoRepo *repo, const char *branch, OjoStrings **out);
+ojo_status ojo_conflict_count(OjoRepo *repo, const char *branch, const char *path, size_t *out);
+ojo_status ojo_conflict_causes(OjoRepo *repo, const char *branch, const char *path,
+                               OjoStrings **out);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* OJO_H */
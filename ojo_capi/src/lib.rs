@@ -0,0 +1,694 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+//! A C interface to `libojo`, for programs (like editors) that aren't written in Rust.
+//!
+//! The functions here are declared in `include/ojo.h`. Repositories, and lists of strings, are
+//! passed around as pointers to opaque types, which have to be freed with the matching `_free`
+//! function. Every function returns an [`OjoStatus`], and puts its results in the locations that
+//! its `out` arguments point to; if it fails, [`ojo_last_error`] says why.
+//!
+//! Strings are passed in and out as NUL-terminated UTF-8. Patches are referred to by their ids,
+//! in the same base64 format that the `ojo` command line uses, and a branch can be given as NULL
+//! to mean the repository's current branch.
+//!
+//! # Safety
+//!
+//! All of the functions taking pointers require them to be either NULL (which makes the function
+//! fail with [`OjoStatus::InvalidArgument`], unless the pointer is documented as optional) or
+//! valid: pointers to strings have to point to NUL-terminated strings, handles have to have come
+//! from this library and not have been freed, and `out` arguments have to point to memory that
+//! can be written. A repository handle can only be used by one thread at a time.
+
+#![allow(clippy::missing_safety_doc)]
+
+use libojo::{Error, PatchId, PatchQuery, RecordOptions, Repo, DEFAULT_WORKING_FILE};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+/// The result of a function in this library.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OjoStatus {
+    /// The function succeeded.
+    Ok = 0,
+    /// An argument was NULL, wasn't UTF-8, or wasn't a valid patch id.
+    InvalidArgument = 1,
+    /// There is no repository at the given path.
+    RepoNotFound = 2,
+    /// The branch doesn't exist.
+    UnknownBranch = 3,
+    /// The patch doesn't exist.
+    UnknownPatch = 4,
+    /// The file doesn't exist.
+    UnknownFile = 5,
+    /// There were no changes to record.
+    NoChanges = 6,
+    /// Reading or writing a file failed.
+    Io = 7,
+    /// Something else went wrong.
+    Other = 8,
+    /// The library panicked; this is a bug in the library.
+    Panic = 9,
+}
+
+/// An open repository.
+pub struct OjoRepo {
+    inner: Repo,
+}
+
+/// A list of strings.
+pub struct OjoStrings {
+    strings: Vec<CString>,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+// The ways that a function can fail.
+enum Failure {
+    Ojo(Error),
+    InvalidArgument(String),
+}
+
+impl From<Error> for Failure {
+    fn from(e: Error) -> Failure {
+        Failure::Ojo(e)
+    }
+}
+
+fn status(e: &Error) -> OjoStatus {
+    match e {
+        Error::RepoNotFound(_) => OjoStatus::RepoNotFound,
+        Error::UnknownBranch(_) => OjoStatus::UnknownBranch,
+        Error::UnknownPatch(_) | Error::UnknownPatchPrefix(_) => OjoStatus::UnknownPatch,
+        Error::UnknownFile(_) => OjoStatus::UnknownFile,
+        Error::NoChanges => OjoStatus::NoChanges,
+        Error::Io(..) => OjoStatus::Io,
+        _ => OjoStatus::Other,
+    }
+}
+
+fn set_last_error(msg: String) {
+    // Error messages shouldn't have NULs in them, but if they do, it's better to cut them short
+    // than to lose them.
+    let msg = match CString::new(msg) {
+        Ok(msg) => msg,
+        Err(e) => {
+            let end = e.nul_position();
+            CString::new(&e.into_vec()[..end]).unwrap()
+        }
+    };
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(msg));
+}
+
+// Runs the body of a function, turning its errors (and panics) into a status.
+fn run<F: FnOnce() -> Result<(), Failure>>(f: F) -> OjoStatus {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => OjoStatus::Ok,
+        Ok(Err(Failure::Ojo(e))) => {
+            set_last_error(e.to_string());
+            status(&e)
+        }
+        Ok(Err(Failure::InvalidArgument(msg))) => {
+            set_last_error(msg);
+            OjoStatus::InvalidArgument
+        }
+        Err(_) => {
+            set_last_error("ojo panicked".to_owned());
+            OjoStatus::Panic
+        }
+    }
+}
+
+unsafe fn opt_str_arg<'a>(s: *const c_char, name: &str) -> Result<Option<&'a str>, Failure> {
+    if s.is_null() {
+        return Ok(None);
+    }
+    match CStr::from_ptr(s).to_str() {
+        Ok(s) => Ok(Some(s)),
+        Err(_) => Err(Failure::InvalidArgument(format!("{} isn't UTF-8", name))),
+    }
+}
+
+unsafe fn str_arg<'a>(s: *const c_char, name: &str) -> Result<&'a str, Failure> {
+    opt_str_arg(s, name)?.ok_or_else(|| Failure::InvalidArgument(format!("{} is NULL", name)))
+}
+
+unsafe fn patch_arg(s: *const c_char) -> Result<PatchId, Failure> {
+    let s = str_arg(s, "patch_id")?;
+    PatchId::from_base64(s)
+        .map_err(|_| Failure::InvalidArgument(format!("{:?} isn't a valid patch id", s)))
+}
+
+unsafe fn repo_arg<'a>(repo: *mut OjoRepo) -> Result<&'a mut Repo, Failure> {
+    match repo.as_mut() {
+        Some(repo) => Ok(&mut repo.inner),
+        None => Err(Failure::InvalidArgument("repo is NULL".to_owned())),
+    }
+}
+
+// The branch that an optional branch argument refers to.
+unsafe fn branch_arg(repo: &Repo, branch: *const c_char) -> Result<String, Failure> {
+    Ok(opt_str_arg(branch, "branch")?
+        .map(|b| b.to_owned())
+        .unwrap_or_else(|| repo.current_branch.clone()))
+}
+
+// Checks an `out` argument before anything is done, so that a NULL one doesn't make a function
+// fail after it has already changed the repository.
+fn out_arg<T>(out: *mut T, name: &str) -> Result<(), Failure> {
+    if out.is_null() {
+        return Err(Failure::InvalidArgument(format!("{} is NULL", name)));
+    }
+    Ok(())
+}
+
+// Writes a result to an `out` argument that was already checked with `out_arg`.
+unsafe fn set_out<T>(out: *mut T, value: T) -> Result<(), Failure> {
+    out.write(value);
+    Ok(())
+}
+
+fn c_string(s: &str) -> CString {
+    CString::new(s.replace('\0', "")).unwrap()
+}
+
+/// Returns a message describing why the last function called on this thread failed, or NULL if
+/// it succeeded.
+///
+/// The message belongs to the library, and stays valid until the next function is called on
+/// this thread.
+#[no_mangle]
+pub extern "C" fn ojo_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match &*last.borrow() {
+        Some(msg) => msg.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// Frees a string that was returned by this library.
+#[no_mangle]
+pub unsafe extern "C" fn ojo_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// Returns the number of strings in a list.
+#[no_mangle]
+pub unsafe extern "C" fn ojo_strings_len(list: *const OjoStrings) -> usize {
+    list.as_ref().map(|l| l.strings.len()).unwrap_or(0)
+}
+
+/// Returns a string in a list, or NULL if `index` is out of bounds. The string belongs to the
+/// list.
+#[no_mangle]
+pub unsafe extern "C" fn ojo_strings_get(list: *const OjoStrings, index: usize) -> *const c_char {
+    match list.as_ref().and_then(|l| l.strings.get(index)) {
+        Some(s) => s.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// Frees a list of strings.
+#[no_mangle]
+pub unsafe extern "C" fn ojo_strings_free(list: *mut OjoStrings) {
+    if !list.is_null() {
+        drop(Box::from_raw(list));
+    }
+}
+
+/// Frees the contents of a file that were returned by [`ojo_render`].
+#[no_mangle]
+pub unsafe extern "C" fn ojo_bytes_free(bytes: *mut u8, len: usize) {
+    if !bytes.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(bytes, len)));
+    }
+}
+
+/// Opens the repository in the directory `path`.
+#[no_mangle]
+pub unsafe extern "C" fn ojo_repo_open(path: *const c_char, out: *mut *mut OjoRepo) -> OjoStatus {
+    run(|| {
+        out_arg(out, "out")?;
+        let inner = Repo::open(str_arg(path, "path")?)?;
+        set_out(out, Box::into_raw(Box::new(OjoRepo { inner })))
+    })
+}
+
+/// Creates a repository in the directory `path`, and opens it.
+#[no_mangle]
+pub unsafe extern "C" fn ojo_repo_init(path: *const c_char, out: *mut *mut OjoRepo) -> OjoStatus {
+    run(|| {
+        out_arg(out, "out")?;
+        let inner = Repo::init(str_arg(path, "path")?)?;
+        inner.write()?;
+        set_out(out, Box::into_raw(Box::new(OjoRepo { inner })))
+    })
+}
+
+/// Closes a repository. Everything that was done to it has already been saved.
+#[no_mangle]
+pub unsafe extern "C" fn ojo_repo_free(repo: *mut OjoRepo) {
+    if !repo.is_null() {
+        drop(Box::from_raw(repo));
+    }
+}
+
+/// Records the changes in the working copy as a patch on `branch` (which may be NULL), and puts
+/// the new patch's id in `out_id`. This fails with [`OjoStatus::NoChanges`] if nothing changed.
+#[no_mangle]
+pub unsafe extern "C" fn ojo_record(
+    repo: *mut OjoRepo,
+    branch: *const c_char,
+    author: *const c_char,
+    message: *const c_char,
+    out_id: *mut *mut c_char,
+) -> OjoStatus {
+    run(|| {
+        out_arg(out_id, "out_id")?;
+        let repo = repo_arg(repo)?;
+        let opts = RecordOptions {
+            branch: Some(branch_arg(repo, branch)?),
+            ..RecordOptions::default()
+        };
+        let author = str_arg(author, "author")?;
+        let message = str_arg(message, "message")?;
+        let id = repo.record(author, message, &opts)?;
+        repo.write()?;
+        set_out(out_id, c_string(&id.to_base64()).into_raw())
+    })
+}
+
+/// Applies a patch (along with the patches that it depends on) to `branch` (which may be NULL).
+#[no_mangle]
+pub unsafe extern "C" fn ojo_apply(
+    repo: *mut OjoRepo,
+    branch: *const c_char,
+    patch_id: *const c_char,
+) -> OjoStatus {
+    run(|| {
+        let repo = repo_arg(repo)?;
+        let branch = branch_arg(repo, branch)?;
+        repo.apply_patch(&branch, &patch_arg(patch_id)?)?;
+        repo.write()?;
+        Ok(())
+    })
+}
+
+/// Unapplies a patch (along with the patches that depend on it) from `branch` (which may be
+/// NULL).
+#[no_mangle]
+pub unsafe extern "C" fn ojo_unapply(
+    repo: *mut OjoRepo,
+    branch: *const c_char,
+    patch_id: *const c_char,
+) -> OjoStatus {
+    run(|| {
+        let repo = repo_arg(repo)?;
+        let branch = branch_arg(repo, branch)?;
+        repo.unapply_patch(&branch, &patch_arg(patch_id)?)?;
+        repo.write()?;
+        Ok(())
+    })
+}
+
+/// Puts the ids of the patches on `branch` (which may be NULL) in `out`, newest first.
+#[no_mangle]
+pub unsafe extern "C" fn ojo_log(
+    repo: *mut OjoRepo,
+    branch: *const c_char,
+    out: *mut *mut OjoStrings,
+) -> OjoStatus {
+    run(|| {
+        out_arg(out, "out")?;
+        let repo = repo_arg(repo)?;
+        let branch = branch_arg(repo, branch)?;
+        let strings = repo
+            .log(&branch, &PatchQuery::default())?
+            .map(|id| c_string(&id.to_base64()))
+            .collect();
+        set_out(out, Box::into_raw(Box::new(OjoStrings { strings })))
+    })
+}
+
+/// Puts the author of a patch in `out`.
+#[no_mangle]
+pub unsafe extern "C" fn ojo_patch_author(
+    repo: *mut OjoRepo,
+    patch_id: *const c_char,
+    out: *mut *mut c_char,
+) -> OjoStatus {
+    run(|| {
+        out_arg(out, "out")?;
+        let repo = repo_arg(repo)?;
+        let header = repo.patch_header(&patch_arg(patch_id)?)?;
+        set_out(out, c_string(&header.author).into_raw())
+    })
+}
+
+/// Puts the description of a patch in `out`.
+#[no_mangle]
+pub unsafe extern "C" fn ojo_patch_description(
+    repo: *mut OjoRepo,
+    patch_id: *const c_char,
+    out: *mut *mut c_char,
+) -> OjoStatus {
+    run(|| {
+        out_arg(out, "out")?;
+        let repo = repo_arg(repo)?;
+        let header = repo.patch_header(&patch_arg(patch_id)?)?;
+        set_out(out, c_string(&header.description).into_raw())
+    })
+}
+
+/// Puts the time that a patch was created, in seconds since the Unix epoch, in `out`.
+#[no_mangle]
+pub unsafe extern "C" fn ojo_patch_timestamp(
+    repo: *mut OjoRepo,
+    patch_id: *const c_char,
+    out: *mut i64,
+) -> OjoStatus {
+    run(|| {
+        out_arg(out, "out")?;
+        let repo = repo_arg(repo)?;
+        let header = repo.patch_header(&patch_arg(patch_id)?)?;
+        set_out(out, header.timestamp.timestamp())
+    })
+}
+
+/// Puts the paths of the files on `branch` (which may be NULL) in `out`.
+#[no_mangle]
+pub unsafe extern "C" fn ojo_files(
+    repo: *mut OjoRepo,
+    branch: *const c_char,
+    out: *mut *mut OjoStrings,
+) -> OjoStatus {
+    run(|| {
+        out_arg(out, "out")?;
+        let repo = repo_arg(repo)?;
+        let branch = branch_arg(repo, branch)?;
+        let strings = repo.files(&branch)?.map(c_string).collect();
+        set_out(out, Box::into_raw(Box::new(OjoStrings { strings })))
+    })
+}
+
+/// Renders the file at `path` on `branch` (which may be NULL), and puts its contents in `out`,
+/// and their length in `out_len`. The branch's own lines are at the path `ojo_file.txt` (see
+/// [`DEFAULT_WORKING_FILE`]). Conflicts are written out with conflict markers. The contents
+/// aren't NUL-terminated, and have to be freed with [`ojo_bytes_free`].
+#[no_mangle]
+pub unsafe extern "C" fn ojo_render(
+    repo: *mut OjoRepo,
+    branch: *const c_char,
+    path: *const c_char,
+    out: *mut *mut u8,
+    out_len: *mut usize,
+) -> OjoStatus {
+    run(|| {
+        out_arg(out, "out")?;
+        out_arg(out_len, "out_len")?;
+        let repo = repo_arg(repo)?;
+        let branch = branch_arg(repo, branch)?;
+        let path = str_arg(path, "path")?;
+        let mut contents = Vec::new();
+        if path == DEFAULT_WORKING_FILE {
+            repo.render(&branch, &mut contents)?;
+        } else {
+            repo.render_file(&branch, path, &mut contents)?;
+        }
+        let contents = contents.into_boxed_slice();
+        out_len.write(contents.len());
+        out.write(Box::into_raw(contents) as *mut u8);
+        Ok(())
+    })
+}
+
+/// Puts the paths of the files on `branch` (which may be NULL) that have conflicts in `out`.
+#[no_mangle]
+pub unsafe extern "C" fn ojo_conflicted_files(
+    repo: *mut OjoRepo,
+    branch: *const c_char,
+    out: *mut *mut OjoStrings,
+) -> OjoStatus {
+    run(|| {
+        out_arg(out, "out")?;
+        let repo = repo_arg(repo)?;
+        let branch = branch_arg(repo, branch)?;
+        let strings = repo
+            .conflicts(&branch)?
+            .keys()
+            .map(|path| c_string(path))
+            .collect();
+        set_out(out, Box::into_raw(Box::new(OjoStrings { strings })))
+    })
+}
+
+/// Puts the number of conflicts in the file at `path` on `branch` (which may be NULL) in `out`.
+#[no_mangle]
+pub unsafe extern "C" fn ojo_conflict_count(
+    repo: *mut OjoRepo,
+    branch: *const c_char,
+    path: *const c_char,
+    out: *mut usize,
+) -> OjoStatus {
+    run(|| {
+        out_arg(out, "out")?;
+        let repo = repo_arg(repo)?;
+        let branch = branch_arg(repo, branch)?;
+        let path = str_arg(path, "path")?;
+        let conflicts = repo.conflicts(&branch)?;
+        set_out(out, conflicts.get(path).map(|c| c.len()).unwrap_or(0))
+    })
+}
+
+/// Puts the ids of the patches that caused the conflicts in the file at `path` on `branch`
+/// (which may be NULL) in `out`. Leaving out any one of them (with [`ojo_unapply`]) usually gets
+/// rid of a conflict.
+#[no_mangle]
+pub unsafe extern "C" fn ojo_conflict_causes(
+    repo: *mut OjoRepo,
+    branch: *const c_char,
+    path: *const c_char,
+    out: *mut *mut OjoStrings,
+) -> OjoStatus {
+    run(|| {
+        out_arg(out, "out")?;
+        let repo = repo_arg(repo)?;
+        let branch = branch_arg(repo, branch)?;
+        let path = str_arg(path, "path")?;
+        let conflicts = repo.conflicts(&branch)?;
+        let mut causes = conflicts
+            .get(path)
+            .into_iter()
+            .flatten()
+            .flat_map(|c| c.causes.iter())
+            .collect::<Vec<_>>();
+        causes.sort();
+        causes.dedup();
+        let strings = causes
+            .into_iter()
+            .map(|id| c_string(&id.to_base64()))
+            .collect();
+        set_out(out, Box::into_raw(Box::new(OjoStrings { strings })))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // A directory that is deleted at the end of the test.
+    struct TmpDir(PathBuf);
+
+    impl TmpDir {
+        fn new() -> TmpDir {
+            static COUNT: AtomicUsize = AtomicUsize::new(0);
+            let name = format!(
+                "ojo_capi-{}-{}",
+                std::process::id(),
+                COUNT.fetch_add(1, Ordering::SeqCst)
+            );
+            let dir = std::env::temp_dir().join(name);
+            std::fs::create_dir_all(&dir).unwrap();
+            TmpDir(dir)
+        }
+
+        fn c_path(&self) -> CString {
+            CString::new(self.0.to_str().unwrap()).unwrap()
+        }
+    }
+
+    impl Drop for TmpDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn take_string(s: *mut c_char) -> String {
+        let ret = unsafe { CStr::from_ptr(s) }.to_str().unwrap().to_owned();
+        unsafe { ojo_string_free(s) };
+        ret
+    }
+
+    fn take_strings(list: *mut OjoStrings) -> Vec<String> {
+        let ret = (0..unsafe { ojo_strings_len(list) })
+            .map(|i| unsafe { CStr::from_ptr(ojo_strings_get(list, i)) })
+            .map(|s| s.to_str().unwrap().to_owned())
+            .collect();
+        unsafe { ojo_strings_free(list) };
+        ret
+    }
+
+    fn record(repo: *mut OjoRepo, msg: &str) -> String {
+        let author = CString::new("Author").unwrap();
+        let msg = CString::new(msg).unwrap();
+        let mut id = ptr::null_mut();
+        let status =
+            unsafe { ojo_record(repo, ptr::null(), author.as_ptr(), msg.as_ptr(), &mut id) };
+        assert_eq!(status, OjoStatus::Ok);
+        take_string(id)
+    }
+
+    fn render(repo: *mut OjoRepo, path: &str) -> Vec<u8> {
+        let path = CString::new(path).unwrap();
+        let (mut bytes, mut len) = (ptr::null_mut(), 0);
+        let status = unsafe { ojo_render(repo, ptr::null(), path.as_ptr(), &mut bytes, &mut len) };
+        assert_eq!(status, OjoStatus::Ok);
+        let ret = unsafe { std::slice::from_raw_parts(bytes, len) }.to_vec();
+        unsafe { ojo_bytes_free(bytes, len) };
+        ret
+    }
+
+    #[test]
+    fn record_and_log() {
+        let dir = TmpDir::new();
+        let mut repo = ptr::null_mut();
+        assert_eq!(
+            unsafe { ojo_repo_init(dir.c_path().as_ptr(), &mut repo) },
+            OjoStatus::Ok
+        );
+        std::fs::write(dir.0.join("ojo_file.txt"), "first\n").unwrap();
+        let first = record(repo, "First");
+        std::fs::write(dir.0.join("ojo_file.txt"), "first\nsecond\n").unwrap();
+        let second = record(repo, "Second");
+        unsafe { ojo_repo_free(repo) };
+
+        // Everything was saved, so it's all still there after opening the repository again.
+        let mut repo = ptr::null_mut();
+        assert_eq!(
+            unsafe { ojo_repo_open(dir.c_path().as_ptr(), &mut repo) },
+            OjoStatus::Ok
+        );
+        let mut log = ptr::null_mut();
+        assert_eq!(
+            unsafe { ojo_log(repo, ptr::null(), &mut log) },
+            OjoStatus::Ok
+        );
+        assert_eq!(take_strings(log), vec![second.clone(), first.clone()]);
+        let mut desc = ptr::null_mut();
+        let id = CString::new(second.clone()).unwrap();
+        assert_eq!(
+            unsafe { ojo_patch_description(repo, id.as_ptr(), &mut desc) },
+            OjoStatus::Ok
+        );
+        assert_eq!(take_string(desc), "Second");
+        assert_eq!(render(repo, "ojo_file.txt"), b"first\nsecond\n");
+
+        assert_eq!(
+            unsafe { ojo_unapply(repo, ptr::null(), id.as_ptr()) },
+            OjoStatus::Ok
+        );
+        assert_eq!(render(repo, "ojo_file.txt"), b"first\n");
+        assert_eq!(
+            unsafe { ojo_apply(repo, ptr::null(), id.as_ptr()) },
+            OjoStatus::Ok
+        );
+        assert_eq!(render(repo, "ojo_file.txt"), b"first\nsecond\n");
+
+        let mut files = ptr::null_mut();
+        assert_eq!(
+            unsafe { ojo_conflicted_files(repo, ptr::null(), &mut files) },
+            OjoStatus::Ok
+        );
+        assert!(take_strings(files).is_empty());
+        unsafe { ojo_repo_free(repo) };
+    }
+
+    #[test]
+    fn errors() {
+        let dir = TmpDir::new();
+        let mut repo = ptr::null_mut();
+        assert_eq!(
+            unsafe { ojo_repo_open(dir.c_path().as_ptr(), &mut repo) },
+            OjoStatus::RepoNotFound
+        );
+        assert!(repo.is_null());
+        assert!(!ojo_last_error().is_null());
+
+        assert_eq!(
+            unsafe { ojo_repo_init(dir.c_path().as_ptr(), &mut repo) },
+            OjoStatus::Ok
+        );
+        assert!(ojo_last_error().is_null());
+        let mut id = ptr::null_mut();
+        let author = CString::new("Author").unwrap();
+        assert_eq!(
+            unsafe { ojo_record(repo, ptr::null(), author.as_ptr(), ptr::null(), &mut id) },
+            OjoStatus::InvalidArgument
+        );
+        let msg = CString::new("Msg").unwrap();
+        assert_eq!(
+            unsafe { ojo_record(repo, ptr::null(), author.as_ptr(), msg.as_ptr(), &mut id) },
+            OjoStatus::NoChanges
+        );
+
+        // A NULL out pointer is caught before the patch is recorded.
+        std::fs::write(dir.0.join("ojo_file.txt"), "line\n").unwrap();
+        assert_eq!(
+            unsafe {
+                ojo_record(
+                    repo,
+                    ptr::null(),
+                    author.as_ptr(),
+                    msg.as_ptr(),
+                    ptr::null_mut(),
+                )
+            },
+            OjoStatus::InvalidArgument
+        );
+        let mut log = ptr::null_mut();
+        assert_eq!(
+            unsafe { ojo_log(repo, ptr::null(), &mut log) },
+            OjoStatus::Ok
+        );
+        assert!(take_strings(log).is_empty());
+        let branch = CString::new("nope").unwrap();
+        assert_eq!(
+            unsafe { ojo_log(repo, branch.as_ptr(), &mut log) },
+            OjoStatus::UnknownBranch
+        );
+        let bad_id = CString::new("not an id").unwrap();
+        assert_eq!(
+            unsafe { ojo_apply(repo, ptr::null(), bad_id.as_ptr()) },
+            OjoStatus::InvalidArgument
+        );
+        unsafe { ojo_repo_free(repo) };
+    }
+}
@@ -0,0 +1,478 @@
+// Copyright 2018-2019 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// See the LICENSE-APACHE or LICENSE-MIT files at the top-level directory
+// of this distribution.
+
+//! Python bindings for `libojo`, as a module called `ojo`.
+//!
+//! The module has a `Repo` class, which wraps [`libojo::Repo`], and a `Patch` class with the
+//! metadata of a patch. Like in Rust, changes to a repository are only saved when
+//! `Repo.write()` is called. Errors are raised as `ojo.OjoError`, or as one of its subclasses
+//! for the errors that scripts are likely to want to handle (like `ojo.NoChangesError`). Patches
+//! can be referred to by any unambiguous prefix of their ids.
+//!
+//! To build a module that python can import, use `maturin` (which turns on the
+//! `extension-module` feature).
+
+use libojo::{Error, LineDiff, PatchId, PatchQuery, RecordOptions, DEFAULT_WORKING_FILE};
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyOSError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+create_exception!(ojo, OjoError, PyException, "An error from ojo.");
+create_exception!(
+    ojo,
+    RepoNotFoundError,
+    OjoError,
+    "There is no repository at the given path."
+);
+create_exception!(
+    ojo,
+    UnknownBranchError,
+    OjoError,
+    "The branch doesn't exist."
+);
+create_exception!(ojo, UnknownPatchError, OjoError, "The patch doesn't exist.");
+create_exception!(ojo, UnknownFileError, OjoError, "The file doesn't exist.");
+create_exception!(
+    ojo,
+    NoChangesError,
+    OjoError,
+    "There was nothing to record."
+);
+
+fn py_err(e: Error) -> PyErr {
+    let msg = e.to_string();
+    match e {
+        Error::RepoNotFound(_) => RepoNotFoundError::new_err(msg),
+        Error::UnknownBranch(_) => UnknownBranchError::new_err(msg),
+        Error::UnknownPatch(_) | Error::UnknownPatchPrefix(_) => UnknownPatchError::new_err(msg),
+        Error::UnknownFile(_) => UnknownFileError::new_err(msg),
+        Error::NoChanges => NoChangesError::new_err(msg),
+        Error::Io(..) => PyOSError::new_err(msg),
+        _ => OjoError::new_err(msg),
+    }
+}
+
+/// An ojo repository.
+#[pyclass(module = "ojo")]
+struct Repo {
+    inner: libojo::Repo,
+}
+
+impl Repo {
+    fn branch(&self, branch: Option<&str>) -> String {
+        branch.unwrap_or(&self.inner.current_branch).to_owned()
+    }
+
+    fn patch_id(&self, id: &str) -> PyResult<PatchId> {
+        self.inner.resolve_patch_prefix(id).map_err(py_err)
+    }
+}
+
+#[pymethods]
+impl Repo {
+    /// Opens the repository containing the directory `path`.
+    #[staticmethod]
+    fn open(path: PathBuf) -> PyResult<Repo> {
+        let inner = libojo::Repo::open(path).map_err(py_err)?;
+        Ok(Repo { inner })
+    }
+
+    /// Creates a repository in the directory `path`, and writes it to disk.
+    #[staticmethod]
+    fn init(path: PathBuf) -> PyResult<Repo> {
+        let inner = libojo::Repo::init(path).map_err(py_err)?;
+        inner.write().map_err(py_err)?;
+        Ok(Repo { inner })
+    }
+
+    /// The name of the current branch.
+    #[getter]
+    fn current_branch(&self) -> String {
+        self.inner.current_branch.clone()
+    }
+
+    /// The path of the repository's root directory.
+    #[getter]
+    fn root_dir(&self) -> PathBuf {
+        self.inner.root_dir.clone()
+    }
+
+    /// Saves the changes that were made to the repository.
+    fn write(&self) -> PyResult<()> {
+        self.inner.write().map_err(py_err)
+    }
+
+    /// Returns the names of all the branches.
+    fn branches(&self) -> Vec<String> {
+        self.inner.branches().map(|b| b.to_owned()).collect()
+    }
+
+    /// Returns the paths of the files on a branch (the current one, by default).
+    #[pyo3(signature = (branch = None))]
+    fn files(&self, branch: Option<&str>) -> PyResult<Vec<String>> {
+        let branch = self.branch(branch);
+        let files = self.inner.files(&branch).map_err(py_err)?;
+        Ok(files.map(|f| f.to_owned()).collect())
+    }
+
+    /// Returns the contents of a file on a branch, as bytes. Conflicts are written out with
+    /// conflict markers. By default, this is the branch's own lines, on the current branch.
+    #[pyo3(signature = (path = None, branch = None))]
+    fn render<'py>(
+        &self,
+        py: Python<'py>,
+        path: Option<&str>,
+        branch: Option<&str>,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let branch = self.branch(branch);
+        let mut contents = Vec::new();
+        match path {
+            Some(path) if path != DEFAULT_WORKING_FILE => {
+                self.inner.render_file(&branch, path, &mut contents)
+            }
+            _ => self.inner.render(&branch, &mut contents),
+        }
+        .map_err(py_err)?;
+        Ok(PyBytes::new(py, &contents))
+    }
+
+    /// Compares the lines of a file on a branch with `contents`.
+    ///
+    /// Returns a list of `(op, line)` pairs, where `op` is `"keep"`, `"delete"` or `"insert"`,
+    /// and `line` is the line's bytes. By default, the file is the branch's own lines, on the
+    /// current branch.
+    #[pyo3(signature = (contents, path = None, branch = None))]
+    fn diff<'py>(
+        &self,
+        py: Python<'py>,
+        contents: &[u8],
+        path: Option<&str>,
+        branch: Option<&str>,
+    ) -> PyResult<Vec<(&'static str, Bound<'py, PyBytes>)>> {
+        let branch = self.branch(branch);
+        let diff = match path {
+            Some(path) if path != DEFAULT_WORKING_FILE => {
+                self.inner.diff_file(&branch, path, contents)
+            }
+            _ => self.inner.diff(&branch, contents),
+        }
+        .map_err(py_err)?;
+        let ret = diff
+            .diff
+            .iter()
+            .map(|op| match *op {
+                LineDiff::Keep(i, _) => ("keep", diff.file_a.node(i)),
+                LineDiff::Delete(i) => ("delete", diff.file_a.node(i)),
+                LineDiff::New(j) => ("insert", diff.file_b.node(j)),
+            })
+            .map(|(op, line)| (op, PyBytes::new(py, line)))
+            .collect();
+        Ok(ret)
+    }
+
+    /// Records the changes in the working copy as a patch on a branch (the current one, by
+    /// default), and returns the new patch's id. Raises `NoChangesError` if nothing changed.
+    #[pyo3(signature = (author, message, branch = None, email = None))]
+    fn record(
+        &mut self,
+        author: &str,
+        message: &str,
+        branch: Option<&str>,
+        email: Option<String>,
+    ) -> PyResult<String> {
+        let opts = RecordOptions {
+            branch: Some(self.branch(branch)),
+            email,
+            ..RecordOptions::default()
+        };
+        let id = self.inner.record(author, message, &opts).map_err(py_err)?;
+        Ok(id.to_base64())
+    }
+
+    /// Applies a patch (and the patches that it depends on) to a branch (the current one, by
+    /// default). Returns the ids of the patches that were applied.
+    #[pyo3(signature = (patch, branch = None))]
+    fn apply(&mut self, patch: &str, branch: Option<&str>) -> PyResult<Vec<String>> {
+        let id = self.patch_id(patch)?;
+        let branch = self.branch(branch);
+        let applied = self.inner.apply_patch(&branch, &id).map_err(py_err)?;
+        Ok(applied.iter().map(|p| p.to_base64()).collect())
+    }
+
+    /// Unapplies a patch (and the patches that depend on it) from a branch (the current one, by
+    /// default). Returns the ids of the patches that were unapplied.
+    #[pyo3(signature = (patch, branch = None))]
+    fn unapply(&mut self, patch: &str, branch: Option<&str>) -> PyResult<Vec<String>> {
+        let id = self.patch_id(patch)?;
+        let branch = self.branch(branch);
+        let unapplied = self.inner.unapply_patch(&branch, &id).map_err(py_err)?;
+        Ok(unapplied.iter().map(|p| p.to_base64()).collect())
+    }
+
+    /// Returns the patch with the given id.
+    fn patch(slf: &Bound<'_, Self>, id: &str) -> PyResult<Patch> {
+        let id = slf.borrow().patch_id(id)?;
+        Patch::new(slf, id)
+    }
+
+    /// Iterates over the patches on a branch (the current one, by default), newest first.
+    ///
+    /// Only the patches whose author has the name or email address `author`, whose description
+    /// contains all the words in `words`, and that touch the file at `path` are included.
+    #[pyo3(signature = (branch = None, *, author = None, words = None, path = None))]
+    fn log(
+        slf: &Bound<'_, Self>,
+        branch: Option<&str>,
+        author: Option<String>,
+        words: Option<String>,
+        path: Option<String>,
+    ) -> PyResult<Log> {
+        let repo = slf.borrow();
+        let branch = repo.branch(branch);
+        let query = PatchQuery {
+            author,
+            words,
+            path,
+            ..PatchQuery::default()
+        };
+        let ids = repo.inner.log(&branch, &query).map_err(py_err)?.collect();
+        Ok(Log {
+            repo: slf.clone().unbind(),
+            ids,
+            pos: 0,
+        })
+    }
+
+    /// Returns the number of conflicts in each file on a branch (the current one, by default)
+    /// that has any.
+    #[pyo3(signature = (branch = None))]
+    fn conflicts(&self, branch: Option<&str>) -> PyResult<BTreeMap<String, usize>> {
+        let branch = self.branch(branch);
+        let conflicts = self.inner.conflicts(&branch).map_err(py_err)?;
+        Ok(conflicts.into_iter().map(|(p, c)| (p, c.len())).collect())
+    }
+
+    fn __repr__(&self) -> String {
+        format!("<ojo.Repo at {:?}>", self.inner.root_dir)
+    }
+}
+
+/// The metadata of a patch.
+#[pyclass(module = "ojo")]
+struct Patch {
+    repo: Py<Repo>,
+    /// The patch's id.
+    #[pyo3(get)]
+    id: String,
+    /// The patch's author.
+    #[pyo3(get)]
+    author: String,
+    /// The email address of the patch's author, if it has one.
+    #[pyo3(get)]
+    email: Option<String>,
+    /// The patch's description.
+    #[pyo3(get)]
+    description: String,
+    // The time that the patch was created, in seconds since the Unix epoch.
+    timestamp: i64,
+    /// The ids of the patches that this one depends on.
+    #[pyo3(get)]
+    deps: Vec<String>,
+}
+
+impl Patch {
+    fn new(repo: &Bound<'_, Repo>, id: PatchId) -> PyResult<Patch> {
+        let r = repo.borrow();
+        let header = r.inner.patch_header(&id).map_err(py_err)?;
+        Ok(Patch {
+            repo: repo.clone().unbind(),
+            id: id.to_base64(),
+            author: header.author.clone(),
+            email: header.email.clone(),
+            description: header.description.clone(),
+            timestamp: header.timestamp.timestamp(),
+            deps: r.inner.patch_deps(&id).map(|p| p.to_base64()).collect(),
+        })
+    }
+}
+
+#[pymethods]
+impl Patch {
+    /// The time that the patch was created, as a `datetime` in UTC.
+    #[getter]
+    fn timestamp<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let datetime = py.import("datetime")?;
+        let utc = datetime.getattr("timezone")?.getattr("utc")?;
+        datetime
+            .getattr("datetime")?
+            .call_method1("fromtimestamp", (self.timestamp, utc))
+    }
+
+    /// Returns the patch's changes, as a unified diff in the format of `git diff`.
+    fn unified_diff(&self, py: Python<'_>) -> PyResult<String> {
+        let repo = self.repo.borrow(py);
+        let id = PatchId::from_base64(&self.id).expect("a patch has a valid id");
+        let patch = repo.inner.open_patch(&id).map_err(py_err)?;
+        patch.to_unified_diff(&repo.inner).map_err(py_err)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("<ojo.Patch {}>", self.id)
+    }
+}
+
+/// An iterator over the patches on a branch (see `Repo.log`).
+#[pyclass(module = "ojo")]
+struct Log {
+    repo: Py<Repo>,
+    ids: Vec<PatchId>,
+    // The number of patches that have been returned.
+    pos: usize,
+}
+
+#[pymethods]
+impl Log {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python<'_>) -> PyResult<Option<Patch>> {
+        let id = match self.ids.get(self.pos) {
+            Some(id) => *id,
+            None => return Ok(None),
+        };
+        self.pos += 1;
+        Patch::new(self.repo.bind(py), id).map(Some)
+    }
+
+    fn __len__(&self) -> usize {
+        self.ids.len() - self.pos
+    }
+}
+
+#[pymodule]
+fn ojo(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = m.py();
+    m.add_class::<Repo>()?;
+    m.add_class::<Patch>()?;
+    m.add_class::<Log>()?;
+    m.add("OjoError", py.get_type::<OjoError>())?;
+    m.add("RepoNotFoundError", py.get_type::<RepoNotFoundError>())?;
+    m.add("UnknownBranchError", py.get_type::<UnknownBranchError>())?;
+    m.add("UnknownPatchError", py.get_type::<UnknownPatchError>())?;
+    m.add("UnknownFileError", py.get_type::<UnknownFileError>())?;
+    m.add("NoChangesError", py.get_type::<NoChangesError>())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::types::PyDict;
+    use std::ffi::CString;
+    use std::sync::Once;
+
+    // Runs some python code, with the `ojo` module imported and the path of a fresh directory in
+    // the variable `tmp`.
+    fn run(name: &str, code: &str) {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            pyo3::append_to_inittab!(ojo);
+            pyo3::prepare_freethreaded_python();
+        });
+
+        let dir = std::env::temp_dir().join(format!("ojo_py-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let result = Python::with_gil(|py| {
+            let locals = PyDict::new(py);
+            locals.set_item("ojo", py.import("ojo")?)?;
+            locals.set_item("tmp", &dir)?;
+            let code = CString::new(code).unwrap();
+            py.run(&code, None, Some(&locals))
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+        if let Err(e) = result {
+            panic!("{}", e);
+        }
+    }
+
+    #[test]
+    fn record_and_log() {
+        run(
+            "record-and-log",
+            r#"
+import os
+repo = ojo.Repo.init(tmp)
+with open(os.path.join(tmp, "ojo_file.txt"), "w") as f:
+    f.write("a\nb\n")
+first = repo.record("Alice", "First", email="alice@example.com")
+with open(os.path.join(tmp, "ojo_file.txt"), "w") as f:
+    f.write("a\nc\n")
+assert repo.diff(b"a\nc\n") == [("keep", b"a\n"), ("delete", b"b\n"), ("insert", b"c\n")]
+second = repo.record("Bob", "Second")
+repo.write()
+
+repo = ojo.Repo.open(tmp)
+assert repo.current_branch == "master"
+patches = list(repo.log())
+assert [p.id for p in patches] == [second, first]
+assert patches[1].author == "Alice" and patches[1].email == "alice@example.com"
+assert patches[0].deps == [first]
+assert patches[0].timestamp.tzinfo is not None
+assert [p.id for p in repo.log(author="alice")] == [first]
+assert "-b\n+c\n" in repo.patch(second[:6]).unified_diff()
+assert repo.render() == b"a\nc\n"
+
+assert repo.unapply(second) == [second]
+assert repo.render() == b"a\nb\n"
+assert repo.apply(second) == [second]
+assert repo.conflicts() == {}
+"#,
+        );
+    }
+
+    #[test]
+    fn errors() {
+        run(
+            "errors",
+            r#"
+try:
+    ojo.Repo.open(tmp)
+    assert False
+except ojo.RepoNotFoundError:
+    pass
+
+repo = ojo.Repo.init(tmp)
+try:
+    repo.record("Alice", "Nothing")
+    assert False
+except ojo.NoChangesError as e:
+    assert isinstance(e, ojo.OjoError)
+
+try:
+    repo.files("nope")
+    assert False
+except ojo.UnknownBranchError:
+    pass
+
+try:
+    repo.patch("Pnope")
+    assert False
+except ojo.UnknownPatchError:
+    pass
+"#,
+        );
+    }
+}
@@ -28,7 +28,7 @@ impl Repo {
     pub fn commit(&mut self, new_input: &str) {
         match self.inner.diff("master", new_input.as_bytes()) {
             Ok(diff) => {
-                let changes = libojo::Changes::from_diff(&diff.file_a, &diff.file_b, &diff.diff);
+                let changes = diff.changes();
                 if !changes.changes.is_empty() {
                     let id = self.inner.create_patch("You", "Msg", changes).unwrap();
                     self.inner.apply_patch("master", &id).unwrap();